@@ -14,10 +14,10 @@ static GLOBAL: Jemalloc = Jemalloc;
 
 extern crate clash_lib as clash;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use clash::TokioRuntime;
 use std::{
-    io::Write,
+    io::{Read, Write},
     path::{Path, PathBuf},
     process::exit,
 };
@@ -33,17 +33,26 @@ struct Cli {
         long,
         visible_short_aliases = ['f'], // -f is used by clash, it is a compatibility option
         value_parser,
-        value_name = "FILE",
+        value_name = "FILE|URL",
         default_value = "config.yaml",
-        help = "Specify configuration file"
+        help = "Specify configuration file, or an http(s) URL to download it from \
+                at startup, the last download is used when it can't be reached"
     )]
     config: PathBuf,
+    #[clap(
+        long,
+        value_name = "SHA256",
+        help = "The checksum in hex the configuration downloaded must have"
+    )]
+    config_sha256: Option<String>,
     #[clap(
         short = 't',
         long,
+        visible_alias = "test",
         value_parser,
         default_value = "false",
-        help = "Test configuration and exit"
+        help = "Test configuration, the providers and databases it refers to and \
+                exit, non-zero if there are errors"
     )]
     test_config: bool,
     #[clap(
@@ -65,11 +74,111 @@ struct Cli {
         help = "Enable crash report to help improve clash"
     )]
     help_improve: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a subscription to the clash proxies format and exit
+    ///
+    /// Base64 or plain share URI lists, sing-box and surge configs are read
+    /// the same way as by the proxy providers
+    ConvertProvider {
+        #[clap(value_name = "URL|FILE")]
+        source: String,
+        #[clap(long, value_enum, default_value = "yaml")]
+        out: OutputFormat,
+    },
+    /// Compile a domain or ipcidr rule set to the binary format and exit
+    ///
+    /// The rule providers tell the compiled files from the YAML ones, and load
+    /// them without parsing the rules
+    CompileRuleset {
+        #[clap(value_name = "FILE")]
+        input: PathBuf,
+        #[clap(long, value_enum)]
+        behavior: RulesetBehavior,
+        #[clap(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// Encrypt a value read from stdin for the `secrets` section and exit
+    ///
+    /// The passphrase is the content of the key file, or the
+    /// CLASH_SECRETS_PASSPHRASE environment variable without one
+    EncryptSecret {
+        #[clap(long, value_name = "FILE")]
+        key_file: Option<String>,
+    },
+    /// Handle a clash:// URL with the running instance and exit
+    ///
+    /// clash://select?group=<group>&name=<proxy> selects a proxy of a group.
+    /// Register this as the handler of the clash URL scheme to use such links
+    /// in launchers and scripts
+    OpenUrl {
+        url: String,
+        /// the external controller address
+        #[clap(long, default_value = "127.0.0.1:9090")]
+        controller: String,
+        /// not needed with controller-local-select
+        #[clap(long)]
+        secret: Option<String>,
+    },
+    /// Ask the running instance over its external controller, for cron jobs
+    /// and scripts
+    Ctl {
+        #[clap(subcommand)]
+        command: CtlCommand,
+        /// the external controller address
+        #[clap(long, default_value = "127.0.0.1:9090", global = true)]
+        controller: String,
+        #[clap(long, global = true)]
+        secret: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CtlCommand {
+    Providers {
+        #[clap(subcommand)]
+        command: ProvidersCommand,
+    },
+    Proxies {
+        #[clap(subcommand)]
+        command: ProxiesCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProvidersCommand {
+    /// Update a proxy provider from its source
+    Update { name: String },
+}
+
+#[derive(Subcommand)]
+enum ProxiesCommand {
+    /// Health-check the proxies of a group, printing the delays of the ones
+    /// alive
+    Check { group: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Yaml,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RulesetBehavior {
+    Domain,
+    Ipcidr,
 }
 
 fn main() {
     #[cfg(feature = "dhat-heap")]
     let _profiler = dhat::Profiler::new_heap();
+    #[cfg(all(feature = "jemallocator", not(feature = "dhat-heap")))]
+    clash::set_allocator_stats(jemalloc_stats);
 
     let cli = Cli::parse();
 
@@ -79,42 +188,151 @@ fn main() {
             env!("CARGO_PKG_NAME"),
             env!("CLASH_VERSION_OVERRIDE") // Generated by build.rs
         );
+        let mut info = clash::build_info();
+        if cfg!(all(feature = "jemallocator", not(feature = "dhat-heap"))) {
+            info.features.push("jemallocator");
+        }
+        if !info.git_sha.is_empty() {
+            println!("git sha: {}", info.git_sha);
+        }
+        println!("build date: {}", info.build_date);
+        println!("platform: {}", info.platform);
+        println!("features: {}", info.features.join(", "));
         exit(0)
     }
 
-    let file = cli
-        .directory
-        .as_ref()
-        .unwrap_or(&std::env::current_dir().unwrap())
-        .join(cli.config)
-        .to_string_lossy()
-        .to_string();
-
-    if !Path::new(&file).exists() {
-        let default_config = "port: 7890";
-        let mut config_file = match std::fs::File::create(&file) {
-            Ok(config_file) => config_file,
-            _ => {
-                eprintln!("default profile cannot be created: {}", file);
+    if let Some(Command::EncryptSecret { key_file }) = &cli.command {
+        let mut value = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut value) {
+            eprintln!("failed to read the value from stdin: {}", e);
+            exit(1);
+        }
+        let value = value.trim_end_matches(['\r', '\n']);
+        match clash::encrypt_secret(key_file.as_deref(), value) {
+            Ok(encrypted) => {
+                println!("{}", encrypted);
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("failed to encrypt: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(Command::OpenUrl {
+        url,
+        controller,
+        secret,
+    }) = &cli.command
+    {
+        match clash::open_url(controller, url, secret.as_deref()) {
+            Ok(response) => {
+                println!("{}", response);
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("failed to open {}: {}", url, e);
                 exit(1);
             }
+        }
+    }
+
+    if let Some(Command::Ctl {
+        command,
+        controller,
+        secret,
+    }) = &cli.command
+    {
+        let command = match command {
+            CtlCommand::Providers {
+                command: ProvidersCommand::Update { name },
+            } => clash::CtlCommand::UpdateProvider(name.clone()),
+            CtlCommand::Proxies {
+                command: ProxiesCommand::Check { group },
+            } => clash::CtlCommand::CheckGroup(group.clone()),
         };
+        match clash::ctl(controller, &command, secret.as_deref()) {
+            Ok(response) => {
+                println!("{}", response);
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("failed to request {}: {}", controller, e);
+                exit(1);
+            }
+        }
+    }
 
-        if config_file.write_all(default_config.as_bytes()).is_err() {
-            eprintln!("default profile cannot be written: {}", file);
-            exit(1);
+    if let Some(Command::CompileRuleset {
+        input,
+        behavior,
+        output,
+    }) = &cli.command
+    {
+        let behavior = match behavior {
+            RulesetBehavior::Domain => clash::RuleSetBehavior::Domain,
+            RulesetBehavior::Ipcidr => clash::RuleSetBehavior::Ipcidr,
         };
+        let compiled = std::fs::read(input)
+            .map_err(clash::Error::from)
+            .and_then(|x| clash::compile_ruleset(behavior, &x));
+        match compiled.and_then(|x| Ok(std::fs::write(output, x)?)) {
+            Ok(()) => exit(0),
+            Err(e) => {
+                eprintln!("failed to compile {}: {}", input.display(), e);
+                exit(1);
+            }
+        }
+    }
 
-        println!(
-            "the configuration file cannot be found, the template has been created \
-             and used: {}",
-            file
-        );
+    if let Some(Command::ConvertProvider { source, out }) = cli.command {
+        match clash::convert_provider(&source) {
+            Ok(conversion) => {
+                for skipped in conversion.skipped {
+                    eprintln!("skipped: {}", skipped);
+                }
+                match out {
+                    OutputFormat::Yaml => print!("{}", conversion.yaml),
+                }
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("failed to convert {}: {}", source, e);
+                exit(1);
+            }
+        }
     }
 
+    let cwd = cli
+        .directory
+        .clone()
+        .unwrap_or_else(|| std::env::current_dir().unwrap());
+    let (config, file) =
+        match cli.config.to_str().filter(|x| clash::is_config_url(x)) {
+            Some(url) => (
+                remote_config(url, cli.config_sha256.as_deref(), &cwd),
+                url.to_owned(),
+            ),
+            None => {
+                let file = cwd.join(&cli.config).to_string_lossy().to_string();
+                create_default_config(&file);
+                (clash::Config::File(file.clone()), file)
+            }
+        };
+
     if cli.test_config {
-        match clash::Config::File(file.clone()).try_parse() {
-            Ok(_) => {
+        let cwd = cli
+            .directory
+            .as_ref()
+            .map(|x| x.to_string_lossy().to_string());
+        match clash::test_config(config, cwd) {
+            Ok(report) => {
+                print!("{}", report);
+                if report.has_errors() {
+                    eprintln!("configuration file {} test failed", file);
+                    exit(1);
+                }
                 println!("configuration file {} test is successful", file);
                 exit(0);
             }
@@ -147,10 +365,11 @@ fn main() {
     }
 
     match clash::start_scaffold(clash::Options {
-        config: clash::Config::File(file),
+        config,
         cwd: cli.directory.map(|x| x.to_string_lossy().to_string()),
         rt: Some(TokioRuntime::MultiThread),
         log_file: cli.log_file,
+        hooks: vec![],
     }) {
         Ok(_) => {}
         Err(_) => {
@@ -158,3 +377,82 @@ fn main() {
         }
     }
 }
+
+/// `stats.allocated` and `stats.resident` of jemalloc for `/memory`
+#[cfg(all(feature = "jemallocator", not(feature = "dhat-heap")))]
+fn jemalloc_stats() -> Option<clash::AllocatorStats> {
+    use std::{
+        ffi::{CStr, c_void},
+        ptr,
+    };
+    use tikv_jemalloc_sys::mallctl;
+
+    // the stats are a snapshot refreshed by advancing the epoch
+    let mut epoch: u64 = 1;
+    let mut len = size_of::<u64>();
+    let epoch_ptr = &mut epoch as *mut u64 as *mut c_void;
+    // SAFETY: `epoch` is the u64 both read and written, of `len` bytes
+    unsafe { mallctl(c"epoch".as_ptr(), epoch_ptr, &mut len, epoch_ptr, len) };
+
+    let read = |name: &CStr| {
+        let mut value = 0usize;
+        let mut len = size_of::<usize>();
+        // SAFETY: the stats are size_t, of the size of `value`
+        let rv = unsafe {
+            mallctl(
+                name.as_ptr(),
+                &mut value as *mut usize as *mut c_void,
+                &mut len,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        (rv == 0).then_some(value)
+    };
+    Some(clash::AllocatorStats {
+        allocated: read(c"stats.allocated")?,
+        resident: read(c"stats.resident")?,
+    })
+}
+
+/// the config downloaded from `url`, or its cached copy, exits if neither
+fn remote_config(url: &str, sha256: Option<&str>, cwd: &Path) -> clash::Config {
+    match clash::load_remote_config(url, sha256, cwd) {
+        Ok(remote) => {
+            if let Some(stale) = remote.stale {
+                eprintln!("{}, starting from the cached copy", stale);
+            }
+            clash::Config::Str(remote.content)
+        }
+        Err(e) => {
+            eprintln!("failed to load the configuration from {}: {}", url, e);
+            exit(1);
+        }
+    }
+}
+
+/// creates a minimal config at `file` if there's none
+fn create_default_config(file: &str) {
+    if Path::new(file).exists() {
+        return;
+    }
+    let default_config = "port: 7890";
+    let mut config_file = match std::fs::File::create(file) {
+        Ok(config_file) => config_file,
+        _ => {
+            eprintln!("default profile cannot be created: {}", file);
+            exit(1);
+        }
+    };
+
+    if config_file.write_all(default_config.as_bytes()).is_err() {
+        eprintln!("default profile cannot be written: {}", file);
+        exit(1);
+    };
+
+    println!(
+        "the configuration file cannot be found, the template has been created and \
+         used: {}",
+        file
+    );
+}
@@ -1,3 +1,8 @@
+#[cfg(feature = "uniffi")]
+mod mobile;
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
+
 use clash_lib::{Config, Options, TokioRuntime, shutdown, start_scaffold};
 use std::{
     ffi::{CStr, CString},
@@ -32,6 +37,7 @@ pub unsafe extern "C" fn clash_start(
             cwd: Some(cwd_str),
             rt,
             log_file: Some(log_str),
+            hooks: vec![],
         };
 
         match start_scaffold(options) {
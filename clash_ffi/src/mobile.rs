@@ -0,0 +1,178 @@
+//! Bindings for the Android and iOS apps embedding clash, generated with
+//! `uniffi-bindgen` from the built library, e.g.
+//! `uniffi-bindgen generate --library libclashrs.so --language kotlin`
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use clash_lib::{Config, ConnectionHandle, Runtime};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, uniffi::Error)]
+pub enum ClashError {
+    Failed { message: String },
+}
+
+impl fmt::Display for ClashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failed { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ClashError {}
+
+impl From<clash_lib::Error> for ClashError {
+    fn from(e: clash_lib::Error) -> Self {
+        Self::Failed {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for ClashError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Failed {
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Bytes per second through all the connections
+#[derive(uniffi::Record)]
+pub struct Traffic {
+    pub up: u64,
+    pub down: u64,
+}
+
+#[uniffi::export(callback_interface)]
+pub trait TrafficListener: Send + Sync {
+    fn on_traffic(&self, traffic: Traffic);
+}
+
+/// `VpnService.protect()` on Android
+#[uniffi::export(callback_interface)]
+pub trait SocketProtector: Send + Sync {
+    /// false fails the connection of the socket
+    fn protect(&self, fd: i32) -> bool;
+}
+
+/// Has each outbound socket protected before it's connected, so that it
+/// doesn't loop back into the TUN of the VPN. Set it before starting clash
+#[uniffi::export]
+pub fn set_socket_protector(protector: Option<Box<dyn SocketProtector>>) {
+    clash_lib::set_socket_protector(protector.map(|protector| {
+        Arc::new(move |fd| protector.protect(fd)) as clash_lib::SocketProtector
+    }));
+}
+
+/// clash running on its own threads, in the process of the app
+#[derive(uniffi::Object)]
+pub struct ClashRuntime {
+    /// only taken on drop
+    rt: Option<tokio::runtime::Runtime>,
+    inner: Mutex<Option<Runtime>>,
+    watchers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[uniffi::export]
+impl ClashRuntime {
+    /// Starts the YAML `config`, with the paths in it relative to `cwd`
+    #[uniffi::constructor]
+    pub fn start(config: String, cwd: String) -> Result<Arc<Self>, ClashError> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let inner =
+            rt.block_on(Runtime::start(Config::Str(config), Some(cwd), vec![]))?;
+        Ok(Arc::new(Self {
+            rt: Some(rt),
+            inner: Mutex::new(Some(inner)),
+            watchers: Mutex::new(vec![]),
+        }))
+    }
+
+    /// Applies the YAML `config`, keeping the connections
+    pub fn reload(&self, config: String) -> Result<(), ClashError> {
+        let inner = self.inner.lock().unwrap();
+        let inner = inner.as_ref().ok_or_else(stopped)?;
+        self.rt().block_on(inner.reload(Config::Str(config)))?;
+        Ok(())
+    }
+
+    /// Stops clash, after which the other methods fail
+    pub fn stop(&self) -> Result<(), ClashError> {
+        for watcher in self.watchers.lock().unwrap().drain(..) {
+            watcher.abort();
+        }
+        let inner = self.inner.lock().unwrap().take().ok_or_else(stopped)?;
+        self.rt().block_on(inner.stop())?;
+        Ok(())
+    }
+
+    pub fn traffic(&self) -> Result<Traffic, ClashError> {
+        let (up, down) = self.connections()?.traffic();
+        Ok(Traffic { up, down })
+    }
+
+    /// Calls `listener` with the traffic every `interval_ms` until clash stops
+    pub fn watch_traffic(
+        &self,
+        listener: Box<dyn TrafficListener>,
+        interval_ms: u64,
+    ) -> Result<(), ClashError> {
+        let connections = self.connections()?;
+        let interval = Duration::from_millis(interval_ms.max(100));
+        let watcher = self.rt().spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let (up, down) = connections.traffic();
+                listener.on_traffic(Traffic { up, down });
+            }
+        });
+        self.watchers.lock().unwrap().push(watcher);
+        Ok(())
+    }
+}
+
+impl Drop for ClashRuntime {
+    /// The last reference may be dropped on a thread of a runtime, which
+    /// can't block on the stop nor drop `rt`, both are left to a thread
+    fn drop(&mut self) {
+        let inner = self
+            .inner
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let Some(rt) = self.rt.take() else {
+            return;
+        };
+        std::thread::spawn(move || {
+            if let Some(inner) = inner {
+                let _ = rt.block_on(inner.stop());
+            }
+        });
+    }
+}
+
+impl ClashRuntime {
+    fn rt(&self) -> &tokio::runtime::Runtime {
+        self.rt.as_ref().expect("only taken on drop")
+    }
+
+    fn connections(&self) -> Result<ConnectionHandle, ClashError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.as_ref().ok_or_else(stopped)?.connections())
+    }
+}
+
+fn stopped() -> ClashError {
+    ClashError::Failed {
+        message: "clash is stopped".to_owned(),
+    }
+}
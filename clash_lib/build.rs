@@ -1,3 +1,6 @@
+#![feature(let_chains)]
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() -> std::io::Result<()> {
     println!("cargo::rustc-check-cfg=cfg(docker_test)");
     println!("cargo:rerun-if-env-changed=CLASH_DOCKER_TEST");
@@ -5,9 +8,72 @@ fn main() -> std::io::Result<()> {
         println!("cargo::rustc-cfg=docker_test");
     }
 
+    build_info();
+
     println!("cargo:rerun-if-changed=src/common/geodata/geodata.proto");
     prost_build::compile_protos(
         &["src/common/geodata/geodata.proto"],
         &["src/common/geodata"],
     )
 }
+
+/// the commit and the date of the build, for `common::build_info`
+fn build_info() {
+    println!("cargo:rerun-if-env-changed=CLASH_GIT_SHA");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // a new commit changes the ref HEAD points to
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string("../.git/HEAD")
+        && let Some(r) = head.trim().strip_prefix("ref: ")
+    {
+        println!("cargo:rerun-if-changed=../.git/{}", r);
+    }
+
+    let sha = std::env::var("CLASH_GIT_SHA")
+        .ok()
+        .or_else(|| {
+            let out = std::process::Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()?;
+            out.status
+                .success()
+                .then(|| String::from_utf8_lossy(&out.stdout).trim().to_owned())
+        })
+        .unwrap_or_default();
+    println!(
+        "cargo:rustc-env=CLASH_BUILD_SHA={}",
+        &sha[..sha.len().min(7)]
+    );
+
+    // reproducible builds set the date
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default()
+        });
+    let (y, m, d) = civil_from_days((secs / 86400) as i64);
+    println!(
+        "cargo:rustc-env=CLASH_BUILD_DATE={:04}-{:02}-{:02}",
+        y, m, d
+    );
+}
+
+/// the date of the days since 1970-01-01, after Howard Hinnant's
+/// `civil_from_days`
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use axum::{Json, Router, extract::State, response::IntoResponse, routing::get};
+use http::StatusCode;
+
+use crate::app::{
+    api::AppState,
+    dispatcher::{CaptureManager, CaptureSettings},
+};
+
+#[derive(Clone)]
+struct CaptureState {
+    capture_manager: Arc<CaptureManager>,
+}
+
+pub fn routes(capture_manager: Arc<CaptureManager>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_capture).put(update_capture))
+        .with_state(CaptureState { capture_manager })
+}
+
+async fn get_capture(State(state): State<CaptureState>) -> impl IntoResponse {
+    Json(state.capture_manager.settings())
+}
+
+/// replaces the settings, `{"all": false, "rules": []}` stops capturing
+/// new connections
+async fn update_capture(
+    State(state): State<CaptureState>,
+    Json(payload): Json<CaptureSettings>,
+) -> impl IntoResponse {
+    match state.capture_manager.set(payload) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
@@ -1,4 +1,7 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use axum::{
     Json, Router,
@@ -19,6 +22,8 @@ use crate::{
         dispatcher,
         dns::ThreadSafeDNSResolver,
         inbound::manager::{InboundManager, Ports},
+        logging,
+        remote_config::{self, RemoteConfig},
     },
     config::{def, internal::config::BindAddress},
 };
@@ -68,15 +73,18 @@ async fn get_configs(State(state): State<ConfigState>) -> impl IntoResponse {
         mode: Some(run_mode),
         log_level: Some(global_state.log_level),
         ipv6: Some(dns_resolver.ipv6()),
-        allow_lan: Some(state.inbound_manager.get_bind_address().0.is_unspecified()),
+        allow_lan: Some(state.inbound_manager.get_allow_lan().await),
     })
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct UpdateConfigRequest {
+    /// a file, or an http(s) URL to download the config from
     path: Option<String>,
     payload: Option<String>,
+    /// the checksum in hex the config downloaded must have
+    sha256: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -90,8 +98,25 @@ async fn update_configs(
     Json(req): Json<UpdateConfigRequest>,
 ) -> impl IntoResponse {
     let (done, wait) = tokio::sync::oneshot::channel();
+    // a config at a URL is downloaded again, its cached copy is no update
+    let (path, payload) = match (req.path, req.payload) {
+        (Some(url), None) if remote_config::is_url(&url) => {
+            let cwd = state.global_state.lock().await.cwd.clone();
+            let sha256 = req.sha256.as_deref();
+            match remote_config::load(&url, sha256, Path::new(&cwd)).await {
+                Ok(RemoteConfig {
+                    content,
+                    stale: None,
+                }) => (None, Some(content)),
+                Ok(RemoteConfig { stale: Some(e), .. }) | Err(e) => {
+                    return (StatusCode::BAD_GATEWAY, e).into_response();
+                }
+            }
+        }
+        x => x,
+    };
     let g = state.global_state.lock().await;
-    match (req.path, req.payload) {
+    match (path, payload) {
         (_, Some(payload)) => {
             let msg = "config reloading from payload".to_string();
             let cfg = crate::Config::Str(payload);
@@ -166,6 +191,7 @@ impl PatchConfigRequest {
             || self.tproxy_port.is_some()
             || self.mixed_port.is_some()
             || self.bind_address.is_some()
+            || self.allow_lan.is_some()
     }
 }
 
@@ -173,13 +199,6 @@ async fn patch_configs(
     State(state): State<ConfigState>,
     Json(payload): Json<PatchConfigRequest>,
 ) -> impl IntoResponse {
-    if payload.allow_lan.is_some() {
-        warn!(
-            "setting allow_lan doesn't do anything. please set bind_address to a \
-             LAN address instead."
-        );
-    }
-
     let inbound_manager = state.inbound_manager.clone();
     let mut need_restart = false;
     if let Some(bind_address) = payload.bind_address.clone() {
@@ -200,6 +219,16 @@ async fn patch_configs(
 
     let mut global_state = state.global_state.lock().await;
 
+    if let Some(allow_lan) = payload.allow_lan {
+        if allow_lan && inbound_manager.get_bind_address().0.is_loopback() {
+            warn!(
+                "allow-lan is set to true, but bind-address is set to localhost. \
+                 This will not allow any connections from the local network."
+            );
+        }
+        inbound_manager.set_allow_lan(allow_lan).await;
+    }
+
     if payload.rebuild_listeners() {
         // TODO: maybe buggy
         let current_ports = inbound_manager.get_ports().await;
@@ -223,6 +252,10 @@ async fn patch_configs(
     }
 
     if let Some(log_level) = payload.log_level {
+        // not when embedded with the logging set up by the host
+        if let Err(e) = logging::set_log_level(log_level) {
+            warn!("{}", e);
+        }
         global_state.log_level = log_level;
     }
 
@@ -26,6 +26,7 @@ struct ConnectionState {
 pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(get_connections).delete(close_all_connection))
+        .route("/closed", get(get_closed_connections))
         .route("/{id}", delete(close_connection))
         .with_state(ConnectionState { statistics_manager })
 }
@@ -35,6 +36,12 @@ struct GetConnectionsQuery {
     interval: Option<u64>,
 }
 
+async fn get_closed_connections(
+    State(state): State<ConnectionState>,
+) -> impl IntoResponse {
+    Json(state.statistics_manager.closed_snapshot().await)
+}
+
 async fn get_connections(
     headers: HeaderMap,
     State(state): State<ConnectionState>,
@@ -62,9 +69,10 @@ async fn get_connections(
         let interval = q.interval;
 
         let mgr = state.statistics_manager.clone();
+        let mut since = chrono::Utc::now();
 
         loop {
-            let snapshot = mgr.snapshot().await;
+            let snapshot = mgr.snapshot_since(&mut since).await;
             let j = serde_json::to_vec(&snapshot).unwrap();
             let body = String::from_utf8(j).unwrap();
 
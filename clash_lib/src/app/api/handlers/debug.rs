@@ -0,0 +1,49 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{Json, Router, extract::State, response::IntoResponse, routing::get};
+use serde::Serialize;
+
+use crate::{
+    app::{api::AppState, router::ThreadSafeRouter},
+    common::trie::TrieStats,
+};
+
+#[derive(Clone)]
+struct DebugState {
+    router: ThreadSafeRouter,
+}
+
+#[derive(Serialize)]
+struct TriesResponse {
+    tries: Vec<HashMap<String, Box<dyn erased_serde::Serialize + Send>>>,
+    total: TrieStats,
+}
+
+pub fn routes(router: ThreadSafeRouter) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/tries", get(get_tries))
+        .with_state(DebugState { router })
+}
+
+/// the domain tries behind the rules, with their node count and approximate
+/// bytes, the ones `memory-profile: low` compacts
+async fn get_tries(State(state): State<DebugState>) -> impl IntoResponse {
+    let mut total = TrieStats::default();
+    let tries = state
+        .router
+        .get_all_rules()
+        .iter()
+        .filter_map(|r| {
+            let stats = r.trie_stats()?;
+            total.nodes += stats.nodes;
+            total.bytes += stats.bytes;
+
+            let mut m = r.as_map();
+            m.insert("nodes".to_string(), Box::new(stats.nodes));
+            m.insert("bytes".to_string(), Box::new(stats.bytes));
+            Some(m)
+        })
+        .collect::<Vec<_>>();
+
+    Json(TriesResponse { tries, total })
+}
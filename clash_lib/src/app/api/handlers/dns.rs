@@ -11,21 +11,44 @@ use http::StatusCode;
 use serde::Deserialize;
 use serde_json::{Map, Value};
 
-use crate::app::{api::AppState, dns::ThreadSafeDNSResolver};
+use crate::app::{
+    api::AppState, dns::ThreadSafeDNSResolver,
+    outbound::manager::ThreadSafeOutboundManager,
+};
 
 #[derive(Clone)]
 struct DNSState {
-    #[allow(dead_code)]
     resolver: ThreadSafeDNSResolver,
+    outbound_manager: ThreadSafeOutboundManager,
 }
 
-pub fn routes(resolver: ThreadSafeDNSResolver) -> Router<Arc<AppState>> {
-    let state = DNSState { resolver };
+pub fn routes(
+    resolver: ThreadSafeDNSResolver,
+    outbound_manager: ThreadSafeOutboundManager,
+) -> Router<Arc<AppState>> {
+    let state = DNSState {
+        resolver,
+        outbound_manager,
+    };
     Router::new()
         .route("/query", get(query_dns))
+        .route("/proxy-servers", get(proxy_servers))
+        .route("/upstreams", get(upstreams))
         .with_state(state)
 }
 
+/// the addresses the proxy servers resolved to last, and the errors of those
+/// that failed
+async fn proxy_servers(State(state): State<DNSState>) -> impl IntoResponse {
+    Json(state.outbound_manager.server_addrs())
+}
+
+/// the probes of the nameservers, those failing consistently are left out
+/// of the queries while the others answer
+async fn upstreams(State(state): State<DNSState>) -> impl IntoResponse {
+    Json(state.resolver.upstreams())
+}
+
 #[derive(Deserialize)]
 struct DnsQUery {
     name: String,
@@ -0,0 +1,54 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    Router,
+    extract::{ConnectInfo, State, WebSocketUpgrade, ws::Message},
+    response::IntoResponse,
+    routing::get,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::app::{api::AppState, outbound::manager::ThreadSafeOutboundManager};
+
+#[derive(Clone)]
+struct EventsState {
+    outbound_manager: ThreadSafeOutboundManager,
+}
+
+pub fn routes(outbound_manager: ThreadSafeOutboundManager) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(handle))
+        .with_state(EventsState { outbound_manager })
+}
+
+/// a websocket of the proxies going down, recovering and changing delay, e.g.
+/// `{"type": "ProxyWentDown", "proxy": "ss"}` or
+/// `{"type": "DelayChanged", "proxy": "ss", "delay": 120}`
+async fn handle(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<EventsState>,
+) -> impl IntoResponse {
+    let mut events = state.outbound_manager.subscribe_events();
+    ws.on_failed_upgrade(move |e| {
+        warn!("ws upgrade error: {} with {}", e, addr);
+    })
+    .on_upgrade(move |mut socket| async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    warn!("{} missed {} proxy events", addr, n);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let j = serde_json::to_string(&event).unwrap();
+            if let Err(e) = socket.send(Message::Text(j.into())).await {
+                warn!("ws send error: {}", e);
+                break;
+            }
+        }
+    })
+}
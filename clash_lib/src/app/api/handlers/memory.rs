@@ -10,7 +10,10 @@ use http::HeaderMap;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
 
-use crate::app::api::AppState;
+use crate::{
+    app::{api::AppState, dispatcher::StatisticsManager},
+    common::memory::{self, AllocatorStats},
+};
 
 use super::utils::is_request_websocket;
 
@@ -20,10 +23,30 @@ pub struct GetMemoryQuery {
 }
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 struct GetMemoryResponse {
+    /// resident bytes of the process
     inuse: usize,
+    /// of its cgroup, 0 without one
     oslimit: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allocator: Option<AllocatorStats>,
+    /// the biggest of the caches growing with the traffic
+    connections: usize,
+    closed_connections: usize,
+}
+
+async fn memory_snapshot(mgr: &StatisticsManager) -> GetMemoryResponse {
+    let (connections, closed_connections) = mgr.connection_counts().await;
+    GetMemoryResponse {
+        inuse: mgr.memory_usage(),
+        oslimit: memory::os_limit(),
+        allocator: memory::allocator_stats(),
+        connections,
+        closed_connections,
+    }
 }
+
 pub async fn handle(
     headers: HeaderMap,
     State(state): State<Arc<AppState>>,
@@ -31,11 +54,7 @@ pub async fn handle(
     req: Request<Body>,
 ) -> impl IntoResponse {
     if !is_request_websocket(headers) {
-        let mgr = state.statistics_manager.clone();
-        let snapshot = GetMemoryResponse {
-            inuse: mgr.memory_usage(),
-            oslimit: 0,
-        };
+        let snapshot = memory_snapshot(&state.statistics_manager).await;
         return Json(snapshot).into_response();
     }
 
@@ -56,10 +75,7 @@ pub async fn handle(
         let mgr = state.statistics_manager.clone();
 
         loop {
-            let snapshot = GetMemoryResponse {
-                inuse: mgr.memory_usage(),
-                oslimit: 0,
-            };
+            let snapshot = memory_snapshot(&mgr).await;
             let j = serde_json::to_vec(&snapshot).unwrap();
             let body = String::from_utf8(j).unwrap();
 
@@ -1,6 +1,9 @@
+pub mod capture;
 pub mod config;
 pub mod connection;
+pub mod debug;
 pub mod dns;
+pub mod events;
 pub mod hello;
 pub mod log;
 pub mod memory;
@@ -8,6 +11,8 @@ pub mod provider;
 pub mod proxy;
 pub mod restart;
 pub mod rule;
+pub mod statistics;
+pub mod status;
 pub mod traffic;
 pub mod version;
 
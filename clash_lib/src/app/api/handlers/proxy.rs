@@ -6,7 +6,7 @@ use axum::{
     http::Request,
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
 };
 
 use http::{HeaderMap, StatusCode, header};
@@ -41,6 +41,9 @@ pub fn routes(
             Router::new()
                 .route("/", get(get_proxy).put(update_proxy))
                 .route("/delay", get(get_proxy_delay))
+                .route("/speedtest", get(get_proxy_speed))
+                .route("/select", post(select_proxy))
+                .route("/healthcheck", get(group_healthcheck))
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
                     find_proxy_by_name,
@@ -94,6 +97,25 @@ async fn update_proxy(
     Extension(proxy): Extension<AnyOutboundHandler>,
     Json(payload): Json<UpdateProxyRequest>,
 ) -> impl IntoResponse {
+    select(state, proxy, payload).await
+}
+
+/// the same as `PUT /proxies/{name}`, with the name in the query for scripts
+/// and the `clash://` URL scheme handler. Not a GET, so a link or an image
+/// on a web page can't switch the selection
+async fn select_proxy(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+    Query(q): Query<UpdateProxyRequest>,
+) -> impl IntoResponse {
+    select(state, proxy, q).await
+}
+
+async fn select(
+    state: ProxyState,
+    proxy: AnyOutboundHandler,
+    payload: UpdateProxyRequest,
+) -> (StatusCode, String) {
     let outbound_manager = state.outbound_manager.clone();
     match outbound_manager.get_selector_control(proxy.name()) {
         Some(ctrl) => match ctrl.lock().await.select(&payload.name).await {
@@ -152,3 +174,70 @@ async fn get_proxy_delay(
             .into_response(),
     }
 }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct SpeedRequest {
+    url: String,
+    /// bytes
+    max_bytes: Option<u64>,
+    /// ms
+    timeout: Option<u64>,
+}
+
+/// 10 MiB, as the bandwidth checks of the providers
+const SPEED_TEST_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const SPEED_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// the most a request can ask for, as each test spends the traffic of the
+/// proxy's plan
+const SPEED_TEST_MAX_BYTES_LIMIT: u64 = 100 * 1024 * 1024;
+const SPEED_TEST_TIMEOUT_LIMIT: Duration = Duration::from_secs(60);
+
+/// downloads from `url` through the proxy, returns its bandwidth, also
+/// shown as `bandwidth` in `GET /proxies/{name}` afterwards. At most 100 MiB
+/// for 60 seconds, and not with a read-only token
+async fn get_proxy_speed(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+    Query(q): Query<SpeedRequest>,
+) -> impl IntoResponse {
+    let outbound_manager = state.outbound_manager.clone();
+    let max_bytes = q
+        .max_bytes
+        .unwrap_or(SPEED_TEST_MAX_BYTES)
+        .min(SPEED_TEST_MAX_BYTES_LIMIT);
+    let timeout = q
+        .timeout
+        .map(Duration::from_millis)
+        .unwrap_or(SPEED_TEST_TIMEOUT)
+        .min(SPEED_TEST_TIMEOUT_LIMIT);
+    let n = proxy.name().to_owned();
+    match outbound_manager
+        .speed_test(proxy, &q.url, max_bytes, timeout)
+        .await
+    {
+        Ok(bandwidth) => axum::response::Json(bandwidth).into_response(),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            format!("speed test for {} failed with error: {}", n, err),
+        )
+            .into_response(),
+    }
+}
+
+/// health-checks the providers of a group, returns the delays of the proxies
+/// alive, for `clash-rs ctl proxies check`
+async fn group_healthcheck(
+    State(state): State<ProxyState>,
+    Extension(proxy): Extension<AnyOutboundHandler>,
+) -> impl IntoResponse {
+    let outbound_manager = state.outbound_manager.clone();
+    match outbound_manager.healthcheck_group(proxy.name()).await {
+        Some(delays) => axum::response::Json(delays).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("proxy {} is not a group", proxy.name()),
+        )
+            .into_response(),
+    }
+}
@@ -1,26 +1,114 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
 
-use axum::{Router, extract::State, response::IntoResponse, routing::get};
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{delete, get},
+};
+use http::StatusCode;
+use serde::Deserialize;
+use tokio::time::Instant;
 
-use crate::app::{api::AppState, router::ThreadSafeRouter};
+use crate::app::{
+    api::AppState, outbound::manager::ThreadSafeOutboundManager,
+    router::ThreadSafeRouter,
+};
 
 #[derive(Clone)]
 struct RuleState {
     router: ThreadSafeRouter,
+    outbound_manager: ThreadSafeOutboundManager,
 }
 
-pub fn routes(router: ThreadSafeRouter) -> Router<Arc<AppState>> {
+pub fn routes(
+    router: ThreadSafeRouter,
+    outbound_manager: ThreadSafeOutboundManager,
+) -> Router<Arc<AppState>> {
     Router::new()
-        .route("/", get(get_rules))
-        .with_state(RuleState { router })
+        .route("/", get(get_rules).post(add_rule).delete(clear_temp_rules))
+        .route("/{id}", delete(delete_rule))
+        .with_state(RuleState {
+            router,
+            outbound_manager,
+        })
 }
 
 async fn get_rules(State(state): State<RuleState>) -> impl IntoResponse {
+    let now = Instant::now();
+    let temp_rules = state.router.get_temp_rules();
     let rules = state.router.get_all_rules();
+    let hits = state.router.get_rule_hits();
     let mut r = HashMap::new();
     r.insert(
         "rules",
-        rules.iter().map(|r| r.as_map()).collect::<Vec<_>>(),
+        temp_rules
+            .iter()
+            .map(|t| {
+                let mut m = t.rule.as_map();
+                m.insert("id".to_string(), Box::new(t.id.to_string()));
+                m.insert(
+                    "ttl".to_string(),
+                    Box::new(t.expire.map(|e| (e - now).as_secs())),
+                );
+                m.insert(
+                    "hits".to_string(),
+                    Box::new(t.hits.load(Ordering::Relaxed)),
+                );
+                m
+            })
+            .chain(rules.iter().zip(hits).map(|(r, hits)| {
+                let mut m = r.as_map();
+                m.insert("hits".to_string(), Box::new(hits));
+                m
+            }))
+            .collect::<Vec<_>>(),
     );
     axum::response::Json(r)
 }
+
+#[derive(Deserialize)]
+struct AddRuleRequest {
+    /// same syntax as in the config, e.g. `DOMAIN,example.com,DIRECT`
+    rule: String,
+    /// seconds before the rule is removed, never if not set
+    ttl: Option<u64>,
+}
+
+async fn add_rule(
+    State(state): State<RuleState>,
+    Json(payload): Json<AddRuleRequest>,
+) -> impl IntoResponse {
+    match state.router.add_temp_rule(
+        &payload.rule,
+        payload.ttl.map(Duration::from_secs),
+        |target| state.outbound_manager.get_outbound(target).is_some(),
+    ) {
+        Ok(id) => {
+            let mut r = HashMap::new();
+            r.insert("id", id.to_string());
+            (StatusCode::CREATED, Json(r)).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+async fn delete_rule(
+    State(state): State<RuleState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    if state.router.remove_temp_rule(id) {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, format!("rule {} not found", id)).into_response()
+    }
+}
+
+async fn clear_temp_rules(State(state): State<RuleState>) -> impl IntoResponse {
+    state.router.clear_temp_rules();
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    response::IntoResponse,
+    routing::get,
+};
+use http::header;
+use serde::Deserialize;
+
+use crate::app::{api::AppState, dispatcher::StatisticsManager};
+
+#[derive(Clone)]
+struct StatisticsState {
+    statistics_manager: Arc<StatisticsManager>,
+}
+
+pub fn routes(statistics_manager: Arc<StatisticsManager>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_statistics))
+        .with_state(StatisticsState { statistics_manager })
+}
+
+#[derive(Deserialize)]
+struct GetStatisticsQuery {
+    /// `csv` for a spreadsheet, JSON otherwise
+    format: Option<String>,
+}
+
+/// the bytes through each proxy and each rule by day and by month, e.g.
+/// `{"days": {"2024-01-31": {"proxies": {"ss": {"upload": 1, "download": 2}},
+/// "rules": {"Match": {...}}}}, "months": {"2024-01": {...}}}`
+async fn get_statistics(
+    State(state): State<StatisticsState>,
+    Query(q): Query<GetStatisticsQuery>,
+) -> impl IntoResponse {
+    let usage = state.statistics_manager.usage();
+    if q.format.as_deref() == Some("csv") {
+        return ([(header::CONTENT_TYPE, "text/csv")], usage.to_csv())
+            .into_response();
+    }
+    Json(usage).into_response()
+}
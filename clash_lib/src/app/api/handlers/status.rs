@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use axum::{Json, Router, extract::State, response::IntoResponse, routing::get};
+use serde::Serialize;
+
+use crate::app::{
+    api::AppState,
+    lifecycle::{ComponentStatus, Lifecycle},
+};
+
+#[derive(Clone)]
+struct StatusState {
+    lifecycle: Arc<Lifecycle>,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    components: Vec<ComponentStatus>,
+}
+
+pub fn routes(lifecycle: Arc<Lifecycle>) -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/", get(get_status))
+        .with_state(StatusState { lifecycle })
+}
+
+/// the components in startup order, e.g.
+/// `{"components": [{"name": "dns", "state": "running"},
+/// {"name": "tun", "state": "failed", "reason": "..."}]}`
+async fn get_status(State(state): State<StatusState>) -> impl IntoResponse {
+    Json(StatusResponse {
+        components: state.lifecycle.status(),
+    })
+}
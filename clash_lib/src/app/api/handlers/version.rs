@@ -1,11 +1,8 @@
-use std::collections::HashMap;
-
 use axum::response::IntoResponse;
 
-const VERSION: &str = env!("CARGO_PKG_VERSION");
+use crate::common::build_info::build_info;
 
+/// `version` as in clash, and what the build was made from
 pub async fn handle() -> impl IntoResponse {
-    let mut val = HashMap::new();
-    val.insert("version".to_owned(), VERSION.to_owned());
-    axum::response::Json(val)
+    axum::response::Json(build_info())
 }
@@ -1,22 +1,98 @@
-use axum::{body::Body, extract::Query, http::Request, response::Response};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, OriginalUri, Query},
+    http::Request,
+    response::Response,
+};
 use futures::future::BoxFuture;
+use http::{Method, StatusCode, Uri, uri::Authority};
 
 use serde::Deserialize;
 use tower::{Layer, Service};
 
+use crate::config::def::{ControllerScope, ControllerToken};
+
 #[derive(Debug, Clone, Deserialize)]
 struct AuthQuery {
     token: String,
 }
 
+#[derive(Debug)]
+struct Token {
+    token: String,
+    scope: ControllerScope,
+    limiter: Option<RateLimiter>,
+}
+
+/// A token bucket refilled at `rate` per second, holding at most `rate`
+#[derive(Debug)]
+struct RateLimiter {
+    rate: f64,
+    /// the tokens left at the instant
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate)
+            .min(self.rate);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AuthMiddlewareLayer {
-    pub token: String,
+    /// no auth if empty
+    tokens: Arc<Vec<Token>>,
+    local_select: bool,
 }
 
 impl AuthMiddlewareLayer {
-    pub fn new(token: String) -> Self {
-        Self { token }
+    /// `secret` is a `control` token without a limit
+    pub fn new(
+        secret: String,
+        tokens: Vec<ControllerToken>,
+        local_select: bool,
+    ) -> Self {
+        let secret = (!secret.is_empty()).then(|| Token {
+            token: secret,
+            scope: ControllerScope::Control,
+            limiter: None,
+        });
+        let tokens = secret
+            .into_iter()
+            .chain(tokens.into_iter().map(|t| Token {
+                token: t.token,
+                scope: t.scope,
+                limiter: t.rate_limit.filter(|r| *r > 0).map(RateLimiter::new),
+            }))
+            .collect();
+        Self {
+            tokens: Arc::new(tokens),
+            local_select,
+        }
     }
 }
 
@@ -24,27 +100,128 @@ impl<S> Layer<S> for AuthMiddlewareLayer {
     type Service = AuthMiddleware<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        AuthMiddleware::new(inner, self.token.clone())
+        AuthMiddleware {
+            inner,
+            tokens: self.tokens.clone(),
+            local_select: self.local_select,
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct AuthMiddleware<S> {
     inner: S,
-    token: String,
+    tokens: Arc<Vec<Token>>,
+    local_select: bool,
 }
 
 impl<S> AuthMiddleware<S> {
-    pub fn new(inner: S, token: String) -> Self {
-        Self { inner, token }
-    }
-
     fn is_websocket(&self, req: &Request<Body>) -> bool {
         req.headers()
             .get("upgrade")
             .map(|upgrade| upgrade == "websocket")
             .unwrap_or(false)
     }
+
+    /// an empty token is never valid
+    fn find(&self, token: &str) -> Option<&Token> {
+        if token.is_empty() {
+            return None;
+        }
+        self.tokens.iter().find(|t| t.token == token)
+    }
+
+    fn find_token(&self, req: &Request<Body>) -> Option<&Token> {
+        if self.is_websocket(req) {
+            let q = Query::<AuthQuery>::try_from_uri(req.uri()).ok();
+            if let Some(q) = q
+                && let Some(token) = self.find(&q.token)
+            {
+                return Some(token);
+            }
+        }
+
+        let header = req
+            .headers()
+            .get("authorization")
+            .map(|x| x.to_str().unwrap_or_default())
+            .unwrap_or_default();
+        let bearer = header.strip_prefix("Bearer ")?;
+        self.find(bearer)
+    }
+}
+
+fn path(req: &Request<Body>) -> &str {
+    req.extensions()
+        .get::<OriginalUri>()
+        .map(|x| x.0.path())
+        .unwrap_or_else(|| req.uri().path())
+}
+
+/// `/proxies/{name}/<action>`
+fn is_proxy_action(req: &Request<Body>, action: &str) -> bool {
+    path(req)
+        .strip_prefix("/proxies/")
+        .and_then(|x| x.strip_suffix(action))
+        .and_then(|x| x.strip_suffix('/'))
+        .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+}
+
+/// `POST /proxies/{group}/select`, which changes the selection
+fn is_select(req: &Request<Body>) -> bool {
+    req.method() == Method::POST && is_proxy_action(req, "select")
+}
+
+/// the `GET`s testing proxies, which send traffic through them and change
+/// their delays, e.g. `/proxies/{name}/speedtest` or
+/// `/providers/proxies/{provider}/healthcheck`
+fn is_test(req: &Request<Body>) -> bool {
+    let path = path(req);
+    (path.starts_with("/proxies/") || path.starts_with("/providers/"))
+        && ["/delay", "/healthcheck", "/speedtest"]
+            .iter()
+            .any(|x| path.trim_end_matches('/').ends_with(x))
+}
+
+fn is_loopback_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// from a local process, and not from a web page of another origin, nor
+/// one of a name rebound to 127.0.0.1, which sends its own `Host`
+fn is_local(req: &Request<Body>) -> bool {
+    let loopback = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .is_some_and(|x| x.0.ip().is_loopback());
+    let local_host = req
+        .headers()
+        .get("host")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<Authority>().ok())
+        // HTTP/2 sends it in the URI
+        .or_else(|| req.uri().authority().cloned())
+        .is_some_and(|x| is_loopback_host(x.host()));
+    let local_origin = req.headers().get("origin").is_none_or(|origin| {
+        origin
+            .to_str()
+            .ok()
+            .and_then(|x| x.parse::<Uri>().ok())
+            .is_some_and(|x| x.host().is_some_and(is_loopback_host))
+    });
+    let cross_site = req
+        .headers()
+        .get("sec-fetch-site")
+        .is_some_and(|x| x == "cross-site" || x == "same-site");
+    loopback && local_host && local_origin && !cross_site
+}
+
+fn reject(status: StatusCode, reason: &str) -> Response {
+    Response::builder()
+        .status(status)
+        .body(reason.to_string().into())
+        .unwrap()
 }
 
 impl<S> Service<Request<Body>> for AuthMiddleware<S>
@@ -64,34 +241,208 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        if self.token.is_empty() {
+        if self.tokens.is_empty() {
             return Box::pin(self.inner.call(req));
         }
 
-        let unauthorised = Response::builder()
-            .status(http::StatusCode::UNAUTHORIZED)
-            .body("unauthorized".to_string().into())
-            .unwrap();
+        let select = is_select(&req);
+        if select && self.local_select && is_local(&req) {
+            return Box::pin(self.inner.call(req));
+        }
 
-        if self.is_websocket(&req) {
-            let q = Query::<AuthQuery>::try_from_uri(req.uri()).ok();
-            if let Some(q) = q {
-                if q.token == self.token {
-                    return Box::pin(self.inner.call(req));
-                }
+        let rejected = match self.find_token(&req) {
+            None => Some(reject(StatusCode::UNAUTHORIZED, "unauthorized")),
+            Some(token)
+                if token.scope == ControllerScope::Read
+                    && (select
+                        || is_test(&req)
+                        || !matches!(*req.method(), Method::GET | Method::HEAD)) =>
+            {
+                Some(reject(StatusCode::FORBIDDEN, "read-only token"))
+            }
+            Some(token)
+                if token.limiter.as_ref().is_some_and(|l| !l.try_acquire()) =>
+            {
+                Some(reject(StatusCode::TOO_MANY_REQUESTS, "rate limited"))
             }
+            Some(_) => None,
+        };
+
+        match rejected {
+            Some(resp) => Box::pin(async move { Ok(resp) }),
+            None => Box::pin(self.inner.call(req)),
         }
+    }
+}
 
-        let header = req
-            .headers()
-            .get("authorization")
-            .map(|x| x.to_str().unwrap_or_default())
-            .unwrap_or_default();
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
 
-        if header == format!("Bearer {}", self.token) {
-            return Box::pin(self.inner.call(req));
+    use axum::{
+        body::Body, extract::ConnectInfo, http::Request, response::Response,
+    };
+    use http::{Method, StatusCode};
+    use tower::{Layer, ServiceExt, service_fn};
+
+    use crate::config::def::{ControllerScope, ControllerToken};
+
+    use super::AuthMiddlewareLayer;
+
+    #[tokio::test]
+    async fn test_token_scopes_and_rate_limit() {
+        let layer = AuthMiddlewareLayer::new(
+            "secret".to_owned(),
+            vec![ControllerToken {
+                token: "dashboard".to_owned(),
+                scope: ControllerScope::Read,
+                rate_limit: Some(2),
+            }],
+            false,
+        );
+        let svc = layer.layer(service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }));
+
+        let status = |method: Method, token: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri("/proxies/GLOBAL")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            let svc = svc.clone();
+            async move { svc.oneshot(req).await.unwrap().status() }
+        };
+
+        assert_eq!(status(Method::PUT, "secret").await, StatusCode::OK);
+        assert_eq!(status(Method::GET, "wrong").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Method::PUT, "dashboard").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(Method::GET, "dashboard").await, StatusCode::OK);
+        assert_eq!(status(Method::GET, "dashboard").await, StatusCode::OK);
+        assert_eq!(
+            status(Method::GET, "dashboard").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // the secret has no limit
+        assert_eq!(status(Method::GET, "secret").await, StatusCode::OK);
+
+        for uri in [
+            "/proxies/GLOBAL/speedtest?url=http://speed.example.com/10mb",
+            "/proxies/GLOBAL/delay?url=http://www.gstatic.com/generate_204",
+            "/proxies/auto/healthcheck",
+            "/providers/proxies/sub/healthcheck",
+            "/providers/proxies/sub/node/healthcheck",
+        ] {
+            let req = Request::builder()
+                .uri(uri)
+                .header("authorization", "Bearer dashboard")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(
+                svc.clone().oneshot(req).await.unwrap().status(),
+                StatusCode::FORBIDDEN,
+                "{}",
+                uri
+            );
         }
+    }
 
-        Box::pin(async move { Ok(unauthorised) })
+    #[tokio::test]
+    async fn test_empty_token() {
+        let layer = AuthMiddlewareLayer::new(
+            "".to_owned(),
+            vec![ControllerToken {
+                token: "".to_owned(),
+                scope: ControllerScope::Control,
+                rate_limit: None,
+            }],
+            false,
+        );
+        let svc = layer.layer(service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }));
+
+        for req in [
+            Request::builder()
+                .uri("/configs")
+                .header("authorization", "Bearer "),
+            Request::builder()
+                .uri("/logs?token=")
+                .header("upgrade", "websocket"),
+        ] {
+            let resp = svc.clone().oneshot(req.body(Body::empty()).unwrap());
+            assert_eq!(resp.await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_select() {
+        let layer = AuthMiddlewareLayer::new(
+            "secret".to_owned(),
+            vec![ControllerToken {
+                token: "dashboard".to_owned(),
+                scope: ControllerScope::Read,
+                rate_limit: None,
+            }],
+            true,
+        );
+        let svc = layer.layer(service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        }));
+
+        let request = |method: Method, peer: &str, host: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/proxies/GLOBAL/select?name=DIRECT")
+                .header("host", host)
+                .extension(ConnectInfo(peer.parse::<SocketAddr>().unwrap()))
+        };
+        let status = |peer: &str, origin: Option<&str>, token: Option<&str>| {
+            let mut req = request(Method::POST, peer, "127.0.0.1:9090");
+            if let Some(origin) = origin {
+                req = req.header("origin", origin);
+            }
+            if let Some(token) = token {
+                req = req.header("authorization", format!("Bearer {}", token));
+            }
+            let req = req.body(Body::empty()).unwrap();
+            let svc = svc.clone();
+            async move { svc.oneshot(req).await.unwrap().status() }
+        };
+
+        assert_eq!(status("127.0.0.1:1", None, None).await, StatusCode::OK);
+        assert_eq!(
+            status("[::1]:1", Some("http://localhost:9090"), None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("127.0.0.1:1", Some("https://evil.example.com"), None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status("192.168.1.2:1", None, None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // a page of a name rebound to 127.0.0.1, and a link or an image
+        for req in [
+            request(Method::POST, "127.0.0.1:1", "rebind.example.com:9090"),
+            request(Method::GET, "127.0.0.1:1", "127.0.0.1:9090"),
+        ] {
+            let resp = svc.clone().oneshot(req.body(Body::empty()).unwrap());
+            assert_eq!(resp.await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        }
+        // selecting changes state, so a read-only token can't
+        assert_eq!(
+            status("192.168.1.2:1", None, Some("dashboard")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status("192.168.1.2:1", None, Some("secret")).await,
+            StatusCode::OK
+        );
     }
 }
@@ -22,6 +22,7 @@ use super::{
     dispatcher::{self, StatisticsManager},
     dns::ThreadSafeDNSResolver,
     inbound::manager::InboundManager,
+    lifecycle::Lifecycle,
     logging::LogEvent,
     outbound::manager::ThreadSafeOutboundManager,
     profile::ThreadSafeCacheFile,
@@ -48,6 +49,7 @@ pub fn get_api_runner(
     statistics_manager: Arc<StatisticsManager>,
     cache_store: ThreadSafeCacheFile,
     router: ThreadSafeRouter,
+    lifecycle: Arc<Lifecycle>,
     cwd: String,
 ) -> Option<Runner> {
     if let Some(bind_addr) = controller_cfg.external_controller {
@@ -57,7 +59,13 @@ pub fn get_api_runner(
         });
 
         let cors = CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH])
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
             .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
             .allow_origin(Any);
 
@@ -68,6 +76,8 @@ pub fn get_api_runner(
             bind_addr
         };
 
+        let capture_manager = dispatcher.capture_manager();
+
         let runner = async move {
             info!("Starting API server at {}", bind_addr);
             let mut app = Router::new()
@@ -86,22 +96,41 @@ pub fn get_api_runner(
                         dns_resolver.clone(),
                     ),
                 )
-                .nest("/rules", handlers::rule::routes(router))
+                .nest(
+                    "/rules",
+                    handlers::rule::routes(router.clone(), outbound_manager.clone()),
+                )
                 .nest(
                     "/proxies",
                     handlers::proxy::routes(outbound_manager.clone(), cache_store),
                 )
                 .nest(
                     "/connections",
-                    handlers::connection::routes(statistics_manager),
+                    handlers::connection::routes(statistics_manager.clone()),
+                )
+                .nest(
+                    "/statistics",
+                    handlers::statistics::routes(statistics_manager),
+                )
+                .nest(
+                    "/events",
+                    handlers::events::routes(outbound_manager.clone()),
                 )
                 .nest(
                     "/providers/proxies",
-                    handlers::provider::routes(outbound_manager),
+                    handlers::provider::routes(outbound_manager.clone()),
                 )
-                .nest("/dns", handlers::dns::routes(dns_resolver))
+                .nest(
+                    "/dns",
+                    handlers::dns::routes(dns_resolver, outbound_manager),
+                )
+                .nest("/capture", handlers::capture::routes(capture_manager))
+                .nest("/status", handlers::status::routes(lifecycle))
+                .nest("/debug", handlers::debug::routes(router))
                 .route_layer(middlewares::auth::AuthMiddlewareLayer::new(
                     controller_cfg.secret.unwrap_or_default(),
+                    controller_cfg.tokens,
+                    controller_cfg.local_select,
                 ))
                 .route_layer(cors)
                 .with_state(app_state)
@@ -116,7 +145,11 @@ pub fn get_api_runner(
                     );
             }
 
-            let listener = tokio::net::TcpListener::bind(&bind_addr).await.unwrap();
+            let listener = match bind_addr.parse() {
+                Ok(addr) => crate::app::handover::bind_tcp(addr).await,
+                Err(_) => tokio::net::TcpListener::bind(&bind_addr).await,
+            }
+            .unwrap();
 
             axum::serve(
                 listener,
@@ -0,0 +1,307 @@
+//! The dry run behind `clash-rs -t`.
+//! Everything the config refers to is looked at, but no listener is started
+//! and nothing is written to disk: providers are fetched and parsed, the
+//! databases must be present or downloadable.
+
+use std::{fmt::Display, path::Path, sync::Arc, time::Duration};
+
+use http_body_util::BodyExt;
+use serde::Deserialize;
+
+use crate::{
+    app::{
+        dns::SystemResolver,
+        remote_content_manager::providers::proxy_provider::subscription,
+    },
+    common::http::{HttpClient, new_http_client},
+    config::internal::{
+        InternalConfig,
+        config::RuleProviderDef,
+        proxy::{OutboundProxyProtocol, OutboundProxyProviderDef},
+        rule::RuleType,
+    },
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn error(&mut self, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    fn warn(&mut self, message: String) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for d in self.diagnostics.iter() {
+            match d.severity {
+                Severity::Error => writeln!(f, "error: {}", d.message)?,
+                Severity::Warning => writeln!(f, "warning: {}", d.message)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct RuleProviderScheme {
+    payload: Vec<String>,
+}
+
+/// `config` has been parsed and validated already
+pub async fn check(config: &InternalConfig, cwd: &Path) -> Report {
+    let mut report = Report::default();
+
+    check_rule_sets(config, &mut report);
+    check_database(
+        &mut report,
+        "mmdb",
+        &cwd.join(&config.general.mmdb),
+        config.general.mmdb_download_url.as_deref(),
+        true,
+    );
+    check_database(
+        &mut report,
+        "geosite",
+        &cwd.join(&config.general.geosite),
+        config.general.geosite_download_url.as_deref(),
+        true,
+    );
+    check_database(
+        &mut report,
+        "asn mmdb",
+        &cwd.join(&config.general.asn_mmdb),
+        config.general.asn_mmdb_download_url.as_deref(),
+        false,
+    );
+
+    let client = match SystemResolver::new(config.dns.ipv6)
+        .map_err(|x| std::io::Error::other(x.to_string()))
+        .and_then(|r| new_http_client(Arc::new(r)))
+    {
+        Ok(client) => Some(client),
+        Err(e) => {
+            report.error(format!("failed to create http client: {}", e));
+            None
+        }
+    };
+
+    for (name, provider) in config.proxy_providers.iter() {
+        let content = match provider {
+            OutboundProxyProviderDef::Http(http) => match &client {
+                Some(client) => fetch(client, &http.url).await,
+                None => continue,
+            },
+            OutboundProxyProviderDef::File(file) => {
+                std::fs::read(cwd.join(&file.path)).map_err(|e| e.to_string())
+            }
+        };
+        match content {
+            Ok(content) => check_proxy_provider(&mut report, name, &content),
+            Err(e) => report.error(format!("proxy provider {}: {}", name, e)),
+        }
+    }
+
+    for (name, provider) in config.rule_providers.iter() {
+        let content = match provider {
+            RuleProviderDef::Http(http) => match &client {
+                Some(client) => fetch(client, &http.url).await,
+                None => continue,
+            },
+            RuleProviderDef::File(file) => {
+                std::fs::read(cwd.join(&file.path)).map_err(|e| e.to_string())
+            }
+        };
+        match content.and_then(|c| {
+            serde_yaml::from_slice::<RuleProviderScheme>(&c)
+                .map_err(|e| e.to_string())
+        }) {
+            Ok(scheme) if scheme.payload.is_empty() => {
+                report.warn(format!("rule provider {} has no rules", name))
+            }
+            Ok(_) => {}
+            Err(e) => report.error(format!("rule provider {}: {}", name, e)),
+        }
+    }
+
+    report
+}
+
+fn check_rule_sets(config: &InternalConfig, report: &mut Report) {
+    for rule in config.rules.iter() {
+        let mut rule = rule;
+        while let RuleType::Rewrite { rule: inner, .. }
+        | RuleType::NoDelay { rule: inner, .. }
+        | RuleType::Scheduled { rule: inner, .. } = rule
+        {
+            rule = inner;
+        }
+        if let RuleType::RuleSet { rule_set, .. } = rule
+            && !config.rule_providers.contains_key(rule_set)
+        {
+            report.error(format!(
+                "rule provider `{}` referenced in a rule was not found",
+                rule_set
+            ));
+        }
+    }
+}
+
+fn check_database(
+    report: &mut Report,
+    name: &str,
+    path: &Path,
+    download_url: Option<&str>,
+    required: bool,
+) {
+    match (path.exists(), download_url) {
+        (true, _) => {}
+        (false, Some(url)) => report.warn(format!(
+            "{} {} not found, it will be downloaded from {}",
+            name,
+            path.display(),
+            url
+        )),
+        (false, None) if required => report.error(format!(
+            "{} {} not found and no download url is set",
+            name,
+            path.display()
+        )),
+        (false, None) => {}
+    }
+}
+
+fn check_proxy_provider(report: &mut Report, name: &str, content: &[u8]) {
+    let scheme = match subscription::parse(content) {
+        Ok(scheme) => scheme,
+        Err(e) => {
+            report.error(format!("proxy provider {}: {}", name, e));
+            return;
+        }
+    };
+    for skipped in scheme.skipped {
+        report.warn(format!(
+            "proxy provider {}: {} will be skipped",
+            name, skipped
+        ));
+    }
+    let proxies = scheme.proxies;
+    if proxies.is_empty() {
+        report.error(format!("proxy provider {} has no proxies", name));
+    }
+    for proxy in proxies {
+        let proxy_name = proxy
+            .get("name")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_owned();
+        // the provider skips invalid proxies when loading
+        if let Err(e) = OutboundProxyProtocol::try_from(subscription::to_map(proxy))
+        {
+            report.warn(format!(
+                "proxy provider {}: proxy `{}` will be skipped: {}",
+                name, proxy_name, e
+            ));
+        }
+    }
+}
+
+pub(crate) async fn fetch(
+    client: &HttpClient,
+    url: &str,
+) -> Result<Vec<u8>, String> {
+    let uri = url
+        .parse::<hyper::Uri>()
+        .map_err(|e| format!("invalid url {}: {}", url, e))?;
+    let fetch = async {
+        let res = client.get(uri).await.map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("{} returned {}", url, res.status()));
+        }
+        res.into_body()
+            .collect()
+            .await
+            .map(|x| x.to_bytes().to_vec())
+            .map_err(|e| e.to_string())
+    };
+    tokio::time::timeout(FETCH_TIMEOUT, fetch)
+        .await
+        .map_err(|_| format!("timeout fetching {}", url))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Report, Severity, check_database, check_proxy_provider};
+
+    #[test]
+    fn test_check_proxy_provider() {
+        let mut report = Report::default();
+        check_proxy_provider(
+            &mut report,
+            "p",
+            br#"
+proxies:
+  - name: ok
+    type: socks5
+    server: 10.0.0.1
+    port: 1080
+  - name: bad
+    type: unknown
+"#,
+        );
+        assert!(!report.has_errors());
+        assert_eq!(report.diagnostics.len(), 1);
+        assert!(report.diagnostics[0].message.contains("`bad`"));
+
+        let mut report = Report::default();
+        check_proxy_provider(&mut report, "p", b"proxies: []");
+        assert!(report.has_errors());
+    }
+
+    #[test]
+    fn test_check_database() {
+        let path = std::path::Path::new("/nonexistent/Country.mmdb");
+
+        let mut report = Report::default();
+        check_database(&mut report, "mmdb", path, None, false);
+        assert!(report.diagnostics.is_empty());
+
+        check_database(&mut report, "mmdb", path, Some("https://x/y.mmdb"), true);
+        assert_eq!(report.diagnostics[0].severity, Severity::Warning);
+
+        check_database(&mut report, "mmdb", path, None, true);
+        assert!(report.has_errors());
+        assert_eq!(report.to_string().lines().count(), 2);
+    }
+}
@@ -0,0 +1,129 @@
+//! The requests of `clash-rs ctl` to the controller of a running instance,
+//! for cron jobs and scripts:
+//! - `ctl providers update <name>` is `PUT /providers/<name>`
+//! - `ctl proxies check <group>` is `GET /proxies/<group>/healthcheck`
+
+use std::sync::Arc;
+
+use http::Method;
+use http_body_util::BodyExt;
+use url::Url;
+
+use crate::{app::dns::SystemResolver, common::http::new_http_client};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CtlCommand {
+    /// update a proxy provider from its source
+    UpdateProvider(String),
+    /// health-check the proxies of a group
+    CheckGroup(String),
+}
+
+/// `http://<controller>/<segments>`, with the segments escaped
+pub(crate) fn path_url(controller: &str, segments: &[&str]) -> Result<Url, String> {
+    let mut target = Url::parse(&format!("http://{}/", controller))
+        .map_err(|e| format!("invalid controller {}: {}", controller, e))?;
+    target
+        .path_segments_mut()
+        .map_err(|_| format!("invalid controller {}", controller))?
+        .clear()
+        .extend(segments);
+    Ok(target)
+}
+
+/// the method and the controller URL to request for `command`
+pub fn controller_request(
+    controller: &str,
+    command: &CtlCommand,
+) -> Result<(Method, String), String> {
+    let (method, segments) = match command {
+        CtlCommand::UpdateProvider(name) => {
+            (Method::PUT, ["providers", name.as_str()].to_vec())
+        }
+        CtlCommand::CheckGroup(group) => (
+            Method::GET,
+            ["proxies", group.as_str(), "healthcheck"].to_vec(),
+        ),
+    };
+    Ok((method, path_url(controller, &segments)?.to_string()))
+}
+
+pub async fn run(
+    controller: &str,
+    command: &CtlCommand,
+    secret: Option<&str>,
+) -> Result<String, String> {
+    let (method, target) = controller_request(controller, command)?;
+    request(method, &target, secret).await
+}
+
+/// Requests `target` with the `secret` of the controller, returns the body
+/// of a successful response
+pub(crate) async fn request(
+    method: Method,
+    target: &str,
+    secret: Option<&str>,
+) -> Result<String, String> {
+    let client = SystemResolver::new(false)
+        .map_err(|x| std::io::Error::other(x.to_string()))
+        .and_then(|r| new_http_client(Arc::new(r)))
+        .map_err(|e| format!("failed to create http client: {}", e))?;
+
+    let mut req = http::Request::builder().method(method).uri(target);
+    if let Some(secret) = secret {
+        req = req.header(http::header::AUTHORIZATION, format!("Bearer {}", secret));
+    }
+    let req = req
+        .body(Default::default())
+        .map_err(|e| format!("invalid request {}: {}", target, e))?;
+    let res = client.request(req).await.map_err(|e| e.to_string())?;
+
+    let status = res.status();
+    let body = res
+        .into_body()
+        .collect()
+        .await
+        .map(|x| String::from_utf8_lossy(&x.to_bytes()).into_owned())
+        .map_err(|e| e.to_string())?;
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(format!("{}: {}", status, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Method;
+
+    use super::{CtlCommand, controller_request};
+
+    #[test]
+    fn test_controller_request() {
+        assert_eq!(
+            controller_request(
+                "127.0.0.1:9090",
+                &CtlCommand::UpdateProvider("my provider".to_owned()),
+            )
+            .unwrap(),
+            (
+                Method::PUT,
+                "http://127.0.0.1:9090/providers/my%20provider".to_owned()
+            )
+        );
+        assert_eq!(
+            controller_request(
+                "127.0.0.1:9090",
+                &CtlCommand::CheckGroup("Auto/HK".to_owned()),
+            )
+            .unwrap(),
+            (
+                Method::GET,
+                "http://127.0.0.1:9090/proxies/Auto%2FHK/healthcheck".to_owned()
+            )
+        );
+        assert!(
+            controller_request("", &CtlCommand::CheckGroup("g".to_owned())).is_err()
+        );
+    }
+}
@@ -0,0 +1,65 @@
+//! The `clash://` URL scheme handler behind `clash-rs open-url`.
+//! `clash://select?group=<group>&name=<proxy>` is sent to the local
+//! controller as `POST /proxies/<group>/select?name=<proxy>`, which needs
+//! `controller-local-select` or a `secret`.
+
+use http::Method;
+use url::Url;
+
+use crate::app::ctl::{path_url, request};
+
+/// the controller URL to request for `url`
+pub fn controller_url(controller: &str, url: &str) -> Result<String, String> {
+    let url = Url::parse(url).map_err(|e| format!("invalid url {}: {}", url, e))?;
+    if url.scheme() != "clash" {
+        return Err(format!("not a clash:// url: {}", url));
+    }
+    let param = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+            .ok_or_else(|| format!("{} is missing in {}", key, url))
+    };
+
+    match url.host_str() {
+        Some("select") => {
+            let (group, name) = (param("group")?, param("name")?);
+            let mut target =
+                path_url(controller, &["proxies", group.as_str(), "select"])?;
+            target.query_pairs_mut().append_pair("name", &name);
+            Ok(target.to_string())
+        }
+        _ => Err(format!("unsupported action in {}", url)),
+    }
+}
+
+pub async fn open(
+    controller: &str,
+    url: &str,
+    secret: Option<&str>,
+) -> Result<String, String> {
+    let target = controller_url(controller, url)?;
+    request(Method::POST, &target, secret).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::controller_url;
+
+    #[test]
+    fn test_controller_url() {
+        assert_eq!(
+            controller_url(
+                "127.0.0.1:9090",
+                "clash://select?group=Auto%20Select&name=HK%2F01",
+            )
+            .unwrap(),
+            "http://127.0.0.1:9090/proxies/Auto%20Select/select?name=HK%2F01"
+        );
+        assert!(controller_url("127.0.0.1:9090", "clash://select?group=g").is_err());
+        assert!(
+            controller_url("127.0.0.1:9090", "https://select?group=g&name=n")
+                .is_err()
+        );
+    }
+}
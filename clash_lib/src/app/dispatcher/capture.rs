@@ -0,0 +1,313 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    Error,
+    app::router::{RuleMatcher, ThreadSafeRouter},
+    common::pcapng::{Direction, PcapngWriter, TcpFlow},
+    proxy::ClientStream,
+    session::{Session, SocksAddr},
+};
+
+type Record = (Direction, SystemTime, Vec<u8>);
+
+/// the records of a connection waiting for the file, past that they're
+/// dropped rather than held while the disk is slower than the connection
+const QUEUE_SIZE: usize = 1024;
+
+/// what is captured, as shown and set by the API
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct CaptureSettings {
+    /// capture every connection
+    #[serde(default)]
+    pub all: bool,
+    /// rules without a target, a connection matching any of them is captured
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+/// Writes the client side of selected TCP connections, after the inbound
+/// has decrypted it, to a pcapng file per connection for debugging.
+pub struct CaptureManager {
+    dir: PathBuf,
+    router: ThreadSafeRouter,
+    settings: std::sync::RwLock<(CaptureSettings, Vec<Box<dyn RuleMatcher>>)>,
+    seq: AtomicU64,
+}
+
+impl CaptureManager {
+    /// nothing is captured until `set` is called
+    pub fn new(dir: PathBuf, router: ThreadSafeRouter) -> Self {
+        Self {
+            dir,
+            router,
+            settings: Default::default(),
+            seq: AtomicU64::new(0),
+        }
+    }
+
+    pub fn settings(&self) -> CaptureSettings {
+        self.settings.read().unwrap().0.clone()
+    }
+
+    pub fn set(&self, settings: CaptureSettings) -> Result<(), Error> {
+        let matchers = settings
+            .rules
+            .iter()
+            .map(|r| self.router.build_selector(r))
+            .collect::<Result<Vec<_>, _>>()?;
+        if settings.all || !matchers.is_empty() {
+            warn!(
+                "capturing connections to {}, this is meant for debugging only",
+                self.dir.display()
+            );
+        }
+        *self.settings.write().unwrap() = (settings, matchers);
+        Ok(())
+    }
+
+    fn selected(&self, sess: &Session) -> bool {
+        let settings = self.settings.read().unwrap();
+        settings.0.all || settings.1.iter().any(|m| m.apply(sess))
+    }
+
+    /// returns `stream` as is if the connection isn't selected
+    pub fn wrap(
+        &self,
+        sess: &Session,
+        stream: Box<dyn ClientStream>,
+    ) -> Box<dyn ClientStream> {
+        if !self.selected(sess) {
+            return stream;
+        }
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let host = sess
+            .destination
+            .host()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let path = self.dir.join(format!(
+            "{}-{}-{}.pcapng",
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            seq,
+            host
+        ));
+        debug!("capturing {} to {}", sess, path.display());
+
+        let server = match (&sess.destination, sess.resolved_ip) {
+            (SocksAddr::Ip(addr), _) => *addr,
+            (SocksAddr::Domain(_, port), Some(ip)) => SocketAddr::new(ip, *port),
+            (SocksAddr::Domain(_, port), None) => {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), *port)
+            }
+        };
+        let flow = TcpFlow::new(sess.source, server);
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let comment = sess.to_string();
+        let writer_dropped = dropped.clone();
+        tokio::spawn(async move {
+            let r = write_capture(path.clone(), flow, comment, rx, writer_dropped);
+            if let Err(e) = r.await {
+                warn!("failed to write capture {}: {}", path.display(), e);
+            }
+        });
+
+        Box::new(CaptureStream {
+            inner: stream,
+            tx,
+            dropped,
+        })
+    }
+}
+
+async fn write_capture(
+    path: PathBuf,
+    mut flow: TcpFlow,
+    comment: String,
+    mut rx: mpsc::Receiver<Record>,
+    dropped: Arc<AtomicU64>,
+) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut file = tokio::fs::File::create(&path).await?;
+    // packets are built in memory and written out as they come
+    let mut w = PcapngWriter::new(Vec::new())?;
+
+    let now = SystemTime::now();
+    let [syn, syn_ack, ack] = flow.handshake();
+    w.write_packet(now, &syn, Some(&comment))?;
+    w.write_packet(now, &syn_ack, None)?;
+    w.write_packet(now, &ack, None)?;
+
+    loop {
+        file.write_all(w.get_mut()).await?;
+        w.get_mut().clear();
+
+        let Some((dir, time, data)) = rx.recv().await else {
+            break;
+        };
+        for p in flow.data(dir, &data) {
+            w.write_packet(time, &p, None)?;
+        }
+    }
+
+    let now = SystemTime::now();
+    let dropped = match dropped.load(Ordering::Relaxed) {
+        0 => None,
+        n => {
+            warn!("capture {} dropped {} reads or writes", path.display(), n);
+            Some(format!(
+                "{} reads or writes dropped, the capture has gaps",
+                n
+            ))
+        }
+    };
+    w.write_packet(now, &flow.fin(Direction::Up), dropped.as_deref())?;
+    w.write_packet(now, &flow.fin(Direction::Down), None)?;
+    file.write_all(w.get_mut()).await?;
+    file.flush().await
+}
+
+/// reads from the client are recorded as up, writes to it as down
+struct CaptureStream {
+    inner: Box<dyn ClientStream>,
+    tx: mpsc::Sender<Record>,
+    /// the records the queue had no room for
+    dropped: Arc<AtomicU64>,
+}
+
+impl CaptureStream {
+    fn record(&self, dir: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let record = (dir, SystemTime::now(), data.to_vec());
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl AsyncRead for CaptureStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = r {
+            self.record(Direction::Up, &buf.filled()[filled..]);
+        }
+        r
+    }
+}
+
+impl AsyncWrite for CaptureStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = r {
+            self.record(Direction::Down, &buf[..n]);
+        }
+        r
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, atomic::Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{CaptureStream, Direction};
+
+    #[tokio::test]
+    async fn test_capture_stream() {
+        let (client, server) = tokio::io::duplex(64);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let mut stream = CaptureStream {
+            inner: Box::new(server),
+            tx,
+            dropped: Default::default(),
+        };
+
+        let mut client = client;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+        drop(stream);
+
+        let (dir, _, data) = rx.recv().await.unwrap();
+        assert_eq!((dir, data.as_slice()), (Direction::Up, &b"ping"[..]));
+        let (dir, _, data) = rx.recv().await.unwrap();
+        assert_eq!((dir, data.as_slice()), (Direction::Down, &b"pong"[..]));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream_full() {
+        let (_client, server) = tokio::io::duplex(64);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let dropped = Arc::new(Default::default());
+        let mut stream = CaptureStream {
+            inner: Box::new(server),
+            tx,
+            dropped: Arc::clone(&dropped),
+        };
+
+        stream.write_all(b"one").await.unwrap();
+        stream.write_all(b"two").await.unwrap();
+        drop(stream);
+
+        let (_, _, data) = rx.recv().await.unwrap();
+        assert_eq!(data, b"one");
+        assert!(rx.recv().await.is_none());
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+    }
+}
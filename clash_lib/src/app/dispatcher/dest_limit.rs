@@ -0,0 +1,113 @@
+//! Caps the concurrent connections to each destination host through the
+//! proxies, as some providers flag the accounts of apps opening hundreds of
+//! connections to the same host at once. The connections over the cap wait
+//! for one to close, rather than being refused right away.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+/// a connection waiting longer is refused
+const WAIT: Duration = Duration::from_secs(10);
+
+type Hosts = Arc<Mutex<HashMap<String, Arc<Semaphore>>>>;
+
+pub struct DestinationLimiter {
+    max: usize,
+    hosts: Hosts,
+}
+
+/// A connection to the host counted until it's dropped
+pub struct DestinationPermit {
+    host: String,
+    hosts: Hosts,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for DestinationPermit {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        // the hosts nobody is connected to or waiting for are forgotten
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts
+            .get(&self.host)
+            .is_some_and(|x| Arc::strong_count(x) == 1)
+        {
+            hosts.remove(&self.host);
+        }
+    }
+}
+
+impl DestinationLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            hosts: Default::default(),
+        }
+    }
+
+    /// waits for a free slot of `host`
+    pub async fn acquire(&self, host: &str) -> io::Result<DestinationPermit> {
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max)))
+            .clone();
+        let permit = match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!("{} connections to {}, waiting", self.max, host);
+                tokio::time::timeout(WAIT, semaphore.acquire_owned())
+                    .await
+                    .map_err(|_| {
+                        io::Error::other(format!("too many connections to {}", host))
+                    })?
+                    .expect("never closed")
+            }
+        };
+        Ok(DestinationPermit {
+            host: host.to_owned(),
+            hosts: self.hosts.clone(),
+            permit: Some(permit),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::DestinationLimiter;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_destination_limit() {
+        let limiter = DestinationLimiter::new(2);
+
+        let a = limiter.acquire("example.com").await.unwrap();
+        let _b = limiter.acquire("example.com").await.unwrap();
+        let other = limiter.acquire("example.org").await.unwrap();
+        assert!(limiter.acquire("example.com").await.is_err());
+
+        let waiting = limiter.acquire("example.com");
+        tokio::pin!(waiting);
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), &mut waiting)
+                .await
+                .is_err()
+        );
+        drop(a);
+        let _c = waiting.await.unwrap();
+
+        drop(other);
+        assert!(!limiter.hosts.lock().unwrap().contains_key("example.org"));
+        assert!(limiter.hosts.lock().unwrap().contains_key("example.com"));
+    }
+}
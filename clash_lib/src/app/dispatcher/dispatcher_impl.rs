@@ -1,31 +1,55 @@
 use crate::{
     app::{
-        dispatcher::tracked::{TrackedDatagram, TrackedStream},
+        dispatcher::{
+            BoxedChainedStream, CaptureManager,
+            dest_limit::{DestinationLimiter, DestinationPermit},
+            sniffer,
+            tracked::{TrackedDatagram, TrackedStream},
+        },
         outbound::manager::ThreadSafeOutboundManager,
-        router::ThreadSafeRouter,
+        router::{RuleMatcher, ThreadSafeRouter},
+    },
+    common::{
+        errors,
+        io::{CopyBidirectionalError, copy_bidirectional},
     },
-    common::io::copy_bidirectional,
     config::{
-        def::RunMode,
-        internal::proxy::{PROXY_DIRECT, PROXY_GLOBAL},
+        def::{RunMode, Sniffer},
+        internal::{
+            listener::{CommonInboundOpts, UdpNat},
+            proxy::{PROXY_DIRECT, PROXY_GLOBAL},
+        },
+    },
+    proxy::{
+        AnyInboundDatagram, ClientStream, OutboundType, PendingBind,
+        datagram::UdpPacket, utils::with_nodelay,
     },
-    proxy::{AnyInboundDatagram, ClientStream, datagram::UdpPacket},
-    session::{Session, SocksAddr},
+    session::{Protocol, Session, SocksAddr},
 };
-use futures::{SinkExt, StreamExt};
+use futures::{FutureExt, SinkExt, StreamExt};
 use std::{
     collections::HashMap,
     fmt::{Debug, Formatter},
-    net::SocketAddr,
-    sync::Arc,
+    io,
+    net::{IpAddr, SocketAddr},
+    panic::AssertUnwindSafe,
+    sync::{Arc, PoisonError},
     time::{Duration, Instant},
 };
-use tokio::{io::AsyncWriteExt, sync::RwLock, task::JoinHandle};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{RwLock, broadcast},
+    task::JoinHandle,
+};
 use tracing::{Instrument, debug, error, info, info_span, instrument, trace, warn};
 
 use crate::app::dns::ThreadSafeDNSResolver;
 
-use super::statistics_manager::Manager;
+use super::statistics_manager::{CloseReason, Manager};
+
+/// a failed connection is tried again only within this time of the first
+/// attempt, the time a dial to a server is given
+const RETRY_BUDGET: Duration = Duration::from_secs(10);
 
 const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
 
@@ -36,6 +60,35 @@ pub struct Dispatcher {
     mode: Arc<RwLock<RunMode>>,
     manager: Arc<Manager>,
     tcp_buffer_size: usize,
+    capture: Arc<CaptureManager>,
+    sniffer: Sniffer,
+    /// the `sub-rules` entry of the listener it dispatches for
+    sub_rules: Option<String>,
+    udp_nat: UdpNat,
+    dest_limiter: Option<Arc<DestinationLimiter>>,
+}
+
+/// A remote connection made by [`Dispatcher::connect_stream`]
+pub struct ConnectedStream {
+    sess: Session,
+    rhs: BoxedChainedStream,
+    rule: Option<Arc<dyn RuleMatcher>>,
+    outbound_name: String,
+    _fake_ip: Option<FakeIpGuard>,
+    _permit: Option<DestinationPermit>,
+}
+
+/// keeps the fake ip of a connection from being reused until it's closed
+struct FakeIpGuard {
+    ip: IpAddr,
+    resolver: ThreadSafeDNSResolver,
+}
+
+impl Drop for FakeIpGuard {
+    fn drop(&mut self) {
+        let (ip, resolver) = (self.ip, self.resolver.clone());
+        tokio::spawn(async move { resolver.release_fake_ip(ip).await });
+    }
 }
 
 impl Debug for Dispatcher {
@@ -52,7 +105,30 @@ impl Dispatcher {
         mode: RunMode,
         statistics_manager: Arc<Manager>,
         tcp_buffer_size: Option<usize>,
+        capture: Arc<CaptureManager>,
+        sniffer: Sniffer,
     ) -> Self {
+        // ends with the outbound manager when the config is reloaded
+        let mut switches = outbound_manager.subscribe_switches();
+        let manager = statistics_manager.clone();
+        tokio::spawn(async move {
+            loop {
+                match switches.recv().await {
+                    Ok(s) => {
+                        let n = manager.close_switched(&s.group, &s.proxy).await;
+                        info!(
+                            "`{}` switched to `{}`, {} connections interrupted",
+                            s.group, s.proxy, n
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("{} group switches missed", n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
         Self {
             outbound_manager,
             router,
@@ -60,9 +136,70 @@ impl Dispatcher {
             mode: Arc::new(RwLock::new(mode)),
             manager: statistics_manager,
             tcp_buffer_size: tcp_buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE),
+            capture,
+            sniffer,
+            sub_rules: None,
+            udp_nat: UdpNat::default(),
+            dest_limiter: None,
         }
     }
 
+    /// caps the TCP connections to each destination host through the
+    /// proxies at `max`
+    pub fn with_destination_limit(mut self, max: Option<usize>) -> Self {
+        self.dest_limiter = max.map(|x| Arc::new(DestinationLimiter::new(x)));
+        self
+    }
+
+    /// a dispatcher sharing everything with this one but with the routing
+    /// and NAT settings of a listener
+    pub fn for_listener(&self, opts: &CommonInboundOpts) -> Self {
+        Self {
+            outbound_manager: self.outbound_manager.clone(),
+            router: self.router.clone(),
+            resolver: self.resolver.clone(),
+            mode: self.mode.clone(),
+            manager: self.manager.clone(),
+            tcp_buffer_size: self.tcp_buffer_size,
+            capture: self.capture.clone(),
+            sniffer: self.sniffer.clone(),
+            sub_rules: opts.rules.clone(),
+            udp_nat: opts.udp_nat,
+            dest_limiter: self.dest_limiter.clone(),
+        }
+    }
+
+    pub(crate) fn outbound_manager(&self) -> &ThreadSafeOutboundManager {
+        &self.outbound_manager
+    }
+
+    /// the outbound `sess` goes to and the rule that picked it: the one
+    /// pinned by the inbound, else by the mode and the rules
+    pub(crate) async fn route(
+        &self,
+        sess: &mut Session,
+    ) -> (String, Option<Arc<dyn RuleMatcher>>) {
+        let mode = *self.mode.read().await;
+        let (outbound_name, rule) = match (sess.outbound.clone(), mode) {
+            (Some(outbound), _) => (outbound, None),
+            (None, RunMode::Global) => (PROXY_GLOBAL.to_owned(), None),
+            (None, RunMode::Rule) => {
+                let sub_rules = sess.sub_rules.clone();
+                self.router.match_route_in(sess, sub_rules.as_deref()).await
+            }
+            (None, RunMode::Direct) => (PROXY_DIRECT.to_owned(), None),
+        };
+        let outbound_name =
+            self.manager
+                .hooks()
+                .rule_matched(sess, rule.as_deref(), outbound_name);
+        (outbound_name, rule)
+    }
+
+    pub fn capture_manager(&self) -> Arc<CaptureManager> {
+        self.capture.clone()
+    }
+
     pub async fn set_mode(&self, mode: RunMode) {
         info!("run mode switched to {}", mode);
 
@@ -77,8 +214,44 @@ impl Dispatcher {
     pub async fn dispatch_stream(
         &self,
         mut sess: Session,
-        mut lhs: Box<dyn ClientStream>,
+        lhs: Box<dyn ClientStream>,
     ) {
+        let mut lhs = self.sniff_stream(&mut sess, lhs).await;
+        match self.connect_stream(sess.clone()).await {
+            Ok(remote) => self.relay_connected(lhs, remote).await,
+            Err(_) => {
+                if let Err(e) = lhs.shutdown().await {
+                    warn!("error closing local connection {}: {}", sess, e)
+                }
+            }
+        }
+    }
+
+    /// `lhs` as it was, after its protocol is put in `sess` if sniffing,
+    /// which the `FORCE-SNIFF` and `SKIP-SNIFF` rules override
+    pub async fn sniff_stream(
+        &self,
+        sess: &mut Session,
+        lhs: Box<dyn ClientStream>,
+    ) -> Box<dyn ClientStream> {
+        if self
+            .router
+            .sniff_override(sess)
+            .unwrap_or(self.sniffer.enable)
+        {
+            sniffer::sniff_stream(sess, lhs).await
+        } else {
+            lhs
+        }
+    }
+
+    /// Route `sess` and connect to the remote, for a local stream that is
+    /// accepted later, or not at all if this fails
+    pub async fn connect_stream(
+        &self,
+        mut sess: Session,
+    ) -> io::Result<ConnectedStream> {
+        let mut fake_ip = None;
         let dest: SocksAddr = match &sess.destination {
             crate::session::SocksAddr::Ip(socket_addr) => {
                 if self.resolver.fake_ip_enabled() {
@@ -87,12 +260,22 @@ impl Dispatcher {
                     if self.resolver.is_fake_ip(ip).await {
                         let host = self.resolver.reverse_lookup(ip).await;
                         match host {
-                            Some(host) => (host, socket_addr.port())
-                                .try_into()
-                                .expect("must be valid domain"),
+                            Some(host) => {
+                                self.resolver.retain_fake_ip(ip).await;
+                                fake_ip = Some(FakeIpGuard {
+                                    ip,
+                                    resolver: self.resolver.clone(),
+                                });
+                                (host, socket_addr.port())
+                                    .try_into()
+                                    .expect("must be valid domain")
+                            }
                             None => {
                                 error!("failed to reverse lookup fake ip: {}", ip);
-                                return;
+                                return Err(io::Error::new(
+                                    io::ErrorKind::NotFound,
+                                    format!("unknown fake ip {}", ip),
+                                ));
                             }
                         }
                     } else {
@@ -117,133 +300,269 @@ impl Dispatcher {
 
         sess.destination = dest.clone();
 
+        sess.sub_rules = self.sub_rules.clone();
         let mode = *self.mode.read().await;
-        let (outbound_name, rule) = match mode {
-            RunMode::Global => (PROXY_GLOBAL, None),
-            RunMode::Rule => self.router.match_route(&mut sess).await,
-            RunMode::Direct => (PROXY_DIRECT, None),
-        };
+        let (outbound_name, rule) = self.route(&mut sess).await;
+
+        if let Some(dst) = rule.as_ref().and_then(|r| r.destination_override()) {
+            let dest = dst.apply(&sess.destination);
+            debug!("rewriting destination of {} to {}", sess, dest);
+            sess.destination = dest;
+            sess.resolved_ip = None;
+        }
 
         debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
 
         let mgr = self.outbound_manager.clone();
-        let handler = mgr.get_outbound(outbound_name).unwrap_or_else(|| {
-            debug!("unknown rule: {}, fallback to direct", outbound_name);
-            mgr.get_outbound(PROXY_DIRECT).unwrap()
-        });
+        let handler = match mgr.get_outbound(&outbound_name) {
+            Some(handler) => handler,
+            // the outbound pinned by the inbound is never swapped for another
+            None if sess.outbound.is_some() => {
+                warn!("outbound {} of {} not found", outbound_name, sess);
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("outbound {} not found", outbound_name),
+                ));
+            }
+            None => {
+                debug!("unknown rule: {}, fallback to direct", outbound_name);
+                mgr.get_outbound(PROXY_DIRECT).unwrap()
+            }
+        };
 
-        match handler
-            .connect_stream(&sess, self.resolver.clone())
-            .instrument(info_span!("connect_stream", outbound_name = outbound_name,))
-            .await
-        {
-            Ok(rhs) => {
-                debug!("remote connection established {}", sess);
-                let rhs = TrackedStream::new(
-                    rhs,
-                    self.manager.clone(),
-                    sess.clone(),
-                    rule,
-                )
-                .await;
-                match copy_bidirectional(
-                    lhs,
-                    rhs,
-                    self.tcp_buffer_size,
-                    Duration::from_secs(10),
-                    Duration::from_secs(10),
+        // a group picking DIRECT or REJECT doesn't go through a proxy either
+        let last_hop = match &self.dest_limiter {
+            Some(_) => mgr.last_hop(handler.name(), &sess).await,
+            None => None,
+        };
+        let permit = match &self.dest_limiter {
+            Some(limiter)
+                if !matches!(
+                    last_hop.as_ref().unwrap_or(&handler).proto(),
+                    OutboundType::Direct | OutboundType::Reject
+                ) =>
+            {
+                let permit = limiter.acquire(&sess.destination.host()).await;
+                if let Err(e) = &permit {
+                    warn!("failed to connect {}: {}", sess, e);
+                }
+                Some(permit?)
+            }
+            _ => None,
+        };
+
+        let nodelay = rule.as_ref().and_then(|r| r.nodelay());
+        let connect = || {
+            connect_isolated(
+                with_nodelay(
+                    nodelay,
+                    handler
+                        .connect_stream(&sess, self.resolver.clone())
+                        .instrument(info_span!(
+                            "connect_stream",
+                            outbound_name = outbound_name.as_str(),
+                        )),
+                ),
+                &self.outbound_manager,
+                &outbound_name,
+                &sess,
+            )
+        };
+        // transient failures, e.g. a dns timeout or a reset during handshake,
+        // get a second chance before the local connection is dropped, in what
+        // is left of the time a dial may take, so a timeout isn't waited twice
+        let start = Instant::now();
+        let remote = match connect().await {
+            Err(err)
+                if errors::is_retryable(&err) && start.elapsed() < RETRY_BUDGET =>
+            {
+                debug!(
+                    "retrying remote connection {} after {} error: {}",
+                    sess,
+                    errors::error_kind(&err),
+                    err
+                );
+                tokio::time::timeout(
+                    RETRY_BUDGET.saturating_sub(start.elapsed()),
+                    connect(),
                 )
-                .instrument(info_span!(
-                    "copy_bidirectional",
-                    outbound_name = outbound_name,
-                ))
                 .await
-                {
-                    Ok((up, down)) => {
-                        debug!(
-                            "connection {} closed with {} bytes up, {} bytes down",
-                            sess, up, down
-                        );
-                    }
-                    Err(err) => match err {
-                        crate::common::io::CopyBidirectionalError::LeftClosed(
-                            err,
-                        ) => match err.kind() {
-                            std::io::ErrorKind::UnexpectedEof
-                            | std::io::ErrorKind::ConnectionReset
-                            | std::io::ErrorKind::BrokenPipe => {
-                                debug!(
-                                    "connection {} closed with error {} by local",
-                                    sess, err
-                                );
-                            }
-                            _ => {
-                                warn!(
-                                    "connection {} closed with error {} by local",
-                                    sess, err
-                                );
-                            }
-                        },
-                        crate::common::io::CopyBidirectionalError::RightClosed(
-                            err,
-                        ) => match err.kind() {
-                            std::io::ErrorKind::UnexpectedEof
-                            | std::io::ErrorKind::ConnectionReset
-                            | std::io::ErrorKind::BrokenPipe => {
-                                debug!(
-                                    "connection {} closed with error {} by remote",
-                                    sess, err
-                                );
-                            }
-                            _ => {
-                                warn!(
-                                    "connection {} closed with error {} by remote",
-                                    sess, err
-                                );
-                            }
-                        },
-                        crate::common::io::CopyBidirectionalError::Other(err) => {
-                            match err.kind() {
-                                std::io::ErrorKind::UnexpectedEof
-                                | std::io::ErrorKind::ConnectionReset
-                                | std::io::ErrorKind::BrokenPipe => {
-                                    debug!(
-                                        "connection {} closed with error {}",
-                                        sess, err
-                                    );
-                                }
-                                _ => {
-                                    warn!(
-                                        "connection {} closed with error {}",
-                                        sess, err
-                                    );
-                                }
-                            }
-                        }
-                    },
-                }
+                .unwrap_or(Err(err))
             }
+            r => r,
+        };
+        self.outbound_manager
+            .report_dial(&outbound_name, &sess, remote.is_ok())
+            .await;
+        self.outbound_manager
+            .report_connect(&outbound_name, remote.as_ref().err())
+            .await;
+
+        match remote {
+            Ok(rhs) => Ok(ConnectedStream {
+                sess,
+                rhs,
+                rule,
+                outbound_name,
+                _fake_ip: fake_ip,
+                _permit: permit,
+            }),
             Err(err) => {
                 warn!(
-                    "failed to establish remote connection {}, error: {}",
-                    sess, err
+                    "failed to establish remote connection {}, {} error: {}",
+                    sess,
+                    errors::error_kind(&err),
+                    err
                 );
-                if let Err(e) = lhs.shutdown().await {
-                    warn!("error closing local connection {}: {}", sess, e)
+                Err(err)
+            }
+        }
+    }
+
+    /// Relay `lhs` to the remote connected for it by `connect_stream`
+    pub async fn relay_connected(
+        &self,
+        lhs: Box<dyn ClientStream>,
+        remote: ConnectedStream,
+    ) {
+        let ConnectedStream {
+            sess,
+            rhs,
+            rule,
+            outbound_name,
+            _fake_ip,
+            _permit,
+        } = remote;
+        // the remote leg is connected with it already, the local one can only
+        // be changed if it's a plain TCP connection
+        if let Some(nodelay) = rule.as_ref().and_then(|r| r.nodelay())
+            && let Some(s) = lhs.downcast_ref::<tokio::net::TcpStream>()
+            && let Err(e) = s.set_nodelay(nodelay)
+        {
+            debug!("failed to set TCP_NODELAY of {}: {}", sess, e);
+        }
+        let lhs = self.capture.wrap(&sess, lhs);
+        self.relay_stream(&sess, lhs, rhs, rule.as_deref(), &outbound_name)
+            .await;
+    }
+
+    async fn relay_stream(
+        &self,
+        sess: &Session,
+        lhs: Box<dyn ClientStream>,
+        rhs: BoxedChainedStream,
+        rule: Option<&dyn RuleMatcher>,
+        outbound_name: &str,
+    ) {
+        debug!("remote connection established {}", sess);
+        // lent to the copy, so the close reason is recorded before it's
+        // untracked
+        let mut rhs =
+            TrackedStream::new(rhs, self.manager.clone(), sess.clone(), rule).await;
+        let tracker = rhs.tracker_info();
+        let _active = self
+            .outbound_manager
+            .track_connection(tracker.clone())
+            .await;
+        let copy = copy_bidirectional(
+            lhs,
+            &mut rhs,
+            self.tcp_buffer_size,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        )
+        .instrument(info_span!(
+            "copy_bidirectional",
+            outbound_name = outbound_name,
+        ));
+        let res = match catch_panic(copy).await {
+            Ok(res) => res,
+            Err(panic) => {
+                let chain = tracker.proxy_chain_holder.to_vec().await;
+                report_panic(&self.outbound_manager, &chain, sess, &panic).await;
+                tracker.set_close_reason(CloseReason::Error);
+                return;
+            }
+        };
+
+        tracker.set_close_reason(close_reason(&res));
+        let reason = tracker.close_reason().unwrap_or(CloseReason::Error);
+        match res {
+            Ok((up, down)) => {
+                debug!(
+                    "connection {} closed ({}) with {} bytes up, {} bytes down",
+                    sess, reason, up, down
+                );
+            }
+            Err(err) => {
+                let (err, by) = match err {
+                    CopyBidirectionalError::LeftClosed(err) => (err, " by local"),
+                    CopyBidirectionalError::RightClosed(err) => (err, " by remote"),
+                    CopyBidirectionalError::Other(err) => (err, ""),
+                };
+                match err.kind() {
+                    io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::BrokenPipe => {
+                        debug!(
+                            "connection {} closed ({}) with error {}{}",
+                            sess, reason, err, by
+                        );
+                    }
+                    _ => {
+                        warn!(
+                            "connection {} closed ({}) with error {}{}",
+                            sess, reason, err, by
+                        );
+                    }
                 }
             }
         }
     }
 
+    /// Route a SOCKS5 BIND request to an outbound which listens for the peer
+    /// in `sess.destination`
+    pub async fn bind_stream(&self, mut sess: Session) -> io::Result<PendingBind> {
+        sess.sub_rules = self.sub_rules.clone();
+        let mode = *self.mode.read().await;
+        let (outbound_name, rule) = self.route(&mut sess).await;
+
+        debug!("binding {} via {}[{}]", sess, outbound_name, mode);
+
+        let handler = self
+            .outbound_manager
+            .get_outbound(&outbound_name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("outbound {} not found", outbound_name),
+                )
+            })?;
+        handler.bind_stream(&sess, self.resolver.clone()).await
+    }
+
+    /// Relay an accepted BIND connection
+    pub async fn dispatch_bound(
+        &self,
+        sess: Session,
+        lhs: Box<dyn ClientStream>,
+        outbound_name: &str,
+        rhs: BoxedChainedStream,
+    ) {
+        self.relay_stream(&sess, lhs, rhs, None, outbound_name)
+            .await
+    }
+
     /// Dispatch a UDP packet to outbound handler
     /// returns the close sender
     #[instrument]
     #[must_use]
     pub async fn dispatch_datagram(
         &self,
-        sess: Session,
+        mut sess: Session,
         udp_inbound: AnyInboundDatagram,
     ) -> tokio::sync::oneshot::Sender<u8> {
+        sess.sub_rules = self.sub_rules.clone();
         let outbound_handle_guard = TimeoutUdpSessionManager::new();
 
         let router = self.router.clone();
@@ -251,6 +570,8 @@ impl Dispatcher {
         let resolver = self.resolver.clone();
         let mode = self.mode.clone();
         let manager = self.manager.clone();
+        let udp_nat = self.udp_nat;
+        let sniff_enabled = self.sniffer.enable;
 
         let (mut local_w, mut local_r) = udp_inbound.split();
         let (remote_receiver_w, mut remote_receiver_r) =
@@ -259,17 +580,43 @@ impl Dispatcher {
         let s = sess.clone();
         let ss = sess.clone();
         let t1 = tokio::spawn(async move {
+            let mut quic_hosts = sniffer::QuicHosts::default();
+            let mut udp_protocols = sniffer::UdpProtocols::default();
+            // the fake ips sent to, kept from reuse while the session lasts
+            let mut fake_ips: HashMap<IpAddr, FakeIpGuard> = HashMap::new();
             while let Some(packet) = local_r.next().await {
                 let mut sess = sess.clone();
                 sess.source = packet.src_addr.clone().must_into_socket_addr();
+                // replies come from where the client sent to
+                let sent_to = packet.dst_addr.clone();
+                sess.destination = sent_to.clone();
+                let sniff = router.sniff_override(&sess).unwrap_or(sniff_enabled);
+                let host = if sniff {
+                    quic_hosts.sniff(sess.source, &packet.dst_addr, &packet.data)
+                } else {
+                    None
+                };
 
                 let dest: SocksAddr = match &packet.dst_addr {
                     crate::session::SocksAddr::Ip(socket_addr) => {
                         if resolver.fake_ip_enabled() {
                             let ip = socket_addr.ip();
                             if resolver.is_fake_ip(ip).await {
-                                let host = resolver.reverse_lookup(ip).await;
-                                match host {
+                                let reversed = resolver.reverse_lookup(ip).await;
+                                if reversed.is_some() && !fake_ips.contains_key(&ip)
+                                {
+                                    resolver.retain_fake_ip(ip).await;
+                                    fake_ips.insert(
+                                        ip,
+                                        FakeIpGuard {
+                                            ip,
+                                            resolver: resolver.clone(),
+                                        },
+                                    );
+                                }
+                                // the sniffed name stands in for a fake ip that
+                                // is not known anymore
+                                match reversed.or_else(|| host.clone()) {
                                     Some(host) => (host, socket_addr.port())
                                         .try_into()
                                         .expect("must be valid domain"),
@@ -301,6 +648,24 @@ impl Dispatcher {
                 };
                 sess.destination = dest.clone();
 
+                if sniff {
+                    sess.protocol = match &host {
+                        Some(_) => Some(Protocol::Quic),
+                        None => {
+                            udp_protocols.sniff(sess.source, &sent_to, &packet.data)
+                        }
+                    };
+                }
+                // routed by the sniffed name, but still sent to the ip
+                if let Some(host) = host
+                    && let Some(ip) = sess.destination.ip()
+                {
+                    sess.destination = (host, sess.destination.port())
+                        .try_into()
+                        .expect("must be valid domain");
+                    sess.resolved_ip = Some(ip);
+                }
+
                 // mutate packet for fake ip
                 let mut packet = packet;
                 // resolve is done in OutboundDatagramImpl so it's fine to have
@@ -310,27 +675,54 @@ impl Dispatcher {
 
                 let mode = *mode.read().await;
 
-                let (outbound_name, rule) = match mode {
-                    RunMode::Global => (PROXY_GLOBAL, None),
-                    RunMode::Rule => router.match_route(&mut sess).await,
-                    RunMode::Direct => (PROXY_DIRECT, None),
+                let (outbound_name, rule) = match (sess.outbound.clone(), mode) {
+                    (Some(outbound), _) => (outbound, None),
+                    (None, RunMode::Global) => (PROXY_GLOBAL.to_owned(), None),
+                    (None, RunMode::Rule) => {
+                        let sub_rules = sess.sub_rules.clone();
+                        router.match_route_in(&mut sess, sub_rules.as_deref()).await
+                    }
+                    (None, RunMode::Direct) => (PROXY_DIRECT.to_owned(), None),
                 };
+                let outbound_name = manager.hooks().rule_matched(
+                    &sess,
+                    rule.as_deref(),
+                    outbound_name,
+                );
 
-                let outbound_name = outbound_name.to_string();
+                // the reply still comes from sess.destination, so only the
+                // packet is sent elsewhere
+                if let Some(dst) =
+                    rule.as_ref().and_then(|r| r.destination_override())
+                {
+                    packet.dst_addr = dst.apply(&packet.dst_addr);
+                    debug!(
+                        "rewriting destination of {} to {}",
+                        sess, packet.dst_addr
+                    );
+                }
 
                 debug!("dispatching {} to {}[{}]", sess, outbound_name, mode);
 
                 let remote_receiver_w = remote_receiver_w.clone();
+                let dst = sent_to;
 
                 let mgr = outbound_manager.clone();
-                let handler =
-                    mgr.get_outbound(&outbound_name).unwrap_or_else(|| {
+                let handler = match mgr.get_outbound(&outbound_name) {
+                    Some(handler) => handler,
+                    // the outbound pinned by the inbound is never swapped
+                    None if sess.outbound.is_some() => {
+                        warn!("outbound {} of {} not found", outbound_name, sess);
+                        continue;
+                    }
+                    None => {
                         debug!(
                             "unknown rule: {}, fallback to direct",
                             outbound_name
                         );
                         mgr.get_outbound(PROXY_DIRECT).unwrap()
-                    });
+                    }
+                };
 
                 match outbound_handle_guard
                     .get_outbound_sender_mut(
@@ -345,10 +737,25 @@ impl Dispatcher {
                 {
                     None => {
                         debug!("building {} outbound datagram connecting", sess);
-                        let outbound_datagram = match handler
-                            .connect_datagram(&sess, resolver.clone())
-                            .await
-                        {
+                        let outbound_datagram = connect_isolated(
+                            handler.connect_datagram(&sess, resolver.clone()),
+                            &mgr,
+                            &outbound_name,
+                            &sess,
+                        )
+                        .await;
+                        mgr.report_dial(
+                            &outbound_name,
+                            &sess,
+                            outbound_datagram.is_ok(),
+                        )
+                        .await;
+                        mgr.report_connect(
+                            &outbound_name,
+                            outbound_datagram.as_ref().err(),
+                        )
+                        .await;
+                        let outbound_datagram = match outbound_datagram {
                             Ok(v) => v,
                             Err(err) => {
                                 error!("failed to connect outbound: {}", err);
@@ -362,49 +769,119 @@ impl Dispatcher {
                             outbound_datagram,
                             manager.clone(),
                             sess.clone(),
-                            rule,
+                            rule.as_deref(),
                         )
                         .await;
+                        let tracker = outbound_datagram.tracker_info();
+                        let active = mgr.track_connection(tracker.clone()).await;
 
                         let (mut remote_w, mut remote_r) = outbound_datagram.split();
                         let (remote_sender, mut remote_forwarder) =
-                            tokio::sync::mpsc::channel::<UdpPacket>(32);
+                            tokio::sync::mpsc::channel::<(UdpPacket, SocksAddr)>(32);
+                        // locked by both relays, either may panic holding it
+                        let peers =
+                            Arc::new(std::sync::Mutex::new(UdpPeers::default()));
 
                         // remote -> local
+                        let r_peers = peers.clone();
+                        let (r_mgr, w_mgr) = (mgr.clone(), mgr.clone());
+                        let (w_sess, w_tracker) = (sess.clone(), tracker.clone());
+                        let w_resolver = resolver.clone();
                         let r_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_r.next().await {
-                                // NAT
-                                let mut packet = packet;
-                                packet.src_addr = sess.destination.clone();
-                                packet.dst_addr = sess.source.into();
-
-                                debug!(
-                                    "UDP NAT for packet: {:?}, session: {}",
-                                    packet, sess
-                                );
-                                match remote_receiver_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        warn!(
-                                            "failed to send packet to local: {}",
-                                            err
+                            // counted as active until the session is closed
+                            let _active = active;
+                            let relay = async {
+                                while let Some(packet) = remote_r.next().await {
+                                    // NAT
+                                    let mut packet = packet;
+                                    let Some(src) = r_peers
+                                        .lock()
+                                        .unwrap_or_else(PoisonError::into_inner)
+                                        .reply_source(&packet.src_addr, udp_nat)
+                                    else {
+                                        debug!(
+                                            "dropping packet from {} not sent to, \
+                                             session: {}",
+                                            packet.src_addr, sess
                                         );
+                                        continue;
+                                    };
+                                    packet.src_addr = src;
+                                    packet.dst_addr = sess.source.into();
+
+                                    debug!(
+                                        "UDP NAT for packet: {:?}, session: {}",
+                                        packet, sess
+                                    );
+                                    match remote_receiver_w.send(packet).await {
+                                        Ok(_) => {}
+                                        Err(err) => {
+                                            warn!(
+                                                "failed to send packet to local: {}",
+                                                err
+                                            );
+                                        }
                                     }
                                 }
+                            };
+                            if let Err(panic) = catch_panic(relay).await {
+                                let chain =
+                                    tracker.proxy_chain_holder.to_vec().await;
+                                report_panic(&r_mgr, &chain, &sess, &panic).await;
+                                tracker.set_close_reason(CloseReason::Error);
+                                return;
                             }
+                            tracker.set_close_reason(CloseReason::UpstreamReset);
                         });
                         // local -> remote
                         let w_handle = tokio::spawn(async move {
-                            while let Some(packet) = remote_forwarder.recv().await {
-                                match remote_w.send(packet).await {
-                                    Ok(_) => {}
-                                    Err(err) => {
+                            let relay = async {
+                                while let Some((packet, dst)) =
+                                    remote_forwarder.recv().await
+                                {
+                                    let new_host = match &packet.dst_addr {
+                                        SocksAddr::Domain(host, _) => Some(host)
+                                            .filter(|x| {
+                                                !peers
+                                                    .lock()
+                                                    .unwrap_or_else(
+                                                        PoisonError::into_inner,
+                                                    )
+                                                    .knows(x)
+                                            }),
+                                        SocksAddr::Ip(_) => None,
+                                    };
+                                    let resolved = match new_host {
+                                        Some(host) => {
+                                            resolve_peer(&w_resolver, host).await
+                                        }
+                                        None => vec![],
+                                    };
+                                    peers
+                                        .lock()
+                                        .unwrap_or_else(PoisonError::into_inner)
+                                        .add(&packet.dst_addr, &resolved, dst);
+                                    // the packets queued behind are fed too,
+                                    // for the datagram to send them together
+                                    let res = match remote_w.feed(packet).await {
+                                        Ok(_) if remote_forwarder.is_empty() => {
+                                            remote_w.flush().await
+                                        }
+                                        res => res,
+                                    };
+                                    if let Err(err) = res {
                                         warn!(
                                             "failed to send packet to remote: {}",
                                             err
                                         );
                                     }
                                 }
+                            };
+                            if let Err(panic) = catch_panic(relay).await {
+                                let chain =
+                                    w_tracker.proxy_chain_holder.to_vec().await;
+                                report_panic(&w_mgr, &chain, &w_sess, &panic).await;
+                                w_tracker.set_close_reason(CloseReason::Error);
                             }
                         });
 
@@ -418,14 +895,14 @@ impl Dispatcher {
                             )
                             .await;
 
-                        match remote_sender.send(packet).await {
+                        match remote_sender.send((packet, dst)).await {
                             Ok(_) => {}
                             Err(err) => {
                                 error!("failed to send packet to remote: {}", err);
                             }
                         };
                     }
-                    Some(handle) => match handle.send(packet).await {
+                    Some(handle) => match handle.send((packet, dst)).await {
                         // TODO: need to reset when GLOBAL select is changed
                         Ok(_) => {
                             debug!("reusing {} sent to remote", sess);
@@ -472,7 +949,147 @@ impl Dispatcher {
     }
 }
 
-type OutboundPacketSender = tokio::sync::mpsc::Sender<UdpPacket>; // outbound packet sender
+/// Runs `f`, a panic in it is caught and returned as its message, so that
+/// it ends the session only rather than the task handling it. Built with
+/// `panic = "abort"`, as the release profile is, nothing is caught
+async fn catch_panic<T>(f: impl Future<Output = T>) -> Result<T, String> {
+    AssertUnwindSafe(f).catch_unwind().await.map_err(|e| {
+        e.downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| e.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_owned())
+    })
+}
+
+/// `connect` through `outbound`, failing if it panics
+async fn connect_isolated<T>(
+    connect: impl Future<Output = io::Result<T>>,
+    mgr: &ThreadSafeOutboundManager,
+    outbound: &str,
+    sess: &Session,
+) -> io::Result<T> {
+    match catch_panic(connect).await {
+        Ok(r) => r,
+        Err(panic) => {
+            report_panic(mgr, &[outbound.to_owned()], sess, &panic).await;
+            Err(io::Error::other(format!("{} panicked", outbound)))
+        }
+    }
+}
+
+/// logs a panic of `sess` in the outbounds `names`, counted against them
+async fn report_panic(
+    mgr: &ThreadSafeOutboundManager,
+    names: &[String],
+    sess: &Session,
+    panic: &str,
+) {
+    error!("{} panicked in {}: {}", sess, names.join(" -> "), panic);
+    for name in names {
+        mgr.report_panic(name).await;
+    }
+}
+
+// outbound packet sender, with the destination the client sent the packet to
+type OutboundPacketSender = tokio::sync::mpsc::Sender<(UdpPacket, SocksAddr)>;
+
+/// forgotten all at once beyond this, so a NAT mapping sending to many peers
+/// doesn't grow without bound
+const MAX_UDP_PEERS: usize = 1024;
+
+/// The peers a NAT mapping has sent to, with the destinations the client sent
+/// to them as, so the replies appear to come from those
+#[derive(Default)]
+struct UdpPeers {
+    /// including the addresses the domains sent to resolve to here, the
+    /// proxy most likely resolves them to the same
+    ips: HashMap<IpAddr, SocksAddr>,
+    /// for the replies a proxy gives the domain as the source of
+    domains: HashMap<String, SocksAddr>,
+}
+
+impl UdpPeers {
+    fn knows(&self, host: &str) -> bool {
+        self.domains.contains_key(host)
+    }
+
+    /// `resolved` are the addresses of `sent` if it's a domain
+    fn add(&mut self, sent: &SocksAddr, resolved: &[IpAddr], dst: SocksAddr) {
+        if self.ips.len() + resolved.len() > MAX_UDP_PEERS {
+            self.ips.clear();
+        }
+        match sent {
+            SocksAddr::Ip(addr) => {
+                self.ips.insert(addr.ip(), dst);
+            }
+            SocksAddr::Domain(host, _) => {
+                if self.domains.len() >= MAX_UDP_PEERS {
+                    self.domains.clear();
+                }
+                for ip in resolved {
+                    self.ips.insert(*ip, dst.clone());
+                }
+                self.domains.insert(host.clone(), dst);
+            }
+        }
+    }
+
+    /// the source of a reply from `from` as the client sees it, `None` if
+    /// it's not let through
+    fn reply_source(&self, from: &SocksAddr, nat: UdpNat) -> Option<SocksAddr> {
+        let known = match from {
+            SocksAddr::Ip(addr) => self.ips.get(&addr.ip()),
+            SocksAddr::Domain(host, _) => self.domains.get(host),
+        };
+        match (known, nat) {
+            (Some(SocksAddr::Ip(addr)), _) => {
+                Some(SocketAddr::new(addr.ip(), from.port()).into())
+            }
+            (Some(SocksAddr::Domain(host, _)), _) => {
+                Some(SocksAddr::Domain(host.clone(), from.port()))
+            }
+            (None, UdpNat::FullCone) => Some(from.clone()),
+            (None, UdpNat::AddressRestricted) => None,
+        }
+    }
+}
+
+/// the addresses `host` resolves to here, looked up the first time a NAT
+/// mapping sends to it
+async fn resolve_peer(resolver: &ThreadSafeDNSResolver, host: &str) -> Vec<IpAddr> {
+    let v4 = resolver.resolve_v4(host, false).await.ok().flatten();
+    let v6 = if resolver.ipv6() {
+        resolver.resolve_v6(host, false).await.ok().flatten()
+    } else {
+        None
+    };
+    v4.map(IpAddr::from)
+        .into_iter()
+        .chain(v6.map(IpAddr::from))
+        .collect()
+}
+
+/// why a relay ended, as far as the side that failed tells
+fn close_reason(res: &Result<(u64, u64), CopyBidirectionalError>) -> CloseReason {
+    let (err, reset) = match res {
+        Ok(_) => return CloseReason::Eof,
+        Err(CopyBidirectionalError::LeftClosed(err)) => {
+            (err, CloseReason::ClientReset)
+        }
+        Err(CopyBidirectionalError::RightClosed(err)) => {
+            (err, CloseReason::UpstreamReset)
+        }
+        Err(CopyBidirectionalError::Other(err)) => (err, CloseReason::Error),
+    };
+    match err.kind() {
+        io::ErrorKind::TimedOut => CloseReason::IdleTimeout,
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => reset,
+        _ => CloseReason::Error,
+    }
+}
 
 struct TimeoutUdpSessionManager {
     map: Arc<RwLock<OutboundHandleMap>>,
@@ -616,3 +1233,110 @@ impl Drop for OutboundHandleMap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::{
+        app::dispatcher::CloseReason, common::io::CopyBidirectionalError,
+        config::internal::listener::UdpNat, session::SocksAddr,
+    };
+
+    use super::{UdpPeers, catch_panic, close_reason};
+
+    #[test]
+    fn test_udp_peers() {
+        let mut peers = UdpPeers::default();
+        let fake_ip: SocksAddr = "198.18.0.5:3478".parse().unwrap();
+        peers.add(&"203.0.113.1:3478".parse().unwrap(), &[], fake_ip.clone());
+        let domain = SocksAddr::Domain("dns.example.com".to_owned(), 53);
+        peers.add(&domain, &["192.0.2.1".parse().unwrap()], domain.clone());
+        assert!(peers.knows("dns.example.com"));
+
+        let from = "203.0.113.1:3479".parse().unwrap();
+        assert_eq!(
+            peers.reply_source(&from, UdpNat::AddressRestricted),
+            Some("198.18.0.5:3479".parse().unwrap()),
+            "any port of a peer should be let through"
+        );
+        assert_eq!(
+            peers.reply_source(
+                &"192.0.2.1:53".parse().unwrap(),
+                UdpNat::AddressRestricted
+            ),
+            Some(domain.clone())
+        );
+        assert_eq!(
+            peers.reply_source(&domain, UdpNat::AddressRestricted),
+            Some(domain.clone())
+        );
+        assert_eq!(
+            peers.reply_source(
+                &"192.0.2.9:53".parse().unwrap(),
+                UdpNat::AddressRestricted
+            ),
+            None,
+            "another host on the port of a domain shouldn't be let through"
+        );
+
+        let stranger = "192.0.2.2:4000".parse().unwrap();
+        assert_eq!(
+            peers.reply_source(&stranger, UdpNat::AddressRestricted),
+            None
+        );
+        assert_eq!(
+            peers.reply_source(&stranger, UdpNat::FullCone),
+            Some(stranger)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_catch_panic() {
+        assert_eq!(catch_panic(async { 1 }).await, Ok(1));
+        let panicking = async {
+            if std::hint::black_box(true) {
+                panic!("boom {}", 1);
+            }
+            1
+        };
+        assert_eq!(catch_panic(panicking).await, Err("boom 1".to_owned()));
+        let panicking = async {
+            std::panic::panic_any(1);
+        };
+        assert_eq!(
+            catch_panic(panicking).await,
+            Err("unknown panic".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_close_reason() {
+        let err = |kind: io::ErrorKind| io::Error::from(kind);
+        assert_eq!(close_reason(&Ok((1, 2))), CloseReason::Eof);
+        assert_eq!(
+            close_reason(&Err(CopyBidirectionalError::LeftClosed(err(
+                io::ErrorKind::ConnectionReset
+            )))),
+            CloseReason::ClientReset
+        );
+        assert_eq!(
+            close_reason(&Err(CopyBidirectionalError::RightClosed(err(
+                io::ErrorKind::BrokenPipe
+            )))),
+            CloseReason::UpstreamReset
+        );
+        assert_eq!(
+            close_reason(&Err(CopyBidirectionalError::RightClosed(err(
+                io::ErrorKind::TimedOut
+            )))),
+            CloseReason::IdleTimeout
+        );
+        assert_eq!(
+            close_reason(&Err(CopyBidirectionalError::Other(err(
+                io::ErrorKind::InvalidData
+            )))),
+            CloseReason::Error
+        );
+    }
+}
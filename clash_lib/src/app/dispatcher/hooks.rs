@@ -0,0 +1,213 @@
+//! Hooks for applications embedding clash, to account for or decide on the
+//! connections without forking the dispatcher.
+
+use std::sync::Arc;
+
+use tracing::warn;
+
+use crate::{
+    Error,
+    app::{outbound::manager::OutboundManager, router::RuleMatcher},
+    session::Session,
+};
+
+use super::TrackerInfo;
+
+/// Called inline as connections are routed and closed, so they should return
+/// quickly. All of them do nothing by default.
+pub trait DispatcherHook: Send + Sync {
+    /// a connection is connected, or a UDP session to an outbound is set up,
+    /// and shows in `/connections` from now on
+    fn on_session_start(&self, _info: &TrackerInfo) {}
+
+    /// the outbounds `on_rule_matched` may return, which the config has to
+    /// have as it's loaded
+    fn targets(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// `outbound` is picked for `sess` by the rule of `rule_type` and
+    /// `payload`, which are empty if the mode or the inbound picked it.
+    /// The name of another outbound of `targets`, e.g. `REJECT`, can be
+    /// returned to use it instead. This is called for each UDP packet, as
+    /// each is routed
+    fn on_rule_matched(
+        &self,
+        _sess: &Session,
+        _rule_type: &str,
+        _payload: &str,
+        _outbound: &str,
+    ) -> Option<String> {
+        None
+    }
+
+    /// a connection that started is closed, `info` has its final byte counts
+    fn on_session_end(&self, _info: &TrackerInfo) {}
+}
+
+/// The hooks of the dispatcher, called in order, with their `targets`
+#[derive(Clone, Default)]
+pub struct Hooks(Arc<[(Arc<dyn DispatcherHook>, Vec<String>)]>);
+
+impl Hooks {
+    pub fn new(hooks: Vec<Arc<dyn DispatcherHook>>) -> Self {
+        Self(
+            hooks
+                .into_iter()
+                .map(|hook| {
+                    let targets = hook.targets();
+                    (hook, targets)
+                })
+                .collect(),
+        )
+    }
+
+    /// every target of the hooks is an outbound of `outbound_manager`
+    pub fn check(&self, outbound_manager: &OutboundManager) -> Result<(), Error> {
+        for target in self.0.iter().flat_map(|(_, targets)| targets) {
+            if outbound_manager.get_outbound(target).is_none() {
+                return Err(Error::InvalidConfig(format!(
+                    "outbound {} of a dispatcher hook not found",
+                    target
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn session_start(&self, info: &TrackerInfo) {
+        for (hook, _) in self.0.iter() {
+            hook.on_session_start(info);
+        }
+    }
+
+    /// the outbound to use, that of the last hook which changes it
+    pub fn rule_matched(
+        &self,
+        sess: &Session,
+        rule: Option<&dyn RuleMatcher>,
+        outbound: String,
+    ) -> String {
+        if self.0.is_empty() {
+            return outbound;
+        }
+        let rule_type = rule.map(|x| x.type_name()).unwrap_or_default();
+        let payload = rule.map(|x| x.payload()).unwrap_or_default();
+        self.0.iter().fold(outbound, |outbound, (hook, targets)| {
+            match hook.on_rule_matched(sess, rule_type, &payload, &outbound) {
+                Some(target) if targets.contains(&target) => target,
+                Some(target) => {
+                    warn!("a dispatcher hook returned {}, not a target", target);
+                    outbound
+                }
+                None => outbound,
+            }
+        })
+    }
+
+    pub fn session_end(&self, info: &TrackerInfo) {
+        for (hook, _) in self.0.iter() {
+            hook.on_session_end(info);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::{app::dispatcher::TrackerInfo, session::Session};
+
+    use super::{DispatcherHook, Hooks};
+
+    #[derive(Default)]
+    struct Counter {
+        started: AtomicUsize,
+        ended: AtomicUsize,
+    }
+
+    impl DispatcherHook for Counter {
+        fn on_session_start(&self, _info: &TrackerInfo) {
+            self.started.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_session_end(&self, _info: &TrackerInfo) {
+            self.ended.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    struct Block(&'static str);
+
+    impl DispatcherHook for Block {
+        fn targets(&self) -> Vec<String> {
+            vec!["REJECT".to_owned()]
+        }
+
+        fn on_rule_matched(
+            &self,
+            sess: &Session,
+            _rule_type: &str,
+            _payload: &str,
+            _outbound: &str,
+        ) -> Option<String> {
+            (sess.destination.domain() == Some(self.0)).then(|| "REJECT".to_owned())
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let counter = Arc::new(Counter::default());
+        let hooks = Hooks::new(vec![
+            counter.clone() as Arc<dyn DispatcherHook>,
+            Arc::new(Block("ads.example.com")),
+        ]);
+
+        let info = TrackerInfo::default();
+        hooks.session_start(&info);
+        hooks.session_end(&info);
+        assert_eq!(counter.started.load(Ordering::Relaxed), 1);
+        assert_eq!(counter.ended.load(Ordering::Relaxed), 1);
+
+        let mut sess = Session {
+            destination: ("ads.example.com".to_owned(), 443).try_into().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            hooks.rule_matched(&sess, None, "PROXY".to_owned()),
+            "REJECT"
+        );
+        assert_eq!(
+            Hooks::default().rule_matched(&sess, None, "PROXY".to_owned()),
+            "PROXY"
+        );
+        sess.destination = ("example.com".to_owned(), 443).try_into().unwrap();
+        assert_eq!(hooks.rule_matched(&sess, None, "PROXY".to_owned()), "PROXY");
+    }
+
+    struct Stray;
+
+    impl DispatcherHook for Stray {
+        fn on_rule_matched(
+            &self,
+            _sess: &Session,
+            _rule_type: &str,
+            _payload: &str,
+            _outbound: &str,
+        ) -> Option<String> {
+            Some("MISSING".to_owned())
+        }
+    }
+
+    #[test]
+    fn test_hooks_undeclared_target() {
+        let hooks = Hooks::new(vec![Arc::new(Stray) as Arc<dyn DispatcherHook>]);
+        assert_eq!(
+            hooks.rule_matched(&Session::default(), None, "PROXY".to_owned()),
+            "PROXY",
+            "an outbound not in the targets should be ignored"
+        );
+    }
+}
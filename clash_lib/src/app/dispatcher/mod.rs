@@ -1,9 +1,18 @@
+mod capture;
+mod dest_limit;
 mod dispatcher_impl;
+mod hooks;
+mod sniffer;
 mod statistics_manager;
 mod tracked;
+mod usage;
 
-pub use dispatcher_impl::Dispatcher;
-pub use statistics_manager::Manager as StatisticsManager;
+pub use capture::{CaptureManager, CaptureSettings};
+pub use dispatcher_impl::{ConnectedStream, Dispatcher};
+pub use hooks::{DispatcherHook, Hooks};
+pub use statistics_manager::{
+    CloseReason, Manager as StatisticsManager, TrackerInfo,
+};
 #[allow(unused)]
 pub use tracked::{
     BoxedChainedDatagram, BoxedChainedStream, ChainedDatagram,
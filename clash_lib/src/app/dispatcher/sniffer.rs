@@ -0,0 +1,716 @@
+//! `sniffer`: the protocol of a connection is told from the first bytes the
+//! client sends, or from the first packet of each UDP flow, for the
+//! `PROTOCOL` rules. The server
+//! name of QUIC flows is read from their Initial packets.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray};
+use aes_gcm::{
+    Aes128Gcm,
+    aead::{Aead, Payload},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    proxy::ClientStream,
+    session::{Network, Protocol, Session, SocksAddr},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// how long a TCP connection waits for the client to send something
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(100);
+const SNIFF_LEN: usize = 2048;
+
+const BITTORRENT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+const UTP_HEADER_LEN: usize = 20;
+/// the type ST_SYN and version 1
+const UTP_SYN: u8 = 0x41;
+const UTP_SELECTIVE_ACK: u8 = 1;
+const MAX_UDP_FLOWS: usize = 4096;
+
+const QUIC_PORT: u16 = 443;
+const QUIC_V1: u32 = 1;
+/// RFC 9001 5.2
+const QUIC_V1_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4,
+    0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+];
+const MAX_QUIC_FLOWS: usize = 1024;
+/// the CRYPTO data kept of a flow until its ClientHello goes as far as the
+/// server name
+const MAX_CRYPTO_LEN: usize = 16 * 1024;
+
+type CryptoFrames = Vec<(u64, Vec<u8>)>;
+
+/// the protocol of `data`, the first bytes of a stream or a whole packet
+pub fn sniff(data: &[u8], network: Network) -> Option<Protocol> {
+    let bittorrent = match network {
+        Network::Tcp => data.starts_with(BITTORRENT_HANDSHAKE),
+        Network::Udp => is_dht(data) || is_utp_syn(data),
+    };
+    bittorrent.then_some(Protocol::BitTorrent)
+}
+
+/// a bencoded KRPC message of the DHT, with its `y` key
+fn is_dht(data: &[u8]) -> bool {
+    data.starts_with(b"d1:")
+        && data.ends_with(b"e")
+        && data.windows(5).any(|w| w == b"1:y1:")
+}
+
+/// a uTP SYN, the first packet of a connection, of version 1 and whose
+/// extensions end where the packet does, as it carries no data
+fn is_utp_syn(data: &[u8]) -> bool {
+    if data.len() < UTP_HEADER_LEN || data[0] != UTP_SYN {
+        return false;
+    }
+    let mut extension = data[1];
+    let mut at = UTP_HEADER_LEN;
+    while extension != 0 {
+        // selective acks and the extension bits are the only ones defined
+        if extension > 2 {
+            return false;
+        }
+        let Some(&[next, len]) = data.get(at..at + 2) else {
+            return false;
+        };
+        // a bitmask of a multiple of 32 bits
+        if extension == UTP_SELECTIVE_ACK && (len == 0 || len % 4 != 0) {
+            return false;
+        }
+        extension = next;
+        at += 2 + len as usize;
+    }
+    at == data.len()
+}
+
+/// The protocols of UDP flows, told once by the first packet of each, as
+/// a packet in the middle of a flow can look like anything
+#[derive(Default)]
+pub struct UdpProtocols {
+    flows: HashMap<(SocketAddr, SocksAddr), Option<Protocol>>,
+}
+
+impl UdpProtocols {
+    /// the protocol of the flow from `src` to `dst` that `data` is sent on
+    pub fn sniff(
+        &mut self,
+        src: SocketAddr,
+        dst: &SocksAddr,
+        data: &[u8],
+    ) -> Option<Protocol> {
+        let flow = (src, dst.clone());
+        if let Some(protocol) = self.flows.get(&flow) {
+            return *protocol;
+        }
+        if self.flows.len() >= MAX_UDP_FLOWS {
+            self.flows.clear();
+        }
+        let protocol = sniff(data, Network::Udp);
+        self.flows.insert(flow, protocol);
+        protocol
+    }
+}
+
+/// The server names sniffed off QUIC flows, as only their Initial packets
+/// carry one and the packets after should be routed the same
+#[derive(Default)]
+pub struct QuicHosts {
+    hosts: HashMap<(SocketAddr, SocketAddr), String>,
+    /// the CRYPTO frames of the flows whose ClientHello spans Initial
+    /// packets, e.g. with post-quantum key shares
+    pending: HashMap<(SocketAddr, SocketAddr), CryptoFrames>,
+}
+
+impl QuicHosts {
+    /// the server name of the flow from `src` to `dst` that `data` is sent on,
+    /// where `dst` isn't a domain already
+    pub fn sniff(
+        &mut self,
+        src: SocketAddr,
+        dst: &SocksAddr,
+        data: &[u8],
+    ) -> Option<String> {
+        let SocksAddr::Ip(dst) = dst else {
+            return None;
+        };
+        if dst.port() != QUIC_PORT {
+            return None;
+        }
+        let flow = (src, *dst);
+        let Some(mut frames) = initial_crypto(data) else {
+            return self.hosts.get(&flow).cloned();
+        };
+        if let Some(earlier) = self.pending.remove(&flow) {
+            frames.extend(earlier);
+        }
+        match server_name(&crypto_data(&frames)) {
+            Some(host) => {
+                if self.hosts.len() >= MAX_QUIC_FLOWS
+                    && !self.hosts.contains_key(&flow)
+                {
+                    self.hosts.clear();
+                }
+                self.hosts.insert(flow, host.clone());
+                Some(host)
+            }
+            None if self.hosts.contains_key(&flow) => self.hosts.get(&flow).cloned(),
+            None => {
+                if frames.iter().map(|(_, x)| x.len()).sum::<usize>()
+                    <= MAX_CRYPTO_LEN
+                {
+                    if self.pending.len() >= MAX_QUIC_FLOWS {
+                        self.pending.clear();
+                    }
+                    self.pending.insert(flow, frames);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// the server name in the ClientHello of a QUIC v1 Initial packet, if the
+/// part of it in this packet goes as far as the name
+pub fn sniff_quic(data: &[u8]) -> Option<String> {
+    server_name(&crypto_data(&initial_crypto(data)?))
+}
+
+/// the CRYPTO frames of a QUIC v1 Initial packet from a client
+fn initial_crypto(data: &[u8]) -> Option<CryptoFrames> {
+    let mut r = Reader::new(data);
+    let first = r.u8()?;
+    // a long header of the Initial type
+    if first & 0xf0 != 0xc0 || r.u32()? != QUIC_V1 {
+        return None;
+    }
+    let len = r.u8()? as usize;
+    if len > 20 {
+        return None;
+    }
+    let dcid = r.bytes(len)?;
+    let len = r.u8()? as usize;
+    r.bytes(len)?;
+    let len = r.varint()? as usize;
+    r.bytes(len)?;
+    let len = r.varint()? as usize;
+    let pn_offset = r.at;
+    let end = pn_offset.checked_add(len).filter(|x| *x <= data.len())?;
+
+    let keys = InitialKeys::client(dcid);
+    let mask = keys.mask(data.get(pn_offset + 4..pn_offset + 20)?);
+    let mut header = data[..pn_offset].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    let pn_len = (header[0] & 0x03) as usize + 1;
+    if pn_offset + pn_len > end {
+        return None;
+    }
+    let mut nonce = keys.iv;
+    let mut pn = 0u64;
+    for (i, b) in data[pn_offset..pn_offset + pn_len].iter().enumerate() {
+        let b = b ^ mask[1 + i];
+        header.push(b);
+        pn = (pn << 8) | b as u64;
+    }
+    for (n, p) in nonce[4..].iter_mut().zip(pn.to_be_bytes()) {
+        *n ^= p;
+    }
+
+    let payload = Aes128Gcm::new(GenericArray::from_slice(&keys.key))
+        .decrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &data[pn_offset + pn_len..end],
+                aad: &header,
+            },
+        )
+        .ok()?;
+    Some(crypto_frames(&payload))
+}
+
+/// The keys of the Initial packets from the client, RFC 9001 5.2
+struct InitialKeys {
+    key: [u8; 16],
+    iv: [u8; 12],
+    hp: [u8; 16],
+}
+
+impl InitialKeys {
+    fn client(dcid: &[u8]) -> Self {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&QUIC_V1_SALT)
+            .expect("hmac takes keys of any length");
+        mac.update(dcid);
+        let mut initial = [0u8; 32];
+        initial.copy_from_slice(&mac.finalize().into_bytes());
+
+        let secret = expand_label(&initial, b"client in");
+        Self {
+            key: expand_label(&secret, b"quic key"),
+            iv: expand_label(&secret, b"quic iv"),
+            hp: expand_label(&secret, b"quic hp"),
+        }
+    }
+
+    /// the header protection mask for the `sample` of the ciphertext
+    fn mask(&self, sample: &[u8]) -> [u8; 16] {
+        let mut block = GenericArray::clone_from_slice(sample);
+        aes::Aes128::new(GenericArray::from_slice(&self.hp))
+            .encrypt_block(&mut block);
+        let mut mask = [0u8; 16];
+        mask.copy_from_slice(&block);
+        mask
+    }
+}
+
+/// HKDF-Expand-Label of TLS 1.3 with no context, for at most one block
+fn expand_label<const N: usize>(secret: &[u8; 32], label: &[u8]) -> [u8; N] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(secret)
+        .expect("hmac takes keys of any length");
+    mac.update(&(N as u16).to_be_bytes());
+    mac.update(&[(b"tls13 ".len() + label.len()) as u8]);
+    mac.update(b"tls13 ");
+    mac.update(label);
+    mac.update(&[0, 1]);
+    let mut out = [0u8; N];
+    out.copy_from_slice(&mac.finalize().into_bytes()[..N]);
+    out
+}
+
+/// the CRYPTO frames of a packet, up to a frame not allowed in Initial
+/// packets
+fn crypto_frames(payload: &[u8]) -> CryptoFrames {
+    let mut r = Reader::new(payload);
+    let mut frames = vec![];
+    while let Some(typ) = r.varint() {
+        if frame(&mut r, typ, &mut frames).is_none() {
+            break;
+        }
+    }
+    frames
+        .into_iter()
+        .map(|(offset, bytes)| (offset, bytes.to_vec()))
+        .collect()
+}
+
+/// the CRYPTO `frames` put in order, up to the first gap
+fn crypto_data(frames: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut frames: Vec<_> = frames.iter().collect();
+    frames.sort_by_key(|(offset, _)| *offset);
+    let mut data = vec![];
+    for (offset, bytes) in frames {
+        let Some(skip) = (data.len() as u64).checked_sub(*offset) else {
+            break;
+        };
+        data.extend_from_slice(bytes.get(skip as usize..).unwrap_or_default());
+    }
+    data
+}
+
+/// skips a frame allowed in Initial packets, or keeps it if it's CRYPTO
+fn frame<'a>(
+    r: &mut Reader<'a>,
+    typ: u64,
+    frames: &mut Vec<(u64, &'a [u8])>,
+) -> Option<()> {
+    match typ {
+        // PADDING, PING
+        0x00 | 0x01 => {}
+        // ACK, with ECN counts or not
+        0x02 | 0x03 => {
+            r.varint()?;
+            r.varint()?;
+            let ranges = r.varint()?;
+            r.varint()?;
+            for _ in 0..ranges {
+                r.varint()?;
+                r.varint()?;
+            }
+            if typ == 0x03 {
+                for _ in 0..3 {
+                    r.varint()?;
+                }
+            }
+        }
+        // CRYPTO
+        0x06 => {
+            let offset = r.varint()?;
+            let len = r.varint()? as usize;
+            frames.push((offset, r.bytes(len)?));
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// the `server_name` of a TLS ClientHello, which may be cut short after it
+fn server_name(hello: &[u8]) -> Option<String> {
+    let mut r = Reader::new(hello);
+    if r.u8()? != 1 {
+        return None;
+    }
+    // length, version and random
+    r.bytes(3 + 2 + 32)?;
+    let len = r.u8()? as usize;
+    r.bytes(len)?;
+    let len = r.u16()? as usize;
+    r.bytes(len)?;
+    let len = r.u8()? as usize;
+    r.bytes(len)?;
+    r.u16()?;
+    loop {
+        let typ = r.u16()?;
+        let len = r.u16()? as usize;
+        let extension = r.bytes(len)?;
+        if typ == 0 {
+            let mut r = Reader::new(extension);
+            // the list length, and the host_name type
+            r.u16()?;
+            if r.u8()? != 0 {
+                return None;
+            }
+            let len = r.u16()? as usize;
+            let name = std::str::from_utf8(r.bytes(len)?).ok()?;
+            // it's routed on as a domain
+            let valid = !name.is_empty()
+                && name.len() <= 0xff
+                && name.parse::<IpAddr>().is_err()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b));
+            return valid.then(|| name.to_owned());
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, at: 0 }
+    }
+
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.at..self.at.checked_add(n)?)?;
+        self.at += n;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|x| x[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    /// a QUIC variable-length integer
+    fn varint(&mut self) -> Option<u64> {
+        let first = self.u8()?;
+        let rest = self.bytes((1 << (first >> 6)) - 1)?;
+        Some(
+            rest.iter()
+                .fold((first & 0x3f) as u64, |v, b| (v << 8) | *b as u64),
+        )
+    }
+}
+
+/// `lhs` with what was read off it to sniff put back
+pub async fn sniff_stream(
+    sess: &mut Session,
+    mut lhs: Box<dyn ClientStream>,
+) -> Box<dyn ClientStream> {
+    let mut buf = vec![0u8; SNIFF_LEN];
+    match tokio::time::timeout(SNIFF_TIMEOUT, lhs.read(&mut buf)).await {
+        Ok(Ok(n)) if n > 0 => {
+            buf.truncate(n);
+            sess.protocol = sniff(&buf, Network::Tcp);
+            Box::new(Sniffed {
+                read: buf,
+                at: 0,
+                inner: lhs,
+            })
+        }
+        _ => lhs,
+    }
+}
+
+struct Sniffed {
+    read: Vec<u8>,
+    at: usize,
+    inner: Box<dyn ClientStream>,
+}
+
+impl AsyncRead for Sniffed {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.at < this.read.len() {
+            let n = buf.remaining().min(this.read.len() - this.at);
+            buf.put_slice(&this.read[this.at..this.at + n]);
+            this.at += n;
+            if this.at == this.read.len() {
+                this.read = Vec::new();
+                this.at = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Sniffed {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::{KeyInit, generic_array::GenericArray};
+    use aes_gcm::{
+        Aes128Gcm,
+        aead::{Aead, Payload},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::session::{Network, Protocol, Session};
+
+    use super::{
+        InitialKeys, QuicHosts, UTP_HEADER_LEN, UdpProtocols, sniff, sniff_quic,
+        sniff_stream,
+    };
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// an Initial packet with the packet number 1
+    fn initial(dcid: &[u8], payload: &[u8]) -> Vec<u8> {
+        let keys = InitialKeys::client(dcid);
+        let mut packet = vec![0xc1, 0, 0, 0, 1, dcid.len() as u8];
+        packet.extend_from_slice(dcid);
+        // no source connection id and no token
+        packet.extend_from_slice(&[0, 0]);
+        let len = 2 + payload.len() + 16;
+        packet.extend_from_slice(&(0x4000 | len as u16).to_be_bytes());
+        let pn_offset = packet.len();
+        packet.extend_from_slice(&[0, 1]);
+
+        let mut nonce = keys.iv;
+        nonce[11] ^= 1;
+        let sealed = Aes128Gcm::new(GenericArray::from_slice(&keys.key))
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: payload,
+                    aad: &packet,
+                },
+            )
+            .unwrap();
+        packet.extend_from_slice(&sealed);
+
+        let mask = keys.mask(&packet[pn_offset + 4..pn_offset + 20]);
+        packet[0] ^= mask[0] & 0x0f;
+        packet[pn_offset] ^= mask[1];
+        packet[pn_offset + 1] ^= mask[2];
+        packet
+    }
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = (name.len() as u16 + 3).to_be_bytes().to_vec();
+        sni.push(0);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+        // supported_versions, then server_name
+        let mut extensions = vec![0x00, 0x2b, 0, 3, 2, 0x03, 0x04, 0, 0];
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        // no session id, one cipher suite, no compression
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut hello = vec![1, 0];
+        hello.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&body);
+        hello
+    }
+
+    fn crypto_frame(offset: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x06, offset];
+        frame.extend_from_slice(&(0x4000 | data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn test_initial_keys() {
+        // RFC 9001 A.1
+        let keys = InitialKeys::client(&hex("8394c8f03e515708"));
+        assert_eq!(keys.key.to_vec(), hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(keys.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(keys.hp.to_vec(), hex("9f50449e04a0e810283a1e9933adedd2"));
+    }
+
+    #[test]
+    fn test_sniff_quic() {
+        let dcid = hex("8394c8f03e515708");
+        let hello = client_hello("example.com");
+        // the CRYPTO frames out of order, as browsers send them
+        let mut payload = crypto_frame(10, &hello[10..]);
+        payload.extend_from_slice(&[0x01, 0x00]);
+        payload.extend_from_slice(&crypto_frame(0, &hello[..10]));
+        payload.resize(200, 0);
+        let packet = initial(&dcid, &payload);
+        assert_eq!(sniff_quic(&packet).as_deref(), Some("example.com"));
+
+        // the name isn't in this packet yet
+        let mut payload = crypto_frame(0, &hello[..40]);
+        payload.resize(200, 0);
+        assert_eq!(sniff_quic(&initial(&dcid, &payload)), None);
+        let mut corrupted = packet.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(sniff_quic(&corrupted), None);
+        let mut payload = crypto_frame(0, &client_hello("1.1.1.1"));
+        payload.resize(200, 0);
+        assert_eq!(sniff_quic(&initial(&dcid, &payload)), None);
+
+        let mut hosts = QuicHosts::default();
+        let src = "10.0.0.2:50000".parse().unwrap();
+        let dst = "203.0.113.1:443".parse().unwrap();
+        assert_eq!(
+            hosts.sniff(src, &dst, &packet).as_deref(),
+            Some("example.com")
+        );
+        // a short header packet of the same flow
+        assert_eq!(
+            hosts.sniff(src, &dst, &[0x40, 1, 2, 3]).as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            hosts.sniff("10.0.0.2:50001".parse().unwrap(), &dst, &[0x40, 1, 2, 3]),
+            None
+        );
+        assert_eq!(
+            hosts.sniff(src, &"203.0.113.1:8443".parse().unwrap(), &packet),
+            None
+        );
+
+        // a ClientHello over two Initial packets
+        let src = "10.0.0.3:50000".parse().unwrap();
+        let mut first = crypto_frame(0, &hello[..40]);
+        first.resize(200, 0);
+        let mut second = crypto_frame(40, &hello[40..]);
+        second.resize(200, 0);
+        assert_eq!(hosts.sniff(src, &dst, &initial(&dcid, &first)), None);
+        assert_eq!(
+            hosts.sniff(src, &dst, &initial(&dcid, &second)).as_deref(),
+            Some("example.com")
+        );
+        assert!(hosts.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sniff_bittorrent() {
+        let mut handshake = b"\x13BitTorrent protocol".to_vec();
+        handshake.extend_from_slice(&[0; 8]);
+        assert_eq!(sniff(&handshake, Network::Tcp), Some(Protocol::BitTorrent));
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\n", Network::Tcp), None);
+
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(sniff(ping, Network::Udp), Some(Protocol::BitTorrent));
+        // ST_SYN, no extension
+        let mut syn = vec![0x41, 0];
+        syn.extend_from_slice(&[0; 18]);
+        assert_eq!(sniff(&syn, Network::Udp), Some(Protocol::BitTorrent));
+        // a SYN with data after it
+        let mut data = syn.clone();
+        data.push(0);
+        assert_eq!(sniff(&data, Network::Udp), None);
+        // ST_DATA, which a SYN always goes before
+        let mut st_data = syn.clone();
+        st_data[0] = 0x01;
+        assert_eq!(sniff(&st_data, Network::Udp), None);
+        // version 2
+        let mut v2 = syn.clone();
+        v2[0] = 0x42;
+        assert_eq!(sniff(&v2, Network::Udp), None);
+        // a selective ack of 4 bytes, and one longer than the packet
+        let mut ack = syn.clone();
+        ack[1] = 1;
+        ack.extend_from_slice(&[0, 4, 0xff, 0, 0, 0]);
+        assert_eq!(sniff(&ack, Network::Udp), Some(Protocol::BitTorrent));
+        ack.extend_from_slice(&[0, 8, 0xff]);
+        ack[UTP_HEADER_LEN] = 1;
+        assert_eq!(sniff(&ack, Network::Udp), None);
+        assert_eq!(sniff(&[0x16, 0x03, 0x01, 0, 0], Network::Udp), None);
+
+        // decided by the first packet of each flow
+        let mut flows = UdpProtocols::default();
+        let src = "10.0.0.2:6881".parse().unwrap();
+        let peer = "203.0.113.1:6881".parse().unwrap();
+        let other = "203.0.113.2:6881".parse().unwrap();
+        assert_eq!(flows.sniff(src, &peer, &syn), Some(Protocol::BitTorrent));
+        assert_eq!(
+            flows.sniff(src, &peer, &st_data),
+            Some(Protocol::BitTorrent)
+        );
+        assert_eq!(flows.sniff(src, &other, &st_data), None);
+        assert_eq!(flows.sniff(src, &other, &syn), None);
+
+        let (mut client, server) = tokio::io::duplex(64);
+        client.write_all(&handshake).await.unwrap();
+        let mut sess = Session::default();
+        let mut stream = sniff_stream(&mut sess, Box::new(server)).await;
+        assert_eq!(sess.protocol, Some(Protocol::BitTorrent));
+        let mut read = vec![0; handshake.len()];
+        stream.read_exact(&mut read).await.unwrap();
+        assert_eq!(read, handshake);
+    }
+}
@@ -1,19 +1,33 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque, hash_map},
+    fmt,
     sync::{
-        Arc,
-        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock as SyncRwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
+    time::Duration,
 };
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use memory_stats::memory_stats;
 use serde::Serialize;
 use tokio::sync::{Mutex, RwLock, oneshot::Sender};
+use tracing::{debug, info};
 
-use crate::session::Session;
+use crate::{
+    app::{dispatcher::Dispatcher, profile::ThreadSafeCacheFile},
+    session::{Session, Type},
+};
+
+use super::{
+    hooks::Hooks,
+    tracked::Tracked,
+    usage::{Traffic, Usage, UsageSnapshot},
+};
 
-use super::tracked::Tracked;
+/// seconds between the additions of the traffic of the live connections to
+/// the usage
+const USAGE_INTERVAL: u64 = 60;
 
 #[derive(Default, Clone, Debug)]
 pub struct ProxyChain(Arc<RwLock<Vec<String>>>);
@@ -23,6 +37,46 @@ impl ProxyChain {
         let mut chain = self.0.write().await;
         chain.push(s);
     }
+
+    pub async fn to_vec(&self) -> Vec<String> {
+        self.0.read().await.clone()
+    }
+}
+
+/// Why a connection was closed
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CloseReason {
+    /// both sides finished
+    Eof,
+    /// the client reset or broke the connection
+    ClientReset,
+    /// the proxy or the remote reset or broke the connection
+    UpstreamReset,
+    /// no data in time, or a UDP session left idle
+    IdleTimeout,
+    /// closed through the API, by the connection limit, or by switching the
+    /// proxy of a group
+    Killed,
+    /// routed differently by a reloaded config
+    Reload,
+    /// any other error
+    Error,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Eof => "eof",
+            Self::ClientReset => "client reset",
+            Self::UpstreamReset => "upstream reset",
+            Self::IdleTimeout => "idle timeout",
+            Self::Killed => "killed",
+            Self::Reload => "reload",
+            Self::Error => "error",
+        };
+        write!(f, "{}", s)
+    }
 }
 
 #[derive(Serialize, Default)]
@@ -43,11 +97,35 @@ pub struct TrackerInfo {
     pub rule: String,
     #[serde(rename = "rulePayload")]
     pub rule_payload: String,
+    #[serde(rename = "end", skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<DateTime<Utc>>,
+    #[serde(rename = "closeReason", skip_serializing_if = "Option::is_none")]
+    pub close_reason: Option<CloseReason>,
 
     #[serde(skip)]
     pub proxy_chain_holder: ProxyChain,
     #[serde(skip)]
     pub session_holder: Session,
+    /// the first reason set wins, e.g. a killed connection also fails to
+    /// read
+    #[serde(skip)]
+    pub close_reason_holder: OnceLock<CloseReason>,
+    /// of `upload_total` and `download_total`, added to the usage
+    #[serde(skip)]
+    pub counted_upload: AtomicU64,
+    #[serde(skip)]
+    pub counted_download: AtomicU64,
+}
+
+impl TrackerInfo {
+    /// records why the connection is closed unless it's recorded already
+    pub fn set_close_reason(&self, reason: CloseReason) {
+        let _ = self.close_reason_holder.set(reason);
+    }
+
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason_holder.get().copied()
+    }
 }
 
 #[derive(Serialize)]
@@ -56,31 +134,94 @@ pub struct Snapshot {
     download_total: u64,
     upload_total: u64,
     connections: Vec<TrackerInfo>,
+    /// closed since the previous update of the websocket
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    closed: Vec<TrackerInfo>,
     memory: usize,
 }
 
-type ConnectionMap = HashMap<uuid::Uuid, (Tracked, Sender<()>)>;
+/// the live connections, also ordered by start time to close the oldest
+#[derive(Default)]
+struct ConnectionMap {
+    by_id: HashMap<uuid::Uuid, (Tracked, Sender<()>)>,
+    by_start: BTreeSet<(DateTime<Utc>, uuid::Uuid)>,
+}
+
+impl ConnectionMap {
+    fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    fn iter(&self) -> hash_map::Iter<'_, uuid::Uuid, (Tracked, Sender<()>)> {
+        self.by_id.iter()
+    }
+
+    fn values(&self) -> hash_map::Values<'_, uuid::Uuid, (Tracked, Sender<()>)> {
+        self.by_id.values()
+    }
+
+    fn insert(&mut self, item: Tracked, close_notify: Sender<()>) {
+        let id = item.id();
+        self.by_start.insert((item.tracker_info().start_time, id));
+        if let Some((old, _)) = self.by_id.insert(id, (item, close_notify)) {
+            self.by_start.remove(&(old.tracker_info().start_time, id));
+        }
+    }
+
+    fn remove(&mut self, id: &uuid::Uuid) -> Option<(Tracked, Sender<()>)> {
+        let removed = self.by_id.remove(id)?;
+        self.by_start
+            .remove(&(removed.0.tracker_info().start_time, *id));
+        Some(removed)
+    }
+
+    fn drain(&mut self) -> hash_map::Drain<'_, uuid::Uuid, (Tracked, Sender<()>)> {
+        self.by_start.clear();
+        self.by_id.drain()
+    }
+
+    fn oldest(&self) -> Option<uuid::Uuid> {
+        self.by_start.first().map(|(_, id)| *id)
+    }
+}
 
 pub struct Manager {
     connections: Arc<Mutex<ConnectionMap>>,
+    /// recently closed connections, the most recently closed at the back
+    closed: Arc<Mutex<VecDeque<(DateTime<Utc>, Arc<TrackerInfo>)>>>,
+    history_size: AtomicUsize,
+    /// 0 for no limit
+    max_connections: AtomicUsize,
     upload_temp: AtomicU64,
     download_temp: AtomicU64,
     upload_blip: AtomicU64,
     download_blip: AtomicU64,
     upload_total: AtomicU64,
     download_total: AtomicU64,
+    hooks: SyncRwLock<Hooks>,
+    usage: Usage,
 }
 
 impl Manager {
-    pub fn new() -> Arc<Self> {
+    /// `history_size` bounds the closed connection records kept in memory,
+    /// `max_connections` bounds the live connections, the oldest one is
+    /// closed when a new connection would exceed it.
+    pub fn new(history_size: usize, max_connections: Option<usize>) -> Arc<Self> {
         let v = Arc::new(Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
+            connections: Default::default(),
+            closed: Arc::new(Mutex::new(VecDeque::new())),
+            history_size: AtomicUsize::new(history_size),
+            max_connections: AtomicUsize::new(
+                max_connections.map_or(0, |x| x.max(1)),
+            ),
             upload_temp: AtomicU64::new(0),
             download_temp: AtomicU64::new(0),
             upload_blip: AtomicU64::new(0),
             download_blip: AtomicU64::new(0),
             upload_total: AtomicU64::new(0),
             download_total: AtomicU64::new(0),
+            hooks: Default::default(),
+            usage: Default::default(),
         });
         let c = v.clone();
         tokio::spawn(async move {
@@ -89,20 +230,101 @@ impl Manager {
         v
     }
 
+    /// the same manager is kept across reloads, with the limits of the new
+    /// config
+    pub fn set_limits(&self, history_size: usize, max_connections: Option<usize>) {
+        self.history_size.store(history_size, Ordering::Relaxed);
+        self.max_connections
+            .store(max_connections.map_or(0, |x| x.max(1)), Ordering::Relaxed);
+    }
+
+    /// kept across reloads like the manager
+    pub fn set_hooks(&self, hooks: Hooks) {
+        *self.hooks.write().unwrap() = hooks;
+    }
+
+    pub fn hooks(&self) -> Hooks {
+        self.hooks.read().unwrap().clone()
+    }
+
+    /// where the usage is kept, the cache store of each reloaded config
+    pub async fn set_cache_store(&self, cache_store: ThreadSafeCacheFile) {
+        self.usage.set_cache_store(cache_store).await;
+    }
+
+    /// the bytes through each proxy and each rule by day and by month
+    pub fn usage(&self) -> UsageSnapshot {
+        self.usage.snapshot()
+    }
+
+    /// adds the traffic of `t` not added yet to the usage
+    fn count_usage(&self, t: &TrackerInfo, chain: &[String]) {
+        let upload = t.upload_total.load(Ordering::Relaxed);
+        let download = t.download_total.load(Ordering::Relaxed);
+        let traffic = Traffic {
+            upload: upload
+                .saturating_sub(t.counted_upload.swap(upload, Ordering::Relaxed)),
+            download: download.saturating_sub(
+                t.counted_download.swap(download, Ordering::Relaxed),
+            ),
+        };
+        if traffic == Traffic::default() {
+            return;
+        }
+        let rule = match (t.rule.as_str(), t.rule_payload.as_str()) {
+            ("", _) => None,
+            (rule, "") => Some(rule.to_owned()),
+            (rule, payload) => Some(format!("{},{}", rule, payload)),
+        };
+        self.usage
+            .add(chain.first().map(String::as_str), rule.as_deref(), traffic);
+    }
+
     pub async fn track(&self, item: Tracked, close_notify: Sender<()>) {
         let mut connections = self.connections.lock().await;
 
-        connections.insert(item.id(), (item, close_notify));
+        let max = self.max_connections.load(Ordering::Relaxed);
+        if max > 0 {
+            while connections.len() >= max {
+                let Some(id) = connections.oldest() else {
+                    break;
+                };
+                if let Some((t, close_notify)) = connections.remove(&id) {
+                    debug!("connection limit {} reached, closing {}", max, id);
+                    t.tracker_info().set_close_reason(CloseReason::Killed);
+                    let _ = close_notify.send(());
+                }
+            }
+        }
+
+        connections.insert(item, close_notify);
     }
 
     /// Untrack a connection.
     /// this method is not async because it is called in Drop.
-    pub fn untrack(&self, id: uuid::Uuid) {
+    pub fn untrack(self: &Arc<Self>, tracker: &Arc<TrackerInfo>) {
+        let id = tracker.uuid;
         let connections = self.connections.clone();
+        let closed = self.closed.clone();
+        let history_size = self.history_size.load(Ordering::Relaxed);
+        let this = self.clone();
+        let tracker = tracker.clone();
 
         tokio::spawn(async move {
-            let mut connections = connections.lock().await;
-            connections.remove(&id);
+            let chain = tracker.proxy_chain_holder.to_vec().await;
+            this.count_usage(&tracker, &chain);
+            Self::log_access(&tracker, &chain);
+
+            let removed = connections.lock().await.remove(&id);
+            if let Some((t, _)) = removed
+                && history_size > 0
+            {
+                let mut closed = closed.lock().await;
+                while closed.len() >= history_size {
+                    closed.pop_front();
+                }
+                closed.push_back((Utc::now(), t.tracker_info()));
+            }
         });
     }
 
@@ -111,17 +333,132 @@ impl Manager {
 
         tokio::spawn(async move {
             let mut connections = connections.lock().await;
-            if let Some((_, close_notify)) = connections.remove(&id) {
+            if let Some((t, close_notify)) = connections.remove(&id) {
+                t.tracker_info().set_close_reason(CloseReason::Killed);
                 let _ = close_notify.send(());
             }
         });
     }
 
+    /// closes the connections through `group` but not through `proxy`,
+    /// returns how many were closed
+    pub async fn close_switched(&self, group: &str, proxy: &str) -> usize {
+        let mut connections = self.connections.lock().await;
+        let mut switched = vec![];
+        for (id, (t, _)) in connections.iter() {
+            let chain = t.tracker_info().proxy_chain_holder.to_vec().await;
+            if chain.iter().any(|x| x == group) && !chain.iter().any(|x| x == proxy)
+            {
+                switched.push(*id);
+            }
+        }
+        for id in switched.iter() {
+            if let Some((t, close_notify)) = connections.remove(id) {
+                t.tracker_info().set_close_reason(CloseReason::Killed);
+                let _ = close_notify.send(());
+            }
+        }
+        switched.len()
+    }
+
+    /// closes the connections accepted by the inbound of `typ`, returns how
+    /// many were closed
+    pub async fn close_inbound(&self, typ: Type) -> usize {
+        let mut connections = self.connections.lock().await;
+        let ids: Vec<_> = connections
+            .iter()
+            .filter(|(_, (t, _))| t.tracker_info().session_holder.typ == typ)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids.iter() {
+            if let Some((t, close_notify)) = connections.remove(id) {
+                t.tracker_info().set_close_reason(CloseReason::Killed);
+                let _ = close_notify.send(());
+            }
+        }
+        ids.len()
+    }
+
+    /// closes the connections `dispatcher` of a reloaded config wouldn't
+    /// route the same way: the rules pick another outbound, it's gone, or a
+    /// group on the way picks another member now. The others keep their
+    /// handlers and go on. returns how many were closed
+    pub async fn close_changed(&self, dispatcher: &Dispatcher) -> usize {
+        let tracked: Vec<_> = self
+            .connections
+            .lock()
+            .await
+            .iter()
+            .map(|(id, (t, _))| (*id, t.tracker_info()))
+            .collect();
+        let mut changed = vec![];
+        for (id, info) in tracked {
+            let chain = info.proxy_chain_holder.to_vec().await;
+            let mut sess = info.session_holder.clone();
+            let (routed, _) = dispatcher.route(&mut sess).await;
+            let mut picks = HashMap::new();
+            for name in chain.iter() {
+                if let Some(outbound) =
+                    dispatcher.outbound_manager().get_outbound(name)
+                {
+                    picks.insert(name.clone(), outbound.current_proxy(&sess).await);
+                }
+            }
+            if !Self::same_route(&chain, &routed, &picks) {
+                changed.push(id);
+            }
+        }
+
+        let mut connections = self.connections.lock().await;
+        for id in changed.iter() {
+            if let Some((t, close_notify)) = connections.remove(id) {
+                t.tracker_info().set_close_reason(CloseReason::Reload);
+                let _ = close_notify.send(());
+            }
+        }
+        changed.len()
+    }
+
+    /// `chain` starts with the proxy dialed and ends with the outbound of
+    /// the rule, `routed` is the outbound the rules pick now and `picks`
+    /// what each outbound still there picks now, if a group
+    fn same_route(
+        chain: &[String],
+        routed: &str,
+        picks: &HashMap<String, Option<String>>,
+    ) -> bool {
+        let Some(outbound) = chain.last() else {
+            return true;
+        };
+        if outbound != routed || !picks.contains_key(outbound) {
+            return false;
+        }
+        chain.windows(2).all(|pair| match picks.get(&pair[1]) {
+            Some(Some(current)) => *current == pair[0],
+            Some(None) => true,
+            None => false,
+        })
+    }
+
+    /// Waits up to `timeout` for the connections to close on their own,
+    /// returns how many are still open
+    pub async fn wait_closed(&self, timeout: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let open = self.connections.lock().await.len();
+            if open == 0 || tokio::time::Instant::now() >= deadline {
+                return open;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
     pub async fn close_all(&self) {
         let connections = self.connections.clone();
 
         let mut connections = connections.lock().await;
-        for (_, (_, close_notify)) in connections.drain() {
+        for (_, (t, close_notify)) in connections.drain() {
+            t.tracker_info().set_close_reason(CloseReason::Killed);
             let _ = close_notify.send(());
         }
     }
@@ -152,21 +489,7 @@ impl Manager {
         let mut connections = vec![];
         let conns = self.connections.lock().await;
         for (_, v) in conns.iter() {
-            let t = v.0.tracker_info();
-            let chain = t.proxy_chain_holder.0.read().await;
-            connections.push(TrackerInfo {
-                uuid: t.uuid,
-                upload_total: AtomicU64::new(t.upload_total.load(Ordering::Acquire)),
-                download_total: AtomicU64::new(
-                    t.download_total.load(Ordering::Acquire),
-                ),
-                start_time: t.start_time,
-                proxy_chain: chain.clone(),
-                rule: t.rule.clone(),
-                rule_payload: t.rule_payload.clone(),
-                session: t.session_holder.as_map(),
-                ..Default::default()
-            });
+            connections.push(Self::copy_info(&v.0.tracker_info()).await);
         }
 
         Snapshot {
@@ -177,10 +500,82 @@ impl Manager {
                 .upload_total
                 .load(std::sync::atomic::Ordering::Relaxed),
             connections,
+            closed: vec![],
             memory: self.memory_usage(),
         }
     }
 
+    /// The snapshot with the connections closed after `since`, as far as the
+    /// closed connection records go, and `since` moved to the last of them.
+    /// A connection closed while the snapshot is taken is only listed as
+    /// closed.
+    pub async fn snapshot_since(&self, since: &mut DateTime<Utc>) -> Snapshot {
+        let mut snapshot = self.snapshot().await;
+        let closed = self.closed.lock().await;
+        for (end, t) in closed.iter().rev().take_while(|(end, _)| *end > *since) {
+            let mut info = Self::copy_info(t).await;
+            info.end_time = Some(*end);
+            snapshot.closed.push(info);
+        }
+        if let Some((end, _)) = closed.back() {
+            *since = (*since).max(*end);
+        }
+        drop(closed);
+
+        let ids = snapshot
+            .closed
+            .iter()
+            .map(|x| x.uuid)
+            .collect::<HashSet<_>>();
+        snapshot.connections.retain(|x| !ids.contains(&x.uuid));
+        snapshot
+    }
+
+    /// the access log line of a closed connection that went through `chain`
+    fn log_access(tracker: &TrackerInfo, chain: &[String]) {
+        info!(
+            "{} closed ({}) via {} by {} {}, {} bytes up, {} bytes down, {}s",
+            tracker.session_holder,
+            tracker
+                .close_reason()
+                .map_or_else(|| "unknown".to_owned(), |x| x.to_string()),
+            chain.join(" -> "),
+            tracker.rule,
+            tracker.rule_payload,
+            tracker.upload_total.load(Ordering::Relaxed),
+            tracker.download_total.load(Ordering::Relaxed),
+            (Utc::now() - tracker.start_time).num_seconds(),
+        );
+    }
+
+    /// Recently closed connections, most recently closed first.
+    pub async fn closed_snapshot(&self) -> Vec<TrackerInfo> {
+        let closed = self.closed.lock().await;
+        let mut rv = Vec::with_capacity(closed.len());
+        for (end, t) in closed.iter().rev() {
+            let mut info = Self::copy_info(t).await;
+            info.end_time = Some(*end);
+            rv.push(info);
+        }
+        rv
+    }
+
+    async fn copy_info(t: &TrackerInfo) -> TrackerInfo {
+        let chain = t.proxy_chain_holder.0.read().await;
+        TrackerInfo {
+            uuid: t.uuid,
+            upload_total: AtomicU64::new(t.upload_total.load(Ordering::Acquire)),
+            download_total: AtomicU64::new(t.download_total.load(Ordering::Acquire)),
+            start_time: t.start_time,
+            proxy_chain: chain.clone(),
+            rule: t.rule.clone(),
+            rule_payload: t.rule_payload.clone(),
+            session: t.session_holder.as_map(),
+            close_reason: t.close_reason(),
+            ..Default::default()
+        }
+    }
+
     #[allow(dead_code)]
     pub fn reset_statistic(&self) {
         self.upload_temp.store(0, Ordering::Relaxed);
@@ -195,10 +590,37 @@ impl Manager {
         memory_stats().map(|x| x.physical_mem).unwrap_or(0)
     }
 
+    /// the live connections and the closed ones kept for the history
+    pub async fn connection_counts(&self) -> (usize, usize) {
+        let active = self.connections.lock().await.len();
+        (active, self.closed.lock().await.len())
+    }
+
+    /// adds the traffic of the live connections to the usage and stores it
+    async fn count_live_usage(&self) {
+        let trackers: Vec<_> = self
+            .connections
+            .lock()
+            .await
+            .values()
+            .map(|(t, _)| t.tracker_info())
+            .collect();
+        for t in trackers {
+            let chain = t.proxy_chain_holder.to_vec().await;
+            self.count_usage(&t, &chain);
+        }
+        self.usage.flush().await;
+    }
+
     async fn kick_off(&self) {
         let mut ticker = tokio::time::interval(std::time::Duration::from_secs(1));
+        let mut ticks = 0u64;
         loop {
             ticker.tick().await;
+            ticks += 1;
+            if ticks % USAGE_INTERVAL == 0 {
+                self.count_live_usage().await;
+            }
             self.upload_blip
                 .store(self.upload_temp.load(Ordering::Relaxed), Ordering::Relaxed);
             self.upload_temp.store(0, Ordering::Relaxed);
@@ -210,3 +632,114 @@ impl Manager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use chrono::Utc;
+    use tokio::sync::oneshot;
+
+    use super::{CloseReason, Manager, Tracked, TrackerInfo};
+
+    #[tokio::test]
+    async fn test_track_closes_oldest() {
+        let manager = Manager::new(0, Some(2));
+        let now = Utc::now();
+        let mut tracked = vec![];
+        for i in 0..4 {
+            let info = Arc::new(TrackerInfo {
+                uuid: uuid::Uuid::new_v4(),
+                start_time: now + chrono::Duration::seconds(i),
+                ..Default::default()
+            });
+            let (tx, rx) = oneshot::channel();
+            manager.track(Tracked(info.uuid, info.clone()), tx).await;
+            tracked.push((info, rx));
+        }
+
+        assert_eq!(manager.connection_counts().await.0, 2);
+        for (i, (info, rx)) in tracked.iter_mut().enumerate() {
+            if i < 2 {
+                assert!(rx.try_recv().is_ok(), "connection {} not closed", i);
+                assert_eq!(info.close_reason(), Some(CloseReason::Killed));
+            } else {
+                assert!(rx.try_recv().is_err(), "connection {} closed", i);
+                assert_eq!(info.close_reason(), None);
+            }
+        }
+
+        let mut connections = manager.connections.lock().await;
+        assert_eq!(connections.oldest(), Some(tracked[2].0.uuid));
+        connections.remove(&tracked[2].0.uuid);
+        assert_eq!(connections.oldest(), Some(tracked[3].0.uuid));
+        let _ = connections.drain();
+        assert_eq!(connections.oldest(), None);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_since() {
+        let manager = Manager::new(10, None);
+        let info = Arc::new(TrackerInfo {
+            uuid: uuid::Uuid::new_v4(),
+            ..Default::default()
+        });
+        let (tx, _rx) = oneshot::channel();
+        manager.track(Tracked(info.uuid, info.clone()), tx).await;
+
+        let mut since = Utc::now();
+        let snapshot = manager.snapshot_since(&mut since).await;
+        assert_eq!(snapshot.connections.len(), 1);
+        assert!(snapshot.closed.is_empty());
+
+        // closed while the connections are read
+        manager
+            .closed
+            .lock()
+            .await
+            .push_back((Utc::now(), info.clone()));
+        let snapshot = manager.snapshot_since(&mut since).await;
+        assert!(snapshot.connections.is_empty());
+        assert_eq!(snapshot.closed.len(), 1);
+        assert_eq!(snapshot.closed[0].uuid, info.uuid);
+
+        // reported once
+        manager.connections.lock().await.remove(&info.uuid);
+        let snapshot = manager.snapshot_since(&mut since).await;
+        assert!(snapshot.connections.is_empty());
+        assert!(snapshot.closed.is_empty());
+    }
+
+    #[test]
+    fn test_same_route() {
+        let chain = vec!["ss-1".to_owned(), "auto".to_owned(), "PROXY".to_owned()];
+        let picks = |auto: &str| {
+            HashMap::from([
+                ("ss-1".to_owned(), None),
+                ("auto".to_owned(), Some(auto.to_owned())),
+                ("PROXY".to_owned(), Some("auto".to_owned())),
+            ])
+        };
+
+        assert!(Manager::same_route(&chain, "PROXY", &picks("ss-1")));
+        assert!(
+            !Manager::same_route(&chain, "DIRECT", &picks("ss-1")),
+            "the rules pick another outbound"
+        );
+        assert!(
+            !Manager::same_route(&chain, "PROXY", &picks("ss-2")),
+            "a url-test on the way picks another member"
+        );
+        let mut gone = picks("ss-1");
+        gone.remove("PROXY");
+        assert!(!Manager::same_route(&chain, "PROXY", &gone));
+
+        let direct = vec!["DIRECT".to_owned()];
+        assert!(Manager::same_route(
+            &direct,
+            "DIRECT",
+            &HashMap::from([("DIRECT".to_owned(), None)])
+        ));
+        assert!(!Manager::same_route(&direct, "DIRECT", &HashMap::new()));
+    }
+}
@@ -13,9 +13,9 @@ use crate::{
     app::router::RuleMatcher, proxy::datagram::UdpPacket, session::Session,
 };
 
-use super::statistics_manager::{Manager, ProxyChain, TrackerInfo};
+use super::statistics_manager::{CloseReason, Manager, ProxyChain, TrackerInfo};
 
-pub struct Tracked(uuid::Uuid, Arc<TrackerInfo>);
+pub struct Tracked(pub(super) uuid::Uuid, pub(super) Arc<TrackerInfo>);
 
 impl Tracked {
     pub fn id(&self) -> uuid::Uuid {
@@ -121,12 +121,11 @@ pub struct TrackedStream {
 
 #[allow(unused)]
 impl TrackedStream {
-    #[allow(clippy::borrowed_box)]
     pub async fn new(
         inner: BoxedChainedStream,
         manager: Arc<Manager>,
         sess: Session,
-        rule: Option<&Box<dyn RuleMatcher>>,
+        rule: Option<&dyn RuleMatcher>,
     ) -> Self {
         let uuid = uuid::Uuid::new_v4();
         let chain = inner.chain().clone();
@@ -153,6 +152,7 @@ impl TrackedStream {
         };
 
         manager.track(Tracked(uuid, s.tracker_info()), tx).await;
+        manager.hooks().session_start(&s.tracker);
 
         s
     }
@@ -243,7 +243,8 @@ impl WriteTracker {
 impl Drop for TrackedStream {
     fn drop(&mut self) {
         debug!("untrack connection: {}", self.id());
-        self.manager.untrack(self.id());
+        self.manager.untrack(&self.tracker);
+        self.manager.hooks().session_end(&self.tracker);
     }
 }
 
@@ -435,12 +436,11 @@ pub struct TrackedDatagram {
 }
 
 impl TrackedDatagram {
-    #[allow(clippy::borrowed_box)]
     pub async fn new(
         inner: BoxedChainedDatagram,
         manager: Arc<Manager>,
         sess: Session,
-        rule: Option<&Box<dyn RuleMatcher>>,
+        rule: Option<&dyn RuleMatcher>,
     ) -> Self {
         let uuid = uuid::Uuid::new_v4();
         let chain = inner.chain().clone();
@@ -467,6 +467,7 @@ impl TrackedDatagram {
         };
 
         manager.track(Tracked(uuid, s.tracker_info()), tx).await;
+        manager.hooks().session_start(&s.tracker);
 
         s
     }
@@ -483,7 +484,11 @@ impl TrackedDatagram {
 impl Drop for TrackedDatagram {
     fn drop(&mut self) {
         debug!("untrack connection: {}", self.id());
-        self.manager.untrack(self.id());
+        // unless the manager or the remote closed it, the session is dropped
+        // by the cleaner once idle
+        self.tracker.set_close_reason(CloseReason::IdleTimeout);
+        self.manager.untrack(&self.tracker);
+        self.manager.hooks().session_end(&self.tracker);
     }
 }
 
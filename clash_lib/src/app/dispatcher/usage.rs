@@ -0,0 +1,287 @@
+//! The bytes through each proxy and each rule by day and by month, for the
+//! proxy plans metered by traffic. Kept in the cache store, so they add up
+//! across restarts.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    sync::Mutex,
+};
+
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::app::profile::ThreadSafeCacheFile;
+
+const DAYS_KEPT: usize = 62;
+const MONTHS_KEPT: usize = 24;
+const DAY: &str = "day";
+const MONTH: &str = "month";
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+pub struct Traffic {
+    pub upload: u64,
+    pub download: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, other: Traffic) {
+        self.upload += other.upload;
+        self.download += other.download;
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct UsageTable {
+    /// by the proxy dialed, the first of the chain
+    pub proxies: BTreeMap<String, Traffic>,
+    /// by the rule matched, e.g. `DomainSuffix,example.com` or `Match`
+    pub rules: BTreeMap<String, Traffic>,
+}
+
+/// The days as `2024-01-31` and the months as `2024-01`, in local time
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct UsageSnapshot {
+    pub days: BTreeMap<String, UsageTable>,
+    pub months: BTreeMap<String, UsageTable>,
+}
+
+impl UsageSnapshot {
+    /// `period,kind,name,upload,download`, a line per proxy and per rule of
+    /// each day and month
+    pub fn to_csv(&self) -> String {
+        let mut csv = "period,kind,name,upload,download\n".to_owned();
+        for (period, table) in self.days.iter().chain(self.months.iter()) {
+            let rows = table
+                .proxies
+                .iter()
+                .map(|x| ("proxy", x))
+                .chain(table.rules.iter().map(|x| ("rule", x)));
+            for (kind, (name, traffic)) in rows {
+                let _ = writeln!(
+                    csv,
+                    "{},{},{},{},{}",
+                    period,
+                    kind,
+                    csv_field(name),
+                    traffic.upload,
+                    traffic.download
+                );
+            }
+        }
+        csv
+    }
+}
+
+/// rule payloads have commas
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    snapshot: UsageSnapshot,
+    /// `day/<day>` and `month/<month>` changed since the last flush
+    dirty: HashSet<String>,
+}
+
+#[derive(Default)]
+pub struct Usage {
+    inner: Mutex<Inner>,
+    cache_store: Mutex<Option<ThreadSafeCacheFile>>,
+}
+
+impl Usage {
+    pub fn add(&self, proxy: Option<&str>, rule: Option<&str>, traffic: Traffic) {
+        self.add_on(Local::now().date_naive(), proxy, rule, traffic);
+    }
+
+    fn add_on(
+        &self,
+        day: NaiveDate,
+        proxy: Option<&str>,
+        rule: Option<&str>,
+        traffic: Traffic,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let day = day.format("%Y-%m-%d").to_string();
+        let month = day[..7].to_owned();
+        for (kind, period) in [(DAY, day), (MONTH, month)] {
+            let periods = match kind {
+                DAY => &mut inner.snapshot.days,
+                _ => &mut inner.snapshot.months,
+            };
+            let table = periods.entry(period.clone()).or_default();
+            if let Some(proxy) = proxy {
+                table
+                    .proxies
+                    .entry(proxy.to_owned())
+                    .or_default()
+                    .add(traffic);
+            }
+            if let Some(rule) = rule {
+                table.rules.entry(rule.to_owned()).or_default().add(traffic);
+            }
+            inner.dirty.insert(format!("{}/{}", kind, period));
+        }
+    }
+
+    pub fn snapshot(&self) -> UsageSnapshot {
+        self.inner.lock().unwrap().snapshot.clone()
+    }
+
+    /// loads what `cache_store` has of the periods not counted in memory,
+    /// the changes are stored in it from now on
+    pub async fn set_cache_store(&self, cache_store: ThreadSafeCacheFile) {
+        let stored = cache_store.get_usage().await;
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for (key, table) in stored {
+                let Some((kind, period)) = key.split_once('/') else {
+                    continue;
+                };
+                let table: UsageTable = match serde_json::from_str(&table) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("failed to load the usage of {}: {}", key, e);
+                        continue;
+                    }
+                };
+                let periods = match kind {
+                    DAY => &mut inner.snapshot.days,
+                    MONTH => &mut inner.snapshot.months,
+                    _ => continue,
+                };
+                periods.entry(period.to_owned()).or_insert(table);
+            }
+        }
+        *self.cache_store.lock().unwrap() = Some(cache_store);
+        self.flush().await;
+    }
+
+    /// stores the periods changed since the last flush, and forgets the
+    /// oldest ones beyond 62 days and 24 months
+    pub async fn flush(&self) {
+        let Some(cache_store) = self.cache_store.lock().unwrap().clone() else {
+            return;
+        };
+        let (changed, expired) = {
+            let mut inner = self.inner.lock().unwrap();
+            let mut expired = vec![];
+            for (kind, kept) in [(DAY, DAYS_KEPT), (MONTH, MONTHS_KEPT)] {
+                let periods = match kind {
+                    DAY => &mut inner.snapshot.days,
+                    _ => &mut inner.snapshot.months,
+                };
+                while periods.len() > kept {
+                    if let Some((period, _)) = periods.pop_first() {
+                        expired.push(format!("{}/{}", kind, period));
+                    }
+                }
+            }
+
+            let dirty = std::mem::take(&mut inner.dirty);
+            let changed: Vec<_> = dirty
+                .into_iter()
+                .filter_map(|key| {
+                    let (kind, period) = key.split_once('/')?;
+                    let table = match kind {
+                        DAY => inner.snapshot.days.get(period),
+                        _ => inner.snapshot.months.get(period),
+                    }?;
+                    Some((key.clone(), serde_json::to_string(table).ok()?))
+                })
+                .collect();
+            (changed, expired)
+        };
+
+        for (key, table) in changed {
+            cache_store.set_usage(&key, &table).await;
+        }
+        for key in expired {
+            cache_store.delete_usage(&key).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{Traffic, Usage, UsageTable};
+    use crate::app::profile::{MemoryStore, ThreadSafeCacheFile};
+
+    fn traffic(upload: u64, download: u64) -> Traffic {
+        Traffic { upload, download }
+    }
+
+    #[test]
+    fn test_usage() {
+        let usage = Usage::default();
+        let day = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+        usage.add_on(day(30), Some("ss"), Some("Match"), traffic(1, 10));
+        usage.add_on(
+            day(31),
+            Some("ss"),
+            Some("DomainSuffix,example.com"),
+            traffic(2, 20),
+        );
+        usage.add_on(day(31), Some("DIRECT"), None, traffic(3, 30));
+
+        let snapshot = usage.snapshot();
+        assert_eq!(snapshot.days.len(), 2);
+        assert_eq!(snapshot.days["2024-01-31"].proxies["ss"], traffic(2, 20));
+        let month = &snapshot.months["2024-01"];
+        assert_eq!(month.proxies["ss"], traffic(3, 30));
+        assert_eq!(month.proxies["DIRECT"], traffic(3, 30));
+        assert_eq!(month.rules.len(), 2);
+
+        let csv = snapshot.to_csv();
+        assert!(csv.starts_with("period,kind,name,upload,download\n"));
+        assert!(csv.contains("2024-01-30,rule,Match,1,10\n"));
+        assert!(csv.contains("2024-01,rule,\"DomainSuffix,example.com\",2,20\n"));
+    }
+
+    #[tokio::test]
+    async fn test_usage_stored() {
+        let cache_store = ThreadSafeCacheFile::with_store(
+            Box::new(MemoryStore::default()),
+            false,
+            false,
+        );
+        let usage = Usage::default();
+        usage.set_cache_store(cache_store.clone()).await;
+        for d in 1..=31 {
+            let day = NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+            usage.add_on(day, Some("ss"), None, traffic(1, 1));
+        }
+        for d in 1..=29 {
+            let day = NaiveDate::from_ymd_opt(2024, 2, d).unwrap();
+            usage.add_on(day, Some("ss"), None, traffic(1, 1));
+        }
+        for d in 1..=3 {
+            let day = NaiveDate::from_ymd_opt(2024, 3, d).unwrap();
+            usage.add_on(day, Some("ss"), None, traffic(1, 1));
+        }
+        usage.flush().await;
+        assert_eq!(usage.snapshot().days.len(), 62);
+        assert!(!usage.snapshot().days.contains_key("2024-01-01"));
+
+        let restored = Usage::default();
+        restored.set_cache_store(cache_store).await;
+        let snapshot = restored.snapshot();
+        assert_eq!(snapshot.days.len(), 62);
+        assert_eq!(
+            snapshot.months["2024-01"],
+            UsageTable {
+                proxies: [("ss".to_owned(), traffic(31, 31))].into(),
+                ..Default::default()
+            }
+        );
+    }
+}
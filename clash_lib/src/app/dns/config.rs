@@ -1,7 +1,7 @@
 use std::{
     collections::HashMap,
     fmt::Display,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
 };
 
@@ -15,16 +15,25 @@ use watfaq_dns::{DNSListenAddr, DoH3Config, DoHConfig, DoTConfig};
 use crate::{
     Error,
     common::trie,
-    config::def::{DNSListen, DNSMode},
+    config::def::{DNSListen, DNSMode, LocalNameMode},
 };
 
-use super::dns_client::DNSNetMode;
+use super::{
+    dns_client::DNSNetMode,
+    hosts::{Hosts, HostsTarget},
+};
 
 #[derive(Clone, Debug)]
 pub struct NameServer {
     pub net: DNSNetMode,
     pub address: String,
     pub interface: Option<String>,
+    /// the IP of a nameserver given by its host name, which is otherwise
+    /// resolved with the `default-nameserver`
+    pub ip: Option<IpAddr>,
+    /// the nameservers resolving the host name of this one instead of the
+    /// `default-nameserver`, given by their IPs
+    pub bootstrap: Vec<NameServer>,
 }
 impl Display for NameServer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -59,8 +68,13 @@ pub struct Config {
     pub fake_ip_range: ipnet::IpNet,
     pub fake_ip_filter: Vec<String>,
     pub store_fake_ip: bool,
-    pub hosts: Option<trie::StringTrie<IpAddr>>,
+    pub hosts: Option<Hosts>,
     pub nameserver_policy: HashMap<String, NameServer>,
+    pub search_domains: Vec<String>,
+    pub ndots: usize,
+    pub local_names: LocalNameMode,
+    /// max number of cached answers
+    pub cache_size: usize,
 }
 
 impl Config {
@@ -83,6 +97,36 @@ impl Config {
             let host = url.host_str().expect("dns host must be valid");
 
             let iface = url.fragment();
+            let ip = url
+                .query_pairs()
+                .find(|(k, _)| k == "ip")
+                .map(|(_, ip)| {
+                    ip.parse::<IpAddr>().map_err(|_| {
+                        Error::InvalidConfig(format!(
+                            "invalid ip of dns server {}: {}",
+                            server, ip
+                        ))
+                    })
+                })
+                .transpose()?;
+            let bootstrap = match url.query_pairs().find(|(k, _)| k == "bootstrap") {
+                Some((_, servers)) => {
+                    let servers =
+                        servers.split(',').map(str::to_owned).collect::<Vec<_>>();
+                    let bootstrap = Config::parse_nameserver(&servers)?;
+                    if let Some(x) = bootstrap.iter().find(|x| {
+                        x.net != DNSNetMode::Dhcp
+                            && x.address.parse::<SocketAddr>().is_err()
+                    }) {
+                        return Err(Error::InvalidConfig(format!(
+                            "bootstrap of dns server {} must be given by ip: {}",
+                            server, x
+                        )));
+                    }
+                    bootstrap
+                }
+                None => vec![],
+            };
             let addr: String;
             let net: &str;
 
@@ -103,6 +147,10 @@ impl Config {
                     addr = Config::host_with_default_port(host, "443")?;
                     net = "DoH";
                 }
+                "h3" => {
+                    addr = Config::host_with_default_port(host, "443")?;
+                    net = "DoH3";
+                }
                 "dhcp" => {
                     addr = host.to_string();
                     net = "DHCP";
@@ -121,6 +169,8 @@ impl Config {
                 address: addr,
                 net,
                 interface: iface.map(String::from),
+                ip,
+                bootstrap,
             });
         }
 
@@ -162,19 +212,21 @@ impl Config {
         Ok(output)
     }
 
+    /// a host maps to an IP or to another domain
     pub fn parse_hosts(
         hosts_mapping: &HashMap<String, String>,
-    ) -> anyhow::Result<trie::StringTrie<IpAddr>> {
-        let mut tree = trie::StringTrie::new();
+    ) -> anyhow::Result<Hosts> {
+        let mut tree = Hosts::new();
         tree.insert(
             "localhost",
-            Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
+            Arc::new(HostsTarget::Ip(Ipv4Addr::LOCALHOST.into())),
         );
 
-        for (host, ip_str) in hosts_mapping.iter() {
-            let ip = ip_str.parse::<IpAddr>()?;
-            tree.insert(host.as_str(), Arc::new(ip));
+        for (host, target) in hosts_mapping.iter() {
+            let target = target.parse::<HostsTarget>().map_err(|e| anyhow!(e))?;
+            tree.insert(host.as_str(), Arc::new(target));
         }
+        tree.shrink();
 
         Ok(tree)
     }
@@ -227,6 +279,19 @@ impl TryFrom<&crate::config::def::Config> for Config {
         }
         let default_nameserver = Config::parse_nameserver(&dc.default_nameserver)?;
 
+        for name in c.hosts.values().filter_map(|x| x.strip_prefix("rule-set:")) {
+            if !c
+                .rule_provider
+                .as_ref()
+                .is_some_and(|p| p.contains_key(name))
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "rule provider {} in hosts not found",
+                    name
+                )));
+            }
+        }
+
         Ok(Self {
             enable: dc.enable,
             ipv6: c.ipv6 && dc.ipv6,
@@ -357,14 +422,33 @@ impl TryFrom<&crate::config::def::Config> for Config {
             hosts: if dc.user_hosts && !c.hosts.is_empty() {
                 Config::parse_hosts(&c.hosts).ok()
             } else {
-                let mut tree = trie::StringTrie::new();
+                let mut tree = Hosts::new();
                 tree.insert(
                     "localhost",
-                    Arc::new("127.0.0.1".parse::<IpAddr>().unwrap()),
+                    Arc::new(HostsTarget::Ip(Ipv4Addr::LOCALHOST.into())),
                 );
                 Some(tree)
             },
             nameserver_policy,
+            search_domains: dc
+                .search_domains
+                .iter()
+                .map(|d| {
+                    let d = d.trim_matches('.');
+                    let (_, valid) = trie::valid_and_split_domain(d);
+                    if valid {
+                        Ok(d.to_owned())
+                    } else {
+                        Err(Error::InvalidConfig(format!(
+                            "invalid dns search domain: {}",
+                            d
+                        )))
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+            ndots: dc.ndots,
+            local_names: dc.local_names,
+            cache_size: c.memory_profile.dns_cache_size(),
         })
     }
 }
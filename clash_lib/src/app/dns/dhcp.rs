@@ -96,9 +96,12 @@ impl DhcpClient {
                         net: DNSNetMode::Udp,
                         address: format!("{}:53", s),
                         interface: None,
+                        ip: None,
+                        bootstrap: vec![],
                     })
                     .collect(),
                 None,
+                None,
             )
             .await;
         }
@@ -3,7 +3,7 @@ use std::{
     net,
     str::FromStr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
@@ -11,19 +11,26 @@ use async_trait::async_trait;
 use futures::{TryFutureExt, future::BoxFuture};
 use hickory_client::client;
 use hickory_proto::{
-    ProtoError, runtime::iocompat::AsyncIoTokioAsStd,
-    rustls::tls_client_stream::tls_client_connect_with_future, tcp::TcpClientStream,
+    ProtoError,
+    runtime::iocompat::AsyncIoTokioAsStd,
+    rustls::tls_client_stream::tls_client_connect_with_future,
+    serialize::binary::{BinDecodable, BinEncodable},
+    tcp::TcpClientStream,
     udp::UdpClientStream,
 };
+use quinn::{Runtime, TokioRuntime};
 use rustls::ClientConfig;
 use tokio::{sync::RwLock, task::JoinHandle};
 use tracing::{info, warn};
 
 use crate::{
     app::net::{Interface, TUN_SOMARK},
-    common::tls::{self, GLOBAL_ROOT_STORE},
+    common::{
+        http::h3::H3Connection,
+        tls::{self, GLOBAL_ROOT_STORE},
+    },
     dns::{ThreadSafeDNSClient, dhcp::DhcpClient},
-    proxy::utils::new_tcp_stream,
+    proxy::utils::{new_tcp_stream, new_udp_socket},
 };
 use hickory_proto::{
     DnsHandle,
@@ -37,12 +44,20 @@ use crate::Error;
 
 use super::{ClashResolver, Client, runtime::DnsRuntimeProvider};
 
+/// how long DoH3 stays on HTTP/2 after QUIC failed
+const H3_RETRY_INTERVAL: Duration = Duration::from_secs(600);
+/// for the QUIC handshake and the query together, leaving HTTP/2 the rest of
+/// the time a query may take
+const H3_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum DNSNetMode {
     Udp,
     Tcp,
     DoT,
     DoH,
+    /// DoH over HTTP/3, HTTP/2 when the server doesn't do QUIC
+    DoH3,
     Dhcp,
 }
 
@@ -53,6 +68,7 @@ impl Display for DNSNetMode {
             Self::Tcp => write!(f, "TCP"),
             Self::DoT => write!(f, "DoT"),
             Self::DoH => write!(f, "DoH"),
+            Self::DoH3 => write!(f, "DoH3"),
             Self::Dhcp => write!(f, "DHCP"),
         }
     }
@@ -67,6 +83,7 @@ impl FromStr for DNSNetMode {
             "TCP" => Ok(Self::Tcp),
             "DoH" => Ok(Self::DoH),
             "DoT" => Ok(Self::DoT),
+            "DoH3" => Ok(Self::DoH3),
             "DHCP" => Ok(Self::Dhcp),
             _ => Err(Error::DNSError("unsupported protocol".into())),
         }
@@ -80,6 +97,8 @@ pub struct Opts {
     pub port: u16,
     pub net: DNSNetMode,
     pub iface: Option<Interface>,
+    /// the address of `host`, resolved with `r` otherwise
+    pub ip: Option<net::IpAddr>,
 }
 
 enum DnsConfig {
@@ -87,6 +106,7 @@ enum DnsConfig {
     Tcp(net::SocketAddr, Option<Interface>),
     Tls(net::SocketAddr, String, Option<Interface>),
     Https(net::SocketAddr, String, Option<Interface>),
+    H3(net::SocketAddr, String, Option<Interface>),
 }
 
 impl Display for DnsConfig {
@@ -120,6 +140,13 @@ impl Display for DnsConfig {
                 }
                 write!(f, "host: {}", host)
             }
+            DnsConfig::H3(addr, host, iface) => {
+                write!(f, "HTTP/3: {}:{} ", addr.ip(), addr.port())?;
+                if let Some(iface) = iface {
+                    write!(f, "bind: {} ", iface)?;
+                }
+                write!(f, "host: {}", host)
+            }
         }
     }
 }
@@ -127,6 +154,9 @@ impl Display for DnsConfig {
 struct Inner {
     c: Option<client::Client>,
     bg_handle: Option<JoinHandle<Result<(), ProtoError>>>,
+    h3: Option<H3Connection>,
+    /// DoH3 is on HTTP/2 until then
+    h3_retry_at: Option<Instant>,
 }
 
 /// DnsClient
@@ -149,8 +179,9 @@ impl DnsClient {
             DNSNetMode::Dhcp => Ok(Arc::new(DhcpClient::new(&opts.host).await)),
 
             other => {
-                let ip = match opts.r {
-                    Some(r) => {
+                let ip = match (opts.ip, opts.r) {
+                    (Some(ip), _) => ip,
+                    (None, Some(r)) => {
                         match r.resolve(&opts.host, false).await.map_err(|x| {
                             anyhow!("resolve hostname failure: {}", x.to_string())
                         })? {
@@ -164,12 +195,14 @@ impl DnsClient {
                             }
                         }
                     }
-                    _ => opts.host.parse::<net::IpAddr>().map_err(|x| {
-                        Error::DNSError(format!(
-                            "resolve DNS hostname error: {}, {}",
-                            x, opts.host
-                        ))
-                    })?,
+                    (None, None) => {
+                        opts.host.parse::<net::IpAddr>().map_err(|x| {
+                            Error::DNSError(format!(
+                                "resolve DNS hostname error: {}, {}",
+                                x, opts.host
+                            ))
+                        })?
+                    }
                 };
 
                 match other {
@@ -183,6 +216,8 @@ impl DnsClient {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                h3: None,
+                                h3_retry_at: None,
                             })),
 
                             cfg,
@@ -203,6 +238,8 @@ impl DnsClient {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                h3: None,
+                                h3_retry_at: None,
                             })),
 
                             cfg,
@@ -224,6 +261,8 @@ impl DnsClient {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                h3: None,
+                                h3_retry_at: None,
                             })),
 
                             cfg,
@@ -234,17 +273,28 @@ impl DnsClient {
                             iface: opts.iface,
                         }))
                     }
-                    DNSNetMode::DoH => {
-                        let cfg = DnsConfig::Https(
-                            net::SocketAddr::new(ip, opts.port),
-                            opts.host.clone(),
-                            opts.iface.clone(),
-                        );
+                    DNSNetMode::DoH | DNSNetMode::DoH3 => {
+                        let addr = net::SocketAddr::new(ip, opts.port);
+                        let cfg = if other == &DNSNetMode::DoH3 {
+                            DnsConfig::H3(
+                                addr,
+                                opts.host.clone(),
+                                opts.iface.clone(),
+                            )
+                        } else {
+                            DnsConfig::Https(
+                                addr,
+                                opts.host.clone(),
+                                opts.iface.clone(),
+                            )
+                        };
 
                         Ok(Arc::new(Self {
                             inner: Arc::new(RwLock::new(Inner {
                                 c: None,
                                 bg_handle: None,
+                                h3: None,
+                                h3_retry_at: None,
                             })),
 
                             cfg,
@@ -281,6 +331,17 @@ impl Client for DnsClient {
     async fn exchange(&self, msg: &Message) -> anyhow::Result<Message> {
         let mut inner = self.inner.write().await;
 
+        if let DnsConfig::H3(addr, host, iface) = &self.cfg
+            && inner.h3_retry_at.is_none_or(|t| t <= Instant::now())
+        {
+            match exchange_h3(&mut inner, *addr, host, iface, msg).await {
+                Ok(res) => return Ok(res),
+                Err(e) => {
+                    warn!("DoH3 query failed, using HTTP/2 for {}: {}", self.cfg, e)
+                }
+            }
+        }
+
         match &inner.bg_handle {
             Some(bg) => {
                 if bg.is_finished() {
@@ -385,7 +446,8 @@ async fn dns_stream_builder(
             .map(|(x, y)| (x, tokio::spawn(y)))
             .map_err(|x| Error::DNSError(x.to_string()))
         }
-        DnsConfig::Https(addr, host, iface) => {
+        // DoH3 falls back to HTTP/2
+        DnsConfig::Https(addr, host, iface) | DnsConfig::H3(addr, host, iface) => {
             let mut tls_config = ClientConfig::builder()
                 .with_root_certificates(GLOBAL_ROOT_STORE.clone())
                 .with_no_client_auth();
@@ -410,3 +472,87 @@ async fn dns_stream_builder(
         }
     }
 }
+
+async fn exchange_h3(
+    inner: &mut Inner,
+    addr: net::SocketAddr,
+    host: &str,
+    iface: &Option<Interface>,
+    msg: &Message,
+) -> anyhow::Result<Message> {
+    let deadline = tokio::time::Instant::now() + H3_TIMEOUT;
+    if inner.h3.as_ref().is_none_or(H3Connection::is_closed) {
+        let conn =
+            match tokio::time::timeout_at(deadline, h3_connect(addr, host, iface))
+                .await
+            {
+                Ok(conn) => conn,
+                Err(_) => Err(anyhow!("timed out connecting to {}", addr)),
+            };
+        match conn {
+            Ok(conn) => {
+                inner.h3.replace(conn);
+                inner.h3_retry_at = None;
+            }
+            Err(e) => {
+                inner.h3_retry_at = Some(Instant::now() + H3_RETRY_INTERVAL);
+                return Err(e);
+            }
+        }
+    }
+
+    let req = http::Request::post(format!("https://{}/dns-query", host))
+        .header(http::header::CONTENT_TYPE, "application/dns-message")
+        .header(http::header::ACCEPT, "application/dns-message")
+        .body(())?;
+    let body = msg.to_vec()?.into();
+    let conn = inner.h3.as_ref().expect("connected above");
+    let res = match tokio::time::timeout_at(deadline, conn.send(req, Some(body)))
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("timed out")))
+    {
+        Ok(res) => res,
+        Err(e) => {
+            inner.h3 = None;
+            return Err(e);
+        }
+    };
+    if !res.status().is_success() {
+        return Err(anyhow!("server responded with {}", res.status()));
+    }
+    Ok(Message::from_bytes(res.body())?)
+}
+
+async fn h3_connect(
+    addr: net::SocketAddr,
+    host: &str,
+    iface: &Option<Interface>,
+) -> anyhow::Result<H3Connection> {
+    let src = if addr.is_ipv4() {
+        net::SocketAddr::new(net::Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        net::SocketAddr::new(net::Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+    let socket = new_udp_socket(
+        Some(src),
+        iface.clone(),
+        #[cfg(target_os = "linux")]
+        *TUN_SOMARK.read().await,
+    )
+    .await?;
+
+    let mut tls_config = H3Connection::tls_config();
+    if host == addr.ip().to_string() {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(tls::NoHostnameTlsVerifier::new()));
+    }
+
+    H3Connection::connect(
+        TokioRuntime.wrap_udp_socket(socket.into_std()?)?,
+        addr,
+        host,
+        tls_config,
+    )
+    .await
+}
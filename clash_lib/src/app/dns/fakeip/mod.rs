@@ -1,4 +1,9 @@
-use std::{net, sync::Arc};
+use std::{
+    collections::HashMap,
+    net,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{Error, common::trie};
 
@@ -31,6 +36,18 @@ pub trait Store: Sync + Send {
 
 pub type ThreadSafeFakeDns = Arc<RwLock<FakeDns>>;
 
+/// how long a fake ip is considered in use after it was handed out or seen,
+/// matches the TTL the DNS server answers with
+const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(60);
+
+/// Liveness of an allocated fake ip
+struct Lease {
+    /// number of live sessions using the ip
+    refs: usize,
+    /// the DNS answer carrying the ip may be cached by clients until then
+    expire: Instant,
+}
+
 pub struct FakeDns {
     max: u32,
     min: u32,
@@ -40,6 +57,8 @@ pub struct FakeDns {
     skipped_hostnames: Option<trie::StringTrie<bool>>,
     ipnet: ipnet::IpNet,
     store: Box<dyn Store>,
+    leases: HashMap<net::IpAddr, Lease>,
+    lease_ttl: Duration,
 }
 
 impl FakeDns {
@@ -64,16 +83,20 @@ impl FakeDns {
             skipped_hostnames: opt.skipped_hostnames,
             ipnet: opt.ipnet,
             store: opt.store,
+            leases: HashMap::new(),
+            lease_ttl: DEFAULT_LEASE_TTL,
         })
     }
 
     pub async fn lookup(&mut self, host: &str) -> net::IpAddr {
         if let Some(ip) = self.store.get_by_host(host).await {
+            self.renew(ip);
             return ip;
         }
 
         let ip = self.get(host).await;
         self.store.pub_by_host(host, ip).await;
+        self.renew(ip);
         ip
     }
 
@@ -81,10 +104,47 @@ impl FakeDns {
         if !ip.is_ipv4() {
             None
         } else {
-            self.store.get_by_ip(ip).await
+            let host = self.store.get_by_ip(ip).await;
+            if host.is_some() {
+                self.renew(ip);
+            }
+            host
+        }
+    }
+
+    /// Mark the ip as used by a live session, it won't be recycled until
+    /// every session called `release`
+    pub fn retain(&mut self, ip: net::IpAddr) {
+        let expire = Instant::now() + self.lease_ttl;
+        self.leases
+            .entry(ip)
+            .or_insert(Lease { refs: 0, expire })
+            .refs += 1;
+    }
+
+    pub fn release(&mut self, ip: net::IpAddr) {
+        if let Some(lease) = self.leases.get_mut(&ip) {
+            lease.refs = lease.refs.saturating_sub(1);
+            if lease.refs == 0 && lease.expire <= Instant::now() {
+                self.leases.remove(&ip);
+            }
         }
     }
 
+    fn renew(&mut self, ip: net::IpAddr) {
+        let expire = Instant::now() + self.lease_ttl;
+        self.leases
+            .entry(ip)
+            .and_modify(|l| l.expire = expire)
+            .or_insert(Lease { refs: 0, expire });
+    }
+
+    fn in_use(&self, ip: &net::IpAddr) -> bool {
+        self.leases
+            .get(ip)
+            .is_some_and(|l| l.refs > 0 || l.expire > Instant::now())
+    }
+
     pub fn should_skip(&self, domain: &str) -> bool {
         match &self.skipped_hostnames {
             None => false,
@@ -131,9 +191,15 @@ impl FakeDns {
             self.offset = (self.offset + 1) % (self.max - self.min);
 
             if self.offset == current {
-                self.offset = (self.offset + 1) % (self.max - self.min);
-                let ip = net::Ipv4Addr::from(self.min + self.offset - 1);
-                self.store.del_by_ip(std::net::IpAddr::V4(ip)).await;
+                // the pool is exhausted, take over an address nobody uses,
+                // or the next one in order if every address is busy
+                self.offset = self
+                    .find_recyclable(current)
+                    .unwrap_or((self.offset + 1) % (self.max - self.min));
+                let ip =
+                    net::IpAddr::V4(net::Ipv4Addr::from(self.min + self.offset - 1));
+                self.store.del_by_ip(ip).await;
+                self.leases.remove(&ip);
                 break;
             }
 
@@ -148,6 +214,20 @@ impl FakeDns {
         std::net::IpAddr::V4(ip)
     }
 
+    fn find_recyclable(&mut self, current: u32) -> Option<u32> {
+        let now = Instant::now();
+        self.leases.retain(|_, l| l.refs > 0 || l.expire > now);
+
+        let size = self.max - self.min;
+        (1..=size)
+            .map(|i| (current + i) % size)
+            .filter(|offset| *offset != 0)
+            .find(|offset| {
+                let ip = net::Ipv4Addr::from(self.min + offset - 1);
+                !self.in_use(&net::IpAddr::V4(ip))
+            })
+    }
+
     fn ip_to_uint(ip: &net::Ipv4Addr) -> u32 {
         BigEndian::read_u32(&ip.octets())
     }
@@ -155,7 +235,7 @@ impl FakeDns {
 
 #[cfg(test)]
 mod tests {
-    use std::{net, sync::Arc};
+    use std::{net, sync::Arc, time::Duration};
 
     use crate::{app::dns::fakeip::mem_store::InMemStore, common::trie};
 
@@ -217,6 +297,37 @@ mod tests {
         assert_eq!(next, bar);
     }
 
+    #[tokio::test]
+    async fn test_inmem_recycle_unused() {
+        let store = Box::new(InMemStore::new(10));
+
+        let ipnet = "192.168.0.0/29".parse::<ipnet::IpNet>().unwrap();
+        let mut pool = FakeDns::new(Opts {
+            ipnet,
+            skipped_hostnames: None,
+            store,
+        })
+        .unwrap();
+        pool.lease_ttl = Duration::ZERO;
+
+        let foo = pool.lookup("foo.com").await;
+        let bar = pool.lookup("bar.com").await;
+        pool.retain(foo);
+
+        for i in 0..3 {
+            pool.lookup(&format!("{}.com", i)).await;
+        }
+
+        // foo is still used by a session, bar is the first unused address
+        let baz = pool.lookup("baz.com").await;
+        assert_eq!(baz, bar);
+        assert_eq!(pool.reverse_lookup(foo).await, Some("foo.com".into()));
+
+        assert!(pool.in_use(&foo));
+        pool.release(foo);
+        assert!(!pool.in_use(&foo));
+    }
+
     #[tokio::test]
     async fn test_pool_skip() {
         let store = Box::new(InMemStore::new(10));
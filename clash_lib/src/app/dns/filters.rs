@@ -49,6 +49,7 @@ impl DomainFilter {
         for d in domains {
             f.0.insert(d, Arc::new(None));
         }
+        f.0.shrink();
         f
     }
 }
@@ -1,19 +1,26 @@
 use crate::{
-    app::net::{DEFAULT_OUTBOUND_INTERFACE, get_outbound_interface},
+    app::{
+        net::{DEFAULT_OUTBOUND_INTERFACE, get_outbound_interface},
+        profile::ThreadSafeCacheFile,
+    },
     dns::{
         ClashResolver, ThreadSafeDNSClient,
         dns_client::{DNSNetMode, DnsClient, Opts},
+        resolver::EnhancedResolver,
     },
 };
-use std::sync::Arc;
+use std::{net::IpAddr, sync::Arc};
 use tracing::{debug, warn};
 
 use super::config::NameServer;
 use crate::print_and_exit;
 
+/// `resolver` resolves the host names of `servers` without a `bootstrap` of
+/// their own, which are kept in `cache` for when it fails
 pub async fn make_clients(
     servers: Vec<NameServer>,
     resolver: Option<Arc<dyn ClashResolver>>,
+    cache: Option<&ThreadSafeCacheFile>,
 ) -> Vec<ThreadSafeDNSClient> {
     let mut rv = Vec::new();
 
@@ -33,6 +40,31 @@ pub async fn make_clients(
             (host, port)
         };
 
+        let resolver = if s.bootstrap.is_empty() {
+            resolver.clone()
+        } else {
+            // boxed, as the bootstrap resolver is made of clients too
+            let bootstrap =
+                Box::pin(EnhancedResolver::new_bootstrap(s.bootstrap.clone())).await;
+            Some(Arc::new(bootstrap) as Arc<dyn ClashResolver>)
+        };
+
+        let ip = match (s.ip, resolver.as_deref()) {
+            (Some(ip), _) => Some(ip),
+            (None, Some(r))
+                if s.net != DNSNetMode::Dhcp && host.parse::<IpAddr>().is_err() =>
+            {
+                match resolve_nameserver(host, r, cache).await {
+                    Some(ip) => Some(ip),
+                    None => {
+                        warn!("initializing DNS client {}: can't resolve it", &s);
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
+
         match DnsClient::new_client(Opts {
             r: resolver.as_ref().cloned(),
             host: host.to_string(),
@@ -55,6 +87,7 @@ pub async fn make_clients(
                     .as_ref()
                     .map(|x| x.name.as_str().into()))
                 .inspect(|x| debug!("DNS client interface: {:?}", x)),
+            ip,
         })
         .await
         {
@@ -65,3 +98,102 @@ pub async fn make_clients(
 
     rv
 }
+
+/// `host` resolved with `resolver`, or to what it was the last time if that
+/// fails, e.g. when the network blocks the `default-nameserver`
+async fn resolve_nameserver(
+    host: &str,
+    resolver: &dyn ClashResolver,
+    cache: Option<&ThreadSafeCacheFile>,
+) -> Option<IpAddr> {
+    match resolver.resolve(host, false).await {
+        Ok(Some(ip)) => {
+            if let Some(cache) = cache {
+                cache.set_nameserver_ip(host, ip).await;
+            }
+            Some(ip)
+        }
+        r => {
+            let reason = match r {
+                Ok(_) => "no address".to_owned(),
+                Err(e) => e.to_string(),
+            };
+            let cached = match cache {
+                Some(cache) => cache.get_nameserver_ip(host).await,
+                None => None,
+            };
+            if let Some(ip) = cached {
+                warn!(
+                    "failed to resolve nameserver {}: {}, using {} it was resolved \
+                     to before",
+                    host, reason, ip
+                );
+            }
+            cached
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::{
+        dns::{MockClashResolver, config::Config},
+        profile::{MemoryStore, ThreadSafeCacheFile},
+    };
+
+    use super::resolve_nameserver;
+
+    #[tokio::test]
+    async fn test_resolve_nameserver() {
+        let ns = Config::parse_nameserver(&[
+            "https://dns.google/dns-query?ip=8.8.8.8".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(ns[0].address, "dns.google:443");
+        assert_eq!(ns[0].ip, Some("8.8.8.8".parse().unwrap()));
+        assert!(
+            Config::parse_nameserver(&["tls://dns.google?ip=x".to_owned()]).is_err()
+        );
+        let ns = Config::parse_nameserver(&[
+            "https://dns.google/dns-query?bootstrap=9.9.9.9,tls://1.1.1.1"
+                .to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(ns[0].ip, None);
+        let bootstrap = ns[0]
+            .bootstrap
+            .iter()
+            .map(|x| x.address.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(bootstrap, ["9.9.9.9:53", "1.1.1.1:853"]);
+        assert!(
+            Config::parse_nameserver(&[
+                "https://dns.google/dns-query?bootstrap=dns.quad9.net".to_owned()
+            ])
+            .is_err()
+        );
+
+        let cache = ThreadSafeCacheFile::with_store(
+            Box::new(MemoryStore::default()),
+            false,
+            false,
+        );
+        let mut resolver = MockClashResolver::new();
+        resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some("8.8.4.4".parse().unwrap())));
+        let ip = resolve_nameserver("dns.google", &resolver, Some(&cache)).await;
+        assert_eq!(ip, Some("8.8.4.4".parse().unwrap()));
+
+        let mut blocked = MockClashResolver::new();
+        blocked
+            .expect_resolve()
+            .returning(|_, _| Err(anyhow!("timed out")));
+        let ip = resolve_nameserver("dns.google", &blocked, Some(&cache)).await;
+        assert_eq!(ip, Some("8.8.4.4".parse().unwrap()));
+        assert_eq!(
+            resolve_nameserver("cloudflare-dns.com", &blocked, Some(&cache)).await,
+            None
+        );
+    }
+}
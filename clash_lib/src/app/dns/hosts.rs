@@ -0,0 +1,129 @@
+//! `hosts` entries map a domain, or a wildcard like `*.example.com`, to an IP
+//! or to another domain. The other domain is looked up like a CNAME, in
+//! `hosts` first and then with the nameservers.
+//!
+//! An entry may map to `rule-set:<name>` instead, the names it covers are
+//! then looked up in the payload of that rule provider, of the `hosts`
+//! behavior, which is refreshed like any rule set.
+
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+use tracing::warn;
+
+use crate::{
+    app::remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider,
+    common::trie,
+};
+
+/// more than this many aliases in a row is taken as a loop
+const MAX_ALIASES: usize = 8;
+
+/// the rule providers by name, for the `rule-set:` entries
+pub type RuleSets = HashMap<String, ThreadSafeRuleProvider>;
+
+pub type Hosts = trie::StringTrie<HostsTarget>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostsTarget {
+    Ip(IpAddr),
+    Alias(String),
+    /// only in `hosts`, `search` looks it up in the rule provider
+    RuleSet(String),
+}
+
+impl FromStr for HostsTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix("rule-set:") {
+            return Ok(Self::RuleSet(name.to_owned()));
+        }
+        if let Ok(ip) = s.parse() {
+            return Ok(Self::Ip(ip));
+        }
+        let domain = s.trim_end_matches('.');
+        let valid = !domain.split('.').any(str::is_empty)
+            && domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if valid {
+            Ok(Self::Alias(domain.to_owned()))
+        } else {
+            Err(format!("invalid hosts value {}: not an IP or a domain", s))
+        }
+    }
+}
+
+/// what `host` maps to after following the aliases within `hosts` and the
+/// rule sets, an alias if the chain ends outside of them
+pub fn search(
+    hosts: &Hosts,
+    host: &str,
+    rule_sets: &RuleSets,
+) -> Option<HostsTarget> {
+    let mut target = lookup(hosts, host, rule_sets)?;
+    for _ in 0..MAX_ALIASES {
+        let HostsTarget::Alias(alias) = &target else {
+            return Some(target);
+        };
+        match lookup(hosts, alias, rule_sets) {
+            Some(next) => target = next,
+            None => return Some(target),
+        }
+    }
+    warn!("the aliases of {} in hosts loop", host);
+    None
+}
+
+fn lookup(hosts: &Hosts, host: &str, rule_sets: &RuleSets) -> Option<HostsTarget> {
+    match hosts.search(host)?.get_data()? {
+        HostsTarget::RuleSet(name) => rule_sets.get(name)?.search_hosts(host),
+        target => Some(target.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::{Hosts, HostsTarget};
+
+    fn search(hosts: &Hosts, host: &str) -> Option<HostsTarget> {
+        super::search(hosts, host, &HashMap::new())
+    }
+
+    #[test]
+    fn test_hosts_aliases() {
+        let mut hosts = Hosts::new();
+        for (host, target) in [
+            ("dev.example.com", "internal-lb.corp"),
+            ("*.staging.example.com", "dev.example.com"),
+            ("internal-lb.corp", "10.0.0.8"),
+            ("api.example.com", "edge.cdn.example.net."),
+            ("a.loop", "b.loop"),
+            ("b.loop", "a.loop"),
+            ("+.zone.example.com", "rule-set:not-loaded"),
+        ] {
+            hosts.insert(host, Arc::new(target.parse().unwrap()));
+        }
+
+        let ip = Some(HostsTarget::Ip("10.0.0.8".parse().unwrap()));
+        assert_eq!(search(&hosts, "dev.example.com"), ip);
+        assert_eq!(search(&hosts, "web.staging.example.com"), ip);
+        assert_eq!(
+            search(&hosts, "api.example.com"),
+            Some(HostsTarget::Alias("edge.cdn.example.net".to_owned()))
+        );
+        assert_eq!(search(&hosts, "a.loop"), None);
+        assert_eq!(search(&hosts, "example.com"), None);
+        assert_eq!(search(&hosts, "dev.zone.example.com"), None);
+
+        assert_eq!(
+            "rule-set:corp-zone".parse::<HostsTarget>(),
+            Ok(HostsTarget::RuleSet("corp-zone".to_owned()))
+        );
+
+        assert!("*.example.com".parse::<HostsTarget>().is_err());
+        assert!("not a domain".parse::<HostsTarget>().is_err());
+    }
+}
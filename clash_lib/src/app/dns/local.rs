@@ -0,0 +1,169 @@
+//! Names that only make sense on the LAN: `.local` for mDNS (RFC 6762) and
+//! single label names for LLMNR (RFC 4795).
+//! Sending them to public nameservers leaks what's on the LAN and never gets
+//! an answer anyway, see `LocalNameMode` for the alternatives.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use hickory_proto::{op, rr};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+use super::hosts::{self, Hosts, HostsTarget, RuleSets};
+
+const MDNS_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+const LLMNR_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::new(224, 0, 0, 252)), 5355);
+const MULTICAST_TIMEOUT: Duration = Duration::from_secs(1);
+const LOCAL_TTL: u32 = 10;
+
+/// `host` without the trailing dot
+pub fn is_local_name(host: &str) -> bool {
+    is_mdns_name(host)
+        || (!host.is_empty()
+            && !host.contains('.')
+            && !host.eq_ignore_ascii_case("localhost"))
+}
+
+fn is_mdns_name(host: &str) -> bool {
+    host.len() > ".local".len()
+        && host[host.len() - ".local".len()..].eq_ignore_ascii_case(".local")
+}
+
+pub fn empty_response(req: &op::Message, code: op::ResponseCode) -> op::Message {
+    let mut res = op::Message::error_msg(req.id(), req.op_code(), code);
+    res.add_queries(req.queries().to_vec());
+    res.set_recursion_desired(req.recursion_desired());
+    res.set_recursion_available(true);
+    res
+}
+
+/// answer A/AAAA queries from `hosts`, everything else is NXDOMAIN
+pub fn answer_from_hosts(
+    req: &op::Message,
+    host: &str,
+    hosts: Option<&Hosts>,
+    rule_sets: &RuleSets,
+) -> op::Message {
+    let q = match req.query() {
+        Some(q) => q,
+        None => return empty_response(req, op::ResponseCode::FormErr),
+    };
+    let rdata = match hosts.and_then(|h| hosts::search(h, host, rule_sets)) {
+        Some(HostsTarget::Ip(std::net::IpAddr::V4(ip)))
+            if q.query_type() == rr::RecordType::A =>
+        {
+            rr::RData::A(rr::rdata::A(ip))
+        }
+        Some(HostsTarget::Ip(std::net::IpAddr::V6(ip)))
+            if q.query_type() == rr::RecordType::AAAA =>
+        {
+            rr::RData::AAAA(rr::rdata::AAAA(ip))
+        }
+        _ => return empty_response(req, op::ResponseCode::NXDomain),
+    };
+
+    let mut res = empty_response(req, op::ResponseCode::NoError);
+    res.add_answer(rr::Record::from_rdata(q.name().clone(), LOCAL_TTL, rdata));
+    res
+}
+
+/// Ask the LAN with a one-shot query, the responders reply to our ephemeral
+/// port directly. NXDOMAIN if nobody answers in time.
+pub async fn multicast_exchange(
+    req: &op::Message,
+    host: &str,
+) -> anyhow::Result<op::Message> {
+    let target = if is_mdns_name(host) {
+        MDNS_ADDR
+    } else {
+        LLMNR_ADDR
+    };
+
+    let mut m = req.clone();
+    m.set_recursion_desired(false);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.send_to(&m.to_vec()?, target).await?;
+    trace!("sent multicast query for {} to {}", host, target);
+
+    let mut buf = vec![0u8; 9000];
+    let rv = tokio::time::timeout(MULTICAST_TIMEOUT, async {
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            match op::Message::from_vec(&buf[..n]) {
+                Ok(res)
+                    if res.id() == req.id()
+                        && res.message_type() == op::MessageType::Response =>
+                {
+                    debug!("multicast answer for {} from {}", host, from);
+                    return Ok::<_, std::io::Error>(res);
+                }
+                _ => continue,
+            }
+        }
+    })
+    .await;
+
+    match rv {
+        Ok(Ok(mut res)) => {
+            // responders may leave the question out
+            res.take_queries();
+            res.add_queries(req.queries().to_vec());
+            res.set_recursion_desired(req.recursion_desired());
+            Ok(res)
+        }
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => {
+            debug!("no multicast answer for {}", host);
+            Ok(empty_response(req, op::ResponseCode::NXDomain))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+    use hickory_proto::{op, rr};
+
+    use super::{answer_from_hosts, is_local_name};
+    use crate::app::dns::hosts::{Hosts, HostsTarget};
+
+    #[test]
+    fn test_is_local_name() {
+        assert!(is_local_name("printer.local"));
+        assert!(is_local_name("Printer.LOCAL"));
+        assert!(is_local_name("nas"));
+        assert!(!is_local_name("localhost"));
+        assert!(!is_local_name("example.com"));
+        assert!(!is_local_name("localhost.example.com"));
+    }
+
+    #[test]
+    fn test_answer_from_hosts() {
+        let mut hosts = Hosts::new();
+        hosts.insert(
+            "nas.local",
+            Arc::new(HostsTarget::Ip("192.168.1.2".parse::<IpAddr>().unwrap())),
+        );
+
+        let mut req = op::Message::new();
+        let mut q = op::Query::new();
+        q.set_name(rr::Name::from_ascii("nas.local.").unwrap());
+        q.set_query_type(rr::RecordType::A);
+        req.add_query(q);
+
+        let res =
+            answer_from_hosts(&req, "nas.local", Some(&hosts), &HashMap::new());
+        assert_eq!(res.response_code(), op::ResponseCode::NoError);
+        assert_eq!(res.answers().len(), 1);
+
+        let res = answer_from_hosts(&req, "nas.local", None, &HashMap::new());
+        assert_eq!(res.response_code(), op::ResponseCode::NXDomain);
+        assert_eq!(res.queries().len(), 1);
+    }
+}
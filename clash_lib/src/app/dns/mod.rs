@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 
-use std::fmt::Debug;
+use std::{collections::HashMap, fmt::Debug};
 
 use hickory_proto::op;
 use std::sync::Arc;
@@ -14,15 +14,19 @@ mod dns_client;
 mod fakeip;
 mod filters;
 mod helper;
+pub mod hosts;
+mod local;
 pub mod resolver;
 mod runtime;
 mod server;
+mod upstreams;
 
 pub use config::Config;
 
 pub use resolver::{EnhancedResolver, SystemResolver, new as new_resolver};
 
 pub use server::{exchange_with_resolver, get_dns_listener};
+pub use upstreams::UpstreamStatus;
 #[async_trait]
 pub trait Client: Sync + Send + Debug {
     /// used to identify the client for logging
@@ -70,10 +74,22 @@ pub trait ClashResolver: Sync + Send {
     /// Only used for look up fake IP
     async fn reverse_lookup(&self, ip: std::net::IpAddr) -> Option<String>;
     async fn is_fake_ip(&self, ip: std::net::IpAddr) -> bool;
+    /// Keep a fake IP from being recycled while a session uses it
+    async fn retain_fake_ip(&self, ip: std::net::IpAddr);
+    async fn release_fake_ip(&self, ip: std::net::IpAddr);
     fn fake_ip_enabled(&self) -> bool;
 
     fn ipv6(&self) -> bool;
     fn set_ipv6(&self, enable: bool);
 
     fn kind(&self) -> ResolverKind;
+
+    /// the probes of the upstreams, by their client id
+    fn upstreams(&self) -> HashMap<String, UpstreamStatus> {
+        HashMap::new()
+    }
+
+    /// the rule providers the `rule-set:` entries of `hosts` are looked up
+    /// in, once the router has loaded them
+    fn set_rule_providers(&self, _providers: hosts::RuleSets) {}
 }
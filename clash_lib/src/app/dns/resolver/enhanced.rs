@@ -1,7 +1,9 @@
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use futures::{FutureExt, TryFutureExt};
 use rand::seq::IndexedRandom;
 use std::{
+    collections::HashMap,
     net,
     sync::{
         Arc,
@@ -18,12 +20,19 @@ use crate::{
     Error,
     app::profile::ThreadSafeCacheFile,
     common::{mmdb::Mmdb, trie},
-    config::def::DNSMode,
-    dns::{ThreadSafeDNSClient, helper::make_clients},
+    config::def::{DNSMode, LocalNameMode},
+    dns::{
+        ThreadSafeDNSClient,
+        config::NameServer,
+        helper::make_clients,
+        hosts::{self, Hosts, HostsTarget, RuleSets},
+        local,
+        upstreams::Upstreams,
+    },
 };
 
 use crate::dns::{
-    ClashResolver, Config, ResolverKind,
+    ClashResolver, Config, ResolverKind, UpstreamStatus,
     fakeip::{self, FileStore, InMemStore, ThreadSafeFakeDns},
     filters::{
         DomainFilter, FallbackDomainFilter, FallbackIPFilter, GeoIPFilter,
@@ -31,11 +40,14 @@ use crate::dns::{
     },
 };
 
-static TTL: Duration = Duration::from_secs(60);
+/// how long an answer is cached
+pub(crate) const TTL: Duration = Duration::from_secs(60);
 
 pub struct EnhancedResolver {
     ipv6: AtomicBool,
-    hosts: Option<trie::StringTrie<net::IpAddr>>,
+    hosts: Option<Hosts>,
+    /// the rule providers of the `rule-set:` entries of `hosts`
+    rule_sets: ArcSwap<RuleSets>,
     main: Vec<ThreadSafeDNSClient>,
 
     fallback: Option<Vec<ThreadSafeDNSClient>>,
@@ -50,6 +62,13 @@ pub struct EnhancedResolver {
 
     reverse_lookup_cache:
         Option<Arc<RwLock<lru_time_cache::LruCache<net::IpAddr, String>>>>,
+
+    search_domains: Vec<String>,
+    ndots: usize,
+    local_names: LocalNameMode,
+
+    /// the health of the upstreams, probed in the background
+    upstreams: Option<Arc<Upstreams>>,
 }
 
 impl EnhancedResolver {
@@ -58,20 +77,24 @@ impl EnhancedResolver {
     pub async fn new_default() -> Self {
         use crate::app::dns::dns_client::DNSNetMode;
 
-        use crate::app::dns::config::NameServer;
+        Self::new_bootstrap(vec![NameServer {
+            net: DNSNetMode::Udp,
+            address: "8.8.8.8:53".to_string(),
+            interface: None,
+            ip: None,
+            bootstrap: vec![],
+        }])
+        .await
+    }
 
+    /// A plain resolver of `servers`, given by their IPs, for the host names
+    /// of other nameservers
+    pub async fn new_bootstrap(servers: Vec<NameServer>) -> Self {
         EnhancedResolver {
             ipv6: AtomicBool::new(false),
             hosts: None,
-            main: make_clients(
-                vec![NameServer {
-                    net: DNSNetMode::Udp,
-                    address: "8.8.8.8:53".to_string(),
-                    interface: None,
-                }],
-                None,
-            )
-            .await,
+            rule_sets: Default::default(),
+            main: make_clients(servers, None, None).await,
             fallback: None,
             fallback_domain_filters: None,
             fallback_ip_filters: None,
@@ -81,6 +104,11 @@ impl EnhancedResolver {
             fake_dns: None,
 
             reverse_lookup_cache: None,
+
+            search_domains: vec![],
+            ndots: 1,
+            local_names: LocalNameMode::Upstream,
+            upstreams: None,
         }
     }
 
@@ -89,40 +117,56 @@ impl EnhancedResolver {
         store: ThreadSafeCacheFile,
         mmdb: Arc<Mmdb>,
     ) -> Self {
-        let default_resolver = Arc::new(EnhancedResolver {
-            ipv6: AtomicBool::new(false),
-            hosts: None,
-            main: make_clients(cfg.default_nameserver.clone(), None).await,
-            fallback: None,
-            fallback_domain_filters: None,
-            fallback_ip_filters: None,
-            lru_cache: None,
-            policy: None,
-
-            fake_dns: None,
+        let default_resolver = Arc::new(
+            EnhancedResolver::new_bootstrap(cfg.default_nameserver.clone()).await,
+        );
 
-            reverse_lookup_cache: None,
-        });
+        let main = make_clients(
+            cfg.nameserver.clone(),
+            Some(default_resolver.clone()),
+            Some(&store),
+        )
+        .await;
+        let fallback = if !cfg.fallback.is_empty() {
+            Some(
+                make_clients(
+                    cfg.fallback.clone(),
+                    Some(default_resolver.clone()),
+                    Some(&store),
+                )
+                .await,
+            )
+        } else {
+            None
+        };
+        let mut probed = main.clone();
+        probed.extend(fallback.iter().flatten().cloned());
+        let policy = if !cfg.nameserver_policy.is_empty() {
+            let mut p = trie::StringTrie::new();
+            for (domain, ns) in &cfg.nameserver_policy {
+                let clients = make_clients(
+                    vec![ns.to_owned()],
+                    Some(default_resolver.clone()),
+                    Some(&store),
+                )
+                .await;
+                probed.extend(clients.iter().cloned());
+                p.insert(domain.as_str(), Arc::new(clients));
+            }
+            p.shrink();
+            Some(p)
+        } else {
+            None
+        };
+        let upstreams = Upstreams::new();
+        upstreams.keep_probed(probed);
 
         Self {
             ipv6: AtomicBool::new(cfg.ipv6),
-            main: make_clients(
-                cfg.nameserver.clone(),
-                Some(default_resolver.clone()),
-            )
-            .await,
+            main,
             hosts: cfg.hosts,
-            fallback: if !cfg.fallback.is_empty() {
-                Some(
-                    make_clients(
-                        cfg.fallback.clone(),
-                        Some(default_resolver.clone()),
-                    )
-                    .await,
-                )
-            } else {
-                None
-            },
+            rule_sets: Default::default(),
+            fallback,
             fallback_domain_filters: if !cfg.fallback_filter.domain.is_empty() {
                 Some(vec![Box::new(DomainFilter::new(
                     cfg.fallback_filter
@@ -157,27 +201,11 @@ impl EnhancedResolver {
             },
             lru_cache: Some(Arc::new(RwLock::new(
                 lru_time_cache::LruCache::with_expiry_duration_and_capacity(
-                    TTL, 4096,
+                    TTL,
+                    cfg.cache_size.max(1),
                 ),
             ))),
-            policy: if !cfg.nameserver_policy.is_empty() {
-                let mut p = trie::StringTrie::new();
-                for (domain, ns) in &cfg.nameserver_policy {
-                    p.insert(
-                        domain.as_str(),
-                        Arc::new(
-                            make_clients(
-                                vec![ns.to_owned()],
-                                Some(default_resolver.clone()),
-                            )
-                            .await,
-                        ),
-                    );
-                }
-                Some(p)
-            } else {
-                None
-            },
+            policy,
             fake_dns: match cfg.enhance_mode {
                 DNSMode::FakeIp => Some(Arc::new(RwLock::new(
                     fakeip::FakeDns::new(fakeip::Opts {
@@ -187,6 +215,7 @@ impl EnhancedResolver {
                             for domain in cfg.fake_ip_filter.iter() {
                                 host.insert(domain.as_str(), Arc::new(true));
                             }
+                            host.shrink();
                             Some(host)
                         } else {
                             None
@@ -215,9 +244,23 @@ impl EnhancedResolver {
                                              * different server after the ip is
                                              * reverse mapped to hostname and
                                              * being resolved again */
-                    4096,
+                    cfg.cache_size.max(1),
                 ),
             ))),
+
+            search_domains: cfg.search_domains,
+            ndots: cfg.ndots,
+            local_names: cfg.local_names,
+            upstreams: Some(upstreams),
+        }
+    }
+
+    /// the clients of `clients` the probes didn't demote, all of them if
+    /// none is left
+    fn healthy(&self, clients: &[ThreadSafeDNSClient]) -> Vec<ThreadSafeDNSClient> {
+        match &self.upstreams {
+            Some(upstreams) => upstreams.healthy(clients),
+            None => clients.to_vec(),
         }
     }
 
@@ -259,6 +302,21 @@ impl EnhancedResolver {
         &self,
         host: &str,
         record_type: rr::record_type::RecordType,
+    ) -> anyhow::Result<Vec<net::IpAddr>> {
+        let mut last_err = None;
+        for name in self.search_names(host) {
+            match self.lookup_ip_exact(&name, record_type).await {
+                Ok(ip_list) => return Ok(ip_list),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow!("invalid domain: {}", host)))
+    }
+
+    async fn lookup_ip_exact(
+        &self,
+        host: &str,
+        record_type: rr::record_type::RecordType,
     ) -> anyhow::Result<Vec<net::IpAddr>> {
         let mut m = op::Message::new();
         let mut q = op::Query::new();
@@ -299,6 +357,88 @@ impl EnhancedResolver {
         }
     }
 
+    /// `exchange` with the search domains applied to the query name.
+    /// records of the name that answered are renamed to the one asked for
+    async fn exchange_with_search(
+        &self,
+        message: &op::Message,
+    ) -> anyhow::Result<op::Message> {
+        let q = message.query().ok_or(anyhow!("invalid query"))?;
+        let names = match EnhancedResolver::domain_name_of_message(message) {
+            Some(host) => self.search_names(&host),
+            None => vec![],
+        };
+        if names.len() <= 1 {
+            return self.exchange(message).await;
+        }
+
+        let mut last = None;
+        for name in names {
+            let fqdn = rr::Name::from_str_relaxed(&name)
+                .map_err(|_x| anyhow!("invalid domain: {}", name))?
+                .append_domain(&rr::Name::root())?;
+            let mut query = q.clone();
+            query.set_name(fqdn.clone());
+            let mut m = message.clone();
+            m.take_queries();
+            m.add_query(query);
+
+            let rv = self.exchange(&m).await;
+            let found = matches!(
+                &rv,
+                Ok(res) if res.response_code() == op::ResponseCode::NoError
+                    && res.answer_count() > 0
+            );
+            last = Some(rv.map(|mut res| {
+                let answers = res
+                    .take_answers()
+                    .into_iter()
+                    .map(|mut r| {
+                        if r.name() == &fqdn {
+                            r.set_name(q.name().clone());
+                        }
+                        r
+                    })
+                    .collect();
+                res.insert_answers(answers);
+                res.take_queries();
+                res.add_queries(message.queries().to_vec());
+                res
+            }));
+            if found {
+                break;
+            }
+        }
+        last.expect("at least 2 names to search")
+    }
+
+    fn search_hosts(&self, host: &str) -> Option<HostsTarget> {
+        hosts::search(self.hosts.as_ref()?, host, &self.rule_sets.load())
+    }
+
+    /// the names to look up for `host` in order, resolv.conf style:
+    /// names with fewer than `ndots` dots go through the search domains
+    /// first, others are tried as is first.
+    fn search_names(&self, host: &str) -> Vec<String> {
+        let host = host.trim_end_matches('.');
+        if self.search_domains.is_empty()
+            || host.is_empty()
+            || host.parse::<net::IpAddr>().is_ok()
+        {
+            return vec![host.to_owned()];
+        }
+
+        let searched = self
+            .search_domains
+            .iter()
+            .map(|d| format!("{}.{}", host, d));
+        if host.matches('.').count() < self.ndots {
+            searched.chain(std::iter::once(host.to_owned())).collect()
+        } else {
+            std::iter::once(host.to_owned()).chain(searched).collect()
+        }
+    }
+
     async fn exchange_no_cache(
         &self,
         message: &op::Message,
@@ -306,15 +446,32 @@ impl EnhancedResolver {
         let q = message.query().unwrap();
 
         let query = async move {
+            if let Some(host) = self.local_name_of_message(message) {
+                return match self.local_names {
+                    LocalNameMode::Hosts => Ok(local::answer_from_hosts(
+                        message,
+                        &host,
+                        self.hosts.as_ref(),
+                        &self.rule_sets.load(),
+                    )),
+                    _ => local::multicast_exchange(message, &host).await,
+                };
+            }
+
             if EnhancedResolver::is_ip_request(q) {
                 return self.ip_exchange(message).await;
             }
 
             if let Some(matched) = self.match_policy(message) {
-                return EnhancedResolver::batch_exchange(matched, message).await;
+                return EnhancedResolver::batch_exchange(
+                    &self.healthy(matched),
+                    message,
+                )
+                .await;
             }
 
-            EnhancedResolver::batch_exchange(&self.main, message).await
+            EnhancedResolver::batch_exchange(&self.healthy(&self.main), message)
+                .await
         };
 
         let rv = query.await;
@@ -370,28 +527,31 @@ impl EnhancedResolver {
         message: &op::Message,
     ) -> anyhow::Result<op::Message> {
         if let Some(matched) = self.match_policy(message) {
-            return EnhancedResolver::batch_exchange(matched, message).await;
+            return EnhancedResolver::batch_exchange(
+                &self.healthy(matched),
+                message,
+            )
+            .await;
         }
 
         if self.should_only_query_fallback(message) {
             // self.fallback guaranteed in the above check
             return EnhancedResolver::batch_exchange(
-                self.fallback.as_ref().unwrap(),
+                &self.healthy(self.fallback.as_ref().unwrap()),
                 message,
             )
             .await;
         }
 
-        let main_query = EnhancedResolver::batch_exchange(&self.main, message);
+        let main = self.healthy(&self.main);
+        let main_query = EnhancedResolver::batch_exchange(&main, message);
 
         if self.fallback.is_none() {
             return main_query.await;
         }
 
-        let fallback_query = EnhancedResolver::batch_exchange(
-            self.fallback.as_ref().unwrap(),
-            message,
-        );
+        let fallback = self.healthy(self.fallback.as_ref().unwrap());
+        let fallback_query = EnhancedResolver::batch_exchange(&fallback, message);
 
         if let Ok(main_result) = main_query.await {
             let ip_list = EnhancedResolver::ip_list_of_message(&main_result);
@@ -439,6 +599,16 @@ impl EnhancedResolver {
                 || q.query_type() == rr::RecordType::AAAA)
     }
 
+    /// the queried name if it's for the LAN and shouldn't go to the
+    /// nameservers
+    fn local_name_of_message(&self, m: &op::Message) -> Option<String> {
+        if self.local_names == LocalNameMode::Upstream {
+            return None;
+        }
+        EnhancedResolver::domain_name_of_message(m)
+            .filter(|host| local::is_local_name(host))
+    }
+
     fn domain_name_of_message(m: &op::Message) -> Option<String> {
         m.query()
             .map(|x| x.name().to_ascii().trim_end_matches('.').to_owned())
@@ -504,22 +674,23 @@ impl ClashResolver for EnhancedResolver {
         host: &str,
         enhanced: bool,
     ) -> anyhow::Result<Option<net::Ipv4Addr>> {
-        if enhanced {
-            if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V4(v4) => *v4,
-                        _ => unreachable!("invalid IP family"),
-                    }));
-                }
-            }
+        if enhanced && let Some(target) = self.search_hosts(host) {
+            return match target {
+                HostsTarget::Ip(net::IpAddr::V4(v4)) => Ok(Some(v4)),
+                HostsTarget::Alias(alias) => self.resolve_v4(&alias, enhanced).await,
+                _ => Ok(None),
+            };
         }
 
         if let Ok(ip) = host.parse::<net::Ipv4Addr>() {
             return Ok(Some(ip));
         }
 
-        if enhanced && self.fake_ip_enabled() {
+        // LAN devices are reached directly, a fake IP would send them
+        // through the rules instead
+        let is_local = self.local_names != LocalNameMode::Upstream
+            && local::is_local_name(host);
+        if enhanced && self.fake_ip_enabled() && !is_local {
             let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
             if !fake_dns.should_skip(host) {
                 let ip = fake_dns.lookup(host).await;
@@ -549,15 +720,12 @@ impl ClashResolver for EnhancedResolver {
             return Err(Error::DNSError("ipv6 disabled".into()).into());
         }
 
-        if enhanced {
-            if let Some(hosts) = &self.hosts {
-                if let Some(v) = hosts.search(host) {
-                    return Ok(v.get_data().map(|v| match v {
-                        net::IpAddr::V6(v6) => *v6,
-                        _ => unreachable!("invalid IP family"),
-                    }));
-                }
-            }
+        if enhanced && let Some(target) = self.search_hosts(host) {
+            return match target {
+                HostsTarget::Ip(net::IpAddr::V6(v6)) => Ok(Some(v6)),
+                HostsTarget::Alias(alias) => self.resolve_v6(&alias, enhanced).await,
+                _ => Ok(None),
+            };
         }
 
         if let Ok(ip) = host.parse::<net::Ipv6Addr>() {
@@ -586,7 +754,7 @@ impl ClashResolver for EnhancedResolver {
     }
 
     async fn exchange(&self, message: &op::Message) -> anyhow::Result<op::Message> {
-        let rv = self.exchange(message).await?;
+        let rv = self.exchange_with_search(message).await?;
         let hostname = message
             .query()
             .unwrap()
@@ -615,6 +783,17 @@ impl ClashResolver for EnhancedResolver {
         ResolverKind::Clash
     }
 
+    fn upstreams(&self) -> HashMap<String, UpstreamStatus> {
+        self.upstreams
+            .as_ref()
+            .map(|x| x.snapshot())
+            .unwrap_or_default()
+    }
+
+    fn set_rule_providers(&self, providers: RuleSets) {
+        self.rule_sets.store(Arc::new(providers));
+    }
+
     fn fake_ip_enabled(&self) -> bool {
         self.fake_dns.is_some()
     }
@@ -637,6 +816,18 @@ impl ClashResolver for EnhancedResolver {
         let mut fake_dns = self.fake_dns.as_ref().unwrap().write().await;
         fake_dns.reverse_lookup(ip).await
     }
+
+    async fn retain_fake_ip(&self, ip: net::IpAddr) {
+        if let Some(fake_dns) = &self.fake_dns {
+            fake_dns.write().await.retain(ip);
+        }
+    }
+
+    async fn release_fake_ip(&self, ip: net::IpAddr) {
+        if let Some(fake_dns) = &self.fake_dns {
+            fake_dns.write().await.release(ip);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -657,6 +848,33 @@ mod tests {
         runtime::DnsRuntimeProvider,
     };
 
+    #[tokio::test]
+    async fn test_search_names() {
+        let mut resolver = EnhancedResolver::new_default().await;
+        assert_eq!(resolver.search_names("nas"), vec!["nas"]);
+
+        resolver.search_domains = vec!["lan".to_owned(), "home.arpa".to_owned()];
+        assert_eq!(
+            resolver.search_names("nas"),
+            vec!["nas.lan", "nas.home.arpa", "nas"]
+        );
+        assert_eq!(
+            resolver.search_names("example.com."),
+            vec!["example.com", "example.com.lan", "example.com.home.arpa"]
+        );
+        assert_eq!(resolver.search_names("10.0.0.1"), vec!["10.0.0.1"]);
+
+        resolver.ndots = 2;
+        assert_eq!(
+            resolver.search_names("printer.office"),
+            vec![
+                "printer.office.lan",
+                "printer.office.home.arpa",
+                "printer.office"
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_bad_labels_with_custom_resolver() {
         let name = rr::Name::from_str_relaxed("some_domain.understore")
@@ -697,6 +915,7 @@ mod tests {
             port: 53,
             net: DNSNetMode::Udp,
             iface: None,
+            ip: None,
         })
         .await
         .expect("build client");
@@ -713,6 +932,7 @@ mod tests {
             port: 53,
             net: DNSNetMode::Tcp,
             iface: None,
+            ip: None,
         })
         .await
         .expect("build client");
@@ -729,6 +949,7 @@ mod tests {
             port: 853,
             net: DNSNetMode::DoT,
             iface: None,
+            ip: None,
         })
         .await
         .expect("build client");
@@ -747,6 +968,26 @@ mod tests {
             port: 443,
             net: DNSNetMode::DoH,
             iface: None,
+            ip: None,
+        })
+        .await
+        .expect("build client");
+
+        test_client(c).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "network unstable on CI"]
+    async fn test_doh3_resolve() {
+        let default_resolver = Arc::new(EnhancedResolver::new_default().await);
+
+        let c = DnsClient::new_client(Opts {
+            r: Some(default_resolver.clone()),
+            host: "cloudflare-dns.com".to_string(),
+            port: 443,
+            net: DNSNetMode::DoH3,
+            iface: None,
+            ip: None,
         })
         .await
         .expect("build client");
@@ -763,6 +1004,7 @@ mod tests {
             port: 0,
             net: DNSNetMode::Dhcp,
             iface: None,
+            ip: None,
         })
         .await
         .expect("build client");
@@ -13,6 +13,8 @@ use std::sync::Arc;
 pub use enhanced::EnhancedResolver;
 pub use system::SystemResolver;
 
+pub(crate) use enhanced::TTL as CACHE_TTL;
+
 use crate::{app::profile::ThreadSafeCacheFile, common::mmdb::Mmdb, print_and_exit};
 
 use super::{Config, ThreadSafeDNSResolver};
@@ -87,6 +87,10 @@ impl ClashResolver for SystemResolver {
     async fn reverse_lookup(&self, _: std::net::IpAddr) -> Option<String> {
         None
     }
+
+    async fn retain_fake_ip(&self, _: std::net::IpAddr) {}
+
+    async fn release_fake_ip(&self, _: std::net::IpAddr) {}
 }
 
 #[cfg(test)]
@@ -1,8 +1,14 @@
 use hickory_proto::{
     op::{Message, ResponseCode},
-    rr::{RData, Record, rdata::A},
+    rr::{
+        RData, Record, RecordType,
+        rdata::{
+            A, HTTPS, SVCB,
+            svcb::{IpHint, SvcParamKey, SvcParamValue},
+        },
+    },
 };
-use tracing::debug;
+use tracing::{debug, trace};
 
 use crate::app::dns::ThreadSafeDNSResolver;
 
@@ -14,12 +20,17 @@ pub async fn exchange_with_resolver<'a>(
     enhanced: bool,
 ) -> Result<Message, watfaq_dns::DNSError> {
     if resolver.fake_ip_enabled() {
-        let name = req
-            .query()
-            .ok_or(watfaq_dns::DNSError::InvalidOpQuery(
-                "malformed query message".to_string(),
-            ))?
-            .name();
+        let query = req.query().ok_or(watfaq_dns::DNSError::InvalidOpQuery(
+            "malformed query message".to_string(),
+        ))?;
+        let name = query.name();
+
+        // the other types, e.g. HTTPS, SRV and TXT, go to the nameservers
+        if !matches!(query.query_type(), RecordType::A | RecordType::AAAA) {
+            let mut res = exchange(resolver, req).await?;
+            rewrite_ip_hints(resolver, &mut res, enhanced).await;
+            return Ok(res);
+        }
 
         let host = req
             .query()
@@ -67,6 +78,13 @@ pub async fn exchange_with_resolver<'a>(
             }
         }
     }
+    exchange(resolver, req).await
+}
+
+async fn exchange(
+    resolver: &ThreadSafeDNSResolver,
+    req: &Message,
+) -> Result<Message, watfaq_dns::DNSError> {
     match resolver.exchange(req).await {
         Ok(m) => Ok(m),
         Err(e) => {
@@ -75,3 +93,151 @@ pub async fn exchange_with_resolver<'a>(
         }
     }
 }
+
+/// Points the address hints of the HTTPS and SVCB records of the faked hosts
+/// at their fake IP, or the browsers connecting by the hints would bypass it
+async fn rewrite_ip_hints(
+    resolver: &ThreadSafeDNSResolver,
+    res: &mut Message,
+    enhanced: bool,
+) {
+    for record in res.answers_mut().iter_mut() {
+        let svcb = match record.data() {
+            RData::HTTPS(https) => &https.0,
+            RData::SVCB(svcb) => svcb,
+            _ => continue,
+        };
+        let has_hints = svcb.svc_params().iter().any(|(k, _)| {
+            matches!(k, SvcParamKey::Ipv4Hint | SvcParamKey::Ipv6Hint)
+        });
+        if !has_hints {
+            continue;
+        }
+
+        // the service is at the owner of the record unless it names a target
+        let host = if svcb.target_name().is_root() {
+            record.name()
+        } else {
+            svcb.target_name()
+        };
+        let host = host.to_ascii().trim_end_matches('.').to_owned();
+        let fake_ip = match resolver.resolve_v4(&host, enhanced).await {
+            Ok(Some(ip)) if resolver.is_fake_ip(ip.into()).await => ip,
+            _ => continue,
+        };
+        trace!("rewriting the ip hints of {} to {}", host, fake_ip);
+
+        let params = svcb
+            .svc_params()
+            .iter()
+            .filter(|(k, _)| *k != SvcParamKey::Ipv6Hint)
+            .map(|(k, v)| match k {
+                SvcParamKey::Ipv4Hint => {
+                    (*k, SvcParamValue::Ipv4Hint(IpHint(vec![A(fake_ip)])))
+                }
+                _ => (*k, v.clone()),
+            })
+            .collect();
+        let rewritten =
+            SVCB::new(svcb.svc_priority(), svcb.target_name().clone(), params);
+        let rdata = match record.data() {
+            RData::HTTPS(_) => RData::HTTPS(HTTPS(rewritten)),
+            _ => RData::SVCB(rewritten),
+        };
+        record.set_data(rdata);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{
+            Name, RData, Record, RecordType,
+            rdata::{
+                A, AAAA, HTTPS, SVCB, TXT,
+                svcb::{Alpn, IpHint, SvcParamKey, SvcParamValue},
+            },
+        },
+    };
+
+    use super::exchange_with_resolver;
+    use crate::app::dns::{MockClashResolver, ThreadSafeDNSResolver};
+
+    fn https_record(name: &Name) -> Record {
+        let svcb = SVCB::new(
+            1,
+            Name::root(),
+            vec![
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h2".to_owned()])),
+                ),
+                (
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![A::new(1, 2, 3, 4)])),
+                ),
+                (
+                    SvcParamKey::Ipv6Hint,
+                    SvcParamValue::Ipv6Hint(IpHint(vec![AAAA::new(
+                        0x2001, 0xdb8, 0, 0, 0, 0, 0, 1,
+                    )])),
+                ),
+            ],
+        );
+        Record::from_rdata(name.clone(), 300, RData::HTTPS(HTTPS(svcb)))
+    }
+
+    #[tokio::test]
+    async fn test_fake_ip_other_types() {
+        let name = Name::from_str("example.com.").unwrap();
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_fake_ip_enabled().return_const(true);
+        resolver
+            .expect_resolve_v4()
+            .returning(|_, _| Ok(Some("198.18.0.3".parse().unwrap())));
+        resolver.expect_is_fake_ip().returning(|_| true);
+        let answer = name.clone();
+        resolver.expect_exchange().returning(move |req| {
+            let mut res = req.clone();
+            let record = match req.query().unwrap().query_type() {
+                RecordType::HTTPS => https_record(&answer),
+                _ => Record::from_rdata(
+                    answer.clone(),
+                    300,
+                    RData::TXT(TXT::new(vec!["hello".to_owned()])),
+                ),
+            };
+            res.add_answer(record);
+            Ok(res)
+        });
+        let resolver: ThreadSafeDNSResolver = Arc::new(resolver);
+
+        let mut req = Message::new();
+        req.add_query(Query::query(name.clone(), RecordType::TXT));
+        let res = exchange_with_resolver(&resolver, &req, true).await.unwrap();
+        assert!(matches!(res.answers()[0].data(), RData::TXT(_)));
+
+        let mut req = Message::new();
+        req.add_query(Query::query(name, RecordType::HTTPS));
+        let res = exchange_with_resolver(&resolver, &req, true).await.unwrap();
+        let RData::HTTPS(HTTPS(svcb)) = res.answers()[0].data() else {
+            panic!("not an HTTPS record");
+        };
+        assert_eq!(
+            svcb.svc_params(),
+            &[
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h2".to_owned()]))
+                ),
+                (
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![A::new(198, 18, 0, 3)]))
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use hickory_proto::{op, rr};
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use super::ThreadSafeDNSClient;
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// probes failed in a row before an upstream is left out of the queries
+const DEMOTE_AFTER: u32 = 3;
+
+/// The last probes of a DNS upstream
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct UpstreamStatus {
+    /// false once `DEMOTE_AFTER` probes failed in a row, until one succeeds
+    pub healthy: bool,
+    /// probes failed in a row
+    pub failures: u32,
+    /// milliseconds the last successful probe took
+    pub delay: Option<u16>,
+    pub error: Option<String>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+impl Default for UpstreamStatus {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            failures: 0,
+            delay: None,
+            error: None,
+            time: None,
+        }
+    }
+}
+
+/// Probes the DNS upstreams in the background, so that the queries skip
+/// those failing consistently while any other of their list answers
+#[derive(Default)]
+pub struct Upstreams {
+    upstreams: std::sync::RwLock<HashMap<String, UpstreamStatus>>,
+}
+
+impl Upstreams {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Probes `clients` every 30 seconds until `self` is dropped, a client
+    /// listed more than once is probed once
+    pub fn keep_probed(self: &Arc<Self>, clients: Vec<ThreadSafeDNSClient>) {
+        let mut clients = clients;
+        let mut seen = std::collections::HashSet::new();
+        clients.retain(|x| seen.insert(x.id()));
+        if clients.is_empty() {
+            return;
+        }
+
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PROBE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let Some(this) = Weak::upgrade(&this) else {
+                    break;
+                };
+                let results = futures::future::join_all(
+                    clients
+                        .iter()
+                        .map(|x| async move { (x.id(), probe(x).await) }),
+                )
+                .await;
+                for (id, result) in results {
+                    this.report(&id, result);
+                }
+            }
+        });
+    }
+
+    /// the clients of `clients` not demoted, or all of them if none is left
+    pub fn healthy(
+        &self,
+        clients: &[ThreadSafeDNSClient],
+    ) -> Vec<ThreadSafeDNSClient> {
+        let upstreams = self.upstreams.read().unwrap();
+        let healthy: Vec<_> = clients
+            .iter()
+            .filter(|x| upstreams.get(&x.id()).is_none_or(|x| x.healthy))
+            .cloned()
+            .collect();
+        if healthy.is_empty() {
+            clients.to_vec()
+        } else {
+            healthy
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, UpstreamStatus> {
+        self.upstreams.read().unwrap().clone()
+    }
+
+    fn report(&self, id: &str, result: Result<u16, String>) {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let status = upstreams.entry(id.to_owned()).or_default();
+        status.time = Some(Utc::now());
+        match result {
+            Ok(delay) => {
+                if !status.healthy {
+                    info!("DNS upstream {} answers again, back in use", id);
+                }
+                *status = UpstreamStatus {
+                    delay: Some(delay),
+                    time: status.time,
+                    ..Default::default()
+                };
+            }
+            Err(e) => {
+                debug!("DNS upstream {} failed a probe: {}", id, e);
+                status.failures += 1;
+                status.error = Some(e);
+                if status.healthy && status.failures >= DEMOTE_AFTER {
+                    warn!(
+                        "DNS upstream {} failed {} probes in a row, leaving it out \
+                         while the others answer",
+                        id, status.failures
+                    );
+                    status.healthy = false;
+                }
+            }
+        }
+    }
+}
+
+/// milliseconds until `client` answers a query of the root name servers,
+/// which any DNS server answers from its cache
+async fn probe(client: &ThreadSafeDNSClient) -> Result<u16, String> {
+    let mut query = op::Query::new();
+    query.set_name(rr::Name::root());
+    query.set_query_type(rr::RecordType::NS);
+    let mut req = op::Message::new();
+    req.set_id(rand::random::<u16>())
+        .set_recursion_desired(true)
+        .add_query(query);
+
+    let start = Instant::now();
+    let res = tokio::time::timeout(PROBE_TIMEOUT, client.exchange(&req))
+        .await
+        .map_err(|_| "timeout".to_owned())?
+        .map_err(|e| e.to_string())?;
+    match res.response_code() {
+        op::ResponseCode::ServFail | op::ResponseCode::Refused => {
+            Err(format!("answered {}", res.response_code()))
+        }
+        _ => Ok(start.elapsed().as_millis().min(u16::MAX as u128) as u16),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use hickory_proto::op;
+
+    use super::{DEMOTE_AFTER, Upstreams};
+    use crate::app::dns::{Client, ThreadSafeDNSClient};
+
+    #[derive(Debug)]
+    struct Upstream(&'static str);
+
+    #[async_trait]
+    impl Client for Upstream {
+        fn id(&self) -> String {
+            self.0.to_owned()
+        }
+
+        async fn exchange(&self, msg: &op::Message) -> anyhow::Result<op::Message> {
+            Ok(msg.clone())
+        }
+    }
+
+    #[test]
+    fn test_upstream_demotion() {
+        let upstreams = Upstreams::new();
+        let clients: Vec<ThreadSafeDNSClient> = vec![
+            Arc::new(Upstream("udp#a:53")),
+            Arc::new(Upstream("udp#b:53")),
+        ];
+
+        for _ in 0..DEMOTE_AFTER {
+            upstreams.report("udp#a:53", Err("timeout".to_owned()));
+        }
+        upstreams.report("udp#b:53", Ok(20));
+        let healthy = upstreams.healthy(&clients);
+        assert_eq!(healthy.len(), 1);
+        assert_eq!(healthy[0].id(), "udp#b:53");
+        assert!(!upstreams.snapshot()["udp#a:53"].healthy);
+
+        // none left, all of them are tried
+        for _ in 0..DEMOTE_AFTER {
+            upstreams.report("udp#b:53", Err("timeout".to_owned()));
+        }
+        assert_eq!(upstreams.healthy(&clients).len(), 2);
+
+        upstreams.report("udp#a:53", Ok(30));
+        let status = &upstreams.snapshot()["udp#a:53"];
+        assert!(status.healthy);
+        assert_eq!(status.failures, 0);
+        assert_eq!(status.delay, Some(30));
+        assert_eq!(upstreams.healthy(&clients)[0].id(), "udp#a:53");
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let client: ThreadSafeDNSClient = Arc::new(Upstream("udp#a:53"));
+        assert!(super::probe(&client).await.is_ok());
+    }
+}
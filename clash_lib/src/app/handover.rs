@@ -0,0 +1,393 @@
+//! Upgrades of the binary without dropping the connections. On `SIGUSR2`
+//! the running process starts its binary again with the same arguments,
+//! handing over the listening sockets and the TUN device as inherited file
+//! descriptors. The new process listens on them instead of binding, so no
+//! connection is refused in between, and the old one stops listening once
+//! the new one is up, then exits when its connections are done, or after
+//! `experimental.handover-drain`.
+//!
+//! The old process stops reading the TUN device before the new one starts,
+//! so no packet is read by both. The connections through the TUN are
+//! closed at handover though: their TCP state lives in the stack of the old
+//! process, the clients reconnect through the new one.
+//!
+//! The new process is a child of the old one until it exits, service
+//! managers tracking the main PID have to follow it, e.g. a `PIDFile` of
+//! systemd written by a hook.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::watch,
+};
+
+/// how long the connections of the old process are waited for by default
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+/// set from the config, in seconds
+static DRAIN_SECS: AtomicU64 = AtomicU64::new(DRAIN_TIMEOUT.as_secs());
+/// true from the moment the TUN device is handed over, back to false if
+/// the new process fails to come up
+static HANDING_OVER: LazyLock<watch::Sender<bool>> =
+    LazyLock::new(|| watch::Sender::new(false));
+
+/// `kind://address=fd` separated by `;`, e.g. `tcp://0.0.0.0:7890=5`
+#[cfg(unix)]
+const LISTEN_FDS_ENV: &str = "CLASH_LISTEN_FDS";
+/// the socket a byte is written to once the new process is up
+#[cfg(unix)]
+const READY_FD_ENV: &str = "CLASH_READY_FD";
+#[cfg(unix)]
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// the inherited sockets not listened on by then are closed, as they're gone
+/// from the config
+#[cfg(unix)]
+const UNCLAIMED_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether this process handed over its sockets, the routes of the TUN are
+/// left to the new process then
+pub fn handed_over() -> bool {
+    HANDED_OVER.load(Ordering::Relaxed)
+}
+
+/// Whether the TUN device is being handed over, its reader pauses while true
+pub fn handing_over() -> watch::Receiver<bool> {
+    HANDING_OVER.subscribe()
+}
+
+pub fn set_drain_timeout(timeout: Option<Duration>) {
+    DRAIN_SECS.store(
+        timeout.unwrap_or(DRAIN_TIMEOUT).as_secs(),
+        Ordering::Relaxed,
+    );
+}
+
+/// How long the connections are waited for once handed over
+pub fn drain_timeout() -> Duration {
+    Duration::from_secs(DRAIN_SECS.load(Ordering::Relaxed))
+}
+
+pub fn tcp_key(addr: SocketAddr) -> String {
+    format!("tcp://{}", addr)
+}
+
+pub fn udp_key(addr: SocketAddr) -> String {
+    format!("udp://{}", addr)
+}
+
+pub fn tun_key(device_id: &str) -> String {
+    format!("tun://{}", device_id)
+}
+
+/// A TCP listener on `addr`, the one handed over for it if any
+pub async fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    #[cfg(unix)]
+    {
+        let key = tcp_key(addr);
+        let listener = match imp::take_inherited(&key) {
+            Some(fd) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(addr).await?,
+        };
+        imp::register(key, &listener);
+        Ok(listener)
+    }
+    #[cfg(not(unix))]
+    TcpListener::bind(addr).await
+}
+
+/// A UDP socket on `addr`, the one handed over for it if any
+pub async fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    #[cfg(unix)]
+    {
+        let key = udp_key(addr);
+        let socket = match imp::take_inherited(&key) {
+            Some(fd) => {
+                let socket = std::net::UdpSocket::from(fd);
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)?
+            }
+            None => UdpSocket::bind(addr).await?,
+        };
+        imp::register(key, &socket);
+        Ok(socket)
+    }
+    #[cfg(not(unix))]
+    UdpSocket::bind(addr).await
+}
+
+#[cfg(unix)]
+pub use imp::*;
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        collections::HashMap,
+        io,
+        os::{
+            fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+            unix::process::CommandExt,
+        },
+        process::Command,
+        sync::{
+            LazyLock, Mutex,
+            atomic::{AtomicBool, Ordering},
+        },
+    };
+
+    use tokio::io::AsyncReadExt;
+    use tracing::{debug, info, warn};
+
+    use super::{
+        HANDED_OVER, HANDING_OVER, LISTEN_FDS_ENV, READY_FD_ENV, READY_TIMEOUT,
+        UNCLAIMED_TIMEOUT,
+    };
+
+    /// the sockets of this process, by key. The fds aren't owned, those
+    /// closed since are told apart when handing over
+    static BOUND: LazyLock<Mutex<HashMap<String, RawFd>>> =
+        LazyLock::new(Default::default);
+    /// the sockets handed over to this process, taken when listened on
+    pub(super) static INHERITED: LazyLock<Mutex<HashMap<String, OwnedFd>>> =
+        LazyLock::new(|| Mutex::new(inherited_from_env()));
+
+    fn inherited_from_env() -> HashMap<String, OwnedFd> {
+        let Ok(fds) = std::env::var(LISTEN_FDS_ENV) else {
+            return HashMap::new();
+        };
+        parse_fds(&fds)
+            .into_iter()
+            .map(|(key, fd)| {
+                // SAFETY: the fds in the env are inherited open, and only
+                // taken from here
+                unsafe {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    (key, OwnedFd::from_raw_fd(fd))
+                }
+            })
+            .collect()
+    }
+
+    pub(super) fn parse_fds(s: &str) -> Vec<(String, RawFd)> {
+        s.split(';')
+            .filter_map(|x| {
+                let (key, fd) = x.rsplit_once('=')?;
+                Some((key.to_owned(), fd.parse().ok()?))
+            })
+            .collect()
+    }
+
+    pub(super) fn format_fds(fds: &[(String, RawFd)]) -> String {
+        fds.iter()
+            .map(|(key, fd)| format!("{}={}", key, fd))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// The socket of `key` handed over to this process, if any
+    pub fn take_inherited(key: &str) -> Option<OwnedFd> {
+        let fd = INHERITED.lock().unwrap().remove(key);
+        if fd.is_some() {
+            debug!("listening on the handed over {}", key);
+        }
+        fd
+    }
+
+    /// Records the socket of `key` to be handed over on upgrade
+    pub fn register(key: String, fd: &impl AsRawFd) {
+        BOUND.lock().unwrap().insert(key, fd.as_raw_fd());
+    }
+
+    pub fn deregister(key: &str) {
+        BOUND.lock().unwrap().remove(key);
+    }
+
+    /// Whether `fd` is still the socket registered as `key`, rather than
+    /// closed, or reused by another socket since
+    pub(super) fn is_registered_socket(key: &str, fd: RawFd) -> bool {
+        // SAFETY: only borrowed for the checks below, which fail on an fd
+        // that's closed
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        if key.starts_with("tun://") {
+            return unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) } != -1;
+        }
+        let socket = socket2::SockRef::from(&fd);
+        let (kind, addr) = match key.split_once("://") {
+            Some(("tcp", addr)) => (socket2::Type::STREAM, addr),
+            Some(("udp", addr)) => (socket2::Type::DGRAM, addr),
+            _ => return false,
+        };
+        socket.r#type().is_ok_and(|x| x == kind)
+            && socket
+                .local_addr()
+                .ok()
+                .and_then(|x| x.as_socket())
+                .is_some_and(|x| x.to_string() == addr)
+            // not a connection accepted on the address
+            && (kind == socket2::Type::DGRAM || socket.peer_addr().is_err())
+    }
+
+    /// Starts the binary again with the sockets of this process, and waits
+    /// for it to be up. Returns its pid
+    pub async fn upgrade() -> io::Result<u32> {
+        let fds: Vec<_> = {
+            let mut bound = BOUND.lock().unwrap();
+            bound.retain(|key, fd| is_registered_socket(key, *fd));
+            bound.iter().map(|(k, v)| (k.clone(), *v)).collect()
+        };
+        let (ready, child_ready) = std::os::unix::net::UnixStream::pair()?;
+        let child_ready_fd = child_ready.as_raw_fd();
+
+        let exe = std::env::current_exe()?;
+        info!(
+            "upgrading to {}, handing over {} sockets",
+            exe.display(),
+            fds.len()
+        );
+        let mut cmd = Command::new(&exe);
+        cmd.args(std::env::args_os().skip(1))
+            .env(LISTEN_FDS_ENV, format_fds(&fds))
+            .env(READY_FD_ENV, child_ready_fd.to_string());
+        let inherited: Vec<_> = fds
+            .iter()
+            .map(|(_, fd)| *fd)
+            .chain([child_ready_fd])
+            .collect();
+        // SAFETY: only fcntl, which is async-signal-safe, runs in the fork
+        unsafe {
+            cmd.pre_exec(move || {
+                for fd in &inherited {
+                    if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        // the TUN isn't read by both processes at once
+        HANDING_OVER.send_replace(true);
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                HANDING_OVER.send_replace(false);
+                return Err(e);
+            }
+        };
+        drop(child_ready);
+
+        ready.set_nonblocking(true)?;
+        let mut ready = tokio::net::UnixStream::from_std(ready)?;
+        let mut byte = [0u8; 1];
+        match tokio::time::timeout(READY_TIMEOUT, ready.read_exact(&mut byte)).await
+        {
+            Ok(Ok(_)) => {
+                HANDED_OVER.store(true, Ordering::Relaxed);
+                Ok(child.id())
+            }
+            result => {
+                HANDING_OVER.send_replace(false);
+                let _ = child.kill();
+                tokio::task::spawn_blocking(move || child.wait());
+                Err(match result {
+                    Ok(Err(e)) => io::Error::other(format!(
+                        "the new process exited before it was up: {}",
+                        e
+                    )),
+                    _ => io::Error::other("the new process wasn't up in time"),
+                })
+            }
+        }
+    }
+
+    /// Tells the process upgraded from that this one is up, if it is one
+    pub fn notify_ready() {
+        static NOTIFIED: AtomicBool = AtomicBool::new(false);
+        let Some(fd) = std::env::var(READY_FD_ENV)
+            .ok()
+            .and_then(|x| x.parse::<RawFd>().ok())
+        else {
+            return;
+        };
+        if NOTIFIED.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        // SAFETY: inherited open from the process upgraded from for this
+        let mut ready = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+        if let Err(e) = std::io::Write::write_all(&mut ready, &[1]) {
+            warn!("failed to tell the process upgraded from: {}", e);
+        }
+
+        tokio::spawn(async {
+            tokio::time::sleep(UNCLAIMED_TIMEOUT).await;
+            for (key, _) in INHERITED.lock().unwrap().drain() {
+                debug!("closing the handed over {}, not listened on", key);
+            }
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::fd::{AsRawFd, OwnedFd};
+
+    use super::{
+        bind_tcp,
+        imp::{INHERITED, format_fds, is_registered_socket, parse_fds},
+        tcp_key,
+    };
+
+    #[test]
+    fn test_fds_env() {
+        let fds = vec![
+            ("tcp://0.0.0.0:7890".to_owned(), 5),
+            ("udp://[::1]:53".to_owned(), 6),
+            ("tun://dev://utun1989".to_owned(), 7),
+        ];
+        let s = format_fds(&fds);
+        assert_eq!(
+            s,
+            "tcp://0.0.0.0:7890=5;udp://[::1]:53=6;tun://dev://utun1989=7"
+        );
+        assert_eq!(parse_fds(&s), fds);
+        assert!(parse_fds("").is_empty());
+        assert_eq!(parse_fds("tcp://0.0.0.0:1=x;udp://0.0.0.0:2=3").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_bind_handed_over() {
+        let old = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = old.local_addr().unwrap();
+        // queued on the listener of the old process
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        INHERITED
+            .lock()
+            .unwrap()
+            .insert(tcp_key(addr), OwnedFd::from(old));
+
+        let new = bind_tcp(addr).await.unwrap();
+        assert_eq!(new.local_addr().unwrap(), addr);
+        let (_, peer) = new.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert!(INHERITED.lock().unwrap().get(&tcp_key(addr)).is_none());
+
+        // handed over again on the next upgrade
+        assert!(is_registered_socket(&tcp_key(addr), new.as_raw_fd()));
+        let fd = new.as_raw_fd();
+        drop(new);
+        assert!(!is_registered_socket(&tcp_key(addr), fd));
+    }
+}
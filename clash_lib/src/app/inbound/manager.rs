@@ -4,7 +4,7 @@ use tokio::{
     sync::{RwLock, oneshot},
     task::{JoinHandle, JoinSet},
 };
-use tracing::error;
+use tracing::{error, warn};
 
 use crate::{
     Result,
@@ -12,7 +12,10 @@ use crate::{
         dispatcher::Dispatcher, inbound::network_listener::NetworkInboundHandler,
     },
     common::auth::ThreadSafeAuthenticator,
-    config::internal::{config::BindAddress, listener::InboundOpts},
+    config::internal::{
+        config::BindAddress,
+        listener::{CommonInboundOpts, InboundOpts},
+    },
 };
 use std::{collections::HashMap, sync::Arc};
 
@@ -149,6 +152,7 @@ impl InboundManager {
                 InboundOpts::Http {
                     common_opts,
                     inherited,
+                    ..
                 } => {
                     if *inherited {
                         ports.port = Some(common_opts.port)
@@ -209,37 +213,92 @@ impl InboundManager {
         }
     }
 
-    pub async fn change_ports(&self, ports: Ports) {
+    /// whether the listeners of the top-level ports accept LAN clients
+    pub async fn get_allow_lan(&self) -> bool {
+        self.inbounds_opt
+            .read()
+            .await
+            .values()
+            .any(|opts| opts.inherited() && opts.common_opts().allow_lan)
+    }
+
+    pub async fn set_allow_lan(&self, allow_lan: bool) {
         let mut guard = self.inbounds_opt.write().await;
         for (_, opts) in guard.iter_mut() {
-            match &opts {
-                InboundOpts::Http { inherited, .. } => {
-                    if *inherited && let Some(port) = ports.port {
-                        *opts.port_mut() = port
-                    }
-                }
-                InboundOpts::Socks { inherited, .. } => {
-                    if *inherited && let Some(port) = ports.socks_port {
-                        *opts.port_mut() = port
-                    }
-                }
-                InboundOpts::Mixed { inherited, .. } => {
-                    if *inherited && let Some(port) = ports.mixed_port {
-                        *opts.port_mut() = port
-                    }
+            if opts.inherited() {
+                opts.common_opts_mut().allow_lan = allow_lan
+            }
+        }
+    }
+
+    /// a port of 0 closes its listener, one that's not listening yet is
+    /// opened
+    pub async fn change_ports(&self, ports: Ports) {
+        let allow_lan = self.get_allow_lan().await;
+        let listen = self.get_bind_address();
+        let mut guard = self.inbounds_opt.write().await;
+        for (name, port) in [
+            ("HTTP-IN", ports.port),
+            ("SOCKS-IN", ports.socks_port),
+            ("MIXED-IN", ports.mixed_port),
+            ("TPROXY-IN", ports.tproxy_port),
+            ("REDIR-IN", ports.redir_port),
+        ] {
+            let Some(port) = port else {
+                continue;
+            };
+            match guard.get_mut(name) {
+                Some(opts) if !opts.inherited() => {
+                    warn!("listener {} is not of the top-level ports", name);
                 }
-                InboundOpts::TProxy { inherited, .. } => {
-                    if *inherited && let Some(port) = ports.tproxy_port {
-                        *opts.port_mut() = port
-                    }
+                Some(_) if port == 0 => {
+                    guard.remove(name);
                 }
-                InboundOpts::Redir { inherited, .. } => {
-                    if *inherited && let Some(port) = ports.redir_port {
-                        *opts.port_mut() = port
-                    }
+                Some(opts) => *opts.port_mut() = port,
+                None if port == 0 => {}
+                None => {
+                    let common_opts = CommonInboundOpts {
+                        name: name.to_owned(),
+                        listen,
+                        allow_lan,
+                        port,
+                        ..Default::default()
+                    };
+                    guard.insert(name.to_owned(), inherited_inbound(common_opts));
                 }
-                _ => {}
             }
         }
     }
 }
+
+/// the listener of a top-level port, by its name
+fn inherited_inbound(common_opts: CommonInboundOpts) -> InboundOpts {
+    match common_opts.name.as_str() {
+        "HTTP-IN" => InboundOpts::Http {
+            common_opts,
+            inherited: true,
+            tls: None,
+        },
+        "SOCKS-IN" => InboundOpts::Socks {
+            common_opts,
+            udp: true,
+            inherited: true,
+            tls: None,
+        },
+        "MIXED-IN" => InboundOpts::Mixed {
+            common_opts,
+            udp: true,
+            inherited: true,
+            tls: None,
+        },
+        "TPROXY-IN" => InboundOpts::TProxy {
+            common_opts,
+            udp: true,
+            inherited: true,
+        },
+        _ => InboundOpts::Redir {
+            common_opts,
+            inherited: true,
+        },
+    }
+}
@@ -1,8 +1,8 @@
 use crate::{
-    common::auth::ThreadSafeAuthenticator,
-    config::listener::InboundOpts,
+    common::{auth::ThreadSafeAuthenticator, tls::new_server_config},
+    config::listener::{InboundOpts, InboundTls},
     proxy::{
-        http::HttpInbound,
+        http::{HttpInbound, ReverseProxyInbound},
         inbound::{InboudHandler, InboundHandlerTrait as _},
         mixed::MixedInbound,
         socks::SocksInbound,
@@ -15,9 +15,10 @@ use crate::proxy::tproxy::TproxyInbound;
 
 use crate::Dispatcher;
 use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 use tracing::{info, warn};
 
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 
 pub struct NetworkInboundHandler {
     pub name: String,
@@ -34,30 +35,62 @@ impl NetworkInboundHandler {
         self.build_and_insert_listener(set)
     }
 
+    fn tls_acceptor(
+        &self,
+        tls: Option<&InboundTls>,
+    ) -> crate::Result<Option<TlsAcceptor>> {
+        let Some(tls) = tls else {
+            return Ok(None);
+        };
+        let config = new_server_config(
+            Path::new(&tls.certificate),
+            Path::new(&tls.private_key),
+            tls.client_ca.as_deref().map(Path::new),
+        )
+        .map_err(|e| {
+            crate::Error::InvalidConfig(format!(
+                "invalid tls config for listener {}: {}",
+                self.name, e
+            ))
+        })?;
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+
     fn build_and_insert_listener(
         &self,
         set: &mut JoinSet<Result<(), crate::Error>>,
     ) -> crate::Result<()> {
+        let dispatcher =
+            Arc::new(self.dispatcher.for_listener(self.listener.common_opts()));
         let handler: InboudHandler = match &self.listener {
-            InboundOpts::Http { common_opts, .. } => HttpInbound::new(
+            InboundOpts::Http {
+                common_opts, tls, ..
+            } => HttpInbound::new(
                 (common_opts.listen.0, common_opts.port).into(),
                 common_opts.allow_lan,
-                self.dispatcher.clone(),
+                dispatcher.clone(),
                 self.authenticator.clone(),
+                self.tls_acceptor(tls.as_ref())?,
             )
             .into(),
-            InboundOpts::Socks { common_opts, .. } => SocksInbound::new(
+            InboundOpts::Socks {
+                common_opts, tls, ..
+            } => SocksInbound::new(
                 (common_opts.listen.0, common_opts.port).into(),
                 common_opts.allow_lan,
-                self.dispatcher.clone(),
+                dispatcher.clone(),
                 self.authenticator.clone(),
+                self.tls_acceptor(tls.as_ref())?,
             )
             .into(),
-            InboundOpts::Mixed { common_opts, .. } => MixedInbound::new(
+            InboundOpts::Mixed {
+                common_opts, tls, ..
+            } => MixedInbound::new(
                 (common_opts.listen.0, common_opts.port).into(),
                 common_opts.allow_lan,
-                self.dispatcher.clone(),
+                dispatcher.clone(),
                 self.authenticator.clone(),
+                self.tls_acceptor(tls.as_ref())?,
             )
             .into(),
             #[allow(unused)]
@@ -67,7 +100,7 @@ impl NetworkInboundHandler {
                     TproxyInbound::new(
                         (common_opts.listen.0, common_opts.port).into(),
                         common_opts.allow_lan,
-                        self.dispatcher.clone(),
+                        dispatcher.clone(),
                     )
                     .into()
                 }
@@ -83,11 +116,27 @@ impl NetworkInboundHandler {
                 common_opts,
                 network,
                 target,
+                proxy,
             } => TunnelInbound::new(
                 (common_opts.listen.0, common_opts.port).into(),
-                self.dispatcher.clone(),
+                dispatcher.clone(),
                 network.clone(),
                 target.clone(),
+                proxy.clone(),
+            )?
+            .into(),
+            InboundOpts::ReverseProxy {
+                common_opts,
+                upstream,
+                proxy,
+                tls,
+            } => ReverseProxyInbound::new(
+                (common_opts.listen.0, common_opts.port).into(),
+                common_opts.allow_lan,
+                dispatcher.clone(),
+                upstream,
+                proxy.clone(),
+                self.tls_acceptor(tls.as_ref())?,
             )?
             .into(),
         };
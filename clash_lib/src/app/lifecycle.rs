@@ -0,0 +1,223 @@
+//! Starts the subsystems in dependency order and keeps track of how far each
+//! of them got, for `/status`.
+//! Proxy providers are loaded with the outbounds, rule providers with the
+//! rules. TUN and the DNS listener are optional, the startup goes on without
+//! them if they fail.
+
+use std::{
+    fmt::{Display, Formatter},
+    future::Future,
+    sync::{Arc, RwLock},
+};
+
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::{Error, Result, Runner};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Component {
+    Dns,
+    Outbounds,
+    Rules,
+    Inbounds,
+    Tun,
+    DnsListener,
+}
+
+impl Component {
+    pub fn dependencies(&self) -> &'static [Component] {
+        match self {
+            Component::Dns => &[],
+            Component::Outbounds | Component::Rules => &[Component::Dns],
+            Component::Inbounds | Component::Tun => {
+                &[Component::Outbounds, Component::Rules]
+            }
+            Component::DnsListener => &[Component::Dns],
+        }
+    }
+}
+
+impl Display for Component {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Component::Dns => write!(f, "dns"),
+            Component::Outbounds => write!(f, "outbounds"),
+            Component::Rules => write!(f, "rules"),
+            Component::Inbounds => write!(f, "inbounds"),
+            Component::Tun => write!(f, "tun"),
+            Component::DnsListener => write!(f, "dns-listener"),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase", tag = "state", content = "reason")]
+pub enum ComponentState {
+    Starting,
+    Running,
+    /// not enabled in the config
+    Disabled,
+    Failed(String),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ComponentStatus {
+    pub name: Component,
+    #[serde(flatten)]
+    pub state: ComponentState,
+}
+
+/// The components of one config, in the order they were started
+#[derive(Default)]
+pub struct Lifecycle {
+    components: RwLock<Vec<ComponentStatus>>,
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(&self) -> Vec<ComponentStatus> {
+        self.components.read().unwrap().clone()
+    }
+
+    pub fn state(&self, c: Component) -> Option<ComponentState> {
+        self.components
+            .read()
+            .unwrap()
+            .iter()
+            .find(|s| s.name == c)
+            .map(|s| s.state.clone())
+    }
+
+    fn set(&self, c: Component, state: ComponentState) {
+        let mut components = self.components.write().unwrap();
+        match components.iter_mut().find(|s| s.name == c) {
+            Some(s) => s.state = state,
+            None => components.push(ComponentStatus { name: c, state }),
+        }
+    }
+
+    /// the first dependency of `c` that isn't running
+    fn missing_dependency(&self, c: Component) -> Option<Component> {
+        c.dependencies()
+            .iter()
+            .find(|d| self.state(**d) != Some(ComponentState::Running))
+            .copied()
+    }
+
+    /// starts a required component, its failure is the startup's
+    pub async fn start<T, F>(&self, c: Component, f: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        if let Some(d) = self.missing_dependency(c) {
+            let reason = format!("{} is not running", d);
+            self.set(c, ComponentState::Failed(reason.clone()));
+            return Err(Error::Operation(format!(
+                "failed to start {}: {}",
+                c, reason
+            )));
+        }
+
+        self.set(c, ComponentState::Starting);
+        match f.await {
+            Ok(v) => {
+                self.set(c, ComponentState::Running);
+                Ok(v)
+            }
+            Err(e) => {
+                error!("failed to start {}: {}", c, e);
+                self.set(c, ComponentState::Failed(e.to_string()));
+                Err(e)
+            }
+        }
+    }
+
+    /// starts an optional component, `None` if it's disabled or failed.
+    /// e.g. no permission to create the TUN device leaves TUN out only
+    pub async fn start_optional<T, F>(&self, c: Component, f: F) -> Option<T>
+    where
+        F: Future<Output = Result<Option<T>>>,
+    {
+        if let Some(d) = self.missing_dependency(c) {
+            warn!("{} is not started, {} is not running", c, d);
+            self.set(c, ComponentState::Failed(format!("{} is not running", d)));
+            return None;
+        }
+
+        self.set(c, ComponentState::Starting);
+        match f.await {
+            Ok(Some(v)) => {
+                self.set(c, ComponentState::Running);
+                Some(v)
+            }
+            Ok(None) => {
+                self.set(c, ComponentState::Disabled);
+                None
+            }
+            Err(e) => {
+                warn!("{} is not started: {}", c, e);
+                self.set(c, ComponentState::Failed(e.to_string()));
+                None
+            }
+        }
+    }
+
+    /// marks `c` as failed when `runner` stops with an error
+    pub fn watch(self: &Arc<Self>, c: Component, runner: Runner) -> Runner {
+        let this = self.clone();
+        Box::pin(async move {
+            let r = runner.await;
+            if let Err(e) = &r {
+                this.set(c, ComponentState::Failed(e.to_string()));
+            }
+            r
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Component, ComponentState, Lifecycle};
+    use crate::Error;
+
+    #[tokio::test]
+    async fn test_partial_startup() {
+        let lifecycle = Lifecycle::new();
+
+        lifecycle
+            .start(Component::Dns, async { Ok(()) })
+            .await
+            .unwrap();
+        // rules aren't started yet
+        assert!(
+            lifecycle
+                .start(Component::Inbounds, async { Ok(()) })
+                .await
+                .is_err()
+        );
+
+        let listener = lifecycle
+            .start_optional(Component::DnsListener, async {
+                Err::<Option<()>, _>(Error::Operation("permission denied".into()))
+            })
+            .await;
+        assert!(listener.is_none());
+        assert_eq!(
+            lifecycle.state(Component::DnsListener),
+            Some(ComponentState::Failed(
+                "operation error: permission denied".into()
+            ))
+        );
+
+        let status = serde_json::to_value(lifecycle.status()).unwrap();
+        assert_eq!(status[0]["name"], "dns");
+        assert_eq!(status[0]["state"], "running");
+        assert_eq!(status[1]["state"], "failed");
+        assert_eq!(status[2]["name"], "dns-listener");
+    }
+}
@@ -2,6 +2,7 @@ use std::io::IsTerminal;
 
 use crate::def::LogLevel;
 
+use once_cell::sync::OnceCell;
 use serde::Serialize;
 use tokio::sync::broadcast::Sender;
 
@@ -10,9 +11,13 @@ use tracing_appender::non_blocking::WorkerGuard;
 #[cfg(target_os = "ios")]
 use tracing_oslog::OsLogger;
 use tracing_subscriber::{
-    EnvFilter, Layer, filter::filter_fn, fmt::time::LocalTime, prelude::*,
+    EnvFilter, Layer, Registry, filter::filter_fn, fmt::time::LocalTime, prelude::*,
+    reload,
 };
 
+/// the global filter, swapped when the log level is changed at runtime
+static LOG_FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
 impl From<LogLevel> for LevelFilter {
     fn from(level: LogLevel) -> Self {
         match level {
@@ -70,16 +75,35 @@ where
     }
 }
 
+fn env_filter(level: LogLevel) -> EnvFilter {
+    let mut filter = EnvFilter::from_default_env()
+        .add_directive(format!("clash={}", level).parse().unwrap())
+        .add_directive(format!("clash_lib={}", level).parse().unwrap())
+        .add_directive("warn".parse().unwrap());
+    if cfg!(feature = "tokio-console") {
+        filter = filter
+            .add_directive("tokio=trace".parse().unwrap())
+            .add_directive("runtime=trace".parse().unwrap());
+    }
+    filter
+}
+
+/// changes the level set up by `setup_logging`
+pub fn set_log_level(level: LogLevel) -> anyhow::Result<()> {
+    LOG_FILTER
+        .get()
+        .ok_or_else(|| anyhow!("logging is not set up"))?
+        .reload(env_filter(level))
+        .map_err(|x| anyhow!("failed to change log level: {}", x))
+}
+
 pub fn setup_logging(
     level: LogLevel,
     collector: EventCollector,
     cwd: &str,
     log_file: Option<String>,
 ) -> anyhow::Result<Option<WorkerGuard>> {
-    let filter = EnvFilter::from_default_env()
-        .add_directive(format!("clash={}", level).parse().unwrap())
-        .add_directive(format!("clash_lib={}", level).parse().unwrap())
-        .add_directive("warn".parse().unwrap());
+    let (filter, handle) = reload::Layer::new(env_filter(level));
 
     let (appender, guard) = if let Some(log_file) = log_file {
         let file_appender = tracing_appender::rolling::daily(cwd, log_file);
@@ -89,16 +113,13 @@ pub fn setup_logging(
         (None, None)
     };
 
-    let subscriber = tracing_subscriber::registry();
+    // Global filter
+    let subscriber = tracing_subscriber::registry().with(filter);
 
     // Collect and expose data about the Tokio runtime (tasks, threads, resources,
     // etc.)
     #[cfg(feature = "tokio-console")]
     let subscriber = subscriber.with(console_subscriber::spawn());
-    #[cfg(feature = "tokio-console")]
-    let filter = filter
-        .add_directive("tokio=trace".parse().unwrap())
-        .add_directive("runtime=trace".parse().unwrap());
     let exclude = filter_fn(|metadata| {
         !metadata.target().contains("tokio")
             && !metadata.target().contains("runtime")
@@ -109,7 +130,6 @@ pub fn setup_logging(
     ));
 
     let subscriber = subscriber
-        .with(filter) // Global filter
         .with(collector.with_filter(exclude.clone())) // Log collector for API controller
         .with(appender.map(|x| {
             tracing_subscriber::fmt::Layer::new()
@@ -142,6 +162,7 @@ pub fn setup_logging(
 
     tracing::subscriber::set_global_default(subscriber)
         .map_err(|x| anyhow!("setup logging error: {}", x))?;
+    _ = LOG_FILTER.set(handle);
 
     Ok(guard)
 }
@@ -1,10 +1,18 @@
+#[cfg(feature = "api")]
 pub mod api;
+pub mod check;
+pub mod convert;
+pub mod ctl;
+pub mod deeplink;
 pub mod dispatcher;
 pub mod dns;
+pub mod handover;
 pub mod inbound;
+pub mod lifecycle;
 pub mod logging;
 pub mod net;
 pub mod outbound;
 pub mod profile;
+pub mod remote_config;
 pub mod remote_content_manager;
 pub mod router;
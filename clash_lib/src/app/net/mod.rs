@@ -9,6 +9,8 @@ use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 use serde::{Deserialize, Serialize};
 use tracing::trace;
 
+pub mod nat64;
+
 pub static DEFAULT_OUTBOUND_INTERFACE: LazyLock<
     Arc<tokio::sync::RwLock<Option<OutboundInterface>>>,
 > = LazyLock::new(Default::default);
@@ -133,7 +135,7 @@ pub fn get_outbound_interface() -> Option<OutboundInterface> {
     all_outbounds.into_iter().next()
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Interface {
     IpAddr(IpAddr),
     Name(String),
@@ -0,0 +1,234 @@
+//! IPv4 destinations on an IPv6-only network are dialed at the addresses
+//! synthesized with the NAT64 prefix (RFC 6052). The prefix is learned from
+//! the DNS64 answer for `ipv4only.arpa` (RFC 7050) unless configured with
+//! `nat64-prefix`.
+
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::RwLock,
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use super::get_outbound_interface;
+
+static PREFIX: RwLock<Option<Nat64Prefix>> = RwLock::new(None);
+
+/// what `ipv4only.arpa` resolves to without DNS64
+const WELL_KNOWN_V4: [Ipv4Addr; 2] =
+    [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+/// the prefix lengths RFC 6052 allows, the common one first
+const PREFIX_LENS: [u8; 6] = [96, 64, 56, 48, 40, 32];
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Nat64Prefix {
+    prefix: Ipv6Addr,
+    len: u8,
+}
+
+impl Nat64Prefix {
+    pub fn new(prefix: Ipv6Addr, len: u8) -> Option<Self> {
+        if !PREFIX_LENS.contains(&len) {
+            return None;
+        }
+        let mut octets = prefix.octets();
+        octets[len as usize / 8..].fill(0);
+        Some(Self {
+            prefix: octets.into(),
+            len,
+        })
+    }
+
+    /// the octets holding the IPv4 address, the 8th is reserved
+    fn positions(len: u8) -> impl Iterator<Item = usize> {
+        (len as usize / 8..16).filter(|i| *i != 8).take(4)
+    }
+
+    pub fn synthesize(&self, v4: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.octets();
+        for (i, b) in Self::positions(self.len).zip(v4.octets()) {
+            octets[i] = b;
+        }
+        octets.into()
+    }
+
+    /// the IPv4 address in `v6` if it's synthesized with this prefix
+    pub fn extract(&self, v6: Ipv6Addr) -> Option<Ipv4Addr> {
+        let n = self.len as usize / 8;
+        if v6.octets()[..n] != self.prefix.octets()[..n] {
+            return None;
+        }
+        Some(embedded(v6, self.len))
+    }
+
+    /// the prefix of a DNS64 answer for `ipv4only.arpa`
+    fn from_synthesized(v6: Ipv6Addr) -> Option<Self> {
+        PREFIX_LENS
+            .iter()
+            .find(|len| WELL_KNOWN_V4.contains(&embedded(v6, **len)))
+            .and_then(|len| Self::new(v6, *len))
+    }
+}
+
+fn embedded(v6: Ipv6Addr, len: u8) -> Ipv4Addr {
+    let octets = v6.octets();
+    let mut v4 = [0u8; 4];
+    for (b, i) in v4.iter_mut().zip(Nat64Prefix::positions(len)) {
+        *b = octets[i];
+    }
+    v4.into()
+}
+
+impl FromStr for Nat64Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let net = s
+            .parse::<ipnet::Ipv6Net>()
+            .map_err(|e| format!("invalid nat64 prefix {}: {}", s, e))?;
+        Self::new(net.network(), net.prefix_len()).ok_or_else(|| {
+            format!(
+                "invalid nat64 prefix {}: the length must be one of {:?}",
+                s, PREFIX_LENS
+            )
+        })
+    }
+}
+
+impl Display for Nat64Prefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.prefix, self.len)
+    }
+}
+
+/// the prefix in use, `None` unless the host is IPv6-only or it's configured
+pub fn prefix() -> Option<Nat64Prefix> {
+    *PREFIX.read().unwrap()
+}
+
+/// `addr` through NAT64 if it's a non-loopback IPv4 one and NAT64 is in use
+pub fn map_socket_addr(addr: SocketAddr) -> SocketAddr {
+    match (addr, prefix()) {
+        (SocketAddr::V4(v4), Some(p)) if !v4.ip().is_loopback() => {
+            SocketAddr::new(p.synthesize(*v4.ip()).into(), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+/// the other way round, for the sources of the replies
+pub fn unmap_socket_addr(addr: SocketAddr) -> SocketAddr {
+    match (addr, prefix()) {
+        (SocketAddr::V6(v6), Some(p)) => match p.extract(*v6.ip()) {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+/// sets the prefix to use, looking it up on an IPv6-only host unless
+/// `configured`
+pub async fn init(configured: Option<Nat64Prefix>) {
+    let prefix = match configured {
+        Some(p) => Some(p),
+        None if is_ipv6_only() => discover().await,
+        None => None,
+    };
+    if let Some(p) = prefix {
+        info!("IPv4 destinations are reached through NAT64 prefix {}", p);
+    }
+    *PREFIX.write().unwrap() = prefix;
+}
+
+fn is_ipv6_only() -> bool {
+    get_outbound_interface()
+        .is_some_and(|iface| iface.addr_v4.is_none() && iface.addr_v6.is_some())
+}
+
+async fn discover() -> Option<Nat64Prefix> {
+    let addrs = match tokio::time::timeout(
+        DISCOVERY_TIMEOUT,
+        tokio::net::lookup_host(("ipv4only.arpa", 0)),
+    )
+    .await
+    {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(e)) => {
+            warn!("failed to look up the NAT64 prefix: {}", e);
+            return None;
+        }
+        Err(_) => {
+            warn!("timed out looking up the NAT64 prefix");
+            return None;
+        }
+    };
+
+    let prefix = addrs
+        .filter_map(|a| match a.ip() {
+            IpAddr::V6(v6) => Nat64Prefix::from_synthesized(v6),
+            IpAddr::V4(_) => None,
+        })
+        .next();
+    if prefix.is_none() {
+        debug!("the host is IPv6-only but there is no DNS64");
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::Nat64Prefix;
+
+    #[test]
+    fn test_rfc6052_examples() {
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, synthesized) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::/96", "64:ff9b::192.0.2.33"),
+        ] {
+            let prefix = prefix.parse::<Nat64Prefix>().unwrap();
+            let synthesized = synthesized.parse::<Ipv6Addr>().unwrap();
+            assert_eq!(prefix.synthesize(v4), synthesized);
+            assert_eq!(prefix.extract(synthesized), Some(v4));
+        }
+
+        assert!("64:ff9b::/80".parse::<Nat64Prefix>().is_err());
+        assert_eq!(
+            "64:ff9b::/96"
+                .parse::<Nat64Prefix>()
+                .unwrap()
+                .extract("2001:db8::1".parse().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_prefix_from_dns64() {
+        assert_eq!(
+            Nat64Prefix::from_synthesized("64:ff9b::c000:aa".parse().unwrap()),
+            Some("64:ff9b::/96".parse().unwrap())
+        );
+        assert_eq!(
+            Nat64Prefix::from_synthesized(
+                "2001:db8:122:3c0:0:ab::".parse().unwrap()
+            ),
+            Some("2001:db8:122:300::/56".parse().unwrap())
+        );
+        assert_eq!(
+            Nat64Prefix::from_synthesized("2001:db8::1".parse().unwrap()),
+            None
+        );
+    }
+}
@@ -2,16 +2,18 @@ use anyhow::Result;
 use erased_serde::Serialize;
 use hyper::Uri;
 use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, RwLock, broadcast};
 use tracing::{debug, error, warn};
 
 use tracing::info;
 
 use crate::app::{
+    dispatcher::TrackerInfo,
     dns::ThreadSafeDNSResolver,
     profile::ThreadSafeCacheFile,
     remote_content_manager::{
-        ProxyManager,
+        ActiveConnection, Bandwidth, GroupSwitch, ProxyEvent, ProxyManager,
+        ServerAddr, UNKNOWN_DELAY,
         healthcheck::HealthCheck,
         providers::{file_vehicle, http_vehicle},
     },
@@ -19,24 +21,29 @@ use crate::app::{
 
 use crate::{
     app::remote_content_manager::providers::proxy_provider::{
-        PlainProvider, ProxySetProvider, ThreadSafeProxyProvider,
+        PlainProvider, ProxySetProvider, RegionClassifier, RegionProvider,
+        ThreadSafeProxyProvider,
     },
+    common::mmdb::Mmdb,
     config::internal::proxy::{
-        OutboundProxyProviderDef, PROXY_DIRECT, PROXY_GLOBAL, PROXY_REJECT,
+        HealthCheckSettings, OutboundProxyProviderDef, PROXY_DIRECT, PROXY_GLOBAL,
+        PROXY_REJECT, RegionGroups, TlsFragmentOpt, UiMeta,
     },
     print_and_exit,
     proxy::{
-        OutboundType, fallback, loadbalance, selector, socks, trojan,
+        OutboundType, dialer, fallback, loadbalance, multi_endpoint, selector,
+        smart, socks, trojan,
         utils::{DirectConnector, ProxyConnector},
         vmess, wg,
     },
+    session::Session,
 };
 
 use crate::{
     Error,
     config::internal::proxy::{OutboundGroupProtocol, OutboundProxyProtocol},
     proxy::{
-        AnyOutboundHandler, direct, reject, relay,
+        AnyOutboundHandler, block_page, direct, reject, relay,
         selector::ThreadSafeSelectorControl, urltest,
     },
 };
@@ -59,37 +66,86 @@ pub struct OutboundManager {
     proxy_providers: HashMap<String, ThreadSafeProxyProvider>,
     proxy_manager: ProxyManager,
     selector_control: HashMap<String, ThreadSafeSelectorControl>,
+    /// the providers of each proxy group, inline `proxies` included
+    group_providers: HashMap<String, Vec<ThreadSafeProxyProvider>>,
+    /// the global health check settings
+    health_check: HealthCheckSettings,
+    /// the `icon`, `hidden` and `order` of the proxies and groups that set any
+    ui_meta: HashMap<String, UiMeta>,
 }
 
-static DEFAULT_LATENCY_TEST_URL: &str = "http://www.gstatic.com/generate_204";
-
 pub type ThreadSafeOutboundManager = Arc<OutboundManager>;
 
 impl OutboundManager {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        outbounds: Vec<OutboundProxyProtocol>,
+        mut outbounds: Vec<OutboundProxyProtocol>,
         outbound_groups: Vec<OutboundGroupProtocol>,
         proxy_providers: HashMap<String, OutboundProxyProviderDef>,
         proxy_names: Vec<String>,
+        health_check: HealthCheckSettings,
+        tls_fragment: Option<TlsFragmentOpt>,
         dns_resolver: ThreadSafeDNSResolver,
         cache_store: ThreadSafeCacheFile,
+        country_mmdb: Option<Arc<Mmdb>>,
         cwd: String,
     ) -> Result<Self, Error> {
         let handlers = HashMap::new();
         let provider_registry = HashMap::new();
         let selector_control = HashMap::new();
-        let proxy_manager = ProxyManager::new(dns_resolver.clone());
+        let proxy_manager = ProxyManager::new(dns_resolver.clone())
+            .with_check_limit(health_check.max_concurrent())
+            .with_stale_after(health_check.stale_after())
+            .with_history_size(health_check.history_size())
+            .with_delay_sampling(health_check.delay_sampling())
+            .with_cache_store(cache_store.clone());
+        proxy_manager.restore().await;
+
+        for outbound in outbounds.iter_mut() {
+            outbound.inherit_tls_fragment(tls_fragment.as_ref());
+        }
 
         let mut m = Self {
             handlers,
             proxy_manager,
             selector_control,
             proxy_providers: provider_registry,
+            group_providers: HashMap::new(),
+            health_check,
+            ui_meta: outbounds
+                .iter()
+                .filter_map(|x| x.ui().map(|ui| (x.name().to_owned(), ui.clone())))
+                .chain(
+                    outbound_groups
+                        .iter()
+                        .map(|x| (x.name().to_owned(), x.ui().clone())),
+                )
+                .filter(|(_, ui)| *ui != UiMeta::default())
+                .collect(),
         };
 
+        let server_addrs = m.proxy_manager.server_addrs();
+        server_addrs.set(None, outbounds.iter().flat_map(|x| x.servers()));
+        server_addrs.keep_refreshed();
+        for outbound in outbounds.iter() {
+            if let Some(url) = outbound.health_check_url() {
+                m.proxy_manager.set_health_check_url(outbound.name(), url);
+            }
+            if let Some(server) = outbound.servers().first() {
+                m.proxy_manager.set_proxy_server(outbound.name(), server);
+            }
+        }
+
         debug!("initializing proxy providers");
-        m.load_proxy_providers(cwd, proxy_providers, dns_resolver)
-            .await?;
+        m.load_proxy_providers(
+            cwd,
+            proxy_providers,
+            tls_fragment,
+            dns_resolver,
+            cache_store.clone(),
+            country_mmdb,
+        )
+        .await?;
 
         debug!("initializing handlers");
         m.load_handlers(outbounds, outbound_groups, proxy_names, cache_store)
@@ -105,12 +161,43 @@ impl OutboundManager {
         self.handlers.get(name).cloned()
     }
 
+    /// the last resolution of each proxy server
+    pub fn server_addrs(&self) -> HashMap<String, ServerAddr> {
+        self.proxy_manager.server_addrs().snapshot()
+    }
+
     /// this doesn't populate history/liveness information
     pub fn get_proxy_provider(&self, name: &str) -> Option<ThreadSafeProxyProvider> {
         self.proxy_providers.get(name).cloned()
     }
 
     // API handles start
+    /// Health-checks the providers of the proxy group `name`, returns the
+    /// delays of its proxies alive after the check
+    pub async fn healthcheck_group(
+        &self,
+        name: &str,
+    ) -> Option<HashMap<String, u16>> {
+        let providers = self.group_providers.get(name)?;
+        futures::future::join_all(
+            providers
+                .iter()
+                .map(|p| async move { p.read().await.healthcheck().await }),
+        )
+        .await;
+
+        let mut r = HashMap::new();
+        for p in providers {
+            for proxy in p.read().await.proxies().await {
+                let delay = self.proxy_manager.last_delay(proxy.name()).await;
+                if delay < UNKNOWN_DELAY {
+                    r.insert(proxy.name().to_owned(), delay);
+                }
+            }
+        }
+        Some(r)
+    }
+
     pub fn get_selector_control(
         &self,
         name: &str,
@@ -129,12 +216,31 @@ impl OutboundManager {
             let alive = proxy_manager.alive(k).await;
             let history = proxy_manager.delay_history(k).await;
             let support_udp = v.support_udp().await;
-            let icon = v.icon();
 
             m.insert("history".to_string(), Box::new(history));
             m.insert("alive".to_string(), Box::new(alive));
             m.insert("name".to_string(), Box::new(k.to_owned()));
             m.insert("udp".to_string(), Box::new(support_udp));
+            m.insert(
+                "stats".to_string(),
+                Box::new(proxy_manager.outbound_stats(k)),
+            );
+            if let Some(bandwidth) = proxy_manager.bandwidth(k).await {
+                m.insert("bandwidth".to_string(), Box::new(bandwidth));
+            }
+            if let Some(stats) = proxy_manager.delay_stats(k).await {
+                m.insert("delayStats".to_string(), Box::new(stats));
+            }
+            let udp_history = proxy_manager.udp_delay_history(k).await;
+            if !udp_history.is_empty() {
+                m.insert("udpHistory".to_string(), Box::new(udp_history));
+            }
+            if let Some(sessions) = v.tls_sessions() {
+                m.insert("tlsSessions".to_string(), Box::new(sessions));
+            }
+            if let Some(url) = proxy_manager.health_check_url(k) {
+                m.insert("testUrl".to_string(), Box::new(url));
+            }
 
             if matches!(
                 v.proto(),
@@ -143,9 +249,11 @@ impl OutboundManager {
                     | OutboundType::Selector
                     | OutboundType::Fallback
                     | OutboundType::LoadBalance
+                    | OutboundType::Smart
             ) {
-                m.insert("icon".to_string(), Box::new(icon));
+                m.insert("icon".to_string(), Box::new(v.icon()));
             }
+            self.insert_ui_meta(k, &mut m);
 
             r.insert(k.clone(), Box::new(m) as _);
         }
@@ -169,10 +277,48 @@ impl OutboundManager {
         r.insert("alive".to_string(), Box::new(alive));
         r.insert("name".to_string(), Box::new(proxy.name().to_owned()));
         r.insert("udp".to_string(), Box::new(support_udp));
+        r.insert(
+            "stats".to_string(),
+            Box::new(proxy_manager.outbound_stats(proxy.name())),
+        );
+        if let Some(bandwidth) = proxy_manager.bandwidth(proxy.name()).await {
+            r.insert("bandwidth".to_string(), Box::new(bandwidth));
+        }
+        if let Some(stats) = proxy_manager.delay_stats(proxy.name()).await {
+            r.insert("delayStats".to_string(), Box::new(stats));
+        }
+        let udp_history = proxy_manager.udp_delay_history(proxy.name()).await;
+        if !udp_history.is_empty() {
+            r.insert("udpHistory".to_string(), Box::new(udp_history));
+        }
+        if let Some(sessions) = proxy.tls_sessions() {
+            r.insert("tlsSessions".to_string(), Box::new(sessions));
+        }
+        self.insert_ui_meta(proxy.name(), &mut r);
 
         r
     }
 
+    /// the fields of `UiMeta` that are set for `name`, for the API
+    fn insert_ui_meta(
+        &self,
+        name: &str,
+        m: &mut HashMap<String, Box<dyn Serialize + Send>>,
+    ) {
+        let Some(ui) = self.ui_meta.get(name) else {
+            return;
+        };
+        if let Some(icon) = &ui.icon {
+            m.insert("icon".to_string(), Box::new(icon.clone()));
+        }
+        if let Some(hidden) = ui.hidden {
+            m.insert("hidden".to_string(), Box::new(hidden));
+        }
+        if let Some(order) = ui.order {
+            m.insert("order".to_string(), Box::new(order));
+        }
+    }
+
     /// a wrapper of proxy_manager.url_test so that proxy_manager is not exposed
     pub async fn url_test(
         &self,
@@ -184,12 +330,82 @@ impl OutboundManager {
         proxy_manager.url_test(proxy, url, Some(timeout)).await
     }
 
+    pub async fn speed_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        url: &str,
+        max_bytes: u64,
+        duration: Duration,
+    ) -> std::io::Result<Bandwidth> {
+        self.proxy_manager
+            .speed_test(proxy, url, max_bytes, duration)
+            .await
+    }
+
     pub fn get_proxy_providers(&self) -> HashMap<String, ThreadSafeProxyProvider> {
         self.proxy_providers.clone()
     }
 
     // API handlers end
 
+    /// the groups switching with `interrupt-exist-connections`, for the
+    /// dispatcher to close their connections
+    pub fn subscribe_switches(&self) -> broadcast::Receiver<GroupSwitch> {
+        self.proxy_manager.subscribe_switches()
+    }
+
+    /// the changes the health checks find, for the API
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ProxyEvent> {
+        self.proxy_manager.subscribe_events()
+    }
+
+    /// wrappers of the proxy_manager connection counters for the dispatcher.
+    /// a dial counts on `name` and on the proxies its groups pick for `sess`
+    pub async fn report_dial(&self, name: &str, sess: &Session, ok: bool) {
+        for hop in self.hops(name, sess).await {
+            self.proxy_manager.report_dial(&hop, ok);
+        }
+    }
+
+    /// the proxy `name` dials `sess` through once its groups have picked
+    pub async fn last_hop(
+        &self,
+        name: &str,
+        sess: &Session,
+    ) -> Option<AnyOutboundHandler> {
+        let hops = self.hops(name, sess).await;
+        self.get_outbound(hops.last()?)
+    }
+
+    /// `name`, then the proxy each group on the way picks for `sess`
+    async fn hops(&self, name: &str, sess: &Session) -> Vec<String> {
+        let mut hops = vec![name.to_owned()];
+        let mut outbound = self.get_outbound(name);
+        while let Some(h) = outbound
+            && let Some(next) = h.current_proxy(sess).await
+            && !hops.contains(&next)
+        {
+            outbound = self.get_outbound(&next);
+            hops.push(next);
+        }
+        hops
+    }
+
+    pub async fn report_panic(&self, name: &str) {
+        self.proxy_manager.report_panic(name).await;
+    }
+
+    pub async fn report_connect(&self, name: &str, err: Option<&std::io::Error>) {
+        self.proxy_manager.report_connect(name, err).await;
+    }
+
+    pub async fn track_connection(
+        &self,
+        tracker: Arc<TrackerInfo>,
+    ) -> ActiveConnection {
+        self.proxy_manager.track_connection(tracker).await
+    }
+
     async fn init_handler_connectors(&self) -> Result<(), Error> {
         let mut connectors = HashMap::new();
         for handler in self.handlers.values() {
@@ -225,87 +441,43 @@ impl OutboundManager {
         let provider_registry = &mut self.proxy_providers;
         let handlers = &mut self.handlers;
         let selector_control = &mut self.selector_control;
+        let group_providers = &mut self.group_providers;
+        let global_hc = &self.health_check;
+        // relays and selectors are only checked when asked to
+        let manual_hc = HealthCheckSettings {
+            interval: Some(0),
+            lazy: Some(true),
+            ..Default::default()
+        }
+        .inherit(global_hc);
 
         let mut proxy_providers = vec![];
 
         for outbound in outbounds.iter() {
-            match outbound {
-                OutboundProxyProtocol::Direct => {
-                    handlers.insert(PROXY_DIRECT.to_string(), {
-                        let h = direct::Handler::new();
-                        Arc::new(h)
-                    });
-                }
-
-                OutboundProxyProtocol::Reject => {
-                    handlers.insert(PROXY_REJECT.to_string(), {
-                        let h = reject::Handler::new();
-                        Arc::new(h)
-                    });
-                }
-                #[cfg(feature = "shadowsocks")]
-                OutboundProxyProtocol::Ss(s) => {
-                    handlers.insert(s.common_opts.name.clone(), {
-                        let h: shadowsocks::Handler = s.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                OutboundProxyProtocol::Socks5(s) => {
-                    handlers.insert(s.common_opts.name.clone(), {
-                        let h: socks::Handler = s.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                OutboundProxyProtocol::Vmess(v) => {
-                    handlers.insert(v.common_opts.name.clone(), {
-                        let h: vmess::Handler = v.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                OutboundProxyProtocol::Trojan(v) => {
-                    handlers.insert(v.common_opts.name.clone(), {
-                        let h: trojan::Handler = v.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-                OutboundProxyProtocol::Hysteria2(h) => {
-                    handlers.insert(h.name.clone(), h.clone().try_into()?);
-                }
-
-                OutboundProxyProtocol::Wireguard(wg) => {
-                    warn!("wireguard is experimental");
-                    handlers.insert(wg.common_opts.name.clone(), {
-                        let h: wg::Handler = wg.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-
-                #[cfg(feature = "ssh")]
-                OutboundProxyProtocol::Ssh(ssh) => {
-                    handlers.insert(ssh.common_opts.name.clone(), {
-                        let h: ssh::Handler = ssh.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
+            let name = outbound.name().to_owned();
+            let handler = build_handler(outbound)?;
+            let backups = outbound.backup_endpoints()?;
+            if backups.is_empty() {
+                handlers.insert(name, handler);
+                continue;
+            }
 
-                #[cfg(feature = "onion")]
-                OutboundProxyProtocol::Tor(tor) => {
-                    handlers.insert(tor.name.clone(), {
-                        let h: tor::Handler = tor.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
-                #[cfg(feature = "tuic")]
-                OutboundProxyProtocol::Tuic(tuic) => {
-                    handlers.insert(tuic.common_opts.name.clone(), {
-                        let h: tuic::Handler = tuic.try_into()?;
-                        Arc::new(h) as _
-                    });
-                }
+            let mut endpoints =
+                vec![(outbound.endpoint().unwrap_or_default(), handler)];
+            for backup in backups.iter() {
+                endpoints.push((
+                    backup.endpoint().unwrap_or_default(),
+                    build_handler(backup)?,
+                ));
             }
+            handlers.insert(
+                name.clone(),
+                Arc::new(multi_endpoint::Handler::new(
+                    name,
+                    endpoints,
+                    proxy_manager.clone(),
+                )),
+            );
         }
 
         let mut outbound_groups = outbound_groups;
@@ -315,8 +487,7 @@ impl OutboundManager {
         fn make_provider_from_proxies(
             name: &str,
             proxies: &[String],
-            interval: u64,
-            lazy: bool,
+            settings: &HealthCheckSettings,
             handlers: &HashMap<String, AnyOutboundHandler>,
             proxy_manager: ProxyManager,
             proxy_providers: &mut Vec<ThreadSafeProxyProvider>,
@@ -342,12 +513,19 @@ impl OutboundManager {
 
             let hc = HealthCheck::new(
                 proxies.clone(),
-                DEFAULT_LATENCY_TEST_URL.to_owned(),
-                interval,
-                lazy,
+                settings.url(),
+                settings.interval(),
+                settings.lazy(),
                 proxy_manager.clone(),
             )
-            .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?;
+            .map_err(|e| Error::InvalidConfig(format!("invalid hc config {}", e)))?
+            .with_expected(
+                settings.timeout,
+                settings.expected_status.clone(),
+                settings.expected_keyword.clone(),
+            )
+            .with_udp_target(settings.udp_target.clone())
+            .with_mode(settings.mode.unwrap_or_default());
 
             let pd = Arc::new(RwLock::new(
                 PlainProvider::new(name.to_owned(), proxies, hc).map_err(|x| {
@@ -361,6 +539,24 @@ impl OutboundManager {
             Ok(pd)
         }
 
+        /// a group testing with its own url, expected response or mode keeps
+        /// the results apart from the other tests of its proxies
+        fn group_proxy_manager(
+            name: &str,
+            settings: &HealthCheckSettings,
+            proxy_manager: &ProxyManager,
+        ) -> ProxyManager {
+            if settings.url.is_some()
+                || settings.expected_status.is_some()
+                || settings.expected_keyword.is_some()
+                || settings.mode.is_some()
+            {
+                proxy_manager.for_group(name)
+            } else {
+                proxy_manager.clone()
+            }
+        }
+
         for outbound_group in outbound_groups.iter() {
             match outbound_group {
                 OutboundGroupProtocol::Relay(proto) => {
@@ -383,8 +579,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
-                            0,
-                            true,
+                            &manual_hc,
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -407,11 +602,12 @@ impl OutboundManager {
                         }
                     }
 
+                    group_providers.insert(proto.name.clone(), providers.clone());
                     let relay = relay::Handler::new(
                         relay::HandlerOptions {
                             name: proto.name.clone(),
                             common_opts: crate::proxy::HandlerCommonOptions {
-                                icon: proto.icon.clone(),
+                                icon: proto.ui.icon.clone(),
                                 ..Default::default()
                             },
                         },
@@ -421,6 +617,19 @@ impl OutboundManager {
                     handlers.insert(proto.name.clone(), relay);
                 }
                 OutboundGroupProtocol::UrlTest(proto) => {
+                    let proxy_manager = &group_proxy_manager(
+                        &proto.name,
+                        &proto.health_check,
+                        proxy_manager,
+                    );
+                    if proto.rank_by_udp.unwrap_or_default()
+                        && proto.health_check.inherit(global_hc).udp_target.is_none()
+                    {
+                        return Err(Error::InvalidConfig(format!(
+                            "proxy group {} ranks by udp without a udp-target",
+                            proto.name
+                        )));
+                    }
                     if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
@@ -440,8 +649,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
-                            proto.interval,
-                            proto.lazy.unwrap_or_default(),
+                            &proto.health_check.inherit(global_hc),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -464,16 +672,22 @@ impl OutboundManager {
                         }
                     }
 
+                    group_providers.insert(proto.name.clone(), providers.clone());
                     let url_test = urltest::Handler::new(
                         urltest::HandlerOptions {
                             name: proto.name.clone(),
                             common_opts: crate::proxy::HandlerCommonOptions {
-                                icon: proto.icon.clone(),
+                                icon: proto.ui.icon.clone(),
                                 ..Default::default()
                             },
+                            interrupt_exist_connections: proto
+                                .interrupt_exist_connections
+                                .unwrap_or_default(),
+                            unknown_nodes: proto.unknown_nodes.unwrap_or_default(),
                             ..Default::default()
                         },
                         proto.tolerance.unwrap_or_default(),
+                        proto.rank_by_udp.unwrap_or_default(),
                         providers,
                         proxy_manager.clone(),
                     );
@@ -481,6 +695,11 @@ impl OutboundManager {
                     handlers.insert(proto.name.clone(), Arc::new(url_test));
                 }
                 OutboundGroupProtocol::Fallback(proto) => {
+                    let proxy_manager = &group_proxy_manager(
+                        &proto.name,
+                        &proto.health_check,
+                        proxy_manager,
+                    );
                     if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
@@ -500,8 +719,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
-                            proto.interval,
-                            proto.lazy.unwrap_or_default(),
+                            &proto.health_check.inherit(global_hc),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -524,13 +742,15 @@ impl OutboundManager {
                         }
                     }
 
+                    group_providers.insert(proto.name.clone(), providers.clone());
                     let fallback = fallback::Handler::new(
                         fallback::HandlerOptions {
                             name: proto.name.clone(),
                             common_opts: crate::proxy::HandlerCommonOptions {
-                                icon: proto.icon.clone(),
+                                icon: proto.ui.icon.clone(),
                                 ..Default::default()
                             },
+                            unknown_nodes: proto.unknown_nodes.unwrap_or_default(),
                             ..Default::default()
                         },
                         providers,
@@ -540,6 +760,11 @@ impl OutboundManager {
                     handlers.insert(proto.name.clone(), Arc::new(fallback));
                 }
                 OutboundGroupProtocol::LoadBalance(proto) => {
+                    let proxy_manager = &group_proxy_manager(
+                        &proto.name,
+                        &proto.health_check,
+                        proxy_manager,
+                    );
                     if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
                             .use_provider
@@ -559,8 +784,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
-                            proto.interval,
-                            proto.lazy.unwrap_or_default(),
+                            &proto.health_check.inherit(global_hc),
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -583,13 +807,18 @@ impl OutboundManager {
                         }
                     }
 
+                    group_providers.insert(proto.name.clone(), providers.clone());
                     let load_balance = loadbalance::Handler::new(
                         loadbalance::HandlerOptions {
                             name: proto.name.clone(),
                             common_opts: crate::proxy::HandlerCommonOptions {
-                                icon: proto.icon.clone(),
+                                icon: proto.ui.icon.clone(),
                                 ..Default::default()
                             },
+                            strategy: proto.strategy.unwrap_or_default(),
+                            spill_factor: proto.spill_factor,
+                            sticky_ttl: proto.sticky_ttl.map(Duration::from_secs),
+                            unknown_nodes: proto.unknown_nodes.unwrap_or_default(),
                             ..Default::default()
                         },
                         providers,
@@ -598,6 +827,74 @@ impl OutboundManager {
 
                     handlers.insert(proto.name.clone(), Arc::new(load_balance));
                 }
+                OutboundGroupProtocol::Smart(proto) => {
+                    let proxy_manager = &group_proxy_manager(
+                        &proto.name,
+                        &proto.health_check,
+                        proxy_manager,
+                    );
+                    if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
+                        + proto
+                            .use_provider
+                            .as_ref()
+                            .map(|x| x.len())
+                            .unwrap_or_default()
+                        == 0
+                    {
+                        return Err(Error::InvalidConfig(format!(
+                            "proxy group {} has no proxies",
+                            proto.name
+                        )));
+                    }
+                    let mut providers: Vec<ThreadSafeProxyProvider> = vec![];
+
+                    if let Some(proxies) = &proto.proxies {
+                        providers.push(make_provider_from_proxies(
+                            &proto.name,
+                            proxies,
+                            &proto.health_check.inherit(global_hc),
+                            handlers,
+                            proxy_manager.clone(),
+                            &mut proxy_providers,
+                            provider_registry,
+                        )?);
+                    }
+
+                    if let Some(provider_names) = &proto.use_provider {
+                        for provider_name in provider_names {
+                            let provider = provider_registry
+                                .get(provider_name)
+                                .unwrap_or_else(|| {
+                                    print_and_exit!(
+                                        "provider {} not found",
+                                        provider_name
+                                    );
+                                })
+                                .clone();
+                            providers.push(provider);
+                        }
+                    }
+
+                    group_providers.insert(proto.name.clone(), providers.clone());
+                    let stored_routes =
+                        cache_store.get_smart_routes(&proto.name).await;
+                    let smart = smart::Handler::new(
+                        smart::HandlerOptions {
+                            name: proto.name.clone(),
+                            common_opts: crate::proxy::HandlerCommonOptions {
+                                icon: proto.ui.icon.clone(),
+                                ..Default::default()
+                            },
+                            unknown_nodes: proto.unknown_nodes.unwrap_or_default(),
+                        },
+                        providers,
+                        proxy_manager.clone(),
+                        cache_store.clone(),
+                        stored_routes,
+                    );
+
+                    handlers.insert(proto.name.clone(), Arc::new(smart));
+                }
                 OutboundGroupProtocol::Select(proto) => {
                     if proto.proxies.as_ref().map(|x| x.len()).unwrap_or_default()
                         + proto
@@ -618,8 +915,7 @@ impl OutboundManager {
                         providers.push(make_provider_from_proxies(
                             &proto.name,
                             proxies,
-                            0,
-                            true,
+                            &manual_hc,
                             handlers,
                             proxy_manager.clone(),
                             &mut proxy_providers,
@@ -646,16 +942,21 @@ impl OutboundManager {
                     let stored_selection =
                         cache_store.get_selected(&proto.name).await;
 
+                    group_providers.insert(proto.name.clone(), providers.clone());
                     let selector = selector::Handler::new(
                         selector::HandlerOptions {
                             name: proto.name.clone(),
                             udp: proto.udp.unwrap_or(true),
                             common_opts: crate::proxy::HandlerCommonOptions {
-                                icon: proto.icon.clone(),
+                                icon: proto.ui.icon.clone(),
                                 ..Default::default()
                             },
+                            interrupt_exist_connections: proto
+                                .interrupt_exist_connections
+                                .unwrap_or_default(),
                         },
                         providers,
+                        proxy_manager.clone(),
                         stored_selection,
                     )
                     .await;
@@ -674,12 +975,19 @@ impl OutboundManager {
         }
         let hc = HealthCheck::new(
             g.clone(),
-            DEFAULT_LATENCY_TEST_URL.to_owned(),
-            0, // this is a manual HC
-            true,
+            manual_hc.url(),
+            manual_hc.interval(),
+            manual_hc.lazy(),
             proxy_manager.clone(),
         )
-        .unwrap();
+        .unwrap()
+        .with_expected(
+            manual_hc.timeout,
+            manual_hc.expected_status.clone(),
+            manual_hc.expected_keyword.clone(),
+        )
+        .with_udp_target(manual_hc.udp_target.clone())
+        .with_mode(manual_hc.mode.unwrap_or_default());
         let pd = Arc::new(RwLock::new(
             PlainProvider::new(PROXY_GLOBAL.to_owned(), g, hc).unwrap(),
         ));
@@ -693,12 +1001,15 @@ impl OutboundManager {
                     icon: None,
                     ..Default::default()
                 },
+                interrupt_exist_connections: false,
             },
             vec![pd.clone()],
+            proxy_manager.clone(),
             stored_selection,
         )
         .await;
 
+        group_providers.insert(PROXY_GLOBAL.to_owned(), vec![pd.clone()]);
         provider_registry.insert(RESERVED_PROVIDER_NAME.to_owned(), pd);
         handlers.insert(PROXY_GLOBAL.to_owned(), Arc::new(h.clone()));
         selector_control.insert(PROXY_GLOBAL.to_owned(), Arc::new(Mutex::new(h)));
@@ -710,10 +1021,18 @@ impl OutboundManager {
         &mut self,
         cwd: String,
         proxy_providers: HashMap<String, OutboundProxyProviderDef>,
+        tls_fragment: Option<TlsFragmentOpt>,
         resolver: ThreadSafeDNSResolver,
+        cache_store: ThreadSafeCacheFile,
+        country_mmdb: Option<Arc<Mmdb>>,
     ) -> Result<(), Error> {
         let proxy_manager = &self.proxy_manager;
         let provider_registry = &mut self.proxy_providers;
+        let global_hc = &self.health_check;
+        let region_groups: Vec<_> = proxy_providers
+            .iter()
+            .filter_map(|(name, p)| Some((name.clone(), p.region_groups()?.clone())))
+            .collect();
         for (name, provider) in proxy_providers.into_iter() {
             match provider {
                 OutboundProxyProviderDef::Http(http) => {
@@ -724,22 +1043,33 @@ impl OutboundManager {
                         http.path,
                         Some(cwd.clone()),
                         resolver.clone(),
+                        Some(cache_store.clone()),
                     );
+                    let settings = http.health_check.settings(global_hc);
                     let hc = HealthCheck::new(
                         vec![],
-                        http.health_check.url,
-                        http.health_check.interval,
-                        http.health_check.lazy.unwrap_or_default(),
+                        settings.url(),
+                        settings.interval(),
+                        settings.lazy(),
                         proxy_manager.clone(),
                     )
                     .map_err(|e| {
                         Error::InvalidConfig(format!("invalid hc config {}", e))
-                    })?;
+                    })?
+                    .with_bandwidth(http.health_check.bandwidth)
+                    .with_expected(
+                        settings.timeout,
+                        settings.expected_status,
+                        settings.expected_keyword,
+                    )
+                    .with_udp_target(settings.udp_target)
+                    .with_mode(settings.mode.unwrap_or_default());
                     let provider = ProxySetProvider::new(
                         name.clone(),
                         Duration::from_secs(http.interval),
                         Arc::new(vehicle),
                         hc,
+                        tls_fragment.clone(),
                     )
                     .map_err(|x| {
                         Error::InvalidConfig(format!(
@@ -757,22 +1087,32 @@ impl OutboundManager {
                             .to_str()
                             .unwrap(),
                     );
+                    let settings = file.health_check.settings(global_hc);
                     let hc = HealthCheck::new(
                         vec![],
-                        file.health_check.url,
-                        file.health_check.interval,
-                        file.health_check.lazy.unwrap_or_default(),
+                        settings.url(),
+                        settings.interval(),
+                        settings.lazy(),
                         proxy_manager.clone(),
                     )
                     .map_err(|e| {
                         Error::InvalidConfig(format!("invalid hc config {}", e))
-                    })?;
+                    })?
+                    .with_bandwidth(file.health_check.bandwidth)
+                    .with_expected(
+                        settings.timeout,
+                        settings.expected_status,
+                        settings.expected_keyword,
+                    )
+                    .with_udp_target(settings.udp_target)
+                    .with_mode(settings.mode.unwrap_or_default());
 
                     let provider = ProxySetProvider::new(
                         name.clone(),
                         Duration::from_secs(file.interval.unwrap_or_default()),
                         Arc::new(vehicle),
                         hc,
+                        tls_fragment.clone(),
                     )
                     .map_err(|x| {
                         Error::InvalidConfig(format!(
@@ -802,6 +1142,86 @@ impl OutboundManager {
             info!("initialized provider {}", p.name());
         }
 
+        for (name, groups) in region_groups {
+            let parent = provider_registry[&name].clone();
+            let regions = groups.regions();
+            let classifier = Arc::new(RegionClassifier::new(
+                &regions,
+                &groups.patterns,
+                country_mmdb.clone(),
+                proxy_manager.clone(),
+            )?);
+            for region in regions {
+                let region_name = RegionGroups::group_name(&name, &region);
+                if provider_registry.contains_key(&region_name) {
+                    return Err(Error::InvalidConfig(format!(
+                        "provider {} is taken by the region groups of {}",
+                        region_name, name
+                    )));
+                }
+                let provider = RegionProvider::new(
+                    region_name.clone(),
+                    region,
+                    parent.clone(),
+                    classifier.clone(),
+                );
+                provider_registry
+                    .insert(region_name, Arc::new(RwLock::new(provider)));
+            }
+        }
+
         Ok(())
     }
 }
+
+fn build_handler(
+    outbound: &OutboundProxyProtocol,
+) -> Result<AnyOutboundHandler, Error> {
+    let handler: AnyOutboundHandler = match outbound {
+        OutboundProxyProtocol::Direct => Arc::new(direct::Handler::new()),
+        OutboundProxyProtocol::Reject => Arc::new(reject::Handler::new()),
+        OutboundProxyProtocol::BlockPage(b) => {
+            let h: block_page::Handler = b.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "shadowsocks")]
+        OutboundProxyProtocol::Ss(s) => {
+            let h: shadowsocks::Handler = s.try_into()?;
+            Arc::new(h)
+        }
+        OutboundProxyProtocol::Socks5(s) => {
+            let h: socks::Handler = s.try_into()?;
+            Arc::new(h)
+        }
+        OutboundProxyProtocol::Vmess(v) => {
+            let h: vmess::Handler = v.try_into()?;
+            Arc::new(h)
+        }
+        OutboundProxyProtocol::Trojan(v) => {
+            let h: trojan::Handler = v.try_into()?;
+            Arc::new(h)
+        }
+        OutboundProxyProtocol::Hysteria2(h) => h.clone().try_into()?,
+        OutboundProxyProtocol::Wireguard(wg) => {
+            warn!("wireguard is experimental");
+            let h: wg::Handler = wg.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "ssh")]
+        OutboundProxyProtocol::Ssh(ssh) => {
+            let h: ssh::Handler = ssh.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "onion")]
+        OutboundProxyProtocol::Tor(tor) => {
+            let h: tor::Handler = tor.try_into()?;
+            Arc::new(h)
+        }
+        #[cfg(feature = "tuic")]
+        OutboundProxyProtocol::Tuic(tuic) => {
+            let h: tuic::Handler = tuic.try_into()?;
+            Arc::new(h)
+        }
+    };
+    Ok(dialer::Handler::wrap(handler, outbound.dialer_opts()))
+}
@@ -0,0 +1,80 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tracing::{error, trace, warn};
+
+use super::{CacheStore, memory::MemoryStore};
+
+/// A yaml file with a map per bucket, written out as a whole on flush
+pub struct FileStore {
+    path: PathBuf,
+    inner: MemoryStore,
+    dirty: AtomicBool,
+}
+
+impl FileStore {
+    /// a missing or broken file starts an empty store
+    pub fn open<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let buckets = match std::fs::read_to_string(&path) {
+            Ok(s) => {
+                serde_yaml::from_str::<HashMap<String, HashMap<String, String>>>(&s)
+                    .unwrap_or_else(|e| {
+                        error!(
+                            "failed to parse cache file: {}, initializing a new one",
+                            e
+                        );
+                        HashMap::new()
+                    })
+            }
+            Err(e) => {
+                warn!("failed to read cache file: {}, initializing a new one", e);
+                HashMap::new()
+            }
+        };
+
+        Self {
+            path,
+            inner: MemoryStore::from_buckets(buckets),
+            dirty: AtomicBool::new(false),
+        }
+    }
+}
+
+impl CacheStore for FileStore {
+    fn get(&self, bucket: &str, key: &str) -> Option<String> {
+        self.inner.get(bucket, key)
+    }
+
+    fn set(&self, bucket: &str, key: &str, value: &str) {
+        self.inner.set(bucket, key, value);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn delete(&self, bucket: &str, key: &str) {
+        self.inner.delete(bucket, key);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn entries(&self, bucket: &str) -> HashMap<String, String> {
+        self.inner.entries(bucket)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let s = serde_yaml::to_string(&self.inner.buckets())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        std::fs::write(&self.path, s).inspect_err(|_| {
+            // try again next time
+            self.dirty.store(true, Ordering::Relaxed);
+        })?;
+        trace!("cache file flushed to {}", self.path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use std::{collections::HashMap, io, sync::RwLock};
+
+use super::CacheStore;
+
+/// Nothing survives a restart
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: RwLock<HashMap<String, HashMap<String, String>>>,
+}
+
+impl MemoryStore {
+    pub fn from_buckets(buckets: HashMap<String, HashMap<String, String>>) -> Self {
+        Self {
+            buckets: RwLock::new(buckets),
+        }
+    }
+
+    pub fn buckets(&self) -> HashMap<String, HashMap<String, String>> {
+        self.buckets.read().unwrap().clone()
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get(&self, bucket: &str, key: &str) -> Option<String> {
+        self.buckets
+            .read()
+            .unwrap()
+            .get(bucket)
+            .and_then(|b| b.get(key))
+            .cloned()
+    }
+
+    fn set(&self, bucket: &str, key: &str, value: &str) {
+        self.buckets
+            .write()
+            .unwrap()
+            .entry(bucket.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value.to_owned());
+    }
+
+    fn delete(&self, bucket: &str, key: &str) {
+        if let Some(b) = self.buckets.write().unwrap().get_mut(bucket) {
+            b.remove(key);
+        }
+    }
+
+    fn entries(&self, bucket: &str) -> HashMap<String, String> {
+        self.buckets
+            .read()
+            .unwrap()
+            .get(bucket)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,71 +1,164 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap, io, net::IpAddr, path::Path, sync::Arc, time::Duration,
+};
 
-use serde::{Deserialize, Serialize};
-use tracing::{error, trace, warn};
+use tracing::error;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Db {
-    selected: HashMap<String, String>,
-    ip_to_host: HashMap<String, String>,
-    host_to_ip: HashMap<String, String>,
+use crate::config::def::CacheBackend;
+
+mod file;
+mod memory;
+#[cfg(feature = "sled")]
+mod sled_store;
+
+pub use file::FileStore;
+pub use memory::MemoryStore;
+
+const CACHE_FILE: &str = "cache.db";
+#[cfg(feature = "sled")]
+const CACHE_SLED: &str = "cache.sled";
+
+const BUCKET_SELECTED: &str = "selected";
+const BUCKET_IP_TO_HOST: &str = "ip_to_host";
+const BUCKET_HOST_TO_IP: &str = "host_to_ip";
+const BUCKET_ETAG: &str = "etag";
+const BUCKET_NAMESERVER_IP: &str = "nameserver_ip";
+/// `<group>/<destination>` to the member of a `smart` group
+const BUCKET_SMART_ROUTES: &str = "smart_routes";
+const BUCKET_DELAY_HISTORY: &str = "delay_history";
+/// `day/<day>` and `month/<month>` to the bytes through each proxy and rule
+const BUCKET_USAGE: &str = "usage";
+const BUCKETS: [&str; 8] = [
+    BUCKET_SELECTED,
+    BUCKET_IP_TO_HOST,
+    BUCKET_HOST_TO_IP,
+    BUCKET_ETAG,
+    BUCKET_NAMESERVER_IP,
+    BUCKET_SMART_ROUTES,
+    BUCKET_DELAY_HISTORY,
+    BUCKET_USAGE,
+];
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A string key-value store of named buckets
+pub trait CacheStore: Send + Sync {
+    fn get(&self, bucket: &str, key: &str) -> Option<String>;
+    fn set(&self, bucket: &str, key: &str, value: &str);
+    fn delete(&self, bucket: &str, key: &str);
+    fn entries(&self, bucket: &str) -> HashMap<String, String>;
+    /// persists the changes, called periodically
+    fn flush(&self) -> io::Result<()>;
+}
+
+/// copies everything known in `from` into `to`
+#[cfg_attr(not(feature = "sled"), allow(dead_code))]
+pub fn migrate(from: &dyn CacheStore, to: &dyn CacheStore) -> io::Result<()> {
+    for bucket in BUCKETS {
+        for (k, v) in from.entries(bucket) {
+            to.set(bucket, &k, &v);
+        }
+    }
+    to.flush()
+}
+
+struct Inner {
+    store: Box<dyn CacheStore>,
+    store_selected: bool,
+    store_delay_history: bool,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Err(e) = self.store.flush() {
+            error!("failed to flush cache store: {}", e);
+        }
+    }
 }
 
 #[derive(Clone)]
-pub struct ThreadSafeCacheFile(Arc<tokio::sync::RwLock<CacheFile>>);
+pub struct ThreadSafeCacheFile(Arc<Inner>);
 
 impl ThreadSafeCacheFile {
-    pub fn new(path: &str, store_selected: bool) -> Self {
-        let store = Arc::new(tokio::sync::RwLock::new(CacheFile::new(
-            path,
-            store_selected,
-        )));
-
-        let path = path.to_string();
-        let store_clone = store.clone();
-
-        if store_selected {
-            tokio::spawn(async move {
-                let store = store_clone;
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-                    let r = store.read().await;
-                    let db = r.db.clone();
-                    drop(r);
-
-                    let s = match serde_yaml::to_string(&db) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            error!("failed to serialize cache file: {}", e);
-                            continue;
-                        }
-                    };
-
-                    match tokio::fs::write(&path, s).await {
-                        Err(e) => {
-                            error!("failed to write cache file: {}", e);
-                        }
-                        _ => {
-                            trace!("cache file flushed to {}", path);
-                        }
+    /// the store of `backend` in `cwd`, a cache file left by the `file`
+    /// backend is moved into a persistent one
+    pub fn open(
+        cwd: &Path,
+        backend: CacheBackend,
+        store_selected: bool,
+        store_delay_history: bool,
+    ) -> Self {
+        let file = cwd.join(CACHE_FILE);
+        let store: Box<dyn CacheStore> = match backend {
+            CacheBackend::File => Box::new(FileStore::open(file)),
+            CacheBackend::Memory => Box::new(MemoryStore::default()),
+            #[cfg(feature = "sled")]
+            CacheBackend::Sled => {
+                match sled_store::SledStore::open(cwd.join(CACHE_SLED)) {
+                    Ok(store) => {
+                        migrate_file(&file, &store);
+                        Box::new(store)
+                    }
+                    Err(e) => {
+                        error!(
+                            "failed to open sled cache store: {}, using the cache \
+                             file",
+                            e
+                        );
+                        Box::new(FileStore::open(file))
                     }
                 }
-            });
-        }
+            }
+            #[cfg(not(feature = "sled"))]
+            CacheBackend::Sled => {
+                tracing::warn!(
+                    "sled cache store is not built in, using the cache file"
+                );
+                Box::new(FileStore::open(file))
+            }
+        };
+        Self::with_store(store, store_selected, store_delay_history)
+    }
 
-        Self(store)
+    pub fn with_store(
+        store: Box<dyn CacheStore>,
+        store_selected: bool,
+        store_delay_history: bool,
+    ) -> Self {
+        let inner = Arc::new(Inner {
+            store,
+            store_selected,
+            store_delay_history,
+        });
+
+        let weak = Arc::downgrade(&inner);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(FLUSH_INTERVAL).await;
+                let Some(inner) = weak.upgrade() else {
+                    break;
+                };
+                match tokio::task::spawn_blocking(move || inner.store.flush()).await
+                {
+                    Ok(Err(e)) => error!("failed to flush cache store: {}", e),
+                    Err(e) => error!("failed to flush cache store: {}", e),
+                    _ => {}
+                }
+            }
+        });
+
+        Self(inner)
     }
 
     pub async fn set_selected(&self, group: &str, server: &str) {
-        let mut g = self.0.write().await;
-        if g.store_selected() {
-            g.set_selected(group, server);
+        if self.0.store_selected {
+            self.0.store.set(BUCKET_SELECTED, group, server);
         }
     }
 
     pub async fn get_selected(&self, group: &str) -> Option<String> {
-        let g = self.0.read().await;
-        if g.store_selected() {
-            g.db.selected.get(group).cloned()
+        if self.0.store_selected {
+            self.0.store.get(BUCKET_SELECTED, group)
         } else {
             None
         }
@@ -73,99 +166,183 @@ impl ThreadSafeCacheFile {
 
     #[allow(dead_code)]
     pub async fn get_selected_map(&self) -> HashMap<String, String> {
-        let g = self.0.read().await;
-        if g.store_selected() {
-            g.get_selected_map()
+        if self.0.store_selected {
+            self.0.store.entries(BUCKET_SELECTED)
         } else {
             HashMap::new()
         }
     }
 
     pub async fn set_ip_to_host(&self, ip: &str, host: &str) {
-        self.0.write().await.set_ip_to_host(ip, host);
+        self.0.store.set(BUCKET_IP_TO_HOST, ip, host);
     }
 
     pub async fn set_host_to_ip(&self, host: &str, ip: &str) {
-        self.0.write().await.set_host_to_ip(host, ip);
+        self.0.store.set(BUCKET_HOST_TO_IP, host, ip);
     }
 
     pub async fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
-        self.0.read().await.get_fake_ip(ip_or_host)
+        self.0
+            .store
+            .get(BUCKET_IP_TO_HOST, ip_or_host)
+            .or_else(|| self.0.store.get(BUCKET_HOST_TO_IP, ip_or_host))
     }
 
     pub async fn delete_fake_ip_pair(&self, ip: &str, host: &str) {
-        self.0.write().await.delete_fake_ip_pair(ip, host);
+        self.0.store.delete(BUCKET_IP_TO_HOST, ip);
+        self.0.store.delete(BUCKET_HOST_TO_IP, host);
     }
-}
 
-struct CacheFile {
-    db: Db,
+    /// the ETag of the last download from `url`
+    pub async fn get_etag(&self, url: &str) -> Option<String> {
+        self.0.store.get(BUCKET_ETAG, url)
+    }
 
-    store_selected: bool,
-}
+    pub async fn set_etag(&self, url: &str, etag: &str) {
+        self.0.store.set(BUCKET_ETAG, url, etag);
+    }
 
-impl CacheFile {
-    pub fn new(path: &str, store_selected: bool) -> Self {
-        let db = match std::fs::read_to_string(path) {
-            Ok(s) => match serde_yaml::from_str(&s) {
-                Ok(db) => db,
-                Err(e) => {
-                    error!(
-                        "failed to parse cache file: {}, initializing a new one",
-                        e
-                    );
-                    Db {
-                        selected: HashMap::new(),
-                        ip_to_host: HashMap::new(),
-                        host_to_ip: HashMap::new(),
-                    }
-                }
-            },
-            Err(e) => {
-                warn!("failed to read cache file: {}, initializing a new one", e);
-                Db {
-                    selected: HashMap::new(),
-                    ip_to_host: HashMap::new(),
-                    host_to_ip: HashMap::new(),
-                }
-            }
-        };
+    /// the address the nameserver `host` was last resolved to
+    pub async fn get_nameserver_ip(&self, host: &str) -> Option<IpAddr> {
+        self.0
+            .store
+            .get(BUCKET_NAMESERVER_IP, host)
+            .and_then(|ip| ip.parse().ok())
+    }
 
-        Self { db, store_selected }
+    pub async fn set_nameserver_ip(&self, host: &str, ip: IpAddr) {
+        if self.get_nameserver_ip(host).await != Some(ip) {
+            self.0
+                .store
+                .set(BUCKET_NAMESERVER_IP, host, &ip.to_string());
+        }
+    }
+
+    /// the liveness and delays of each proxy last stored, as JSON
+    pub async fn get_delay_histories(&self) -> HashMap<String, String> {
+        if self.0.store_delay_history {
+            self.0.store.entries(BUCKET_DELAY_HISTORY)
+        } else {
+            HashMap::new()
+        }
     }
 
-    pub fn store_selected(&self) -> bool {
-        self.store_selected
+    pub async fn set_delay_history(&self, proxy: &str, history: &str) {
+        if self.0.store_delay_history {
+            self.0.store.set(BUCKET_DELAY_HISTORY, proxy, history);
+        }
     }
 
-    pub fn set_selected(&mut self, group: &str, server: &str) {
-        self.db
-            .selected
-            .insert(group.to_string(), server.to_string());
+    /// the traffic of each day and month kept, as JSON
+    pub async fn get_usage(&self) -> HashMap<String, String> {
+        self.0.store.entries(BUCKET_USAGE)
     }
 
-    pub fn get_selected_map(&self) -> HashMap<String, String> {
-        self.db.selected.clone()
+    pub async fn set_usage(&self, period: &str, usage: &str) {
+        self.0.store.set(BUCKET_USAGE, period, usage);
     }
 
-    pub fn set_ip_to_host(&mut self, ip: &str, host: &str) {
-        self.db.ip_to_host.insert(ip.to_string(), host.to_string());
+    pub async fn delete_usage(&self, period: &str) {
+        self.0.store.delete(BUCKET_USAGE, period);
     }
 
-    pub fn set_host_to_ip(&mut self, host: &str, ip: &str) {
-        self.db.host_to_ip.insert(host.to_string(), ip.to_string());
+    /// the destinations learned by the `smart` group `group`, to the members
+    /// they were sent through
+    pub async fn get_smart_routes(&self, group: &str) -> HashMap<String, String> {
+        let prefix = format!("{}/", group);
+        self.0
+            .store
+            .entries(BUCKET_SMART_ROUTES)
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix(&prefix)?.to_owned(), v)))
+            .filter(|(k, _)| !k.contains('/'))
+            .collect()
     }
 
-    pub fn get_fake_ip(&self, ip_or_host: &str) -> Option<String> {
-        self.db
-            .ip_to_host
-            .get(ip_or_host)
-            .or_else(|| self.db.host_to_ip.get(ip_or_host))
-            .cloned()
+    pub async fn set_smart_route(&self, group: &str, dest: &str, proxy: &str) {
+        self.0
+            .store
+            .set(BUCKET_SMART_ROUTES, &format!("{}/{}", group, dest), proxy);
     }
 
-    pub fn delete_fake_ip_pair(&mut self, ip: &str, host: &str) {
-        self.db.ip_to_host.remove(ip);
-        self.db.host_to_ip.remove(host);
+    pub async fn delete_smart_route(&self, group: &str, dest: &str) {
+        self.0
+            .store
+            .delete(BUCKET_SMART_ROUTES, &format!("{}/{}", group, dest));
+    }
+}
+
+/// the file is renamed once moved, so it's only done once
+#[cfg(feature = "sled")]
+fn migrate_file(path: &Path, to: &dyn CacheStore) {
+    if !path.exists() {
+        return;
+    }
+    if let Err(e) = migrate(&FileStore::open(path), to) {
+        error!("failed to migrate cache file {}: {}", path.display(), e);
+        return;
+    }
+    let migrated = path.with_extension("db.migrated");
+    match std::fs::rename(path, &migrated) {
+        Ok(_) => tracing::info!(
+            "cache file migrated, the old one is kept at {}",
+            migrated.display()
+        ),
+        Err(e) => error!("failed to rename cache file {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStore, FileStore, MemoryStore, ThreadSafeCacheFile, migrate};
+
+    #[test]
+    fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        // written by the older versions
+        std::fs::write(
+            &path,
+            "selected:\n  PROXY: ss\nip_to_host: {}\nhost_to_ip: {}\n",
+        )
+        .unwrap();
+
+        let store = FileStore::open(&path);
+        assert_eq!(store.get("selected", "PROXY").as_deref(), Some("ss"));
+        store.set("etag", "https://example.com", "\"abc\"");
+        store.delete("selected", "PROXY");
+        store.flush().unwrap();
+
+        let store = FileStore::open(&path);
+        assert_eq!(store.get("selected", "PROXY"), None);
+        assert_eq!(
+            store.get("etag", "https://example.com").as_deref(),
+            Some("\"abc\"")
+        );
+
+        let memory = MemoryStore::default();
+        migrate(&store, &memory).unwrap();
+        assert_eq!(memory.entries("etag").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fake_ip_pair() {
+        let cache = ThreadSafeCacheFile::with_store(
+            Box::new(MemoryStore::default()),
+            false,
+            false,
+        );
+        cache.set_ip_to_host("198.18.0.1", "example.com").await;
+        cache.set_host_to_ip("example.com", "198.18.0.1").await;
+        cache.set_selected("PROXY", "ss").await;
+
+        assert_eq!(
+            cache.get_fake_ip("example.com").await.as_deref(),
+            Some("198.18.0.1")
+        );
+        assert_eq!(cache.get_selected("PROXY").await, None);
+
+        cache.delete_fake_ip_pair("198.18.0.1", "example.com").await;
+        assert_eq!(cache.get_fake_ip("198.18.0.1").await, None);
     }
 }
@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+
+use tracing::error;
+
+use super::CacheStore;
+
+/// a database can only be opened once in a process, it's shared by the
+/// stores of the old and the new config on reload
+static OPENED: LazyLock<Mutex<HashMap<PathBuf, sled::Db>>> =
+    LazyLock::new(Default::default);
+
+/// A bucket per sled tree, written to disk as it changes
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut opened = OPENED.lock().unwrap();
+        let path = path.as_ref().to_path_buf();
+        let db = match opened.get(&path) {
+            Some(db) => db.clone(),
+            None => {
+                let db = sled::open(&path).map_err(io::Error::other)?;
+                opened.insert(path, db.clone());
+                db
+            }
+        };
+        Ok(Self { db })
+    }
+
+    fn tree(&self, bucket: &str) -> Option<sled::Tree> {
+        self.db
+            .open_tree(bucket)
+            .inspect_err(|e| error!("failed to open cache bucket {}: {}", bucket, e))
+            .ok()
+    }
+}
+
+impl CacheStore for SledStore {
+    fn get(&self, bucket: &str, key: &str) -> Option<String> {
+        let v = self.tree(bucket)?.get(key).ok()??;
+        String::from_utf8(v.to_vec()).ok()
+    }
+
+    fn set(&self, bucket: &str, key: &str, value: &str) {
+        if let Some(tree) = self.tree(bucket)
+            && let Err(e) = tree.insert(key, value)
+        {
+            error!("failed to write cache: {}", e);
+        }
+    }
+
+    fn delete(&self, bucket: &str, key: &str) {
+        if let Some(tree) = self.tree(bucket)
+            && let Err(e) = tree.remove(key)
+        {
+            error!("failed to write cache: {}", e);
+        }
+    }
+
+    fn entries(&self, bucket: &str) -> HashMap<String, String> {
+        let Some(tree) = self.tree(bucket) else {
+            return HashMap::new();
+        };
+        tree.iter()
+            .filter_map(Result::ok)
+            .filter_map(|(k, v)| {
+                Some((
+                    String::from_utf8(k.to_vec()).ok()?,
+                    String::from_utf8(v.to_vec()).ok()?,
+                ))
+            })
+            .collect()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.db.flush().map(|_| ()).map_err(io::Error::other)
+    }
+}
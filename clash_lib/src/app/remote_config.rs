@@ -0,0 +1,156 @@
+//! The config of `-f https://...`. It's downloaded at startup and cached in
+//! the working directory, so that clash still starts from the last download
+//! when the URL can't be reached. A pinned checksum is checked against
+//! either.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    app::{check::fetch, dns::SystemResolver},
+    common::http::new_http_client,
+};
+
+pub struct RemoteConfig {
+    pub content: String,
+    /// why the cached copy is used instead of a download, if it is
+    pub stale: Option<String>,
+}
+
+pub fn is_url(config: &str) -> bool {
+    config.starts_with("http://") || config.starts_with("https://")
+}
+
+/// The config at `url`, from its copy cached in `cwd` if the download fails
+/// or doesn't match `sha256`
+pub async fn load(
+    url: &str,
+    sha256: Option<&str>,
+    cwd: &Path,
+) -> Result<RemoteConfig, String> {
+    let cache = cache_path(cwd, url);
+    let err = match download(url).await.and_then(|x| verify(x, sha256)) {
+        Ok(content) => {
+            save(&cache, &content).map_err(|e| {
+                format!("failed to cache the config at {}: {}", cache.display(), e)
+            })?;
+            return Ok(RemoteConfig {
+                content,
+                stale: None,
+            });
+        }
+        Err(e) => format!("failed to download the config: {}", e),
+    };
+
+    let content = std::fs::read(&cache)
+        .map_err(|e| e.to_string())
+        .and_then(|x| verify(x, sha256))
+        .map_err(|e| {
+            format!("{}, nor read its cache at {}: {}", err, cache.display(), e)
+        })?;
+    Ok(RemoteConfig {
+        content,
+        stale: Some(err),
+    })
+}
+
+async fn download(url: &str) -> Result<Vec<u8>, String> {
+    let client = SystemResolver::new(false)
+        .map_err(|x| std::io::Error::other(x.to_string()))
+        .and_then(|r| new_http_client(Arc::new(r)))
+        .map_err(|e| format!("failed to create http client: {}", e))?;
+    fetch(&client, url).await
+}
+
+/// the content as a config, if it's YAML and has the `sha256` pinned, in hex
+fn verify(content: Vec<u8>, sha256: Option<&str>) -> Result<String, String> {
+    if let Some(expected) = sha256 {
+        let actual = hex(&Sha256::digest(&content));
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(format!("checksum mismatch, the sha256 is {}", actual));
+        }
+    }
+    let content = String::from_utf8(content).map_err(|e| e.to_string())?;
+    // an error page of a captive portal or of the server mustn't replace
+    // the cache
+    serde_yaml::from_str::<serde_yaml::Mapping>(&content)
+        .map_err(|e| format!("not a YAML config: {}", e))?;
+    Ok(content)
+}
+
+/// named after the hash of `url`, which may have a token in it
+fn cache_path(cwd: &Path, url: &str) -> PathBuf {
+    let hash = hex(&Sha256::digest(url.as_bytes()));
+    cwd.join(format!("config-{}.yaml", &hash[..16]))
+}
+
+/// readable by the owner only, as the config has the secrets of the proxies
+fn save(path: &Path, content: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("yaml.tmp");
+    // the mode is only set when the file is created
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(content.as_bytes())?;
+    std::fs::rename(tmp, path)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_path, hex, is_url, load, save, verify};
+
+    use sha2::{Digest, Sha256};
+
+    const CONFIG: &str = "port: 7890\n";
+
+    #[test]
+    fn test_verify() {
+        let sha256 = hex(&Sha256::digest(CONFIG));
+        assert_eq!(
+            verify(CONFIG.into(), Some(&sha256.to_uppercase())).unwrap(),
+            CONFIG
+        );
+        assert!(verify(CONFIG.into(), Some("00")).is_err());
+        assert!(verify(b"<html>".to_vec(), None).is_err());
+
+        assert!(is_url("https://example.com/config.yaml"));
+        assert!(!is_url("config.yaml"));
+    }
+
+    #[tokio::test]
+    async fn test_load_from_cache() {
+        let cwd = std::env::temp_dir().join("clash-rs-remote-config");
+        std::fs::create_dir_all(&cwd).unwrap();
+        // nothing listens on the port 1 of the loopback
+        let url = "http://127.0.0.1:1/config.yaml";
+
+        std::fs::remove_file(cache_path(&cwd, url)).ok();
+        assert!(load(url, None, &cwd).await.is_err());
+
+        save(&cache_path(&cwd, url), CONFIG).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(cache_path(&cwd, url))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let config = load(url, None, &cwd).await.unwrap();
+        assert_eq!(config.content, CONFIG);
+        assert!(config.stale.is_some());
+        assert!(load(url, Some("00"), &cwd).await.is_err());
+    }
+}
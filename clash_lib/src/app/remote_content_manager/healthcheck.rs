@@ -1,24 +1,43 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use tokio::time::Instant;
 use tracing::debug;
 
-use crate::proxy::AnyOutboundHandler;
+use crate::{
+    config::internal::proxy::{
+        BandwidthCheck, CheckMode, ExpectedStatus, HealthCheck as HealthCheckDef,
+        HealthCheckSettings,
+    },
+    proxy::AnyOutboundHandler,
+};
 
-use super::ProxyManager;
+use super::{Expected, MAX_DELAY, ProxyManager};
+
+/// how long a proxy is given to download the bandwidth check size
+const BANDWIDTH_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
 
 struct HealCheckInner {
     last_check: Instant,
     proxies: Vec<AnyOutboundHandler>,
-    task_handle: Option<Arc<tokio::task::JoinHandle<()>>>,
 }
 
 pub struct HealthCheck {
     url: String,
     interval: u64,
     lazy: bool,
+    udp_target: Option<String>,
+    /// milliseconds
+    timeout: Option<u64>,
+    expected: Expected,
+    bandwidth: Option<BandwidthCheck>,
     proxy_manager: ProxyManager,
     inner: Arc<tokio::sync::RwLock<HealCheckInner>>,
+    /// the periodic checks and bandwidth checks, aborted when the provider
+    /// is dropped on a reload or kicked off again
+    tasks: Mutex<Vec<tokio::task::JoinHandle<()>>>,
 }
 
 impl HealthCheck {
@@ -33,17 +52,61 @@ impl HealthCheck {
             url,
             interval,
             lazy,
+            udp_target: None,
+            timeout: None,
+            expected: Expected::default(),
+            bandwidth: None,
             proxy_manager,
             inner: Arc::new(tokio::sync::RwLock::new(HealCheckInner {
                 last_check: tokio::time::Instant::now(),
                 proxies,
-                task_handle: None,
             })),
+            tasks: Default::default(),
         };
         Ok(health_check)
     }
 
+    /// also measures the bandwidth of the proxies, one at a time as it's
+    /// heavier than the latency checks
+    pub fn with_bandwidth(mut self, bandwidth: Option<BandwidthCheck>) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// also measures the UDP round trip time of the proxies relaying UDP to
+    /// `udp_target` on each check
+    pub fn with_udp_target(mut self, udp_target: Option<String>) -> Self {
+        self.udp_target = udp_target;
+        self
+    }
+
+    /// counts the proxies alive only on responses within `timeout`
+    /// milliseconds, with the `status` and containing the `keyword` if set
+    pub fn with_expected(
+        mut self,
+        timeout: Option<u64>,
+        status: Option<ExpectedStatus>,
+        keyword: Option<String>,
+    ) -> Self {
+        self.timeout = timeout;
+        self.expected = Expected {
+            status,
+            keyword,
+            mode: self.expected.mode,
+        };
+        self
+    }
+
+    /// times only the connection to the host of the url, or its TLS
+    /// handshake too, rather than requests to it
+    pub fn with_mode(mut self, mode: CheckMode) -> Self {
+        self.expected.mode = mode;
+        self
+    }
+
     pub async fn kick_off(&self) {
+        self.abort();
+
         let proxy_manager = self.proxy_manager.clone();
         let interval = self.interval;
         let lazy = self.lazy;
@@ -51,15 +114,29 @@ impl HealthCheck {
 
         {
             let url = self.url.clone();
+            let udp_target = self.udp_target.clone();
+            let timeout = self.timeout();
+            let expected = self.expected.clone();
             let proxies = proxies.clone();
             tokio::spawn(async move {
-                proxy_manager.check(&proxies, &url, None).await;
+                check(
+                    &proxy_manager,
+                    &proxies,
+                    &url,
+                    udp_target.as_deref(),
+                    timeout,
+                    &expected,
+                )
+                .await;
             });
         }
 
         let inner = self.inner.clone();
         let proxy_manager = self.proxy_manager.clone();
         let url = self.url.clone();
+        let udp_target = self.udp_target.clone();
+        let timeout = self.timeout();
+        let expected = self.expected.clone();
         let task_handle = tokio::spawn(async move {
             let mut ticker =
                 tokio::time::interval(tokio::time::Duration::from_secs(interval));
@@ -70,7 +147,15 @@ impl HealthCheck {
                         let now = tokio::time::Instant::now();
                         let last_check = inner.read().await.last_check;
                         if !lazy || now.duration_since(last_check).as_secs() >= interval {
-                            proxy_manager.check(&proxies, &url, None).await;
+                            check(
+                                &proxy_manager,
+                                &proxies,
+                                &url,
+                                udp_target.as_deref(),
+                                timeout,
+                                &expected,
+                            )
+                            .await;
                             let mut w = inner.write().await;
                             w.last_check = now;
                         }
@@ -79,7 +164,45 @@ impl HealthCheck {
             }
         });
 
-        self.inner.write().await.task_handle = Some(Arc::new(task_handle));
+        self.tasks.lock().unwrap().push(task_handle);
+
+        if let Some(bandwidth) = self.bandwidth.clone() {
+            self.kick_off_bandwidth(bandwidth);
+        }
+    }
+
+    fn kick_off_bandwidth(&self, bandwidth: BandwidthCheck) {
+        let inner = self.inner.clone();
+        let proxy_manager = self.proxy_manager.clone();
+        let task_handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(
+                bandwidth.interval.max(1),
+            ));
+            loop {
+                ticker.tick().await;
+                debug!("bandwidth check ticking: {}", bandwidth.url);
+                // the proxies may be updated by the provider in between
+                let proxies = inner.read().await.proxies.clone();
+                for proxy in proxies {
+                    let _ = proxy_manager
+                        .speed_test(
+                            proxy,
+                            &bandwidth.url,
+                            bandwidth.size,
+                            BANDWIDTH_CHECK_TIMEOUT,
+                        )
+                        .await;
+                }
+            }
+        });
+
+        self.tasks.lock().unwrap().push(task_handle);
+    }
+
+    fn abort(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
     }
 
     pub async fn touch(&self) {
@@ -88,7 +211,21 @@ impl HealthCheck {
 
     pub async fn check(&self) {
         let proxies = self.inner.read().await.proxies.clone();
-        self.proxy_manager.check(&proxies, &self.url, None).await;
+        check(
+            &self.proxy_manager,
+            &proxies,
+            &self.url,
+            self.udp_target.as_deref(),
+            self.timeout(),
+            &self.expected,
+        )
+        .await;
+    }
+
+    /// clamped to the largest delay a check can report
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+            .map(|t| Duration::from_millis(t.min(MAX_DELAY as u64)))
     }
 
     pub async fn update(&self, proxies: Vec<AnyOutboundHandler>) {
@@ -98,4 +235,102 @@ impl HealthCheck {
     pub fn auto(&self) -> bool {
         self.interval != 0
     }
+
+    /// the settings in effect, for the API
+    pub fn settings(&self) -> HealthCheckDef {
+        let settings = HealthCheckSettings {
+            url: Some(self.url.clone()),
+            interval: Some(self.interval),
+            lazy: Some(self.lazy),
+            udp_target: self.udp_target.clone(),
+            timeout: self.timeout,
+            expected_status: self.expected.status.clone(),
+            expected_keyword: self.expected.keyword.clone(),
+            mode: Some(self.expected.mode),
+            max_concurrent: None,
+            stale_after: None,
+            history_size: None,
+            delay_sampling: None,
+        };
+        HealthCheckDef {
+            enable: Some(self.auto()),
+            settings,
+            bandwidth: self.bandwidth.clone(),
+        }
+    }
+
+    pub fn proxy_manager(&self) -> &ProxyManager {
+        &self.proxy_manager
+    }
+}
+
+impl Drop for HealthCheck {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+async fn check(
+    proxy_manager: &ProxyManager,
+    proxies: &[AnyOutboundHandler],
+    url: &str,
+    udp_target: Option<&str>,
+    timeout: Option<Duration>,
+    expected: &Expected,
+) {
+    match udp_target {
+        Some(udp_target) => {
+            tokio::join!(
+                proxy_manager.check(proxies, url, timeout, expected),
+                proxy_manager.udp_check(proxies, udp_target)
+            );
+        }
+        None => proxy_manager.check(proxies, url, timeout, expected).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        app::{dns::MockClashResolver, remote_content_manager::ProxyManager},
+        config::internal::proxy::BandwidthCheck,
+    };
+
+    use super::HealthCheck;
+
+    #[tokio::test]
+    async fn test_tasks_aborted_on_drop() {
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let hc = HealthCheck::new(
+            vec![],
+            "http://www.gstatic.com/generate_204".to_owned(),
+            3600,
+            false,
+            ProxyManager::new(Arc::new(mock_resolver)),
+        )
+        .unwrap()
+        .with_bandwidth(Some(BandwidthCheck {
+            url: "http://speed.example.com/10mb".to_owned(),
+            size: 1024,
+            interval: 3600,
+        }));
+        let inner = hc.inner.clone();
+
+        hc.kick_off().await;
+        hc.kick_off().await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        // the tasks of the second kick off only
+        assert_eq!(Arc::strong_count(&inner), 4);
+
+        drop(hc);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(Arc::strong_count(&inner), 1);
+    }
 }
@@ -1,19 +1,28 @@
 use std::{
+    io::IoSliceMut,
+    net::SocketAddr,
     pin::Pin,
+    sync::Mutex,
     task::{Context, Poll},
 };
 
-use futures::Future;
+use futures::{Future, SinkExt, StreamExt, ready};
 use hyper::Uri;
 
 use hyper_util::client::legacy::connect::{Connected, Connection};
+use quinn::{
+    AsyncUdpSocket, UdpPoller,
+    udp::{RecvMeta, Transmit},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
 use tower::Service;
+use tracing::trace;
 
 use crate::{
     app::{dispatcher::BoxedChainedStream, dns::ThreadSafeDNSResolver},
     print_and_exit,
-    proxy::AnyOutboundHandler,
-    session::Session,
+    proxy::{AnyOutboundHandler, datagram::UdpPacket},
+    session::{Network, Session, SocksAddr},
 };
 
 #[derive(Clone)]
@@ -108,3 +117,143 @@ impl hyper::rt::Write for BoxedChainedStream {
         tokio::io::AsyncWrite::poll_shutdown(self, cx)
     }
 }
+
+/// A UDP socket for QUIC through an outbound, for the HTTP/3 url test.
+/// Every packet is sent to `destination`, and the replies look like they come
+/// from `peer`, which is what QUIC is told the server is.
+pub struct ProxiedUdpSocket {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: Mutex<mpsc::Receiver<Vec<u8>>>,
+    peer: SocketAddr,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl ProxiedUdpSocket {
+    pub async fn new(
+        handler: &AnyOutboundHandler,
+        resolver: ThreadSafeDNSResolver,
+        destination: SocksAddr,
+        peer: SocketAddr,
+    ) -> std::io::Result<Self> {
+        let sess = Session {
+            destination: destination.clone(),
+            network: Network::Udp,
+            ..Default::default()
+        };
+        let (mut sink, mut stream) =
+            handler.connect_datagram(&sess, resolver).await?.split();
+
+        let (tx, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+        let (incoming, rx) = mpsc::channel(64);
+        let send = tokio::spawn(async move {
+            while let Some(data) = outgoing.recv().await {
+                let pkt = UdpPacket {
+                    data,
+                    src_addr: SocksAddr::any_ipv4(),
+                    dst_addr: destination.clone(),
+                };
+                if let Err(e) = sink.send(pkt).await {
+                    trace!("failed to send to {}: {}", destination, e);
+                    break;
+                }
+            }
+        });
+        let recv = tokio::spawn(async move {
+            while let Some(pkt) = stream.next().await {
+                if incoming.send(pkt.data).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            tx,
+            rx: Mutex::new(rx),
+            peer,
+            tasks: [send, recv],
+        })
+    }
+}
+
+impl Drop for ProxiedUdpSocket {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+impl std::fmt::Debug for ProxiedUdpSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxiedUdpSocket")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+/// the sends are queued, so it's always writable
+#[derive(Debug)]
+struct Writable;
+
+impl UdpPoller for Writable {
+    fn poll_writable(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncUdpSocket for ProxiedUdpSocket {
+    fn create_io_poller(self: std::sync::Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Writable)
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> std::io::Result<()> {
+        let size = transmit
+            .segment_size
+            .unwrap_or(transmit.contents.len())
+            .max(1);
+        for segment in transmit.contents.chunks(size) {
+            self.tx.send(segment.to_vec()).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "the datagram is closed",
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut rx = self.rx.lock().unwrap();
+        match ready!(rx.poll_recv(cx)) {
+            Some(data) => {
+                let len = data.len().min(bufs[0].len());
+                bufs[0][..len].copy_from_slice(&data[..len]);
+                meta[0] = RecvMeta {
+                    addr: self.peer,
+                    len,
+                    stride: len,
+                    ..Default::default()
+                };
+                Poll::Ready(Ok(1))
+            }
+            None => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the datagram is closed",
+            ))),
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        // of the same family as `peer`, or quinn refuses to connect
+        Ok(match self.peer {
+            SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        })
+    }
+}
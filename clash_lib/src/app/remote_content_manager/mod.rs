@@ -1,56 +1,360 @@
 use std::{
     collections::{HashMap, VecDeque},
     error::Error,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::{
-        Arc,
-        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 
-use futures::{StreamExt, stream::FuturesUnordered};
-use http_body_util::Empty;
+use futures::{SinkExt, StreamExt, stream::FuturesUnordered};
+use hickory_proto::{op, rr};
+use http_body_util::{BodyExt, Empty};
 use hyper::Request;
 use hyper_util::{client::legacy::Client, rt::TokioExecutor};
-use serde::Serialize;
-use tokio::sync::RwLock;
-use tracing::{debug, instrument, trace};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, Semaphore, broadcast};
+use tracing::{debug, instrument, trace, warn};
 
 use crate::{
-    common::{errors::new_io_error, timed_future::TimedFuture},
-    proxy::AnyOutboundHandler,
+    app::{
+        dispatcher::{BoxedChainedDatagram, TrackerInfo},
+        profile::ThreadSafeCacheFile,
+    },
+    common::{
+        clock,
+        errors::{self, new_io_error},
+        http::h3::H3Connection,
+        timed_future::TimedFuture,
+        utils::rand_range,
+    },
+    config::internal::proxy::{
+        CheckMode, DelaySampling, ExpectedStatus, PROXY_DIRECT, PROXY_REJECT,
+        UnknownNodes,
+    },
+    proxy::{AnyOutboundHandler, OutboundType, datagram::UdpPacket},
+    session::{Network, Session, SocksAddr},
 };
 
-use self::http_client::LocalConnector;
+use self::http_client::{LocalConnector, ProxiedUdpSocket};
 
 use super::dns::ThreadSafeDNSResolver;
 
 pub mod healthcheck;
 mod http_client;
 pub mod providers;
+mod server_addrs;
 
-#[derive(Clone, Serialize)]
+pub use server_addrs::{ServerAddr, ServerAddrs};
+
+/// a proxy whose sessions panicked this many times within `PANIC_WINDOW` is
+/// marked dead, until a health check finds it alive again
+const PANIC_LIMIT: u64 = 3;
+const PANIC_WINDOW: Duration = Duration::from_secs(600);
+/// a proxy whose connections failed this many times in a row is marked dead,
+/// until a health check or a connection through it succeeds. The limit
+/// doubles each time it's reached without a connection succeeding in
+/// between, e.g. for a proxy the health checks find alive but that can't
+/// reach the destinations in use, up to `MAX_FAILURE_LIMIT`
+const FAILURE_LIMIT: u64 = 3;
+const MAX_FAILURE_LIMIT: u64 = 48;
+/// bytes of a response searched for the `expected-keyword`
+const KEYWORD_BODY_LIMIT: usize = 1024 * 1024;
+/// the checks of a batch start at random offsets up to this much per proxy,
+/// so they don't all hit the uplink in the same instant
+const CHECK_JITTER_PER_PROXY: Duration = Duration::from_millis(20);
+const MAX_CHECK_JITTER: Duration = Duration::from_secs(1);
+/// a url test reports `DelayChanged` when its delay is this far from the
+/// one last reported, or when it fails or succeeds again
+const DELAY_CHANGE_THRESHOLD: u16 = 50;
+/// the delay of a proxy whose liveness is unknown, between those of the
+/// proxies tested alive and the dead ones
+pub const UNKNOWN_DELAY: u16 = u16::MAX - 1;
+/// the largest delay a check can measure, below the unknown and dead ones;
+/// longer checks saturate here
+pub const MAX_DELAY: u16 = UNKNOWN_DELAY - 1;
+/// the TLS config of the pings in `CheckMode::Tls`
+static PING_TLS_CONFIG: LazyLock<Arc<rustls::ClientConfig>> = LazyLock::new(|| {
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(crate::common::tls::GLOBAL_ROOT_STORE.clone())
+            .with_no_client_auth(),
+    )
+});
+/// the `udp-target` prefix of the STUN servers
+const STUN_SCHEME: &str = "stun://";
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+
+/// What the checks tell of a proxy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Liveness {
+    Alive,
+    Dead,
+    /// never checked, or not within the `stale-after` of the health check
+    Unknown,
+}
+
+fn is_usable(liveness: Liveness, unknown: UnknownNodes) -> bool {
+    match liveness {
+        Liveness::Alive => true,
+        Liveness::Dead => false,
+        Liveness::Unknown => unknown == UnknownNodes::Tolerate,
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DelayHistory {
     time: DateTime<Utc>,
     delay: u16,
     #[serde(rename = "meanDelay")]
     mean_delay: u16,
+    /// what the delay is of, left out for the url tests
+    #[serde(default, skip_serializing_if = "CheckMode::is_http")]
+    mode: CheckMode,
+}
+
+/// What is kept of a proxy in the cache store with `store-delay-history`
+#[derive(Serialize, Deserialize)]
+struct StoredState {
+    alive: bool,
+    history: Vec<DelayHistory>,
+}
+
+/// What a url test must get besides a response in time for the proxy to be
+/// alive
+#[derive(Clone, Debug, Default)]
+pub struct Expected {
+    pub status: Option<ExpectedStatus>,
+    pub keyword: Option<String>,
+    /// not a request at all but the connection only, leaving out the others
+    pub mode: CheckMode,
+}
+
+impl Expected {
+    fn is_empty(&self) -> bool {
+        self.status.is_none() && self.keyword.is_none()
+    }
 }
 
+/// The spread of the successful delays in the history of a proxy, in ms
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct DelayStats {
+    /// the successful tests they're of
+    pub samples: usize,
+    pub mean: u16,
+    pub p50: u16,
+    pub p95: u16,
+    /// mean difference between consecutive delays
+    pub jitter: u16,
+}
+
+impl DelayStats {
+    /// `None` without any successful test in `history`
+    fn of(history: &VecDeque<DelayHistory>) -> Option<Self> {
+        let delays: Vec<u32> = history
+            .iter()
+            .filter(|x| x.delay > 0)
+            .map(|x| x.delay as u32)
+            .collect();
+        if delays.is_empty() {
+            return None;
+        }
+        let n = delays.len() as u32;
+        let jitter = if n > 1 {
+            delays.windows(2).map(|x| x[0].abs_diff(x[1])).sum::<u32>() / (n - 1)
+        } else {
+            0
+        };
+        let mut sorted = delays.clone();
+        sorted.sort_unstable();
+        // nearest rank
+        let percentile =
+            |p: usize| sorted[(p * sorted.len()).div_ceil(100).max(1) - 1] as u16;
+        Some(Self {
+            samples: delays.len(),
+            mean: (delays.iter().sum::<u32>() / n) as u16,
+            p50: percentile(50),
+            p95: percentile(95),
+            jitter: jitter as u16,
+        })
+    }
+}
+
+/// The last bandwidth check of a proxy
+#[derive(Clone, Serialize, Debug)]
+pub struct Bandwidth {
+    time: DateTime<Utc>,
+    /// bytes downloaded
+    bytes: u64,
+    /// MB/s
+    speed: f64,
+}
+
+impl Bandwidth {
+    /// MB/s
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+}
+
+type SharedProxyState = Arc<std::sync::RwLock<ProxyState>>;
+
 #[derive(Default)]
 struct ProxyState {
     alive: AtomicBool,
+    /// when `alive` was last reported
+    checked: Option<tokio::time::Instant>,
     delay_history: VecDeque<DelayHistory>,
+    /// the delay last sent in a `DelayChanged`
+    reported_delay: Option<u16>,
+    /// of the DNS queries to the `udp-target`, kept apart from the delays
+    /// to the `url`
+    udp_delay_history: VecDeque<DelayHistory>,
+    /// whether the last UDP test got an answer, `None` before the first
+    udp_alive: Option<bool>,
+    bandwidth: Option<Bandwidth>,
+    /// the url tests of the groups with their own `url` or expected
+    /// response, seen by those groups instead, see `ProxyManager::for_group`
+    groups: HashMap<String, ProxyState>,
+}
+
+/// whether a url test of `delay` is worth a `DelayChanged` after
+/// `reported`, 0 being a failure
+fn delay_changed(reported: Option<u16>, delay: u16) -> bool {
+    match reported {
+        None => true,
+        Some(reported) if (reported == 0) != (delay == 0) => true,
+        Some(reported) => reported.abs_diff(delay) >= DELAY_CHANGE_THRESHOLD,
+    }
+}
+
+/// the moving average of the successful delays of `state`
+fn smoothed_delay_of(state: &ProxyState) -> Option<u16> {
+    state
+        .delay_history
+        .iter()
+        .filter(|x| x.delay > 0)
+        .fold(None, |avg: Option<f64>, x| {
+            Some(match avg {
+                Some(avg) => avg * 0.7 + x.delay as f64 * 0.3,
+                None => x.delay as f64,
+            })
+        })
+        .map(|x| x as u16)
+}
+
+/// Connection counters of an outbound, a proxy or a group alike
+#[derive(Default)]
+struct OutboundCounters {
+    active: AtomicU64,
+    dials: AtomicU64,
+    dial_errors: AtomicU64,
+    /// sessions ended by a panic of the outbound
+    panics: AtomicU64,
+    /// the first of the panics counted towards `PANIC_LIMIT`, and how many
+    recent_panics: std::sync::Mutex<Option<(tokio::time::Instant, u64)>>,
+    /// connections failed in a row, see `FAILURE_LIMIT`
+    failures: AtomicU64,
+    /// 0 until the first time it's reached
+    failure_limit: AtomicU64,
+    /// marked dead by `failures`, rather than by a health check
+    failed: AtomicBool,
+    upload: AtomicU64,
+    download: AtomicU64,
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboundStats {
+    pub active_connections: u64,
+    pub dials: u64,
+    pub dial_errors: u64,
+    /// `dial_errors / dials`, 0 before the first dial
+    pub error_rate: f64,
+    pub panics: u64,
+    /// bytes of the closed connections
+    pub upload_total: u64,
+    pub download_total: u64,
+}
+
+/// A group switched to `proxy`, with `interrupt-exist-connections` its
+/// connections through the other ones are closed
+#[derive(Clone, Debug)]
+pub struct GroupSwitch {
+    pub group: String,
+    pub proxy: String,
+}
+
+/// A change of a proxy found by the health checks
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum ProxyEvent {
+    /// dead now, alive or never checked before
+    ProxyWentDown { proxy: String },
+    /// alive again after it was dead
+    ProxyRecovered { proxy: String },
+    /// a url test failed, succeeded again, or got a delay at least
+    /// `DELAY_CHANGE_THRESHOLD` away from the one last reported, 0 if it
+    /// failed
+    DelayChanged { proxy: String, delay: u16 },
+}
+
+/// Counts a connection as active on every outbound of its chain until it's
+/// dropped, when its traffic is added to them.
+pub struct ActiveConnection {
+    counters: Vec<Arc<OutboundCounters>>,
+    tracker: Arc<TrackerInfo>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let upload = self.tracker.upload_total.load(Ordering::Relaxed);
+        let download = self.tracker.download_total.load(Ordering::Relaxed);
+        for c in self.counters.iter() {
+            c.active.fetch_sub(1, Ordering::Relaxed);
+            c.upload.fetch_add(upload, Ordering::Relaxed);
+            c.download.fetch_add(download, Ordering::Relaxed);
+        }
+    }
 }
 
 /// ProxyManager is the latency registry.
+/// it also keeps the connection counters of each outbound.
 #[derive(Clone)]
 pub struct ProxyManager {
-    proxy_state: Arc<RwLock<HashMap<String, ProxyState>>>,
+    /// only written when a proxy is seen the first time, each state has its
+    /// own lock so the lookups of the groups don't wait on one another, nor
+    /// on the checks of the other proxies
+    proxy_state: Arc<std::sync::RwLock<HashMap<String, SharedProxyState>>>,
+    outbound_counters:
+        Arc<std::sync::RwLock<HashMap<String, Arc<OutboundCounters>>>>,
+    /// the endpoint of a proxy with backup endpoints that connected last
+    preferred_endpoints: Arc<std::sync::RwLock<HashMap<String, usize>>>,
+    switches: broadcast::Sender<GroupSwitch>,
+    events: broadcast::Sender<ProxyEvent>,
     dns_resolver: ThreadSafeDNSResolver,
+    server_addrs: Arc<ServerAddrs>,
+    /// the `health-check-url` of the proxies that set one
+    health_check_urls: Arc<std::sync::RwLock<HashMap<String, String>>>,
+    /// the server of each proxy
+    proxy_servers: Arc<std::sync::RwLock<HashMap<String, String>>>,
+    /// the group whose url tests are recorded and read, see `for_group`
+    group: Option<Arc<str>>,
+    /// caps the checks in flight over all the groups and providers
+    check_limit: Option<Arc<Semaphore>>,
+    /// the results older than this are unknown
+    stale_after: Option<Duration>,
+    /// url tests kept for each proxy
+    history_size: usize,
+    delay_sampling: DelaySampling,
+    /// where the url tests are stored to be restored on the next start
+    cache_store: Option<ThreadSafeCacheFile>,
 
     connector_map:
         Arc<RwLock<HashMap<String, hyper_rustls::HttpsConnector<LocalConnector>>>>,
@@ -59,26 +363,438 @@ pub struct ProxyManager {
 impl ProxyManager {
     pub fn new(dns_resolver: ThreadSafeDNSResolver) -> Self {
         Self {
+            server_addrs: ServerAddrs::new(dns_resolver.clone()),
+            health_check_urls: Default::default(),
+            proxy_servers: Default::default(),
+            group: None,
+            check_limit: None,
+            stale_after: None,
+            history_size: 10,
+            delay_sampling: DelaySampling::default(),
+            cache_store: None,
             dns_resolver,
-            proxy_state: Arc::new(RwLock::new(HashMap::new())),
+            proxy_state: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            outbound_counters: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            preferred_endpoints: Arc::new(std::sync::RwLock::new(HashMap::new())),
+            switches: broadcast::channel(16).0,
+            events: broadcast::channel(64).0,
             connector_map: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// The manager as seen by `group`, which tests its proxies with its own
+    /// url or expected response: the url tests through it are kept apart
+    /// from those of the providers and the other groups, and `alive` and the
+    /// delays read them, or the proxy's own until the group has tested it.
+    pub fn for_group(&self, group: &str) -> Self {
+        Self {
+            group: Some(group.into()),
+            ..self.clone()
+        }
+    }
+
+    fn state(&self, name: &str) -> Option<SharedProxyState> {
+        self.proxy_state.read().unwrap().get(name).cloned()
+    }
+
+    fn state_or_default(&self, name: &str) -> SharedProxyState {
+        if let Some(state) = self.state(name) {
+            return state;
+        }
+        self.proxy_state
+            .write()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// `f` of the state of `name` as the group of `self` sees it, `None` if
+    /// it was never tested
+    fn view<T>(&self, name: &str, f: impl FnOnce(&ProxyState) -> T) -> Option<T> {
+        let state = self.state(name)?;
+        let state = state.read().unwrap();
+        let view = self.group.as_deref().and_then(|x| state.groups.get(x));
+        Some(f(view.unwrap_or(&state)))
+    }
+
+    fn view_mut<T>(&self, name: &str, f: impl FnOnce(&mut ProxyState) -> T) -> T {
+        let state = self.state_or_default(name);
+        let mut state = state.write().unwrap();
+        match self.group.as_deref() {
+            Some(group) => f(state.groups.entry(group.to_owned()).or_default()),
+            None => f(&mut state),
+        }
+    }
+
+    /// at most `max` health checks run at once, the others wait their turn
+    /// without it counting towards their delay. 0 is unlimited
+    pub fn with_check_limit(mut self, max: usize) -> Self {
+        self.check_limit = (max > 0).then(|| Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// the liveness of a proxy checked more than `secs` ago is unknown
+    /// again. 0 keeps it until the next check
+    pub fn with_stale_after(mut self, secs: u64) -> Self {
+        self.stale_after = (secs > 0).then(|| Duration::from_secs(secs));
+        self
+    }
+
+    /// keeps the last `n` url tests of each proxy, and as many UDP tests
+    pub fn with_history_size(mut self, n: usize) -> Self {
+        self.history_size = n.max(1);
+        self
+    }
+
+    /// what `last_delay` is taken from
+    pub fn with_delay_sampling(mut self, sampling: DelaySampling) -> Self {
+        self.delay_sampling = sampling;
+        self
+    }
+
+    /// stores the liveness and delays of each proxy after its url tests,
+    /// if the cache store is set to
+    pub fn with_cache_store(mut self, cache_store: ThreadSafeCacheFile) -> Self {
+        self.cache_store = Some(cache_store);
+        self
+    }
+
+    /// loads the liveness and delays stored by the last run, their results
+    /// as old as they were when stored
+    pub async fn restore(&self) {
+        let Some(cache_store) = &self.cache_store else {
+            return;
+        };
+        let stored = cache_store.get_delay_histories().await;
+        let mut restored = 0;
+        for (name, stored) in stored {
+            let stored: StoredState = match serde_json::from_str(&stored) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("failed to restore the delays of {}: {}", name, e);
+                    continue;
+                }
+            };
+            let Some(last) = stored.history.last() else {
+                continue;
+            };
+            let age = (Utc::now() - last.time).to_std().unwrap_or_default();
+            let now = tokio::time::Instant::now();
+
+            let state = self.state_or_default(&name);
+            let mut state = state.write().unwrap();
+            state.alive.store(stored.alive, Ordering::Relaxed);
+            state.checked = if self.stale_after.is_some_and(|ttl| age > ttl) {
+                None
+            } else {
+                Some(now.checked_sub(age).unwrap_or(now))
+            };
+            let skip = stored.history.len().saturating_sub(self.history_size);
+            state.delay_history = stored.history.into_iter().skip(skip).collect();
+            restored += 1;
+        }
+        debug!("restored the delays of {} proxies", restored);
+    }
+
+    /// waits for a random start offset of a batch of `n` checks, then for a
+    /// free slot, held until the returned permit is dropped
+    async fn check_slot(
+        &self,
+        n: usize,
+    ) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        let jitter = CHECK_JITTER_PER_PROXY
+            .saturating_mul(n as u32)
+            .min(MAX_CHECK_JITTER);
+        if !jitter.is_zero() {
+            tokio::time::sleep(rand_range(Duration::ZERO..=jitter)).await;
+        }
+        match &self.check_limit {
+            Some(limit) => limit.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// the resolved addresses of the proxy servers
+    pub fn server_addrs(&self) -> &Arc<ServerAddrs> {
+        &self.server_addrs
+    }
+
+    /// `url` overrides the one `name` is health checked with
+    pub fn set_health_check_url(&self, name: &str, url: &str) {
+        self.health_check_urls
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), url.to_owned());
+    }
+
+    pub fn health_check_url(&self, name: &str) -> Option<String> {
+        self.health_check_urls.read().unwrap().get(name).cloned()
+    }
+
+    pub fn set_proxy_server(&self, name: &str, server: &str) {
+        self.proxy_servers
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), server.to_owned());
+    }
+
+    /// the hostname or IP of the server of `name`
+    pub fn proxy_server(&self, name: &str) -> Option<String> {
+        self.proxy_servers.read().unwrap().get(name).cloned()
+    }
+
+    fn counters(&self, name: &str) -> Arc<OutboundCounters> {
+        if let Some(c) = self.outbound_counters.read().unwrap().get(name) {
+            return c.clone();
+        }
+        self.outbound_counters
+            .write()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// index of the endpoint of `name` to try first, 0 is the primary one
+    pub fn preferred_endpoint(&self, name: &str) -> usize {
+        self.preferred_endpoints
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set_preferred_endpoint(&self, name: &str, index: usize) {
+        self.preferred_endpoints
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), index);
+    }
+
+    /// to be called by the groups that interrupt the existing connections
+    /// when switching to another proxy
+    pub fn report_switch(&self, group: &str, proxy: &str) {
+        debug!("`{}` switched to `{}`", group, proxy);
+        // nobody listens in tests
+        let _ = self.switches.send(GroupSwitch {
+            group: group.to_owned(),
+            proxy: proxy.to_owned(),
+        });
+    }
+
+    pub fn subscribe_switches(&self) -> broadcast::Receiver<GroupSwitch> {
+        self.switches.subscribe()
+    }
+
+    /// the proxies going down, recovering and changing delay, as they're
+    /// checked
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ProxyEvent> {
+        self.events.subscribe()
+    }
+
+    fn send_event(&self, event: ProxyEvent) {
+        trace!("proxy event: {:?}", event);
+        // nobody may listen
+        let _ = self.events.send(event);
+    }
+
+    /// broadcasts `name` going down or recovering, `was_alive` is `None`
+    /// if it was never checked
+    fn alive_changed(&self, name: &str, was_alive: Option<bool>, alive: bool) {
+        let proxy = name.to_owned();
+        match (was_alive, alive) {
+            (Some(true) | None, false) => {
+                self.send_event(ProxyEvent::ProxyWentDown { proxy })
+            }
+            (Some(false), true) => {
+                self.send_event(ProxyEvent::ProxyRecovered { proxy })
+            }
+            _ => {}
+        }
+    }
+
+    /// record the result of connecting through `name`
+    pub fn report_dial(&self, name: &str, ok: bool) {
+        let c = self.counters(name);
+        c.dials.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            c.dial_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// drops the counters of the proxies gone, e.g. from a provider
+    pub fn forget(&self, names: &[String]) {
+        let mut counters = self.outbound_counters.write().unwrap();
+        for name in names {
+            counters.remove(name);
+        }
+    }
+
+    /// record a session through `name` ended by a panic, marking it dead
+    /// once it keeps panicking
+    pub async fn report_panic(&self, name: &str) {
+        let c = self.counters(name);
+        c.panics.fetch_add(1, Ordering::Relaxed);
+        let now = tokio::time::Instant::now();
+        let panics = {
+            // a panic while this is held mustn't stop the counting
+            let mut recent = c
+                .recent_panics
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            let (first, panics) = match *recent {
+                Some((first, n)) if now - first < PANIC_WINDOW => (first, n + 1),
+                _ => (now, 1),
+            };
+            *recent = (panics < PANIC_LIMIT).then_some((first, panics));
+            panics
+        };
+        if panics >= PANIC_LIMIT {
+            warn!(
+                "{} panicked {} times in {:?}, marking it dead",
+                name, panics, PANIC_WINDOW
+            );
+            self.mark_dead(name);
+        }
+    }
+
+    /// record a connection through `name` that failed, marking it dead once
+    /// they keep failing, see `FAILURE_LIMIT`
+    pub async fn report_failure(&self, name: &str) {
+        // rejecting is all it does, and direct fails only with the
+        // destinations
+        if name == PROXY_REJECT || name == PROXY_DIRECT {
+            return;
+        }
+        let c = self.counters(name);
+        let failures = c.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        let limit = c.failure_limit.load(Ordering::Relaxed).max(FAILURE_LIMIT);
+        if failures < limit {
+            return;
+        }
+        c.failures.store(0, Ordering::Relaxed);
+        c.failure_limit
+            .store((limit * 2).min(MAX_FAILURE_LIMIT), Ordering::Relaxed);
+        c.failed.store(true, Ordering::Relaxed);
+        warn!(
+            "{} failed {} connections in a row, marking it dead",
+            name, failures
+        );
+        self.mark_dead(name);
+    }
+
+    /// record a connection through `name` that succeeded, which brings it
+    /// back if its failures marked it dead. The groups testing it with their
+    /// own url wait for their next check
+    pub async fn report_success(&self, name: &str) {
+        let c = self.counters(name);
+        c.failures.store(0, Ordering::Relaxed);
+        c.failure_limit.store(FAILURE_LIMIT, Ordering::Relaxed);
+        if c.failed.swap(false, Ordering::Relaxed) {
+            debug!("{} connected again, marking it alive", name);
+            if let Some(state) = self.state(name) {
+                let was_alive = {
+                    let mut state = state.write().unwrap();
+                    state.checked = Some(tokio::time::Instant::now());
+                    state.alive.swap(true, Ordering::Relaxed)
+                };
+                self.alive_changed(name, Some(was_alive), true);
+            }
+        }
+    }
+
+    /// `report_success` without `err`, or `report_failure` when the proxy
+    /// itself failed, not the destination behind it, e.g. refusing the
+    /// connection
+    pub async fn report_connect(&self, name: &str, err: Option<&std::io::Error>) {
+        match err {
+            None => self.report_success(name).await,
+            Some(e) if errors::is_proxy_fault(e) => self.report_failure(name).await,
+            Some(_) => {}
+        }
+    }
+
+    /// for the groups testing it with their own url as well
+    fn mark_dead(&self, name: &str) {
+        let now = tokio::time::Instant::now();
+        let was_alive = {
+            let state = self.state_or_default(name);
+            let mut state = state.write().unwrap();
+            for group in state.groups.values_mut() {
+                group.alive.store(false, Ordering::Relaxed);
+                group.checked = Some(now);
+            }
+            let was_alive = state.alive.swap(false, Ordering::Relaxed);
+            state.checked.replace(now).map(|_| was_alive)
+        };
+        self.alive_changed(name, was_alive, false);
+    }
+
+    /// count the connection as active on the outbounds in its chain,
+    /// until the returned guard is dropped
+    pub async fn track_connection(
+        &self,
+        tracker: Arc<TrackerInfo>,
+    ) -> ActiveConnection {
+        let mut chain = tracker.proxy_chain_holder.to_vec().await;
+        chain.sort();
+        chain.dedup();
+
+        let counters = chain
+            .iter()
+            .map(|name| {
+                let c = self.counters(name);
+                c.active.fetch_add(1, Ordering::Relaxed);
+                c
+            })
+            .collect();
+        ActiveConnection { counters, tracker }
+    }
+
+    pub fn outbound_stats(&self, name: &str) -> OutboundStats {
+        let counters = self.outbound_counters.read().unwrap();
+        let Some(c) = counters.get(name) else {
+            return OutboundStats::default();
+        };
+        let dials = c.dials.load(Ordering::Relaxed);
+        let dial_errors = c.dial_errors.load(Ordering::Relaxed);
+        OutboundStats {
+            active_connections: c.active.load(Ordering::Relaxed),
+            dials,
+            dial_errors,
+            error_rate: if dials > 0 {
+                dial_errors as f64 / dials as f64
+            } else {
+                0.0
+            },
+            panics: c.panics.load(Ordering::Relaxed),
+            upload_total: c.upload.load(Ordering::Relaxed),
+            download_total: c.download.load(Ordering::Relaxed),
+        }
+    }
+
     pub async fn check(
         &self,
-        proxies: &Vec<AnyOutboundHandler>,
+        proxies: &[AnyOutboundHandler],
         url: &str,
         timeout: Option<Duration>,
+        expected: &Expected,
     ) {
         let mut futs = vec![];
         for proxy in proxies {
             let proxy = proxy.clone();
-            let url = url.to_owned();
+            let url = self
+                .health_check_url(proxy.name())
+                .unwrap_or_else(|| url.to_owned());
             let manager = self.clone();
+            let expected = expected.clone();
+            let n = proxies.len();
             futs.push(tokio::spawn(async move {
+                let _slot = manager.check_slot(n).await;
                 manager
-                    .url_test(proxy, url.as_str(), timeout)
+                    .url_test_expecting(proxy, url.as_str(), timeout, &expected)
                     .await
                     .map_err(|e| debug!("healthcheck failed: {}", e))
             }));
@@ -88,78 +804,342 @@ impl ProxyManager {
         let _: Vec<_> = futs.collect().await;
     }
 
+    /// tests the UDP round trip time to `target` of the `proxies` that relay
+    /// UDP
+    pub async fn udp_check(&self, proxies: &[AnyOutboundHandler], target: &str) {
+        let mut futs = vec![];
+        for proxy in proxies {
+            if !proxy.support_udp().await {
+                continue;
+            }
+            let proxy = proxy.clone();
+            let target = target.to_owned();
+            let manager = self.clone();
+            let n = proxies.len();
+            futs.push(tokio::spawn(async move {
+                let _slot = manager.check_slot(n).await;
+                manager
+                    .udp_test(proxy, &target, None)
+                    .await
+                    .map_err(|e| debug!("udp healthcheck failed: {}", e))
+            }));
+        }
+
+        let futs: FuturesUnordered<_> = futs.into_iter().collect();
+        let _: Vec<_> = futs.collect().await;
+    }
+
+    pub async fn liveness(&self, name: &str) -> Liveness {
+        self.view(name, |state| self.liveness_of(state))
+            .unwrap_or(Liveness::Unknown)
+    }
+
+    fn liveness_of(&self, state: &ProxyState) -> Liveness {
+        match state.checked {
+            Some(t) if self.stale_after.is_none_or(|ttl| t.elapsed() <= ttl) => {
+                if state.alive.load(Ordering::Relaxed) {
+                    Liveness::Alive
+                } else {
+                    Liveness::Dead
+                }
+            }
+            _ => Liveness::Unknown,
+        }
+    }
+
+    /// whether a group may pick `name`, the proxies of unknown liveness
+    /// as `unknown` says
+    pub async fn usable(&self, name: &str, unknown: UnknownNodes) -> bool {
+        is_usable(self.liveness(name).await, unknown)
+    }
+
+    /// whether each of `names` is usable and its smoothed delay, read under
+    /// one lock of the states for a pick among them
+    pub fn usable_with_delays(
+        &self,
+        names: &[&str],
+        unknown: UnknownNodes,
+    ) -> Vec<(bool, Option<u16>)> {
+        let states = self.proxy_state.read().unwrap();
+        names
+            .iter()
+            .map(|name| {
+                let Some(state) = states.get(*name) else {
+                    return (is_usable(Liveness::Unknown, unknown), None);
+                };
+                let state = state.read().unwrap();
+                let view = self.group.as_deref().and_then(|x| state.groups.get(x));
+                let view = view.unwrap_or(&state);
+                (
+                    is_usable(self.liveness_of(view), unknown),
+                    smoothed_delay_of(view),
+                )
+            })
+            .collect()
+    }
+
+    /// not known to be dead
     pub async fn alive(&self, name: &str) -> bool {
-        self.proxy_state
-            .read()
-            .await
-            .get(name)
-            .map(|x| x.alive.load(Ordering::Relaxed))
-            .unwrap_or(true) // if not found, assume it's alive
+        self.usable(name, UnknownNodes::Tolerate).await
     }
 
     pub async fn report_alive(&self, name: &str, alive: bool) {
-        let mut state = self.proxy_state.write().await;
-        let state = state.entry(name.to_owned()).or_default();
-        state.alive.store(alive, Ordering::Relaxed)
+        let was_alive = self.view_mut(name, |x| {
+            let was_alive = x.alive.swap(alive, Ordering::Relaxed);
+            x.checked
+                .replace(tokio::time::Instant::now())
+                .map(|_| was_alive)
+        });
+        // the groups with their own url see the proxy their own way
+        if self.group.is_none() {
+            self.alive_changed(name, was_alive, alive);
+        }
     }
 
     pub async fn delay_history(&self, name: &str) -> Vec<DelayHistory> {
-        self.proxy_state
-            .read()
-            .await
-            .get(name)
-            .map(|x| x.delay_history.clone())
+        self.view(name, |x| x.delay_history.clone())
             .unwrap_or_default()
             .into()
     }
 
+    /// the last delay of `name`, or its moving average with the `ewma`
+    /// sampling. `u16::MAX` if `name` is dead, `UNKNOWN_DELAY` if its
+    /// liveness is unknown
     pub async fn last_delay(&self, name: &str) -> u16 {
-        let max = u16::MAX;
-        if !self.alive(name).await {
-            return max;
+        match self.liveness(name).await {
+            Liveness::Alive => {}
+            Liveness::Dead => return u16::MAX,
+            Liveness::Unknown => return UNKNOWN_DELAY,
+        }
+        if self.delay_sampling == DelaySampling::Ewma {
+            return self.smoothed_delay(name).await.unwrap_or(u16::MAX);
         }
-        self.delay_history(name)
+        self.view(name, |x| x.delay_history.back().map(|x| x.delay))
+            .flatten()
+            .unwrap_or(u16::MAX)
+    }
+
+    pub async fn udp_delay_history(&self, name: &str) -> Vec<DelayHistory> {
+        self.state(name)
+            .map(|x| x.read().unwrap().udp_delay_history.clone())
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// not known to fail to relay UDP, whatever its TCP does
+    pub async fn udp_alive(&self, name: &str) -> bool {
+        self.state(name)
+            .and_then(|x| x.read().unwrap().udp_alive)
+            .unwrap_or(true)
+    }
+
+    /// the last UDP round trip time of `name`, `u16::MAX` if it failed or
+    /// was never tested
+    pub async fn last_udp_delay(&self, name: &str) -> u16 {
+        self.udp_delay_history(name)
             .await
             .last()
             .map(|x| x.delay)
-            .unwrap_or(max)
+            .filter(|x| *x > 0)
+            .unwrap_or(u16::MAX)
+    }
+
+    /// the mean, median, 95th percentile and jitter of the successful delays
+    /// in the history of `name`, `None` if none of its tests succeeded
+    pub async fn delay_stats(&self, name: &str) -> Option<DelayStats> {
+        self.view(name, |x| DelayStats::of(&x.delay_history))
+            .flatten()
+    }
+
+    /// Exponentially weighted moving average of the successful delays,
+    /// `None` if the proxy has never been tested successfully.
+    pub async fn smoothed_delay(&self, name: &str) -> Option<u16> {
+        self.view(name, smoothed_delay_of).flatten()
+    }
+
+    /// the last bandwidth measured through `name`, `None` if its last check
+    /// failed
+    pub async fn bandwidth(&self, name: &str) -> Option<Bandwidth> {
+        self.state(name)
+            .and_then(|x| x.read().unwrap().bandwidth.clone())
     }
 
+    async fn connector(
+        &self,
+        proxy: &AnyOutboundHandler,
+    ) -> hyper_rustls::HttpsConnector<LocalConnector> {
+        use crate::common::tls::GLOBAL_ROOT_STORE;
+
+        let mut g = self.connector_map.write().await;
+        g.entry(proxy.name().to_owned())
+            .or_insert_with(|| {
+                let connector =
+                    LocalConnector(proxy.clone(), self.dns_resolver.clone());
+
+                let mut tls_config = rustls::ClientConfig::builder()
+                    .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+                    .with_no_client_auth();
+
+                tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+
+                hyper_rustls::HttpsConnectorBuilder::new()
+                    .with_tls_config(tls_config)
+                    .https_or_http()
+                    .enable_all_versions()
+                    .wrap_connector(connector)
+            })
+            .clone()
+    }
+
+    /// downloads up to `max_bytes` from `url` through `proxy` within
+    /// `duration`, the speed is of what's downloaded by then. The result is
+    /// kept as the bandwidth of `proxy`
     #[instrument(skip(self, proxy))]
+    pub async fn speed_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        url: &str,
+        max_bytes: u64,
+        duration: Duration,
+    ) -> std::io::Result<Bandwidth> {
+        let name = proxy.name().to_owned();
+        let client: Client<_, Empty<Bytes>> = Client::builder(TokioExecutor::new())
+            .build(self.connector(&proxy).await);
+
+        let tester = async {
+            let req = Request::get(url)
+                .header("Connection", "Close")
+                .version(hyper::Version::HTTP_11)
+                .body(Empty::new())
+                .unwrap();
+            let start = Instant::now();
+            let res = tokio::time::timeout(duration, client.request(req))
+                .await
+                .map_err(|_| new_io_error(format!("timeout for {}", url)))?
+                .map_err(|e| new_io_error(format!("{}: {}", url, e)))?;
+            // an error page would be measured instead of the payload
+            if !res.status().is_success() {
+                return Err(new_io_error(format!(
+                    "{} responded {}",
+                    url,
+                    res.status()
+                )));
+            }
+            let mut body = res.into_body();
+
+            let mut bytes = 0;
+            // 30 years as tokio for the durations beyond what `Instant` holds
+            let deadline = start
+                .checked_add(duration)
+                .unwrap_or_else(|| start + Duration::from_secs(86400 * 365 * 30));
+            while bytes < max_bytes {
+                match tokio::time::timeout_at(deadline.into(), body.frame()).await {
+                    Ok(Some(Ok(frame))) => {
+                        if let Some(data) = frame.data_ref() {
+                            bytes += data.len() as u64;
+                        }
+                    }
+                    Ok(Some(Err(e))) => {
+                        return Err(new_io_error(format!("{}: {}", url, e)));
+                    }
+                    // the whole body, or as much as arrived in time
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            if bytes == 0 {
+                return Err(new_io_error(format!(
+                    "nothing downloaded from {}",
+                    url
+                )));
+            }
+
+            Ok(Bandwidth {
+                time: Utc::now(),
+                bytes,
+                speed: bytes as f64 / start.elapsed().as_secs_f64() / 1_000_000.0,
+            })
+        };
+
+        let result = tester.await;
+        match &result {
+            Ok(b) => debug!("bandwidth of {} is {:.2} MB/s", name, b.speed),
+            Err(e) => debug!("bandwidth test for {} failed: {}", name, e),
+        }
+
+        self.state_or_default(&name).write().unwrap().bandwidth =
+            result.as_ref().ok().cloned();
+
+        result
+    }
+
+    /// An `h3://` url is tested with HTTP/3 as `https://`, if the proxy relays
+    /// UDP. It falls back to the test over TCP when that fails, or takes more
+    /// than half the timeout.
     pub async fn url_test(
         &self,
         proxy: AnyOutboundHandler,
         url: &str,
         timeout: Option<Duration>,
+    ) -> std::io::Result<(u16, u16)> {
+        self.url_test_expecting(proxy, url, timeout, &Expected::default())
+            .await
+    }
+
+    /// `url_test` failing unless the response is `expected`, always over
+    /// TCP then
+    #[instrument(skip(self, proxy))]
+    pub async fn url_test_expecting(
+        &self,
+        proxy: AnyOutboundHandler,
+        url: &str,
+        timeout: Option<Duration>,
+        expected: &Expected,
     ) -> std::io::Result<(u16, u16)> {
         let name = proxy.name().to_owned();
         let name_clone = name.clone();
         let default_timeout = Duration::from_secs(5);
+        let (url, h3) = match url.strip_prefix("h3://") {
+            Some(rest) => (format!("https://{}", rest), true),
+            None => (url.to_owned(), false),
+        };
+        let url = url.as_str();
 
-        let dns_resolver = self.dns_resolver.clone();
         let tester = async move {
             let name = name_clone;
-            let connector = LocalConnector(proxy.clone(), dns_resolver);
-
-            let connector = {
-                use crate::common::tls::GLOBAL_ROOT_STORE;
-
-                let mut tls_config = rustls::ClientConfig::builder()
-                    .with_root_certificates(GLOBAL_ROOT_STORE.clone())
-                    .with_no_client_auth();
 
-                tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+            if !expected.mode.is_http() {
+                return self
+                    .ping_test(
+                        &proxy,
+                        url,
+                        expected.mode,
+                        timeout.unwrap_or(default_timeout),
+                    )
+                    .await
+                    .map_err(|e| new_io_error(format!("{}: {}", url, e)));
+            }
 
-                let connector = hyper_rustls::HttpsConnectorBuilder::new()
-                    .with_tls_config(tls_config)
-                    .https_or_http()
-                    .enable_all_versions()
-                    .wrap_connector(connector);
+            if h3 && expected.is_empty() && proxy.support_udp().await {
+                // e.g. UDP dropped on the way, TCP shouldn't wait for all of it
+                match tokio::time::timeout(
+                    timeout.unwrap_or(default_timeout) / 2,
+                    self.h3_test(&proxy, url),
+                )
+                .await
+                {
+                    Ok(Ok(delays)) => return Ok(delays),
+                    Ok(Err(e)) => debug!(
+                        "h3 urltest for proxy {} with url {} failed, trying TCP: {}",
+                        &name, url, e
+                    ),
+                    Err(_) => debug!(
+                        "h3 urltest for proxy {} with url {} timed out, trying TCP",
+                        &name, url
+                    ),
+                }
+            }
 
-                let mut g = self.connector_map.write().await;
-                let connector = g.entry(name.clone()).or_insert(connector);
-                connector.clone()
-            };
+            let connector = self.connector(&proxy).await;
 
             // Build the hyper client from the HTTPS connector.
             let client: Client<_, Empty<Bytes>> =
@@ -173,16 +1153,20 @@ impl ProxyManager {
 
             let resp = TimedFuture::new(client.request(req), None);
 
-            let delay: u16 =
+            let (res, delay): (_, u16) =
                 match tokio::time::timeout(timeout.unwrap_or(default_timeout), resp)
                     .await
                 {
                     Ok((res, delay)) => match res {
                         Ok(res) => {
-                            let delay = delay
-                                .as_millis()
-                                .try_into()
-                                .expect("delay is too large");
+                            if let Some(date) = res
+                                .headers()
+                                .get(hyper::header::DATE)
+                                .and_then(|x| x.to_str().ok())
+                            {
+                                clock::observe_http_date(date);
+                            }
+                            let delay = delay_ms(delay.as_millis());
                             trace!(
                                 "urltest for proxy {} with url {} returned \
                                  response {} in {}ms",
@@ -191,7 +1175,7 @@ impl ProxyManager {
                                 res.status(),
                                 delay
                             );
-                            Ok(delay)
+                            Ok((res, delay))
                         }
                         Err(e) => {
                             debug!(
@@ -214,6 +1198,31 @@ impl ProxyManager {
                     }
                 }?;
 
+            if let Some(status) = &expected.status
+                && !status.matches(res.status().as_u16())
+            {
+                return Err(new_io_error(format!(
+                    "{} returned {}, not {}",
+                    url,
+                    res.status(),
+                    status
+                )));
+            }
+            if let Some(keyword) = &expected.keyword {
+                let found = tokio::time::timeout(
+                    timeout.unwrap_or(default_timeout),
+                    body_contains(res.into_body(), keyword.as_bytes()),
+                )
+                .await
+                .map_err(|_| new_io_error(format!("timeout for {}", url)))??;
+                if !found {
+                    return Err(new_io_error(format!(
+                        "{} returned no {}",
+                        url, keyword
+                    )));
+                }
+            }
+
             let req2 = Request::get(url)
                 .header("Connection", "Close")
                 .version(hyper::Version::HTTP_11)
@@ -228,54 +1237,431 @@ impl ProxyManager {
             .await
             {
                 Ok((res, delay2)) => match res {
-                    Ok(_) => ((delay2.as_millis() + delay as u128) / 2)
-                        .try_into()
-                        .expect("delay is too large"),
+                    Ok(_) => delay_ms((delay2.as_millis() + delay as u128) / 2),
                     Err(_) => 0,
                 },
                 Err(_) => 0,
             };
 
-            Ok((delay, mean_delay))
+            Ok((delay, mean_delay))
+        };
+
+        let result = tester.await;
+
+        self.report_alive(&name, result.is_ok()).await;
+
+        let ins = DelayHistory {
+            time: Utc::now(),
+            delay: result.as_ref().map(|x| x.0).unwrap_or(0),
+            mean_delay: result.as_ref().map(|x| x.1).unwrap_or(0),
+            mode: expected.mode,
+        };
+
+        let (changed, stored) = self.view_mut(&name, |state| {
+            let changed = delay_changed(state.reported_delay, ins.delay);
+            if changed {
+                state.reported_delay = Some(ins.delay);
+            }
+            state.delay_history.push_back(ins);
+            if state.delay_history.len() > self.history_size {
+                state.delay_history.pop_front();
+            }
+            // the tests of the groups with their own url aren't restored
+            let stored = (self.group.is_none()).then(|| StoredState {
+                alive: state.alive.load(Ordering::Relaxed),
+                history: state.delay_history.iter().cloned().collect(),
+            });
+            (changed, stored)
+        });
+
+        if changed && self.group.is_none() {
+            self.send_event(ProxyEvent::DelayChanged {
+                proxy: name.clone(),
+                delay: result.as_ref().map(|x| x.0).unwrap_or(0),
+            });
+        }
+        if let Some(cache_store) = &self.cache_store
+            && let Some(stored) = stored
+            && let Ok(stored) = serde_json::to_string(&stored)
+        {
+            cache_store.set_delay_history(&name, &stored).await;
+        }
+
+        result
+    }
+
+    /// Two DNS queries to `target`, a `host:port` or a host on port 53,
+    /// through the UDP relay of `proxy`, like the two requests of the url
+    /// test. A `stun://` target gets STUN binding requests instead, on port
+    /// 3478 unless it has one
+    #[instrument(skip(self, proxy))]
+    pub async fn udp_test(
+        &self,
+        proxy: AnyOutboundHandler,
+        target: &str,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<(u16, u16)> {
+        let name = proxy.name().to_owned();
+        let timeout = timeout.unwrap_or(Duration::from_secs(5));
+
+        let (probe, host) = match target.strip_prefix(STUN_SCHEME) {
+            Some(host) => (UdpProbe::Stun, host),
+            None => (UdpProbe::Dns, target),
+        };
+
+        let tester = async {
+            let destination = udp_destination(host, probe.default_port())
+                .ok_or_else(|| {
+                    new_io_error(format!("invalid udp target: {}", target))
+                })?;
+            let sess = Session {
+                destination: destination.clone(),
+                network: Network::Udp,
+                ..Default::default()
+            };
+            // the relay is set up within the time of the first query
+            let first = async {
+                let mut datagram = proxy
+                    .connect_datagram(&sess, self.dns_resolver.clone())
+                    .await?;
+                let delay = probe.round_trip(&mut datagram, &destination).await?;
+                Ok::<_, std::io::Error>((datagram, delay))
+            };
+            let (mut datagram, delay) = tokio::time::timeout(timeout, first)
+                .await
+                .map_err(|_| new_io_error(format!("timeout for {}", target)))??;
+            let mean_delay = match tokio::time::timeout(
+                timeout,
+                probe.round_trip(&mut datagram, &destination),
+            )
+            .await
+            {
+                Ok(Ok(delay2)) => ((delay as u32 + delay2 as u32) / 2) as u16,
+                _ => 0,
+            };
+            Ok((delay, mean_delay))
+        };
+
+        let result = tester.await;
+        match &result {
+            Ok((delay, _)) => trace!("udp test for {} took {}ms", name, delay),
+            Err(e) => debug!("udp test for {} failed: {}", name, e),
+        }
+
+        let ins = DelayHistory {
+            time: Utc::now(),
+            delay: result.as_ref().map(|x| x.0).unwrap_or(0),
+            mean_delay: result.as_ref().map(|x| x.1).unwrap_or(0),
+            mode: CheckMode::default(),
+        };
+
+        let state = self.state_or_default(&name);
+        let mut state = state.write().unwrap();
+
+        state.udp_alive = Some(result.is_ok());
+        state.udp_delay_history.push_back(ins);
+        if state.udp_delay_history.len() > self.history_size {
+            state.udp_delay_history.pop_front();
+        }
+
+        result
+    }
+
+    /// the same two requests as the url test, each on a new QUIC connection
+    async fn h3_test(
+        &self,
+        proxy: &AnyOutboundHandler,
+        url: &str,
+    ) -> anyhow::Result<(u16, u16)> {
+        let uri = url.parse::<http::Uri>()?;
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow!("invalid url: {}", url))?
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(443);
+        let destination: SocksAddr = (host.clone(), port)
+            .try_into()
+            .map_err(|_| anyhow!("invalid url: {}", url))?;
+        // the proxy resolves domains, QUIC only needs an address to talk to
+        let peer = match &destination {
+            SocksAddr::Ip(addr) => *addr,
+            SocksAddr::Domain(..) => {
+                SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), port)
+            }
+        };
+
+        let request = || async {
+            let start = Instant::now();
+            let socket = ProxiedUdpSocket::new(
+                proxy,
+                self.dns_resolver.clone(),
+                destination.clone(),
+                peer,
+            )
+            .await?;
+            let conn = H3Connection::connect(
+                Arc::new(socket),
+                peer,
+                &host,
+                H3Connection::tls_config(),
+            )
+            .await?;
+            let res = conn.send(http::Request::get(url).body(())?, None).await?;
+            trace!(
+                "h3 urltest with url {} returned response {} in {:?}",
+                url,
+                res.status(),
+                start.elapsed()
+            );
+            anyhow::Ok(start.elapsed().as_millis() as u32)
+        };
+
+        let delay = request().await?;
+        let mean_delay = match request().await {
+            Ok(delay2) => (delay + delay2) / 2,
+            Err(_) => 0,
+        };
+        Ok((delay_ms(delay as u128), delay_ms(mean_delay as u128)))
+    }
+
+    /// connects to the host of `url` through `proxy`, and in
+    /// `CheckMode::Tls` completes the TLS handshake with it, twice like the
+    /// requests of the url test. The proxies other than DIRECT and socks5
+    /// hand out their streams before the server answers, so those are
+    /// pinged with the TLS handshake in `CheckMode::Tcp` too
+    async fn ping_test(
+        &self,
+        proxy: &AnyOutboundHandler,
+        url: &str,
+        mode: CheckMode,
+        timeout: Duration,
+    ) -> anyhow::Result<(u16, u16)> {
+        let mode = match proxy.proto() {
+            OutboundType::Direct | OutboundType::Socks5 => mode,
+            _ => CheckMode::Tls,
+        };
+        let uri = url.parse::<http::Uri>()?;
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow!("invalid url: {}", url))?
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+        let sess = Session {
+            destination: (host.clone(), port)
+                .try_into()
+                .map_err(|_| anyhow!("invalid url: {}", url))?,
+            ..Default::default()
+        };
+
+        let connect = || async {
+            let start = Instant::now();
+            let stream = proxy
+                .connect_stream(&sess, self.dns_resolver.clone())
+                .await?;
+            if mode == CheckMode::Tls {
+                let server_name =
+                    rustls::pki_types::ServerName::try_from(host.clone())?;
+                tokio_rustls::TlsConnector::from(PING_TLS_CONFIG.clone())
+                    .connect(server_name, stream)
+                    .await?;
+            }
+            trace!("{:?} ping of {} took {:?}", mode, url, start.elapsed());
+            anyhow::Ok(start.elapsed().as_millis() as u32)
         };
 
-        let result = tester.await;
+        let delay = tokio::time::timeout(timeout, connect())
+            .await
+            .map_err(|_| anyhow!("timeout"))??;
+        let mean_delay = match tokio::time::timeout(timeout, connect()).await {
+            Ok(Ok(delay2)) => (delay + delay2) / 2,
+            _ => 0,
+        };
+        Ok((delay_ms(delay as u128), delay_ms(mean_delay as u128)))
+    }
+}
 
-        self.report_alive(&name, result.is_ok()).await;
+/// whether the first `KEYWORD_BODY_LIMIT` bytes of `body` contain `keyword`
+async fn body_contains(
+    mut body: hyper::body::Incoming,
+    keyword: &[u8],
+) -> std::io::Result<bool> {
+    let mut buf = vec![];
+    while buf.len() < KEYWORD_BODY_LIMIT {
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    buf.extend_from_slice(data);
+                }
+            }
+            Some(Err(e)) => return Err(new_io_error(e.to_string())),
+            None => break,
+        }
+    }
+    Ok(keyword.is_empty() || buf.windows(keyword.len()).any(|x| x == keyword))
+}
 
-        let ins = DelayHistory {
-            time: Utc::now(),
-            delay: result.as_ref().map(|x| x.0).unwrap_or(0),
-            mean_delay: result.as_ref().map(|x| x.1).unwrap_or(0),
-        };
+/// `host` of a `udp-target`, an IP, a name or either with a port, on
+/// `port` unless it has one
+fn udp_destination(host: &str, port: u16) -> Option<SocksAddr> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Some(addr.into());
+    }
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    if let Ok(ip) = ip.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, port).into());
+    }
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) => (name, port.parse().ok()?),
+        None => (host, port),
+    };
+    if name.is_empty() || name.contains(':') {
+        return None;
+    }
+    (name.to_owned(), port).try_into().ok()
+}
+
+/// What the UDP tests send to the `udp-target`
+#[derive(Clone, Copy)]
+enum UdpProbe {
+    Dns,
+    Stun,
+}
 
-        let mut state = self.proxy_state.write().await;
-        let state = state.entry(name.to_owned()).or_default();
+impl UdpProbe {
+    fn default_port(self) -> u16 {
+        match self {
+            UdpProbe::Dns => 53,
+            UdpProbe::Stun => 3478,
+        }
+    }
 
-        state.delay_history.push_back(ins);
-        if state.delay_history.len() > 10 {
-            state.delay_history.pop_front();
+    async fn round_trip(
+        self,
+        datagram: &mut BoxedChainedDatagram,
+        destination: &SocksAddr,
+    ) -> std::io::Result<u16> {
+        match self {
+            UdpProbe::Dns => dns_round_trip(datagram, destination).await,
+            UdpProbe::Stun => stun_round_trip(datagram, destination).await,
         }
+    }
+}
 
-        result
+/// milliseconds until the answer to a query of the root name servers, which
+/// any DNS server answers from its cache
+async fn dns_round_trip(
+    datagram: &mut BoxedChainedDatagram,
+    destination: &SocksAddr,
+) -> std::io::Result<u16> {
+    let id = rand::random::<u16>();
+    let mut query = op::Query::new();
+    query.set_name(rr::Name::root());
+    query.set_query_type(rr::RecordType::NS);
+    let mut req = op::Message::new();
+    req.set_id(id).set_recursion_desired(true).add_query(query);
+    let data = req.to_vec().map_err(|e| new_io_error(e.to_string()))?;
+
+    round_trip(datagram, destination, data, |answer| {
+        op::Message::from_vec(answer).is_ok_and(|x| x.id() == id)
+    })
+    .await
+}
+
+/// a binding request, answered by any STUN server with a response of the
+/// same transaction, RFC 8489
+async fn stun_round_trip(
+    datagram: &mut BoxedChainedDatagram,
+    destination: &SocksAddr,
+) -> std::io::Result<u16> {
+    let transaction = rand::random::<[u8; 12]>();
+    let mut data = Vec::with_capacity(20);
+    data.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+    data.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    data.extend_from_slice(&transaction);
+
+    round_trip(datagram, destination, data, |answer| {
+        answer.len() >= 20
+            // a success or an error response, either way it got through
+            && answer[0] & 0x01 == 0x01
+            && answer[4..8] == STUN_MAGIC_COOKIE.to_be_bytes()
+            && answer[8..20] == transaction
+    })
+    .await
+}
+
+/// milliseconds from sending `data` to the first answer to it
+async fn round_trip(
+    datagram: &mut BoxedChainedDatagram,
+    destination: &SocksAddr,
+    data: Vec<u8>,
+    is_answer: impl Fn(&[u8]) -> bool,
+) -> std::io::Result<u16> {
+    let start = Instant::now();
+    datagram
+        .send(UdpPacket {
+            data,
+            src_addr: SocksAddr::any_ipv4(),
+            dst_addr: destination.clone(),
+        })
+        .await?;
+    loop {
+        let pkt = datagram
+            .next()
+            .await
+            .ok_or_else(|| new_io_error("the datagram is closed"))?;
+        // a late answer to the previous query is skipped
+        if is_answer(&pkt.data) {
+            // 0 is for a failed test
+            let delay = start.elapsed().as_millis().clamp(1, u16::MAX as u128);
+            return Ok(delay as u16);
+        }
     }
 }
 
+/// a delay in milliseconds, saturated at `MAX_DELAY`
+fn delay_ms(millis: u128) -> u16 {
+    millis.min(MAX_DELAY as u128) as u16
+}
+
 #[cfg(test)]
 mod tests {
     use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 
     use futures::TryFutureExt;
 
+    use super::{
+        DelayHistory, DelayStats, Expected, FAILURE_LIMIT, Liveness, MAX_DELAY,
+        PANIC_LIMIT, PANIC_WINDOW, UNKNOWN_DELAY, delay_ms,
+    };
     use crate::{
         app::{
-            dispatcher::ChainedStreamWrapper, dns::MockClashResolver,
+            dispatcher::{ChainedStreamWrapper, TrackerInfo},
+            dns::MockClashResolver,
             remote_content_manager,
         },
-        config::internal::proxy::PROXY_DIRECT,
+        common::errors::NetError,
+        config::internal::proxy::{
+            CheckMode, PROXY_DIRECT, PROXY_REJECT, UnknownNodes,
+        },
         proxy::{direct, mocks::MockDummyOutboundHandler},
     };
 
+    #[test]
+    fn test_delay_ms_saturates() {
+        assert_eq!(delay_ms(120), 120);
+        assert_eq!(delay_ms(MAX_DELAY as u128), MAX_DELAY);
+        assert_eq!(delay_ms(u16::MAX as u128 + 1), MAX_DELAY);
+        assert_eq!(delay_ms(u128::MAX), MAX_DELAY);
+        assert!(MAX_DELAY < UNKNOWN_DELAY);
+    }
+
     #[tokio::test]
     async fn test_proxy_manager_alive() {
         let mut mock_resolver = MockClashResolver::new();
@@ -321,6 +1707,241 @@ mod tests {
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 10);
     }
 
+    #[tokio::test]
+    async fn test_proxy_state_locked_apart() {
+        use std::sync::atomic::Ordering;
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        manager.report_alive("a", true).await;
+        manager.report_alive("b", false).await;
+
+        // a check of `a` recording its result doesn't hold up `b`
+        let a = manager.state("a").unwrap();
+        let _guard = a.write().unwrap();
+        let alive = |name| manager.view(name, |x| x.alive.load(Ordering::Relaxed));
+        assert_eq!(alive("b"), Some(false));
+        assert_eq!(alive("c"), None);
+    }
+
+    #[tokio::test]
+    async fn test_udp_test() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            // a query echoed back is as good as an answer, only its id is
+            // checked
+            while let Ok((n, from)) = server.recv_from(&mut buf).await {
+                let _ = server.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let direct = Arc::new(direct::Handler::new());
+
+        manager
+            .udp_test(direct.clone(), &target, None)
+            .await
+            .expect("test failed");
+        assert!(manager.last_udp_delay(PROXY_DIRECT).await < u16::MAX);
+        assert!(manager.delay_history(PROXY_DIRECT).await.is_empty());
+
+        manager
+            .udp_test(direct, "a:b:c", Some(Duration::from_millis(100)))
+            .await
+            .expect_err("should fail");
+        assert_eq!(manager.last_udp_delay(PROXY_DIRECT).await, u16::MAX);
+        assert_eq!(manager.udp_delay_history(PROXY_DIRECT).await.len(), 2);
+    }
+
+    #[test]
+    fn test_udp_destination() {
+        use super::udp_destination;
+
+        let parsed = |host| udp_destination(host, 53).map(|x| x.to_string());
+        assert_eq!(parsed("1.1.1.1").as_deref(), Some("1.1.1.1:53"));
+        assert_eq!(parsed("1.1.1.1:5353").as_deref(), Some("1.1.1.1:5353"));
+        assert_eq!(parsed("2001:db8::1").as_deref(), Some("[2001:db8::1]:53"));
+        assert_eq!(parsed("[2001:db8::1]").as_deref(), Some("[2001:db8::1]:53"));
+        assert_eq!(
+            parsed("[2001:db8::1]:5353").as_deref(),
+            Some("[2001:db8::1]:5353")
+        );
+        assert_eq!(
+            parsed("dns.example.com").as_deref(),
+            Some("dns.example.com:53")
+        );
+        assert_eq!(parsed("a:b:c"), None);
+        assert_eq!(parsed("a:b:53"), None);
+    }
+
+    #[tokio::test]
+    async fn test_udp_test_stun() {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = format!("stun://{}", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            // a binding success response, without the mapped address
+            while let Ok((n, from)) = server.recv_from(&mut buf).await {
+                buf[..2].copy_from_slice(&0x0101u16.to_be_bytes());
+                let _ = server.send_to(&buf[..n.min(20)], from).await;
+            }
+        });
+        let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let direct = Arc::new(direct::Handler::new());
+        assert!(manager.udp_alive(PROXY_DIRECT).await);
+
+        manager
+            .udp_test(direct.clone(), &target, None)
+            .await
+            .expect("test failed");
+        assert!(manager.udp_alive(PROXY_DIRECT).await);
+        assert!(manager.last_udp_delay(PROXY_DIRECT).await < u16::MAX);
+
+        manager
+            .udp_test(
+                direct,
+                &format!("stun://{}", silent.local_addr().unwrap()),
+                Some(Duration::from_millis(100)),
+            )
+            .await
+            .expect_err("should fail");
+        assert!(!manager.udp_alive(PROXY_DIRECT).await);
+        // the TCP liveness is its own
+        assert!(manager.alive(PROXY_DIRECT).await);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_stats() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+
+        manager.report_dial("auto", true);
+        manager.report_dial("auto", false);
+
+        let tracker = Arc::new(TrackerInfo::default());
+        tracker.proxy_chain_holder.push("node".to_owned()).await;
+        tracker.proxy_chain_holder.push("auto".to_owned()).await;
+
+        let active = manager.track_connection(tracker.clone()).await;
+        assert_eq!(manager.outbound_stats("node").active_connections, 1);
+        assert_eq!(manager.outbound_stats("auto").active_connections, 1);
+
+        tracker
+            .upload_total
+            .store(100, std::sync::atomic::Ordering::Relaxed);
+        drop(active);
+
+        let stats = manager.outbound_stats("auto");
+        assert_eq!(stats.active_connections, 0);
+        assert_eq!(stats.dials, 2);
+        assert_eq!(stats.dial_errors, 1);
+        assert_eq!(stats.error_rate, 0.5);
+        assert_eq!(stats.upload_total, 100);
+        assert_eq!(manager.outbound_stats("node").upload_total, 100);
+        assert_eq!(manager.outbound_stats("unknown"), Default::default());
+
+        manager.forget(&["auto".to_owned()]);
+        assert_eq!(manager.outbound_stats("auto"), Default::default());
+        assert_eq!(manager.outbound_stats("node").upload_total, 100);
+
+        for _ in 0..PANIC_LIMIT - 1 {
+            manager.report_panic("node").await;
+        }
+        assert!(manager.alive("node").await);
+        manager.report_panic("node").await;
+        assert!(!manager.alive("node").await);
+        assert_eq!(manager.outbound_stats("node").panics, PANIC_LIMIT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_panic_window() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+
+        for _ in 0..PANIC_LIMIT - 1 {
+            manager.report_panic("node").await;
+        }
+        tokio::time::advance(PANIC_WINDOW).await;
+        manager.report_panic("node").await;
+        assert!(manager.alive("node").await);
+        assert_eq!(manager.outbound_stats("node").panics, PANIC_LIMIT);
+
+        for _ in 0..PANIC_LIMIT - 1 {
+            manager.report_panic("node").await;
+        }
+        assert!(!manager.alive("node").await);
+    }
+
+    #[tokio::test]
+    async fn test_report_failure() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+        let scoped = manager.for_group("JP");
+        scoped.report_alive("node", true).await;
+
+        for _ in 0..FAILURE_LIMIT - 1 {
+            manager.report_failure("node").await;
+        }
+        assert!(manager.alive("node").await);
+        manager.report_failure("node").await;
+        assert!(!manager.alive("node").await);
+        assert!(!scoped.alive("node").await);
+
+        // brought back by a health check, it takes twice the failures
+        manager.report_alive("node", true).await;
+        for _ in 0..FAILURE_LIMIT * 2 - 1 {
+            manager.report_failure("node").await;
+        }
+        assert!(manager.alive("node").await);
+        manager.report_failure("node").await;
+        assert!(!manager.alive("node").await);
+
+        // and a connection through it succeeding resets the limit
+        manager.report_success("node").await;
+        assert!(manager.alive("node").await);
+        assert!(!scoped.alive("node").await);
+        for _ in 0..FAILURE_LIMIT {
+            manager.report_failure("node").await;
+        }
+        assert!(!manager.alive("node").await);
+
+        for _ in 0..FAILURE_LIMIT {
+            manager.report_failure(PROXY_REJECT).await;
+            manager.report_failure(PROXY_DIRECT).await;
+        }
+        assert!(manager.alive(PROXY_REJECT).await);
+        assert!(manager.alive(PROXY_DIRECT).await);
+
+        // the destination refusing isn't the proxy failing
+        manager.report_success("other").await;
+        let refused =
+            NetError::remote("SOCKS5 request failed with connection refused");
+        for _ in 0..FAILURE_LIMIT {
+            manager.report_connect("other", Some(&refused)).await;
+        }
+        assert!(manager.alive("other").await);
+        let down = NetError::dial("connection refused");
+        for _ in 0..FAILURE_LIMIT {
+            manager.report_connect("other", Some(&down)).await;
+        }
+        assert!(!manager.alive("other").await);
+    }
+
     #[tokio::test]
     async fn test_proxy_manager_timeout() {
         let mut mock_resolver = MockClashResolver::new();
@@ -359,4 +1980,476 @@ mod tests {
         assert!(manager.last_delay(PROXY_DIRECT).await == u16::MAX);
         assert!(manager.delay_history(PROXY_DIRECT).await.len() == 1);
     }
+
+    #[test]
+    fn test_delay_stats() {
+        let history = |delays: &[u16]| {
+            delays
+                .iter()
+                .map(|x| DelayHistory {
+                    time: chrono::Utc::now(),
+                    delay: *x,
+                    mean_delay: *x,
+                    mode: Default::default(),
+                })
+                .collect()
+        };
+
+        assert_eq!(DelayStats::of(&history(&[])), None);
+        assert_eq!(DelayStats::of(&history(&[0, 0])), None);
+        // the failed test in between is left out
+        assert_eq!(
+            DelayStats::of(&history(&[100, 0, 200, 300, 400])),
+            Some(DelayStats {
+                samples: 4,
+                mean: 250,
+                p50: 200,
+                p95: 400,
+                jitter: 100,
+            })
+        );
+        let one = DelayStats::of(&history(&[120])).unwrap();
+        assert_eq!((one.p50, one.p95, one.jitter), (120, 120, 0));
+    }
+
+    #[tokio::test]
+    async fn test_group_url_test() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut stream, _)) = server.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\
+                          Connection: close\r\n\r\ncountry: JP",
+                    )
+                    .await;
+            }
+        });
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let jp = manager.for_group("JP");
+        let us = manager.for_group("US");
+        let direct = Arc::new(direct::Handler::new());
+
+        let expected = Expected {
+            keyword: Some("JP".to_owned()),
+            ..Default::default()
+        };
+        jp.url_test_expecting(direct.clone(), &url, None, &expected)
+            .await
+            .expect("test failed");
+        let expected = Expected {
+            keyword: Some("US".to_owned()),
+            ..Default::default()
+        };
+        manager
+            .url_test_expecting(direct, &url, None, &expected)
+            .await
+            .expect_err("should fail");
+
+        assert!(jp.alive(PROXY_DIRECT).await);
+        assert_eq!(jp.delay_history(PROXY_DIRECT).await.len(), 1);
+        assert!(!manager.alive(PROXY_DIRECT).await);
+        assert_eq!(manager.last_delay(PROXY_DIRECT).await, u16::MAX);
+        // a group yet to test it sees the proxy's own results
+        assert!(!us.alive(PROXY_DIRECT).await);
+    }
+
+    #[tokio::test]
+    async fn test_url_test_expected() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut stream, _)) = server.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\
+                          Connection: close\r\n\r\ncountry: JP",
+                    )
+                    .await;
+            }
+        });
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let direct = Arc::new(direct::Handler::new());
+
+        let expected = Expected {
+            status: Some("200-299".parse().unwrap()),
+            keyword: Some("JP".to_owned()),
+            ..Default::default()
+        };
+        manager
+            .url_test_expecting(direct.clone(), &url, None, &expected)
+            .await
+            .expect("test failed");
+        assert!(manager.alive(PROXY_DIRECT).await);
+
+        let expected = Expected {
+            keyword: Some("US".to_owned()),
+            ..Default::default()
+        };
+        manager
+            .url_test_expecting(direct.clone(), &url, None, &expected)
+            .await
+            .expect_err("should fail");
+        assert!(!manager.alive(PROXY_DIRECT).await);
+
+        let expected = Expected {
+            status: Some("204".parse().unwrap()),
+            ..Default::default()
+        };
+        manager
+            .url_test_expecting(direct, &url, None, &expected)
+            .await
+            .expect_err("should fail");
+        assert_eq!(manager.last_delay(PROXY_DIRECT).await, u16::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_speed_test() {
+        const PAYLOAD: usize = 1_000_000;
+
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut stream, _)) = server.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    let res = if buf[..n].starts_with(b"GET /payload ") {
+                        let mut res = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: \
+                             close\r\n\r\n",
+                            PAYLOAD
+                        )
+                        .into_bytes();
+                        res.resize(res.len() + PAYLOAD, b'x');
+                        res
+                    } else {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                          Connection: close\r\n\r\n"
+                            .to_vec()
+                    };
+                    let _ = stream.write_all(&res).await;
+                });
+            }
+        });
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let direct = Arc::new(direct::Handler::new());
+
+        let bandwidth = manager
+            .speed_test(
+                direct.clone(),
+                &format!("http://{}/payload", addr),
+                64 * 1024,
+                Duration::from_secs(5),
+            )
+            .await
+            .expect("test failed");
+        // stops once the cap is reached, not at the end of the payload
+        assert!(bandwidth.bytes >= 64 * 1024);
+        assert!(bandwidth.bytes < PAYLOAD as u64);
+        assert!(manager.bandwidth(PROXY_DIRECT).await.is_some());
+
+        manager
+            .speed_test(
+                direct,
+                &format!("http://{}/missing", addr),
+                64 * 1024,
+                Duration::from_secs(5),
+            )
+            .await
+            .expect_err("should fail");
+        assert!(manager.bandwidth(PROXY_DIRECT).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ping_test() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("https://{}/", server.local_addr().unwrap());
+        tokio::spawn(async move {
+            // nothing is sent in a TCP ping
+            while server.accept().await.is_ok() {}
+        });
+
+        let mut mock_resolver = MockClashResolver::new();
+        mock_resolver.expect_ipv6().return_const(false);
+        let manager =
+            remote_content_manager::ProxyManager::new(Arc::new(mock_resolver));
+        let direct = Arc::new(direct::Handler::new());
+        let expected = Expected {
+            mode: CheckMode::Tcp,
+            ..Default::default()
+        };
+
+        manager
+            .url_test_expecting(direct.clone(), &url, None, &expected)
+            .await
+            .expect("test failed");
+        assert!(manager.alive(PROXY_DIRECT).await);
+        let history = manager.delay_history(PROXY_DIRECT).await;
+        assert_eq!(serde_json::to_value(&history[0]).unwrap()["mode"], "tcp");
+
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", closed.local_addr().unwrap());
+        drop(closed);
+        manager
+            .url_test_expecting(direct, &url, None, &expected)
+            .await
+            .expect_err("should fail");
+        assert!(!manager.alive(PROXY_DIRECT).await);
+
+        // a stream handed out before any server answers doesn't pass
+        let mut mock_handler = MockDummyOutboundHandler::new();
+        mock_handler.expect_name().return_const("ss".to_owned());
+        mock_handler
+            .expect_proto()
+            .return_const(crate::proxy::OutboundType::Shadowsocks);
+        mock_handler.expect_connect_stream().returning(|_, _| {
+            Ok(Box::new(ChainedStreamWrapper::new(
+                tokio_test::io::Builder::new().build(),
+            )))
+        });
+        manager
+            .url_test_expecting(
+                Arc::new(mock_handler),
+                "https://example.com/",
+                Some(Duration::from_secs(1)),
+                &expected,
+            )
+            .await
+            .expect_err("should fail");
+        assert!(!manager.alive("ss").await);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_events() {
+        use super::ProxyEvent;
+
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ));
+        let mut events = manager.subscribe_events();
+
+        manager.report_alive("node", true).await;
+        manager.report_alive("node", true).await;
+        manager.report_alive("node", false).await;
+        manager.report_alive("node", false).await;
+        manager.report_alive("node", true).await;
+        manager.report_alive("gone", false).await;
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ProxyEvent::ProxyWentDown {
+                proxy: "node".to_owned()
+            }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ProxyEvent::ProxyRecovered {
+                proxy: "node".to_owned()
+            }
+        );
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ProxyEvent::ProxyWentDown {
+                proxy: "gone".to_owned()
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_delay_changed() {
+        use super::delay_changed;
+
+        assert!(delay_changed(None, 120));
+        assert!(!delay_changed(Some(120), 150));
+        assert!(delay_changed(Some(120), 170));
+        assert!(delay_changed(Some(120), 70));
+        // liveness changes
+        assert!(delay_changed(Some(20), 0));
+        assert!(delay_changed(Some(0), 20));
+        assert!(!delay_changed(Some(0), 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_after() {
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ))
+        .with_stale_after(60);
+        assert_eq!(manager.liveness("node").await, Liveness::Unknown);
+        assert!(manager.alive("node").await);
+        assert!(!manager.usable("node", UnknownNodes::Skip).await);
+
+        manager.report_alive("node", true).await;
+        assert_eq!(manager.liveness("node").await, Liveness::Alive);
+        assert!(manager.usable("node", UnknownNodes::Skip).await);
+        manager.report_alive("dead", false).await;
+        assert_eq!(manager.last_delay("dead").await, u16::MAX);
+        assert_eq!(
+            manager.usable_with_delays(&["node", "dead", "new"], UnknownNodes::Skip),
+            vec![(true, None), (false, None), (false, None)]
+        );
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(manager.liveness("node").await, Liveness::Unknown);
+        assert_eq!(manager.liveness("dead").await, Liveness::Unknown);
+        assert!(!manager.usable("node", UnknownNodes::Skip).await);
+        assert!(manager.usable("dead", UnknownNodes::Tolerate).await);
+        assert_eq!(manager.last_delay("node").await, UNKNOWN_DELAY);
+
+        manager.report_alive("node", false).await;
+        assert_eq!(manager.liveness("node").await, Liveness::Dead);
+    }
+
+    #[tokio::test]
+    async fn test_restore_delay_history() {
+        use chrono::Utc;
+
+        use super::{DelayHistory, StoredState};
+        use crate::app::profile::{MemoryStore, ThreadSafeCacheFile};
+
+        let cache_store = ThreadSafeCacheFile::with_store(
+            Box::new(MemoryStore::default()),
+            false,
+            true,
+        );
+        let stored = |alive, delay, age| {
+            serde_json::to_string(&StoredState {
+                alive,
+                history: vec![DelayHistory {
+                    time: Utc::now() - chrono::Duration::seconds(age),
+                    delay,
+                    mean_delay: delay,
+                    mode: Default::default(),
+                }],
+            })
+            .unwrap()
+        };
+        cache_store
+            .set_delay_history("fast", &stored(true, 80, 10))
+            .await;
+        cache_store
+            .set_delay_history("dead", &stored(false, 0, 10))
+            .await;
+        cache_store
+            .set_delay_history("old", &stored(true, 50, 3600))
+            .await;
+        cache_store.set_delay_history("broken", "{").await;
+
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ))
+        .with_stale_after(60)
+        .with_cache_store(cache_store);
+        manager.restore().await;
+
+        assert_eq!(manager.liveness("fast").await, Liveness::Alive);
+        assert_eq!(manager.last_delay("fast").await, 80);
+        assert_eq!(manager.liveness("dead").await, Liveness::Dead);
+        assert_eq!(manager.liveness("old").await, Liveness::Unknown);
+        assert_eq!(manager.delay_history("old").await.len(), 1);
+        assert_eq!(manager.liveness("broken").await, Liveness::Unknown);
+    }
+
+    #[tokio::test]
+    async fn test_delay_sampling() {
+        use chrono::Utc;
+
+        use super::{DelayHistory, StoredState};
+        use crate::{
+            app::profile::{MemoryStore, ThreadSafeCacheFile},
+            config::internal::proxy::DelaySampling,
+        };
+
+        let cache_store = ThreadSafeCacheFile::with_store(
+            Box::new(MemoryStore::default()),
+            false,
+            true,
+        );
+        let history = [100, 100, 400]
+            .into_iter()
+            .map(|delay| DelayHistory {
+                time: Utc::now(),
+                delay,
+                mean_delay: delay,
+                mode: Default::default(),
+            })
+            .collect();
+        let stored = StoredState {
+            alive: true,
+            history,
+        };
+        cache_store
+            .set_delay_history("node", &serde_json::to_string(&stored).unwrap())
+            .await;
+
+        let manager = |sampling| {
+            remote_content_manager::ProxyManager::new(Arc::new(
+                MockClashResolver::new(),
+            ))
+            .with_history_size(2)
+            .with_delay_sampling(sampling)
+            .with_cache_store(cache_store.clone())
+        };
+
+        let last = manager(DelaySampling::Last);
+        last.restore().await;
+        assert_eq!(last.delay_history("node").await.len(), 2);
+        assert_eq!(last.last_delay("node").await, 400);
+
+        let ewma = manager(DelaySampling::Ewma);
+        ewma.restore().await;
+        assert_eq!(ewma.last_delay("node").await, 190);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_limit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let manager = remote_content_manager::ProxyManager::new(Arc::new(
+            MockClashResolver::new(),
+        ))
+        .with_check_limit(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..6)
+            .map(|_| {
+                let (manager, running, peak) =
+                    (manager.clone(), running.clone(), peak.clone());
+                tokio::spawn(async move {
+                    let _slot = manager.check_slot(6).await;
+                    let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(n, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
 }
@@ -115,6 +115,7 @@ where
             }
             fs::write(self.vehicle.path(), &content)?;
         }
+        self.vehicle.commit().await;
 
         inner.hash = utils::md5(&content)[..16]
             .try_into()
@@ -159,6 +160,7 @@ where
         if hash == this.hash {
             this.updated_at = now;
             filetime::set_file_times(vehicle.path(), now.into(), now.into())?;
+            vehicle.commit().await;
             return Ok((proxies, true));
         }
 
@@ -172,6 +174,7 @@ where
 
             fs::write(vehicle.path(), &content)?;
         }
+        vehicle.commit().await;
 
         this.hash = hash;
         this.updated_at = now;
@@ -272,6 +275,7 @@ mod tests {
             .expect_path()
             .return_const(mock_file.to_str().unwrap().to_owned());
         mock_vehicle.expect_read().returning(|| Ok(vec![4, 5, 6]));
+        mock_vehicle.expect_commit().return_const(());
         mock_vehicle
             .expect_typ()
             .return_const(ProviderVehicleType::File);
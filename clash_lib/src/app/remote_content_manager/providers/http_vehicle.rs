@@ -1,6 +1,6 @@
 use super::{ProviderVehicle, ProviderVehicleType};
 use crate::{
-    app::dns::ThreadSafeDNSResolver,
+    app::{dns::ThreadSafeDNSResolver, profile::ThreadSafeCacheFile},
     common::{
         errors::map_io_error,
         http::{HttpClient, new_http_client},
@@ -9,10 +9,14 @@ use crate::{
 
 use async_trait::async_trait;
 
-use http_body_util::BodyExt;
+use http::{
+    Request, StatusCode,
+    header::{ETAG, IF_NONE_MATCH},
+};
+use http_body_util::{BodyExt, Empty};
 use hyper::Uri;
 
-use std::io;
+use std::{io, sync::Mutex};
 
 use std::path::{Path, PathBuf};
 
@@ -20,6 +24,12 @@ pub struct Vehicle {
     pub url: Uri,
     pub path: PathBuf,
     http_client: HttpClient,
+    /// keeps the ETag of the last download, to skip unchanged ones
+    cache_store: Option<ThreadSafeCacheFile>,
+    /// the ETag of the last download, stored on `commit` only, so a
+    /// download that fails to parse is fetched again rather than answered
+    /// with a 304 for the old file
+    pending_etag: Mutex<Option<String>>,
 }
 
 impl Vehicle {
@@ -28,6 +38,7 @@ impl Vehicle {
         path: P,
         cwd: Option<P>,
         dns_resolver: ThreadSafeDNSResolver,
+        cache_store: Option<ThreadSafeCacheFile>,
     ) -> Self {
         let client =
             new_http_client(dns_resolver).expect("failed to create http client");
@@ -38,6 +49,8 @@ impl Vehicle {
                 None => path.as_ref().to_path_buf(),
             },
             http_client: client,
+            cache_store,
+            pending_etag: Mutex::new(None),
         }
     }
 }
@@ -45,15 +58,49 @@ impl Vehicle {
 #[async_trait]
 impl ProviderVehicle for Vehicle {
     async fn read(&self) -> std::io::Result<Vec<u8>> {
-        self.http_client
-            .get(self.url.clone())
+        let url = self.url.to_string();
+        // only worth asking if the last download is still there
+        let etag = match &self.cache_store {
+            Some(store) if self.path.exists() => store.get_etag(&url).await,
+            _ => None,
+        };
+
+        let mut req = Request::get(self.url.clone());
+        if let Some(etag) = &etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        let res = self
+            .http_client
+            .request(req.body(Empty::new()).map_err(io::Error::other)?)
             .await
-            .map_err(|x| io::Error::new(io::ErrorKind::Other, x.to_string()))?
+            .map_err(|x| io::Error::new(io::ErrorKind::Other, x.to_string()))?;
+
+        if etag.is_some() && res.status() == StatusCode::NOT_MODIFIED {
+            *self.pending_etag.lock().unwrap() = None;
+            return tokio::fs::read(&self.path).await;
+        }
+        let new_etag = res
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned);
+
+        let body = res
             .into_body()
             .collect()
             .await
             .map(|x| x.to_bytes().to_vec())
-            .map_err(map_io_error)
+            .map_err(map_io_error)?;
+
+        *self.pending_etag.lock().unwrap() = new_etag;
+        Ok(body)
+    }
+
+    async fn commit(&self) {
+        let etag = self.pending_etag.lock().unwrap().take();
+        if let (Some(store), Some(etag)) = (&self.cache_store, etag) {
+            store.set_etag(&self.url.to_string(), &etag).await;
+        }
     }
 
     fn path(&self) -> &str {
@@ -81,7 +128,13 @@ mod tests {
             .unwrap();
         let p = std::env::temp_dir().join("test_http_vehicle");
         let r = Arc::new(EnhancedResolver::new_default().await);
-        let v = super::Vehicle::new(u, p, None, r.clone() as ThreadSafeDNSResolver);
+        let v = super::Vehicle::new(
+            u,
+            p,
+            None,
+            r.clone() as ThreadSafeDNSResolver,
+            None,
+        );
 
         let data = v.read().await.unwrap();
         assert_eq!(str::from_utf8(&data).unwrap(), "HTTPBIN is awesome");
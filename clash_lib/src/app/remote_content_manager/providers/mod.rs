@@ -40,6 +40,9 @@ pub type ThreadSafeProviderVehicle = Arc<dyn ProviderVehicle + Send + Sync>;
 #[async_trait]
 pub trait ProviderVehicle {
     async fn read(&self) -> io::Result<Vec<u8>>;
+    /// the content of the last `read` was parsed and saved, so it's safe to
+    /// remember, e.g. its ETag
+    async fn commit(&self) {}
     fn path(&self) -> &str;
     fn typ(&self) -> ProviderVehicleType;
 }
@@ -1,9 +1,12 @@
 pub mod plain_provider;
 
 pub mod proxy_set_provider;
+pub mod region_provider;
+pub mod subscription;
 
 pub use plain_provider::PlainProvider;
 pub use proxy_set_provider::ProxySetProvider;
+pub use region_provider::{RegionClassifier, RegionProvider};
 
 use std::sync::Arc;
 
@@ -78,6 +78,7 @@ impl Provider for PlainProvider {
             "vehicleType".to_owned(),
             Box::new(self.vehicle_type().to_string()),
         );
+        m.insert("healthCheck".to_owned(), Box::new(self.hc.settings()));
 
         m
     }
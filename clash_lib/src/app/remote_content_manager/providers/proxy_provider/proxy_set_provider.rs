@@ -3,11 +3,9 @@ use std::{collections::HashMap, sync::Arc, time::Duration};
 use async_trait::async_trait;
 use erased_serde::Serialize as ESerialize;
 use futures::future::BoxFuture;
-use serde::{Deserialize, Serialize};
-use serde_yaml::Value;
 use tracing::debug;
 
-use super::ProxyProvider;
+use super::{ProxyProvider, subscription};
 use crate::{
     Error,
     app::remote_content_manager::{
@@ -18,8 +16,11 @@ use crate::{
         },
     },
     common::errors::map_io_error,
-    config::internal::proxy::OutboundProxyProtocol,
-    proxy::{AnyOutboundHandler, direct, reject, socks, trojan, vmess, wg},
+    config::internal::proxy::{OutboundProxyProtocol, TlsFragmentOpt},
+    proxy::{
+        AnyOutboundHandler, block_page, dialer, direct, reject, socks, trojan,
+        vmess, wg,
+    },
 };
 
 #[cfg(feature = "shadowsocks")]
@@ -31,12 +32,6 @@ use crate::proxy::tor;
 #[cfg(feature = "tuic")]
 use crate::proxy::tuic;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ProviderScheme {
-    #[serde(rename = "proxies")]
-    proxies: Option<Vec<HashMap<String, Value>>>,
-}
-
 struct Inner {
     proxies: Vec<AnyOutboundHandler>,
     hc: Arc<HealthCheck>,
@@ -63,6 +58,7 @@ impl ProxySetProvider {
         interval: Duration,
         vehicle: ThreadSafeProviderVehicle,
         hc: HealthCheck,
+        tls_fragment: Option<TlsFragmentOpt>,
     ) -> anyhow::Result<Self> {
         let hc = Arc::new(hc);
 
@@ -90,6 +86,13 @@ impl ProxySetProvider {
                 Box::pin(async move {
                     let mut inner = inner.write().await;
                     debug!("updating {} proxies for: {}", n, input.len());
+                    let gone: Vec<_> = inner
+                        .proxies
+                        .iter()
+                        .filter(|x| input.iter().all(|y| y.name() != x.name()))
+                        .map(|x| x.name().to_owned())
+                        .collect();
+                    hc.proxy_manager().forget(&gone);
                     inner.proxies.clone_from(&input);
                     hc.update(input).await;
                     // check once after update
@@ -100,75 +103,96 @@ impl ProxySetProvider {
             },
         );
 
+        let proxy_manager = hc.proxy_manager().clone();
         let n = name.clone();
         let parser: ProxyParser = Box::new(
             move |input: &[u8]| -> anyhow::Result<Vec<AnyOutboundHandler>> {
-                let scheme: ProviderScheme =
-                    serde_yaml::from_slice(input).map_err(|x| {
-                        Error::InvalidConfig(format!(
-                            "proxy provider parse error {}: {}",
-                            n, x
-                        ))
-                    })?;
-                let proxies = scheme.proxies;
-                match proxies {
-                    Some(proxies) => {
-                        let proxies = proxies
-                            .into_iter()
-                            .filter_map(|x| OutboundProxyProtocol::try_from(x).ok())
-                            .map(|x| match x {
-                                OutboundProxyProtocol::Direct => {
-                                    Ok(Arc::new(direct::Handler::new()) as _)
-                                }
-                                OutboundProxyProtocol::Reject => {
-                                    Ok(Arc::new(reject::Handler::new()) as _)
-                                }
-                                #[cfg(feature = "shadowsocks")]
-                                OutboundProxyProtocol::Ss(s) => {
-                                    let h: shadowsocks::Handler = s.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                                OutboundProxyProtocol::Socks5(s) => {
-                                    let h: socks::Handler = s.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                                OutboundProxyProtocol::Trojan(tr) => {
-                                    let h: trojan::Handler = tr.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                                OutboundProxyProtocol::Vmess(vm) => {
-                                    let h: vmess::Handler = vm.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                                OutboundProxyProtocol::Hysteria2(h) => h.try_into(),
-                                #[cfg(feature = "ssh")]
-                                OutboundProxyProtocol::Ssh(s) => {
-                                    let h: ssh::Handler = s.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                                OutboundProxyProtocol::Wireguard(wg) => {
-                                    let h: wg::Handler = wg.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                                #[cfg(feature = "onion")]
-                                OutboundProxyProtocol::Tor(tor) => {
-                                    let h: tor::Handler = tor.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                                #[cfg(feature = "tuic")]
-                                OutboundProxyProtocol::Tuic(tuic) => {
-                                    let h: tuic::Handler = tuic.try_into()?;
-                                    Ok(Arc::new(h) as _)
-                                }
-                            })
-                            .collect::<Result<Vec<_>, crate::Error>>();
-                        Ok(proxies?)
-                    }
-                    _ => {
-                        Err(Error::InvalidConfig(format!("{}: proxies is empty", n))
-                            .into())
-                    }
+                let scheme = subscription::parse(input).map_err(|x| {
+                    Error::InvalidConfig(format!(
+                        "proxy provider parse error {}: {}",
+                        n, x
+                    ))
+                })?;
+                for skipped in scheme.skipped {
+                    debug!("proxy provider {} skipped {}", n, skipped);
                 }
+                let mut all_servers = vec![];
+                let proxies = scheme
+                    .proxies
+                    .into_iter()
+                    .filter_map(|x| {
+                        OutboundProxyProtocol::try_from(subscription::to_map(x)).ok()
+                    })
+                    .map(|mut x| {
+                        x.inherit_tls_fragment(tls_fragment.as_ref());
+                        x
+                    })
+                    .inspect(|x| {
+                        let servers = x.servers();
+                        if let Some(server) = servers.first() {
+                            proxy_manager.set_proxy_server(x.name(), server);
+                        }
+                        all_servers.extend(servers);
+                        if let Some(url) = x.health_check_url() {
+                            proxy_manager.set_health_check_url(x.name(), url);
+                        }
+                    })
+                    .map(|x| {
+                        let dialer_opts = x.dialer_opts();
+                        let handler: Result<AnyOutboundHandler, Error> = match x {
+                            OutboundProxyProtocol::Direct => {
+                                Ok(Arc::new(direct::Handler::new()) as _)
+                            }
+                            OutboundProxyProtocol::Reject => {
+                                Ok(Arc::new(reject::Handler::new()) as _)
+                            }
+                            OutboundProxyProtocol::BlockPage(b) => {
+                                let h: block_page::Handler = b.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            #[cfg(feature = "shadowsocks")]
+                            OutboundProxyProtocol::Ss(s) => {
+                                let h: shadowsocks::Handler = s.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            OutboundProxyProtocol::Socks5(s) => {
+                                let h: socks::Handler = s.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            OutboundProxyProtocol::Trojan(tr) => {
+                                let h: trojan::Handler = tr.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            OutboundProxyProtocol::Vmess(vm) => {
+                                let h: vmess::Handler = vm.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            OutboundProxyProtocol::Hysteria2(h) => h.try_into(),
+                            #[cfg(feature = "ssh")]
+                            OutboundProxyProtocol::Ssh(s) => {
+                                let h: ssh::Handler = s.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            OutboundProxyProtocol::Wireguard(wg) => {
+                                let h: wg::Handler = wg.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            #[cfg(feature = "onion")]
+                            OutboundProxyProtocol::Tor(tor) => {
+                                let h: tor::Handler = tor.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                            #[cfg(feature = "tuic")]
+                            OutboundProxyProtocol::Tuic(tuic) => {
+                                let h: tuic::Handler = tuic.try_into()?;
+                                Ok(Arc::new(h) as _)
+                            }
+                        };
+                        handler.map(|h| dialer::Handler::wrap(h, dialer_opts))
+                    })
+                    .collect::<Result<Vec<_>, crate::Error>>()?;
+                proxy_manager.server_addrs().set(Some(&n), all_servers);
+                Ok(proxies)
             },
         );
 
@@ -231,6 +255,10 @@ impl Provider for ProxySetProvider {
             "updatedAt".to_owned(),
             Box::new(self.fetcher.updated_at().await),
         );
+        m.insert(
+            "healthCheck".to_owned(),
+            Box::new(self.inner.read().await.hc.settings()),
+        );
 
         m
     }
@@ -274,6 +302,7 @@ mod tests {
     #[tokio::test]
     async fn test_proxy_set_provider() {
         let mut mock_vehicle = MockProviderVehicle::new();
+        mock_vehicle.expect_commit().return_const(());
 
         mock_vehicle.expect_read().returning(|| {
             Ok(r#"
@@ -315,6 +344,7 @@ proxies:
             Duration::from_secs(1),
             vehicle,
             hc,
+            None,
         )
         .unwrap();
 
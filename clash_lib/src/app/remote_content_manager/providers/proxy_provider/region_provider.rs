@@ -0,0 +1,385 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize;
+use regex::Regex;
+use tracing::debug;
+
+use crate::{
+    Error,
+    app::remote_content_manager::{
+        ProxyManager,
+        providers::{Provider, ProviderType, ProviderVehicleType},
+    },
+    common::mmdb::Mmdb,
+    proxy::AnyOutboundHandler,
+};
+
+use super::{ProxyProvider, ThreadSafeProxyProvider};
+
+/// the places in the names of the proxies of each region, lowercase
+const KEYWORDS: &[(&str, &[&str])] = &[
+    ("HK", &["hong kong", "hongkong", "香港"]),
+    ("TW", &["taiwan", "taipei", "台湾", "臺灣", "台北"]),
+    ("JP", &["japan", "tokyo", "osaka", "日本", "东京", "大阪"]),
+    ("SG", &["singapore", "新加坡", "狮城"]),
+    (
+        "US",
+        &[
+            "united states",
+            "america",
+            "los angeles",
+            "san jose",
+            "seattle",
+            "new york",
+            "美国",
+            "美國",
+        ],
+    ),
+    ("KR", &["korea", "seoul", "韩国", "韓國", "首尔"]),
+    ("GB", &["united kingdom", "london", "英国"]),
+    ("DE", &["germany", "frankfurt", "德国"]),
+    ("FR", &["france", "paris", "法国"]),
+    ("NL", &["netherlands", "amsterdam", "荷兰"]),
+    ("CA", &["canada", "toronto", "加拿大"]),
+    ("AU", &["australia", "sydney", "澳大利亚", "澳洲"]),
+    ("RU", &["russia", "moscow", "俄罗斯"]),
+    ("IN", &["india", "mumbai", "印度"]),
+];
+/// codes seen in the names besides the ISO ones
+const ALIASES: &[(&str, &str)] = &[("UK", "GB"), ("USA", "US")];
+
+/// Tells the region of a proxy: by the `patterns`, a flag, a place or a
+/// code in its name, or else by the country of its server
+pub struct RegionClassifier {
+    patterns: Vec<(String, Regex)>,
+    /// the codes taken as regions where they stand alone in a name
+    codes: HashSet<String>,
+    mmdb: Option<Arc<Mmdb>>,
+    proxy_manager: ProxyManager,
+    /// by name, the regions told by the names, which don't change; pruned
+    /// to the proxies of the provider as it's refreshed
+    known: Mutex<HashMap<String, String>>,
+}
+
+impl RegionClassifier {
+    pub fn new(
+        regions: &[String],
+        patterns: &HashMap<String, String>,
+        mmdb: Option<Arc<Mmdb>>,
+        proxy_manager: ProxyManager,
+    ) -> Result<Self, Error> {
+        let patterns = patterns
+            .iter()
+            .map(|(region, pattern)| {
+                Regex::new(pattern)
+                    .map(|x| (region.to_uppercase(), x))
+                    .map_err(|e| {
+                        Error::InvalidConfig(format!(
+                            "invalid pattern of region {}: {}",
+                            region, e
+                        ))
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        let codes = KEYWORDS
+            .iter()
+            .map(|(code, _)| code.to_string())
+            .chain(regions.iter().cloned())
+            .collect();
+        Ok(Self {
+            patterns,
+            codes,
+            mmdb,
+            proxy_manager,
+            known: Default::default(),
+        })
+    }
+
+    pub fn region(&self, name: &str) -> Option<String> {
+        if let Some(region) = self.known.lock().unwrap().get(name) {
+            return Some(region.clone());
+        }
+        if let Some(region) = self.by_name(name) {
+            self.known
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), region.clone());
+            return Some(region);
+        }
+        // not kept, the server may not be resolved yet
+        self.by_server(name)
+    }
+
+    /// forgets the proxies gone from the provider, once there are more
+    /// known than it has
+    fn prune(&self, proxies: &[AnyOutboundHandler]) {
+        let mut known = self.known.lock().unwrap();
+        if known.len() > proxies.len() {
+            let names: HashSet<_> = proxies.iter().map(|x| x.name()).collect();
+            known.retain(|name, _| names.contains(name.as_str()));
+        }
+    }
+
+    fn by_name(&self, name: &str) -> Option<String> {
+        if let Some((region, _)) =
+            self.patterns.iter().find(|(_, x)| x.is_match(name))
+        {
+            return Some(region.clone());
+        }
+        if let Some(region) = flag(name) {
+            return Some(region);
+        }
+        let lower = name.to_lowercase();
+        if let Some((region, _)) = KEYWORDS
+            .iter()
+            .find(|(_, places)| places.iter().any(|x| lower.contains(x)))
+        {
+            return Some(region.to_string());
+        }
+        name.split(|c: char| !c.is_ascii_alphanumeric())
+            .map(|x| x.trim_end_matches(|c: char| c.is_ascii_digit()))
+            .map(|x| {
+                ALIASES
+                    .iter()
+                    .find(|(alias, _)| *alias == x)
+                    .map_or(x, |(_, code)| *code)
+            })
+            .find(|x| self.codes.contains(*x))
+            .map(str::to_owned)
+    }
+
+    fn by_server(&self, name: &str) -> Option<String> {
+        let server = self.proxy_manager.proxy_server(name)?;
+        let ip = self.proxy_manager.server_addrs().ip(&server)?;
+        let country = self.mmdb.as_ref()?.lookup_country(ip).ok()?;
+        country.country?.iso_code.map(str::to_owned)
+    }
+}
+
+/// the code of the first flag emoji in `name`, e.g. `HK` of 🇭🇰
+fn flag(name: &str) -> Option<String> {
+    let letter = |c: char| {
+        (0x1f1e6..=0x1f1ff)
+            .contains(&(c as u32))
+            .then(|| char::from(b'A' + (c as u32 - 0x1f1e6) as u8))
+    };
+    let chars: Vec<_> = name.chars().collect();
+    chars
+        .windows(2)
+        .find_map(|x| Some(format!("{}{}", letter(x[0])?, letter(x[1])?)))
+}
+
+/// The proxies of a provider in a region, none if the provider has none
+/// there
+pub struct RegionProvider {
+    name: String,
+    region: String,
+    parent: ThreadSafeProxyProvider,
+    classifier: Arc<RegionClassifier>,
+}
+
+impl RegionProvider {
+    pub fn new(
+        name: String,
+        region: String,
+        parent: ThreadSafeProxyProvider,
+        classifier: Arc<RegionClassifier>,
+    ) -> Self {
+        Self {
+            name,
+            region,
+            parent,
+            classifier,
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for RegionProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn vehicle_type(&self) -> ProviderVehicleType {
+        ProviderVehicleType::Compatible
+    }
+
+    fn typ(&self) -> ProviderType {
+        ProviderType::Proxy
+    }
+
+    async fn initialize(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// updates the provider the proxies are from
+    async fn update(&self) -> std::io::Result<()> {
+        self.parent.read().await.update().await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m: HashMap<String, Box<dyn Serialize + Send>> = HashMap::new();
+
+        m.insert("name".to_owned(), Box::new(self.name().to_string()));
+        m.insert("type".to_owned(), Box::new(self.typ().to_string()));
+        m.insert(
+            "vehicleType".to_owned(),
+            Box::new(self.vehicle_type().to_string()),
+        );
+        m.insert("region".to_owned(), Box::new(self.region.clone()));
+        m.insert(
+            "provider".to_owned(),
+            Box::new(self.parent.read().await.name().to_owned()),
+        );
+
+        m
+    }
+}
+
+#[async_trait]
+impl ProxyProvider for RegionProvider {
+    async fn proxies(&self) -> Vec<AnyOutboundHandler> {
+        let all = self.parent.read().await.proxies().await;
+        self.classifier.prune(&all);
+        let proxies: Vec<_> = all
+            .iter()
+            .filter(|x| {
+                self.classifier.region(x.name()).as_deref()
+                    == Some(self.region.as_str())
+            })
+            .cloned()
+            .collect();
+        if proxies.is_empty() {
+            debug!("no proxy of {} is in {}", self.name, self.region);
+        }
+        proxies
+    }
+
+    async fn touch(&self) {
+        self.parent.read().await.touch().await;
+    }
+
+    async fn healthcheck(&self) {
+        self.parent.read().await.healthcheck().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::sync::RwLock;
+
+    use super::{ProxyProvider, RegionClassifier, RegionProvider};
+    use crate::{
+        app::{dns::MockClashResolver, remote_content_manager::ProxyManager},
+        proxy::{
+            mocks::MockDummyProxyProvider,
+            utils::test_utils::noop::NoopOutboundHandler,
+        },
+    };
+
+    fn classifier() -> RegionClassifier {
+        RegionClassifier::new(
+            &["TR".to_owned()],
+            &HashMap::from([("JP".to_owned(), "(?i)nrt".to_owned())]),
+            None,
+            ProxyManager::new(Arc::new(MockClashResolver::new())),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_region_by_name() {
+        let c = classifier();
+        assert_eq!(c.region("🇭🇰 Hong Kong 01").as_deref(), Some("HK"));
+        assert_eq!(c.region("香港 IPLC").as_deref(), Some("HK"));
+        assert_eq!(c.region("Taipei BGP").as_deref(), Some("TW"));
+        assert_eq!(c.region("SG-02").as_deref(), Some("SG"));
+        assert_eq!(c.region("UK London").as_deref(), Some("GB"));
+        assert_eq!(c.region("UK01").as_deref(), Some("GB"));
+        assert_eq!(c.region("relay nrt 3").as_deref(), Some("JP"));
+        assert_eq!(c.region("TR Istanbul").as_deref(), Some("TR"));
+        assert_eq!(c.region("V2 IPLC 01"), None);
+    }
+
+    #[tokio::test]
+    async fn test_region_provider() {
+        let mut parent = MockDummyProxyProvider::new();
+        parent.expect_proxies().returning(|| {
+            ["HK 01", "JP 01", "HK 02"]
+                .map(|name| {
+                    Arc::new(NoopOutboundHandler {
+                        name: name.to_owned(),
+                    }) as _
+                })
+                .to_vec()
+        });
+        let parent = Arc::new(RwLock::new(parent));
+        let classifier = Arc::new(classifier());
+
+        let hk = RegionProvider::new(
+            "p-HK".to_owned(),
+            "HK".to_owned(),
+            parent.clone(),
+            classifier.clone(),
+        );
+        let names: Vec<_> = hk
+            .proxies()
+            .await
+            .iter()
+            .map(|x| x.name().to_owned())
+            .collect();
+        assert_eq!(names, ["HK 01", "HK 02"]);
+
+        let us = RegionProvider::new(
+            "p-US".to_owned(),
+            "US".to_owned(),
+            parent,
+            classifier,
+        );
+        assert!(
+            us.proxies().await.is_empty(),
+            "a region with no proxy should not take the others"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_region_known_pruned() {
+        let names = Arc::new(std::sync::Mutex::new(vec!["HK 01", "JP 01"]));
+        let mut parent = MockDummyProxyProvider::new();
+        let current = names.clone();
+        parent.expect_proxies().returning(move || {
+            current
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|name| {
+                    Arc::new(NoopOutboundHandler {
+                        name: name.to_string(),
+                    }) as _
+                })
+                .collect()
+        });
+        let classifier = Arc::new(classifier());
+        let hk = RegionProvider::new(
+            "p-HK".to_owned(),
+            "HK".to_owned(),
+            Arc::new(RwLock::new(parent)),
+            classifier.clone(),
+        );
+
+        assert_eq!(hk.proxies().await.len(), 1);
+        assert_eq!(classifier.known.lock().unwrap().len(), 2);
+
+        *names.lock().unwrap() = vec!["HK 02"];
+        assert_eq!(hk.proxies().await.len(), 1);
+        let known = classifier.known.lock().unwrap();
+        assert_eq!(known.len(), 1);
+        assert!(known.contains_key("HK 02"));
+    }
+}
@@ -17,21 +17,24 @@ impl CidrTrie {
 
     pub fn insert(&mut self, cidr: &str) -> bool {
         if let Ok(cidr) = cidr.parse::<ipnet::IpNet>() {
-            match cidr {
-                ipnet::IpNet::V4(v4) => {
-                    self.v4.insert(v4.addr(), v4.prefix_len() as _, true);
-                    true
-                }
-                ipnet::IpNet::V6(v6) => {
-                    self.v6.insert(v6.addr(), v6.prefix_len() as _, true);
-                    true
-                }
-            }
+            self.insert_net(cidr);
+            true
         } else {
             false
         }
     }
 
+    pub fn insert_net(&mut self, cidr: ipnet::IpNet) {
+        match cidr {
+            ipnet::IpNet::V4(v4) => {
+                self.v4.insert(v4.addr(), v4.prefix_len() as _, true);
+            }
+            ipnet::IpNet::V6(v6) => {
+                self.v6.insert(v6.addr(), v6.prefix_len() as _, true);
+            }
+        }
+    }
+
     pub fn contains(&self, ip: IpAddr) -> bool {
         match ip {
             IpAddr::V4(v4) => self.v4.longest_match(v4).is_some(),
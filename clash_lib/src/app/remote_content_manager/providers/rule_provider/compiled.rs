@@ -0,0 +1,165 @@
+//! Rule sets compiled by the `compile-ruleset` command, loaded without
+//! parsing: the succinct set of the `domain` behavior as it's matched, and
+//! the networks of the `ipcidr` one in binary. They're told apart from the
+//! YAML ones by their magic bytes, so any provider can point to one.
+//!
+//! The layout is the magic bytes, the behavior, the number of rules as a
+//! little-endian u64, then the set.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::common::succinct_set::DomainSet;
+
+use super::{
+    RuleSetBehavior,
+    cidr_trie::CidrTrie,
+    provider::{RuleContent, make_domain_rules},
+};
+
+const MAGIC: &[u8; 4] = b"CRS\x01";
+const DOMAIN: u8 = 0;
+const IPCIDR: u8 = 1;
+
+pub(super) fn is_compiled(input: &[u8]) -> bool {
+    input.starts_with(MAGIC)
+}
+
+#[derive(Deserialize)]
+struct Scheme {
+    payload: Vec<String>,
+}
+
+/// Compiles the rule set `input`, a rule provider in YAML or a list of a
+/// rule per line, for the `domain` and `ipcidr` behaviors
+pub fn compile(behavior: RuleSetBehavior, input: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let payload = match serde_yaml::from_slice::<Scheme>(input) {
+        Ok(scheme) => scheme.payload,
+        Err(_) => String::from_utf8_lossy(input)
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(str::to_owned)
+            .collect(),
+    };
+    ensure!(!payload.is_empty(), "no rules to compile");
+
+    let mut out = MAGIC.to_vec();
+    match behavior {
+        RuleSetBehavior::Domain => {
+            out.push(DOMAIN);
+            out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            let set: DomainSet = make_domain_rules(payload)?.into();
+            set.write_bin(&mut out);
+        }
+        RuleSetBehavior::Ipcidr => {
+            out.push(IPCIDR);
+            out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            for rule in payload {
+                let net: IpNet = rule
+                    .parse()
+                    .map_err(|_| anyhow!("invalid ip cidr: {}", rule))?;
+                match net.addr() {
+                    IpAddr::V4(ip) => {
+                        out.push(4);
+                        out.extend_from_slice(&ip.octets());
+                    }
+                    IpAddr::V6(ip) => {
+                        out.push(6);
+                        out.extend_from_slice(&ip.octets());
+                    }
+                }
+                out.push(net.prefix_len());
+            }
+        }
+        behavior => bail!("{} rule sets can't be compiled", behavior),
+    }
+    Ok(out)
+}
+
+pub(super) fn load(
+    behavior: RuleSetBehavior,
+    input: &[u8],
+) -> anyhow::Result<RuleContent> {
+    let mut buf = &input[MAGIC.len()..];
+    let (&typ, rest) = buf.split_first().ok_or_else(|| anyhow!("truncated"))?;
+    ensure!(rest.len() >= 8, "truncated");
+    let (count, rest) = rest.split_at(8);
+    let count = u64::from_le_bytes(count.try_into().unwrap());
+    buf = rest;
+
+    match (behavior, typ) {
+        (RuleSetBehavior::Domain, DOMAIN) => {
+            let set = DomainSet::read_bin(&mut buf)?;
+            Ok(RuleContent::Domain(set))
+        }
+        (RuleSetBehavior::Ipcidr, IPCIDR) => {
+            let mut trie = CidrTrie::new();
+            for _ in 0..count {
+                let net = match buf {
+                    [4, a, b, c, d, prefix, rest @ ..] => {
+                        buf = rest;
+                        IpNet::new(Ipv4Addr::new(*a, *b, *c, *d).into(), *prefix)
+                    }
+                    [6, rest @ ..] if rest.len() >= 17 => {
+                        let ip: [u8; 16] = rest[..16].try_into().unwrap();
+                        let prefix = rest[16];
+                        buf = &rest[17..];
+                        IpNet::new(Ipv6Addr::from(ip).into(), prefix)
+                    }
+                    _ => bail!("truncated"),
+                }?;
+                trie.insert_net(net);
+            }
+            Ok(RuleContent::Ipcidr(Box::new(trie)))
+        }
+        _ => bail!("compiled for another behavior than {}", behavior),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, is_compiled, load};
+    use crate::app::remote_content_manager::providers::rule_provider::{
+        RuleSetBehavior, provider::RuleContent,
+    };
+
+    #[test]
+    fn test_compiled_domain() {
+        let yaml = b"payload:\n  - '+.google.com'\n  - 'example.com'\n";
+        let compiled = compile(RuleSetBehavior::Domain, yaml).unwrap();
+        assert!(is_compiled(&compiled));
+
+        let Ok(RuleContent::Domain(set)) = load(RuleSetBehavior::Domain, &compiled)
+        else {
+            panic!("not a domain set");
+        };
+        assert!(set.has("www.google.com"));
+        assert!(set.has("example.com"));
+        assert!(!set.has("www.example.com"));
+
+        assert!(load(RuleSetBehavior::Ipcidr, &compiled).is_err());
+        assert!(
+            load(RuleSetBehavior::Domain, &compiled[..compiled.len() - 3]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_compiled_ipcidr() {
+        let text = b"# private\n10.0.0.0/8\n\nfd00::/8\n";
+        let compiled = compile(RuleSetBehavior::Ipcidr, text).unwrap();
+
+        let Ok(RuleContent::Ipcidr(trie)) = load(RuleSetBehavior::Ipcidr, &compiled)
+        else {
+            panic!("not an ip cidr set");
+        };
+        assert!(trie.contains("10.1.2.3".parse().unwrap()));
+        assert!(trie.contains("fd00::1".parse().unwrap()));
+        assert!(!trie.contains("192.168.1.1".parse().unwrap()));
+
+        assert!(compile(RuleSetBehavior::Ipcidr, b"10.0.0.0/33").is_err());
+        assert!(compile(RuleSetBehavior::Classical, b"DOMAIN,a.com").is_err());
+    }
+}
@@ -1,4 +1,6 @@
 mod cidr_trie;
+mod compiled;
 mod provider;
 
+pub use compiled::compile;
 pub use provider::{RuleProviderImpl, RuleSetBehavior, ThreadSafeRuleProvider};
@@ -6,6 +6,7 @@ use std::{
     time::Duration,
 };
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use erased_serde::Serialize as ESerialize;
 use futures::future::BoxFuture;
@@ -15,6 +16,7 @@ use tracing::{debug, trace};
 use crate::{
     Error,
     app::{
+        dns::hosts::{self, Hosts, HostsTarget},
         remote_content_manager::providers::{
             Provider, ProviderType, ProviderVehicleType, ThreadSafeProviderVehicle,
             fetcher::Fetcher,
@@ -28,7 +30,7 @@ use crate::{
     session::Session,
 };
 
-use super::cidr_trie::CidrTrie;
+use super::{cidr_trie::CidrTrie, compiled};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ProviderScheme {
@@ -41,6 +43,9 @@ pub enum RuleSetBehavior {
     Domain,
     Ipcidr,
     Classical,
+    /// `domain,target` lines for the `rule-set:` entries of `hosts`, the
+    /// target being an IP or another domain
+    Hosts,
 }
 
 impl Display for RuleSetBehavior {
@@ -49,15 +54,17 @@ impl Display for RuleSetBehavior {
             RuleSetBehavior::Domain => write!(f, "Domain"),
             RuleSetBehavior::Ipcidr => write!(f, "IPCIDR"),
             RuleSetBehavior::Classical => write!(f, "Classical"),
+            RuleSetBehavior::Hosts => write!(f, "Hosts"),
         }
     }
 }
 
-enum RuleContent {
+pub(super) enum RuleContent {
     // the left will converted into a right
     Domain(succinct_set::DomainSet),
     Ipcidr(Box<CidrTrie>),
     Classical(Vec<Box<dyn RuleMatcher>>),
+    Hosts(Arc<Hosts>),
 }
 
 struct Inner {
@@ -67,6 +74,12 @@ struct Inner {
 pub trait RuleProvider: Provider {
     fn search(&self, sess: &Session) -> bool;
     fn behavior(&self) -> RuleSetBehavior;
+    /// what `host` maps to in the payload, for the `hosts` behavior
+    fn search_hosts(&self, host: &str) -> Option<HostsTarget>;
+    /// the size of the domain tries of the payload, if it has any
+    fn trie_stats(&self) -> Option<trie::TrieStats> {
+        None
+    }
 }
 
 pub type ThreadSafeRuleProvider = Arc<dyn RuleProvider + Send + Sync>;
@@ -79,6 +92,9 @@ type RuleParser =
 pub struct RuleProviderImpl {
     fetcher: Fetcher<RuleUpdater, RuleParser>,
     inner: std::sync::Arc<tokio::sync::RwLock<Inner>>,
+    /// the payload of the `hosts` behavior, also in `inner`, looked up by the
+    /// resolver without waiting for a refresh
+    hosts: Arc<ArcSwap<Hosts>>,
     behavior: RuleSetBehavior,
 }
 
@@ -89,6 +105,7 @@ impl RuleProviderImpl {
         interval: Duration,
         vehicle: ThreadSafeProviderVehicle,
         mmdb: Arc<Mmdb>,
+        asn_mmdb: Option<Arc<Mmdb>>,
         geodata: Arc<GeoData>,
     ) -> Self {
         let inner = Arc::new(tokio::sync::RwLock::new(Inner {
@@ -100,16 +117,22 @@ impl RuleProviderImpl {
                     RuleContent::Ipcidr(Box::new(CidrTrie::new()))
                 }
                 RuleSetBehavior::Classical => RuleContent::Classical(vec![]),
+                RuleSetBehavior::Hosts => RuleContent::Hosts(Default::default()),
             },
         }));
 
         let inner_clone = inner.clone();
+        let hosts: Arc<ArcSwap<Hosts>> = Default::default();
+        let hosts_clone = hosts.clone();
 
         let n = name.clone();
         let updater: RuleUpdater =
             Box::new(move |input: RuleContent| -> BoxFuture<'static, ()> {
                 let n = n.clone();
                 let inner: Arc<tokio::sync::RwLock<Inner>> = inner_clone.clone();
+                if let RuleContent::Hosts(content) = &input {
+                    hosts_clone.store(content.clone());
+                }
                 Box::pin(async move {
                     let mut inner = inner.write().await;
                     trace!("updated rules for: {}", n);
@@ -120,6 +143,11 @@ impl RuleProviderImpl {
         let n = name.clone();
         let parser: RuleParser =
             Box::new(move |input: &[u8]| -> anyhow::Result<RuleContent> {
+                if compiled::is_compiled(input) {
+                    return compiled::load(behovior, input).map_err(|x| {
+                        anyhow!("rule provider parse error {}: {}", n, x)
+                    });
+                }
                 let scheme: ProviderScheme =
                     serde_yaml::from_slice(input).map_err(|x| {
                         Error::InvalidConfig(format!(
@@ -131,6 +159,7 @@ impl RuleProviderImpl {
                     behovior,
                     scheme.payload,
                     mmdb.clone(),
+                    asn_mmdb.clone(),
                     geodata.clone(),
                 )?;
                 Ok(rules)
@@ -141,6 +170,7 @@ impl RuleProviderImpl {
         Self {
             fetcher,
             inner,
+            hosts,
             behavior: behovior,
         }
     }
@@ -167,6 +197,9 @@ impl RuleProvider for RuleProviderImpl {
                     }
                     false
                 }
+                RuleContent::Hosts(hosts) => {
+                    hosts.search(&sess.destination.host()).is_some()
+                }
             },
             Err(_) => {
                 debug!("rule provider {} is busy", self.name());
@@ -178,6 +211,26 @@ impl RuleProvider for RuleProviderImpl {
     fn behavior(&self) -> RuleSetBehavior {
         self.behavior
     }
+
+    fn trie_stats(&self) -> Option<trie::TrieStats> {
+        let inner = self.inner.try_read().ok()?;
+        match &inner.content {
+            RuleContent::Hosts(hosts) => Some(hosts.stats()),
+            RuleContent::Classical(rules) => rules
+                .iter()
+                .filter_map(|x| x.trie_stats())
+                .reduce(|a, b| trie::TrieStats {
+                    nodes: a.nodes + b.nodes,
+                    bytes: a.bytes + b.bytes,
+                }),
+            RuleContent::Domain(_) | RuleContent::Ipcidr(_) => None,
+        }
+    }
+
+    fn search_hosts(&self, host: &str) -> Option<HostsTarget> {
+        // rule sets can't be nested
+        hosts::search(&self.hosts.load(), host, &HashMap::new())
+    }
 }
 
 #[async_trait]
@@ -240,6 +293,7 @@ fn make_rules(
     behavior: RuleSetBehavior,
     rules: Vec<String>,
     mmdb: Arc<Mmdb>,
+    asn_mmdb: Option<Arc<Mmdb>>,
     geodata: Arc<GeoData>,
 ) -> Result<RuleContent, Error> {
     match behavior {
@@ -251,19 +305,48 @@ fn make_rules(
             Ok(RuleContent::Ipcidr(Box::new(make_ip_cidr_rules(rules)?)))
         }
         RuleSetBehavior::Classical => Ok(RuleContent::Classical(
-            make_classical_rules(rules, mmdb, geodata)?,
+            make_classical_rules(rules, mmdb, asn_mmdb, geodata)?,
         )),
+        RuleSetBehavior::Hosts => {
+            Ok(RuleContent::Hosts(Arc::new(make_hosts_rules(rules)?)))
+        }
     }
 }
 
-fn make_domain_rules(rules: Vec<String>) -> Result<trie::StringTrie<bool>, Error> {
+pub(super) fn make_domain_rules(
+    rules: Vec<String>,
+) -> Result<trie::StringTrie<bool>, Error> {
     let mut trie = trie::StringTrie::new();
     for rule in rules {
         trie.insert(&rule, Arc::new(true));
     }
+    trie.shrink();
     Ok(trie)
 }
 
+fn make_hosts_rules(rules: Vec<String>) -> Result<Hosts, Error> {
+    let mut hosts = Hosts::new();
+    for rule in rules {
+        let (host, target) = rule
+            .split_once(',')
+            .map(|(host, target)| (host.trim(), target.trim()))
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!("invalid hosts line: {}", rule))
+            })?;
+        let target = match target.parse() {
+            Ok(HostsTarget::RuleSet(_)) => Err(format!(
+                "invalid hosts line: {}: rule sets can't be nested",
+                rule
+            )),
+            target => target,
+        }
+        .map_err(Error::InvalidConfig)?;
+        hosts.insert(host, Arc::new(target));
+    }
+    hosts.shrink();
+    Ok(hosts)
+}
+
 fn make_ip_cidr_rules(rules: Vec<String>) -> Result<CidrTrie, Error> {
     let mut trie = CidrTrie::new();
     for rule in rules {
@@ -275,6 +358,7 @@ fn make_ip_cidr_rules(rules: Vec<String>) -> Result<CidrTrie, Error> {
 fn make_classical_rules(
     rules: Vec<String>,
     mmdb: Arc<Mmdb>,
+    asn_mmdb: Option<Arc<Mmdb>>,
     geodata: Arc<GeoData>,
 ) -> Result<Vec<Box<dyn RuleMatcher>>, Error> {
     let mut rv = vec![];
@@ -292,9 +376,45 @@ fn make_classical_rules(
             _ => Err(Error::InvalidConfig(format!("invalid rule line: {}", rule))),
         }?;
 
-        let rule_matcher =
-            map_rule_type(rule_type, mmdb.clone(), geodata.clone(), None);
+        let rule_matcher = map_rule_type(
+            rule_type,
+            mmdb.clone(),
+            asn_mmdb.clone(),
+            geodata.clone(),
+            None,
+        );
         rv.push(rule_matcher);
     }
     Ok(rv)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::make_hosts_rules;
+    use crate::app::dns::hosts::{self, HostsTarget};
+
+    #[test]
+    fn test_make_hosts_rules() {
+        let hosts = make_hosts_rules(vec![
+            "dev.corp.example.com, 10.0.0.8".to_owned(),
+            "*.build.corp.example.com,dev.corp.example.com".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(
+            hosts::search(&hosts, "ci.build.corp.example.com", &HashMap::new()),
+            Some(HostsTarget::Ip("10.0.0.8".parse().unwrap()))
+        );
+        assert_eq!(
+            hosts::search(&hosts, "corp.example.com", &HashMap::new()),
+            None
+        );
+
+        assert!(make_hosts_rules(vec!["dev.corp.example.com".to_owned()]).is_err());
+        assert!(
+            make_hosts_rules(vec!["dev.corp.example.com,rule-set:x".to_owned()])
+                .is_err()
+        );
+    }
+}
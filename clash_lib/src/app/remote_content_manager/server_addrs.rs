@@ -0,0 +1,229 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use serde::Serialize;
+use tracing::{debug, info, warn};
+
+use crate::app::dns::{ThreadSafeDNSResolver, resolver::CACHE_TTL};
+
+/// half the time the DNS cache keeps an answer, so it's rarely cold
+const REFRESH_INTERVAL: Duration = Duration::from_secs(CACHE_TTL.as_secs() / 2);
+/// the resolutions in flight at a time, for providers of many servers
+const MAX_CONCURRENT_RESOLVES: usize = 16;
+
+/// The last resolution of a proxy server
+#[derive(Clone, Serialize, Debug, Default, PartialEq)]
+pub struct ServerAddr {
+    pub ip: Option<IpAddr>,
+    pub error: Option<String>,
+    pub time: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct Hosts {
+    addrs: HashMap<String, ServerAddr>,
+    /// the hosts of the proxies of the config, `None`, and of each provider
+    owners: HashMap<Option<String>, HashSet<String>>,
+}
+
+/// Keeps the hostnames of the proxy servers resolved in the background, so
+/// the first connection through a proxy doesn't wait for DNS, and a server
+/// that fails to resolve is logged before it's used
+pub struct ServerAddrs {
+    resolver: ThreadSafeDNSResolver,
+    hosts: std::sync::RwLock<Hosts>,
+}
+
+impl ServerAddrs {
+    pub fn new(resolver: ThreadSafeDNSResolver) -> Arc<Self> {
+        Arc::new(Self {
+            resolver,
+            hosts: Default::default(),
+        })
+    }
+
+    /// Sets the hosts of the proxies of `owner`, a provider or the config if
+    /// `None`, and starts resolving the ones not seen before. IPs are
+    /// skipped, and the hosts no one has anymore are dropped
+    pub fn set(
+        self: &Arc<Self>,
+        owner: Option<&str>,
+        hosts: impl IntoIterator<Item = String>,
+    ) {
+        let hosts: HashSet<_> = hosts
+            .into_iter()
+            .filter(|x| x.parse::<IpAddr>().is_err())
+            .collect();
+        let added: Vec<_> = {
+            let mut known = self.hosts.write().unwrap();
+            let added = hosts
+                .iter()
+                .filter(|x| !known.addrs.contains_key(*x))
+                .cloned()
+                .collect();
+            known.owners.insert(owner.map(str::to_owned), hosts);
+            let kept: HashSet<_> =
+                known.owners.values().flatten().cloned().collect();
+            known.addrs.retain(|host, _| kept.contains(host));
+            for host in &added {
+                known.addrs.insert(host.clone(), Default::default());
+            }
+            added
+        };
+        if added.is_empty() {
+            return;
+        }
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            let this = self.clone();
+            rt.spawn(async move { this.resolve_all(added).await });
+        }
+    }
+
+    /// Re-resolves all the hosts before the DNS cache lets them go, until
+    /// `self` is dropped
+    pub fn keep_refreshed(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(REFRESH_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(this) = Weak::upgrade(&this) else {
+                    break;
+                };
+                let hosts: Vec<_> =
+                    this.hosts.read().unwrap().addrs.keys().cloned().collect();
+                this.resolve_all(hosts).await;
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, ServerAddr> {
+        self.hosts.read().unwrap().addrs.clone()
+    }
+
+    /// the address `host` last resolved to, `host` itself if it's an IP
+    pub fn ip(&self, host: &str) -> Option<IpAddr> {
+        if let Ok(ip) = host.parse() {
+            return Some(ip);
+        }
+        self.hosts.read().unwrap().addrs.get(host)?.ip
+    }
+
+    async fn resolve_all(&self, hosts: Vec<String>) {
+        futures::stream::iter(hosts)
+            .for_each_concurrent(MAX_CONCURRENT_RESOLVES, |host| async move {
+                self.resolve(&host).await
+            })
+            .await;
+    }
+
+    async fn resolve(&self, host: &str) {
+        let (ip, error) = match self.resolver.resolve(host, false).await {
+            Ok(Some(ip)) => (Some(ip), None),
+            Ok(None) => (None, Some("no record".to_owned())),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let prev = {
+            let mut hosts = self.hosts.write().unwrap();
+            // dropped while it was resolved
+            let Some(addr) = hosts.addrs.get_mut(host) else {
+                return;
+            };
+            std::mem::replace(
+                addr,
+                ServerAddr {
+                    ip,
+                    error: error.clone(),
+                    time: Some(Utc::now()),
+                },
+            )
+        };
+        match (ip, error) {
+            (_, Some(e)) if prev.error.is_none() => {
+                warn!("failed to resolve proxy server {}: {}", host, e)
+            }
+            (Some(ip), None) if prev.error.is_some() => {
+                info!("proxy server {} resolved to {} again", host, ip)
+            }
+            (Some(ip), None) if prev.ip.is_some_and(|x| x != ip) => {
+                debug!("proxy server {} moved to {}", host, ip)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use crate::app::dns::MockClashResolver;
+
+    use super::{REFRESH_INTERVAL, ServerAddrs};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_addrs() {
+        let resolves = Arc::new(AtomicUsize::new(0));
+        let counted = resolves.clone();
+        let mut resolver = MockClashResolver::new();
+        resolver.expect_resolve().returning(move |host, _| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            match host {
+                "proxy.example.com" => Ok(Some("10.0.0.1".parse().unwrap())),
+                "sub.example.com" => Ok(Some("10.0.0.3".parse().unwrap())),
+                _ => Err(anyhow::anyhow!("no such host")),
+            }
+        });
+        let addrs = ServerAddrs::new(Arc::new(resolver));
+        addrs.keep_refreshed();
+
+        addrs.set(
+            None,
+            [
+                "proxy.example.com".to_owned(),
+                "gone.example.com".to_owned(),
+                "10.0.0.2".to_owned(),
+            ],
+        );
+        addrs.set(
+            Some("sub"),
+            ["sub.example.com".to_owned(), "proxy.example.com".to_owned()],
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let snapshot = addrs.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(
+            snapshot["proxy.example.com"].ip,
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert!(snapshot["gone.example.com"].ip.is_none());
+        assert!(snapshot["gone.example.com"].error.is_some());
+        assert_eq!(resolves.load(Ordering::Relaxed), 3);
+
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+        assert_eq!(resolves.load(Ordering::Relaxed), 6, "not refreshed");
+
+        // the provider dropped a proxy, one the config still has is kept
+        addrs.set(Some("sub"), ["proxy.example.com".to_owned()]);
+        let snapshot = addrs.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert!(!snapshot.contains_key("sub.example.com"));
+        addrs.set(Some("sub"), []);
+        assert!(addrs.ip("proxy.example.com").is_some());
+    }
+}
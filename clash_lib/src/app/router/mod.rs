@@ -9,35 +9,86 @@ use crate::{
 
 use crate::{
     common::mmdb::Mmdb,
-    config::internal::{config::RuleProviderDef, rule::RuleType},
+    config::internal::{
+        config::RuleProviderDef,
+        rule::{FORCE_SNIFF, RuleType},
+    },
     session::Session,
 };
 
 use crate::app::router::rules::final_::Final;
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
+use arc_swap::ArcSwap;
 use hyper::Uri;
 use rules::domain_regex::DomainRegex;
-use tracing::{error, info, trace};
+use tokio::time::Instant;
+use tracing::{error, info, trace, warn};
 
 use super::{
     dns::ThreadSafeDNSResolver,
+    profile::ThreadSafeCacheFile,
     remote_content_manager::providers::{
         file_vehicle, http_vehicle,
         rule_provider::{RuleProviderImpl, ThreadSafeRuleProvider},
     },
 };
 
+mod optimizer;
 mod rules;
 
 use crate::common::geodata::GeoData;
+use optimizer::{OptimizedRules, Segment};
 pub use rules::RuleMatcher;
 
+/// the target of the rules built by `Router::build_selector`, never routed to
+const SELECTOR_TARGET: &str = "SELECTOR";
+
+/// A rule added at runtime via the API, matched ahead of the config rules.
+/// It's gone once `expire` passes or the config is reloaded.
+#[derive(Clone)]
+pub struct TempRule {
+    pub id: uuid::Uuid,
+    pub rule: Arc<dyn RuleMatcher>,
+    pub expire: Option<Instant>,
+    /// connections matched
+    pub hits: Arc<AtomicU64>,
+}
+
+impl TempRule {
+    fn expired(&self, now: Instant) -> bool {
+        self.expire.is_some_and(|e| e <= now)
+    }
+}
+
 pub struct Router {
-    rules: Vec<Box<dyn RuleMatcher>>,
+    rules: Vec<Arc<dyn RuleMatcher>>,
+    /// connections matched by each of `rules`, the `sub-rules` aren't counted
+    hits: Vec<AtomicU64>,
+    /// `experimental.optimize-rules`
+    optimized: Option<OptimizedRules>,
+    /// the `sub-rules`, by name
+    sub_rules: HashMap<String, Vec<Arc<dyn RuleMatcher>>>,
+    /// the `FORCE-SNIFF` and `SKIP-SNIFF` rules, in their order, kept out of
+    /// `rules` as they don't route
+    sniff_rules: Vec<Arc<dyn RuleMatcher>>,
+    /// swapped whole on a change, so matching reads them without a lock or
+    /// a copy
+    temp_rules: ArcSwap<Vec<TempRule>>,
     dns_resolver: ThreadSafeDNSResolver,
 
+    country_mmdb: Arc<Mmdb>,
     asn_mmdb: Option<Arc<Mmdb>>,
+    geodata: Arc<GeoData>,
+    rule_provider_registry: HashMap<String, ThreadSafeRuleProvider>,
 }
 
 pub type ThreadSafeRouter = Arc<Router>;
@@ -45,14 +96,18 @@ pub type ThreadSafeRouter = Arc<Router>;
 const MATCH: &str = "MATCH";
 
 impl Router {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         rules: Vec<RuleType>,
+        sub_rules: HashMap<String, Vec<RuleType>>,
         rule_providers: HashMap<String, RuleProviderDef>,
         dns_resolver: ThreadSafeDNSResolver,
         country_mmdb: Arc<Mmdb>,
         asn_mmdb: Option<Arc<Mmdb>>,
         geodata: Arc<GeoData>,
+        cache_store: ThreadSafeCacheFile,
         cwd: String,
+        optimize_rules: bool,
     ) -> Self {
         let mut rule_provider_registry = HashMap::new();
 
@@ -61,27 +116,49 @@ impl Router {
             &mut rule_provider_registry,
             dns_resolver.clone(),
             country_mmdb.clone(),
+            asn_mmdb.clone(),
             geodata.clone(),
+            cache_store,
             cwd,
         )
         .await
         .ok();
 
-        Self {
-            rules: rules
+        let map_rules = |rules: Vec<RuleType>| {
+            rules
                 .into_iter()
                 .map(|r| {
-                    map_rule_type(
+                    Arc::from(map_rule_type(
                         r,
                         country_mmdb.clone(),
+                        asn_mmdb.clone(),
                         geodata.clone(),
                         Some(&rule_provider_registry),
-                    )
+                    ))
                 })
+                .collect::<Vec<_>>()
+        };
+
+        let (sniff_rules, rules): (Vec<_>, Vec<_>) =
+            rules.into_iter().partition(RuleType::is_sniff_override);
+        let optimized = optimize_rules.then(|| OptimizedRules::new(&rules));
+        let rules = map_rules(rules);
+        Self {
+            hits: rules.iter().map(|_| AtomicU64::new(0)).collect(),
+            rules,
+            optimized,
+            sniff_rules: map_rules(sniff_rules),
+            sub_rules: sub_rules
+                .into_iter()
+                .map(|(name, rules)| (name, map_rules(rules)))
                 .collect(),
+            temp_rules: Default::default(),
             dns_resolver,
 
+            country_mmdb,
             asn_mmdb,
+            geodata,
+            rule_provider_registry,
         }
     }
 
@@ -89,61 +166,180 @@ impl Router {
     pub async fn match_route(
         &self,
         sess: &mut Session,
-    ) -> (&str, Option<&Box<dyn RuleMatcher>>) {
+    ) -> (String, Option<Arc<dyn RuleMatcher>>) {
+        self.match_route_in(sess, None).await
+    }
+
+    /// matches with the `sub-rules` entry `sub_rules` instead of the
+    /// top-level rules, the temporary rules still go first
+    pub async fn match_route_in(
+        &self,
+        sess: &mut Session,
+        sub_rules: Option<&str>,
+    ) -> (String, Option<Arc<dyn RuleMatcher>>) {
         let mut sess_resolved = false;
 
-        for r in self.rules.iter() {
-            if sess.destination.is_domain()
-                && r.should_resolve_ip()
-                && !sess_resolved
-            {
-                if let Ok(Some(ip)) = self
-                    .dns_resolver
-                    .resolve(sess.destination.domain().unwrap(), false)
-                    .await
-                {
-                    sess.resolved_ip = Some(ip);
-                    sess_resolved = true;
+        let now = Instant::now();
+        let temp_rules = self.temp_rules.load_full();
+        for t in temp_rules.iter().filter(|t| !t.expired(now)) {
+            if self.apply_rule(sess, &t.rule, &mut sess_resolved).await {
+                return Self::matched(sess, &t.rule, Some(t.hits.as_ref()));
+            }
+        }
+
+        let sub_rules = sub_rules.and_then(|name| {
+            let rules = self.sub_rules.get(name);
+            if rules.is_none() {
+                warn!("sub-rules {} not found, using the rules", name);
+            }
+            rules
+        });
+        if let Some(rules) = sub_rules {
+            for r in rules {
+                if self.apply_rule(sess, r, &mut sess_resolved).await {
+                    return Self::matched(sess, r, None);
                 }
             }
+            return (MATCH.to_owned(), None);
+        }
 
-            let mayby_ip = sess.resolved_ip.or(sess.destination.ip());
-            if let (Some(ip), Some(asn_mmdb)) = (mayby_ip, &self.asn_mmdb) {
-                // try simplified mmdb first
-                let rv = asn_mmdb.lookup_country(ip);
-                if let Ok(country) = rv {
-                    sess.asn = country
-                        .country
-                        .and_then(|c| c.iso_code)
-                        .map(|s| s.to_string());
+        let matched = match &self.optimized {
+            Some(optimized) => {
+                self.match_optimized(sess, optimized, &mut sess_resolved)
+                    .await
+            }
+            None => {
+                let mut matched = None;
+                for (i, r) in self.rules.iter().enumerate() {
+                    if self.apply_rule(sess, r, &mut sess_resolved).await {
+                        matched = Some(i);
+                        break;
+                    }
                 }
-                if sess.asn.is_none() {
-                    match asn_mmdb.lookup_asn(ip) {
-                        Ok(asn) => {
-                            trace!("asn for {} is {:?}", ip, asn);
-                            sess.asn = asn
-                                .autonomous_system_organization
-                                .map(|s| s.to_string());
-                        }
-                        Err(e) => {
-                            trace!("failed to lookup ASN for {}: {}", ip, e);
+                matched
+            }
+        };
+        match matched {
+            Some(i) => Self::matched(sess, &self.rules[i], Some(&self.hits[i])),
+            None => (MATCH.to_owned(), None),
+        }
+    }
+
+    /// Whether `sess` is sniffed by the first `FORCE-SNIFF` or `SKIP-SNIFF`
+    /// rule it matches, `None` if none does. The rules see the session as it
+    /// arrived, so the destination isn't resolved for them
+    pub fn sniff_override(&self, sess: &Session) -> Option<bool> {
+        self.sniff_rules
+            .iter()
+            .find(|r| r.apply(sess))
+            .map(|r| r.target() == FORCE_SNIFF)
+    }
+
+    /// the index of the first of the rules matching, see `optimizer`
+    async fn match_optimized(
+        &self,
+        sess: &mut Session,
+        optimized: &OptimizedRules,
+        sess_resolved: &mut bool,
+    ) -> Option<usize> {
+        for segment in optimized.segments() {
+            match segment {
+                Segment::Sequential(range) => {
+                    for i in range.clone() {
+                        if self.apply_rule(sess, &self.rules[i], sess_resolved).await
+                        {
+                            return Some(i);
                         }
                     }
                 }
+                Segment::Indexed(tier) => {
+                    self.lookup_asn(sess);
+                    let mut found = tier.lookup(sess);
+                    if let Some(first) = tier.first_resolving()
+                        && found.is_none_or(|i| i > first)
+                        && sess.destination.is_domain()
+                        && !*sess_resolved
+                    {
+                        self.resolve(sess, sess_resolved).await;
+                        self.lookup_asn(sess);
+                        found = tier.lookup(sess);
+                    }
+                    if found.is_some() {
+                        return found;
+                    }
+                }
             }
+        }
+        None
+    }
 
-            if r.apply(sess) {
-                info!(
-                    "matched {} to target {}[{}]",
-                    &sess,
-                    r.target(),
-                    r.type_name()
-                );
-                return (r.target(), Some(r));
-            }
+    /// resolves the destination first if `r` needs it
+    async fn apply_rule(
+        &self,
+        sess: &mut Session,
+        r: &Arc<dyn RuleMatcher>,
+        sess_resolved: &mut bool,
+    ) -> bool {
+        if sess.destination.is_domain() && r.should_resolve_ip() && !*sess_resolved {
+            self.resolve(sess, sess_resolved).await;
+        }
+        self.lookup_asn(sess);
+        r.apply(sess)
+    }
+
+    fn matched(
+        sess: &Session,
+        r: &Arc<dyn RuleMatcher>,
+        hits: Option<&AtomicU64>,
+    ) -> (String, Option<Arc<dyn RuleMatcher>>) {
+        if let Some(hits) = hits {
+            hits.fetch_add(1, Ordering::Relaxed);
         }
+        info!(
+            "matched {} to target {}[{}]",
+            &sess,
+            r.target(),
+            r.type_name()
+        );
+        (r.target().to_owned(), Some(r.clone()))
+    }
 
-        (MATCH, None)
+    async fn resolve(&self, sess: &mut Session, sess_resolved: &mut bool) {
+        if let Ok(Some(ip)) = self
+            .dns_resolver
+            .resolve(sess.destination.domain().unwrap(), false)
+            .await
+        {
+            sess.resolved_ip = Some(ip);
+            *sess_resolved = true;
+        }
+    }
+
+    fn lookup_asn(&self, sess: &mut Session) {
+        let mayby_ip = sess.resolved_ip.or(sess.destination.ip());
+        if let (Some(ip), Some(asn_mmdb)) = (mayby_ip, &self.asn_mmdb) {
+            // try simplified mmdb first
+            let rv = asn_mmdb.lookup_country(ip);
+            if let Ok(country) = rv {
+                sess.asn = country
+                    .country
+                    .and_then(|c| c.iso_code)
+                    .map(|s| s.to_string());
+            }
+            if sess.asn.is_none() {
+                match asn_mmdb.lookup_asn(ip) {
+                    Ok(asn) => {
+                        trace!("asn for {} is {:?}", ip, asn);
+                        sess.asn = asn
+                            .autonomous_system_organization
+                            .map(|s| s.to_string());
+                    }
+                    Err(e) => {
+                        trace!("failed to lookup ASN for {}: {}", ip, e);
+                    }
+                }
+            }
+        }
     }
 
     async fn load_rule_providers(
@@ -151,7 +347,9 @@ impl Router {
         rule_provider_registry: &mut HashMap<String, ThreadSafeRuleProvider>,
         resolver: ThreadSafeDNSResolver,
         mmdb: Arc<Mmdb>,
+        asn_mmdb: Option<Arc<Mmdb>>,
         geodata: Arc<GeoData>,
+        cache_store: ThreadSafeCacheFile,
         cwd: String,
     ) -> Result<(), Error> {
         for (name, provider) in rule_providers.into_iter() {
@@ -164,6 +362,7 @@ impl Router {
                         http.path,
                         Some(cwd.clone()),
                         resolver.clone(),
+                        Some(cache_store.clone()),
                     );
 
                     let provider = RuleProviderImpl::new(
@@ -172,6 +371,7 @@ impl Router {
                         Duration::from_secs(http.interval),
                         Arc::new(vehicle),
                         mmdb.clone(),
+                        asn_mmdb.clone(),
                         geodata.clone(),
                     );
 
@@ -191,6 +391,7 @@ impl Router {
                         Duration::from_secs(file.interval.unwrap_or_default()),
                         Arc::new(vehicle),
                         mmdb.clone(),
+                        asn_mmdb.clone(),
                         geodata.clone(),
                     );
 
@@ -222,14 +423,165 @@ impl Router {
     }
 
     /// API handlers
-    pub fn get_all_rules(&self) -> &Vec<Box<dyn RuleMatcher>> {
+    pub fn get_all_rules(&self) -> &Vec<Arc<dyn RuleMatcher>> {
         &self.rules
     }
+
+    /// the rule providers, by name
+    pub fn rule_providers(&self) -> &HashMap<String, ThreadSafeRuleProvider> {
+        &self.rule_provider_registry
+    }
+
+    /// connections matched by each rule, in the order of `get_all_rules`
+    pub fn get_rule_hits(&self) -> Vec<u64> {
+        self.hits
+            .iter()
+            .map(|h| h.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// the temporary rules still alive, in match order
+    pub fn get_temp_rules(&self) -> Vec<TempRule> {
+        let now = Instant::now();
+        self.temp_rules
+            .load()
+            .iter()
+            .filter(|t| !t.expired(now))
+            .cloned()
+            .collect()
+    }
+
+    /// parse `rule` in the config syntax, e.g. `DOMAIN,example.com,DIRECT`,
+    /// and append it to the temporary rules, which are matched before the
+    /// config rules. `is_outbound` tells the targets that exist, a rule
+    /// routing anywhere else is refused rather than falling back to DIRECT.
+    pub fn add_temp_rule(
+        &self,
+        rule: &str,
+        ttl: Option<Duration>,
+        is_outbound: impl Fn(&str) -> bool,
+    ) -> Result<uuid::Uuid, Error> {
+        let rule = self.build_temp_rule(rule.parse::<RuleType>()?)?;
+        if !is_outbound(rule.target()) {
+            return Err(Error::InvalidConfig(format!(
+                "outbound {} not found",
+                rule.target()
+            )));
+        }
+        let id = uuid::Uuid::new_v4();
+        let now = Instant::now();
+
+        info!("adding temporary rule {} with ttl {:?}", rule, ttl);
+
+        let temp_rule = TempRule {
+            id,
+            rule: Arc::from(rule),
+            expire: ttl.map(|ttl| now + ttl),
+            hits: Default::default(),
+        };
+        self.temp_rules.rcu(|rules| {
+            let mut rules = rules
+                .iter()
+                .filter(|t| !t.expired(now))
+                .cloned()
+                .collect::<Vec<_>>();
+            rules.push(temp_rule.clone());
+            rules
+        });
+        Ok(id)
+    }
+
+    /// returns false if there is no such rule
+    pub fn remove_temp_rule(&self, id: uuid::Uuid) -> bool {
+        let prev = self.temp_rules.rcu(|rules| {
+            rules
+                .iter()
+                .filter(|t| t.id != id)
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+        prev.iter().any(|t| t.id == id)
+    }
+
+    pub fn clear_temp_rules(&self) {
+        self.temp_rules.store(Default::default());
+    }
+
+    /// parse a rule without its target, e.g. `DOMAIN-SUFFIX,example.com`,
+    /// for picking connections out rather than routing them
+    pub fn build_selector(&self, rule: &str) -> Result<Box<dyn RuleMatcher>, Error> {
+        let mut parts = rule.splitn(3, ',').map(str::trim);
+        let rule = match (parts.next(), parts.next(), parts.next()) {
+            (Some(typ), None, _) => format!("{},{}", typ, SELECTOR_TARGET),
+            (Some(typ), Some(payload), None) => {
+                format!("{},{},{}", typ, payload, SELECTOR_TARGET)
+            }
+            (Some(typ), Some(payload), Some(params)) => {
+                format!("{},{},{},{}", typ, payload, SELECTOR_TARGET, params)
+            }
+            (None, ..) => unreachable!("splitn yields at least one item"),
+        };
+        self.build_temp_rule(rule.parse::<RuleType>()?)
+    }
+
+    /// `map_rule_type` exits on bad config, which is fine at startup but not
+    /// for input coming from the API, so check the fallible cases first
+    fn build_temp_rule(
+        &self,
+        rule: RuleType,
+    ) -> Result<Box<dyn RuleMatcher>, Error> {
+        match rule {
+            RuleType::RuleSet { ref rule_set, .. }
+                if !self.rule_provider_registry.contains_key(rule_set) =>
+            {
+                Err(Error::InvalidConfig(format!(
+                    "rule provider {} not found",
+                    rule_set
+                )))
+            }
+            RuleType::GeoSite {
+                target,
+                country_code,
+            } => rules::geodata::GeoSiteMatcher::new(
+                country_code,
+                target,
+                self.geodata.as_ref(),
+            )
+            .map(|m| Box::new(m) as Box<dyn RuleMatcher>)
+            .map_err(|e| Error::InvalidConfig(e.to_string())),
+            RuleType::Scheduled { rule, schedule } => {
+                Ok(Box::new(rules::schedule::Scheduled {
+                    inner: self.build_temp_rule(*rule)?,
+                    schedule,
+                }))
+            }
+            RuleType::NoDelay { rule, nodelay } => {
+                Ok(Box::new(rules::nodelay::NoDelay {
+                    inner: self.build_temp_rule(*rule)?,
+                    nodelay,
+                }))
+            }
+            RuleType::Rewrite { rule, destination } => {
+                Ok(Box::new(rules::rewrite::Rewrite {
+                    inner: self.build_temp_rule(*rule)?,
+                    destination,
+                }))
+            }
+            rule => Ok(map_rule_type(
+                rule,
+                self.country_mmdb.clone(),
+                self.asn_mmdb.clone(),
+                self.geodata.clone(),
+                Some(&self.rule_provider_registry),
+            )),
+        }
+    }
 }
 
 pub fn map_rule_type(
     rule_type: RuleType,
     mmdb: Arc<Mmdb>,
+    asn_mmdb: Option<Arc<Mmdb>>,
     geodata: Arc<GeoData>,
     rule_provider_registry: Option<&HashMap<String, ThreadSafeRuleProvider>>,
 ) -> Box<dyn RuleMatcher> {
@@ -285,6 +637,21 @@ pub fn map_rule_type(
             no_resolve,
             mmdb: mmdb.clone(),
         }),
+        RuleType::IpAsn {
+            asn,
+            target,
+            no_resolve,
+        } => {
+            if asn_mmdb.is_none() {
+                warn!("IP-ASN,{} never matches without the asn mmdb", asn);
+            }
+            Box::new(rules::ip_asn::IpAsn {
+                target,
+                asn,
+                no_resolve,
+                mmdb: asn_mmdb.clone(),
+            })
+        }
         RuleType::GeoSite {
             target,
             country_code,
@@ -340,18 +707,61 @@ pub fn map_rule_type(
                 unreachable!("you shouldn't nest rule-set within another rule-set")
             }
         },
+        RuleType::Schedule { schedule, target } => {
+            Box::new(rules::schedule::Schedule { schedule, target })
+        }
+        RuleType::Protocol { protocol, target } => {
+            Box::new(rules::protocol::Protocol { protocol, target })
+        }
         RuleType::Match { target } => Box::new(Final { target }),
+        RuleType::Scheduled { rule, schedule } => {
+            Box::new(rules::schedule::Scheduled {
+                inner: map_rule_type(
+                    *rule,
+                    mmdb.clone(),
+                    asn_mmdb.clone(),
+                    geodata.clone(),
+                    rule_provider_registry,
+                ),
+                schedule,
+            })
+        }
+        RuleType::NoDelay { rule, nodelay } => Box::new(rules::nodelay::NoDelay {
+            inner: map_rule_type(
+                *rule,
+                mmdb.clone(),
+                asn_mmdb.clone(),
+                geodata.clone(),
+                rule_provider_registry,
+            ),
+            nodelay,
+        }),
+        RuleType::Rewrite { rule, destination } => {
+            Box::new(rules::rewrite::Rewrite {
+                inner: map_rule_type(
+                    *rule,
+                    mmdb,
+                    asn_mmdb,
+                    geodata,
+                    rule_provider_registry,
+                ),
+                destination,
+            })
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
 
     use anyhow::Ok;
 
     use crate::{
-        app::dns::{MockClashResolver, SystemResolver},
+        app::{
+            dns::{MockClashResolver, SystemResolver},
+            profile::{MemoryStore, ThreadSafeCacheFile},
+        },
         common::{geodata::GeoData, http::new_http_client, mmdb::Mmdb},
         config::internal::rule::RuleType,
         session::Session,
@@ -402,6 +812,14 @@ mod tests {
 
         let router = super::Router::new(
             vec![
+                RuleType::DSTPort {
+                    target: "SKIP-SNIFF".to_string(),
+                    port: 1111,
+                },
+                RuleType::DomainSuffix {
+                    domain_suffix: "t.me".to_string(),
+                    target: "FORCE-SNIFF".to_string(),
+                },
                 RuleType::GeoIP {
                     target: "DIRECT".to_string(),
                     country_code: "CN".to_string(),
@@ -425,12 +843,25 @@ mod tests {
                     target: "DS2".to_string(),
                 },
             ],
+            HashMap::from([(
+                "sub".to_string(),
+                vec![RuleType::DomainSuffix {
+                    domain_suffix: "t.me".to_string(),
+                    target: "SUB".to_string(),
+                }],
+            )]),
             Default::default(),
             mock_resolver,
             Arc::new(mmdb),
             None,
             Arc::new(geodata),
+            ThreadSafeCacheFile::with_store(
+                Box::new(MemoryStore::default()),
+                false,
+                false,
+            ),
             temp_dir.path().to_str().unwrap().to_string(),
+            false,
         )
         .await;
 
@@ -468,5 +899,90 @@ mod tests {
                 desc
             );
         }
+
+        let sess = |domain: &str, port| Session {
+            destination: crate::session::SocksAddr::Domain(domain.to_owned(), port),
+            ..Default::default()
+        };
+        assert_eq!(router.sniff_override(&sess("t.me", 1111)), Some(false));
+        assert_eq!(router.sniff_override(&sess("t.me", 443)), Some(true));
+        assert_eq!(router.sniff_override(&sess("git.io", 443)), None);
+
+        let is_outbound = |t: &str| ["TEMP", "EXPIRED"].contains(&t);
+        let id = router
+            .add_temp_rule("DOMAIN-SUFFIX,t.me,TEMP", None, is_outbound)
+            .unwrap();
+        router
+            .add_temp_rule(
+                "DOMAIN,git.io,EXPIRED",
+                Some(Duration::ZERO),
+                is_outbound,
+            )
+            .unwrap();
+        assert!(
+            router
+                .add_temp_rule("NOT-A-RULE,x", None, is_outbound)
+                .is_err()
+        );
+        assert!(
+            router
+                .add_temp_rule("DOMAIN,git.io,UNKNOWN", None, is_outbound)
+                .is_err(),
+            "rules to unknown outbounds should be refused"
+        );
+        assert_eq!(router.get_temp_rules().len(), 1);
+
+        let mut sess = Session {
+            destination: crate::session::SocksAddr::Domain("t.me".to_string(), 1),
+            ..Default::default()
+        };
+        assert_eq!(
+            router.match_route(&mut sess).await.0,
+            "TEMP",
+            "temporary rules should go before config rules"
+        );
+        assert_eq!(
+            router.get_temp_rules()[0]
+                .hits
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+        let mut sess = Session {
+            destination: crate::session::SocksAddr::Domain("git.io".to_string(), 1),
+            ..Default::default()
+        };
+        assert_eq!(
+            router.match_route(&mut sess).await.0,
+            "DS2",
+            "expired temporary rules should be skipped"
+        );
+
+        assert!(router.remove_temp_rule(id));
+        assert!(!router.remove_temp_rule(id));
+        let mut sess = Session {
+            destination: crate::session::SocksAddr::Domain("t.me".to_string(), 1),
+            ..Default::default()
+        };
+        assert_eq!(router.match_route(&mut sess).await.0, "DS");
+
+        assert_eq!(
+            router.match_route_in(&mut sess, Some("sub")).await.0,
+            "SUB",
+            "sub-rules should replace the rules"
+        );
+        let mut sess = Session {
+            destination: crate::session::SocksAddr::Domain("git.io".to_string(), 1),
+            ..Default::default()
+        };
+        assert_eq!(
+            router.match_route_in(&mut sess, Some("sub")).await.0,
+            "MATCH",
+            "the rules shouldn't be matched after sub-rules"
+        );
+        assert_eq!(
+            router.get_rule_hits(),
+            vec![1, 1, 2, 0, 2],
+            "the sub-rules and temporary rules aren't counted as the rules"
+        );
     }
 }
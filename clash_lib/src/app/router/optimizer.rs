@@ -0,0 +1,252 @@
+//! `experimental.optimize-rules`: runs of DOMAIN, DOMAIN-SUFFIX, IP-CIDR and
+//! SRC-IP-CIDR rules are looked up in hash maps at once instead of one rule
+//! after another. A lookup returns the first rule of the run that matches,
+//! so the result is the same as matching in order.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    ops::Range,
+};
+
+use ipnet::IpNet;
+
+use crate::{config::internal::rule::RuleType, session::Session};
+
+/// shorter runs are cheaper to match one by one
+const MIN_INDEXED_RUN: usize = 4;
+
+pub(super) enum Segment {
+    /// matched in order, by the indices into the rules
+    Sequential(Range<usize>),
+    Indexed(Box<Tier>),
+}
+
+pub(super) struct OptimizedRules {
+    segments: Vec<Segment>,
+}
+
+impl OptimizedRules {
+    pub(super) fn new(rules: &[RuleType]) -> Self {
+        let mut segments = vec![];
+        let mut sequential_start = 0;
+        let mut i = 0;
+        while i < rules.len() {
+            let run = rules[i..].iter().take_while(|r| Tier::indexable(r)).count();
+            if run < MIN_INDEXED_RUN {
+                i += run.max(1);
+                continue;
+            }
+            if sequential_start < i {
+                segments.push(Segment::Sequential(sequential_start..i));
+            }
+            segments
+                .push(Segment::Indexed(Box::new(Tier::new(&rules[i..i + run], i))));
+            i += run;
+            sequential_start = i;
+        }
+        if sequential_start < rules.len() {
+            segments.push(Segment::Sequential(sequential_start..rules.len()));
+        }
+        Self { segments }
+    }
+
+    pub(super) fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+}
+
+/// A run of rules, all indices are into the rules, the smallest match wins
+#[derive(Default)]
+pub(super) struct Tier {
+    domains: HashMap<String, usize>,
+    suffixes: HashMap<String, usize>,
+    /// IP-CIDR with `no-resolve`, on the destination IP
+    dst: CidrSet,
+    /// IP-CIDR, on the resolved IP
+    resolved: CidrSet,
+    src: CidrSet,
+    first_resolving: Option<usize>,
+}
+
+impl Tier {
+    fn indexable(rule: &RuleType) -> bool {
+        match rule {
+            RuleType::Domain { .. }
+            | RuleType::IpCidr { .. }
+            | RuleType::SrcCidr { .. } => true,
+            // the suffix is matched at label boundaries
+            RuleType::DomainSuffix { domain_suffix, .. } => {
+                !domain_suffix.is_empty() && !domain_suffix.starts_with('.')
+            }
+            _ => false,
+        }
+    }
+
+    fn new(rules: &[RuleType], offset: usize) -> Self {
+        let mut tier = Self::default();
+        for (i, rule) in rules.iter().enumerate() {
+            let i = offset + i;
+            match rule {
+                RuleType::Domain { domain, .. } => {
+                    tier.domains.entry(domain.clone()).or_insert(i);
+                }
+                RuleType::DomainSuffix { domain_suffix, .. } => {
+                    tier.suffixes.entry(domain_suffix.clone()).or_insert(i);
+                }
+                RuleType::IpCidr {
+                    ipnet, no_resolve, ..
+                } => {
+                    if *no_resolve {
+                        tier.dst.insert(*ipnet, i);
+                    } else {
+                        tier.resolved.insert(*ipnet, i);
+                        tier.first_resolving.get_or_insert(i);
+                    }
+                }
+                RuleType::SrcCidr {
+                    ipnet, no_resolve, ..
+                } => {
+                    tier.src.insert(*ipnet, i);
+                    if !*no_resolve {
+                        tier.first_resolving.get_or_insert(i);
+                    }
+                }
+                _ => unreachable!("not indexable: {}", rule),
+            }
+        }
+        tier
+    }
+
+    /// the first rule asking for the destination to be resolved, the ones
+    /// before it are matched without
+    pub(super) fn first_resolving(&self) -> Option<usize> {
+        self.first_resolving
+    }
+
+    pub(super) fn lookup(&self, sess: &Session) -> Option<usize> {
+        let by_domain = sess.destination.domain().and_then(|domain| {
+            let suffixes =
+                std::iter::successors(Some(domain), |d| Some(d.split_once('.')?.1));
+            suffixes
+                .filter_map(|s| self.suffixes.get(s))
+                .chain(self.domains.get(domain))
+                .min()
+                .copied()
+        });
+
+        [
+            by_domain,
+            sess.destination.ip().and_then(|ip| self.dst.lookup(ip)),
+            sess.resolved_ip.and_then(|ip| self.resolved.lookup(ip)),
+            self.src.lookup(sess.source.ip()),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+}
+
+#[derive(Default)]
+struct CidrSet {
+    /// the networks by prefix length, IPv4 and IPv6 apart
+    v4: BTreeMap<u8, HashMap<IpAddr, usize>>,
+    v6: BTreeMap<u8, HashMap<IpAddr, usize>>,
+}
+
+impl CidrSet {
+    fn insert(&mut self, net: IpNet, i: usize) {
+        let nets = match net {
+            IpNet::V4(_) => &mut self.v4,
+            IpNet::V6(_) => &mut self.v6,
+        };
+        nets.entry(net.prefix_len())
+            .or_default()
+            .entry(net.network())
+            .or_insert(i);
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<usize> {
+        let nets = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        nets.iter()
+            .filter_map(|(len, nets)| {
+                nets.get(&IpNet::new(ip, *len).ok()?.network())
+            })
+            .min()
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::internal::rule::RuleType,
+        session::{Session, SocksAddr},
+    };
+
+    use super::{OptimizedRules, Segment};
+
+    fn rule(s: &str) -> RuleType {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_optimized_rules() {
+        let rules = [
+            "DOMAIN-KEYWORD,ads,REJECT",
+            "DOMAIN,www.example.com,A",
+            "IP-CIDR,10.0.0.0/8,B,no-resolve",
+            "DOMAIN-SUFFIX,example.com,C",
+            "SRC-IP-CIDR,192.168.1.0/24,D,no-resolve",
+            "IP-CIDR,10.1.0.0/16,E",
+            "DOMAIN-SUFFIX,cdn.example.com,F",
+            "MATCH,DIRECT",
+        ]
+        .map(rule);
+        let optimized = OptimizedRules::new(&rules);
+        let [
+            Segment::Sequential(first),
+            Segment::Indexed(tier),
+            Segment::Sequential(last),
+        ] = optimized.segments()
+        else {
+            panic!("should be indexed in the middle");
+        };
+        assert_eq!((first.clone(), last.clone()), (0..1, 7..8));
+        assert_eq!(tier.first_resolving(), Some(5));
+
+        let sess = |dst: SocksAddr, src: &str| Session {
+            destination: dst,
+            source: src.parse().unwrap(),
+            ..Default::default()
+        };
+        let domain = |d: &str| SocksAddr::Domain(d.to_owned(), 443);
+        let ip = |ip: &str| SocksAddr::Ip(ip.parse().unwrap());
+
+        for (sess, expected) in [
+            (sess(domain("www.example.com"), "127.0.0.1:1"), Some(1)),
+            (sess(domain("img.cdn.example.com"), "127.0.0.1:1"), Some(3)),
+            (sess(domain("example.com"), "127.0.0.1:1"), Some(3)),
+            (sess(domain("notexample.com"), "192.168.1.2:1"), Some(4)),
+            (sess(domain("notexample.com"), "127.0.0.1:1"), None),
+            (sess(ip("10.1.2.3:443"), "192.168.1.2:1"), Some(2)),
+            (sess(ip("11.0.0.1:443"), "127.0.0.1:1"), None),
+        ] {
+            assert_eq!(tier.lookup(&sess), expected, "{}", sess);
+        }
+
+        let mut resolved = sess(domain("internal.lan"), "127.0.0.1:1");
+        assert_eq!(tier.lookup(&resolved), None);
+        resolved.resolved_ip = Some("10.1.0.1".parse().unwrap());
+        assert_eq!(tier.lookup(&resolved), Some(5));
+
+        let short = ["DOMAIN,a.com,A", "DOMAIN,b.com,B"].map(rule);
+        assert!(matches!(
+            OptimizedRules::new(&short).segments(),
+            [Segment::Sequential(r)] if *r == (0..2)
+        ));
+    }
+}
@@ -2,24 +2,40 @@ use crate::{
     app::router::rules::geodata::str_matcher::{Matcher, try_new_matcher},
     common::{
         geodata::geodata_proto::{Domain, domain::Type},
-        trie,
+        succinct_set,
+        trie::{self, TrieStats},
     },
 };
 use std::sync::Arc;
 
 pub trait DomainGroupMatcher: Send + Sync {
     fn apply(&self, domain: &str) -> bool;
+
+    fn trie_stats(&self) -> Option<TrieStats> {
+        None
+    }
+}
+
+enum DomainTrie {
+    Trie(trie::StringTrie<()>),
+    /// much smaller, slower to look up
+    Compact(succinct_set::DomainSet),
 }
 
 pub struct SuccinctMatcherGroup {
-    set: trie::StringTrie<()>,
+    set: DomainTrie,
     other_matchers: Vec<Box<dyn Matcher>>,
     not: bool,
 }
 
 impl SuccinctMatcherGroup {
-    pub fn try_new(domains: Vec<Domain>, not: bool) -> Result<Self, crate::Error> {
+    pub fn try_new(
+        domains: Vec<Domain>,
+        not: bool,
+        compact: bool,
+    ) -> Result<Self, crate::Error> {
         let mut set = trie::StringTrie::new();
+        let mut has_domains = false;
         let mut other_matchers = Vec::new();
         for domain in domains {
             let t = Type::try_from(domain.r#type).map_err(|x| {
@@ -34,12 +50,21 @@ impl SuccinctMatcherGroup {
                 Type::Domain => {
                     let domain = format!("+.{}", domain.value);
                     set.insert(&domain, Arc::new(()));
+                    has_domains = true;
                 }
                 Type::Full => {
                     set.insert(&domain.value, Arc::new(()));
+                    has_domains = true;
                 }
             }
         }
+        // a succinct set can't be built from nothing
+        let set = if compact && has_domains {
+            DomainTrie::Compact(set.into())
+        } else {
+            set.shrink();
+            DomainTrie::Trie(set)
+        };
         Ok(SuccinctMatcherGroup {
             set,
             other_matchers,
@@ -50,7 +75,10 @@ impl SuccinctMatcherGroup {
 
 impl DomainGroupMatcher for SuccinctMatcherGroup {
     fn apply(&self, domain: &str) -> bool {
-        let mut is_matched = self.set.search(domain).is_some();
+        let mut is_matched = match &self.set {
+            DomainTrie::Trie(set) => set.search(domain).is_some(),
+            DomainTrie::Compact(set) => set.has(domain),
+        };
         if !is_matched {
             for matcher in &self.other_matchers {
                 if matcher.matches(domain) {
@@ -61,4 +89,57 @@ impl DomainGroupMatcher for SuccinctMatcherGroup {
         }
         if self.not { !is_matched } else { is_matched }
     }
+
+    fn trie_stats(&self) -> Option<TrieStats> {
+        match &self.set {
+            DomainTrie::Trie(set) => Some(set.stats()),
+            DomainTrie::Compact(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DomainGroupMatcher, SuccinctMatcherGroup};
+    use crate::common::geodata::geodata_proto::{Domain, domain::Type};
+
+    fn domain(t: Type, value: &str) -> Domain {
+        Domain {
+            r#type: t as i32,
+            value: value.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compact_matches_trie() {
+        let domains = vec![
+            domain(Type::Domain, "google.com"),
+            domain(Type::Full, "www.example.com"),
+            domain(Type::Plain, "bilibili"),
+        ];
+        let trie =
+            SuccinctMatcherGroup::try_new(domains.clone(), false, false).unwrap();
+        let compact = SuccinctMatcherGroup::try_new(domains, false, true).unwrap();
+
+        for (d, expected) in [
+            ("google.com", true),
+            ("www.google.com", true),
+            ("www.example.com", true),
+            ("example.com", false),
+            ("www.bilibili.com", true),
+            ("notgoogle.com", false),
+        ] {
+            assert_eq!(trie.apply(d), expected, "{}", d);
+            assert_eq!(compact.apply(d), expected, "{}", d);
+        }
+
+        let empty = SuccinctMatcherGroup::try_new(
+            vec![domain(Type::Plain, "bilibili")],
+            true,
+            true,
+        )
+        .unwrap();
+        assert!(empty.apply("google.com"));
+    }
 }
@@ -6,7 +6,7 @@ use crate::{
         attribute::{AndAttrMatcher, AttrMatcher},
         matcher_group::{DomainGroupMatcher, SuccinctMatcherGroup},
     },
-    common::geodata::GeoData,
+    common::{geodata::GeoData, trie::TrieStats},
 };
 
 mod attribute;
@@ -71,8 +71,9 @@ impl GeoSiteMatcher {
             .filter(|domain| attr_matcher.matches(domain))
             .collect::<Vec<_>>();
 
-        let matcher_group: Box<dyn DomainGroupMatcher> =
-            Box::new(SuccinctMatcherGroup::try_new(domains, not)?);
+        let matcher_group: Box<dyn DomainGroupMatcher> = Box::new(
+            SuccinctMatcherGroup::try_new(domains, not, loader.compact_tries())?,
+        );
         Ok(Self {
             country_code,
             target,
@@ -108,6 +109,10 @@ impl RuleMatcher for GeoSiteMatcher {
     fn type_name(&self) -> &str {
         "GeoSite"
     }
+
+    fn trie_stats(&self) -> Option<TrieStats> {
+        self.matcher.trie_stats()
+    }
 }
 
 #[cfg(test)]
@@ -194,8 +199,9 @@ mod tests {
                 .filter(|domain| attr_matcher.matches(domain))
                 .collect::<Vec<_>>();
 
-            let matcher_group: Box<dyn DomainGroupMatcher> =
-                Box::new(SuccinctMatcherGroup::try_new(domains, not).unwrap());
+            let matcher_group: Box<dyn DomainGroupMatcher> = Box::new(
+                SuccinctMatcherGroup::try_new(domains, not, false).unwrap(),
+            );
 
             for (domain, expected) in suite.expected_results.iter() {
                 assert_eq!(matcher_group.apply(domain), *expected);
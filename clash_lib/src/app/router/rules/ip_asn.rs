@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use tracing::debug;
+
+use crate::{common::mmdb, session::Session};
+
+use super::RuleMatcher;
+
+#[derive(Clone)]
+pub struct IpAsn {
+    pub target: String,
+    pub asn: u32,
+    pub no_resolve: bool,
+    /// never matches without the ASN mmdb
+    pub mmdb: Option<Arc<mmdb::Mmdb>>,
+}
+
+impl std::fmt::Display for IpAsn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IPASN({} - AS{})", self.target, self.asn)
+    }
+}
+
+impl RuleMatcher for IpAsn {
+    fn apply(&self, sess: &Session) -> bool {
+        let ip = if self.no_resolve {
+            sess.destination.ip()
+        } else {
+            sess.resolved_ip
+        };
+
+        match (ip, &self.mmdb) {
+            (Some(ip), Some(mmdb)) => match mmdb.lookup_asn(ip) {
+                Ok(asn) => asn.autonomous_system_number == Some(self.asn),
+                Err(e) => {
+                    debug!("ASN lookup failed: {}", e);
+                    false
+                }
+            },
+            _ => false,
+        }
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        !self.no_resolve
+    }
+
+    fn payload(&self) -> String {
+        self.asn.to_string()
+    }
+
+    fn type_name(&self) -> &str {
+        "IPASN"
+    }
+}
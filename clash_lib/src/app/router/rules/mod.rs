@@ -2,7 +2,10 @@ use std::{collections::HashMap, fmt::Display};
 
 use erased_serde::Serialize;
 
-use crate::session::Session;
+use crate::{
+    common::trie::TrieStats, config::internal::rule::DestinationOverride,
+    session::Session,
+};
 
 pub mod domain;
 pub mod domain_keyword;
@@ -11,10 +14,15 @@ pub mod domain_suffix;
 pub mod final_;
 pub mod geodata;
 pub mod geoip;
+pub mod ip_asn;
 pub mod ipcidr;
+pub mod nodelay;
 pub mod port;
 pub mod process;
+pub mod protocol;
+pub mod rewrite;
 pub mod ruleset;
+pub mod schedule;
 
 pub trait RuleMatcher: Send + Sync + Unpin + Display {
     /// check if the rule should apply to the session
@@ -33,6 +41,21 @@ pub trait RuleMatcher: Send + Sync + Unpin + Display {
         false
     }
 
+    /// where to send the matched connection instead of its destination
+    fn destination_override(&self) -> Option<&DestinationOverride> {
+        None
+    }
+
+    /// TCP_NODELAY of the matched connections instead of the default
+    fn nodelay(&self) -> Option<bool> {
+        None
+    }
+
+    /// the size of the domain trie behind the rule, if there is one
+    fn trie_stats(&self) -> Option<TrieStats> {
+        None
+    }
+
     fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let mut m: HashMap<String, Box<dyn Serialize + Send>> = HashMap::new();
         m.insert("type".to_string(), Box::new(self.type_name().to_owned()));
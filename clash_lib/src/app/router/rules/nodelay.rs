@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use erased_serde::Serialize;
+
+use crate::{
+    app::router::rules::RuleMatcher, common::trie::TrieStats,
+    config::internal::rule::DestinationOverride, session::Session,
+};
+
+/// Wraps a rule whose matches are relayed with its `nodelay=` param rather
+/// than the default TCP_NODELAY
+pub struct NoDelay {
+    pub inner: Box<dyn RuleMatcher>,
+    pub nodelay: bool,
+}
+
+impl std::fmt::Display for NoDelay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} nodelay={}", self.inner, self.nodelay)
+    }
+}
+
+impl RuleMatcher for NoDelay {
+    fn apply(&self, sess: &Session) -> bool {
+        self.inner.apply(sess)
+    }
+
+    fn target(&self) -> &str {
+        self.inner.target()
+    }
+
+    fn payload(&self) -> String {
+        self.inner.payload()
+    }
+
+    fn type_name(&self) -> &str {
+        self.inner.type_name()
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.inner.should_resolve_ip()
+    }
+
+    fn destination_override(&self) -> Option<&DestinationOverride> {
+        self.inner.destination_override()
+    }
+
+    fn nodelay(&self) -> Option<bool> {
+        Some(self.nodelay)
+    }
+
+    fn trie_stats(&self) -> Option<TrieStats> {
+        self.inner.trie_stats()
+    }
+
+    fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.inner.as_map();
+        m.insert("nodelay".to_string(), Box::new(self.nodelay));
+        m
+    }
+}
@@ -0,0 +1,35 @@
+use crate::{
+    app::router::rules::RuleMatcher,
+    session::{Protocol as SniffedProtocol, Session},
+};
+
+/// Matches the protocol told by the sniffer
+#[derive(Clone)]
+pub struct Protocol {
+    pub protocol: SniffedProtocol,
+    pub target: String,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} protocol {}", self.target, self.protocol)
+    }
+}
+
+impl RuleMatcher for Protocol {
+    fn apply(&self, sess: &Session) -> bool {
+        sess.protocol == Some(self.protocol)
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.protocol.to_string()
+    }
+
+    fn type_name(&self) -> &str {
+        "Protocol"
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use erased_serde::Serialize;
+
+use crate::{
+    app::router::rules::RuleMatcher, common::trie::TrieStats,
+    config::internal::rule::DestinationOverride, session::Session,
+};
+
+/// Wraps a rule that sends its matches to another destination
+pub struct Rewrite {
+    pub inner: Box<dyn RuleMatcher>,
+    pub destination: DestinationOverride,
+}
+
+impl std::fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} dst={}", self.inner, self.destination)
+    }
+}
+
+impl RuleMatcher for Rewrite {
+    fn apply(&self, sess: &Session) -> bool {
+        self.inner.apply(sess)
+    }
+
+    fn target(&self) -> &str {
+        self.inner.target()
+    }
+
+    fn payload(&self) -> String {
+        self.inner.payload()
+    }
+
+    fn type_name(&self) -> &str {
+        self.inner.type_name()
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.inner.should_resolve_ip()
+    }
+
+    fn destination_override(&self) -> Option<&DestinationOverride> {
+        Some(&self.destination)
+    }
+
+    fn nodelay(&self) -> Option<bool> {
+        self.inner.nodelay()
+    }
+
+    fn trie_stats(&self) -> Option<TrieStats> {
+        self.inner.trie_stats()
+    }
+
+    fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.inner.as_map();
+        m.insert("dst".to_string(), Box::new(self.destination.to_string()));
+        m
+    }
+}
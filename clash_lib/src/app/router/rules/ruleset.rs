@@ -3,6 +3,7 @@ use crate::{
         remote_content_manager::providers::rule_provider::ThreadSafeRuleProvider,
         router::rules::RuleMatcher,
     },
+    common::trie::TrieStats,
     session::Session,
 };
 
@@ -49,4 +50,8 @@ impl RuleMatcher for RuleSet {
     fn type_name(&self) -> &str {
         "RuleSet"
     }
+
+    fn trie_stats(&self) -> Option<TrieStats> {
+        self.rule_provider.trie_stats()
+    }
 }
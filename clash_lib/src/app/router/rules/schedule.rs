@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use erased_serde::Serialize;
+
+use crate::{
+    app::router::rules::RuleMatcher,
+    common::trie::TrieStats,
+    config::internal::rule::{self, DestinationOverride},
+    session::Session,
+};
+
+/// `SCHEDULE`, matches any connection inside the time window
+pub struct Schedule {
+    pub schedule: rule::Schedule,
+    pub target: String,
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} schedule {}", self.target, self.schedule)
+    }
+}
+
+impl RuleMatcher for Schedule {
+    fn apply(&self, _sess: &Session) -> bool {
+        self.schedule.is_active()
+    }
+
+    fn target(&self) -> &str {
+        self.target.as_str()
+    }
+
+    fn payload(&self) -> String {
+        self.schedule.to_string()
+    }
+
+    fn type_name(&self) -> &str {
+        "Schedule"
+    }
+}
+
+/// Wraps a rule that only matches inside the time window of its
+/// `schedule=` param
+pub struct Scheduled {
+    pub inner: Box<dyn RuleMatcher>,
+    pub schedule: rule::Schedule,
+}
+
+impl std::fmt::Display for Scheduled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} schedule={}", self.inner, self.schedule)
+    }
+}
+
+impl RuleMatcher for Scheduled {
+    fn apply(&self, sess: &Session) -> bool {
+        self.schedule.is_active() && self.inner.apply(sess)
+    }
+
+    fn target(&self) -> &str {
+        self.inner.target()
+    }
+
+    fn payload(&self) -> String {
+        self.inner.payload()
+    }
+
+    fn type_name(&self) -> &str {
+        self.inner.type_name()
+    }
+
+    fn should_resolve_ip(&self) -> bool {
+        self.inner.should_resolve_ip()
+    }
+
+    fn destination_override(&self) -> Option<&DestinationOverride> {
+        self.inner.destination_override()
+    }
+
+    fn nodelay(&self) -> Option<bool> {
+        self.inner.nodelay()
+    }
+
+    fn trie_stats(&self) -> Option<TrieStats> {
+        self.inner.trie_stats()
+    }
+
+    fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.inner.as_map();
+        m.insert("schedule".to_string(), Box::new(self.schedule.to_string()));
+        m
+    }
+}
@@ -0,0 +1,77 @@
+//! What the running binary was built from, for the bug reports: in the
+//! banner at startup, `-v` and `/version`.
+
+use std::fmt::{self, Display};
+
+use serde::Serialize;
+
+/// the optional parts of the library, as built
+const FEATURES: &[(&str, bool)] = &[
+    ("api", cfg!(feature = "api")),
+    ("shadowsocks", cfg!(feature = "shadowsocks")),
+    ("tuic", cfg!(feature = "tuic")),
+    ("ssh", cfg!(feature = "ssh")),
+    ("onion", cfg!(feature = "onion")),
+    ("sled", cfg!(feature = "sled")),
+    ("zero_copy", cfg!(feature = "zero_copy")),
+    ("tokio-console", cfg!(feature = "tokio-console")),
+];
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// empty if not built from a git checkout
+    pub git_sha: &'static str,
+    pub build_date: &'static str,
+    /// `os/arch`
+    pub platform: String,
+    /// the binary adds its own, e.g. the allocator
+    pub features: Vec<&'static str>,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("CLASH_BUILD_SHA"),
+        build_date: env!("CLASH_BUILD_DATE"),
+        platform: format!("{}/{}", std::env::consts::OS, std::env::consts::ARCH),
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect(),
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.version)?;
+        if !self.git_sha.is_empty() {
+            write!(f, " ({})", self.git_sha)?;
+        }
+        write!(
+            f,
+            " built {} for {}, features: {}",
+            self.build_date,
+            self.platform,
+            self.features.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_info;
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.build_date.len(), "2024-01-01".len());
+        assert!(info.platform.contains('/'));
+        assert!(
+            info.to_string()
+                .starts_with(&format!("{} ", env!("CARGO_PKG_VERSION")))
+        );
+    }
+}
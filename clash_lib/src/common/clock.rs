@@ -0,0 +1,101 @@
+//! The clock of the protocols with timestamps in their handshakes, e.g.
+//! VMess, whose servers reject them beyond 2 minutes of skew. The skew is
+//! learned from the `Date` header of the health check responses.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicI64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use tracing::{info, warn};
+
+/// below this the `Date` header, to the second and a round trip late, can't
+/// tell the skew apart
+const TOLERANCE: i64 = 5;
+/// warned about, half the window of VMess
+const WARN_SKEW: i64 = 60;
+
+/// seconds the local clock is behind
+static SKEW: AtomicI64 = AtomicI64::new(0);
+static CORRECT: AtomicBool = AtomicBool::new(false);
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the timestamps of the protocols are offset by the skew
+pub fn set_correct(correct: bool) {
+    CORRECT.store(correct, Ordering::Relaxed);
+}
+
+/// The time for the protocols, the local time unless it's corrected
+pub fn now() -> SystemTime {
+    let now = SystemTime::now();
+    if !CORRECT.load(Ordering::Relaxed) {
+        return now;
+    }
+    let skew = SKEW.load(Ordering::Relaxed);
+    if skew >= 0 {
+        now + Duration::from_secs(skew as u64)
+    } else {
+        now - Duration::from_secs(skew.unsigned_abs())
+    }
+}
+
+/// Learns the skew from the `Date` header of an HTTP response
+pub fn observe_http_date(date: &str) {
+    if let Ok(date) = DateTime::parse_from_rfc2822(date) {
+        observe(date.with_timezone(&Utc), Utc::now());
+    }
+}
+
+fn observe(remote: DateTime<Utc>, local: DateTime<Utc>) {
+    let skew = (remote - local).num_seconds();
+    let skew = if skew.abs() < TOLERANCE { 0 } else { skew };
+    SKEW.store(skew, Ordering::Relaxed);
+
+    if skew.abs() >= WARN_SKEW {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "the system clock is {}s {} the time of the internet, VMess and \
+                 other protocols with timestamps may fail. {}",
+                skew.abs(),
+                if skew > 0 { "behind" } else { "ahead of" },
+                if CORRECT.load(Ordering::Relaxed) {
+                    "their timestamps are corrected"
+                } else {
+                    "set `experimental.correct-clock` to correct their timestamps"
+                }
+            );
+        }
+    } else if WARNED.swap(false, Ordering::Relaxed) {
+        info!("the system clock is in sync again");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::SystemTime};
+
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::{SKEW, now, observe, observe_http_date, set_correct};
+
+    #[test]
+    fn test_clock_skew() {
+        let local = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        observe(local + Duration::seconds(2), local);
+        assert_eq!(SKEW.load(Ordering::Relaxed), 0);
+
+        observe(local - Duration::seconds(300), local);
+        assert_eq!(SKEW.load(Ordering::Relaxed), -300);
+
+        set_correct(true);
+        let corrected = now();
+        set_correct(false);
+        let behind = SystemTime::now().duration_since(corrected).unwrap();
+        assert!(behind.as_secs() >= 299 && behind.as_secs() <= 301);
+
+        observe_http_date(&Utc::now().to_rfc2822().replace("+0000", "GMT"));
+        assert_eq!(SKEW.load(Ordering::Relaxed), 0);
+    }
+}
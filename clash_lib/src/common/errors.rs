@@ -14,6 +14,133 @@ where
     io::Error::new(io::ErrorKind::Other, format!("{:?}", anyhow::anyhow!(err)))
 }
 
+/// Classified connection errors.
+/// They are carried inside `io::Error` so they can flow through the
+/// `io::Result` based proxy APIs, use `NetError::classify` to get them back.
+#[derive(thiserror::Error, Debug)]
+pub enum NetError {
+    #[error("dns error: {0}")]
+    Dns(String),
+    /// the name doesn't exist, e.g. NXDOMAIN, which won't change on a retry
+    #[error("dns error: {0}")]
+    NoRecord(String),
+    #[error("dial error: {0}")]
+    Dial(String),
+    #[error("handshake error: {0}")]
+    Handshake(String),
+    #[error("auth error: {0}")]
+    Auth(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    /// the proxy is fine but failed to reach the destination, e.g. a SOCKS5
+    /// reply of connection refused
+    #[error("remote error: {0}")]
+    Remote(String),
+}
+
+impl NetError {
+    pub fn dns<T: Into<String>>(msg: T) -> io::Error {
+        Self::Dns(msg.into()).into()
+    }
+
+    pub fn no_record<T: Into<String>>(msg: T) -> io::Error {
+        Self::NoRecord(msg.into()).into()
+    }
+
+    pub fn dial<T: Into<String>>(msg: T) -> io::Error {
+        Self::Dial(msg.into()).into()
+    }
+
+    pub fn handshake<T: Into<String>>(msg: T) -> io::Error {
+        Self::Handshake(msg.into()).into()
+    }
+
+    pub fn auth<T: Into<String>>(msg: T) -> io::Error {
+        Self::Auth(msg.into()).into()
+    }
+
+    pub fn protocol<T: Into<String>>(msg: T) -> io::Error {
+        Self::Protocol(msg.into()).into()
+    }
+
+    pub fn remote<T: Into<String>>(msg: T) -> io::Error {
+        Self::Remote(msg.into()).into()
+    }
+
+    /// the short name of the error class, for logs and API responses
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NetError::Dns(_) | NetError::NoRecord(_) => "dns",
+            NetError::Dial(_) => "dial",
+            NetError::Handshake(_) => "handshake",
+            NetError::Auth(_) => "auth",
+            NetError::Protocol(_) => "protocol",
+            NetError::Remote(_) => "remote",
+        }
+    }
+
+    /// whether trying again, possibly via another proxy, may succeed.
+    /// auth and protocol errors are deterministic so they are not retried,
+    /// nor a name that doesn't exist or a destination the proxy can't reach.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            NetError::Dns(_) | NetError::Dial(_) | NetError::Handshake(_)
+        )
+    }
+
+    pub fn classify(err: &io::Error) -> Option<&NetError> {
+        err.get_ref().and_then(|e| e.downcast_ref::<NetError>())
+    }
+
+    /// whether the proxy itself failed, rather than the destination behind
+    /// it, e.g. the server is down or rejects the handshake
+    pub fn is_proxy_fault(&self) -> bool {
+        !matches!(self, NetError::Remote(_))
+    }
+}
+
+impl From<NetError> for io::Error {
+    fn from(err: NetError) -> Self {
+        let kind = match err {
+            NetError::Auth(_) => io::ErrorKind::PermissionDenied,
+            NetError::Protocol(_) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Whether an error returned while establishing a connection is worth
+/// retrying. Errors not created from `NetError` are judged by their kind.
+pub fn is_retryable(err: &io::Error) -> bool {
+    match NetError::classify(err) {
+        Some(e) => e.is_retryable(),
+        None => matches!(
+            err.kind(),
+            io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::Interrupted
+        ),
+    }
+}
+
+/// Whether an error returned while connecting through a proxy is the proxy's
+/// fault, and so counts against it. Errors not created from `NetError` can't
+/// tell the proxy from the destination, so they don't.
+pub fn is_proxy_fault(err: &io::Error) -> bool {
+    NetError::classify(err).is_some_and(|e| e.is_proxy_fault())
+}
+
+/// The error class name of an io error, `"io"` if it's not a `NetError`
+pub fn error_kind(err: &io::Error) -> &'static str {
+    NetError::classify(err).map(|e| e.kind()).unwrap_or("io")
+}
+
 #[macro_export]
 macro_rules! print_and_exit {
     ($($arg:tt)*) => {{
@@ -21,3 +148,45 @@ macro_rules! print_and_exit {
         std::process::exit(1);
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{NetError, error_kind, is_proxy_fault, is_retryable, new_io_error};
+
+    #[test]
+    fn test_classify() {
+        let e = NetError::auth("bad password");
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(error_kind(&e), "auth");
+        assert!(!is_retryable(&e));
+        assert_eq!(e.to_string(), "auth error: bad password");
+
+        let e = NetError::dns("timed out");
+        assert!(is_retryable(&e));
+        assert!(matches!(NetError::classify(&e), Some(NetError::Dns(_))));
+
+        let e = NetError::no_record("no record for a.example.com");
+        assert_eq!(error_kind(&e), "dns");
+        assert!(!is_retryable(&e));
+
+        let e = NetError::dial("connection refused");
+        assert!(is_retryable(&e));
+        assert!(is_proxy_fault(&e));
+
+        let e = NetError::remote("SOCKS5 request failed with connection refused");
+        assert_eq!(error_kind(&e), "remote");
+        assert!(!is_retryable(&e));
+        assert!(!is_proxy_fault(&e));
+
+        let e = new_io_error("whatever");
+        assert!(NetError::classify(&e).is_none());
+        assert_eq!(error_kind(&e), "io");
+        assert!(!is_retryable(&e));
+        assert!(!is_proxy_fault(&e));
+
+        let e = io::Error::from(io::ErrorKind::TimedOut);
+        assert!(is_retryable(&e));
+    }
+}
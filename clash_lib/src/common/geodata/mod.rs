@@ -11,6 +11,7 @@ pub(crate) mod geodata_proto {
 
 pub struct GeoData {
     cache: geodata_proto::GeoSiteList,
+    compact_tries: bool,
 }
 
 impl GeoData {
@@ -46,7 +47,10 @@ impl GeoData {
             geodata_proto::GeoSiteList::decode(bytes.as_slice()).map_err(|x| {
                 Error::InvalidConfig(format!("geosite decode failed: {}", x))
             })?;
-        Ok(Self { cache })
+        Ok(Self {
+            cache,
+            compact_tries: false,
+        })
     }
 
     #[cfg(test)]
@@ -56,7 +60,20 @@ impl GeoData {
             geodata_proto::GeoSiteList::decode(bytes.as_slice()).map_err(|x| {
                 Error::InvalidConfig(format!("geosite decode failed: {}", x))
             })?;
-        Ok(Self { cache })
+        Ok(Self {
+            cache,
+            compact_tries: false,
+        })
+    }
+
+    /// matchers built from this store their domains as succinct sets
+    pub fn with_compact_tries(mut self, compact_tries: bool) -> Self {
+        self.compact_tries = compact_tries;
+        self
+    }
+
+    pub fn compact_tries(&self) -> bool {
+        self.compact_tries
     }
 
     pub fn get(&self, list: &str) -> Option<&geodata_proto::GeoSite> {
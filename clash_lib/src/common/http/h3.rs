@@ -0,0 +1,89 @@
+//! A small HTTP/3 client for the health checks and DNS over HTTP/3, on any
+//! [`AsyncUdpSocket`] so it also works through a proxy.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use h3::client::SendRequest;
+use h3_quinn::OpenStreams;
+use quinn::{AsyncUdpSocket, TokioRuntime, crypto::rustls::QuicClientConfig};
+use tracing::trace;
+
+use crate::common::tls::GLOBAL_ROOT_STORE;
+
+pub struct H3Connection {
+    conn: quinn::Connection,
+    sender: SendRequest<OpenStreams, Bytes>,
+}
+
+impl H3Connection {
+    /// verifies the server with the global root store
+    pub fn tls_config() -> rustls::ClientConfig {
+        rustls::ClientConfig::builder()
+            .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+            .with_no_client_auth()
+    }
+
+    /// the ALPN of `tls_config` is set to `h3`
+    pub async fn connect(
+        socket: Arc<dyn AsyncUdpSocket>,
+        server: SocketAddr,
+        server_name: &str,
+        mut tls_config: rustls::ClientConfig,
+    ) -> anyhow::Result<Self> {
+        tls_config.alpn_protocols = vec![b"h3".to_vec()];
+        let quic_config: QuicClientConfig = tls_config.try_into()?;
+
+        let mut ep = quinn::Endpoint::new_with_abstract_socket(
+            quinn::EndpointConfig::default(),
+            None,
+            socket,
+            Arc::new(TokioRuntime),
+        )?;
+        ep.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quic_config,
+        )));
+
+        let conn = ep.connect(server, server_name)?.await?;
+        let (mut driver, sender) = h3::client::builder()
+            .build::<_, _, Bytes>(h3_quinn::Connection::new(conn.clone()))
+            .await?;
+        tokio::spawn(async move {
+            let _ = futures::future::poll_fn(|cx| driver.poll_close(cx)).await;
+            trace!("h3 connection to {} closed", server);
+        });
+
+        Ok(Self { conn, sender })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.conn.close_reason().is_some()
+    }
+
+    /// sends `req` with `body` and reads the whole response
+    pub async fn send(
+        &self,
+        req: http::Request<()>,
+        body: Option<Bytes>,
+    ) -> anyhow::Result<http::Response<Bytes>> {
+        let mut sender = self.sender.clone();
+        let mut stream = sender.send_request(req).await?;
+        if let Some(body) = body {
+            stream.send_data(body).await?;
+        }
+        stream.finish().await?;
+
+        let resp = stream.recv_response().await?;
+        let mut body = BytesMut::new();
+        while let Some(chunk) = stream.recv_data().await? {
+            body.put(chunk);
+        }
+        Ok(resp.map(|_| body.freeze()))
+    }
+}
+
+impl Drop for H3Connection {
+    fn drop(&mut self) {
+        self.conn.close(0u32.into(), b"");
+    }
+}
@@ -1,4 +1,5 @@
 pub mod client;
+pub mod h3;
 pub mod hyper;
 
 pub use client::*;
@@ -338,7 +338,7 @@ where
 
 pub async fn copy_bidirectional(
     mut a: Box<dyn ClientStream>,
-    mut b: TrackedStream,
+    b: &mut TrackedStream,
     size: usize,
     a_to_b_timeout_duration: Duration,
     b_to_a_timeout_duration: Duration,
@@ -374,7 +374,7 @@ pub async fn copy_bidirectional(
             _ => {
                 copy_buf_bidirectional_with_timeout(
                     &mut a,
-                    &mut b,
+                    &mut *b,
                     size,
                     a_to_b_timeout_duration,
                     b_to_a_timeout_duration,
@@ -387,7 +387,7 @@ pub async fn copy_bidirectional(
     {
         copy_buf_bidirectional_with_timeout(
             &mut a,
-            &mut b,
+            &mut *b,
             size,
             a_to_b_timeout_duration,
             b_to_a_timeout_duration,
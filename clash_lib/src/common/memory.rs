@@ -0,0 +1,126 @@
+//! Memory figures beyond the resident size for `/memory`: the limit of the
+//! cgroup clash runs in, e.g. of a container on a router, and the allocated
+//! bytes as the allocator counts them, if the binary registers its allocator.
+
+use std::sync::OnceLock;
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct AllocatorStats {
+    /// bytes allocated by the application
+    pub allocated: usize,
+    /// bytes of the pages the allocator keeps resident, including its own
+    /// metadata and the freed pages it holds on to
+    pub resident: usize,
+}
+
+/// How the binary reads the stats of its global allocator
+pub type AllocatorStatsFn = fn() -> Option<AllocatorStats>;
+
+static ALLOCATOR_STATS: OnceLock<AllocatorStatsFn> = OnceLock::new();
+
+/// Registers the stats of the global allocator, once
+pub fn set_allocator_stats(f: AllocatorStatsFn) {
+    let _ = ALLOCATOR_STATS.set(f);
+}
+
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    ALLOCATOR_STATS.get().and_then(|f| f())
+}
+
+/// bytes the cgroup of the process may use, 0 without a limit
+#[cfg(target_os = "linux")]
+pub fn os_limit() -> usize {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    cgroup_limit(&cgroups, |x| std::fs::read_to_string(x).ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn os_limit() -> usize {
+    0
+}
+
+/// The smallest limit of the memory cgroup listed in `cgroups`, the content
+/// of /proc/self/cgroup, and of its parents. A v2 line is `0::/path`, a v1
+/// line has `memory` among its controllers. A path of the host that isn't
+/// mounted in a container falls back to the root of the mount.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn cgroup_limit(cgroups: &str, read: impl Fn(&str) -> Option<String>) -> usize {
+    for line in cgroups.lines() {
+        let mut parts = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(path)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let (mount, file) = match controllers {
+            "" => ("/sys/fs/cgroup", "memory.max"),
+            x if x.split(',').any(|x| x == "memory") => {
+                ("/sys/fs/cgroup/memory", "memory.limit_in_bytes")
+            }
+            _ => continue,
+        };
+        let limit = std::iter::successors(Some(path.trim_end_matches('/')), |x| {
+            x.rfind('/').map(|i| &x[..i])
+        })
+        .filter_map(|dir| read(&format!("{}{}/{}", mount, dir, file)))
+        .map(|x| parse_limit(&x))
+        .filter(|x| *x > 0)
+        .min();
+        if let Some(limit) = limit {
+            return limit;
+        }
+    }
+    0
+}
+
+/// `max` is unlimited in v2, a page-aligned `i64::MAX` in v1
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_limit(s: &str) -> usize {
+    match s.trim().parse::<u64>() {
+        Ok(x) if x < 1 << 62 => x as usize,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{cgroup_limit, parse_limit};
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!(parse_limit("536870912\n"), 536870912);
+        assert_eq!(parse_limit("max\n"), 0);
+        assert_eq!(parse_limit("9223372036854771712\n"), 0);
+    }
+
+    #[test]
+    fn test_cgroup_limit() {
+        let files = HashMap::from([
+            ("/sys/fs/cgroup/memory.max", "max\n"),
+            ("/sys/fs/cgroup/system.slice/memory.max", "1073741824\n"),
+            (
+                "/sys/fs/cgroup/system.slice/clash.service/memory.max",
+                "max\n",
+            ),
+            ("/sys/fs/cgroup/memory/memory.limit_in_bytes", "268435456\n"),
+        ]);
+        let read = |x: &str| files.get(x).map(|x| x.to_string());
+
+        // a parent's limit applies
+        assert_eq!(
+            cgroup_limit("0::/system.slice/clash.service\n", read),
+            1073741824
+        );
+        // v1, the host path isn't mounted
+        assert_eq!(
+            cgroup_limit("5:cpu,cpuacct:/docker/abc\n4:memory:/docker/abc\n", read),
+            268435456
+        );
+        assert_eq!(cgroup_limit("0::/\n", read), 0);
+        assert_eq!(cgroup_limit("", read), 0);
+    }
+}
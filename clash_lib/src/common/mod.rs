@@ -1,11 +1,15 @@
 pub mod auth;
+pub mod build_info;
+pub mod clock;
 pub mod crypto;
 pub mod defer;
 pub mod errors;
 pub mod geodata;
 pub mod http;
 pub mod io;
+pub mod memory;
 pub mod mmdb;
+pub mod pcapng;
 pub mod succinct_set;
 pub mod timed_future;
 pub mod tls;
@@ -0,0 +1,317 @@
+//! A minimal pcapng writer, with TCP/IP headers made up around stream
+//! payloads so the captured streams can be followed in Wireshark.
+
+use std::{
+    io::{self, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+/// raw IPv4/IPv6 packets without a link layer header
+const LINKTYPE_RAW: u16 = 101;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+
+/// payload bytes per made up segment, keeps an IPv4 packet under 64K
+const MAX_SEGMENT: usize = 32 * 1024;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+pub struct PcapngWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// writes the section header and the only interface
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // section length not specified
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut inner, BLOCK_SECTION_HEADER, &body)?;
+
+        let mut body = Vec::with_capacity(8);
+        body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // no snap length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut inner, BLOCK_INTERFACE_DESCRIPTION, &body)?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn write_packet(
+        &mut self,
+        time: SystemTime,
+        packet: &[u8],
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        // microseconds, the default resolution
+        let ts = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut body = Vec::with_capacity(packet.len() + 32);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        pad(&mut body);
+        if let Some(comment) = comment {
+            body.extend_from_slice(&OPT_COMMENT.to_le_bytes());
+            body.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+            body.extend_from_slice(comment.as_bytes());
+            pad(&mut body);
+            body.extend_from_slice(&OPT_END.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+        }
+        write_block(&mut self.inner, BLOCK_ENHANCED_PACKET, &body)
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn write_block<W: Write>(w: &mut W, typ: u32, body: &[u8]) -> io::Result<()> {
+    let len = (body.len() + 12) as u32;
+    w.write_all(&typ.to_le_bytes())?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(body)?;
+    w.write_all(&len.to_le_bytes())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// client to server
+    Up,
+    /// server to client
+    Down,
+}
+
+/// Made up TCP packets of one connection
+pub struct TcpFlow {
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl TcpFlow {
+    /// an IPv4 address is mapped to IPv6 if the other side is IPv6
+    pub fn new(client: SocketAddr, server: SocketAddr) -> Self {
+        let (client, server) = match (client.ip(), server.ip()) {
+            (IpAddr::V4(c), IpAddr::V6(_)) => (
+                SocketAddr::new(c.to_ipv6_mapped().into(), client.port()),
+                server,
+            ),
+            (IpAddr::V6(_), IpAddr::V4(s)) => (
+                client,
+                SocketAddr::new(s.to_ipv6_mapped().into(), server.port()),
+            ),
+            _ => (client, server),
+        };
+        Self {
+            client,
+            server,
+            client_seq: 0,
+            server_seq: 0,
+        }
+    }
+
+    pub fn handshake(&mut self) -> [Vec<u8>; 3] {
+        let syn = self.packet(Direction::Up, TCP_SYN, &[]);
+        self.client_seq = self.client_seq.wrapping_add(1);
+        let syn_ack = self.packet(Direction::Down, TCP_SYN | TCP_ACK, &[]);
+        self.server_seq = self.server_seq.wrapping_add(1);
+        let ack = self.packet(Direction::Up, TCP_ACK, &[]);
+        [syn, syn_ack, ack]
+    }
+
+    pub fn data(&mut self, dir: Direction, payload: &[u8]) -> Vec<Vec<u8>> {
+        payload
+            .chunks(MAX_SEGMENT)
+            .map(|chunk| {
+                let p = self.packet(dir, TCP_PSH | TCP_ACK, chunk);
+                self.advance(dir, chunk.len() as u32);
+                p
+            })
+            .collect()
+    }
+
+    pub fn fin(&mut self, dir: Direction) -> Vec<u8> {
+        let p = self.packet(dir, TCP_FIN | TCP_ACK, &[]);
+        self.advance(dir, 1);
+        p
+    }
+
+    fn advance(&mut self, dir: Direction, n: u32) {
+        match dir {
+            Direction::Up => self.client_seq = self.client_seq.wrapping_add(n),
+            Direction::Down => self.server_seq = self.server_seq.wrapping_add(n),
+        }
+    }
+
+    fn packet(&self, dir: Direction, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, seq, ack) = match dir {
+            Direction::Up => {
+                (self.client, self.server, self.client_seq, self.server_seq)
+            }
+            Direction::Down => {
+                (self.server, self.client, self.server_seq, self.client_seq)
+            }
+        };
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&src.port().to_be_bytes());
+        tcp.extend_from_slice(&dst.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.push(5 << 4);
+        tcp.push(flags);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+
+        let mut pseudo = Vec::with_capacity(40 + tcp.len());
+        let mut ip = Vec::with_capacity(40 + tcp.len());
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                pseudo.extend_from_slice(&s.octets());
+                pseudo.extend_from_slice(&d.octets());
+                pseudo.extend_from_slice(&[0, 6]);
+                pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+
+                ip.extend_from_slice(&[0x45, 0]);
+                ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+                // id, don't fragment, ttl 64, tcp
+                ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+                ip.extend_from_slice(&s.octets());
+                ip.extend_from_slice(&d.octets());
+                let sum = checksum(&ip);
+                ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            (s, d) => {
+                let s = to_v6(s);
+                let d = to_v6(d);
+                pseudo.extend_from_slice(&s.octets());
+                pseudo.extend_from_slice(&d.octets());
+                pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+                pseudo.extend_from_slice(&[0, 0, 0, 6]);
+
+                ip.extend_from_slice(&[0x60, 0, 0, 0]);
+                ip.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+                ip.extend_from_slice(&[6, 64]);
+                ip.extend_from_slice(&s.octets());
+                ip.extend_from_slice(&d.octets());
+            }
+        }
+
+        pseudo.extend_from_slice(&tcp);
+        let sum = checksum(&pseudo);
+        tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+        ip.extend_from_slice(&tcp);
+        ip
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// the internet checksum
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|x| u16::from_be_bytes([x[0], *x.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::{Direction, PcapngWriter, TcpFlow, checksum};
+
+    #[test]
+    fn test_pcapng_blocks() {
+        let mut w = PcapngWriter::new(Vec::new()).unwrap();
+        w.write_packet(SystemTime::now(), &[1, 2, 3], Some("hello"))
+            .unwrap();
+        let buf = w.get_mut();
+
+        assert_eq!(&buf[0..4], &0x0A0D0D0Au32.to_le_bytes());
+        assert_eq!(&buf[8..12], &0x1A2B3C4Du32.to_le_bytes());
+
+        // every block starts and ends with its length
+        let mut offset = 0;
+        let mut blocks = 0;
+        while offset < buf.len() {
+            let len =
+                u32::from_le_bytes(buf[offset + 4..offset + 8].try_into().unwrap())
+                    as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(
+                &buf[offset + 4..offset + 8],
+                &buf[offset + len - 4..offset + len]
+            );
+            offset += len;
+            blocks += 1;
+        }
+        assert_eq!(offset, buf.len());
+        assert_eq!(blocks, 3);
+    }
+
+    #[test]
+    fn test_tcp_flow() {
+        let mut flow = TcpFlow::new(
+            "192.168.1.2:50000".parse().unwrap(),
+            "1.1.1.1:443".parse().unwrap(),
+        );
+        let [syn, syn_ack, _] = flow.handshake();
+        assert_eq!(syn[0] >> 4, 4);
+        assert_eq!(syn[20 + 13], 0x02);
+        assert_eq!(syn_ack[20 + 13], 0x12);
+        // a header with its checksum in place sums up to 0
+        assert_eq!(checksum(&syn[..20]), 0);
+
+        let packets = flow.data(Direction::Up, &vec![0u8; 40 * 1024]);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].len(), 20 + 20 + 32 * 1024);
+        let seq = u32::from_be_bytes(packets[1][24..28].try_into().unwrap());
+        assert_eq!(seq, 1 + 32 * 1024);
+
+        let mut flow = TcpFlow::new(
+            "192.168.1.2:50000".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+        );
+        let fin = flow.fin(Direction::Down);
+        assert_eq!(fin[0] >> 4, 6);
+        assert_eq!(fin.len(), 40 + 20);
+    }
+}
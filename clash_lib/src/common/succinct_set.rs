@@ -223,6 +223,94 @@ impl DomainSet {
     }
 }
 
+impl DomainSet {
+    /// Appends the set as is to `out`, for the compiled rule sets, which
+    /// load without building the set again
+    pub fn write_bin(&self, out: &mut Vec<u8>) {
+        write_words(out, &self.leaves);
+        write_words(out, &self.label_bit_map);
+        out.extend_from_slice(&(self.labels.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.labels);
+    }
+
+    /// Reads a set written by `write_bin` off the front of `buf`
+    pub fn read_bin(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        let mut leaves = read_words(buf)?;
+        let label_bit_map = read_words(buf)?;
+        let len = read_len(buf, 1)?;
+        let (labels, rest) = buf.split_at(len);
+        let labels = labels.to_vec();
+        *buf = rest;
+
+        // a one ends each node and a zero is each label, with nothing after
+        // the last node, or the lookups would index out of the labels
+        let nodes = label_bit_map
+            .iter()
+            .map(|x| x.count_ones() as usize)
+            .sum::<usize>();
+        let bits = nodes + labels.len();
+        ensure!(
+            nodes > 0
+                && bits <= label_bit_map.len() << 6
+                && get_bit(&label_bit_map, bits as isize - 1)
+                && label_bit_map[bits >> 6..]
+                    .iter()
+                    .enumerate()
+                    .all(|(i, w)| (i == 0 && w >> (bits & 63) == 0) || *w == 0),
+            "invalid domain set"
+        );
+        // every label leads to a node after the one it's in, the root being
+        // the only node no label leads to
+        ensure!(labels.len() + 1 == nodes, "invalid domain set");
+        let mut ones = 0;
+        for i in 0..bits {
+            if get_bit(&label_bit_map, i as isize) {
+                ones += 1;
+            } else {
+                ensure!(i + 1 - ones > ones, "invalid domain set");
+            }
+        }
+        // the leaf bit of any node is looked up
+        leaves.resize(leaves.len().max(nodes.div_ceil(64)), 0);
+
+        let mut rv = Self {
+            leaves,
+            label_bit_map,
+            labels,
+            ..Default::default()
+        };
+        rv.init();
+        Ok(rv)
+    }
+}
+
+fn write_words(out: &mut Vec<u8>, words: &[u64]) {
+    out.extend_from_slice(&(words.len() as u64).to_le_bytes());
+    for w in words {
+        out.extend_from_slice(&w.to_le_bytes());
+    }
+}
+
+/// a length prefix of items of `size` bytes, which `buf` must hold
+fn read_len(buf: &mut &[u8], size: usize) -> anyhow::Result<usize> {
+    ensure!(buf.len() >= 8, "truncated domain set");
+    let (len, rest) = buf.split_at(8);
+    let len = u64::from_le_bytes(len.try_into().unwrap());
+    *buf = rest;
+    ensure!(len <= (buf.len() / size) as u64, "truncated domain set");
+    Ok(len as usize)
+}
+
+fn read_words(buf: &mut &[u8]) -> anyhow::Result<Vec<u64>> {
+    let len = read_len(buf, 8)?;
+    let (words, rest) = buf.split_at(len << 3);
+    *buf = rest;
+    Ok(words
+        .chunks_exact(8)
+        .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+        .collect())
+}
+
 struct QElt {
     s: usize,
     e: usize,
@@ -230,8 +318,6 @@ struct QElt {
 }
 
 /// Convert a `StringTrie` to a `DomainSet`.
-/// TODO: support loading from a binary file.
-/// e.g. the so called 'mrs' file in the MiHoMo project.
 impl<T> From<StringTrie<T>> for DomainSet {
     fn from(value: StringTrie<T>) -> Self {
         let mut keys = vec![];
@@ -411,6 +497,44 @@ mod tests {
         test_dump(&key_src, &set);
     }
 
+    #[test]
+    fn test_domain_set_bin() {
+        let mut tree = super::StringTrie::new();
+        for d in ["google.com", "+.cn", "*.apple.com"] {
+            tree.insert(d, Arc::new(true));
+        }
+        let set = super::DomainSet::from(tree);
+        let mut out = vec![];
+        set.write_bin(&mut out);
+
+        let mut buf = &out[..];
+        let read = super::DomainSet::read_bin(&mut buf).unwrap();
+        assert!(buf.is_empty());
+        assert!(read.has("google.com"));
+        assert!(read.has("test.cn"));
+        assert!(read.has("www.apple.com"));
+        assert!(!read.has("apple.com"));
+
+        assert!(super::DomainSet::read_bin(&mut &out[..out.len() - 1]).is_err());
+
+        // a single node with the labels of five, which a lookup would read
+        // past
+        let mut bad = vec![];
+        super::write_words(&mut bad, &[0]);
+        super::write_words(&mut bad, &[0b100000]);
+        bad.extend_from_slice(&5u64.to_le_bytes());
+        bad.extend_from_slice(b"aaaaa");
+        assert!(super::DomainSet::read_bin(&mut &bad[..]).is_err());
+
+        // a label of the second node leading to itself
+        let mut bad = vec![];
+        super::write_words(&mut bad, &[0]);
+        super::write_words(&mut bad, &[0b101]);
+        bad.extend_from_slice(&1u64.to_le_bytes());
+        bad.extend_from_slice(b"a");
+        assert!(super::DomainSet::read_bin(&mut &bad[..]).is_err());
+    }
+
     fn test_dump(data_src: &Vec<String>, set: &super::DomainSet) {
         let mut data_set = vec![];
         set.traverse(|key| {
@@ -1,12 +1,18 @@
 use once_cell::sync::Lazy;
 use rustls::{
-    RootCertStore,
+    RootCertStore, ServerConfig,
     client::{WebPkiServerVerifier, danger::ServerCertVerifier},
-    pki_types::{CertificateDer, ServerName, UnixTime},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
 };
 use tracing::warn;
 
-use std::sync::Arc;
+use std::{fs::File, io, io::BufReader, path::Path, sync::Arc, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use crate::common::errors::new_io_error;
 
 pub static GLOBAL_ROOT_STORE: Lazy<Arc<RootCertStore>> =
     Lazy::new(global_root_store);
@@ -153,3 +159,104 @@ impl ServerCertVerifier for NoHostnameTlsVerifier {
         self.0.supported_verify_schemes()
     }
 }
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(new_io_error(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?.ok_or_else(
+        || new_io_error(format!("no private key found in {}", path.display())),
+    )
+}
+
+/// TLS server config for the inbounds, clients must present a certificate
+/// signed by `client_ca` if it's set
+pub fn new_server_config(
+    certificate: &Path,
+    private_key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<ServerConfig> {
+    let builder = match client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert).map_err(new_io_error)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(new_io_error)?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(load_certs(certificate)?, load_private_key(private_key)?)
+        .map_err(new_io_error)
+}
+
+/// how long the clients of the TLS inbounds have to finish the handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts a client of a TLS inbound, one that doesn't finish the handshake
+/// in `HANDSHAKE_TIMEOUT` is dropped
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    acceptor: &TlsAcceptor,
+    socket: S,
+) -> io::Result<TlsStream<S>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+        .await
+        .map_err(|_| {
+            io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+        })?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use tokio_rustls::TlsAcceptor;
+
+    use super::{accept, new_server_config};
+
+    fn config_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../clash/tests/data/config")
+    }
+
+    #[test]
+    fn test_new_server_config() {
+        let dir = config_dir();
+        let cert = dir.join("example.org.pem");
+        let key = dir.join("example.org-key.pem");
+
+        assert!(new_server_config(&cert, &key, None).is_ok());
+        // the self-signed certificate serves as its own CA
+        assert!(new_server_config(&cert, &key, Some(&cert)).is_ok());
+        assert!(new_server_config(&key, &cert, None).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accept_timeout() {
+        let dir = config_dir();
+        let cfg = new_server_config(
+            &dir.join("example.org.pem"),
+            &dir.join("example.org-key.pem"),
+            None,
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(cfg));
+
+        // a client that never says hello
+        let (_client, server) = tokio::io::duplex(1024);
+        let err = accept(&acceptor, server).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
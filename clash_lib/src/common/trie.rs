@@ -1,4 +1,6 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, mem, sync::Arc};
+
+use serde::Serialize;
 
 static DOMAIN_STEP: &str = ".";
 static COMPLEX_WILDCARD: &str = "+";
@@ -48,6 +50,15 @@ impl<T> Node<T> {
         &self.children
     }
 }
+
+/// The size of a trie, the bytes are approximated from the capacities of the
+/// nodes and their keys, the data shared with `Arc`s aren't counted
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct TrieStats {
+    pub nodes: usize,
+    pub bytes: usize,
+}
+
 pub struct StringTrie<T> {
     root: Node<T>,
 }
@@ -83,6 +94,37 @@ impl<T> StringTrie<T> {
         true
     }
 
+    pub fn stats(&self) -> TrieStats {
+        let mut stats = TrieStats::default();
+        Self::stats_inner(&self.root, &mut stats);
+        stats.bytes += mem::size_of::<Node<T>>();
+        stats
+    }
+
+    fn stats_inner(node: &Node<T>, stats: &mut TrieStats) {
+        stats.nodes += 1;
+        // a slot of the map is a key, a node and a control byte
+        stats.bytes +=
+            node.children.capacity() * (mem::size_of::<(String, Node<T>)>() + 1);
+        for (key, child) in &node.children {
+            stats.bytes += key.capacity();
+            Self::stats_inner(child, stats);
+        }
+    }
+
+    /// Shrinks the maps of the nodes to fit, to be called once the trie is
+    /// loaded as they are grown by doubling
+    pub fn shrink(&mut self) {
+        Self::shrink_inner(&mut self.root);
+    }
+
+    fn shrink_inner(node: &mut Node<T>) {
+        node.children.shrink_to_fit();
+        for child in node.children.values_mut() {
+            Self::shrink_inner(child);
+        }
+    }
+
     pub fn search(&self, domain: &str) -> Option<&Node<T>> {
         let (parts, valid) = valid_and_split_domain(domain);
         if !valid {
@@ -218,7 +260,7 @@ pub fn valid_and_split_domain(domain: &str) -> (Option<Vec<&str>>, bool) {
 mod tests {
     use std::{net::Ipv4Addr, sync::Arc};
 
-    use crate::common::trie::StringTrie;
+    use crate::common::trie::{StringTrie, TrieStats};
 
     static LOCAL_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
 
@@ -240,6 +282,26 @@ mod tests {
         assert!(tree.search("www.google.com").is_none());
     }
 
+    #[test]
+    fn test_stats_and_shrink() {
+        let mut tree = StringTrie::new();
+        assert_eq!(tree.stats().nodes, 1);
+
+        for i in 0..100 {
+            tree.insert(&format!("host{}.example.com", i), Arc::new(LOCAL_IP));
+        }
+        // the root, com, example and the hosts
+        let before = tree.stats();
+        assert_eq!(before.nodes, 103);
+
+        tree.shrink();
+        let after = tree.stats();
+        assert_eq!(after.nodes, before.nodes);
+        assert!(after.bytes <= before.bytes);
+        assert_ne!(after, TrieStats::default());
+        assert!(tree.search("host42.example.com").is_some());
+    }
+
     #[test]
     fn test_wildcard() {
         let mut tree = StringTrie::new();
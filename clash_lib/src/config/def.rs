@@ -8,7 +8,10 @@ use serde_yaml::Value;
 const DEFAULT_SO_MARK: u32 = 3389;
 const DEFAULT_ROUTE_TABLE: u32 = 2468;
 
-use super::config::BindAddress;
+use super::{
+    config::BindAddress,
+    proxy::{HealthCheckSettings, TlsFragmentOpt},
+};
 
 fn default_tun_device_id() -> String {
     "utun1989".to_string()
@@ -59,6 +62,9 @@ pub struct TunConfig {
     pub routes: Option<Vec<String>>,
     #[serde(default)]
     pub route_all: bool,
+    /// MTU of the tun interface, 1500 by default and 65535 on Windows.
+    /// larger packets to the tun interface are fragmented and the MSS of TCP
+    /// connections is clamped to fit
     pub mtu: Option<u16>,
     /// fwmark on Linux only
     #[serde(default = "default_tun_so_mark")]
@@ -70,6 +76,26 @@ pub struct TunConfig {
     /// setting to a list has the same effect as setting to true
     #[serde(default)]
     pub dns_hijack: DnsHijack,
+    /// TCP connections are accepted right away and reset if the remote can't
+    /// be connected. If set to true, the SYN is only answered once the remote
+    /// is connected, which is slower but refuses the connection if it fails
+    #[serde(default)]
+    pub strict_tcp_handshake: bool,
+}
+
+/// Tells the protocol of connections from the first bytes, for the `PROTOCOL`
+/// rules. UDP to port 443 to an ip is routed by the server name in its QUIC
+/// Initial packets, and still sent to the ip
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sniffer {
+    /// TCP connections wait up to 100ms for the client to send something, so
+    /// those where the server speaks first are delayed by that. Connections
+    /// from the TUN device with `strict-tcp-handshake` aren't sniffed. The
+    /// `FORCE-SNIFF` and `SKIP-SNIFF` rules override it for the sessions they
+    /// match
+    #[serde(default)]
+    pub enable: bool,
 }
 
 #[derive(Serialize, Deserialize, Default, Copy, Clone)]
@@ -94,6 +120,52 @@ impl Display for RunMode {
     }
 }
 
+/// How much memory is traded for speed
+#[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryProfile {
+    /// for small devices like routers
+    Low,
+    #[default]
+    Default,
+    High,
+}
+
+impl MemoryProfile {
+    /// buffer size of each direction of a relayed tcp connection
+    pub fn tcp_buffer_size(&self) -> usize {
+        match self {
+            MemoryProfile::Low => 4 * 1024,
+            MemoryProfile::Default => 16 * 1024,
+            MemoryProfile::High => 64 * 1024,
+        }
+    }
+
+    /// max number of cached dns answers
+    pub fn dns_cache_size(&self) -> usize {
+        match self {
+            MemoryProfile::Low => 512,
+            MemoryProfile::Default => 4096,
+            MemoryProfile::High => 16384,
+        }
+    }
+
+    /// number of closed connections kept
+    pub fn connection_history_size(&self) -> usize {
+        match self {
+            MemoryProfile::Low => 10,
+            MemoryProfile::Default => 100,
+            MemoryProfile::High => 500,
+        }
+    }
+
+    /// whether the geosite domain tries are compacted into succinct sets
+    /// when the rules are built, smaller but slower to look up
+    pub fn compact_tries(&self) -> bool {
+        matches!(self, MemoryProfile::Low)
+    }
+}
+
 #[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
@@ -157,14 +229,21 @@ impl Display for LogLevel {
 ///   #   - '*.lan'
 ///   #   - localhost.ptlogin2.qq.com
 ///
-///   # Supports UDP, TCP, DoT, DoH. You can specify the port to connect to.
+///   # Supports UDP, TCP, DoT, DoH, DoH3. You can specify the port to connect to.
 ///   # All DNS questions are sent directly to the nameserver, without proxies
 ///   # involved. Clash answers the DNS question with the first result gathered.
+///   # Each nameserver is probed every 30s, one failing 3 probes in a row is
+///   # left out while the others answer, see GET /dns/upstreams
 ///   nameserver:
 ///     - 114.114.114.114 # default value
 ///     - 1.1.1.1 # default value
 ///     - tls://1.1.1.1:853 # DNS over TLS
 ///     - https://1.1.1.1/dns-query # DNS over HTTPS
+///     - h3://1.1.1.1/dns-query # DNS over HTTP/3, or HTTP/2 without QUIC
+///     # not resolved with the default-nameserver, which would be blocked
+///     - https://dns.google/dns-query?ip=8.8.8.8
+///     # resolved with these instead of the default-nameserver
+///     - tls://dns.quad9.net?bootstrap=9.9.9.9,tls://149.112.112.112
 /// #    - dhcp://en0 # dns from dhcp
 ///
 /// allow-lan: true
@@ -198,6 +277,8 @@ impl Display for LogLevel {
 ///     proxies:
 ///       - DIRECT
 ///     url: "http://www.gstatic.com/generate_204"
+///     # h3://www.gstatic.com/generate_204 tests with HTTP/3 if the proxy
+///     # relays UDP, and with HTTPS if that fails or takes half the timeout
 ///     interval: 300
 ///
 ///   - name: "fallback-auto" type: fallback use:
@@ -206,17 +287,44 @@ impl Display for LogLevel {
 ///       - DIRECT
 ///     url: "http://www.gstatic.com/generate_204"
 ///     interval: 300
+///     # alive only on a 204 within 2s, redirects to another region fail
+///     timeout: 2000
+///     expected-status: 204
+///     # expected-keyword: "loc=JP"
+///     # the checks with a url or expected response of its own are kept
+///     # apart from the other checks of its `proxies`, the proxies of the
+///     # providers in `use` keep the results of their providers
+///     # the proxies not checked within the global `stale-after` are left out,
+///     # rather than tried after the alive ones
+///     unknown-nodes: skip
 ///
 ///   - name: "load-balance" type: load-balance use:
 ///       - "file-provider"
 ///     proxies:
 ///       - DIRECT
 ///     strategy: round-robin
+///     url: "https://www.gstatic.com/generate_204"
+///     # tcp times only the connection to the host of the url, tls its TLS
+///     # handshake too, for targets not serving HTTP. tcp is for DIRECT and
+///     # socks5, other proxies are checked with tls
+///     mode: tls
+///     interval: 300
+///
+///   # experimental: by the delay and failures of the connections through
+///   # each proxy and its bandwidth, a destination sticking to its proxy
+///   # while it keeps up, over restarts with the cache file
+///   - name: "smart" type: smart use:
+///       - "file-provider"
 ///     url: "http://www.gstatic.com/generate_204"
 ///     interval: 300
 ///
 ///   - name: select type: select use:
 ///       - "file-provider"
+///     # for the dashboards only: an image, hidden from the lists, and the
+///     # lower order first. proxies take them too
+///     icon: https://example.com/select.png
+///     hidden: false
+///     order: 1
 ///
 ///   - name: test 🌏 type: select use:
 ///       - "file-provider"
@@ -261,6 +369,15 @@ impl Display for LogLevel {
 ///       enable: true
 ///       url: http://www.gstatic.com/generate_204
 ///       interval: 300
+///     # url-test groups `file-provider-HK`, `file-provider-JP` and so on of
+///     # its proxies by region, told by their names or GeoIP. A region
+///     # without proxies is left empty
+///     region-groups:
+///       type: url-test
+///       regions: [HK, JP, SG, US]
+///       # over the flags, places and codes built in
+///       patterns:
+///         JP: "(?i)nrt|kix"
 ///
 /// rule-providers:
 ///   file-provider:
@@ -268,6 +385,11 @@ impl Display for LogLevel {
 ///     path: ./rule-set.yaml
 ///     interval: 300
 ///     behavior: domain
+///   compiled-provider:
+///     type: file
+///     # by `clash compile-ruleset`, told apart from YAML by its content
+///     path: ./rule-set.bin
+///     behavior: domain
 ///
 /// rules:
 ///   - DOMAIN,ipinfo.io,relay
@@ -280,6 +402,10 @@ impl Display for LogLevel {
 ///   - GEOIP,CN,DIRECT
 ///   - DST-PORT,53,trojan
 ///   - SRC-PORT,7777,DIRECT
+///   # sniffing on or off for the sessions matched by their addresses, ahead
+///   # of the routing rules
+///   - DST-PORT,8443,FORCE-SNIFF
+///   - SRC-IP-CIDR,192.168.2.0/24,SKIP-SNIFF
 ///   - MATCH, DIRECT
 /// ...
 /// ```
@@ -321,6 +447,15 @@ pub struct Config {
     /// Log level
     /// Either `debug`, `info`, `warning`, `error` or `off`
     pub log_level: LogLevel,
+    /// Trade memory for speed, either `low`, `default` or `high`.
+    /// Sets the relay buffer size, the DNS cache size, the connection history
+    /// size and how geosite rules are stored. `low` is meant for routers with
+    /// 128MB of memory. `experimental` settings take precedence
+    /// # Example
+    /// ```yaml
+    /// memory-profile: low
+    /// ```
+    pub memory_profile: MemoryProfile,
     /// DNS client/server settings
     pub dns: DNS,
     /// Profile settings
@@ -333,8 +468,37 @@ pub struct Config {
     pub proxy_group: Option<Vec<HashMap<String, Value>>>,
     #[serde(rename = "rules")]
     /// Rule settings
+    /// A `dst=host[:port]` param sends the matched connections to another
+    /// destination, e.g. `DOMAIN,example.com,PROXY,dst=10.0.0.1:443`
+    /// A `schedule=` param limits a rule to a time window, `SCHEDULE` matches
+    /// everything inside one, e.g.
+    /// `DOMAIN-SUFFIX,game.example.com,REJECT,schedule=Sun-Thu 21:00-07:00`,
+    /// `SCHEDULE,Mon-Fri 09:00-17:00 +08:00,DIRECT`. The weekdays, the time
+    /// of day and the UTC offset may each be left out, local time is used
+    /// without an offset
     pub rule: Option<Vec<String>>,
-    /// Hosts
+    /// named rule chains for the listeners with `rules` set, matched instead
+    /// of `rules`
+    /// # Example
+    /// ```yaml
+    /// sub-rules:
+    ///   torrent:
+    ///     - DOMAIN-SUFFIX,tracker.example.com,PROXY
+    ///     - MATCH,DIRECT
+    /// listeners:
+    ///   - name: socks-torrent
+    ///     type: socks
+    ///     port: 7892
+    ///     rules: torrent
+    /// ```
+    #[serde(rename = "sub-rules")]
+    pub sub_rules: Option<HashMap<String, Vec<String>>>,
+    /// Hosts, a domain or a wildcard like `*.example.com` maps to an IP, or
+    /// to another domain which is then resolved in its place, e.g.
+    /// `dev.example.com: internal-lb.corp`. It may map to `rule-set:<name>`
+    /// instead, a rule provider of the `hosts` behavior, whose `domain,target`
+    /// lines are looked up for the names the entry covers, e.g.
+    /// `+.corp.example.com: rule-set:corp-zone`
     pub hosts: HashMap<String, String>,
     /// Country database path relative to the $CWD
     #[educe(Default = "Country.mmdb")]
@@ -343,7 +507,8 @@ pub struct Config {
     // TODO not compatiable with clash-meta
     #[educe(Default = Some("https://github.com/Loyalsoldier/geoip/releases/download/202307271745/Country.mmdb".into()))]
     pub mmdb_download_url: Option<String>,
-    /// Optional ASN database path relative to the working dir
+    /// Optional ASN database path relative to the working dir, for the
+    /// `IP-ASN` rules, e.g. `IP-ASN,13335,PROXY` for Cloudflare
     #[educe(Default = "Country-asn.mmdb")]
     pub asn_mmdb: String,
     /// Optional ASN database download url
@@ -361,12 +526,55 @@ pub struct Config {
     /// this will affect the DNS server response to AAAA questions
     /// default is `false`
     pub ipv6: bool,
+    /// NAT64 prefix to reach IPv4 destinations with on an IPv6-only
+    /// network, looked up with DNS64 when not set
+    /// # Example
+    /// ```yaml
+    /// nat64-prefix: 64:ff9b::/96
+    /// ```
+    pub nat64_prefix: Option<String>,
     /// external controller address
     pub external_controller: Option<String>,
     /// dashboard folder path relative to the $CWD
     pub external_ui: Option<String>,
     /// external controller secret
     pub secret: Option<String>,
+    /// more external controller tokens, with a scope and a rate limit.
+    /// `secret` is a `control` token without a limit. A token can't be empty.
+    /// # Example
+    /// ```yaml
+    /// controller-tokens:
+    ///   # GET requests and the websockets only, e.g. for a public dashboard
+    ///   - token: dashboard
+    ///     scope: read
+    ///     rate-limit: 10 # requests per second
+    ///   - token: automation
+    ///     scope: control
+    /// ```
+    pub controller_tokens: Vec<ControllerToken>,
+    /// allow `POST /proxies/{group}/select?name=` without a token from
+    /// localhost, e.g. for `clash-rs open-url clash://select?...` in a
+    /// launcher. requests from web pages of other origins, or for another
+    /// `Host` than localhost as after a DNS rebinding, are refused.
+    pub controller_local_select: bool,
+    /// encrypted values, put into any string of the config that refers to
+    /// them as `${secrets.<name>}`, e.g. proxy passwords and `secret`.
+    /// the key is derived from the content of `key-file`, or from the
+    /// `CLASH_SECRETS_PASSPHRASE` environment variable without it.
+    /// a value is encrypted with `clash-rs encrypt-secret`.
+    /// # Example
+    /// ```yaml
+    /// secrets:
+    ///   key-file: /etc/clash/secrets.key
+    ///   values:
+    ///     hk-password: 3q2+7wAAAAAAAAAAAAAAAN6tvu8...
+    ///     api: q83vASNFZ4mrze8BI0VniQ...
+    /// secret: ${secrets.api}
+    /// proxies:
+    ///   - name: hk
+    ///     password: ${secrets.hk-password}
+    /// ```
+    pub secrets: Option<Secrets>,
     #[serde(rename = "interface-name")]
     /// outbound interface name
     /// # Note
@@ -382,6 +590,27 @@ pub struct Config {
     #[serde(rename = "rule-providers")]
     /// rule provider settings
     pub rule_provider: Option<HashMap<String, HashMap<String, Value>>>,
+    /// defaults of the health checks, for the providers and groups that
+    /// leave them out
+    /// # Example
+    /// ```yaml
+    /// health-check:
+    ///   url: https://cp.cloudflare.com/generate_204
+    ///   interval: 300
+    ///   lazy: true
+    ///   # or a STUN server, e.g. stun://stun.l.google.com:19302
+    ///   udp-target: 1.1.1.1:53
+    ///   # checks in flight at once, for providers with hundreds of proxies
+    ///   max-concurrent: 16
+    ///   # seconds after which a proxy not checked again is neither alive
+    ///   # nor dead, see `unknown-nodes` of the groups
+    ///   stale-after: 900
+    ///   # url tests kept for each proxy, and ranking the proxies by their
+    ///   # moving average rather than the last one
+    ///   history-size: 20
+    ///   delay-sampling: ewma
+    /// ```
+    pub health_check: HealthCheckSettings,
     /// experimental settings, if any
     pub experimental: Option<Experimental>,
 
@@ -394,8 +623,56 @@ pub struct Config {
     /// ```
     pub tun: Option<TunConfig>,
 
+    /// protocol sniffing
+    /// # Example
+    /// ```yaml
+    /// sniffer:
+    ///   enable: true
+    /// rules:
+    ///   - PROTOCOL,BITTORRENT,DIRECT
+    ///   - PROTOCOL,QUIC,REJECT
+    /// ```
+    pub sniffer: Option<Sniffer>,
+
+    /// extra inbounds, http, socks, mixed and reverse-proxy ones can
+    /// terminate TLS. A reverse-proxy forwards the HTTP requests it accepts
+    /// to its `upstream`, through `proxy` if set or by the rules otherwise
+    /// # Example
+    /// ```yaml
+    /// listeners:
+    ///   - name: socks-tls
+    ///     type: socks
+    ///     port: 1443
+    ///     listen: 0.0.0.0
+    ///     tls:
+    ///       certificate: cert.pem
+    ///       private-key: key.pem
+    ///       # optional, require client certificates signed by this CA
+    ///       client-ca: ca.pem
+    ///     # optional, `address-restricted` lets back only the UDP replies
+    ///     # from the addresses sent to, `full-cone` by default
+    ///     udp-nat: address-restricted
+    ///   - name: grafana
+    ///     type: reverse-proxy
+    ///     port: 3000
+    ///     upstream: https://grafana.corp.example.com
+    ///     proxy: office
+    /// ```
     #[serde(rename = "listeners")]
     pub listener: Option<Vec<HashMap<String, Value>>>,
+
+    /// port forwarding through a proxy
+    /// # Example
+    /// ```yaml
+    /// tunnels:
+    ///   # network,listen address,target,proxy
+    ///   - tcp/udp,127.0.0.1:6553,8.8.8.8:53,proxy
+    ///   - network: [tcp, udp]
+    ///     address: 127.0.0.1:7777
+    ///     target: target.com:80
+    ///     proxy: proxy
+    /// ```
+    pub tunnels: Option<Vec<Tunnel>>,
 }
 
 impl TryFrom<PathBuf> for Config {
@@ -424,6 +701,8 @@ impl FromStr for Config {
             ))
         })?;
 
+        super::secrets::substitute(&mut val)?;
+
         serde_yaml::from_value(val).map_err(|e| {
             Error::InvalidConfig(format!(
                 "counldn't not parse config content {s}: {e}"
@@ -496,6 +775,15 @@ pub struct DNS {
     pub default_nameserver: Vec<String>,
     /// Lookup domains via specific nameservers
     pub nameserver_policy: HashMap<String, String>,
+    /// Domains appended to names with fewer than `ndots` dots, like
+    /// `search` in resolv.conf, so that e.g. `nas` resolves as `nas.lan`
+    pub search_domains: Vec<String>,
+    /// Names with at least this many dots are tried as is before the
+    /// search domains
+    #[educe(Default = 1)]
+    pub ndots: usize,
+    /// How `.local` and single label names are handled
+    pub local_names: LocalNameMode,
 }
 
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
@@ -507,6 +795,20 @@ pub enum DNSMode {
     RedirHost,
 }
 
+/// Names that are only meaningful on the LAN, `.local` for mDNS and single
+/// label names for LLMNR
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LocalNameMode {
+    /// sent to the nameservers like any other name
+    #[default]
+    Upstream,
+    /// answered from `hosts`, NXDOMAIN otherwise
+    Hosts,
+    /// asked on the LAN with one-shot mDNS/LLMNR queries
+    Multicast,
+}
+
 #[derive(Serialize, Deserialize, Clone, Educe)]
 #[serde(default)]
 #[educe(Default)]
@@ -524,21 +826,159 @@ pub struct FallbackFilter {
     pub domain: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Tunnel {
+    /// `network,address,target,proxy`, multiple networks are joined by `/`
+    Short(String),
+    Full {
+        network: Vec<String>,
+        address: String,
+        target: String,
+        proxy: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ControllerToken {
+    pub token: String,
+    #[serde(default)]
+    pub scope: ControllerScope,
+    /// requests per second, unlimited if not set or 0
+    pub rate_limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ControllerScope {
+    /// `GET` and `HEAD` requests, but the delay, health and speed tests of
+    /// the proxies
+    Read,
+    /// everything
+    #[default]
+    Control,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Secrets {
+    pub key_file: Option<String>,
+    /// name to encrypted value
+    #[serde(default)]
+    pub values: HashMap<String, String>,
+}
+
 #[derive(Serialize, Deserialize, Default)]
 #[serde(rename_all = "kebab-case")]
 pub struct Experimental {
-    /// buffer size for tcp stream bidirectional copy
+    /// buffer size for tcp stream bidirectional copy, defaults to the one of
+    /// `memory-profile`
     pub tcp_buffer_size: Option<usize>,
+    /// TCP_NODELAY on both legs of the relays, true by default. A rule can
+    /// override it for its matches with a `nodelay=` param, e.g. to let the
+    /// writes of bulk downloads be batched
+    /// # Example
+    /// ```yaml
+    /// rules:
+    ///   - DOMAIN-SUFFIX,dl.example.com,DIRECT,nodelay=false
+    /// ```
+    pub tcp_nodelay: Option<bool>,
+    /// max number of live connections tracked, the oldest connection is
+    /// closed when exceeded. unlimited by default
+    pub max_connections: Option<usize>,
+    /// max number of TCP connections to each destination host through the
+    /// proxies, the ones beyond wait up to 10s for one to close. Apps
+    /// opening hundreds of connections at once can trip the abuse detection
+    /// of providers. unlimited by default
+    pub max_connections_per_host: Option<usize>,
+    /// number of closed connections kept for `/connections/closed`,
+    /// defaults to the one of `memory-profile`, 0 disables the history
+    pub connection_history_size: Option<usize>,
+    /// write the decrypted client side of selected TCP connections to
+    /// pcapng files, for debugging. can be changed at runtime via `/capture`
+    /// # Example
+    /// ```yaml
+    /// experimental:
+    ///   capture:
+    ///     dir: captures
+    ///     rules:
+    ///       - DOMAIN-SUFFIX,example.com
+    ///       - SRC-IP-CIDR,192.168.1.10/32
+    /// ```
+    pub capture: Option<Capture>,
+    /// split the TLS ClientHello of all TLS based proxies into small records
+    /// sent apart, against SNI based blocking. `tls-fragment` of a proxy
+    /// takes precedence
+    /// # Example
+    /// ```yaml
+    /// experimental:
+    ///   tls-fragment:
+    ///     # bytes per record
+    ///     size: 10-30
+    ///     # milliseconds between records
+    ///     interval: 5-10
+    ///     # bytes added to the ClientHello as unknown ALPN protocols, for
+    ///     # the proxies that offer ALPN
+    ///     padding: 100-200
+    /// ```
+    pub tls_fragment: Option<TlsFragmentOpt>,
+    /// look up runs of DOMAIN, DOMAIN-SUFFIX, IP-CIDR and SRC-IP-CIDR rules
+    /// in hash maps instead of matching them one by one, for long rule lists.
+    /// the first matching rule still wins
+    #[serde(default)]
+    pub optimize_rules: bool,
+    /// offset the timestamps of VMess by the skew of the system clock, as
+    /// learned from the `Date` header of the health check responses. the
+    /// skew is warned about either way
+    #[serde(default)]
+    pub correct_clock: bool,
+    /// seconds the old process waits for its connections after handing over
+    /// to the new one on `SIGUSR2`, the ones still open are closed then. 30
+    /// by default
+    pub handover_drain: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Capture {
+    /// relative to the working directory, defaults to `captures`
+    pub dir: Option<String>,
+    /// capture every connection
+    #[serde(default)]
+    pub all: bool,
+    /// rules without the target, e.g. `DOMAIN,example.com`,
+    /// a connection matching any of them is captured
+    #[serde(default)]
+    pub rules: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(default)]
 #[serde(rename_all = "kebab-case")]
 pub struct Profile {
-    /// Store the `select` results in $CWD/cache.db
+    /// Store the `select` results in the cache store
     pub store_selected: bool,
     /// persistence fakeip
     pub store_fake_ip: bool,
+    /// Store the liveness and delays of the proxies in the cache store, so
+    /// the groups pick by them from the start rather than after the first
+    /// checks
+    pub store_delay_history: bool,
+    /// where the cache is kept, either `file`, `memory` or `sled`, along
+    /// with the traffic by day and by month of the `/statistics` API
+    /// - `file`: a yaml file at $CWD/cache.db, the default
+    /// - `memory`: nothing survives a restart
+    /// - `sled`: a sled database at $CWD/cache.sled, needs the `sled` feature.
+    ///   an existing `file` cache is moved into it on first use
+    /// # Example
+    /// ```yaml
+    /// profile:
+    ///   store-selected: true
+    ///   store-delay-history: true
+    ///   cache-store: sled
+    /// ```
+    pub cache_store: CacheBackend,
 }
 
 impl Default for Profile {
@@ -546,10 +986,21 @@ impl Default for Profile {
         Self {
             store_selected: true,
             store_fake_ip: false,
+            store_delay_history: false,
+            cache_store: CacheBackend::default(),
         }
     }
 }
 
+#[derive(PartialEq, Serialize, Deserialize, Default, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    #[default]
+    File,
+    Memory,
+    Sled,
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Copy)]
 pub struct Port(pub u16);
 
@@ -596,7 +1047,7 @@ mod tests {
 
     use crate::config::def::Port;
 
-    use super::Config;
+    use super::{Config, MemoryProfile, Tunnel};
 
     #[test]
     fn parse_simple() {
@@ -607,6 +1058,40 @@ mod tests {
         assert_eq!(c.port, Some(Port(9090)));
     }
 
+    #[test]
+    fn parse_tunnels() {
+        let cfg = r#"
+        tunnels:
+          - tcp/udp,127.0.0.1:6553,8.8.8.8:53,proxy
+          - network: [tcp]
+            address: 127.0.0.1:7777
+            target: target.com:80
+            proxy: proxy
+        "#;
+        let c = cfg.parse::<Config>().expect("should parse");
+        let tunnels = c.tunnels.expect("tunnels");
+        assert!(matches!(&tunnels[0], Tunnel::Short(s) if s.starts_with("tcp/udp")));
+        assert!(
+            matches!(&tunnels[1], Tunnel::Full { network, .. } if network == &["tcp"])
+        );
+    }
+
+    #[test]
+    fn parse_memory_profile() {
+        let c = "port: 9090".parse::<Config>().expect("should parse");
+        assert_eq!(c.memory_profile, MemoryProfile::Default);
+
+        let c = "memory-profile: low"
+            .parse::<Config>()
+            .expect("should parse");
+        assert_eq!(c.memory_profile, MemoryProfile::Low);
+        assert!(c.memory_profile.compact_tries());
+        assert!(
+            c.memory_profile.dns_cache_size()
+                < MemoryProfile::Default.dns_cache_size()
+        );
+    }
+
     #[test]
     fn test_str_port() {
         let cfg = r#"
@@ -696,6 +1181,7 @@ hosts:
   # '*.clash.dev': 127.0.0.1
   # '.dev': 127.0.0.1
   # 'alpha.clash.dev': '::1'
+  # '+.corp.example.com': rule-set:corp-zone # lines like `dev.corp.example.com,10.0.0.8`
 
 profile:
   # Store the `select` results in $HOME/.config/clash/.cache
@@ -837,7 +1323,13 @@ proxies:
     #   headers:
     #     Host: v2ray.com
     #   max-early-data: 2048
+    #   # the early data goes at the end of the path if this is empty
     #   early-data-header-name: Sec-WebSocket-Protocol
+    #   # or, the same as the two above
+    #   # path: /path?ed=2048
+    #   # {host} (the Host header or the server), {port} and {rand} (16
+    #   # random hex digits) are replaced for each connection
+    #   # path: /{host}/ws?id={rand}
 
   - name: "vmess-h2"
     type: vmess
@@ -885,6 +1377,10 @@ proxies:
     # skip-cert-verify: true
     grpc-opts:
       grpc-service-name: "example"
+      # gun or multi
+      grpc-mode: multi
+      # grpc-user-agent: "grpc-go/1.36.0"
+      # initial-window-size: 65536
 
   # socks5
   - name: "socks"
@@ -1075,8 +1571,9 @@ rules:
   - DOMAIN,google.com,auto
   - DOMAIN-SUFFIX,ad.com,REJECT
   - SRC-IP-CIDR,192.168.1.201/32,DIRECT
-  # optional param "no-resolve" for IP rules (GEOIP, IP-CIDR, IP-CIDR6)
+  # optional param "no-resolve" for IP rules (GEOIP, IP-ASN, IP-CIDR, IP-CIDR6)
   - IP-CIDR,127.0.0.0/8,DIRECT
+  - IP-ASN,13335,auto
   - GEOIP,CN,DIRECT
   - DST-PORT,80,DIRECT
   - SRC-PORT,7777,DIRECT
@@ -11,25 +11,33 @@ use serde::{Deserialize, Serialize};
 use crate::{
     Error,
     app::{
-        dns, net::Interface,
+        dns,
+        net::{Interface, nat64::Nat64Prefix},
         remote_content_manager::providers::rule_provider::RuleSetBehavior,
     },
     common::auth,
     config::{
-        def::{self, LogLevel, RunMode},
+        def::{
+            self, CacheBackend, ControllerToken, LogLevel, MemoryProfile, RunMode,
+        },
         internal::{proxy::OutboundProxy, rule::RuleType},
     },
 };
 
-use super::{listener::InboundOpts, proxy::OutboundProxyProviderDef};
+use super::{
+    listener::InboundOpts,
+    proxy::{HealthCheckSettings, OutboundProxyProviderDef},
+};
 
 pub struct Config {
     pub general: General,
     pub dns: dns::Config,
     pub tun: TunConfig,
+    pub sniffer: def::Sniffer,
     pub experimental: Option<def::Experimental>,
     pub profile: Profile,
     pub rules: Vec<RuleType>,
+    pub sub_rules: HashMap<String, Vec<RuleType>>,
     pub rule_providers: HashMap<String, RuleProviderDef>,
     pub users: Vec<auth::User>,
     /// a list maintaining the order from the config file
@@ -42,8 +50,20 @@ pub struct Config {
 
 impl Config {
     pub fn validate(self) -> Result<Self, crate::Error> {
-        for r in self.rules.iter() {
-            if !self.proxies.contains_key(r.target())
+        if let Some(r) = self
+            .sub_rules
+            .values()
+            .flatten()
+            .find(|r| r.is_sniff_override())
+        {
+            return Err(Error::InvalidConfig(format!(
+                "`{}` is only allowed in the rules, not in sub-rules",
+                r.target()
+            )));
+        }
+        for r in self.rules.iter().chain(self.sub_rules.values().flatten()) {
+            if !r.is_sniff_override()
+                && !self.proxies.contains_key(r.target())
                 && !self.proxy_groups.contains_key(r.target())
             {
                 return Err(Error::InvalidConfig(format!(
@@ -52,6 +72,30 @@ impl Config {
                 )));
             }
         }
+        for (name, l) in self.listeners.iter() {
+            if let Some(rules) = &l.common_opts().rules
+                && !self.sub_rules.contains_key(rules)
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "sub-rules `{}` referenced in listener {} was not found",
+                    rules, name
+                )));
+            }
+            if let InboundOpts::Tunnel {
+                proxy: Some(proxy), ..
+            }
+            | InboundOpts::ReverseProxy {
+                proxy: Some(proxy), ..
+            } = l
+                && !self.proxies.contains_key(proxy)
+                && !self.proxy_groups.contains_key(proxy)
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "proxy `{}` referenced in listener {} was not found",
+                    proxy, name
+                )));
+            }
+        }
         Ok(self)
     }
 }
@@ -62,9 +106,11 @@ pub struct General {
     pub(crate) controller: Controller,
     pub mode: RunMode,
     pub log_level: LogLevel,
+    pub memory_profile: MemoryProfile,
     pub ipv6: bool,
     pub interface: Option<Interface>,
     pub routing_mask: Option<u32>,
+    pub nat64_prefix: Option<Nat64Prefix>,
     pub mmdb: String,
     pub mmdb_download_url: Option<String>,
     pub asn_mmdb: String,
@@ -72,10 +118,13 @@ pub struct General {
 
     pub geosite: String,
     pub geosite_download_url: Option<String>,
+    pub health_check: HealthCheckSettings,
 }
 
 pub struct Profile {
     pub store_selected: bool,
+    pub store_delay_history: bool,
+    pub cache_store: CacheBackend,
     // this is read to dns config directly
     // store_fake_ip: bool,
 }
@@ -91,6 +140,7 @@ pub struct TunConfig {
     pub so_mark: u32,
     pub route_table: u32,
     pub dns_hijack: bool,
+    pub strict_tcp_handshake: bool,
 }
 
 #[derive(Serialize, Clone, Debug, Copy, PartialEq)]
@@ -163,6 +213,8 @@ pub struct Controller {
     pub external_controller: Option<String>,
     pub external_ui: Option<String>,
     pub secret: Option<String>,
+    pub tokens: Vec<ControllerToken>,
+    pub local_select: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -190,7 +242,14 @@ pub struct FileRuleProvider {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{def, internal::convert::convert, listener::InboundOpts};
+    use crate::config::{
+        def,
+        internal::{
+            convert::convert,
+            proxy::{OutboundGroupProtocol, OutboundProxy},
+        },
+        listener::InboundOpts,
+    };
     #[test]
     fn from_def_config() {
         let cfg = r#"
@@ -211,4 +270,33 @@ mod tests {
             _ => false,
         }));
     }
+
+    #[test]
+    fn test_region_groups() {
+        let cfg = r#"
+        proxy-providers:
+          airport:
+            type: file
+            path: ./airport.yaml
+            region-groups:
+              type: select
+              regions: [hk, jp]
+        rules:
+          - MATCH,airport-JP
+        "#;
+        let c = cfg.parse::<def::Config>().expect("should parse");
+        let cc = convert(c).expect("should convert");
+
+        assert!(cc.proxy_groups.contains_key("airport-HK"));
+        let Some(OutboundProxy::ProxyGroup(OutboundGroupProtocol::Select(group))) =
+            cc.proxy_groups.get("airport-JP")
+        else {
+            panic!("not a select group");
+        };
+        assert_eq!(group.use_provider, Some(vec!["airport-JP".to_owned()]));
+        assert!(
+            cc.proxy_names
+                .ends_with(&["airport-HK".to_owned(), "airport-JP".to_owned()])
+        );
+    }
 }
@@ -1,6 +1,7 @@
 use std::net::IpAddr;
 
 use crate::{
+    Error,
     app::net::Interface,
     config::{
         config::{Controller, General},
@@ -9,15 +10,23 @@ use crate::{
 };
 
 pub(super) fn convert(c: &def::Config) -> Result<General, crate::Error> {
+    if c.controller_tokens.iter().any(|x| x.token.is_empty()) {
+        return Err(Error::InvalidConfig(
+            "controller-tokens: a token can't be empty".to_owned(),
+        ));
+    }
     Ok(General {
         authentication: c.authentication.clone(),
         controller: Controller {
             external_controller: c.external_controller.clone(),
             external_ui: c.external_ui.clone(),
             secret: c.secret.clone(),
+            tokens: c.controller_tokens.clone(),
+            local_select: c.controller_local_select,
         },
         mode: c.mode,
         log_level: c.log_level,
+        memory_profile: c.memory_profile,
         ipv6: c.ipv6,
         interface: c.interface.as_ref().map(|iface| {
             if let Ok(addr) = iface.parse::<IpAddr>() {
@@ -27,6 +36,12 @@ pub(super) fn convert(c: &def::Config) -> Result<General, crate::Error> {
             }
         }),
         routing_mask: c.routing_mask,
+        nat64_prefix: c
+            .nat64_prefix
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(Error::InvalidConfig)?,
         mmdb: c.mmdb.to_owned(),
         mmdb_download_url: c.mmdb_download_url.to_owned(),
         asn_mmdb: c.asn_mmdb.to_owned(),
@@ -34,5 +49,6 @@ pub(super) fn convert(c: &def::Config) -> Result<General, crate::Error> {
         geosite: c.geosite.to_owned(),
         geosite_download_url: c.geosite_download_url.to_owned(),
         bind_address: c.bind_address,
+        health_check: c.health_check.clone(),
     })
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::SocketAddr};
 
 use serde::{Deserialize as _, de::value::MapDeserializer};
 use serde_yaml::Value;
@@ -7,6 +7,7 @@ use tracing::warn;
 use crate::{
     Error,
     config::{
+        config::BindAddress,
         def::{self, Port},
         listener::{CommonInboundOpts, InboundOpts},
         proxy::map_serde_error,
@@ -23,6 +24,7 @@ pub(super) fn convert(
     let tpoxy_port = c.tproxy_port;
     let redir_port = c.redir_port;
     let bind_address = c.bind_address;
+    let allow_lan = c.allow_lan.unwrap_or_default();
     let mut inbounds = raw.unwrap_or_default().into_iter().try_fold(
         HashMap::with_capacity(3),
         |mut accum, raw| {
@@ -93,10 +95,12 @@ pub(super) fn convert(
                 common_opts: CommonInboundOpts {
                     name: "HTTP-IN".into(),
                     listen: bind_address,
+                    allow_lan,
                     port: http_port,
                     ..Default::default()
                 },
                 inherited: true,
+                tls: None,
             },
         );
     }
@@ -107,11 +111,13 @@ pub(super) fn convert(
                 common_opts: CommonInboundOpts {
                     name: "SOCKS-IN".into(),
                     listen: bind_address,
+                    allow_lan,
                     port: socks_port,
                     ..Default::default()
                 },
                 udp: true,
                 inherited: true,
+                tls: None,
             },
         );
     }
@@ -122,11 +128,13 @@ pub(super) fn convert(
                 common_opts: CommonInboundOpts {
                     name: "MIXED-IN".into(),
                     listen: bind_address,
+                    allow_lan,
                     port: mixed_port,
                     ..Default::default()
                 },
                 udp: true,
                 inherited: true,
+                tls: None,
             },
         );
     }
@@ -137,6 +145,7 @@ pub(super) fn convert(
                 common_opts: CommonInboundOpts {
                     name: "REDIR-IN".into(),
                     listen: bind_address,
+                    allow_lan,
                     port: redir_port,
                     ..Default::default()
                 },
@@ -144,6 +153,16 @@ pub(super) fn convert(
             },
         );
     }
+    for tunnel in c.tunnels.iter().flatten() {
+        let inbound = convert_tunnel(tunnel)?;
+        let name = inbound.common_opts().name.clone();
+        if inbounds.contains_key(&name) {
+            return Err(Error::InvalidConfig(format!(
+                "duplicated tunnel listener: {name}"
+            )));
+        }
+        inbounds.insert(name, inbound);
+    }
     if let Some(Port(tproxy_port)) = tpoxy_port {
         inbounds.insert(
             "TPROXY-IN".into(),
@@ -151,6 +170,7 @@ pub(super) fn convert(
                 common_opts: CommonInboundOpts {
                     name: "TPROXY-IN".into(),
                     listen: bind_address,
+                    allow_lan,
                     port: tproxy_port,
                     ..Default::default()
                 },
@@ -162,6 +182,57 @@ pub(super) fn convert(
     Ok(inbounds)
 }
 
+fn convert_tunnel(tunnel: &def::Tunnel) -> Result<InboundOpts, Error> {
+    let (network, address, target, proxy) = match tunnel {
+        def::Tunnel::Short(s) => {
+            let parts = s.split(',').map(str::trim).collect::<Vec<_>>();
+            if parts.len() != 3 && parts.len() != 4 {
+                return Err(Error::InvalidConfig(format!(
+                    "invalid tunnel `{s}`, expected `network,address,target,proxy`"
+                )));
+            }
+            (
+                parts[0].split('/').map(str::to_owned).collect(),
+                parts[1].to_owned(),
+                parts[2].to_owned(),
+                parts.get(3).map(|x| x.to_string()),
+            )
+        }
+        def::Tunnel::Full {
+            network,
+            address,
+            target,
+            proxy,
+        } => (
+            network.clone(),
+            address.clone(),
+            target.clone(),
+            proxy.clone(),
+        ),
+    };
+
+    if let Some(n) = network.iter().find(|n| *n != "tcp" && *n != "udp") {
+        return Err(Error::InvalidConfig(format!(
+            "invalid tunnel network `{n}` for {address}"
+        )));
+    }
+    let listen = address.parse::<SocketAddr>().map_err(|e| {
+        Error::InvalidConfig(format!("invalid tunnel address {address}: {e}"))
+    })?;
+
+    Ok(InboundOpts::Tunnel {
+        common_opts: CommonInboundOpts {
+            name: format!("TUNNEL-{address}"),
+            listen: BindAddress(listen.ip()),
+            port: listen.port(),
+            ..Default::default()
+        },
+        network,
+        target,
+        proxy,
+    })
+}
+
 impl TryFrom<HashMap<String, Value>> for InboundOpts {
     type Error = crate::Error;
 
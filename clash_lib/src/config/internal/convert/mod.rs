@@ -49,13 +49,23 @@ pub(super) fn convert(mut c: def::Config) -> Result<config::Config, crate::Error
         );
     }
 
-    config::Config {
+    let mut config = config::Config {
         general: general::convert(&c)?,
         dns: (&c).try_into()?,
         experimental: c.experimental.take(),
         tun: tun::convert(c.tun.take())?,
+        sniffer: c.sniffer.take().unwrap_or_default(),
         profile: Profile {
             store_selected: c.profile.store_selected,
+            store_delay_history: c.profile.store_delay_history,
+            cache_store: match c.profile.cache_store {
+                def::CacheBackend::Sled if !cfg!(feature = "sled") => {
+                    return Err(Error::InvalidConfig(
+                        "sled cache store needs the `sled` feature".to_owned(),
+                    ));
+                }
+                backend => backend,
+            },
         },
         rules: c
             .rule
@@ -67,6 +77,26 @@ pub(super) fn convert(mut c: def::Config) -> Result<config::Config, crate::Error
                     .map_err(|x| Error::InvalidConfig(x.to_string()))
             })
             .collect::<Result<Vec<_>, _>>()?,
+        sub_rules: c
+            .sub_rules
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, rules)| {
+                let rules = rules
+                    .into_iter()
+                    .map(|x| {
+                        x.parse::<RuleType>().map_err(|x| {
+                            Error::InvalidConfig(format!(
+                                "invalid sub-rules {}: {}",
+                                name, x
+                            ))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((name, rules))
+            })
+            .collect::<Result<HashMap<_, _>, Error>>()?,
         rule_providers: rule_provider::convert(c.rule_provider.take()),
         users: c
             .authentication
@@ -132,8 +162,9 @@ pub(super) fn convert(mut c: def::Config) -> Result<config::Config, crate::Error
             })
             .unwrap_or_default(),
         listeners: listener::convert(c.listener.take(), &c)?,
-    }
-    .validate()
+    };
+    proxy_group::add_region_groups(&mut config)?;
+    config.validate()
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundGroupProtocol {
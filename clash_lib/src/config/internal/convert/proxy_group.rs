@@ -2,7 +2,17 @@ use std::collections::HashMap;
 
 use serde_yaml::Value;
 
-use crate::{Error, config::proxy::OutboundProxy};
+use crate::{
+    Error,
+    config::{
+        internal::config::Config,
+        proxy::{
+            OutboundGroupFallback, OutboundGroupLoadBalance, OutboundGroupProtocol,
+            OutboundGroupSelect, OutboundGroupUrlTest, OutboundProxy,
+            RegionGroupType, RegionGroups,
+        },
+    },
+};
 
 pub fn concert(
     before: Option<Vec<HashMap<String, Value>>>,
@@ -26,3 +36,63 @@ pub fn concert(
         },
     )
 }
+
+/// the groups of the `region-groups` of the providers, after the others
+pub fn add_region_groups(config: &mut Config) -> Result<(), Error> {
+    let mut providers: Vec<_> = config
+        .proxy_providers
+        .iter()
+        .filter_map(|(name, p)| Some((name, p.region_groups()?)))
+        .collect();
+    providers.sort_by_key(|(name, _)| *name);
+
+    for (provider, groups) in providers {
+        for region in groups.regions() {
+            let name = RegionGroups::group_name(provider, &region);
+            if config.proxies.contains_key(&name)
+                || config.proxy_groups.contains_key(&name)
+            {
+                return Err(Error::InvalidConfig(format!(
+                    "region group {} of provider {} is taken by a proxy or group",
+                    name, provider
+                )));
+            }
+            let use_provider = Some(vec![name.clone()]);
+            let group = match groups.group_type {
+                RegionGroupType::UrlTest => {
+                    OutboundGroupProtocol::UrlTest(OutboundGroupUrlTest {
+                        name: name.clone(),
+                        use_provider,
+                        ..Default::default()
+                    })
+                }
+                RegionGroupType::Fallback => {
+                    OutboundGroupProtocol::Fallback(OutboundGroupFallback {
+                        name: name.clone(),
+                        use_provider,
+                        ..Default::default()
+                    })
+                }
+                RegionGroupType::LoadBalance => {
+                    OutboundGroupProtocol::LoadBalance(OutboundGroupLoadBalance {
+                        name: name.clone(),
+                        use_provider,
+                        ..Default::default()
+                    })
+                }
+                RegionGroupType::Select => {
+                    OutboundGroupProtocol::Select(OutboundGroupSelect {
+                        name: name.clone(),
+                        use_provider,
+                        ..Default::default()
+                    })
+                }
+            };
+            config.proxy_names.push(name.clone());
+            config
+                .proxy_groups
+                .insert(name, OutboundProxy::ProxyGroup(group));
+        }
+    }
+    Ok(())
+}
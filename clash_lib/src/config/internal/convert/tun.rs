@@ -33,6 +33,7 @@ pub fn convert(
                 def::DnsHijack::Switch(b) => b,
                 def::DnsHijack::List(_) => true,
             },
+            strict_tcp_handshake: t.strict_tcp_handshake,
         }),
         None => Ok(config::TunConfig::default()),
     }
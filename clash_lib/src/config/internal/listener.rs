@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use educe::Educe;
 use serde::{Deserialize, Serialize};
 
@@ -11,18 +13,21 @@ pub enum InboundOpts {
         #[serde(flatten)]
         common_opts: CommonInboundOpts,
         inherited: bool, // TODO users
+        tls: Option<InboundTls>,
     },
     Socks {
         #[serde(flatten)]
         common_opts: CommonInboundOpts,
         udp: bool,
         inherited: bool, // TODO users
+        tls: Option<InboundTls>,
     },
     Mixed {
         #[serde(flatten)]
         common_opts: CommonInboundOpts,
         udp: bool, // TODO users
         inherited: bool,
+        tls: Option<InboundTls>,
     },
     TProxy {
         #[serde(flatten)]
@@ -40,6 +45,18 @@ pub enum InboundOpts {
         common_opts: CommonInboundOpts,
         network: Vec<String>,
         target: String,
+        /// the outbound to forward through, routed by rules if absent
+        proxy: Option<String>,
+    },
+    ReverseProxy {
+        #[serde(flatten)]
+        common_opts: CommonInboundOpts,
+        /// the http(s) URL the requests are forwarded to, its path prefixes
+        /// theirs
+        upstream: String,
+        /// the outbound to forward through, routed by rules if absent
+        proxy: Option<String>,
+        tls: Option<InboundTls>,
     },
 }
 
@@ -52,6 +69,7 @@ impl InboundOpts {
             InboundOpts::TProxy { common_opts, .. } => common_opts,
             InboundOpts::Tunnel { common_opts, .. } => common_opts,
             InboundOpts::Redir { common_opts, .. } => common_opts,
+            InboundOpts::ReverseProxy { common_opts, .. } => common_opts,
         }
     }
 
@@ -63,6 +81,7 @@ impl InboundOpts {
             InboundOpts::TProxy { common_opts, .. } => common_opts,
             InboundOpts::Tunnel { common_opts, .. } => common_opts,
             InboundOpts::Redir { common_opts, .. } => common_opts,
+            InboundOpts::ReverseProxy { common_opts, .. } => common_opts,
         }
     }
 
@@ -74,6 +93,17 @@ impl InboundOpts {
             InboundOpts::TProxy { inherited, .. } => *inherited,
             InboundOpts::Tunnel { .. } => false,
             InboundOpts::Redir { inherited, .. } => *inherited,
+            InboundOpts::ReverseProxy { .. } => false,
+        }
+    }
+
+    pub fn tls_mut(&mut self) -> Option<&mut InboundTls> {
+        match self {
+            InboundOpts::Http { tls, .. } => tls.as_mut(),
+            InboundOpts::Socks { tls, .. } => tls.as_mut(),
+            InboundOpts::Mixed { tls, .. } => tls.as_mut(),
+            InboundOpts::ReverseProxy { tls, .. } => tls.as_mut(),
+            _ => None,
         }
     }
 
@@ -96,4 +126,46 @@ pub struct CommonInboundOpts {
     pub allow_lan: bool,
     #[educe(Default = 0)]
     pub port: u16,
+    /// the `sub-rules` entry the connections are routed with, the top-level
+    /// rules if absent
+    pub rules: Option<String>,
+    #[serde(default)]
+    pub udp_nat: UdpNat,
+}
+
+/// Which replies to relayed UDP are let back to the client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UdpNat {
+    /// from anyone, some games need this
+    #[default]
+    FullCone,
+    /// only from the addresses the client has sent to
+    AddressRestricted,
+}
+
+/// TLS termination of an inbound, so remote clients can reach it over the
+/// public internet. the files are in PEM format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct InboundTls {
+    pub certificate: String,
+    pub private_key: String,
+    /// CA certificates the clients must present a certificate signed by,
+    /// no client certificate is asked for if not set
+    pub client_ca: Option<String>,
+}
+
+impl InboundTls {
+    /// relative paths are relative to the working directory
+    pub fn resolve_paths(&mut self, cwd: &Path) {
+        let resolve = |p: &mut String| {
+            *p = cwd.join(&*p).to_string_lossy().to_string();
+        };
+        resolve(&mut self.certificate);
+        resolve(&mut self.private_key);
+        if let Some(ca) = self.client_ca.as_mut() {
+            resolve(ca);
+        }
+    }
 }
@@ -4,6 +4,8 @@ use serde_yaml::Value;
 use std::{
     collections::HashMap,
     fmt::{Display, Formatter},
+    net::IpAddr,
+    str::FromStr,
 };
 use uuid::Uuid;
 
@@ -44,13 +46,15 @@ pub fn map_serde_error(
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum OutboundProxyProtocol {
     #[serde(skip)]
     Direct,
     #[serde(skip)]
     Reject,
+    #[serde(rename = "block-page")]
+    BlockPage(OutboundBlockPage),
     #[cfg(feature = "shadowsocks")]
     #[serde(rename = "ss")]
     Ss(OutboundShadowsocks),
@@ -76,10 +80,11 @@ pub enum OutboundProxyProtocol {
 }
 
 impl OutboundProxyProtocol {
-    fn name(&self) -> &str {
+    pub(crate) fn name(&self) -> &str {
         match &self {
             OutboundProxyProtocol::Direct => PROXY_DIRECT,
             OutboundProxyProtocol::Reject => PROXY_REJECT,
+            OutboundProxyProtocol::BlockPage(block_page) => &block_page.name,
             #[cfg(feature = "shadowsocks")]
             OutboundProxyProtocol::Ss(ss) => &ss.common_opts.name,
             OutboundProxyProtocol::Socks5(socks5) => &socks5.common_opts.name,
@@ -97,6 +102,226 @@ impl OutboundProxyProtocol {
             OutboundProxyProtocol::Ssh(ssh) => &ssh.common_opts.name,
         }
     }
+
+    fn common_opts_mut(&mut self) -> Option<&mut CommonConfigOptions> {
+        match self {
+            #[cfg(feature = "shadowsocks")]
+            OutboundProxyProtocol::Ss(ss) => Some(&mut ss.common_opts),
+            OutboundProxyProtocol::Socks5(socks5) => Some(&mut socks5.common_opts),
+            OutboundProxyProtocol::Trojan(trojan) => Some(&mut trojan.common_opts),
+            OutboundProxyProtocol::Vmess(vmess) => Some(&mut vmess.common_opts),
+            OutboundProxyProtocol::Wireguard(wireguard) => {
+                Some(&mut wireguard.common_opts)
+            }
+            #[cfg(feature = "tuic")]
+            OutboundProxyProtocol::Tuic(tuic) => Some(&mut tuic.common_opts),
+            #[cfg(feature = "ssh")]
+            OutboundProxyProtocol::Ssh(ssh) => Some(&mut ssh.common_opts),
+            _ => None,
+        }
+    }
+
+    fn common_opts(&self) -> Option<&CommonConfigOptions> {
+        match self {
+            #[cfg(feature = "shadowsocks")]
+            OutboundProxyProtocol::Ss(ss) => Some(&ss.common_opts),
+            OutboundProxyProtocol::Socks5(socks5) => Some(&socks5.common_opts),
+            OutboundProxyProtocol::Trojan(trojan) => Some(&trojan.common_opts),
+            OutboundProxyProtocol::Vmess(vmess) => Some(&vmess.common_opts),
+            OutboundProxyProtocol::Wireguard(wireguard) => {
+                Some(&wireguard.common_opts)
+            }
+            #[cfg(feature = "tuic")]
+            OutboundProxyProtocol::Tuic(tuic) => Some(&tuic.common_opts),
+            #[cfg(feature = "ssh")]
+            OutboundProxyProtocol::Ssh(ssh) => Some(&ssh.common_opts),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn dialer_opts(&self) -> DialerOptions {
+        match self {
+            OutboundProxyProtocol::Hysteria2(hysteria2) => DialerOptions {
+                ip_version: hysteria2.ip_version,
+                ..Default::default()
+            },
+            _ => self
+                .common_opts()
+                .map(|c| DialerOptions {
+                    ip_version: c.ip_version,
+                    routing_mark: c.routing_mark,
+                    source_ip: c.source_ip,
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// `host:port` of the server
+    pub(crate) fn endpoint(&self) -> Option<String> {
+        self.server_opts()
+            .map(|(server, port, _)| format_endpoint(server, port))
+    }
+
+    /// the server, the port and the `backup-endpoints`
+    fn server_opts(&self) -> Option<(&String, u16, &Vec<String>)> {
+        match self {
+            OutboundProxyProtocol::Hysteria2(hysteria2) => Some((
+                &hysteria2.server,
+                hysteria2.port,
+                &hysteria2.backup_endpoints,
+            )),
+            _ => self
+                .common_opts()
+                .map(|c| (&c.server, c.port, &c.backup_endpoints)),
+        }
+    }
+
+    fn server_opts_mut(
+        &mut self,
+    ) -> Option<(&mut String, &mut u16, &mut Vec<String>)> {
+        match self {
+            OutboundProxyProtocol::Hysteria2(hysteria2) => Some((
+                &mut hysteria2.server,
+                &mut hysteria2.port,
+                &mut hysteria2.backup_endpoints,
+            )),
+            _ => self
+                .common_opts_mut()
+                .map(|c| (&mut c.server, &mut c.port, &mut c.backup_endpoints)),
+        }
+    }
+
+    pub(crate) fn ui(&self) -> Option<&UiMeta> {
+        self.common_opts().map(|c| &c.ui)
+    }
+
+    pub(crate) fn health_check_url(&self) -> Option<&str> {
+        self.common_opts()
+            .and_then(|c| c.health_check_url.as_deref())
+    }
+
+    /// hostnames or IPs of the server and its `backup-endpoints`
+    pub(crate) fn servers(&self) -> Vec<String> {
+        self.server_opts()
+            .map(|(server, _, backups)| {
+                std::iter::once(server.clone())
+                    .chain(
+                        backups
+                            .iter()
+                            .filter_map(|x| parse_endpoint(x))
+                            .map(|x| x.0),
+                    )
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// `tls-fragment` of a proxy without its own, i.e. that of `experimental`
+    pub(crate) fn inherit_tls_fragment(&mut self, default: Option<&TlsFragmentOpt>) {
+        if let Some(common) = self.common_opts_mut()
+            && let Some(default) = default
+        {
+            common.tls_fragment.get_or_insert_with(|| default.clone());
+        }
+    }
+
+    /// A copy of the proxy for each of its `backup-endpoints`.
+    /// The TLS server name and the transport host keep pointing to the
+    /// primary server, so an IP endpoint still presents the right name.
+    pub(crate) fn backup_endpoints(&self) -> Result<Vec<Self>, Error> {
+        if self
+            .server_opts()
+            .is_none_or(|(_, _, backups)| backups.is_empty())
+        {
+            return Ok(vec![]);
+        }
+        let mut this = self.clone();
+        let Some((server, _, backups)) = this.server_opts_mut() else {
+            return Ok(vec![]);
+        };
+        let primary = server.clone();
+        let endpoints = std::mem::take(backups);
+
+        match &mut this {
+            OutboundProxyProtocol::Socks5(socks5) if socks5.tls => {
+                socks5.sni.get_or_insert(primary.clone());
+            }
+            OutboundProxyProtocol::Trojan(trojan) => {
+                trojan.sni.get_or_insert(primary.clone());
+                if let Some(ws) = trojan.ws_opts.as_mut() {
+                    ws.keep_host(&primary);
+                }
+            }
+            OutboundProxyProtocol::Vmess(vmess) => {
+                if vmess.server_name.is_none() {
+                    vmess.server_name = Some(
+                        vmess
+                            .ws_opts
+                            .as_ref()
+                            .and_then(|x| x.headers.as_ref())
+                            .and_then(|x| x.get("Host").cloned())
+                            .unwrap_or(primary.clone()),
+                    );
+                }
+                if let Some(ws) = vmess.ws_opts.as_mut() {
+                    ws.keep_host(&primary);
+                }
+                if let Some(h2) = vmess.h2_opts.as_mut() {
+                    h2.host.get_or_insert(vec![primary.clone()]);
+                }
+            }
+            #[cfg(feature = "tuic")]
+            OutboundProxyProtocol::Tuic(tuic) => {
+                tuic.sni.get_or_insert(primary.clone());
+            }
+            OutboundProxyProtocol::Hysteria2(hysteria2) => {
+                hysteria2.sni.get_or_insert(primary.clone());
+            }
+            _ => {}
+        }
+
+        endpoints
+            .iter()
+            .map(|endpoint| {
+                let (server, port) = parse_endpoint(endpoint).ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "invalid backup endpoint `{}` of proxy {}, expected \
+                         host:port",
+                        endpoint,
+                        self.name()
+                    ))
+                })?;
+                let mut backup = this.clone();
+                if let Some(opts) = backup.server_opts_mut() {
+                    *opts.0 = server;
+                    *opts.1 = port;
+                }
+                Ok(backup)
+            })
+            .collect()
+    }
+}
+
+/// `host:port`, with brackets around an IPv6 host
+fn parse_endpoint(endpoint: &str) -> Option<(String, u16)> {
+    let (host, port) = endpoint.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        None if host.contains(':') => return None,
+        None => host,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port.parse().ok()?))
+}
+
+fn format_endpoint(server: &str, port: u16) -> String {
+    if server.contains(':') {
+        format!("[{}]:{}", server, port)
+    } else {
+        format!("{}:{}", server, port)
+    }
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
@@ -110,8 +335,30 @@ impl TryFrom<HashMap<String, Value>> for OutboundProxyProtocol {
                 "missing field `name` in outbound proxy protocol".to_owned(),
             ))?
             .to_owned();
-        OutboundProxyProtocol::deserialize(MapDeserializer::new(mapping.into_iter()))
-            .map_err(map_serde_error(name))
+        let proxy = OutboundProxyProtocol::deserialize(MapDeserializer::new(
+            mapping.into_iter(),
+        ))
+        .map_err(map_serde_error(name.clone()))?;
+        // the proxies tunneling the TLS of a client through a TLS of their own
+        let over_tls = match &proxy {
+            #[cfg(feature = "shadowsocks")]
+            OutboundProxyProtocol::Ss(_) => true,
+            OutboundProxyProtocol::Socks5(_)
+            | OutboundProxyProtocol::Trojan(_)
+            | OutboundProxyProtocol::Vmess(_) => true,
+            _ => false,
+        };
+        if !over_tls
+            && proxy
+                .common_opts()
+                .is_some_and(|c| c.tls_in_tls_fragment.is_some())
+        {
+            return Err(Error::InvalidConfig(format!(
+                "tls-in-tls-fragment is not supported by proxy {}",
+                name
+            )));
+        }
+        Ok(proxy)
     }
 }
 
@@ -123,6 +370,7 @@ impl Display for OutboundProxyProtocol {
             OutboundProxyProtocol::Socks5(_) => write!(f, "Socks5"),
             OutboundProxyProtocol::Direct => write!(f, "{}", PROXY_DIRECT),
             OutboundProxyProtocol::Reject => write!(f, "{}", PROXY_REJECT),
+            OutboundProxyProtocol::BlockPage(_) => write!(f, "BlockPage"),
             OutboundProxyProtocol::Trojan(_) => write!(f, "Trojan"),
             OutboundProxyProtocol::Vmess(_) => write!(f, "Vmess"),
             OutboundProxyProtocol::Wireguard(_) => write!(f, "Wireguard"),
@@ -149,9 +397,93 @@ pub struct CommonConfigOptions {
     /// nothing
     #[serde(alias = "dialer-proxy")]
     pub connect_via: Option<String>,
+    /// millis, deadline for resolving and connecting to the server
+    pub connect_timeout: Option<u64>,
+    /// millis, deadline for the TLS/transport handshake
+    pub tls_handshake_timeout: Option<u64>,
+    /// millis, deadline for the proxy protocol handshake
+    pub handshake_timeout: Option<u64>,
+    /// more `host:port` endpoints of the same server, e.g. a relay and the
+    /// server's own IP, tried in order when the previous one fails to dial.
+    /// the one that worked is tried first next time.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backup_endpoints: Vec<String>,
+    /// split the TLS ClientHello of TLS based proxies, overrides
+    /// `experimental.tls-fragment`
+    pub tls_fragment: Option<TlsFragmentOpt>,
+    /// split the ClientHello of the connections to port 443 through TLS based
+    /// proxies, which would otherwise be TLS in TLS with a telltale pattern.
+    /// supported by trojan, by vmess and socks5 with `tls`, and by ss with a
+    /// TLS plugin
+    pub tls_in_tls_fragment: Option<TlsFragmentOpt>,
+    /// how the server, and the destinations the proxy resolves itself, e.g.
+    /// through WireGuard or for UDP, are resolved. `dual` by default, or the
+    /// family of `source-ip` if that is set
+    pub ip_version: Option<IpVersion>,
+    /// SO_MARK of the sockets to the server, linux only
+    pub routing_mark: Option<u32>,
+    /// the local IP the sockets to the server are bound to, e.g. the address
+    /// on one of the WAN links
+    pub source_ip: Option<IpAddr>,
+    /// the URL this proxy is health checked with, over that of its group or
+    /// provider, e.g. for a node that can't reach the default one
+    pub health_check_url: Option<String>,
+    #[serde(flatten)]
+    pub ui: UiMeta,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+/// How the dashboards show a proxy or a group, through `/proxies`. No effect
+/// on the routing
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct UiMeta {
+    /// URL of an image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// left out of the lists of the dashboards that honor it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hidden: Option<bool>,
+    /// the lower first, where the dashboard sorts by it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<i32>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpVersion {
+    /// whatever the resolver answers
+    Dual,
+    Ipv4,
+    Ipv6,
+    /// IPv4 if the host has one, IPv6 otherwise
+    Ipv4Prefer,
+    Ipv6Prefer,
+}
+
+/// the options of a proxy for dialing its server
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DialerOptions {
+    pub ip_version: Option<IpVersion>,
+    pub routing_mark: Option<u32>,
+    pub source_ip: Option<IpAddr>,
+}
+
+/// sends the TLS ClientHello in records of `size` bytes, `interval`
+/// milliseconds apart, padded with `padding` bytes. all are a number or a
+/// range like `10-30`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsFragmentOpt {
+    #[serde(deserialize_with = "utils::deserialize_range")]
+    pub size: String,
+    #[serde(default, deserialize_with = "utils::deserialize_range")]
+    pub interval: String,
+    /// only for the proxies that offer ALPN, as unknown protocols after them
+    #[serde(default, deserialize_with = "utils::deserialize_range")]
+    pub padding: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundShadowsocks {
     #[serde(flatten)]
@@ -160,11 +492,15 @@ pub struct OutboundShadowsocks {
     pub password: String,
     #[serde(default = "default_bool_true")]
     pub udp: bool,
+    /// carry all UDP sessions over a single socket instead of one socket
+    /// per session. needs a 2022 cipher.
+    #[serde(default)]
+    pub udp_mux: bool,
     pub plugin: Option<String>,
     pub plugin_opts: Option<HashMap<String, serde_yaml::Value>>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundSocks5 {
     #[serde(flatten)]
@@ -173,6 +509,7 @@ pub struct OutboundSocks5 {
     pub password: Option<String>,
     #[serde(default = "Default::default")]
     pub tls: bool,
+    #[serde(alias = "servername")]
     pub sni: Option<String>,
     #[serde(default = "Default::default")]
     pub skip_cert_verify: bool,
@@ -180,34 +517,64 @@ pub struct OutboundSocks5 {
     pub udp: bool,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct WsOpt {
+    /// `{host}`, `{port}` and `{rand}` in it are replaced for each connection
     pub path: Option<String>,
     pub headers: Option<HashMap<String, String>>,
     pub max_early_data: Option<i32>,
     pub early_data_header_name: Option<String>,
+    /// dialing through a browser is not supported, the server is dialed
+    /// directly with a warning
+    pub browser_forwarding: Option<bool>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+impl WsOpt {
+    /// the Host header defaults to the server, pin it before the server
+    /// is replaced by another endpoint
+    fn keep_host(&mut self, server: &str) {
+        self.headers
+            .get_or_insert_with(HashMap::new)
+            .entry("Host".to_owned())
+            .or_insert_with(|| server.to_owned());
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 pub struct H2Opt {
     pub host: Option<Vec<String>>,
     pub path: Option<String>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct GrpcOpt {
     pub grpc_service_name: Option<String>,
+    /// servers usually accept only one of the modes, `gun` by default
+    pub grpc_mode: Option<GrpcMode>,
+    pub grpc_user_agent: Option<String>,
+    /// HTTP/2 flow control windows, per stream and per connection
+    pub initial_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum GrpcMode {
+    #[default]
+    Gun,
+    Multi,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundTrojan {
     #[serde(flatten)]
     pub common_opts: CommonConfigOptions,
     pub password: String,
     pub alpn: Option<Vec<String>>,
+    #[serde(alias = "servername")]
     pub sni: Option<String>,
     pub skip_cert_verify: Option<bool>,
     pub udp: Option<bool>,
@@ -216,7 +583,7 @@ pub struct OutboundTrojan {
     pub ws_opts: Option<WsOpt>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundVmess {
     #[serde(flatten)]
@@ -228,7 +595,7 @@ pub struct OutboundVmess {
     pub udp: Option<bool>,
     pub tls: Option<bool>,
     pub skip_cert_verify: Option<bool>,
-    #[serde(alias = "servername")]
+    #[serde(alias = "servername", alias = "sni")]
     pub server_name: Option<String>,
     pub network: Option<String>,
     pub ws_opts: Option<WsOpt>,
@@ -245,6 +612,9 @@ pub struct OutboundWireguard {
     pub public_key: String,
     pub preshared_key: Option<String>,
     pub mtu: Option<u16>,
+    /// lowers `mtu` to what the path carries, by pinging the first IPv4 of
+    /// `dns`, or 1.1.1.1, through the tunnel
+    pub mtu_discovery: Option<bool>,
     pub udp: Option<bool>,
     pub ip: String,
     pub ipv6: Option<String>,
@@ -254,13 +624,26 @@ pub struct OutboundWireguard {
     pub reserved_bits: Option<Vec<u8>>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+/// A local page for the blocked domains, plain HTTP only
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct OutboundBlockPage {
+    pub name: String,
+    /// the page served, `{host}` is replaced with the blocked host
+    pub html: Option<String>,
+    /// redirects to this URL instead of serving the page
+    pub redirect: Option<String>,
+    /// of the page, 403 if not set
+    pub status: Option<u16>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundTor {
     pub name: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundTuic {
     #[serde(flatten)]
@@ -283,6 +666,7 @@ pub struct OutboundTuic {
     pub fast_open: Option<bool>,
     pub skip_cert_verify: Option<bool>,
     pub max_open_stream: Option<u64>,
+    #[serde(alias = "servername")]
     pub sni: Option<String>,
     /// millis
     pub gc_interval: Option<u64>,
@@ -293,7 +677,7 @@ pub struct OutboundTuic {
 }
 
 #[cfg(feature = "ssh")]
-#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutBoundSsh {
     #[serde(flatten)]
@@ -339,6 +723,8 @@ pub enum OutboundGroupProtocol {
     LoadBalance(OutboundGroupLoadBalance),
     #[serde(rename = "select")]
     Select(OutboundGroupSelect),
+    #[serde(rename = "smart")]
+    Smart(OutboundGroupSmart),
 }
 
 impl OutboundGroupProtocol {
@@ -349,6 +735,18 @@ impl OutboundGroupProtocol {
             OutboundGroupProtocol::Fallback(g) => &g.name,
             OutboundGroupProtocol::LoadBalance(g) => &g.name,
             OutboundGroupProtocol::Select(g) => &g.name,
+            OutboundGroupProtocol::Smart(g) => &g.name,
+        }
+    }
+
+    pub fn ui(&self) -> &UiMeta {
+        match &self {
+            OutboundGroupProtocol::Relay(g) => &g.ui,
+            OutboundGroupProtocol::UrlTest(g) => &g.ui,
+            OutboundGroupProtocol::Fallback(g) => &g.ui,
+            OutboundGroupProtocol::LoadBalance(g) => &g.ui,
+            OutboundGroupProtocol::Select(g) => &g.ui,
+            OutboundGroupProtocol::Smart(g) => &g.ui,
         }
     }
 
@@ -359,6 +757,7 @@ impl OutboundGroupProtocol {
             OutboundGroupProtocol::Fallback(g) => g.proxies.as_ref(),
             OutboundGroupProtocol::LoadBalance(g) => g.proxies.as_ref(),
             OutboundGroupProtocol::Select(g) => g.proxies.as_ref(),
+            OutboundGroupProtocol::Smart(g) => g.proxies.as_ref(),
         }
     }
 }
@@ -371,6 +770,7 @@ impl Display for OutboundGroupProtocol {
             OutboundGroupProtocol::Fallback(g) => write!(f, "{}", g.name),
             OutboundGroupProtocol::LoadBalance(g) => write!(f, "{}", g.name),
             OutboundGroupProtocol::Select(g) => write!(f, "{}", g.name),
+            OutboundGroupProtocol::Smart(g) => write!(f, "{}", g.name),
         }
     }
 }
@@ -381,7 +781,8 @@ pub struct OutboundGroupRelay {
     pub proxies: Option<Vec<String>>,
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
-    pub icon: Option<String>,
+    #[serde(flatten)]
+    pub ui: UiMeta,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -392,12 +793,21 @@ pub struct OutboundGroupUrlTest {
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
 
-    pub url: String,
-    #[serde(deserialize_with = "utils::deserialize_u64")]
-    pub interval: u64,
-    pub lazy: Option<bool>,
+    #[serde(flatten)]
+    pub health_check: HealthCheckSettings,
     pub tolerance: Option<u16>,
-    pub icon: Option<String>,
+    /// pick the proxy with the lowest UDP round trip time to the
+    /// `udp-target`, e.g. for games, rather than the fastest to the `url`.
+    /// the group or the global health check must have a `udp-target`
+    #[serde(rename = "rank-by-udp")]
+    pub rank_by_udp: Option<bool>,
+    /// close the connections through the previous fastest proxy on a switch
+    #[serde(rename = "interrupt-exist-connections")]
+    pub interrupt_exist_connections: Option<bool>,
+    #[serde(rename = "unknown-nodes")]
+    pub unknown_nodes: Option<UnknownNodes>,
+    #[serde(flatten)]
+    pub ui: UiMeta,
 }
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
 pub struct OutboundGroupFallback {
@@ -407,11 +817,12 @@ pub struct OutboundGroupFallback {
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
 
-    pub url: String,
-    #[serde(deserialize_with = "utils::deserialize_u64")]
-    pub interval: u64,
-    pub lazy: Option<bool>,
-    pub icon: Option<String>,
+    #[serde(flatten)]
+    pub health_check: HealthCheckSettings,
+    #[serde(rename = "unknown-nodes")]
+    pub unknown_nodes: Option<UnknownNodes>,
+    #[serde(flatten)]
+    pub ui: UiMeta,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
@@ -422,12 +833,68 @@ pub struct OutboundGroupLoadBalance {
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
 
-    pub url: String,
-    #[serde(deserialize_with = "utils::deserialize_u64")]
-    pub interval: u64,
-    pub lazy: Option<bool>,
+    #[serde(flatten)]
+    pub health_check: HealthCheckSettings,
     pub strategy: Option<LoadBalanceStrategy>,
-    pub icon: Option<String>,
+    /// consistent-hashing only, a node whose smoothed delay is worse than
+    /// the group median by this factor is skipped for the next one. Off by
+    /// default, each destination sticks to its hashed node
+    #[serde(rename = "spill-factor")]
+    pub spill_factor: Option<f64>,
+    /// sticky-sessions only, seconds a (source IP, destination) pair stays
+    /// on the same node since its last use
+    #[serde(rename = "sticky-ttl")]
+    pub sticky_ttl: Option<u64>,
+    #[serde(rename = "unknown-nodes")]
+    pub unknown_nodes: Option<UnknownNodes>,
+    #[serde(flatten)]
+    pub ui: UiMeta,
+}
+
+/// Experimental, picks the member by the delay and failures of the dials
+/// through it and its bandwidth, per destination
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
+pub struct OutboundGroupSmart {
+    pub name: String,
+
+    pub proxies: Option<Vec<String>>,
+    #[serde(rename = "use")]
+    pub use_provider: Option<Vec<String>>,
+
+    #[serde(flatten)]
+    pub health_check: HealthCheckSettings,
+    #[serde(rename = "unknown-nodes")]
+    pub unknown_nodes: Option<UnknownNodes>,
+    #[serde(flatten)]
+    pub ui: UiMeta,
+}
+
+/// How a group treats the proxies whose liveness is unknown: never checked,
+/// or not within the `stale-after` of the health check
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownNodes {
+    /// picked like the alive ones, after them when ranked by delay
+    #[default]
+    Tolerate,
+    /// left out like the dead ones
+    Skip,
+}
+
+/// What the delay a group ranks a proxy by is taken from
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum DelaySampling {
+    /// the last url test
+    #[default]
+    Last,
+    /// the exponentially weighted moving average of the successful url
+    /// tests kept
+    Ewma,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default)]
@@ -437,7 +904,7 @@ pub enum LoadBalanceStrategy {
     ConsistentHashing,
     #[serde(rename = "round-robin")]
     RoundRobin,
-    #[serde(rename = "sticky-session")]
+    #[serde(rename = "sticky-sessions", alias = "sticky-session")]
     StickySession,
 }
 
@@ -449,7 +916,11 @@ pub struct OutboundGroupSelect {
     #[serde(rename = "use")]
     pub use_provider: Option<Vec<String>>,
     pub udp: Option<bool>,
-    pub icon: Option<String>,
+    /// close the connections through the previous selection on a switch
+    #[serde(rename = "interrupt-exist-connections")]
+    pub interrupt_exist_connections: Option<bool>,
+    #[serde(flatten)]
+    pub ui: UiMeta,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -460,6 +931,58 @@ pub enum OutboundProxyProviderDef {
     File(OutboundFileProvider),
 }
 
+impl OutboundProxyProviderDef {
+    pub fn region_groups(&self) -> Option<&RegionGroups> {
+        match self {
+            OutboundProxyProviderDef::Http(p) => p.region_groups.as_ref(),
+            OutboundProxyProviderDef::File(p) => p.region_groups.as_ref(),
+        }
+    }
+}
+
+/// the regions of `region-groups` left out
+pub const DEFAULT_REGIONS: [&str; 6] = ["HK", "TW", "JP", "SG", "US", "KR"];
+
+/// Groups of the proxies of a provider by region, `<provider>-<region>` each,
+/// e.g. `provider1-HK`. A proxy is in the region its name tells, by a flag,
+/// a place or a code, or else in the country of its server by GeoIP
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegionGroups {
+    #[serde(rename = "type", default)]
+    pub group_type: RegionGroupType,
+    /// ISO codes, e.g. `HK`, `DEFAULT_REGIONS` by default
+    pub regions: Option<Vec<String>>,
+    /// a regex of the names of the proxies in a region, over the keywords
+    /// built in, e.g. `HK: "(?i)hkg|港"`
+    #[serde(default)]
+    pub patterns: HashMap<String, String>,
+}
+
+impl RegionGroups {
+    pub fn regions(&self) -> Vec<String> {
+        match &self.regions {
+            Some(regions) => regions.iter().map(|x| x.to_uppercase()).collect(),
+            None => DEFAULT_REGIONS.map(str::to_owned).to_vec(),
+        }
+    }
+
+    /// the name of the group, and of the provider behind it
+    pub fn group_name(provider: &str, region: &str) -> String {
+        format!("{}-{}", provider, region)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegionGroupType {
+    #[default]
+    UrlTest,
+    Fallback,
+    LoadBalance,
+    Select,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub struct OutboundHttpProvider {
@@ -468,7 +991,9 @@ pub struct OutboundHttpProvider {
     pub url: String,
     pub interval: u64,
     pub path: String,
+    #[serde(default)]
     pub health_check: HealthCheck,
+    pub region_groups: Option<RegionGroups>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
@@ -478,15 +1003,272 @@ pub struct OutboundFileProvider {
     pub name: String,
     pub path: String,
     pub interval: Option<u64>,
+    #[serde(default)]
     pub health_check: HealthCheck,
+    pub region_groups: Option<RegionGroups>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
 pub struct HealthCheck {
-    pub enable: bool,
+    /// false stops the periodic checks, whatever the `interval`
+    pub enable: Option<bool>,
+    #[serde(flatten)]
+    pub settings: HealthCheckSettings,
+    pub bandwidth: Option<BandwidthCheck>,
+}
+
+impl HealthCheck {
+    /// the settings of the provider over the global ones
+    pub fn settings(&self, global: &HealthCheckSettings) -> HealthCheckSettings {
+        let mut settings = self.settings.inherit(global);
+        if self.enable == Some(false) {
+            settings.interval = Some(0);
+        }
+        settings
+    }
+}
+
+const DEFAULT_HEALTH_CHECK_URL: &str = "http://www.gstatic.com/generate_204";
+const DEFAULT_HEALTH_CHECK_CONCURRENCY: usize = 16;
+const DEFAULT_DELAY_HISTORY_SIZE: usize = 10;
+
+/// The latency checks of proxies. Each field left out is taken from the level
+/// above: the global `health-check`, then the `health-check` of a provider for
+/// its proxies, or a group for those in its `proxies`. A proxy's own
+/// `health-check-url` overrides the URL it's checked with wherever it is
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthCheckSettings {
+    pub url: Option<String>,
+    /// seconds between the checks, 0 checks only when asked to
+    #[serde(default, deserialize_with = "utils::deserialize_option_u64")]
+    pub interval: Option<u64>,
+    /// skip a check if the proxies were used since the last one
+    pub lazy: Option<bool>,
+    /// a DNS server, e.g. `1.1.1.1:53`, or a STUN server, e.g.
+    /// `stun://stun.l.google.com:19302`, queried through the proxies that
+    /// relay UDP on each check, for their UDP round trip time. The UDP of
+    /// the fallback and load-balance groups skips the proxies it fails on
+    pub udp_target: Option<String>,
+    /// milliseconds a check waits for the response, 5000 by default
+    #[serde(default, deserialize_with = "utils::deserialize_option_u64")]
+    pub timeout: Option<u64>,
+    /// the statuses a proxy must get to be alive, e.g. `204` or `200-299`,
+    /// any by default. Redirects aren't followed, so an exit redirected to
+    /// another region fails `204` with its `302`
+    pub expected_status: Option<ExpectedStatus>,
+    /// a text the body of the response must contain for the proxy to be
+    /// alive
+    pub expected_keyword: Option<String>,
+    /// `tcp` or `tls` only times the connection to the host of the `url`
+    /// through the proxies, or its TLS handshake too, for targets not
+    /// serving HTTP. `http` by default
+    pub mode: Option<CheckMode>,
+    /// checks run at once over all the groups and providers, 16 by default
+    /// and 0 for no limit. Only the global one applies
+    pub max_concurrent: Option<usize>,
+    /// seconds after which the last check of a proxy no longer tells if it's
+    /// alive, 0 by default to keep it until the next one. Only the global one
+    /// applies
+    #[serde(default, deserialize_with = "utils::deserialize_option_u64")]
+    pub stale_after: Option<u64>,
+    /// url tests kept for each proxy, 10 by default. Only the global one
+    /// applies
+    pub history_size: Option<usize>,
+    /// `last` by default, or `ewma` for the groups to rank the proxies by
+    /// the moving average of their history, so a single slow test between
+    /// long intervals doesn't switch them. Only the global one applies
+    pub delay_sampling: Option<DelaySampling>,
+}
+
+impl HealthCheckSettings {
+    /// `self` with the fields left out taken from `parent`
+    pub fn inherit(&self, parent: &Self) -> Self {
+        Self {
+            url: self.url.clone().or_else(|| parent.url.clone()),
+            interval: self.interval.or(parent.interval),
+            lazy: self.lazy.or(parent.lazy),
+            udp_target: self
+                .udp_target
+                .clone()
+                .or_else(|| parent.udp_target.clone()),
+            timeout: self.timeout.or(parent.timeout),
+            expected_status: self
+                .expected_status
+                .clone()
+                .or_else(|| parent.expected_status.clone()),
+            expected_keyword: self
+                .expected_keyword
+                .clone()
+                .or_else(|| parent.expected_keyword.clone()),
+            mode: self.mode.or(parent.mode),
+            max_concurrent: self.max_concurrent.or(parent.max_concurrent),
+            stale_after: self.stale_after.or(parent.stale_after),
+            history_size: self.history_size.or(parent.history_size),
+            delay_sampling: self.delay_sampling.or(parent.delay_sampling),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        self.url
+            .clone()
+            .unwrap_or_else(|| DEFAULT_HEALTH_CHECK_URL.to_owned())
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or_default()
+    }
+
+    pub fn lazy(&self) -> bool {
+        self.lazy.unwrap_or_default()
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+            .unwrap_or(DEFAULT_HEALTH_CHECK_CONCURRENCY)
+    }
+
+    pub fn stale_after(&self) -> u64 {
+        self.stale_after.unwrap_or_default()
+    }
+
+    pub fn history_size(&self) -> usize {
+        self.history_size
+            .unwrap_or(DEFAULT_DELAY_HISTORY_SIZE)
+            .max(1)
+    }
+
+    pub fn delay_sampling(&self) -> DelaySampling {
+        self.delay_sampling.unwrap_or_default()
+    }
+
+    /// every field set, to the defaults where nothing set them
+    pub fn effective(&self) -> Self {
+        Self {
+            url: Some(self.url()),
+            interval: Some(self.interval()),
+            lazy: Some(self.lazy()),
+            udp_target: self.udp_target.clone(),
+            timeout: self.timeout,
+            expected_status: self.expected_status.clone(),
+            expected_keyword: self.expected_keyword.clone(),
+            mode: self.mode,
+            max_concurrent: self.max_concurrent,
+            stale_after: self.stale_after,
+            history_size: self.history_size,
+            delay_sampling: self.delay_sampling,
+        }
+    }
+}
+
+/// What a latency check times through a proxy
+#[derive(
+    serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckMode {
+    /// two requests to the `url`
+    #[default]
+    Http,
+    /// connecting to the host of the `url`, through DIRECT and socks5 only,
+    /// the other proxies connect before the server answers and are checked
+    /// as in `Tls`
+    Tcp,
+    /// connecting to the host of the `url` and the TLS handshake with it
+    Tls,
+}
+
+impl CheckMode {
+    pub fn is_http(&self) -> bool {
+        *self == Self::Http
+    }
+}
+
+/// `/`-separated statuses or ranges of them, e.g. `200/300-399`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpectedStatus(Vec<(u16, u16)>);
+
+impl ExpectedStatus {
+    pub fn matches(&self, status: u16) -> bool {
+        self.0.iter().any(|(lo, hi)| (*lo..=*hi).contains(&status))
+    }
+}
+
+impl FromStr for ExpectedStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || Error::InvalidConfig(format!("invalid expected-status: {}", s));
+        s.split('/')
+            .map(|x| {
+                let (lo, hi) = x.split_once('-').unwrap_or((x, x));
+                let lo = lo.trim().parse::<u16>().map_err(|_| invalid())?;
+                let hi = hi.trim().parse::<u16>().map_err(|_| invalid())?;
+                if lo > hi {
+                    return Err(invalid());
+                }
+                Ok((lo, hi))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Display for ExpectedStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, (lo, hi)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "/")?;
+            }
+            if lo == hi {
+                write!(f, "{}", lo)?;
+            } else {
+                write!(f, "{}-{}", lo, hi)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for ExpectedStatus {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        utils::deserialize_range(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl serde::Serialize for ExpectedStatus {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// Downloads from `url` through each proxy every `interval` seconds, one
+/// proxy at a time, to measure its bandwidth
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct BandwidthCheck {
     pub url: String,
+    /// bytes to download at most
+    #[serde(default = "default_bandwidth_check_size")]
+    pub size: u64,
+    #[serde(default = "default_bandwidth_check_interval")]
     pub interval: u64,
-    pub lazy: Option<bool>,
+}
+
+fn default_bandwidth_check_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_bandwidth_check_interval() -> u64 {
+    3600
 }
 
 impl TryFrom<HashMap<String, Value>> for OutboundProxyProviderDef {
@@ -523,6 +1305,7 @@ pub struct OutboundHysteria2 {
     pub up: Option<u64>,
     /// receive_bps: send by auth request
     pub down: Option<u64>,
+    #[serde(alias = "servername")]
     pub sni: Option<String>,
     pub skip_cert_verify: bool,
     pub ca: Option<String>,
@@ -532,6 +1315,15 @@ pub struct OutboundHysteria2 {
     pub disable_mtu_discovery: Option<bool>,
     /// bbr congestion control window
     pub cwnd: Option<u64>,
+    /// see `CommonConfigOptions::ip_version`
+    pub ip_version: Option<IpVersion>,
+    /// not supported over QUIC, only read to be refused
+    pub connect_timeout: Option<u64>,
+    pub tls_handshake_timeout: Option<u64>,
+    pub handshake_timeout: Option<u64>,
+    /// see `CommonConfigOptions::backup_endpoints`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backup_endpoints: Vec<String>,
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
@@ -539,3 +1331,317 @@ pub struct OutboundHysteria2 {
 pub enum Hysteria2Obfs {
     Salamander,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_yaml::Value;
+
+    use super::{
+        CheckMode, DEFAULT_HEALTH_CHECK_URL, DialerOptions, ExpectedStatus,
+        HealthCheckSettings, IpVersion, OutboundGroupProtocol,
+        OutboundProxyProtocol, OutboundProxyProviderDef, TlsFragmentOpt, UiMeta,
+        parse_endpoint,
+    };
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            parse_endpoint("relay.example.com:8443"),
+            Some(("relay.example.com".to_owned(), 8443))
+        );
+        assert_eq!(
+            parse_endpoint("[2001:db8::1]:443"),
+            Some(("2001:db8::1".to_owned(), 443))
+        );
+        assert_eq!(parse_endpoint("2001:db8::1:443"), None);
+        assert_eq!(parse_endpoint("example.com"), None);
+        assert_eq!(parse_endpoint(":443"), None);
+    }
+
+    #[test]
+    fn test_backup_endpoints() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: t
+type: trojan
+server: proxy.example.com
+port: 443
+password: x
+network: ws
+ws-opts:
+  path: /ws
+backup-endpoints:
+  - 10.0.0.1:8443
+  - "[2001:db8::1]:443"
+"#,
+        )
+        .unwrap();
+        let proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        assert_eq!(proxy.endpoint().as_deref(), Some("proxy.example.com:443"));
+
+        let backups = proxy.backup_endpoints().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[1].endpoint().as_deref(), Some("[2001:db8::1]:443"));
+        let OutboundProxyProtocol::Trojan(backup) = &backups[0] else {
+            unreachable!()
+        };
+        assert_eq!(backup.common_opts.server, "10.0.0.1");
+        assert_eq!(backup.common_opts.port, 8443);
+        assert!(backup.common_opts.backup_endpoints.is_empty());
+        assert_eq!(backup.sni.as_deref(), Some("proxy.example.com"));
+        assert_eq!(
+            backup.ws_opts.as_ref().unwrap().headers.as_ref().unwrap()["Host"],
+            "proxy.example.com"
+        );
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: s, type: socks5, server: a, port: 1, backup-endpoints: [b]}",
+        )
+        .unwrap();
+        let proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        assert!(proxy.backup_endpoints().is_err());
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: h, type: hysteria2, server: hy.example.com, port: 443, \
+             password: x, backup-endpoints: ['10.0.0.2:8443']}",
+        )
+        .unwrap();
+        let proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        assert_eq!(proxy.servers(), vec!["hy.example.com", "10.0.0.2"]);
+        let backups = proxy.backup_endpoints().unwrap();
+        let OutboundProxyProtocol::Hysteria2(backup) = &backups[0] else {
+            unreachable!()
+        };
+        assert_eq!((backup.server.as_str(), backup.port), ("10.0.0.2", 8443));
+        assert!(backup.backup_endpoints.is_empty());
+        assert_eq!(backup.sni.as_deref(), Some("hy.example.com"));
+    }
+
+    #[test]
+    fn test_dialer_opts() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: hy2
+type: hysteria2
+server: hy2.example.com
+port: 443
+password: x
+skip-cert-verify: false
+ip-version: ipv6-prefer
+"#,
+        )
+        .unwrap();
+        let proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        assert_eq!(proxy.dialer_opts().ip_version, Some(IpVersion::Ipv6Prefer));
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: s, type: socks5, server: a, port: 1, routing-mark: 255, \
+             source-ip: 192.168.2.10}",
+        )
+        .unwrap();
+        let proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        assert_eq!(
+            proxy.dialer_opts(),
+            DialerOptions {
+                ip_version: None,
+                routing_mark: Some(255),
+                source_ip: Some("192.168.2.10".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_tls_in_tls_fragment() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: t, type: trojan, server: a, port: 443, password: x, \
+             tls-in-tls-fragment: {size: 10-20}}",
+        )
+        .unwrap();
+        assert!(OutboundProxyProtocol::try_from(mapping).is_ok());
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: w, type: wireguard, server: a, port: 51820, private-key: x, \
+             public-key: y, ip: 10.0.0.2, tls-in-tls-fragment: {size: 10-20}}",
+        )
+        .unwrap();
+        assert!(OutboundProxyProtocol::try_from(mapping).is_err());
+    }
+
+    #[test]
+    fn test_inherit_tls_fragment() {
+        let default = TlsFragmentOpt {
+            size: "10-20".to_owned(),
+            ..Default::default()
+        };
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: t, type: trojan, server: a, port: 443, password: x}",
+        )
+        .unwrap();
+        let mut proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        proxy.inherit_tls_fragment(Some(&default));
+        let OutboundProxyProtocol::Trojan(trojan) = &proxy else {
+            unreachable!()
+        };
+        assert_eq!(
+            trojan.common_opts.tls_fragment.as_ref().unwrap().size,
+            "10-20"
+        );
+
+        // its own stays
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: t, type: trojan, server: a, port: 443, password: x, \
+             tls-fragment: {size: 1-5, padding: 100}}",
+        )
+        .unwrap();
+        let mut proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        proxy.inherit_tls_fragment(Some(&default));
+        let OutboundProxyProtocol::Trojan(trojan) = &proxy else {
+            unreachable!()
+        };
+        let fragment = trojan.common_opts.tls_fragment.as_ref().unwrap();
+        assert_eq!(
+            (fragment.size.as_str(), fragment.padding.as_str()),
+            ("1-5", "100")
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_check() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            r#"
+name: p
+type: file
+path: ./ss.yaml
+health-check:
+  enable: true
+  url: http://www.gstatic.com/generate_204
+  interval: 300
+  bandwidth:
+    url: https://speed.cloudflare.com/__down?bytes=5000000
+    size: 5000000
+"#,
+        )
+        .unwrap();
+        let OutboundProxyProviderDef::File(provider) =
+            OutboundProxyProviderDef::try_from(mapping).unwrap()
+        else {
+            unreachable!()
+        };
+        let bandwidth = provider.health_check.bandwidth.unwrap();
+        assert_eq!(bandwidth.size, 5_000_000);
+        assert_eq!(bandwidth.interval, 3600);
+    }
+
+    #[test]
+    fn test_health_check_inheritance() {
+        let global = HealthCheckSettings {
+            url: Some("https://cp.cloudflare.com/generate_204".to_owned()),
+            interval: Some(600),
+            lazy: None,
+            udp_target: Some("1.1.1.1:53".to_owned()),
+            expected_status: Some("200-299".parse().unwrap()),
+            ..Default::default()
+        };
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: auto, type: url-test, proxies: [a], interval: '300', \
+             rank-by-udp: true, mode: tcp}",
+        )
+        .unwrap();
+        let OutboundGroupProtocol::UrlTest(group) =
+            OutboundGroupProtocol::try_from(mapping).unwrap()
+        else {
+            unreachable!()
+        };
+        let settings = group.health_check.inherit(&global).effective();
+        assert_eq!(settings.url, global.url);
+        assert_eq!(settings.interval, Some(300));
+        assert_eq!(settings.lazy, Some(false));
+        assert_eq!(settings.udp_target, global.udp_target);
+        assert_eq!(settings.expected_status, global.expected_status);
+        assert_eq!(settings.mode, Some(CheckMode::Tcp));
+        assert_eq!(group.rank_by_udp, Some(true));
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: p, type: file, path: p.yaml, health-check: {enable: false}}",
+        )
+        .unwrap();
+        let OutboundProxyProviderDef::File(provider) =
+            OutboundProxyProviderDef::try_from(mapping).unwrap()
+        else {
+            unreachable!()
+        };
+        let settings = provider.health_check.settings(&global);
+        assert_eq!(settings.interval(), 0);
+        assert_eq!(settings.url(), global.url.clone().unwrap());
+
+        let settings = HealthCheckSettings::default().inherit(&Default::default());
+        assert_eq!(settings.url(), DEFAULT_HEALTH_CHECK_URL);
+
+        let proxy = OutboundProxyProtocol::try_from(
+            serde_yaml::from_str::<HashMap<String, Value>>(
+                "{name: s, type: socks5, server: a, port: 1, health-check-url: \
+                 http://example.com}",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(proxy.health_check_url(), Some("http://example.com"));
+    }
+
+    #[test]
+    fn test_expected_status() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: jp, type: fallback, proxies: [a], timeout: 2000, \
+             expected-status: 204, expected-keyword: JP}",
+        )
+        .unwrap();
+        let OutboundGroupProtocol::Fallback(group) =
+            OutboundGroupProtocol::try_from(mapping).unwrap()
+        else {
+            unreachable!()
+        };
+        let settings = group.health_check;
+        assert_eq!(settings.timeout, Some(2000));
+        assert_eq!(settings.expected_keyword.as_deref(), Some("JP"));
+        let status = settings.expected_status.unwrap();
+        assert!(status.matches(204));
+        assert!(!status.matches(302));
+
+        let status: ExpectedStatus = "200 / 300-399".parse().unwrap();
+        assert!(status.matches(200) && status.matches(302));
+        assert!(!status.matches(204));
+        assert_eq!(status.to_string(), "200/300-399");
+
+        assert!("".parse::<ExpectedStatus>().is_err());
+        assert!("399-300".parse::<ExpectedStatus>().is_err());
+    }
+
+    #[test]
+    fn test_ui_meta() {
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: streaming, type: select, proxies: [a], \
+             icon: https://example.com/tv.png, hidden: true, order: 2}",
+        )
+        .unwrap();
+        let group = OutboundGroupProtocol::try_from(mapping).unwrap();
+        assert_eq!(
+            group.ui(),
+            &UiMeta {
+                icon: Some("https://example.com/tv.png".to_owned()),
+                hidden: Some(true),
+                order: Some(2),
+            }
+        );
+
+        let mapping: HashMap<String, Value> = serde_yaml::from_str(
+            "{name: s, type: socks5, server: 10.0.0.1, port: 1080, order: -1}",
+        )
+        .unwrap();
+        let proxy = OutboundProxyProtocol::try_from(mapping).unwrap();
+        assert_eq!(proxy.ui().unwrap().order, Some(-1));
+        assert_eq!(proxy.ui().unwrap().hidden, None);
+    }
+}
@@ -1,5 +1,18 @@
-use crate::{Error, print_and_exit};
-use std::{fmt::Display, str::FromStr};
+use crate::{
+    Error,
+    session::{Protocol, SocksAddr},
+};
+use chrono::{Datelike, FixedOffset, Local, NaiveDateTime, Timelike, Utc, Weekday};
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// the targets of the rules that turn sniffing on or off for the sessions
+/// they match instead of routing them
+pub const FORCE_SNIFF: &str = "FORCE-SNIFF";
+pub const SKIP_SNIFF: &str = "SKIP-SNIFF";
 
 pub enum RuleType {
     Domain {
@@ -27,6 +40,12 @@ pub enum RuleType {
         target: String,
         country_code: String,
     },
+    /// the destination IP in the autonomous system `asn` of the ASN mmdb
+    IpAsn {
+        asn: u32,
+        target: String,
+        no_resolve: bool,
+    },
     IpCidr {
         ipnet: ipnet::IpNet,
         target: String,
@@ -57,9 +76,230 @@ pub enum RuleType {
         rule_set: String,
         target: String,
     },
+    Protocol {
+        protocol: Protocol,
+        target: String,
+    },
+    Schedule {
+        schedule: Schedule,
+        target: String,
+    },
     Match {
         target: String,
     },
+    /// any of the above with a `schedule=` param
+    Scheduled {
+        rule: Box<RuleType>,
+        schedule: Schedule,
+    },
+    /// any of the above with a `nodelay=` param
+    NoDelay {
+        rule: Box<RuleType>,
+        nodelay: bool,
+    },
+    /// any of the above with a `dst=` param
+    Rewrite {
+        rule: Box<RuleType>,
+        destination: DestinationOverride,
+    },
+}
+
+/// Where a matched connection is sent instead of its original destination,
+/// set with the `dst=host[:port]` rule param, e.g.
+/// `DOMAIN,example.com,PROXY,dst=1.2.3.4:443`.
+/// The original port is kept if none is given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DestinationOverride {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl DestinationOverride {
+    pub fn apply(&self, original: &SocksAddr) -> SocksAddr {
+        let port = self.port.unwrap_or(original.port());
+        match self.host.parse::<IpAddr>() {
+            Ok(ip) => SocksAddr::Ip(SocketAddr::new(ip, port)),
+            Err(_) => SocksAddr::Domain(self.host.clone(), port),
+        }
+    }
+}
+
+impl FromStr for DestinationOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                host: addr.ip().to_string(),
+                port: Some(addr.port()),
+            });
+        }
+        if let Ok(ip) = s.trim_matches(['[', ']']).parse::<IpAddr>() {
+            return Ok(Self {
+                host: ip.to_string(),
+                port: None,
+            });
+        }
+
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                Some(port.parse::<u16>().map_err(|_| {
+                    Error::InvalidConfig(format!("invalid dst port: {}", s))
+                })?),
+            ),
+            None => (s, None),
+        };
+        if host.is_empty() || host.contains(':') {
+            return Err(Error::InvalidConfig(format!("invalid dst: {}", s)));
+        }
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+impl Display for DestinationOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let host = match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => self.host.clone(),
+        };
+        match self.port {
+            Some(port) => write!(f, "{}:{}", host, port),
+            None => write!(f, "{}", host),
+        }
+    }
+}
+
+const EVERY_DAY: u8 = 0b111_1111;
+
+/// The time window of the `SCHEDULE` rule and the `schedule=` rule param:
+/// weekdays, a time of day and a UTC offset, separated by spaces, e.g.
+/// `Sun-Thu 21:00-07:00 +08:00`. Any of them may be left out but not all,
+/// the local time is used without an offset.
+/// A window that ends before it starts ends the next day, and belongs to the
+/// weekday it starts on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    spec: String,
+    /// bit 0 is Monday
+    days: u8,
+    /// minutes from midnight
+    window: Option<(u16, u16)>,
+    offset: Option<FixedOffset>,
+}
+
+impl Schedule {
+    pub fn is_active(&self) -> bool {
+        let now = match self.offset {
+            Some(offset) => Utc::now().with_timezone(&offset).naive_local(),
+            None => Local::now().naive_local(),
+        };
+        self.contains(now)
+    }
+
+    fn contains(&self, t: NaiveDateTime) -> bool {
+        let day = t.weekday();
+        let minute = (t.hour() * 60 + t.minute()) as u16;
+        match self.window {
+            None => self.has_day(day),
+            Some((start, end)) if start < end => {
+                self.has_day(day) && (start..end).contains(&minute)
+            }
+            Some((start, end)) => {
+                (self.has_day(day) && minute >= start)
+                    || (self.has_day(day.pred()) && minute < end)
+            }
+        }
+    }
+
+    fn has_day(&self, day: Weekday) -> bool {
+        self.days & (1 << day.num_days_from_monday()) != 0
+    }
+}
+
+/// `HH:MM`, `24:00` for the end of the day
+fn parse_minutes(s: &str) -> Option<u16> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m) = (h.parse::<u16>().ok()?, m.parse::<u16>().ok()?);
+    if m < 60 && (h < 24 || (h, m) == (24, 0)) {
+        Some(h * 60 + m)
+    } else {
+        None
+    }
+}
+
+/// `+HH:MM`, `-HH` or `UTC`
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    if s.eq_ignore_ascii_case("utc") {
+        return FixedOffset::east_opt(0);
+    }
+    let (sign, rest) = match s.split_at_checked(1)? {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return None,
+    };
+    let (h, m) = rest.split_once(':').unwrap_or((rest, "0"));
+    let (h, m) = (h.parse::<i32>().ok()?, m.parse::<i32>().ok()?);
+    if m >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (h * 3600 + m * 60))
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidConfig(format!("invalid schedule: {}", s));
+        let mut schedule = Self {
+            spec: s.trim().to_owned(),
+            days: EVERY_DAY,
+            window: None,
+            offset: None,
+        };
+        let mut empty = true;
+
+        for token in s.split_whitespace() {
+            if token.starts_with(['+', '-']) || token.eq_ignore_ascii_case("utc") {
+                schedule.offset = Some(parse_offset(token).ok_or_else(invalid)?);
+            } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+                let (start, end) = token.split_once('-').ok_or_else(invalid)?;
+                schedule.window = Some((
+                    parse_minutes(start).ok_or_else(invalid)?,
+                    parse_minutes(end).ok_or_else(invalid)?,
+                ));
+                empty = false;
+            } else {
+                // `Mon-Fri`, `Sat+Sun` or both, `Fri-Mon` wraps around
+                schedule.days = 0;
+                for part in token.split('+') {
+                    let (first, last) = part.split_once('-').unwrap_or((part, part));
+                    let first = first.parse::<Weekday>().map_err(|_| invalid())?;
+                    let last = last.parse::<Weekday>().map_err(|_| invalid())?;
+                    let mut day = first;
+                    loop {
+                        schedule.days |= 1 << day.num_days_from_monday();
+                        if day == last {
+                            break;
+                        }
+                        day = day.succ();
+                    }
+                }
+                empty = false;
+            }
+        }
+
+        if empty { Err(invalid()) } else { Ok(schedule) }
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.spec)
+    }
 }
 
 impl RuleType {
@@ -71,6 +311,7 @@ impl RuleType {
             RuleType::DomainKeyword { target, .. } => target,
             RuleType::GeoIP { target, .. } => target,
             RuleType::GeoSite { target, .. } => target,
+            RuleType::IpAsn { target, .. } => target,
             RuleType::IpCidr { target, .. } => target,
             RuleType::SrcCidr { target, .. } => target,
             RuleType::SRCPort { target, .. } => target,
@@ -78,9 +319,19 @@ impl RuleType {
             RuleType::ProcessName { target, .. } => target,
             RuleType::ProcessPath { target, .. } => target,
             RuleType::RuleSet { target, .. } => target,
+            RuleType::Protocol { target, .. } => target,
+            RuleType::Schedule { target, .. } => target,
             RuleType::Match { target } => target,
+            RuleType::Scheduled { rule, .. } => rule.target(),
+            RuleType::NoDelay { rule, .. } => rule.target(),
+            RuleType::Rewrite { rule, .. } => rule.target(),
         }
     }
+
+    /// a `FORCE-SNIFF` or `SKIP-SNIFF` rule
+    pub fn is_sniff_override(&self) -> bool {
+        matches!(self.target(), FORCE_SNIFF | SKIP_SNIFF)
+    }
 }
 
 impl Display for RuleType {
@@ -96,6 +347,7 @@ impl Display for RuleType {
             RuleType::DomainKeyword { .. } => write!(f, "DOMAIN-KEYWORD"),
             RuleType::GeoIP { .. } => write!(f, "GEOIP"),
             RuleType::GeoSite { .. } => write!(f, "GEOSITE"),
+            RuleType::IpAsn { .. } => write!(f, "IP-ASN"),
             RuleType::IpCidr { .. } => write!(f, "IP-CIDR"),
             RuleType::SrcCidr { .. } => write!(f, "SRC-IP-CIDR"),
             RuleType::SRCPort { .. } => write!(f, "SRC-PORT"),
@@ -103,7 +355,12 @@ impl Display for RuleType {
             RuleType::ProcessName { .. } => write!(f, "PROCESS-NAME"),
             RuleType::ProcessPath { .. } => write!(f, "PROCESS-PATH"),
             RuleType::RuleSet { .. } => write!(f, "RULE-SET"),
+            RuleType::Protocol { .. } => write!(f, "PROTOCOL"),
+            RuleType::Schedule { .. } => write!(f, "SCHEDULE"),
             RuleType::Match { .. } => write!(f, "MATCH"),
+            RuleType::Scheduled { rule, .. } => rule.fmt(f),
+            RuleType::NoDelay { rule, .. } => rule.fmt(f),
+            RuleType::Rewrite { rule, .. } => rule.fmt(f),
         }
     }
 }
@@ -146,6 +403,17 @@ impl RuleType {
                     false
                 },
             }),
+            "IP-ASN" => Ok(RuleType::IpAsn {
+                asn: payload.trim_start_matches("AS").parse().map_err(|_| {
+                    Error::InvalidConfig(format!("invalid asn: {}", payload))
+                })?,
+                target: target.to_string(),
+                no_resolve: if let Some(params) = params {
+                    params.contains(&"no-resolve")
+                } else {
+                    false
+                },
+            }),
             "IP-CIDR" | "IP-CIDR6" => Ok(RuleType::IpCidr {
                 ipnet: payload.parse()?,
                 target: target.to_string(),
@@ -166,15 +434,15 @@ impl RuleType {
             }),
             "SRC-PORT" => Ok(RuleType::SRCPort {
                 target: target.to_string(),
-                port: payload.parse().unwrap_or_else(|_| {
-                    print_and_exit!("invalid port: {}", payload)
-                }),
+                port: payload.parse().map_err(|_| {
+                    Error::InvalidConfig(format!("invalid port: {}", payload))
+                })?,
             }),
             "DST-PORT" => Ok(RuleType::DSTPort {
                 target: target.to_string(),
-                port: payload.parse().unwrap_or_else(|_| {
-                    print_and_exit!("invalid port: {}", payload)
-                }),
+                port: payload.parse().map_err(|_| {
+                    Error::InvalidConfig(format!("invalid port: {}", payload))
+                })?,
             }),
             "PROCESS-NAME" => Ok(RuleType::ProcessName {
                 process_name: payload.to_string(),
@@ -188,6 +456,14 @@ impl RuleType {
                 rule_set: payload.to_string(),
                 target: target.to_string(),
             }),
+            "PROTOCOL" => Ok(RuleType::Protocol {
+                protocol: payload.parse().map_err(Error::InvalidConfig)?,
+                target: target.to_string(),
+            }),
+            "SCHEDULE" => Ok(RuleType::Schedule {
+                schedule: payload.parse()?,
+                target: target.to_string(),
+            }),
             "MATCH" => Ok(RuleType::Match {
                 target: target.to_string(),
             }),
@@ -209,7 +485,45 @@ impl TryFrom<String> for RuleType {
             [proto, target] => RuleType::new(proto, "", target, None),
             [proto, payload, target] => RuleType::new(proto, payload, target, None),
             [proto, payload, target, params @ ..] => {
-                RuleType::new(proto, payload, target, Some(params.to_vec()))
+                let (wrappers, params): (Vec<&str>, Vec<&str>) =
+                    params.iter().partition(|p| {
+                        p.starts_with("dst=")
+                            || p.starts_with("schedule=")
+                            || p.starts_with("nodelay=")
+                    });
+                let mut rule = RuleType::new(proto, payload, target, Some(params))?;
+                if let Some(schedule) = wrappers
+                    .iter()
+                    .rev()
+                    .find_map(|p| p.strip_prefix("schedule="))
+                {
+                    rule = RuleType::Scheduled {
+                        rule: Box::new(rule),
+                        schedule: schedule.parse()?,
+                    };
+                }
+                if let Some(nodelay) = wrappers
+                    .iter()
+                    .rev()
+                    .find_map(|p| p.strip_prefix("nodelay="))
+                {
+                    rule = RuleType::NoDelay {
+                        rule: Box::new(rule),
+                        nodelay: nodelay.parse().map_err(|_| {
+                            Error::InvalidConfig(format!(
+                                "invalid nodelay: {}",
+                                nodelay
+                            ))
+                        })?,
+                    };
+                }
+                match wrappers.iter().rev().find_map(|p| p.strip_prefix("dst=")) {
+                    Some(dst) => Ok(RuleType::Rewrite {
+                        rule: Box::new(rule),
+                        destination: dst.parse()?,
+                    }),
+                    None => Ok(rule),
+                }
             }
             _ => Err(Error::InvalidConfig(format!("invalid rule line: {}", line))),
         }
@@ -223,3 +537,152 @@ impl FromStr for RuleType {
         s.to_string().try_into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{DestinationOverride, RuleType, Schedule};
+    use crate::session::SocksAddr;
+
+    #[test]
+    fn test_parse_rewrite() {
+        let rule: RuleType =
+            "DOMAIN,example.com,PROXY,dst=1.2.3.4:443".parse().unwrap();
+        let RuleType::Rewrite { rule, destination } = rule else {
+            panic!("expected a rewrite rule");
+        };
+        assert_eq!(rule.target(), "PROXY");
+        assert_eq!(destination.to_string(), "1.2.3.4:443");
+
+        let rule: RuleType = "IP-CIDR,10.0.0.0/8,DIRECT,no-resolve,dst=[::1]"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            rule,
+            RuleType::Rewrite { ref rule, .. }
+                if matches!(**rule, RuleType::IpCidr { no_resolve: true, .. })
+        ));
+
+        assert!(
+            "DOMAIN,example.com,PROXY,dst=a:b:c"
+                .parse::<RuleType>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_nodelay() {
+        let rule: RuleType = "DOMAIN-SUFFIX,dl.example.com,DIRECT,nodelay=false"
+            .parse()
+            .unwrap();
+        let RuleType::NoDelay { rule, nodelay } = rule else {
+            panic!("expected a nodelay rule");
+        };
+        assert!(!nodelay);
+        assert_eq!(rule.target(), "DIRECT");
+
+        let rule: RuleType = "DOMAIN,example.com,PROXY,nodelay=true,dst=1.2.3.4"
+            .parse()
+            .unwrap();
+        assert!(matches!(
+            rule,
+            RuleType::Rewrite { ref rule, .. }
+                if matches!(**rule, RuleType::NoDelay { nodelay: true, .. })
+        ));
+
+        assert!(
+            "DOMAIN,example.com,PROXY,nodelay=no"
+                .parse::<RuleType>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_ip_asn() {
+        for line in ["IP-ASN,13335,PROXY", "IP-ASN,AS13335,PROXY,no-resolve"] {
+            let rule: RuleType = line.parse().unwrap();
+            assert!(matches!(
+                rule,
+                RuleType::IpAsn { asn: 13335, ref target, .. } if target == "PROXY"
+            ));
+        }
+        assert!(matches!(
+            "IP-ASN,13335,PROXY,no-resolve".parse::<RuleType>().unwrap(),
+            RuleType::IpAsn {
+                no_resolve: true,
+                ..
+            }
+        ));
+        assert!("IP-ASN,cloudflare,PROXY".parse::<RuleType>().is_err());
+    }
+
+    #[test]
+    fn test_destination_override() {
+        let original = SocksAddr::Domain("example.com".to_owned(), 443);
+
+        let d: DestinationOverride = "internal.example.com".parse().unwrap();
+        assert_eq!(
+            d.apply(&original),
+            SocksAddr::Domain("internal.example.com".to_owned(), 443)
+        );
+
+        let d: DestinationOverride = "10.0.0.1:8443".parse().unwrap();
+        assert_eq!(
+            d.apply(&original),
+            SocksAddr::Ip("10.0.0.1:8443".parse().unwrap())
+        );
+
+        let d: DestinationOverride = "::1".parse().unwrap();
+        assert_eq!(
+            d.apply(&original),
+            SocksAddr::Ip("[::1]:443".parse().unwrap())
+        );
+        assert_eq!(d.to_string(), "[::1]");
+    }
+
+    #[test]
+    fn test_schedule() {
+        let at = |day: u32, h: u32, m: u32| {
+            // 2026-10-12 is a Monday
+            NaiveDate::from_ymd_opt(2026, 10, day)
+                .unwrap()
+                .and_hms_opt(h, m, 0)
+                .unwrap()
+        };
+
+        let s: Schedule = "Sun-Thu 21:00-07:00".parse().unwrap();
+        assert!(s.contains(at(18, 21, 0)));
+        assert!(s.contains(at(19, 6, 59)));
+        assert!(!s.contains(at(19, 7, 0)));
+        assert!(!s.contains(at(16, 22, 0)));
+        // the night from Thursday
+        assert!(s.contains(at(16, 1, 0)));
+        assert!(!s.contains(at(17, 1, 0)));
+
+        let s: Schedule = "Sat+Sun".parse().unwrap();
+        assert!(s.contains(at(17, 12, 0)));
+        assert!(!s.contains(at(16, 12, 0)));
+
+        let s: Schedule = "09:00-17:30 +08:00".parse().unwrap();
+        assert!(s.contains(at(14, 17, 29)));
+        assert!(!s.contains(at(14, 17, 30)));
+        assert_eq!(s.to_string(), "09:00-17:30 +08:00");
+
+        for invalid in ["", "+08:00", "Mon-Funday", "25:00-26:00", "09:00"] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+        }
+
+        let rule: RuleType = "DOMAIN-SUFFIX,game.example.com,REJECT,\
+                              schedule=Sun-Thu 21:00-07:00"
+            .parse()
+            .unwrap();
+        assert!(matches!(rule, RuleType::Scheduled { .. }));
+        assert_eq!(rule.target(), "REJECT");
+        assert!(
+            "SCHEDULE,Mon-Fri 09:00-17:00,DIRECT"
+                .parse::<RuleType>()
+                .is_ok()
+        );
+    }
+}
@@ -1,5 +1,6 @@
 pub mod def;
 pub mod internal;
+pub(crate) mod secrets;
 mod utils;
 pub use def::DNSListen;
 pub use internal::{InternalConfig as RuntimeConfig, *};
@@ -0,0 +1,202 @@
+//! The `secrets` section: values encrypted with AES-256-GCM under a key
+//! derived from a passphrase, which is the content of `key-file` or the
+//! `CLASH_SECRETS_PASSPHRASE` environment variable. They are put into the
+//! config where it says `${secrets.<name>}`, before the config is parsed.
+//!
+//! An encrypted value is the base64 of the salt, the nonce and the ciphertext,
+//! as printed by `clash-rs encrypt-secret`.
+
+use std::collections::HashMap;
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, KeyInit},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rand::Rng;
+use serde_yaml::Value;
+
+use crate::{Error, config::def::Secrets};
+
+pub const PASSPHRASE_ENV: &str = "CLASH_SECRETS_PASSPHRASE";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ROUNDS: u32 = 100_000;
+const REFERENCE_PREFIX: &str = "${secrets.";
+
+/// the content of `key_file` without the trailing newline, or the passphrase
+/// in the environment
+pub fn passphrase(key_file: Option<&str>) -> Result<Vec<u8>, Error> {
+    match key_file {
+        Some(path) => {
+            let mut key = std::fs::read(path).map_err(|e| {
+                Error::InvalidConfig(format!("secrets key file {}: {}", path, e))
+            })?;
+            while key.last().is_some_and(|c| c.is_ascii_whitespace()) {
+                key.pop();
+            }
+            Ok(key)
+        }
+        None => std::env::var(PASSPHRASE_ENV)
+            .map(String::into_bytes)
+            .map_err(|_| {
+                Error::InvalidConfig(format!(
+                    "secrets need a key-file or the {} environment variable",
+                    PASSPHRASE_ENV
+                ))
+            }),
+    }
+}
+
+fn cipher(passphrase: &[u8], salt: &[u8]) -> Aes256Gcm {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase, salt, PBKDF2_ROUNDS, &mut key);
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+pub fn encrypt(passphrase: &[u8], plaintext: &str) -> String {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill(&mut salt);
+    rand::rng().fill(&mut nonce);
+
+    let ciphertext = cipher(passphrase, &salt)
+        .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+        .expect("encrypting in memory should not fail");
+    STANDARD.encode([&salt[..], &nonce[..], &ciphertext[..]].concat())
+}
+
+pub fn decrypt(passphrase: &[u8], value: &str) -> Result<String, Error> {
+    let data = STANDARD
+        .decode(value.trim())
+        .map_err(|e| Error::InvalidConfig(format!("invalid base64: {}", e)))?;
+    if data.len() < SALT_LEN + NONCE_LEN {
+        return Err(Error::InvalidConfig("too short".to_owned()));
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let plaintext = cipher(passphrase, salt)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            Error::InvalidConfig("wrong key or corrupted value".to_owned())
+        })?;
+    String::from_utf8(plaintext)
+        .map_err(|_| Error::InvalidConfig("not valid UTF-8".to_owned()))
+}
+
+/// decrypts the `secrets` of `config` and puts them in where they are
+/// referred to
+pub(crate) fn substitute(config: &mut Value) -> Result<(), Error> {
+    let secrets = match config.get("secrets") {
+        Some(secrets) => serde_yaml::from_value::<Secrets>(secrets.clone())
+            .map_err(|e| Error::InvalidConfig(format!("invalid secrets: {}", e)))?,
+        None => Secrets::default(),
+    };
+
+    let mut values = HashMap::new();
+    if !secrets.values.is_empty() {
+        let passphrase = passphrase(secrets.key_file.as_deref())?;
+        for (name, value) in secrets.values.iter() {
+            let plaintext = decrypt(&passphrase, value).map_err(|e| {
+                Error::InvalidConfig(format!(
+                    "failed to decrypt secret {}: {}",
+                    name, e
+                ))
+            })?;
+            values.insert(name.as_str(), plaintext);
+        }
+    }
+
+    replace_references(config, &values)
+}
+
+fn replace_references(
+    value: &mut Value,
+    secrets: &HashMap<&str, String>,
+) -> Result<(), Error> {
+    match value {
+        Value::String(s) if s.contains(REFERENCE_PREFIX) => {
+            let mut replaced = String::with_capacity(s.len());
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find(REFERENCE_PREFIX) {
+                replaced.push_str(&rest[..start]);
+                let reference = &rest[start + REFERENCE_PREFIX.len()..];
+                let Some(end) = reference.find('}') else {
+                    return Err(Error::InvalidConfig(format!(
+                        "unterminated secret reference in {}",
+                        s
+                    )));
+                };
+                let name = &reference[..end];
+                let secret = secrets.get(name).ok_or_else(|| {
+                    Error::InvalidConfig(format!("unknown secret {}", name))
+                })?;
+                replaced.push_str(secret);
+                rest = &reference[end + 1..];
+            }
+            replaced.push_str(rest);
+            *s = replaced;
+        }
+        Value::Sequence(seq) => {
+            for v in seq.iter_mut() {
+                replace_references(v, secrets)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                replace_references(v, secrets)?;
+            }
+        }
+        Value::Tagged(tagged) => replace_references(&mut tagged.value, secrets)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::def::Config;
+
+    use super::{decrypt, encrypt};
+
+    #[test]
+    fn test_secrets() {
+        let encrypted = encrypt(b"passphrase", "p@ss");
+        assert_eq!(decrypt(b"passphrase", &encrypted).unwrap(), "p@ss");
+        assert!(decrypt(b"wrong", &encrypted).is_err());
+
+        let key_file = std::env::temp_dir().join("clash-rs-secrets.key");
+        std::fs::write(&key_file, "passphrase\n").unwrap();
+        let config: Config = format!(
+            r#"
+secrets:
+  key-file: {}
+  values:
+    ss: {}
+    api: {}
+secret: ${{secrets.api}}
+proxies:
+  - name: ss
+    type: ss
+    server: 10.0.0.1
+    port: 8388
+    cipher: aes-256-gcm
+    password: ${{secrets.ss}}
+"#,
+            key_file.display(),
+            encrypted,
+            encrypt(b"passphrase", "controller"),
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(config.secret.as_deref(), Some("controller"));
+        assert_eq!(config.proxy.unwrap()[0]["password"].as_str(), Some("p@ss"));
+
+        let unknown = "secret: ${secrets.missing}".parse::<Config>();
+        assert!(unknown.is_err());
+
+        std::fs::remove_file(key_file).unwrap();
+    }
+}
@@ -20,3 +20,33 @@ where
         StringOrNum::Num(n) => Ok(n),
     }
 }
+
+/// `deserialize_u64` of a field that may be left out, with `#[serde(default)]`
+pub fn deserialize_option_u64<'de, T, D>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr + serde::Deserialize<'de>,
+    <T as FromStr>::Err: Display,
+{
+    deserialize_u64(deserializer).map(Some)
+}
+
+/// a number or a range like `10-30`, kept as written
+pub fn deserialize_range<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNum {
+        String(String),
+        Num(u64),
+    }
+
+    Ok(match StringOrNum::deserialize(deserializer)? {
+        StringOrNum::String(s) => s,
+        StringOrNum::Num(n) => n.to_string(),
+    })
+}
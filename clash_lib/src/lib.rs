@@ -18,24 +18,29 @@ use crate::{
     },
 };
 use app::{
-    dispatcher::StatisticsManager,
+    dispatcher::{CaptureManager, CaptureSettings, StatisticsManager},
     dns::{SystemResolver, ThreadSafeDNSResolver},
+    lifecycle::{Component, Lifecycle},
     logging::LogEvent,
-    net::init_net_config,
+    net::{init_net_config, nat64},
     profile,
 };
-use common::{auth, http::new_http_client, mmdb};
+use common::{auth, clock, http::new_http_client, mmdb};
 use config::def::LogLevel;
 use once_cell::sync::OnceCell;
-use proxy::tun::get_tun_runner;
+use proxy::{tun::get_tun_runner, utils::set_tcp_nodelay};
 
-use std::{io, path::PathBuf, sync::Arc};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use thiserror::Error;
 use tokio::{
-    sync::{Mutex, broadcast, mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
     task::JoinHandle,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, info};
 
 mod app;
 mod common;
@@ -44,13 +49,33 @@ pub mod config;
 #[cfg(not(feature = "internal"))]
 mod config;
 mod proxy;
+mod runtime;
 mod session;
 
 use crate::common::geodata;
+pub use app::{
+    check::Report as ConfigReport,
+    convert::Conversion as ProviderConversion,
+    ctl::CtlCommand,
+    dispatcher::{DispatcherHook, TrackerInfo},
+    remote_config::{RemoteConfig, is_url as is_config_url},
+    remote_content_manager::providers::rule_provider::RuleSetBehavior,
+};
+pub use common::{
+    build_info::{BuildInfo, build_info},
+    memory::{AllocatorStats, set_allocator_stats},
+};
 pub use config::{
     DNSListen as ClashDNSListen, RuntimeConfig as ClashRuntimeConfig,
     def::{Config as ClashConfigDef, DNS as ClashDNSConfigDef},
 };
+#[cfg(feature = "test-fixtures")]
+pub use proxy::utils::test_utils::fixtures as test_fixtures;
+pub use proxy::utils::{
+    SocketFactory, SocketProtector, set_socket_factory, set_socket_protector,
+};
+pub use runtime::{ConnectionHandle, ProxyHandle, ResolverHandle, Runtime};
+pub use session::{Session, SocksAddr};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -81,6 +106,8 @@ pub struct Options {
     pub cwd: Option<String>,
     pub rt: Option<TokioRuntime>,
     pub log_file: Option<String>,
+    /// for applications embedding clash to see and steer the connections
+    pub hooks: Vec<Arc<dyn DispatcherHook>>,
 }
 
 pub enum TokioRuntime {
@@ -109,6 +136,7 @@ impl Config {
     }
 }
 
+#[cfg_attr(not(feature = "api"), allow(dead_code))]
 pub struct GlobalState {
     log_level: LogLevel,
 
@@ -125,6 +153,85 @@ pub struct RuntimeController {
 
 static RUNTIME_CONTROLLER: OnceCell<RuntimeController> = OnceCell::new();
 
+/// Check the config for `-t` without starting anything.
+/// Parse and validation errors are returned as is, problems with the
+/// providers and databases it refers to are listed in the report.
+pub fn test_config(config: Config, cwd: Option<String>) -> Result<ConfigReport> {
+    let config: InternalConfig = config.try_parse()?;
+    let cwd = PathBuf::from(cwd.unwrap_or_else(|| ".".to_string()));
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    Ok(rt.block_on(app::check::check(&config, &cwd)))
+}
+
+/// Convert a subscription, a URL or a file in any of the formats the proxy
+/// providers read, to the clash proxies yaml for `convert-provider`.
+pub fn convert_provider(source: &str) -> Result<ProviderConversion> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(app::convert::convert(source))
+        .map_err(Error::Operation)
+}
+
+/// Compile a `domain` or `ipcidr` rule set, in YAML or a rule per line, to
+/// the binary format the rule providers load without parsing, for
+/// `compile-ruleset`.
+pub fn compile_ruleset(behavior: RuleSetBehavior, input: &[u8]) -> Result<Vec<u8>> {
+    app::remote_content_manager::providers::rule_provider::compile(behavior, input)
+        .map_err(|e| Error::Operation(e.to_string()))
+}
+
+/// Download the config at `url` for `-f <URL>`, or read the copy cached in
+/// `cwd` by the last download if that fails.
+pub fn load_remote_config(
+    url: &str,
+    sha256: Option<&str>,
+    cwd: &Path,
+) -> Result<RemoteConfig> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(app::remote_config::load(url, sha256, cwd))
+        .map_err(Error::Operation)
+}
+
+/// Encrypt a value for the `secrets` section with the passphrase in
+/// `key_file`, or in the environment, for `encrypt-secret`.
+pub fn encrypt_secret(key_file: Option<&str>, value: &str) -> Result<String> {
+    let passphrase = config::secrets::passphrase(key_file)?;
+    Ok(config::secrets::encrypt(&passphrase, value))
+}
+
+/// Handle a `clash://` URL with the controller at `controller`, for
+/// `open-url`. Returns the response of the controller.
+pub fn open_url(
+    controller: &str,
+    url: &str,
+    secret: Option<&str>,
+) -> Result<String> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(app::deeplink::open(controller, url, secret))
+        .map_err(Error::Operation)
+}
+
+/// Run `command` with the controller at `controller`, for `ctl`. Returns
+/// the response of the controller.
+pub fn ctl(
+    controller: &str,
+    command: &CtlCommand,
+    secret: Option<&str>,
+) -> Result<String> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(app::ctl::run(controller, command, secret))
+        .map_err(Error::Operation)
+}
+
 pub fn start_scaffold(opts: Options) -> Result<()> {
     let rt = match opts.rt.as_ref().unwrap_or(&TokioRuntime::MultiThread) {
         TokioRuntime::MultiThread => tokio::runtime::Builder::new_multi_thread()
@@ -137,6 +244,7 @@ pub fn start_scaffold(opts: Options) -> Result<()> {
     let config: InternalConfig = opts.config.try_parse()?;
     let cwd = opts.cwd.unwrap_or_else(|| ".".to_string());
     let (log_tx, _) = broadcast::channel(100);
+    let hooks = opts.hooks;
 
     let log_collector = app::logging::EventCollector::new(vec![log_tx.clone()]);
 
@@ -148,9 +256,10 @@ pub fn start_scaffold(opts: Options) -> Result<()> {
     )
     .map_err(|x| eprintln!("failed to setup logging: {}", x))
     .unwrap_or_default();
+    info!("clash-rs {}", build_info());
 
     rt.block_on(async {
-        match start(config, cwd, log_tx).await {
+        match start(config, cwd, log_tx, hooks).await {
             Err(e) => {
                 eprintln!("start error: {}", e);
                 Err(e)
@@ -171,144 +280,49 @@ pub async fn start(
     config: InternalConfig,
     cwd: String,
     log_tx: broadcast::Sender<LogEvent>,
+    hooks: Vec<Arc<dyn DispatcherHook>>,
 ) -> Result<()> {
-    let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
-
-    let _ = RUNTIME_CONTROLLER.get_or_init(|| RuntimeController { shutdown_tx });
-
-    let mut tasks = Vec::<Runner>::new();
-    let mut runners = Vec::new();
-
-    let cwd = PathBuf::from(cwd);
-
-    // things we need to clone before consuming config
-    let controller_cfg = config.general.controller.clone();
-    let log_level = config.general.log_level;
-
-    let components = create_components(cwd.clone(), config).await?;
-
-    let inbound_manager = components.inbound_manager.clone();
-    inbound_manager.start().await;
-
-    let tun_runner_handle = components.tun_runner.map(tokio::spawn);
-    let dns_listener_handle = components.dns_listener.map(tokio::spawn);
-
-    let (reload_tx, mut reload_rx) = mpsc::channel(1);
-
-    let global_state = Arc::new(Mutex::new(GlobalState {
-        log_level,
-        tunnel_listener_handle: tun_runner_handle,
-        dns_listener_handle,
-        reload_tx,
-        api_listener_handle: None,
-        cwd: cwd.to_string_lossy().to_string(),
-    }));
-
-    let api_runner = app::api::get_api_runner(
-        controller_cfg,
-        log_tx.clone(),
-        components.inbound_manager,
-        components.dispatcher,
-        global_state.clone(),
-        components.dns_resolver,
-        components.outbound_manager,
-        components.statistics_manager,
-        components.cache_store,
-        components.router,
-        cwd.to_string_lossy().to_string(),
-    );
-    if let Some(r) = api_runner {
-        let api_listener_handle = tokio::spawn(r);
-        global_state.lock().await.api_listener_handle = Some(api_listener_handle);
-    }
-
-    runners.push(Box::pin(async move {
-        shutdown_rx.recv().await;
-        info!("receiving shutdown signal");
-        Ok(())
-    }));
-
-    tasks.push(Box::pin(async move {
-        futures::future::select_all(runners).await.0
-    }));
-
-    tasks.push(Box::pin(async move {
+    let runtime =
+        Runtime::start_with_logs(config, PathBuf::from(cwd), log_tx, hooks).await?;
+    let _ = RUNTIME_CONTROLLER.get_or_init(|| RuntimeController {
+        shutdown_tx: runtime.shutdown_tx.clone(),
+    });
+
+    let shutdown_tx = runtime.shutdown_tx.clone();
+    tokio::spawn(async move {
         tokio::signal::ctrl_c()
             .await
             .expect("failed to listen for ^C event");
-        Ok(())
-    }));
-
-    tasks.push(Box::pin(async move {
-        while let Some((config, done)) = reload_rx.recv().await {
-            info!("reloading config");
-            let config = match config.try_parse() {
-                Ok(c) => c,
-                Err(e) => {
-                    error!("failed to reload config: {}", e);
-                    continue;
+        let _ = shutdown_tx.send(()).await;
+    });
+
+    #[cfg(unix)]
+    {
+        app::handover::notify_ready();
+
+        let drain_tx = runtime.drain_tx.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            let mut upgrade = signal(SignalKind::user_defined2())
+                .expect("failed to listen for SIGUSR2");
+            while upgrade.recv().await.is_some() {
+                match app::handover::upgrade().await {
+                    Ok(pid) => {
+                        info!("handed over to process {}", pid);
+                        let _ = drain_tx.send(app::handover::drain_timeout()).await;
+                        break;
+                    }
+                    Err(e) => tracing::error!("failed to upgrade: {}", e),
                 }
-            };
-
-            let controller_cfg = config.general.controller.clone();
-
-            let new_components = create_components(cwd.clone(), config).await?;
-
-            done.send(()).unwrap();
-
-            debug!("stopping listeners");
-            inbound_manager.shutdown().await;
-            let mut g = global_state.lock().await;
-
-            if let Some(h) = g.tunnel_listener_handle.take() {
-                h.abort();
-            }
-            if let Some(h) = g.dns_listener_handle.take() {
-                h.abort();
             }
-            if let Some(h) = g.api_listener_handle.take() {
-                h.abort();
-            }
-
-            let inbound_manager = new_components.inbound_manager.clone();
-            debug!("reloading inbound listener");
-            inbound_manager.restart().await;
-
-            debug!("reloading tun runner");
-            let tun_runner_handle = new_components.tun_runner.map(tokio::spawn);
-
-            debug!("reloading dns listener");
-            let dns_listener_handle = new_components.dns_listener.map(tokio::spawn);
-
-            debug!("reloading api listener");
-            let api_listener_handle = app::api::get_api_runner(
-                controller_cfg,
-                log_tx.clone(),
-                new_components.inbound_manager,
-                new_components.dispatcher,
-                global_state.clone(),
-                new_components.dns_resolver,
-                new_components.outbound_manager,
-                new_components.statistics_manager,
-                new_components.cache_store,
-                new_components.router,
-                cwd.to_string_lossy().to_string(),
-            )
-            .map(tokio::spawn);
-
-            g.tunnel_listener_handle = tun_runner_handle;
-            g.dns_listener_handle = dns_listener_handle;
-            g.api_listener_handle = api_listener_handle;
-        }
-        Ok(())
-    }));
+        });
+    }
 
-    futures::future::select_all(tasks).await.0.map_err(|x| {
-        error!("runtime error: {}, shutting down", x);
-        x
-    })
+    runtime.wait().await
 }
 
+#[cfg_attr(not(feature = "api"), allow(dead_code))]
 struct RuntimeComponents {
     cache_store: profile::ThreadSafeCacheFile,
     dns_resolver: ThreadSafeDNSResolver,
@@ -317,19 +331,24 @@ struct RuntimeComponents {
     dispatcher: Arc<Dispatcher>,
     statistics_manager: Arc<StatisticsManager>,
     inbound_manager: Arc<InboundManager>,
+    lifecycle: Arc<Lifecycle>,
 
     tun_runner: Option<Runner>,
     dns_listener: Option<Runner>,
 }
 
+/// `statistics_manager` is the one of the config being reloaded, its
+/// connections are kept
 async fn create_components(
     cwd: PathBuf,
     config: InternalConfig,
+    statistics_manager: Option<Arc<StatisticsManager>>,
 ) -> Result<RuntimeComponents> {
     if config.tun.enable {
         debug!("tun enabled, initializing default outbound interface");
         init_net_config(config.tun.so_mark).await;
     }
+    nat64::init(config.general.nat64_prefix).await;
     let system_resolver = Arc::new(
         SystemResolver::new(config.dns.ipv6)
             .map_err(|x| Error::DNSError(x.to_string()))?,
@@ -353,51 +372,76 @@ async fn create_components(
             config.general.geosite_download_url,
             client.clone(),
         )
-        .await?,
+        .await?
+        .with_compact_tries(config.general.memory_profile.compact_tries()),
     );
 
     debug!("initializing cache store");
-    let cache_store = profile::ThreadSafeCacheFile::new(
-        cwd.join("cache.db").as_path().to_str().unwrap(),
+    let cache_store = profile::ThreadSafeCacheFile::open(
+        &cwd,
+        config.profile.cache_store,
         config.profile.store_selected,
+        config.profile.store_delay_history,
     );
 
+    let lifecycle = Arc::new(Lifecycle::new());
+
     let dns_listen = config.dns.listen.clone();
     debug!("initializing dns resolver");
-    let dns_resolver = dns::new_resolver(
-        config.dns,
-        Some(cache_store.clone()),
-        Some(country_mmdb.clone()),
-    )
-    .await;
+    let dns_config = config.dns;
+    let dns_resolver = lifecycle
+        .start(Component::Dns, async {
+            Ok(dns::new_resolver(
+                dns_config,
+                Some(cache_store.clone()),
+                Some(country_mmdb.clone()),
+            )
+            .await)
+        })
+        .await?;
 
     debug!("initializing outbound manager");
-    let outbound_manager = Arc::new(
-        OutboundManager::new(
-            config
-                .proxies
-                .into_values()
-                .filter_map(|x| match x {
-                    OutboundProxy::ProxyServer(s) => Some(s),
-                    _ => None,
-                })
-                .collect(),
-            config
-                .proxy_groups
-                .into_values()
-                .filter_map(|x| match x {
-                    OutboundProxy::ProxyGroup(g) => Some(g),
-                    _ => None,
-                })
-                .collect(),
-            config.proxy_providers,
-            config.proxy_names,
-            dns_resolver.clone(),
-            cache_store.clone(),
-            cwd.to_string_lossy().to_string(),
-        )
-        .await?,
-    );
+    let proxies = config
+        .proxies
+        .into_values()
+        .filter_map(|x| match x {
+            OutboundProxy::ProxyServer(s) => Some(s),
+            _ => None,
+        })
+        .collect();
+    let proxy_groups = config
+        .proxy_groups
+        .into_values()
+        .filter_map(|x| match x {
+            OutboundProxy::ProxyGroup(g) => Some(g),
+            _ => None,
+        })
+        .collect();
+    let (proxy_providers, proxy_names) =
+        (config.proxy_providers, config.proxy_names);
+    let health_check = config.general.health_check;
+    let tls_fragment = config
+        .experimental
+        .as_ref()
+        .and_then(|x| x.tls_fragment.clone());
+    let outbound_manager = lifecycle
+        .start(Component::Outbounds, async {
+            OutboundManager::new(
+                proxies,
+                proxy_groups,
+                proxy_providers,
+                proxy_names,
+                health_check,
+                tls_fragment,
+                dns_resolver.clone(),
+                cache_store.clone(),
+                Some(country_mmdb.clone()),
+                cwd.to_string_lossy().to_string(),
+            )
+            .await
+            .map(Arc::new)
+        })
+        .await?;
 
     debug!("initializing country asn mmdb");
     let p = cwd.join(&config.general.asn_mmdb);
@@ -410,54 +454,126 @@ async fn create_components(
         None
     };
 
+    let experimental = config.experimental.unwrap_or_default();
+
     debug!("initializing router");
-    let router = Arc::new(
-        Router::new(
-            config.rules,
-            config.rule_providers,
-            dns_resolver.clone(),
-            country_mmdb,
-            asn_mmdb,
-            geodata,
-            cwd.to_string_lossy().to_string(),
-        )
-        .await,
+    let (rules, sub_rules, rule_providers) =
+        (config.rules, config.sub_rules, config.rule_providers);
+    let router = lifecycle
+        .start(Component::Rules, async {
+            Ok(Arc::new(
+                Router::new(
+                    rules,
+                    sub_rules,
+                    rule_providers,
+                    dns_resolver.clone(),
+                    country_mmdb,
+                    asn_mmdb,
+                    geodata,
+                    cache_store.clone(),
+                    cwd.to_string_lossy().to_string(),
+                    experimental.optimize_rules,
+                )
+                .await,
+            ))
+        })
+        .await?;
+    dns_resolver.set_rule_providers(router.rule_providers().clone());
+
+    let memory_profile = config.general.memory_profile;
+    clock::set_correct(experimental.correct_clock);
+    app::handover::set_drain_timeout(
+        experimental
+            .handover_drain
+            .map(std::time::Duration::from_secs),
     );
+    set_tcp_nodelay(experimental.tcp_nodelay.unwrap_or(true));
+    let history_size = experimental
+        .connection_history_size
+        .unwrap_or(memory_profile.connection_history_size());
+    let statistics_manager = match statistics_manager {
+        Some(manager) => {
+            manager.set_limits(history_size, experimental.max_connections);
+            manager
+        }
+        None => StatisticsManager::new(history_size, experimental.max_connections),
+    };
+    statistics_manager
+        .set_cache_store(cache_store.clone())
+        .await;
 
-    let statistics_manager = StatisticsManager::new();
-
-    debug!("initializing dispatcher");
-    let dispatcher = Arc::new(Dispatcher::new(
-        outbound_manager.clone(),
+    let capture = experimental.capture.unwrap_or_default();
+    let capture_manager = Arc::new(CaptureManager::new(
+        cwd.join(capture.dir.as_deref().unwrap_or("captures")),
         router.clone(),
-        dns_resolver.clone(),
-        config.general.mode,
-        statistics_manager.clone(),
-        config.experimental.and_then(|e| e.tcp_buffer_size),
     ));
+    capture_manager.set(CaptureSettings {
+        all: capture.all,
+        rules: capture.rules,
+    })?;
+
+    debug!("initializing dispatcher");
+    let dispatcher = Arc::new(
+        Dispatcher::new(
+            outbound_manager.clone(),
+            router.clone(),
+            dns_resolver.clone(),
+            config.general.mode,
+            statistics_manager.clone(),
+            Some(
+                experimental
+                    .tcp_buffer_size
+                    .unwrap_or(memory_profile.tcp_buffer_size()),
+            ),
+            capture_manager,
+            config.sniffer,
+        )
+        .with_destination_limit(experimental.max_connections_per_host),
+    );
 
     debug!("initializing authenticator");
     let authenticator = Arc::new(auth::PlainAuthenticator::new(config.users));
 
+    let mut listeners = config.listeners;
+    for listener in listeners.values_mut() {
+        if let Some(tls) = listener.tls_mut() {
+            tls.resolve_paths(&cwd);
+        }
+    }
+
     debug!("initializing inbound manager");
-    let inbound_manager = Arc::new(
-        InboundManager::new(
-            config.general.bind_address,
-            config.general.authentication,
-            dispatcher.clone(),
-            authenticator,
-            config.listeners,
-        )
-        .await?,
-    );
+    let (bind_address, authentication) =
+        (config.general.bind_address, config.general.authentication);
+    let inbound_manager = lifecycle
+        .start(Component::Inbounds, async {
+            InboundManager::new(
+                bind_address,
+                authentication,
+                dispatcher.clone(),
+                authenticator,
+                listeners,
+            )
+            .await
+            .map(Arc::new)
+        })
+        .await?;
 
     debug!("initializing tun runner");
-    let tun_runner =
-        get_tun_runner(config.tun, dispatcher.clone(), dns_resolver.clone())?;
+    let tun_config = config.tun;
+    let tun_runner = lifecycle
+        .start_optional(Component::Tun, async {
+            get_tun_runner(tun_config, dispatcher.clone(), dns_resolver.clone())
+        })
+        .await
+        .map(|r| lifecycle.watch(Component::Tun, r));
 
     debug!("initializing dns listener");
-    let dns_listener =
-        dns::get_dns_listener(dns_listen, dns_resolver.clone(), &cwd).await;
+    let dns_listener = lifecycle
+        .start_optional(Component::DnsListener, async {
+            Ok(dns::get_dns_listener(dns_listen, dns_resolver.clone(), &cwd).await)
+        })
+        .await
+        .map(|r| lifecycle.watch(Component::DnsListener, r));
 
     info!("all components initialized");
     Ok(RuntimeComponents {
@@ -468,6 +584,7 @@ async fn create_components(
         dispatcher,
         statistics_manager,
         inbound_manager,
+        lifecycle,
         tun_runner,
         dns_listener,
     })
@@ -501,6 +618,7 @@ mod tests {
                 cwd: None,
                 rt: None,
                 log_file: None,
+                hooks: vec![],
             })
             .unwrap()
         });
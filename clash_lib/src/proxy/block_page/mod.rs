@@ -0,0 +1,224 @@
+use std::{io, time::Duration};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tracing::trace;
+
+use crate::{
+    app::{
+        dispatcher::{
+            BoxedChainedDatagram, BoxedChainedStream, ChainedStream,
+            ChainedStreamWrapper,
+        },
+        dns::ThreadSafeDNSResolver,
+    },
+    common::errors::new_io_error,
+    session::Session,
+};
+
+use super::{ConnectorType, DialWithConnector, OutboundHandler, OutboundType};
+
+/// how much of the request is read before the response is sent anyway
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PAGE: &str = "<!DOCTYPE html><html><head><meta \
+                            charset=\"utf-8\"><title>Blocked</title></\
+                            head><body><h1>Blocked</h1><p>{host} is blocked by \
+                            policy.</p></body></html>";
+
+pub struct HandlerOptions {
+    pub name: String,
+    /// `{host}` is replaced with the requested host
+    pub html: Option<String>,
+    /// redirects to this URL instead of serving the page
+    pub redirect: Option<String>,
+    pub status: http::StatusCode,
+}
+
+/// Answers plain HTTP requests with a local page, or a redirect, instead of
+/// connecting anywhere. TLS can't be answered without a certificate for the
+/// host, so those connections are closed like with REJECT.
+pub struct Handler {
+    opts: HandlerOptions,
+}
+
+impl std::fmt::Debug for Handler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockPage")
+            .field("name", &self.opts.name)
+            .finish()
+    }
+}
+
+impl Handler {
+    pub fn new(opts: HandlerOptions) -> Self {
+        Self { opts }
+    }
+
+    fn response(&self, host: &str) -> Vec<u8> {
+        match &self.opts.redirect {
+            Some(location) => format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: \
+                 0\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+                location
+            )
+            .into_bytes(),
+            None => {
+                let body = self
+                    .opts
+                    .html
+                    .as_deref()
+                    .unwrap_or(DEFAULT_PAGE)
+                    .replace("{host}", &html_escape(host));
+                format!(
+                    "HTTP/1.1 {} {}\r\nContent-Type: text/html; \
+                     charset=utf-8\r\nContent-Length: {}\r\nCache-Control: \
+                     no-store\r\nConnection: close\r\n\r\n{}",
+                    self.opts.status.as_u16(),
+                    self.opts.status.canonical_reason().unwrap_or_default(),
+                    body.len(),
+                    body
+                )
+                .into_bytes()
+            }
+        }
+    }
+}
+
+async fn respond(mut s: DuplexStream, response: Vec<u8>) -> io::Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD
+    {
+        let n = tokio::time::timeout(READ_TIMEOUT, s.read(&mut buf))
+            .await
+            .map_err(|_| new_io_error("timed out reading the request"))??;
+        if n == 0 {
+            return Ok(());
+        }
+        // a TLS ClientHello
+        if head.is_empty() && buf[0] == 0x16 {
+            trace!("not answering TLS");
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    s.write_all(&response).await?;
+    s.shutdown().await
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl DialWithConnector for Handler {}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        OutboundType::BlockPage
+    }
+
+    async fn support_udp(&self) -> bool {
+        false
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let (client, server) = tokio::io::duplex(MAX_REQUEST_HEAD);
+        let response = self.response(&sess.destination.host());
+        tokio::spawn(async move {
+            if let Err(e) = respond(server, response).await {
+                trace!("block page not served: {}", e);
+            }
+        });
+
+        let s = ChainedStreamWrapper::new(client);
+        s.append_to_chain(self.name()).await;
+        Ok(Box::new(s))
+    }
+
+    async fn connect_datagram(
+        &self,
+        _sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        Err(io::Error::new(io::ErrorKind::Other, "BLOCK-PAGE"))
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        ConnectorType::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        app::dns::MockClashResolver,
+        proxy::OutboundHandler,
+        session::{Session, SocksAddr},
+    };
+
+    use super::{Handler, HandlerOptions};
+
+    fn handler(redirect: Option<&str>) -> Handler {
+        Handler::new(HandlerOptions {
+            name: "BLOCKED".to_owned(),
+            html: None,
+            redirect: redirect.map(ToOwned::to_owned),
+            status: http::StatusCode::FORBIDDEN,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_block_page() {
+        let sess = Session {
+            destination: SocksAddr::Domain("<ads>.example.com".to_owned(), 80),
+            ..Default::default()
+        };
+        let resolver = Arc::new(MockClashResolver::new());
+
+        let mut s = handler(None)
+            .connect_stream(&sess, resolver.clone())
+            .await
+            .unwrap();
+        s.write_all(b"GET / HTTP/1.1\r\nHost: ads.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        s.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(resp.ends_with("</html>"));
+        assert!(resp.contains("&lt;ads&gt;.example.com is blocked"));
+
+        let mut s = handler(Some("http://10.0.0.1/blocked"))
+            .connect_stream(&sess, resolver.clone())
+            .await
+            .unwrap();
+        s.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut resp = String::new();
+        s.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert!(resp.contains("Location: http://10.0.0.1/blocked\r\n"));
+
+        let mut s = handler(None).connect_stream(&sess, resolver).await.unwrap();
+        s.write_all(&[0x16, 0x03, 0x01]).await.unwrap();
+        let mut resp = vec![];
+        s.read_to_end(&mut resp).await.unwrap();
+        assert!(resp.is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    Error,
+    config::internal::proxy::OutboundBlockPage,
+    proxy::block_page::{Handler, HandlerOptions},
+};
+
+impl TryFrom<OutboundBlockPage> for Handler {
+    type Error = crate::Error;
+
+    fn try_from(value: OutboundBlockPage) -> Result<Self, Self::Error> {
+        (&value).try_into()
+    }
+}
+
+impl TryFrom<&OutboundBlockPage> for Handler {
+    type Error = crate::Error;
+
+    fn try_from(s: &OutboundBlockPage) -> Result<Self, Self::Error> {
+        let status =
+            http::StatusCode::from_u16(s.status.unwrap_or(403)).map_err(|e| {
+                Error::InvalidConfig(format!("invalid status of {}: {}", s.name, e))
+            })?;
+        if let Some(redirect) = &s.redirect {
+            redirect.parse::<http::Uri>().map_err(|e| {
+                Error::InvalidConfig(format!(
+                    "invalid redirect of {}: {}",
+                    s.name, e
+                ))
+            })?;
+        }
+        let h = Handler::new(HandlerOptions {
+            name: s.name.to_owned(),
+            html: s.html.clone(),
+            redirect: s.redirect.clone(),
+            status,
+        });
+        Ok(h)
+    }
+}
@@ -10,6 +10,7 @@ use crate::{
     config::internal::proxy::{Hysteria2Obfs, OutboundHysteria2},
     proxy::{
         AnyOutboundHandler,
+        converters::utils::reject_timeouts,
         hysteria2::{self, Handler, HystOption, SalamanderObfs},
     },
     session::SocksAddr,
@@ -84,6 +85,12 @@ impl TryFrom<OutboundHysteria2> for AnyOutboundHandler {
     type Error = crate::Error;
 
     fn try_from(value: OutboundHysteria2) -> Result<Self, Self::Error> {
+        reject_timeouts(
+            &value.name,
+            value.connect_timeout,
+            value.tls_handshake_timeout,
+            value.handshake_timeout,
+        )?;
         let addr = SocksAddr::try_from((value.server, value.port))?;
 
         let obfs = match (value.obfs, value.obfs_password.as_ref()) {
@@ -1,3 +1,4 @@
+pub mod block_page;
 pub mod hysteria2;
 #[cfg(feature = "shadowsocks")]
 pub mod shadowsocks;
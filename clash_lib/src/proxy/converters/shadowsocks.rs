@@ -8,7 +8,7 @@ use crate::{
         shadowsocks::{Handler, HandlerOptions},
         transport::{
             Shadowtls, SimpleOBFSMode, SimpleOBFSOption, SimpleObfsHttp,
-            SimpleObfsTLS, V2RayOBFSOption, V2rayWsClient,
+            SimpleObfsTLS, Sip003Plugin, V2RayOBFSOption, V2rayWsClient,
         },
     },
 };
@@ -25,75 +25,92 @@ impl TryFrom<&OutboundShadowsocks> for Handler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundShadowsocks) -> Result<Self, Self::Error> {
+        if s.udp_mux && !s.cipher.starts_with("2022-") {
+            return Err(Error::InvalidConfig(format!(
+                "udp-mux of proxy {} needs a 2022 cipher, got {}",
+                s.common_opts.name, s.cipher
+            )));
+        }
+        let plugin: Option<Box<dyn Sip003Plugin>> = match &s.plugin {
+            Some(plugin) => match plugin.as_str() {
+                "obfs" => {
+                    tracing::warn!(
+                        "simple-obfs is deprecated, please use v2ray-plugin instead"
+                    );
+                    let opt: SimpleOBFSOption = s
+                        .plugin_opts
+                        .clone()
+                        .ok_or(Error::InvalidConfig(
+                            "plugin_opts is required for plugin obfs".to_owned(),
+                        ))?
+                        .try_into()?;
+                    let plugin = match opt.mode {
+                        SimpleOBFSMode::Http => Box::new(SimpleObfsHttp::new(
+                            opt.host,
+                            s.common_opts.port,
+                        )) as _,
+                        SimpleOBFSMode::Tls => {
+                            Box::new(SimpleObfsTLS::new(opt.host)) as _
+                        }
+                    };
+                    Some(plugin)
+                }
+                "v2ray-plugin" => {
+                    let opt: V2RayOBFSOption = s
+                        .plugin_opts
+                        .clone()
+                        .ok_or(Error::InvalidConfig(
+                            "plugin_opts is required for plugin obfs".to_owned(),
+                        ))?
+                        .try_into()?;
+                    // TODO: support more transport options, replace it with
+                    // `V2rayClient`
+                    let plugin = V2rayWsClient::try_from(opt)?;
+                    Some(Box::new(plugin) as _)
+                }
+                "shadow-tls" => {
+                    let plugin: Shadowtls = s
+                        .plugin_opts
+                        .clone()
+                        .ok_or(Error::InvalidConfig(
+                            "plugin_opts is required for plugin obfs".to_owned(),
+                        ))?
+                        .try_into()?;
+                    Some(Box::new(plugin) as _)
+                }
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "unsupported plugin: {}",
+                        plugin
+                    )));
+                }
+            },
+            None => None,
+        };
+        let tls_in_tls = match &s.common_opts.tls_in_tls_fragment {
+            Some(_) if !plugin.as_ref().is_some_and(|x| x.is_tls()) => {
+                return Err(Error::InvalidConfig(format!(
+                    "tls-in-tls-fragment of proxy {} needs a plugin with TLS",
+                    s.common_opts.name
+                )));
+            }
+            x => x.as_ref().map(TryInto::try_into).transpose()?,
+        };
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                timeouts: (&s.common_opts).into(),
+                tls_in_tls,
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
             port: s.common_opts.port,
             password: s.password.to_owned(),
             cipher: s.cipher.to_owned(),
-            plugin: match &s.plugin {
-                Some(plugin) => match plugin.as_str() {
-                    "obfs" => {
-                        tracing::warn!(
-                            "simple-obfs is deprecated, please use v2ray-plugin \
-                             instead"
-                        );
-                        let opt: SimpleOBFSOption = s
-                            .plugin_opts
-                            .clone()
-                            .ok_or(Error::InvalidConfig(
-                                "plugin_opts is required for plugin obfs".to_owned(),
-                            ))?
-                            .try_into()?;
-                        let plugin = match opt.mode {
-                            SimpleOBFSMode::Http => Box::new(SimpleObfsHttp::new(
-                                opt.host,
-                                s.common_opts.port,
-                            ))
-                                as _,
-                            SimpleOBFSMode::Tls => {
-                                Box::new(SimpleObfsTLS::new(opt.host)) as _
-                            }
-                        };
-                        Some(plugin)
-                    }
-                    "v2ray-plugin" => {
-                        let opt: V2RayOBFSOption = s
-                            .plugin_opts
-                            .clone()
-                            .ok_or(Error::InvalidConfig(
-                                "plugin_opts is required for plugin obfs".to_owned(),
-                            ))?
-                            .try_into()?;
-                        // TODO: support more transport options, replace it with
-                        // `V2rayClient`
-                        let plugin = V2rayWsClient::try_from(opt)?;
-                        Some(Box::new(plugin) as _)
-                    }
-                    "shadow-tls" => {
-                        let plugin: Shadowtls = s
-                            .plugin_opts
-                            .clone()
-                            .ok_or(Error::InvalidConfig(
-                                "plugin_opts is required for plugin obfs".to_owned(),
-                            ))?
-                            .try_into()?;
-                        Some(Box::new(plugin) as _)
-                    }
-                    _ => {
-                        return Err(Error::InvalidConfig(format!(
-                            "unsupported plugin: {}",
-                            plugin
-                        )));
-                    }
-                },
-                None => None,
-            },
+            plugin,
             udp: s.udp,
+            udp_mux: s.udp_mux,
         });
         Ok(h)
     }
@@ -1,4 +1,5 @@
 use crate::{
+    Error,
     config::internal::proxy::OutboundSocks5,
     proxy::{
         HandlerCommonOptions,
@@ -20,19 +21,39 @@ impl TryFrom<&OutboundSocks5> for Handler {
 
     fn try_from(s: &OutboundSocks5) -> Result<Self, Self::Error> {
         let tls_client = if s.tls {
-            Some(Box::new(TlsClient::new(
+            let mut client = TlsClient::new(
                 s.skip_cert_verify,
                 s.sni.clone().unwrap_or(s.common_opts.server.to_owned()),
                 None,
                 None,
-            )) as _)
+            );
+            client.fragment = s
+                .common_opts
+                .tls_fragment
+                .as_ref()
+                .map(TryInto::try_into)
+                .transpose()?;
+            Some(Box::new(client) as _)
         } else {
+            if s.common_opts.tls_in_tls_fragment.is_some() {
+                return Err(Error::InvalidConfig(format!(
+                    "tls-in-tls-fragment of proxy {} needs tls",
+                    s.common_opts.name
+                )));
+            }
             None
         };
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                timeouts: (&s.common_opts).into(),
+                tls_in_tls: s
+                    .common_opts
+                    .tls_in_tls_fragment
+                    .as_ref()
+                    .map(TryInto::try_into)
+                    .transpose()?,
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -121,6 +121,7 @@ impl TryFrom<&OutBoundSsh> for Handler {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                timeouts: (&s.common_opts).into(),
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
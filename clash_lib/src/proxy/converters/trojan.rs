@@ -36,6 +36,13 @@ impl TryFrom<&OutboundTrojan> for Handler {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                timeouts: (&s.common_opts).into(),
+                tls_in_tls: s
+                    .common_opts
+                    .tls_in_tls_fragment
+                    .as_ref()
+                    .map(TryInto::try_into)
+                    .transpose()?,
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -43,7 +50,7 @@ impl TryFrom<&OutboundTrojan> for Handler {
             password: s.password.clone(),
             udp: s.udp.unwrap_or_default(),
             tls: {
-                let client = TlsClient::new(
+                let mut client = TlsClient::new(
                     skip_cert_verify,
                     s.sni
                         .as_ref()
@@ -58,6 +65,12 @@ impl TryFrom<&OutboundTrojan> for Handler {
                     )),
                     None,
                 );
+                client.fragment = s
+                    .common_opts
+                    .tls_fragment
+                    .as_ref()
+                    .map(TryInto::try_into)
+                    .transpose()?;
                 Some(Box::new(client))
             },
             transport: s
@@ -67,15 +80,13 @@ impl TryFrom<&OutboundTrojan> for Handler {
                     "ws" => s
                         .ws_opts
                         .as_ref()
-                        .map(|x| {
-                            let client: WsClient = (x, &s.common_opts)
-                                .try_into()
-                                .expect("invalid ws_opts");
-                            Box::new(client) as _
-                        })
                         .ok_or(Error::InvalidConfig(
                             "ws_opts is required for ws".to_owned(),
-                        )),
+                        ))
+                        .and_then(|x| {
+                            let client: WsClient = (x, &s.common_opts).try_into()?;
+                            Ok(Box::new(client) as _)
+                        }),
                     "grpc" => s
                         .grpc_opts
                         .as_ref()
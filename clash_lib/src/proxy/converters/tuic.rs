@@ -6,6 +6,7 @@ use crate::{
     config::internal::proxy::OutboundTuic,
     proxy::{
         HandlerCommonOptions,
+        converters::utils::reject_timeouts,
         tuic::{Handler, HandlerOptions, types::CongestionControl},
     },
 };
@@ -22,6 +23,12 @@ impl TryFrom<&OutboundTuic> for Handler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundTuic) -> Result<Self, Self::Error> {
+        reject_timeouts(
+            &s.common_opts.name,
+            s.common_opts.connect_timeout,
+            s.common_opts.tls_handshake_timeout,
+            s.common_opts.handshake_timeout,
+        )?;
         Ok(Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             server: s.common_opts.server.to_owned(),
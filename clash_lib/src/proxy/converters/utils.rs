@@ -1,16 +1,76 @@
+use std::time::Duration;
+
 use http::uri::InvalidUri;
 
 use crate::{
-    config::proxy::{CommonConfigOptions, GrpcOpt, H2Opt, WsOpt},
-    proxy::transport::{self, GrpcClient, H2Client, WsClient},
+    Error,
+    config::proxy::{
+        CommonConfigOptions, GrpcMode, GrpcOpt, H2Opt, TlsFragmentOpt, WsOpt,
+    },
+    proxy::{
+        HandshakeTimeouts,
+        transport::{
+            self, GrpcClient, GrpcMode as TransportGrpcMode, H2Client, TlsFragment,
+            WsClient,
+        },
+    },
 };
 
+impl From<&CommonConfigOptions> for HandshakeTimeouts {
+    fn from(common: &CommonConfigOptions) -> Self {
+        Self {
+            connect: common.connect_timeout.map(Duration::from_millis),
+            transport: common.tls_handshake_timeout.map(Duration::from_millis),
+            protocol: common.handshake_timeout.map(Duration::from_millis),
+        }
+    }
+}
+
+/// the proxies over QUIC or WireGuard have none of the phases of
+/// `HandshakeTimeouts`, so the keys are refused rather than ignored
+pub fn reject_timeouts(
+    name: &str,
+    connect: Option<u64>,
+    tls_handshake: Option<u64>,
+    handshake: Option<u64>,
+) -> Result<(), Error> {
+    if connect.is_some() || tls_handshake.is_some() || handshake.is_some() {
+        return Err(Error::InvalidConfig(format!(
+            "proxy {}: connect-timeout, tls-handshake-timeout and \
+             handshake-timeout are not supported by its protocol",
+            name
+        )));
+    }
+    Ok(())
+}
+
+impl TryFrom<&TlsFragmentOpt> for TlsFragment {
+    type Error = Error;
+
+    fn try_from(opt: &TlsFragmentOpt) -> Result<Self, Self::Error> {
+        let interval = Some(opt.interval.as_str()).filter(|x| !x.is_empty());
+        let fragment = TlsFragment::parse(&opt.size, interval);
+        match opt.padding.as_str() {
+            "" => fragment,
+            padding => fragment.and_then(|x| x.with_padding(padding)),
+        }
+        .map_err(Error::InvalidConfig)
+    }
+}
+
 impl TryFrom<(&WsOpt, &CommonConfigOptions)> for WsClient {
-    type Error = std::io::Error;
+    type Error = Error;
 
     fn try_from(pair: (&WsOpt, &CommonConfigOptions)) -> Result<Self, Self::Error> {
         let (x, common) = pair;
+        if x.browser_forwarding == Some(true) {
+            tracing::warn!(
+                "ws browser-forwarding of {} is not supported, dialing directly",
+                common.name
+            );
+        }
         let path = x.path.as_ref().map(|x| x.to_owned()).unwrap_or_default();
+        transport::check_ws_path(&path).map_err(Error::InvalidConfig)?;
         let headers = x.headers.as_ref().map(|x| x.to_owned()).unwrap_or_default();
         let max_early_data = x.max_early_data.unwrap_or_default() as usize;
         let early_data_header_name = x
@@ -39,7 +99,7 @@ impl TryFrom<(Option<String>, &GrpcOpt, &CommonConfigOptions)> for GrpcClient {
         opt: (Option<String>, &GrpcOpt, &CommonConfigOptions),
     ) -> Result<Self, Self::Error> {
         let (sni, x, common) = opt;
-        let client = transport::GrpcClient::new(
+        let mut client = transport::GrpcClient::new(
             sni.as_ref().unwrap_or(&common.server).to_owned(),
             x.grpc_service_name
                 .as_ref()
@@ -47,6 +107,19 @@ impl TryFrom<(Option<String>, &GrpcOpt, &CommonConfigOptions)> for GrpcClient {
                 .unwrap_or_default()
                 .try_into()?,
         );
+        client.mode = match x.grpc_mode.unwrap_or_default() {
+            GrpcMode::Gun => TransportGrpcMode::Gun,
+            GrpcMode::Multi => TransportGrpcMode::Multi,
+        };
+        if let Some(user_agent) = &x.grpc_user_agent {
+            client.user_agent = user_agent.to_owned();
+        }
+        if let Some(size) = x.initial_window_size {
+            client.initial_window_size = size;
+        }
+        if let Some(size) = x.initial_connection_window_size {
+            client.initial_connection_window_size = size;
+        }
         Ok(client)
     }
 }
@@ -29,11 +29,25 @@ impl TryFrom<&OutboundVmess> for Handler {
                 s.common_opts.server
             );
         }
+        if s.common_opts.tls_in_tls_fragment.is_some() && !s.tls.unwrap_or_default()
+        {
+            return Err(Error::InvalidConfig(format!(
+                "tls-in-tls-fragment of proxy {} needs tls",
+                s.common_opts.name
+            )));
+        }
 
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
                 connector: s.common_opts.connect_via.clone(),
+                timeouts: (&s.common_opts).into(),
+                tls_in_tls: s
+                    .common_opts
+                    .tls_in_tls_fragment
+                    .as_ref()
+                    .map(TryInto::try_into)
+                    .transpose()?,
                 ..Default::default()
             },
             server: s.common_opts.server.to_owned(),
@@ -49,15 +63,13 @@ impl TryFrom<&OutboundVmess> for Handler {
                     "ws" => s
                         .ws_opts
                         .as_ref()
-                        .map(|x| {
-                            let client: WsClient = (x, &s.common_opts)
-                                .try_into()
-                                .expect("invalid ws options");
-                            Box::new(client) as _
-                        })
                         .ok_or(Error::InvalidConfig(
                             "ws_opts is required for ws".to_owned(),
-                        )),
+                        ))
+                        .and_then(|x| {
+                            let client: WsClient = (x, &s.common_opts).try_into()?;
+                            Ok(Box::new(client) as _)
+                        }),
                     "h2" => s
                         .h2_opts
                         .as_ref()
@@ -91,7 +103,7 @@ impl TryFrom<&OutboundVmess> for Handler {
                 .transpose()?,
             tls: match s.tls.unwrap_or_default() {
                 true => {
-                    let client = TlsClient::new(
+                    let mut client = TlsClient::new(
                         s.skip_cert_verify.unwrap_or_default(),
                         s.server_name.as_ref().map(|x| x.to_owned()).unwrap_or(
                             s.ws_opts
@@ -119,6 +131,12 @@ impl TryFrom<&OutboundVmess> for Handler {
                             .transpose()?,
                         None,
                     );
+                    client.fragment = s
+                        .common_opts
+                        .tls_fragment
+                        .as_ref()
+                        .map(TryInto::try_into)
+                        .transpose()?;
                     Some(Box::new(client))
                 }
                 false => None,
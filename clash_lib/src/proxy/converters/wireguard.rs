@@ -5,6 +5,7 @@ use crate::{
     config::internal::proxy::OutboundWireguard,
     proxy::{
         HandlerCommonOptions,
+        converters::utils::reject_timeouts,
         wg::{Handler, HandlerOptions},
     },
 };
@@ -21,6 +22,12 @@ impl TryFrom<&OutboundWireguard> for Handler {
     type Error = crate::Error;
 
     fn try_from(s: &OutboundWireguard) -> Result<Self, Self::Error> {
+        reject_timeouts(
+            &s.common_opts.name,
+            s.common_opts.connect_timeout,
+            s.common_opts.tls_handshake_timeout,
+            s.common_opts.handshake_timeout,
+        )?;
         let h = Handler::new(HandlerOptions {
             name: s.common_opts.name.to_owned(),
             common_opts: HandlerCommonOptions {
@@ -71,6 +78,7 @@ impl TryFrom<&OutboundWireguard> for Handler {
             remote_dns_resolve: s.remote_dns_resolve.unwrap_or_default(),
             dns: s.dns.as_ref().map(|x| x.to_owned()),
             mtu: s.mtu,
+            mtu_discovery: s.mtu_discovery.unwrap_or_default(),
             udp: s.udp.unwrap_or_default(),
             allowed_ips: s.allowed_ips.as_ref().map(|x| x.to_owned()),
             reserved_bits: s.reserved_bits.as_ref().map(|x| x.to_owned()),
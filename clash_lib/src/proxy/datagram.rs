@@ -1,15 +1,17 @@
 use crate::{
-    app::dns::ThreadSafeDNSResolver, common::errors::new_io_error,
+    app::{dns::ThreadSafeDNSResolver, net::nat64},
     session::SocksAddr,
 };
 use futures::{Sink, Stream, ready};
 use std::{
+    collections::VecDeque,
     fmt::{Debug, Display, Formatter},
     io,
+    net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::{io::ReadBuf, net::UdpSocket};
+use tokio::net::UdpSocket;
 
 #[derive(Clone)]
 pub struct UdpPacket {
@@ -62,20 +64,136 @@ impl UdpPacket {
 #[must_use = "sinks do nothing unless polled"]
 // TODO: maybe we should use abstract datagram IO interface instead of the
 // Stream + Sink trait
+/// A UDP socket of the NAT relay. On Linux the packets fed to it are sent
+/// together with sendmmsg when flushed, and those waiting to be read are
+/// received together with recvmmsg, up to `BATCH_SIZE` per syscall
 pub struct OutboundDatagramImpl {
     inner: UdpSocket,
     resolver: ThreadSafeDNSResolver,
-    flushed: bool,
+    /// a packet to a domain, queued once it's resolved
     pkt: Option<UdpPacket>,
+    /// the packets to send, in order
+    send_queue: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// the packets received in the last batch not handed out yet
+    recv_queue: VecDeque<UdpPacket>,
+    /// the first fits any packet, the others `SMALL_SLOT` until a packet
+    /// didn't fit in one
+    recv_bufs: Vec<Vec<u8>>,
 }
 
+/// packets sent or received per syscall
+const BATCH_SIZE: usize = 32;
+const MAX_PACKET_SIZE: usize = 65535;
+/// fits the packets of a 1500 bytes MTU
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+const SMALL_SLOT: usize = 2048;
+
 impl OutboundDatagramImpl {
     pub fn new(udp: UdpSocket, resolver: ThreadSafeDNSResolver) -> Self {
         Self {
             inner: udp,
             resolver,
-            flushed: true,
             pkt: None,
+            send_queue: VecDeque::new(),
+            recv_queue: VecDeque::new(),
+            recv_bufs: vec![vec![0u8; MAX_PACKET_SIZE]],
+        }
+    }
+
+    /// sends the queued packets, dropping the one that failed
+    fn poll_send_queue(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Self {
+            inner, send_queue, ..
+        } = self;
+        while !send_queue.is_empty() {
+            #[cfg(target_os = "linux")]
+            {
+                ready!(inner.poll_send_ready(cx))?;
+                let packets = send_queue.make_contiguous();
+                match inner.try_io(tokio::io::Interest::WRITABLE, || {
+                    batch::send_to(inner, packets)
+                }) {
+                    Ok(n) => {
+                        send_queue.drain(..n);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        send_queue.pop_front();
+                        return Poll::Ready(Err(e));
+                    }
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let (data, dst) = send_queue.front().expect("not empty");
+                let res = ready!(inner.poll_send_to(cx, data, *dst));
+                let (data, _) = send_queue.pop_front().expect("not empty");
+                let n = res?;
+                if n != data.len() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("failed to send all data, only sent {} bytes", n),
+                    )));
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// receives the packets waiting into `recv_queue`
+    fn poll_recv_batch(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        #[cfg(target_os = "linux")]
+        loop {
+            ready!(self.inner.poll_recv_ready(cx))?;
+            if self.recv_bufs.len() < BATCH_SIZE {
+                self.recv_bufs
+                    .resize_with(BATCH_SIZE, || vec![0u8; SMALL_SLOT]);
+            }
+            let Self {
+                inner,
+                recv_bufs,
+                recv_queue,
+                ..
+            } = self;
+            match inner.try_io(tokio::io::Interest::READABLE, || {
+                batch::recv_from(inner, recv_bufs)
+            }) {
+                Ok(received) => {
+                    for (i, (len, src, truncated)) in
+                        received.into_iter().enumerate()
+                    {
+                        if truncated {
+                            tracing::debug!(
+                                "dropping a packet of more than {} bytes from {}",
+                                recv_bufs[i].len(),
+                                src
+                            );
+                            recv_bufs[i].resize(MAX_PACKET_SIZE, 0);
+                            continue;
+                        }
+                        recv_queue.push_back(UdpPacket {
+                            data: recv_bufs[i][..len].to_vec(),
+                            src_addr: nat64::unmap_socket_addr(src).into(),
+                            dst_addr: SocksAddr::any_ipv4(),
+                        });
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let mut buf = tokio::io::ReadBuf::new(&mut self.recv_bufs[0]);
+            let src = ready!(self.inner.poll_recv_from(cx, &mut buf))?;
+            let data = buf.filled().to_vec();
+            self.recv_queue.push_back(UdpPacket {
+                data,
+                src_addr: nat64::unmap_socket_addr(src).into(),
+                dst_addr: SocksAddr::any_ipv4(),
+            });
+            Poll::Ready(Ok(()))
         }
     }
 }
@@ -87,11 +205,10 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        if !self.flushed {
-            match self.poll_flush(cx)? {
-                Poll::Ready(()) => {}
-                Poll::Pending => return Poll::Pending,
-            }
+        // a packet to a domain goes out before the next is taken, to keep
+        // the order
+        if self.pkt.is_some() || self.send_queue.len() >= BATCH_SIZE {
+            ready!(self.poll_flush(cx))?;
         }
 
         Poll::Ready(Ok(()))
@@ -99,8 +216,12 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
 
     fn start_send(self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
         let pin = self.get_mut();
-        pin.pkt = Some(item);
-        pin.flushed = false;
+        match item.dst_addr {
+            SocksAddr::Ip(dst) => pin
+                .send_queue
+                .push_back((item.data, nat64::map_socket_addr(dst))),
+            SocksAddr::Domain(..) => pin.pkt = Some(item),
+        }
         Ok(())
     }
 
@@ -108,60 +229,34 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        if self.flushed {
-            return Poll::Ready(Ok(()));
-        }
+        loop {
+            ready!(self.poll_send_queue(cx))?;
 
-        let Self {
-            ref mut inner,
-            ref mut pkt,
-            ref resolver,
-            ..
-        } = *self;
-
-        if pkt.is_some() {
-            let p = pkt.as_ref().unwrap();
-            let dst = &p.dst_addr;
-            let data = &p.data;
-            let dst = match dst {
-                SocksAddr::Domain(domain, port) => {
-                    let domain = domain.to_string();
-                    let port = *port;
-                    let mut fut = resolver.resolve(domain.as_str(), false);
-                    let ip = ready!(fut.as_mut().poll(cx).map_err(|_| {
-                        io::Error::new(io::ErrorKind::Other, "resolve domain failed")
-                    }))?;
-                    if let Some(ip) = ip {
-                        (ip, port).into()
-                    } else {
-                        return Poll::Ready(Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("resolve domain failed: {}", domain),
-                        )));
-                    }
-                }
-                SocksAddr::Ip(addr) => *addr,
+            let Some(SocksAddr::Domain(domain, port)) =
+                self.pkt.as_ref().map(|x| &x.dst_addr)
+            else {
+                return Poll::Ready(Ok(()));
             };
-
-            let n = ready!(inner.poll_send_to(cx, data.as_slice(), dst))?;
-            let wrote_all = n == data.len();
-            self.pkt = None;
-            self.flushed = true;
-
-            let res = if wrote_all {
-                Ok(())
-            } else {
-                Err(new_io_error(format!(
-                    "failed to send all data, only sent {} bytes",
-                    n
-                )))
+            let (domain, port) = (domain.to_string(), *port);
+            let ip = {
+                let mut fut = self.resolver.resolve(domain.as_str(), false);
+                ready!(fut.as_mut().poll(cx).map_err(|_| {
+                    io::Error::new(io::ErrorKind::Other, "resolve domain failed")
+                }))
             };
-            Poll::Ready(res)
-        } else {
-            Poll::Ready(Err(io::Error::new(
-                io::ErrorKind::Other,
-                "no packet to send",
-            )))
+            let pkt = self.pkt.take().expect("checked above");
+            match ip? {
+                Some(ip) => self.send_queue.push_back((
+                    pkt.data,
+                    nat64::map_socket_addr((ip, port).into()),
+                )),
+                None => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("resolve domain failed: {}", domain),
+                    )));
+                }
+            }
         }
     }
 
@@ -173,6 +268,7 @@ impl Sink<UdpPacket> for OutboundDatagramImpl {
         Poll::Ready(Ok(()))
     }
 }
+
 impl Stream for OutboundDatagramImpl {
     type Item = UdpPacket;
 
@@ -180,19 +276,196 @@ impl Stream for OutboundDatagramImpl {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        let Self { ref mut inner, .. } = *self;
-        let mut mem = vec![0u8; 65535];
-        let mut buf = ReadBuf::new(&mut mem);
-        match ready!(inner.poll_recv_from(cx, &mut buf)) {
-            Ok(src) => {
-                let data = buf.filled().to_vec();
-                Poll::Ready(Some(UdpPacket {
-                    data,
-                    src_addr: src.into(),
-                    dst_addr: SocksAddr::any_ipv4(),
-                }))
+        loop {
+            if let Some(packet) = self.recv_queue.pop_front() {
+                return Poll::Ready(Some(packet));
             }
-            Err(_) => Poll::Ready(None),
+            if ready!(self.poll_recv_batch(cx)).is_err() {
+                return Poll::Ready(None);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod batch {
+    use std::{io, net::SocketAddr, os::fd::AsRawFd, ptr};
+
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+
+    use super::BATCH_SIZE;
+
+    fn msghdr(
+        name: *mut libc::c_void,
+        namelen: libc::socklen_t,
+        iov: &mut libc::iovec,
+    ) -> libc::mmsghdr {
+        // SAFETY: all zeros is a valid msghdr, the padding of some libcs
+        // included
+        let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+        hdr.msg_name = name;
+        hdr.msg_namelen = namelen;
+        hdr.msg_iov = iov;
+        hdr.msg_iovlen = 1;
+        libc::mmsghdr {
+            msg_hdr: hdr,
+            msg_len: 0,
+        }
+    }
+
+    /// sends the first `BATCH_SIZE` of `packets` at most, returns how many
+    /// were sent
+    pub fn send_to(
+        socket: &UdpSocket,
+        packets: &[(Vec<u8>, SocketAddr)],
+    ) -> io::Result<usize> {
+        let packets = &packets[..packets.len().min(BATCH_SIZE)];
+        let addrs: Vec<SockAddr> = packets
+            .iter()
+            .map(|(_, dst)| SockAddr::from(*dst))
+            .collect();
+        let mut iovs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|(data, _)| libc::iovec {
+                iov_base: data.as_ptr() as *mut _,
+                iov_len: data.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(addrs.iter())
+            .map(|(iov, addr)| msghdr(addr.as_ptr() as *mut _, addr.len(), iov))
+            .collect();
+        // SAFETY: the headers point into `addrs` and `packets`, which outlive
+        // the call, and the kernel doesn't write through them
+        let sent = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, 0)
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// receives a packet into each of `bufs` at most, returns the length,
+    /// source and whether it was cut short of each
+    pub fn recv_from(
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+    ) -> io::Result<Vec<(usize, SocketAddr, bool)>> {
+        // SAFETY: all zeros is a valid sockaddr_storage
+        let mut addrs: Vec<libc::sockaddr_storage> =
+            vec![unsafe { std::mem::zeroed() }; bufs.len()];
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut _,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iov, addr)| {
+                msghdr(
+                    addr as *mut _ as *mut _,
+                    size_of::<libc::sockaddr_storage>() as _,
+                    iov,
+                )
+            })
+            .collect();
+        // SAFETY: the headers point into `addrs` and `bufs`, which outlive the
+        // call, with their lengths
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as _,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        msgs[..received as usize]
+            .iter()
+            .zip(addrs.iter())
+            .map(|(msg, addr)| {
+                // SAFETY: the kernel wrote an address of `msg_namelen` bytes
+                let src = unsafe { SockAddr::new(*addr, msg.msg_hdr.msg_namelen) }
+                    .as_socket()
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "not an ip source address",
+                        )
+                    })?;
+                let truncated = msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+                Ok((msg.msg_len as usize, src, truncated))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::UdpSocket;
+
+    use super::{OutboundDatagramImpl, UdpPacket};
+    use crate::app::dns::MockClashResolver;
+
+    async fn next(datagram: &mut OutboundDatagramImpl) -> UdpPacket {
+        tokio::time::timeout(Duration::from_secs(1), datagram.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_batches() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let mut datagram =
+            OutboundDatagramImpl::new(socket, Arc::new(MockClashResolver::new()));
+
+        for i in 0..40u8 {
+            datagram
+                .feed(UdpPacket::new(vec![i], addr.into(), peer_addr.into()))
+                .await
+                .unwrap();
+        }
+        datagram.flush().await.unwrap();
+        let mut buf = [0u8; 16];
+        for i in 0..40u8 {
+            let (n, from) = peer.recv_from(&mut buf).await.unwrap();
+            assert_eq!((&buf[..n], from), (&[i][..], addr));
+        }
+
+        // a large one after a small one is cut short of its slot, and
+        // dropped, the next ones fit
+        let large = vec![7u8; 4000];
+        peer.send_to(b"small", addr).await.unwrap();
+        peer.send_to(&large, addr).await.unwrap();
+        peer.send_to(b"after", addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let packet = next(&mut datagram).await;
+        assert_eq!(packet.data, b"small");
+        assert_eq!(packet.src_addr, peer_addr.into());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(next(&mut datagram).await.data, b"after");
+            peer.send_to(b"small", addr).await.unwrap();
+            peer.send_to(&large, addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(next(&mut datagram).await.data, b"small");
         }
+        assert_eq!(next(&mut datagram).await.data, large);
     }
 }
@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use erased_serde::Serialize;
+use hickory_proto::op;
+
+use crate::{
+    app::{
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
+        dns::{ClashResolver, ResolverKind, ThreadSafeDNSResolver},
+        net::Interface,
+    },
+    config::internal::proxy::{DialerOptions, IpVersion},
+    proxy::{
+        AnyOutboundHandler, ConnectorType, DialWithConnector, OutboundHandler,
+        OutboundType, PendingBind, transport::SessionStats, utils::RemoteConnector,
+    },
+    session::Session,
+};
+
+/// A proxy with `ip-version`, `routing-mark` or `source-ip`.
+/// They are applied to the session and the resolver handed to the proxy, so
+/// they take effect where it dials its server, and `ip-version` also where it
+/// resolves a destination itself to pass the IP on.
+pub struct Handler {
+    inner: AnyOutboundHandler,
+    ip_version: IpVersion,
+    routing_mark: Option<u32>,
+    source_ip: Option<IpAddr>,
+}
+
+impl Debug for Handler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dialer")
+            .field("name", &self.inner.name())
+            .field("ip_version", &self.ip_version)
+            .field("routing_mark", &self.routing_mark)
+            .field("source_ip", &self.source_ip)
+            .finish()
+    }
+}
+
+impl Handler {
+    /// `inner` as it is if `opts` sets none of the options
+    pub fn wrap(
+        inner: AnyOutboundHandler,
+        opts: DialerOptions,
+    ) -> AnyOutboundHandler {
+        if opts == DialerOptions::default() {
+            return inner;
+        }
+        let ip_version = opts.ip_version.unwrap_or(match opts.source_ip {
+            Some(IpAddr::V4(_)) => IpVersion::Ipv4,
+            Some(IpAddr::V6(_)) => IpVersion::Ipv6,
+            None => IpVersion::Dual,
+        });
+        Arc::new(Self {
+            inner,
+            ip_version,
+            routing_mark: opts.routing_mark,
+            source_ip: opts.source_ip,
+        })
+    }
+
+    fn session(&self, sess: &Session) -> Session {
+        let mut sess = sess.clone();
+        if let Some(ip) = self.source_ip {
+            sess.iface = Some(Interface::IpAddr(ip));
+        }
+        if let Some(mark) = self.routing_mark {
+            sess.so_mark = Some(mark);
+        }
+        sess
+    }
+
+    fn resolver(&self, resolver: ThreadSafeDNSResolver) -> ThreadSafeDNSResolver {
+        match self.ip_version {
+            IpVersion::Dual => resolver,
+            ip_version => Arc::new(IpVersionResolver {
+                inner: resolver,
+                ip_version,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl DialWithConnector for Handler {
+    fn support_dialer(&self) -> Option<&str> {
+        self.inner.support_dialer()
+    }
+
+    async fn register_connector(&self, connector: Arc<dyn RemoteConnector>) {
+        self.inner.register_connector(connector).await
+    }
+}
+
+#[async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.inner.proto()
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.inner.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        self.inner
+            .connect_stream(&self.session(sess), self.resolver(resolver))
+            .await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.inner
+            .connect_datagram(&self.session(sess), self.resolver(resolver))
+            .await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        self.inner.support_connector().await
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        self.inner
+            .connect_stream_with_connector(
+                &self.session(sess),
+                self.resolver(resolver),
+                connector,
+            )
+            .await
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.inner
+            .connect_datagram_with_connector(
+                &self.session(sess),
+                self.resolver(resolver),
+                connector,
+            )
+            .await
+    }
+
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        self.inner
+            .bind_stream(&self.session(sess), self.resolver(resolver))
+            .await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.inner.as_map().await;
+        m.insert("ip-version".to_string(), Box::new(self.ip_version) as _);
+        if let Some(mark) = self.routing_mark {
+            m.insert("routing-mark".to_string(), Box::new(mark) as _);
+        }
+        if let Some(ip) = self.source_ip {
+            m.insert("source-ip".to_string(), Box::new(ip) as _);
+        }
+        m
+    }
+
+    fn icon(&self) -> Option<String> {
+        self.inner.icon()
+    }
+
+    fn tls_sessions(&self) -> Option<SessionStats> {
+        self.inner.tls_sessions()
+    }
+}
+
+/// Resolves to the addresses of one IP family, or that family first
+struct IpVersionResolver {
+    inner: ThreadSafeDNSResolver,
+    ip_version: IpVersion,
+}
+
+impl IpVersionResolver {
+    async fn v4(
+        &self,
+        host: &str,
+        enhanced: bool,
+    ) -> anyhow::Result<Option<IpAddr>> {
+        Ok(self.inner.resolve_v4(host, enhanced).await?.map(IpAddr::V4))
+    }
+
+    async fn v6(
+        &self,
+        host: &str,
+        enhanced: bool,
+    ) -> anyhow::Result<Option<IpAddr>> {
+        Ok(self.inner.resolve_v6(host, enhanced).await?.map(IpAddr::V6))
+    }
+}
+
+#[async_trait]
+impl ClashResolver for IpVersionResolver {
+    async fn resolve(
+        &self,
+        host: &str,
+        enhanced: bool,
+    ) -> anyhow::Result<Option<IpAddr>> {
+        match self.ip_version {
+            IpVersion::Dual => self.inner.resolve(host, enhanced).await,
+            IpVersion::Ipv4 => self.v4(host, enhanced).await,
+            IpVersion::Ipv6 => self.v6(host, enhanced).await,
+            IpVersion::Ipv4Prefer => match self.v4(host, enhanced).await {
+                Ok(Some(ip)) => Ok(Some(ip)),
+                _ => self.v6(host, enhanced).await,
+            },
+            IpVersion::Ipv6Prefer => match self.v6(host, enhanced).await {
+                Ok(Some(ip)) => Ok(Some(ip)),
+                _ => self.v4(host, enhanced).await,
+            },
+        }
+    }
+
+    async fn resolve_v4(
+        &self,
+        host: &str,
+        enhanced: bool,
+    ) -> anyhow::Result<Option<Ipv4Addr>> {
+        self.inner.resolve_v4(host, enhanced).await
+    }
+
+    async fn resolve_v6(
+        &self,
+        host: &str,
+        enhanced: bool,
+    ) -> anyhow::Result<Option<Ipv6Addr>> {
+        self.inner.resolve_v6(host, enhanced).await
+    }
+
+    async fn cached_for(&self, ip: IpAddr) -> Option<String> {
+        self.inner.cached_for(ip).await
+    }
+
+    async fn exchange(&self, message: &op::Message) -> anyhow::Result<op::Message> {
+        self.inner.exchange(message).await
+    }
+
+    async fn reverse_lookup(&self, ip: IpAddr) -> Option<String> {
+        self.inner.reverse_lookup(ip).await
+    }
+
+    async fn is_fake_ip(&self, ip: IpAddr) -> bool {
+        self.inner.is_fake_ip(ip).await
+    }
+
+    async fn retain_fake_ip(&self, ip: IpAddr) {
+        self.inner.retain_fake_ip(ip).await
+    }
+
+    async fn release_fake_ip(&self, ip: IpAddr) {
+        self.inner.release_fake_ip(ip).await
+    }
+
+    fn fake_ip_enabled(&self) -> bool {
+        self.inner.fake_ip_enabled()
+    }
+
+    fn ipv6(&self) -> bool {
+        self.inner.ipv6()
+    }
+
+    fn set_ipv6(&self, enable: bool) {
+        self.inner.set_ipv6(enable)
+    }
+
+    fn kind(&self) -> ResolverKind {
+        self.inner.kind()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, sync::Arc};
+
+    use crate::{
+        app::{dns::MockClashResolver, net::Interface},
+        config::internal::proxy::{DialerOptions, IpVersion},
+        proxy::{AnyOutboundHandler, mocks::MockDummyOutboundHandler},
+        session::Session,
+    };
+
+    use super::{ClashResolver, Handler, IpVersionResolver};
+
+    #[tokio::test]
+    async fn test_ip_version_resolver() {
+        let mut inner = MockClashResolver::new();
+        inner.expect_resolve_v4().returning(|host, _| match host {
+            "v4.example.com" => Ok(Some(Ipv4Addr::new(10, 0, 0, 1))),
+            _ => Ok(None),
+        });
+        inner
+            .expect_resolve_v6()
+            .returning(|_, _| Ok(Some("2001:db8::1".parse().unwrap())));
+        let inner = Arc::new(inner);
+
+        for (ip_version, host, expected) in [
+            (IpVersion::Ipv4Prefer, "v4.example.com", Some("10.0.0.1")),
+            (IpVersion::Ipv4Prefer, "v6.example.com", Some("2001:db8::1")),
+            (IpVersion::Ipv6Prefer, "v4.example.com", Some("2001:db8::1")),
+            (IpVersion::Ipv4, "v6.example.com", None),
+        ] {
+            let resolver = IpVersionResolver {
+                inner: inner.clone(),
+                ip_version,
+            };
+            let ip = resolver.resolve(host, false).await.unwrap();
+            assert_eq!(
+                ip,
+                expected.map(|x| x.parse().unwrap()),
+                "{:?} {}",
+                ip_version,
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn test_session_options() {
+        let opts = DialerOptions {
+            routing_mark: Some(0x100),
+            source_ip: Some("192.168.2.10".parse().unwrap()),
+            ..Default::default()
+        };
+        let handler = Handler {
+            inner: Arc::new(MockDummyOutboundHandler::new()),
+            ip_version: IpVersion::Ipv4,
+            routing_mark: opts.routing_mark,
+            source_ip: opts.source_ip,
+        };
+
+        let sess = handler.session(&Session::default());
+        assert_eq!(sess.so_mark, Some(0x100));
+        assert!(matches!(
+            sess.iface,
+            Some(Interface::IpAddr(ip)) if ip == opts.source_ip.unwrap()
+        ));
+
+        let unset: AnyOutboundHandler = Arc::new(MockDummyOutboundHandler::new());
+        let wrapped = Handler::wrap(Arc::clone(&unset), Default::default());
+        assert!(Arc::ptr_eq(&unset, &wrapped));
+    }
+}
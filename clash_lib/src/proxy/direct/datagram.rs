@@ -0,0 +1,271 @@
+//! The UDP of DIRECT. A socket per address family, both on the interface of
+//! the session, so one session reaches IPv4 and IPv6 peers alike, and takes
+//! replies from any of them for the full cone NAT of the dispatcher.
+
+use std::{
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use futures::{FutureExt, Sink, Stream, future::BoxFuture, ready};
+use tokio::{io::ReadBuf, net::UdpSocket};
+
+use crate::{
+    app::{dns::ThreadSafeDNSResolver, net::nat64},
+    common::errors::new_io_error,
+    proxy::{datagram::UdpPacket, utils::new_udp_socket},
+    session::{Session, SocksAddr},
+};
+
+pub struct DirectDatagram {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    resolver: ThreadSafeDNSResolver,
+    /// the lookup of the domain the packet is sent to, kept across polls.
+    /// Behind a mutex for `Sync`, only ever used through `get_mut`
+    resolving: Mutex<Option<BoxFuture<'static, anyhow::Result<Option<IpAddr>>>>>,
+    pkt: Option<UdpPacket>,
+    flushed: bool,
+    /// which socket is read first, alternated so neither starves the other
+    v6_first: bool,
+    buf: Vec<u8>,
+}
+
+impl Debug for DirectDatagram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirectDatagram")
+            .field("v4", &self.v4)
+            .field("v6", &self.v6)
+            .finish()
+    }
+}
+
+impl DirectDatagram {
+    /// fails only if neither family has a socket
+    pub async fn new(
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<Self> {
+        let bind = |ip: IpAddr| {
+            new_udp_socket(
+                Some((ip, 0).into()),
+                sess.iface.clone(),
+                #[cfg(target_os = "linux")]
+                sess.so_mark,
+            )
+        };
+        let (v4, v6) = match (
+            bind(Ipv4Addr::UNSPECIFIED.into()).await,
+            bind(Ipv6Addr::UNSPECIFIED.into()).await,
+        ) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => (v4.ok(), v6.ok()),
+        };
+
+        Ok(Self {
+            v4,
+            v6,
+            resolver,
+            resolving: Mutex::new(None),
+            pkt: None,
+            flushed: true,
+            v6_first: false,
+            buf: vec![0; 65535],
+        })
+    }
+}
+
+impl Sink<UdpPacket> for DirectDatagram {
+    type Error = io::Error;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        if !self.flushed {
+            ready!(self.poll_flush(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: UdpPacket) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.pkt = Some(item);
+        this.flushed = false;
+        Ok(())
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.flushed {
+            return Poll::Ready(Ok(()));
+        }
+        let Some(pkt) = this.pkt.as_mut() else {
+            return Poll::Ready(Err(new_io_error("no packet to send")));
+        };
+
+        let dst = match &pkt.dst_addr {
+            SocksAddr::Ip(addr) => *addr,
+            SocksAddr::Domain(host, port) => {
+                let resolving = this.resolving.get_mut().unwrap();
+                let fut = resolving.get_or_insert_with(|| {
+                    let resolver = this.resolver.clone();
+                    let host = host.clone();
+                    // IPv6 only if it's preferred and there is a socket for it
+                    let v6 = this.v6.is_some();
+                    async move {
+                        if v6 {
+                            resolver.resolve(&host, false).await
+                        } else {
+                            Ok(resolver
+                                .resolve_v4(&host, false)
+                                .await?
+                                .map(Into::into))
+                        }
+                    }
+                    .boxed()
+                });
+                let resolved = ready!(fut.as_mut().poll(cx));
+                *resolving = None;
+                match resolved {
+                    Ok(Some(ip)) => {
+                        let addr = SocketAddr::new(ip, *port);
+                        pkt.dst_addr = addr.into();
+                        addr
+                    }
+                    _ => {
+                        let e = format!("failed to resolve {}", host);
+                        this.pkt = None;
+                        this.flushed = true;
+                        return Poll::Ready(Err(new_io_error(e)));
+                    }
+                }
+            }
+        };
+
+        let dst = nat64::map_socket_addr(dst);
+        let socket = match dst {
+            SocketAddr::V4(_) => this.v4.as_ref(),
+            SocketAddr::V6(_) => this.v6.as_ref(),
+        };
+        let res = match socket {
+            Some(socket) => ready!(socket.poll_send_to(cx, &pkt.data, dst))
+                .and_then(|n| match n == pkt.data.len() {
+                    true => Ok(()),
+                    false => Err(new_io_error(format!(
+                        "failed to send all data, only sent {} bytes",
+                        n
+                    ))),
+                }),
+            None => Err(new_io_error(format!("no socket to send to {}", dst))),
+        };
+        this.pkt = None;
+        this.flushed = true;
+        Poll::Ready(res)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl Stream for DirectDatagram {
+    type Item = UdpPacket;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let sockets = match this.v6_first {
+            true => [this.v6.as_ref(), this.v4.as_ref()],
+            false => [this.v4.as_ref(), this.v6.as_ref()],
+        };
+        this.v6_first = !this.v6_first;
+
+        for socket in sockets.into_iter().flatten() {
+            let mut buf = ReadBuf::new(&mut this.buf);
+            match socket.poll_recv_from(cx, &mut buf) {
+                Poll::Ready(Ok(src)) => {
+                    return Poll::Ready(Some(UdpPacket {
+                        data: buf.filled().to_vec(),
+                        src_addr: nat64::unmap_socket_addr(src).into(),
+                        dst_addr: SocksAddr::any_ipv4(),
+                    }));
+                }
+                Poll::Ready(Err(_)) => return Poll::Ready(None),
+                Poll::Pending => {}
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::UdpSocket;
+
+    use super::DirectDatagram;
+    use crate::{
+        app::dns::MockClashResolver,
+        proxy::datagram::UdpPacket,
+        session::{Session, SocksAddr},
+    };
+
+    async fn echo(addr: &str) -> Option<SocketAddr> {
+        let socket = UdpSocket::bind(addr).await.ok()?;
+        let addr = socket.local_addr().ok()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1500];
+            while let Ok((n, src)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&buf[..n], src).await;
+            }
+        });
+        Some(addr)
+    }
+
+    #[tokio::test]
+    async fn test_direct_datagram() {
+        let v4 = echo("127.0.0.1:0").await.unwrap();
+        // not every sandbox has IPv6
+        let v6 = echo("[::1]:0").await;
+
+        let mut resolver = MockClashResolver::new();
+        resolver
+            .expect_resolve()
+            .returning(|_, _| Ok(Some("127.0.0.1".parse().unwrap())));
+        resolver
+            .expect_resolve_v4()
+            .returning(|_, _| Ok(Some("127.0.0.1".parse().unwrap())));
+        let mut datagram =
+            DirectDatagram::new(&Session::default(), Arc::new(resolver))
+                .await
+                .unwrap();
+
+        let mut peers =
+            vec![(SocksAddr::Domain("echo.test".to_owned(), v4.port()), v4)];
+        peers.extend(v6.map(|v6| (SocksAddr::Ip(v6), v6)));
+        for (dst, addr) in peers {
+            datagram
+                .send(UdpPacket::new(b"ping".to_vec(), SocksAddr::any_ipv4(), dst))
+                .await
+                .unwrap();
+            let pkt = datagram.next().await.unwrap();
+            assert_eq!(pkt.data, b"ping");
+            assert_eq!(pkt.src_addr, SocksAddr::Ip(addr));
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
 
 use crate::{
     app::{
@@ -8,24 +12,29 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::errors::map_io_error,
+    common::errors::{NetError, map_io_error},
     config::internal::proxy::PROXY_DIRECT,
     proxy::{
-        OutboundHandler,
-        datagram::OutboundDatagramImpl,
+        OutboundHandler, PendingBind,
         utils::{new_tcp_stream, new_udp_socket},
     },
-    session::Session,
+    session::{Session, SocksAddr},
 };
 
 use async_trait::async_trait;
-use futures::TryFutureExt;
+use futures::{FutureExt, TryFutureExt};
 use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing::warn;
+
+use self::datagram::DirectDatagram;
 
 use super::{
     ConnectorType, DialWithConnector, OutboundType, utils::RemoteConnector,
 };
 
+mod datagram;
+
 #[derive(Serialize)]
 pub struct Handler;
 
@@ -62,13 +71,12 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedStream> {
+        let host = sess.destination.host();
         let remote_ip = resolver
-            .resolve(sess.destination.host().as_str(), false)
-            .map_err(map_io_error)
+            .resolve(host.as_str(), false)
+            .map_err(|e| NetError::dns(format!("can't resolve {}: {}", host, e)))
             .await?
-            .ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::Other, "no dns result")
-            })?;
+            .ok_or_else(|| NetError::no_record(format!("no record for {}", host)))?;
 
         let s = new_tcp_stream(
             (remote_ip, sess.destination.port()).into(),
@@ -83,20 +91,66 @@ impl OutboundHandler for Handler {
         Ok(Box::new(s))
     }
 
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> std::io::Result<PendingBind> {
+        // the peer the client expects the connection from, any for 0.0.0.0
+        let expected = match sess.destination.ip() {
+            Some(ip) => ip,
+            None => resolver
+                .resolve(sess.destination.host().as_str(), false)
+                .map_err(map_io_error)
+                .await?
+                .ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::Other, "no dns result")
+                })?,
+        };
+        let expected = expected.to_canonical();
+        let listener = TcpListener::bind((unspecified(expected), 0)).await?;
+        // the client passes it on to the peer, so it must be reachable from
+        // there rather than from the client
+        let bound = SocketAddr::new(
+            route_source(expected, sess).await?,
+            listener.local_addr()?.port(),
+        );
+
+        let name = self.name().to_owned();
+        let accept = async move {
+            loop {
+                let (s, peer) = listener.accept().await?;
+                if !expected.is_unspecified()
+                    && peer.ip().to_canonical() != expected.to_canonical()
+                {
+                    warn!(
+                        "BIND at {} refused a connection from {}, expecting {}",
+                        bound, peer, expected
+                    );
+                    continue;
+                }
+                let s = ChainedStreamWrapper::new(s);
+                s.append_to_chain(&name).await;
+                return Ok::<(SocksAddr, BoxedChainedStream), std::io::Error>((
+                    peer.into(),
+                    Box::new(s),
+                ));
+            }
+        };
+
+        Ok(PendingBind {
+            outbound: self.name().to_owned(),
+            bound: bound.into(),
+            accept: accept.boxed(),
+        })
+    }
+
     async fn connect_datagram(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<BoxedChainedDatagram> {
-        let d = new_udp_socket(
-            None,
-            sess.iface.clone(),
-            #[cfg(target_os = "linux")]
-            sess.so_mark,
-        )
-        .await
-        .map(|x| OutboundDatagramImpl::new(x, resolver))?;
-
+        let d = DirectDatagram::new(sess, resolver).await?;
         let d = ChainedDatagramWrapper::new(d);
         d.append_to_chain(self.name()).await;
         Ok(Box::new(d))
@@ -148,3 +202,93 @@ impl OutboundHandler for Handler {
         Ok(Box::new(d))
     }
 }
+
+/// the any address of the family of `ip`
+fn unspecified(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// the address the route to `peer` goes out from, the default route of its
+/// family if it's unspecified
+async fn route_source(peer: IpAddr, sess: &Session) -> io::Result<IpAddr> {
+    let peer = match peer {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::new(8, 8, 8, 8).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888).into()
+        }
+        ip => ip,
+    };
+    let socket = new_udp_socket(
+        Some((unspecified(peer), 0).into()),
+        sess.iface.clone(),
+        #[cfg(target_os = "linux")]
+        sess.so_mark,
+    )
+    .await?;
+    // nothing is sent, connecting only picks the route
+    socket.connect((peer, 9)).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::Handler;
+    use crate::{
+        app::dns::MockClashResolver,
+        proxy::OutboundHandler,
+        session::{Session, SocksAddr},
+    };
+
+    #[tokio::test]
+    async fn test_bind_stream() {
+        let handler = Handler::new();
+        let sess = Session {
+            destination: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        };
+        let pending = handler
+            .bind_stream(&sess, Arc::new(MockClashResolver::new()))
+            .await
+            .unwrap();
+        assert_eq!(pending.bound.ip(), Some([127, 0, 0, 1].into()));
+        let port = pending.bound.port();
+        let accept = tokio::spawn(pending.accept);
+
+        // not the peer of the request, e.g. a scan of the port
+        #[cfg(target_os = "linux")]
+        {
+            let other = tokio::net::TcpSocket::new_v4().unwrap();
+            other.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+            let mut other =
+                other.connect(([127, 0, 0, 1], port).into()).await.unwrap();
+            let mut buf = [0; 1];
+            let n = tokio::time::timeout(
+                std::time::Duration::from_secs(1),
+                other.read(&mut buf),
+            )
+            .await
+            .unwrap();
+            assert!(n.is_err() || n.unwrap() == 0);
+            assert!(!accept.is_finished());
+        }
+
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (addr, mut s) = accept.await.unwrap().unwrap();
+        assert_eq!(addr, SocksAddr::from(peer.local_addr().unwrap()));
+
+        peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
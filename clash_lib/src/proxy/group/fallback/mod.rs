@@ -11,12 +11,13 @@ use crate::{
             ProxyManager, providers::proxy_provider::ThreadSafeProxyProvider,
         },
     },
+    config::internal::proxy::UnknownNodes,
     proxy::{
         AnyOutboundHandler, ConnectorType, DialWithConnector, HandlerCommonOptions,
-        OutboundHandler, OutboundType,
+        OutboundHandler, OutboundType, PendingBind,
         utils::{RemoteConnector, provider_helper::get_proxies_from_providers},
     },
-    session::Session,
+    session::{Network, Session},
 };
 
 #[derive(Default, Clone)]
@@ -24,6 +25,7 @@ pub struct HandlerOptions {
     pub common_opts: HandlerCommonOptions,
     pub name: String,
     pub udp: bool,
+    pub unknown_nodes: UnknownNodes,
 }
 
 pub struct Handler {
@@ -57,10 +59,17 @@ impl Handler {
         get_proxies_from_providers(&self.providers, touch).await
     }
 
-    async fn find_alive_proxy(&self, touch: bool) -> AnyOutboundHandler {
+    /// the first usable proxy, for `udp` also not known to fail to relay
+    /// UDP
+    async fn find_alive_proxy(&self, touch: bool, udp: bool) -> AnyOutboundHandler {
         let proxies = self.get_proxies(touch).await;
         for proxy in proxies.iter() {
-            if self.proxy_manager.alive(proxy.name()).await {
+            if self
+                .proxy_manager
+                .usable(proxy.name(), self.opts.unknown_nodes)
+                .await
+                && (!udp || self.proxy_manager.udp_alive(proxy.name()).await)
+            {
                 debug!("`{}` fallback to `{}`", self.name(), proxy.name());
                 return proxy.clone();
             }
@@ -86,7 +95,12 @@ impl OutboundHandler for Handler {
 
     /// whether the outbound handler support UDP
     async fn support_udp(&self) -> bool {
-        self.opts.udp || self.find_alive_proxy(false).await.support_udp().await
+        self.opts.udp
+            || self
+                .find_alive_proxy(false, false)
+                .await
+                .support_udp()
+                .await
     }
 
     /// connect to remote target via TCP
@@ -95,8 +109,12 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let proxy = self.find_alive_proxy(true).await;
-        match proxy.connect_stream(sess, resolver).await {
+        let proxy = self.find_alive_proxy(true, false).await;
+        let s = proxy.connect_stream(sess, resolver).await;
+        self.proxy_manager
+            .report_connect(proxy.name(), s.as_ref().err())
+            .await;
+        match s {
             Ok(s) => {
                 s.append_to_chain(self.name()).await;
                 Ok(s)
@@ -105,14 +123,29 @@ impl OutboundHandler for Handler {
         }
     }
 
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        self.find_alive_proxy(true, false)
+            .await
+            .bind_stream(sess, resolver)
+            .await
+    }
+
     /// connect to remote target via UDP
     async fn connect_datagram(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let proxy = self.find_alive_proxy(true).await;
-        proxy.connect_datagram(sess, resolver).await
+        let proxy = self.find_alive_proxy(true, true).await;
+        let d = proxy.connect_datagram(sess, resolver).await;
+        self.proxy_manager
+            .report_connect(proxy.name(), d.as_ref().err())
+            .await;
+        d
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -125,10 +158,19 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let proxy = self.find_alive_proxy(true).await;
-        proxy
+        let proxy = self.find_alive_proxy(true, false).await;
+        let s = proxy
             .connect_stream_with_connector(sess, resolver, connector)
-            .await
+            .await;
+        self.proxy_manager
+            .report_connect(proxy.name(), s.as_ref().err())
+            .await;
+        s
+    }
+
+    async fn current_proxy(&self, sess: &Session) -> Option<String> {
+        let udp = sess.network == Network::Udp;
+        Some(self.find_alive_proxy(false, udp).await.name().to_owned())
     }
 
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
@@ -138,7 +180,8 @@ impl OutboundHandler for Handler {
         m.insert("type".to_string(), Box::new(self.proto()) as _);
         m.insert(
             "now".to_string(),
-            Box::new(self.find_alive_proxy(false).await.name().to_owned()) as _,
+            Box::new(self.find_alive_proxy(false, false).await.name().to_owned())
+                as _,
         );
         m.insert(
             "all".to_string(),
@@ -1,7 +1,7 @@
 use std::{
     io::Cursor,
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use futures::future::BoxFuture;
@@ -10,7 +10,8 @@ use public_suffix::{DEFAULT_PROVIDER, EffectiveTLDProvider};
 use tokio::sync::Mutex;
 
 use crate::{
-    app::remote_content_manager::ProxyManager, proxy::AnyOutboundHandler,
+    app::remote_content_manager::ProxyManager,
+    config::internal::proxy::UnknownNodes, proxy::AnyOutboundHandler,
     session::Session,
 };
 
@@ -23,7 +24,8 @@ pub type StrategyFn = Box<
         + Sync,
 >;
 
-fn get_key(sess: &Session) -> String {
+/// the eTLD+1 of the destination domain, or its IP
+pub fn get_key(sess: &Session) -> String {
     match &sess.destination {
         crate::session::SocksAddr::Ip(addr) => addr.ip().to_string(),
         crate::session::SocksAddr::Domain(host, _) => DEFAULT_PROVIDER
@@ -63,21 +65,59 @@ pub fn strategy_rr() -> StrategyFn {
     })
 }
 
-pub fn strategy_consistent_hashring() -> StrategyFn {
-    let max_retry = 5;
+/// Bounded-load walk from the hashed node: the first node which is alive and
+/// not slower than `factor` times the median smoothed delay is picked.
+/// Nodes never tested are treated as healthy, and the hashed node is kept
+/// if every node is degraded. `nodes` is `(alive, smoothed delay)`.
+fn spill(index: usize, nodes: &[(bool, Option<u16>)], factor: f64) -> usize {
+    let mut delays = nodes
+        .iter()
+        .filter_map(|(alive, delay)| delay.filter(|_| *alive))
+        .collect::<Vec<_>>();
+    if delays.is_empty() && nodes.iter().all(|(alive, _)| *alive) {
+        return index;
+    }
+    delays.sort_unstable();
+    let median = delays.get(delays.len() / 2).copied().unwrap_or(u16::MAX);
+    let limit = median as f64 * factor;
+
+    (0..nodes.len())
+        .map(|i| (index + i) % nodes.len())
+        .find(|i| match nodes[*i] {
+            (false, _) => false,
+            (true, Some(delay)) => delay as f64 <= limit,
+            (true, None) => true,
+        })
+        .unwrap_or(index)
+}
+
+/// with a `spill_factor`, degraded nodes are walked past, see `spill`.
+/// Plain consistent hashing otherwise
+pub fn strategy_consistent_hashring(
+    proxy_manager: ProxyManager,
+    spill_factor: Option<f64>,
+    unknown: UnknownNodes,
+) -> StrategyFn {
     Box::new(move |proxies, sess| {
         let key = murmur3_32(&mut Cursor::new(get_key(sess)), 0).unwrap() as u64;
-        let buckets = proxies.len() as i32;
-        for _ in 0..max_retry {
-            let index = jump_hash(key, buckets);
-            if let Some(proxy) = proxies.get(index as usize) {
-                return Box::pin(futures::future::ok(proxy.clone()));
+        let proxy_manager = proxy_manager.clone();
+
+        Box::pin(async move {
+            if proxies.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "no proxy found",
+                ));
             }
-        }
-        Box::pin(futures::future::err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            "no proxy found",
-        )))
+            let index = jump_hash(key, proxies.len() as i32) as usize;
+            let Some(factor) = spill_factor.filter(|x| *x > 0.0) else {
+                return Ok(proxies[index].clone());
+            };
+
+            let names = proxies.iter().map(|x| x.name()).collect::<Vec<_>>();
+            let nodes = proxy_manager.usable_with_delays(&names, unknown);
+            Ok(proxies[spill(index, &nodes, factor)].clone())
+        })
     })
 }
 
@@ -91,14 +131,17 @@ const CACHE_HIT: usize = 1;
 #[cfg(test)]
 const CACHE_UPDATE: usize = 2;
 
-pub fn strategy_sticky_session(proxy_manager: ProxyManager) -> StrategyFn {
+/// how long a (source IP, destination) pair sticks to a node since last use
+pub const DEFAULT_STICKY_TTL: Duration = Duration::from_secs(60 * 10);
+
+pub fn strategy_sticky_session(
+    proxy_manager: ProxyManager,
+    ttl: Duration,
+    unknown: UnknownNodes,
+) -> StrategyFn {
     let max_retry = 5;
-    // 10 minutes, 1024 entries
     let lru_cache: lru_time_cache::LruCache<u64, usize> =
-        lru_time_cache::LruCache::with_expiry_duration_and_capacity(
-            std::time::Duration::from_secs(60 * 10),
-            1024,
-        );
+        lru_time_cache::LruCache::with_expiry_duration_and_capacity(ttl, 1024);
     let lru_cache = Arc::new(Mutex::new(lru_cache));
     Box::new(move |proxies, sess| {
         let key_str = get_key_src_and_dst(sess);
@@ -130,7 +173,7 @@ pub fn strategy_sticky_session(proxy_manager: ProxyManager) -> StrategyFn {
             let mut index = start_index;
             for _ in 0..max_retry {
                 if let Some(proxy) = proxies.get(index) {
-                    if proxy_manager_clone.alive(proxy.name()).await {
+                    if proxy_manager_clone.usable(proxy.name(), unknown).await {
                         // now it's a valid proxy
                         // check if it's the same as the last one(likely)
                         // update the cache if:
@@ -182,6 +225,27 @@ mod tests {
         session::SocksAddr,
     };
 
+    #[test]
+    fn test_spill() {
+        let healthy = [(true, Some(100)), (true, Some(120)), (true, Some(90))];
+        assert_eq!(spill(1, &healthy, 3.0), 1);
+
+        // the hashed node is degraded, spill to the next one on the ring
+        let slow = [(true, Some(100)), (true, Some(900)), (true, Some(90))];
+        assert_eq!(spill(1, &slow, 3.0), 2);
+        assert_eq!(spill(1, &slow, 10.0), 1);
+
+        let dead = [(true, Some(100)), (true, Some(120)), (false, None)];
+        assert_eq!(spill(2, &dead, 3.0), 0);
+
+        // nothing usable, keep the affinity
+        let all_dead = [(false, None), (false, Some(100))];
+        assert_eq!(spill(1, &all_dead, 3.0), 1);
+
+        let untested = [(true, None), (true, None)];
+        assert_eq!(spill(1, &untested, 3.0), 1);
+    }
+
     macro_rules! assert_cache_state {
         ($state:expr_2021) => {
             assert_eq!(
@@ -212,7 +276,11 @@ mod tests {
         manager.report_alive("b", false).await;
         manager.report_alive("c", false).await;
 
-        let mut strategy_fn = strategy_sticky_session(manager.clone());
+        let mut strategy_fn = strategy_sticky_session(
+            manager.clone(),
+            DEFAULT_STICKY_TTL,
+            UnknownNodes::Tolerate,
+        );
 
         // all proxies is not alive since we have not setup the proxy manager
         let res = strategy_fn(proxies.clone(), &Session::default()).await;
@@ -1,6 +1,6 @@
 mod helpers;
 
-use std::{collections::HashMap, io, sync::Arc};
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
 
 use erased_serde::Serialize;
 use helpers::strategy_sticky_session;
@@ -15,16 +15,19 @@ use crate::{
             ProxyManager, providers::proxy_provider::ThreadSafeProxyProvider,
         },
     },
-    config::internal::proxy::LoadBalanceStrategy,
+    config::internal::proxy::{LoadBalanceStrategy, UnknownNodes},
     proxy::{
         AnyOutboundHandler, ConnectorType, DialWithConnector, HandlerCommonOptions,
-        OutboundHandler, OutboundType,
+        OutboundHandler, OutboundType, PendingBind,
         utils::{RemoteConnector, provider_helper::get_proxies_from_providers},
     },
     session::Session,
 };
 
-use self::helpers::{StrategyFn, strategy_consistent_hashring, strategy_rr};
+pub(crate) use self::helpers::get_key;
+use self::helpers::{
+    DEFAULT_STICKY_TTL, StrategyFn, strategy_consistent_hashring, strategy_rr,
+};
 
 #[derive(Default, Clone)]
 pub struct HandlerOptions {
@@ -32,6 +35,9 @@ pub struct HandlerOptions {
     pub name: String,
     pub udp: bool,
     pub strategy: LoadBalanceStrategy,
+    pub spill_factor: Option<f64>,
+    pub sticky_ttl: Option<Duration>,
+    pub unknown_nodes: UnknownNodes,
 }
 
 struct HandlerInner {
@@ -42,6 +48,7 @@ pub struct Handler {
     opts: HandlerOptions,
 
     providers: Vec<ThreadSafeProxyProvider>,
+    proxy_manager: ProxyManager,
 
     inner: Arc<Mutex<HandlerInner>>,
 }
@@ -61,16 +68,23 @@ impl Handler {
         proxy_manager: ProxyManager,
     ) -> Self {
         let strategy_fn = match opts.strategy {
-            LoadBalanceStrategy::ConsistentHashing => strategy_consistent_hashring(),
+            LoadBalanceStrategy::ConsistentHashing => strategy_consistent_hashring(
+                proxy_manager.clone(),
+                opts.spill_factor,
+                opts.unknown_nodes,
+            ),
             LoadBalanceStrategy::RoundRobin => strategy_rr(),
-            LoadBalanceStrategy::StickySession => {
-                strategy_sticky_session(proxy_manager)
-            }
+            LoadBalanceStrategy::StickySession => strategy_sticky_session(
+                proxy_manager.clone(),
+                opts.sticky_ttl.unwrap_or(DEFAULT_STICKY_TTL),
+                opts.unknown_nodes,
+            ),
         };
 
         Self {
             opts,
             providers,
+            proxy_manager,
             inner: Arc::new(Mutex::new(HandlerInner { strategy_fn })),
         }
     }
@@ -109,7 +123,11 @@ impl OutboundHandler for Handler {
         let proxies = self.get_proxies(false).await;
         let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
         debug!("{} use proxy {}", self.name(), proxy.name());
-        match proxy.connect_stream(sess, resolver).await {
+        let s = proxy.connect_stream(sess, resolver).await;
+        self.proxy_manager
+            .report_connect(proxy.name(), s.as_ref().err())
+            .await;
+        match s {
             Ok(s) => {
                 s.append_to_chain(self.name()).await;
                 Ok(s)
@@ -118,16 +136,41 @@ impl OutboundHandler for Handler {
         }
     }
 
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        let proxies = self.get_proxies(false).await;
+        let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
+        debug!("{} use proxy {} to bind", self.name(), proxy.name());
+        proxy.bind_stream(sess, resolver).await
+    }
+
     /// connect to remote target via UDP
     async fn connect_datagram(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let proxies = self.get_proxies(false).await;
+        let mut proxies = self.get_proxies(false).await;
+        // those known to fail to relay UDP are left out, unless all are
+        let mut udp_alive = Vec::with_capacity(proxies.len());
+        for proxy in proxies.iter() {
+            if self.proxy_manager.udp_alive(proxy.name()).await {
+                udp_alive.push(proxy.clone());
+            }
+        }
+        if !udp_alive.is_empty() {
+            proxies = udp_alive;
+        }
         let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
         debug!("{} use proxy {}", self.name(), proxy.name());
-        proxy.connect_datagram(sess, resolver).await
+        let d = proxy.connect_datagram(sess, resolver).await;
+        self.proxy_manager
+            .report_connect(proxy.name(), d.as_ref().err())
+            .await;
+        d
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -143,9 +186,13 @@ impl OutboundHandler for Handler {
         let proxies = self.get_proxies(false).await;
         let proxy = (self.inner.lock().await.strategy_fn)(proxies, sess).await?;
         debug!("{} use proxy {}", self.name(), proxy.name());
-        proxy
+        let s = proxy
             .connect_stream_with_connector(sess, resolver, connector)
-            .await
+            .await;
+        self.proxy_manager
+            .report_connect(proxy.name(), s.as_ref().err())
+            .await;
+        s
     }
 
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
@@ -2,4 +2,5 @@ pub mod fallback;
 pub mod loadbalance;
 pub mod relay;
 pub mod selector;
+pub mod smart;
 pub mod urltest;
@@ -240,6 +240,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin: Default::default(),
             udp: false,
+            udp_mux: false,
         };
         let port = ss_opts.port;
         let ss_handler: AnyOutboundHandler =
@@ -276,6 +277,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin: Default::default(),
             udp: false,
+            udp_mux: false,
         };
         let port = ss_opts.port;
         let ss_handler: AnyOutboundHandler =
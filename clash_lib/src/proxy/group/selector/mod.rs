@@ -10,11 +10,13 @@ use crate::{
     app::{
         dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
         dns::ThreadSafeDNSResolver,
-        remote_content_manager::providers::proxy_provider::ThreadSafeProxyProvider,
+        remote_content_manager::{
+            ProxyManager, providers::proxy_provider::ThreadSafeProxyProvider,
+        },
     },
     proxy::{
         AnyOutboundHandler, ConnectorType, DialWithConnector, HandlerCommonOptions,
-        OutboundHandler, OutboundType,
+        OutboundHandler, OutboundType, PendingBind,
         utils::{RemoteConnector, provider_helper::get_proxies_from_providers},
     },
     session::Session,
@@ -37,12 +39,14 @@ pub struct HandlerOptions {
     pub common_opts: HandlerCommonOptions,
     pub name: String,
     pub udp: bool,
+    pub interrupt_exist_connections: bool,
 }
 
 #[derive(Clone)]
 pub struct Handler {
     opts: HandlerOptions,
     providers: Vec<ThreadSafeProxyProvider>,
+    proxy_manager: ProxyManager,
     inner: Arc<RwLock<HandlerInner>>,
 }
 
@@ -58,6 +62,7 @@ impl Handler {
     pub async fn new(
         opts: HandlerOptions,
         providers: Vec<ThreadSafeProxyProvider>,
+        proxy_manager: ProxyManager,
         selected: Option<String>,
     ) -> Self {
         let provider = providers.first().unwrap();
@@ -67,6 +72,7 @@ impl Handler {
         Self {
             opts,
             providers,
+            proxy_manager,
             inner: Arc::new(RwLock::new(HandlerInner {
                 current: selected.unwrap_or(current),
             })),
@@ -94,7 +100,11 @@ impl SelectorControl for Handler {
     async fn select(&mut self, name: &str) -> Result<(), Error> {
         let proxies = get_proxies_from_providers(&self.providers, false).await;
         if proxies.iter().any(|x| x.name() == name) {
-            name.clone_into(&mut self.inner.write().await.current);
+            let mut inner = self.inner.write().await;
+            if self.opts.interrupt_exist_connections && inner.current != name {
+                self.proxy_manager.report_switch(self.name(), name);
+            }
+            name.clone_into(&mut inner.current);
             Ok(())
         } else {
             Err(Error::Operation(format!("proxy {} not found", name)))
@@ -142,6 +152,17 @@ impl OutboundHandler for Handler {
         }
     }
 
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        self.selected_proxy(true)
+            .await
+            .bind_stream(sess, resolver)
+            .await
+    }
+
     async fn connect_datagram(
         &self,
         sess: &Session,
@@ -185,6 +206,10 @@ impl OutboundHandler for Handler {
             .await
     }
 
+    async fn current_proxy(&self, _sess: &Session) -> Option<String> {
+        Some(self.selected_proxy(false).await.name().to_owned())
+    }
+
     /// for API
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let all = get_proxies_from_providers(&self.providers, false).await;
@@ -211,9 +236,12 @@ mod tests {
 
     use tokio::sync::{Mutex, RwLock};
 
-    use crate::proxy::{
-        group::selector::ThreadSafeSelectorControl,
-        mocks::{MockDummyOutboundHandler, MockDummyProxyProvider},
+    use crate::{
+        app::{dns::MockClashResolver, remote_content_manager::ProxyManager},
+        proxy::{
+            group::selector::ThreadSafeSelectorControl,
+            mocks::{MockDummyOutboundHandler, MockDummyProxyProvider},
+        },
     };
 
     #[tokio::test]
@@ -231,13 +259,17 @@ mod tests {
             vec![Arc::new(proxy1), Arc::new(proxy2)]
         });
 
+        let proxy_manager = ProxyManager::new(Arc::new(MockClashResolver::new()));
+        let mut switches = proxy_manager.subscribe_switches();
         let handler = super::Handler::new(
             super::HandlerOptions {
                 name: "test".to_owned(),
                 udp: false,
+                interrupt_exist_connections: true,
                 ..Default::default()
             },
             vec![Arc::new(RwLock::new(mock_provider))],
+            proxy_manager,
             None,
         )
         .await;
@@ -266,6 +298,11 @@ mod tests {
             selector_control.lock().await.current().await,
             "provider2".to_owned()
         );
+        let switch = switches.try_recv().unwrap();
+        assert_eq!(
+            (switch.group.as_str(), switch.proxy.as_str()),
+            ("test", "provider2")
+        );
         assert_eq!(
             outbound_handler.selected_proxy(false).await.name(),
             "provider2".to_owned()
@@ -0,0 +1,563 @@
+//! The `smart` group, experimental. A connection goes through the member
+//! that has done best lately, by the time and failures of the dials through
+//! it and its bandwidth checks, and a destination sticks to the member it
+//! was last sent through while that one keeps up. The destinations learned
+//! are kept in the cache file over restarts.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::Mutex,
+    time::Duration,
+};
+
+use erased_serde::Serialize;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
+        dns::ThreadSafeDNSResolver,
+        profile::ThreadSafeCacheFile,
+        remote_content_manager::{
+            ProxyManager, providers::proxy_provider::ThreadSafeProxyProvider,
+        },
+    },
+    common::errors,
+    config::internal::proxy::UnknownNodes,
+    proxy::{
+        AnyOutboundHandler, ConnectorType, DialWithConnector, HandlerCommonOptions,
+        OutboundHandler, OutboundType, PendingBind,
+        group::loadbalance::get_key,
+        utils::{RemoteConnector, provider_helper::get_proxies_from_providers},
+    },
+    session::Session,
+};
+
+/// the weight of a new sample in the moving averages
+const ALPHA: f64 = 0.3;
+/// ms assumed of a member neither dialed nor checked yet
+const UNTESTED_LATENCY: f64 = 1000.0;
+/// the success rate the cost of a failing member is divided by at worst
+const MIN_SUCCESS: f64 = 0.05;
+/// MB/s of bandwidth halving the cost of a member, faster ones gain no more
+const REFERENCE_SPEED: f64 = 10.0;
+/// a destination leaves its member once that costs this much more than the
+/// best one
+const STICKY_TOLERANCE: f64 = 1.5;
+/// destinations remembered per group
+const MAX_ROUTES: usize = 4096;
+/// the failure rate of a member halves this long after its last dial, so
+/// one left for failing is tried again at some point
+const FAILURE_HALF_LIFE: Duration = Duration::from_secs(300);
+
+#[derive(Default, Clone)]
+pub struct HandlerOptions {
+    pub common_opts: HandlerCommonOptions,
+    pub name: String,
+    pub unknown_nodes: UnknownNodes,
+}
+
+/// What the group learned of a member from the dials through it
+#[derive(Clone, Copy, Debug, Default)]
+struct MemberStats {
+    /// ms, of the successful dials
+    latency: Option<f64>,
+    /// of the dials, from 0 to 1, as of `at`
+    failure: f64,
+    /// of the last dial
+    at: Option<Instant>,
+}
+
+impl MemberStats {
+    /// the failure rate, fading since the last dial
+    fn failure(&self, now: Instant) -> f64 {
+        let Some(at) = self.at else {
+            return self.failure;
+        };
+        let idle = now.saturating_duration_since(at).as_secs_f64();
+        self.failure * 0.5f64.powf(idle / FAILURE_HALF_LIFE.as_secs_f64())
+    }
+
+    fn record(&mut self, latency: Option<f64>, ok: bool, now: Instant) {
+        if let Some(ms) = latency {
+            self.latency = Some(match self.latency {
+                Some(avg) => avg * (1.0 - ALPHA) + ms * ALPHA,
+                None => ms,
+            });
+        }
+        self.failure =
+            self.failure(now) * (1.0 - ALPHA) + if ok { 0.0 } else { ALPHA };
+        self.at = Some(now);
+    }
+}
+
+/// Destinations to the member each was last sent through, the least
+/// recently used one evicted past `MAX_ROUTES`
+#[derive(Default)]
+struct Routes {
+    by_key: HashMap<String, (String, u64)>,
+    /// the keys by their last use
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl Routes {
+    fn touch(&mut self, key: &str) -> Option<u64> {
+        let (_, used) = self.by_key.get_mut(key)?;
+        self.order.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+        Some(self.tick)
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.touch(key)?;
+        self.by_key.get(key).map(|(proxy, _)| proxy.clone())
+    }
+
+    /// the key evicted to make room for `key`, if any
+    fn insert(&mut self, key: &str, proxy: &str) -> Option<String> {
+        if self.touch(key).is_some() {
+            if let Some((p, _)) = self.by_key.get_mut(key) {
+                proxy.clone_into(p);
+            }
+            return None;
+        }
+        let evicted = if self.by_key.len() >= MAX_ROUTES {
+            self.order.pop_first().map(|(_, evicted)| {
+                self.by_key.remove(&evicted);
+                evicted
+            })
+        } else {
+            None
+        };
+        self.tick += 1;
+        self.by_key
+            .insert(key.to_owned(), (proxy.to_owned(), self.tick));
+        self.order.insert(self.tick, key.to_owned());
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.by_key.remove(key) {
+            Some((_, used)) => {
+                self.order.remove(&used);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// lower is better. `latency` in ms and `speed` in MB/s
+fn cost(latency: Option<f64>, failure: f64, speed: Option<f64>) -> f64 {
+    let latency = latency.unwrap_or(UNTESTED_LATENCY).max(1.0);
+    let bonus = speed
+        .map(|x| (x / REFERENCE_SPEED).clamp(0.0, 1.0))
+        .unwrap_or_default();
+    latency / (1.0 - failure).max(MIN_SUCCESS) / (1.0 + bonus)
+}
+
+pub struct Handler {
+    opts: HandlerOptions,
+    providers: Vec<ThreadSafeProxyProvider>,
+    proxy_manager: ProxyManager,
+    cache_store: ThreadSafeCacheFile,
+
+    stats: Mutex<HashMap<String, MemberStats>>,
+    routes: Mutex<Routes>,
+}
+
+impl std::fmt::Debug for Handler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Smart")
+            .field("name", &self.opts.name)
+            .finish()
+    }
+}
+
+impl Handler {
+    /// `routes` are the destinations learned before, from the cache file
+    pub fn new(
+        opts: HandlerOptions,
+        providers: Vec<ThreadSafeProxyProvider>,
+        proxy_manager: ProxyManager,
+        cache_store: ThreadSafeCacheFile,
+        routes: HashMap<String, String>,
+    ) -> Self {
+        let mut learned = Routes::default();
+        for (key, proxy) in routes.into_iter().take(MAX_ROUTES) {
+            learned.insert(&key, &proxy);
+        }
+        Self {
+            opts,
+            providers,
+            proxy_manager,
+            cache_store,
+            stats: Default::default(),
+            routes: Mutex::new(learned),
+        }
+    }
+
+    async fn get_proxies(&self, touch: bool) -> Vec<AnyOutboundHandler> {
+        get_proxies_from_providers(&self.providers, touch).await
+    }
+
+    async fn cost(&self, name: &str) -> f64 {
+        let stats = self
+            .stats
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or_default();
+        let latency = match stats.latency {
+            Some(ms) => Some(ms),
+            None => self.proxy_manager.smoothed_delay(name).await.map(f64::from),
+        };
+        let speed = self.proxy_manager.bandwidth(name).await.map(|x| x.speed());
+        cost(latency, stats.failure(Instant::now()), speed)
+    }
+
+    /// the usable members with their costs, all the members if none is
+    async fn candidates(&self, touch: bool) -> Vec<(AnyOutboundHandler, f64)> {
+        let proxies = self.get_proxies(touch).await;
+        let mut usable = vec![];
+        for proxy in proxies.iter() {
+            if self
+                .proxy_manager
+                .usable(proxy.name(), self.opts.unknown_nodes)
+                .await
+            {
+                usable.push(proxy.clone());
+            }
+        }
+        if usable.is_empty() {
+            usable = proxies;
+        }
+
+        let mut candidates = Vec::with_capacity(usable.len());
+        for proxy in usable {
+            let cost = self.cost(proxy.name()).await;
+            candidates.push((proxy, cost));
+        }
+        candidates
+    }
+
+    async fn pick(&self, sess: &Session) -> io::Result<AnyOutboundHandler> {
+        let candidates = self.candidates(false).await;
+        let Some((best, best_cost)) = candidates
+            .iter()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .cloned()
+        else {
+            return Err(io::Error::other(format!(
+                "no proxy found for {}",
+                self.name()
+            )));
+        };
+
+        let key = get_key(sess);
+        if key.is_empty() {
+            return Ok(best);
+        }
+        let sticky = self.routes.lock().unwrap().get(&key);
+        if let Some(sticky) = sticky
+            && let Some((proxy, cost)) =
+                candidates.iter().find(|(x, _)| x.name() == sticky)
+            && *cost <= best_cost * STICKY_TOLERANCE
+        {
+            return Ok(proxy.clone());
+        }
+
+        self.learn(&key, best.name()).await;
+        Ok(best)
+    }
+
+    async fn learn(&self, key: &str, proxy: &str) {
+        let evicted = self.routes.lock().unwrap().insert(key, proxy);
+        if let Some(evicted) = evicted {
+            self.cache_store
+                .delete_smart_route(self.name(), &evicted)
+                .await;
+        }
+        self.cache_store
+            .set_smart_route(self.name(), key, proxy)
+            .await;
+    }
+
+    /// records a dial through `proxy` started at `start`, failed with `err`
+    /// if any, which unsticks the destination. Only the failures of the
+    /// proxy itself count against it
+    async fn report(
+        &self,
+        sess: &Session,
+        proxy: &AnyOutboundHandler,
+        start: Instant,
+        err: Option<&io::Error>,
+    ) {
+        self.proxy_manager.report_connect(proxy.name(), err).await;
+        let latency = err
+            .is_none()
+            .then(|| start.elapsed().as_secs_f64() * 1000.0);
+        let failed = err.is_some_and(errors::is_proxy_fault);
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(proxy.name().to_owned())
+            .or_default()
+            .record(latency, !failed, Instant::now());
+        if err.is_none() {
+            return;
+        }
+        let key = get_key(sess);
+        if self.routes.lock().unwrap().remove(&key) {
+            self.cache_store.delete_smart_route(self.name(), &key).await;
+        }
+    }
+}
+
+impl DialWithConnector for Handler {}
+
+#[async_trait::async_trait]
+impl OutboundHandler for Handler {
+    /// The name of the outbound handler
+    fn name(&self) -> &str {
+        &self.opts.name
+    }
+
+    /// The protocol of the outbound handler
+    /// only contains Type information, do not rely on the underlying value
+    fn proto(&self) -> OutboundType {
+        OutboundType::Smart
+    }
+
+    /// whether the outbound handler support UDP
+    async fn support_udp(&self) -> bool {
+        for proxy in self.get_proxies(false).await {
+            if proxy.support_udp().await {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// connect to remote target via TCP
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        let proxy = self.pick(sess).await?;
+        debug!("{} use proxy {}", self.name(), proxy.name());
+        let start = Instant::now();
+        let result = proxy.connect_stream(sess, resolver).await;
+        self.report(sess, &proxy, start, result.as_ref().err())
+            .await;
+        let s = result?;
+        s.append_to_chain(self.name()).await;
+        Ok(s)
+    }
+
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        let proxy = self.pick(sess).await?;
+        debug!("{} use proxy {} to bind", self.name(), proxy.name());
+        proxy.bind_stream(sess, resolver).await
+    }
+
+    /// connect to remote target via UDP
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let proxy = self.pick(sess).await?;
+        debug!("{} use proxy {}", self.name(), proxy.name());
+        let start = Instant::now();
+        let result = proxy.connect_datagram(sess, resolver).await;
+        self.report(sess, &proxy, start, result.as_ref().err())
+            .await;
+        let d = result?;
+        d.append_to_chain(self.name()).await;
+        Ok(d)
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        ConnectorType::Tcp
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        let proxy = self.pick(sess).await?;
+        debug!("{} use proxy {}", self.name(), proxy.name());
+        let start = Instant::now();
+        let result = proxy
+            .connect_stream_with_connector(sess, resolver, connector)
+            .await;
+        self.report(sess, &proxy, start, result.as_ref().err())
+            .await;
+        result
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let all = get_proxies_from_providers(&self.providers, false).await;
+        let best = self
+            .candidates(false)
+            .await
+            .into_iter()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(x, _)| x.name().to_owned());
+
+        let mut m = HashMap::new();
+        m.insert("type".to_string(), Box::new(self.proto()) as _);
+        if let Some(best) = best {
+            m.insert("now".to_string(), Box::new(best) as _);
+        }
+        m.insert(
+            "all".to_string(),
+            Box::new(all.iter().map(|x| x.name().to_owned()).collect::<Vec<_>>())
+                as _,
+        );
+        m
+    }
+
+    fn icon(&self) -> Option<String> {
+        self.opts.common_opts.icon.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        sync::Arc,
+    };
+
+    use tokio::sync::RwLock;
+
+    use tokio::time::Instant;
+
+    use super::{
+        FAILURE_HALF_LIFE, Handler, HandlerOptions, MAX_ROUTES, MemberStats, Routes,
+        cost,
+    };
+    use crate::{
+        app::{
+            profile::{MemoryStore, ThreadSafeCacheFile},
+            remote_content_manager::ProxyManager,
+        },
+        proxy::{
+            OutboundHandler,
+            mocks::MockDummyProxyProvider,
+            utils::test_utils::noop::{NoopOutboundHandler, NoopResolver},
+        },
+        session::{Session, SocksAddr},
+    };
+
+    #[test]
+    fn test_smart_cost() {
+        let fast = cost(Some(100.0), 0.0, None);
+        assert!(fast < cost(Some(200.0), 0.0, None));
+        assert!(fast < cost(None, 0.0, None));
+        // failures outweigh the delay
+        assert!(cost(Some(300.0), 0.0, None) < cost(Some(100.0), 0.8, None));
+        assert!(cost(Some(100.0), 0.0, Some(20.0)) < fast);
+
+        let now = Instant::now();
+        let mut stats = MemberStats::default();
+        stats.record(Some(100.0), true, now);
+        stats.record(None, false, now);
+        assert_eq!(stats.latency, Some(100.0));
+        assert!(stats.failure(now) > 0.0 && stats.failure(now) < 1.0);
+
+        // a member no longer dialed for failing gets its chance back
+        let later = now + FAILURE_HALF_LIFE;
+        assert!((stats.failure(later) - stats.failure(now) / 2.0).abs() < 1e-9);
+        assert!(stats.failure(later + FAILURE_HALF_LIFE * 20) < 1e-6);
+    }
+
+    #[test]
+    fn test_smart_routes_lru() {
+        let mut routes = Routes::default();
+        for i in 0..MAX_ROUTES {
+            assert_eq!(routes.insert(&i.to_string(), "a"), None);
+        }
+        // 0 is used again, 1 is the least recently used now
+        assert_eq!(routes.get("0").as_deref(), Some("a"));
+        assert_eq!(routes.insert("new", "b").as_deref(), Some("1"));
+        assert_eq!(routes.get("1"), None);
+        assert_eq!(routes.get("new").as_deref(), Some("b"));
+        assert_eq!(routes.insert("0", "b"), None);
+        assert_eq!(routes.get("0").as_deref(), Some("b"));
+        assert!(routes.remove("0"));
+        assert!(!routes.remove("0"));
+        assert_eq!(routes.by_key.len(), routes.order.len());
+    }
+
+    #[tokio::test]
+    async fn test_smart_routes() {
+        let resolver = Arc::new(NoopResolver);
+        let manager = ProxyManager::new(resolver.clone());
+        let cache = ThreadSafeCacheFile::with_store(
+            Box::new(MemoryStore::default()),
+            false,
+            false,
+        );
+        let mut provider = MockDummyProxyProvider::new();
+        provider.expect_proxies().returning(|| {
+            ["a", "b"]
+                .map(|name| {
+                    Arc::new(NoopOutboundHandler {
+                        name: name.to_owned(),
+                    }) as _
+                })
+                .to_vec()
+        });
+        let providers = vec![Arc::new(RwLock::new(provider)) as _];
+        let routes = HashMap::from([("1.1.1.1".to_owned(), "b".to_owned())]);
+        let handler = Handler::new(
+            HandlerOptions {
+                name: "smart".to_owned(),
+                ..Default::default()
+            },
+            providers,
+            manager,
+            cache.clone(),
+            routes,
+        );
+
+        let sess = Session {
+            destination: SocksAddr::Ip(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(1, 1, 1, 1),
+                443,
+            ))),
+            ..Default::default()
+        };
+        assert_eq!(handler.pick(&sess).await.unwrap().name(), "b");
+
+        // the noop proxies fail, `b` is unstuck and costs more than `a`
+        handler.connect_stream(&sess, resolver).await.unwrap_err();
+        assert!(cache.get_smart_routes("smart").await.is_empty());
+        assert_eq!(handler.pick(&sess).await.unwrap().name(), "a");
+        assert_eq!(
+            cache
+                .get_smart_routes("smart")
+                .await
+                .get("1.1.1.1")
+                .cloned(),
+            Some("a".to_owned())
+        );
+    }
+}
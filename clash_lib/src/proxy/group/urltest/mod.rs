@@ -13,12 +13,13 @@ use crate::{
             ProxyManager, providers::proxy_provider::ThreadSafeProxyProvider,
         },
     },
+    config::internal::proxy::UnknownNodes,
     proxy::{
         AnyOutboundHandler, ConnectorType, DialWithConnector, HandlerCommonOptions,
-        OutboundHandler, OutboundType,
+        OutboundHandler, OutboundType, PendingBind,
         utils::{RemoteConnector, provider_helper::get_proxies_from_providers},
     },
-    session::Session,
+    session::{Network, Session},
 };
 
 #[derive(Default)]
@@ -26,6 +27,8 @@ pub struct HandlerOptions {
     pub common_opts: HandlerCommonOptions,
     pub name: String,
     pub udp: bool,
+    pub interrupt_exist_connections: bool,
+    pub unknown_nodes: UnknownNodes,
 }
 
 struct HandlerInner {
@@ -35,6 +38,8 @@ struct HandlerInner {
 pub struct Handler {
     opts: HandlerOptions,
     tolerance: u16,
+    /// by the UDP round trip time rather than the delay of the url test
+    rank_by_udp: bool,
 
     providers: Vec<ThreadSafeProxyProvider>,
     proxy_manager: ProxyManager,
@@ -54,12 +59,14 @@ impl Handler {
     pub fn new(
         opts: HandlerOptions,
         tolerance: u16,
+        rank_by_udp: bool,
         providers: Vec<ThreadSafeProxyProvider>,
         proxy_manager: ProxyManager,
     ) -> Self {
         Self {
             opts,
             tolerance,
+            rank_by_udp,
             providers,
             proxy_manager,
             inner: Arc::new(Mutex::new(HandlerInner {
@@ -72,16 +79,25 @@ impl Handler {
         get_proxies_from_providers(&self.providers, touch).await
     }
 
+    async fn delay(&self, name: &str) -> u16 {
+        if self.rank_by_udp {
+            self.proxy_manager.last_udp_delay(name).await
+        } else {
+            self.proxy_manager.last_delay(name).await
+        }
+    }
+
     async fn fastest(&self, touch: bool) -> AnyOutboundHandler {
         let proxy_manager = self.proxy_manager.clone();
         let mut inner = self.inner.lock().await;
+        let previous = inner.fastest_proxy.as_ref().map(|p| p.name().to_owned());
 
         let proxies = self.get_proxies(touch).await;
         let mut fastest = proxies
             .first()
             .unwrap_or_else(|| panic!("no proxy found for {}", self.name()));
 
-        let mut fastest_delay = proxy_manager.last_delay(fastest.name()).await;
+        let mut fastest_delay = self.delay(fastest.name()).await;
         let mut fast_not_exist = true;
 
         for proxy in proxies.iter().skip(1) {
@@ -91,11 +107,14 @@ impl Handler {
                 fast_not_exist = false;
             }
 
-            if !proxy_manager.alive(proxy.name()).await {
+            if !proxy_manager
+                .usable(proxy.name(), self.opts.unknown_nodes)
+                .await
+            {
                 continue;
             }
 
-            let delay = proxy_manager.last_delay(proxy.name()).await;
+            let delay = self.delay(proxy.name()).await;
             if delay < fastest_delay {
                 fastest = proxy;
                 fastest_delay = delay;
@@ -103,9 +122,11 @@ impl Handler {
 
             if inner.fastest_proxy.is_some()
                 || fast_not_exist
-                || proxy_manager.alive(fastest.name()).await
                 || proxy_manager
-                    .last_delay(inner.fastest_proxy.as_ref().unwrap().name())
+                    .usable(fastest.name(), self.opts.unknown_nodes)
+                    .await
+                || self
+                    .delay(inner.fastest_proxy.as_ref().unwrap().name())
                     .await
                     > fastest_delay + self.tolerance
             {
@@ -120,11 +141,42 @@ impl Handler {
             fastest_delay
         );
 
-        inner
+        let selected = inner
             .fastest_proxy
             .as_ref()
             .unwrap_or(proxies.first().unwrap())
-            .clone()
+            .clone();
+        if self.opts.interrupt_exist_connections
+            && previous.is_some_and(|p| p != selected.name())
+        {
+            proxy_manager.report_switch(self.name(), selected.name());
+        }
+        selected
+    }
+
+    /// the fastest for datagrams: the fastest unless it's known to fail to
+    /// relay UDP, else the fastest of the usable ones that don't
+    async fn fastest_udp(&self, touch: bool) -> AnyOutboundHandler {
+        let fastest = self.fastest(touch).await;
+        if self.proxy_manager.udp_alive(fastest.name()).await {
+            return fastest;
+        }
+        let mut picked: Option<(AnyOutboundHandler, u16)> = None;
+        for proxy in self.get_proxies(false).await {
+            if !self
+                .proxy_manager
+                .usable(proxy.name(), self.opts.unknown_nodes)
+                .await
+                || !self.proxy_manager.udp_alive(proxy.name()).await
+            {
+                continue;
+            }
+            let delay = self.delay(proxy.name()).await;
+            if picked.as_ref().is_none_or(|(_, d)| delay < *d) {
+                picked = Some((proxy, delay));
+            }
+        }
+        picked.map_or(fastest, |(proxy, _)| proxy)
     }
 }
 
@@ -153,26 +205,36 @@ impl OutboundHandler for Handler {
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedStream> {
-        let s = self
-            .fastest(false)
-            .await
-            .connect_stream(sess, resolver)
-            .await?;
+        let proxy = self.fastest(false).await;
+        let s = proxy.connect_stream(sess, resolver).await;
+        self.proxy_manager
+            .report_connect(proxy.name(), s.as_ref().err())
+            .await;
+        let s = s?;
         s.append_to_chain(self.name()).await;
         Ok(s)
     }
 
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        self.fastest(false).await.bind_stream(sess, resolver).await
+    }
+
     /// connect to remote target via UDP
     async fn connect_datagram(
         &self,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> io::Result<BoxedChainedDatagram> {
-        let d = self
-            .fastest(false)
-            .await
-            .connect_datagram(sess, resolver)
-            .await?;
+        let proxy = self.fastest_udp(false).await;
+        let d = proxy.connect_datagram(sess, resolver).await;
+        self.proxy_manager
+            .report_connect(proxy.name(), d.as_ref().err())
+            .await;
+        let d = d?;
         d.append_to_chain(self.name()).await;
         Ok(d)
     }
@@ -203,12 +265,20 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedDatagram> {
-        self.fastest(true)
+        self.fastest_udp(true)
             .await
             .connect_datagram_with_connector(sess, resolver, connector)
             .await
     }
 
+    async fn current_proxy(&self, sess: &Session) -> Option<String> {
+        let proxy = match sess.network {
+            Network::Udp => self.fastest_udp(false).await,
+            Network::Tcp => self.fastest(false).await,
+        };
+        Some(proxy.name().to_owned())
+    }
+
     async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
         let all = get_proxies_from_providers(&self.providers, false).await;
 
@@ -21,11 +21,26 @@ use super::proxy::maybe_socks_addr;
 pub struct Connector {
     src: SocketAddr,
     dispatcher: Arc<Dispatcher>,
+    typ: Type,
+    outbound: Option<String>,
 }
 
 impl Connector {
     pub fn new(src: SocketAddr, dispatcher: Arc<Dispatcher>) -> Self {
-        Self { src, dispatcher }
+        Self {
+            src,
+            dispatcher,
+            typ: Type::Http,
+            outbound: None,
+        }
+    }
+
+    /// the sessions are of `typ`, and sent to `outbound` instead of by the
+    /// rules if set
+    pub fn with_outbound(mut self, typ: Type, outbound: Option<String>) -> Self {
+        self.typ = typ;
+        self.outbound = outbound;
+        self
     }
 }
 
@@ -45,6 +60,8 @@ impl tower::Service<Uri> for Connector {
     fn call(&mut self, url: Uri) -> Self::Future {
         let src = self.src;
         let dispatcher = self.dispatcher.clone();
+        let typ = self.typ;
+        let outbound = self.outbound.clone();
 
         let destination = maybe_socks_addr(&url);
 
@@ -53,10 +70,11 @@ impl tower::Service<Uri> for Connector {
 
             let sess = Session {
                 network: Network::Tcp,
-                typ: Type::Http,
+                typ,
                 source: src,
                 destination: destination
                     .ok_or(ProxyError::InvalidUrl(url.to_string()))?,
+                outbound,
                 ..Default::default()
             };
 
@@ -1,17 +1,21 @@
 mod auth;
 mod connector;
 mod proxy;
+mod reverse;
+mod upgrade;
 
 use crate::{
     Dispatcher,
-    common::auth::ThreadSafeAuthenticator,
+    app::handover,
+    common::{auth::ThreadSafeAuthenticator, tls},
     proxy::{inbound::InboundHandlerTrait, utils::apply_tcp_options},
 };
 
 pub use proxy::handle as handle_http;
+pub use reverse::ReverseProxyInbound;
 
 use std::{net::SocketAddr, sync::Arc};
-use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use tracing::warn;
 
 #[derive(Clone)]
@@ -20,6 +24,7 @@ pub struct HttpInbound {
     allow_lan: bool,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    tls: Option<TlsAcceptor>,
 }
 
 impl Drop for HttpInbound {
@@ -34,12 +39,14 @@ impl HttpInbound {
         allow_lan: bool,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        tls: Option<TlsAcceptor>,
     ) -> Self {
         Self {
             addr,
             allow_lan,
             dispatcher,
             authenticator,
+            tls,
         }
     }
 }
@@ -54,7 +61,7 @@ impl InboundHandlerTrait for HttpInbound {
     }
 
     async fn listen_tcp(&self) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener = handover::bind_tcp(self.addr).await?;
 
         loop {
             let (socket, _) = listener.accept().await?;
@@ -69,9 +76,29 @@ impl InboundHandlerTrait for HttpInbound {
 
             let dispatcher = self.dispatcher.clone();
             let author = self.authenticator.clone();
+            let tls = self.tls.clone();
 
             tokio::spawn(async move {
-                proxy::handle(Box::new(socket), src_addr, dispatcher, author).await
+                match tls {
+                    Some(acceptor) => match tls::accept(&acceptor, socket).await {
+                        Ok(stream) => {
+                            proxy::handle(
+                                Box::new(stream),
+                                src_addr,
+                                dispatcher,
+                                author,
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            warn!("TLS handshake with {} failed: {}", src_addr, e)
+                        }
+                    },
+                    None => {
+                        proxy::handle(Box::new(socket), src_addr, dispatcher, author)
+                            .await
+                    }
+                }
             });
         }
     }
@@ -22,7 +22,7 @@ use crate::{
     session::{Network, Session, SocksAddr, Type},
 };
 
-use super::{auth::authenticate_req, connector::Connector};
+use super::{auth::authenticate_req, connector::Connector, upgrade};
 
 pub fn maybe_socks_addr(r: &Uri) -> Option<SocksAddr> {
     let port = r.port_u16().unwrap_or(
@@ -59,7 +59,6 @@ async fn proxy(
         .http1_preserve_header_case(true)
         .build(Connector::new(src, dispatcher.clone()));
 
-    // TODO: handle other upgrades: https://github.com/hyperium/hyper/blob/master/examples/upgrades.rs
     if req.method() == Method::CONNECT {
         match maybe_socks_addr(req.uri()) {
             Some(addr) => {
@@ -98,8 +97,7 @@ async fn proxy(
                 .unwrap()),
         }
     } else {
-        match client
-            .request(req)
+        match upgrade::request(&client, req)
             .map_err(|x| ProxyError::General(x.to_string()))
             .await
         {
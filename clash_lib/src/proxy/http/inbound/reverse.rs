@@ -0,0 +1,327 @@
+//! A reverse proxy, the requests it accepts are forwarded to a fixed upstream
+//! through the proxies, so that a remote service is reached at a local port.
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Request, Response, Uri, body::Incoming, server::conn::http1, service::service_fn,
+};
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+use crate::{
+    Dispatcher,
+    app::handover,
+    common::{
+        errors::map_io_error, http::HyperResponseBody, tls, tls::GLOBAL_ROOT_STORE,
+    },
+    proxy::{AnyStream, inbound::InboundHandlerTrait, utils::apply_tcp_options},
+    session::Type,
+};
+
+use super::{
+    connector::Connector,
+    upgrade::{self, is_upgrade},
+};
+
+type UpstreamClient = Client<hyper_rustls::HttpsConnector<Connector>, Incoming>;
+
+/// the headers of RFC 9110 §7.6.1 that only concern the connection they come
+/// on, besides those listed in `Connection`
+static HOP_BY_HOP: [HeaderName; 9] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+#[derive(Clone)]
+pub struct ReverseProxyInbound {
+    addr: SocketAddr,
+    allow_lan: bool,
+    upstream: Uri,
+    /// shared by all the connections, the upstream connections are pooled
+    client: UpstreamClient,
+    tls: Option<TlsAcceptor>,
+}
+
+impl Drop for ReverseProxyInbound {
+    fn drop(&mut self) {
+        warn!("reverse proxy inbound listener on {} stopped", self.addr);
+    }
+}
+
+impl ReverseProxyInbound {
+    pub fn new(
+        addr: SocketAddr,
+        allow_lan: bool,
+        dispatcher: Arc<Dispatcher>,
+        upstream: &str,
+        proxy: Option<String>,
+        tls: Option<TlsAcceptor>,
+    ) -> anyhow::Result<Self> {
+        let upstream: Uri = upstream.parse()?;
+        ensure!(
+            matches!(upstream.scheme_str(), Some("http" | "https"))
+                && upstream.authority().is_some(),
+            "invalid upstream {}: not an http(s) URL",
+            upstream
+        );
+        let client = Self::client(addr, dispatcher, proxy);
+        Ok(Self {
+            addr,
+            allow_lan,
+            upstream,
+            client,
+            tls,
+        })
+    }
+
+    /// A pooled upstream connection serves requests of several clients, so
+    /// its session comes from the listener. The client is in
+    /// `X-Forwarded-For`.
+    fn client(
+        addr: SocketAddr,
+        dispatcher: Arc<Dispatcher>,
+        proxy: Option<String>,
+    ) -> UpstreamClient {
+        let tls_config = rustls::ClientConfig::builder()
+            .with_root_certificates(GLOBAL_ROOT_STORE.clone())
+            .with_no_client_auth();
+        let connector = Connector::new(addr, dispatcher)
+            .with_outbound(Type::ReverseProxy, proxy);
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(connector);
+        Client::builder(TokioExecutor::new()).build(connector)
+    }
+
+    async fn serve(&self, stream: AnyStream, src: SocketAddr) {
+        let client = self.client.clone();
+        let upstream = self.upstream.clone();
+        let proto = if self.tls.is_some() { "https" } else { "http" };
+
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            let client = client.clone();
+            let upstream = upstream.clone();
+            async move {
+                if let Err(e) = rewrite(&mut req, &upstream, src, proto) {
+                    return Ok::<_, Infallible>(status(
+                        StatusCode::BAD_REQUEST,
+                        e.to_string(),
+                    ));
+                }
+                Ok(match upgrade::request(&client, req).await {
+                    Ok(mut res) => {
+                        strip_hop_by_hop(res.headers_mut());
+                        res.map(|b| b.map_err(map_io_error).boxed())
+                    }
+                    Err(e) => {
+                        warn!("reverse proxy to {} failed: {}", upstream, e);
+                        status(StatusCode::BAD_GATEWAY, e.to_string())
+                    }
+                })
+            }
+        });
+
+        if let Err(e) = http1::Builder::new()
+            .preserve_header_case(true)
+            .title_case_headers(true)
+            .serve_connection(stream, service)
+            .with_upgrades()
+            .await
+        {
+            warn!("Error while serving reverse proxy connection: {}", e);
+        }
+    }
+}
+
+impl InboundHandlerTrait for ReverseProxyInbound {
+    fn handle_tcp(&self) -> bool {
+        true
+    }
+
+    fn handle_udp(&self) -> bool {
+        false
+    }
+
+    async fn listen_tcp(&self) -> anyhow::Result<()> {
+        let listener = handover::bind_tcp(self.addr).await?;
+
+        loop {
+            let (socket, src_addr) = listener.accept().await?;
+
+            if !self.allow_lan && src_addr.ip() != socket.local_addr()?.ip() {
+                warn!("Connection from {} is not allowed", src_addr);
+                continue;
+            }
+
+            let socket = apply_tcp_options(socket)?;
+            let this = self.clone();
+
+            tokio::spawn(async move {
+                let stream: AnyStream = match &this.tls {
+                    Some(acceptor) => match tls::accept(acceptor, socket).await {
+                        Ok(stream) => Box::new(stream),
+                        Err(e) => {
+                            warn!("TLS handshake with {} failed: {}", src_addr, e);
+                            return;
+                        }
+                    },
+                    None => Box::new(socket),
+                };
+                this.serve(stream, src_addr).await;
+            });
+        }
+    }
+
+    async fn listen_udp(&self) -> anyhow::Result<()> {
+        Err(anyhow!("unsupported"))
+    }
+}
+
+/// Points `req` to `upstream`, whose path prefixes that of the request. The
+/// original host and the client go to the `X-Forwarded-*` headers, the
+/// `Host` header is then set by the client from the new URI
+fn rewrite<B>(
+    req: &mut Request<B>,
+    upstream: &Uri,
+    src: SocketAddr,
+    proto: &'static str,
+) -> Result<(), http::Error> {
+    let path = req.uri().path_and_query().map_or("/", |x| x.as_str());
+    let uri = Uri::builder()
+        .scheme(upstream.scheme_str().unwrap_or("http"))
+        .authority(upstream.authority().map_or("", |x| x.as_str()))
+        .path_and_query(format!("{}{}", upstream.path().trim_end_matches('/'), path))
+        .build()?;
+
+    let headers = req.headers_mut();
+    strip_hop_by_hop(headers);
+    if let Some(host) = headers.remove(header::HOST) {
+        headers.insert("x-forwarded-host", host);
+    }
+    let forwarded_for =
+        match headers.get("x-forwarded-for").and_then(|x| x.to_str().ok()) {
+            Some(prev) => format!("{}, {}", prev, src.ip()),
+            None => src.ip().to_string(),
+        };
+    headers.insert("x-forwarded-for", HeaderValue::from_str(&forwarded_for)?);
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+
+    *req.uri_mut() = uri;
+    Ok(())
+}
+
+/// Removes the hop-by-hop headers, except the upgrade of a request or
+/// response that switches protocols, which is passed on
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let upgrade = is_upgrade(headers)
+        .then(|| headers.get(header::UPGRADE).cloned())
+        .flatten();
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .filter_map(|x| HeaderName::from_bytes(x.trim().as_bytes()).ok())
+        .collect();
+    for name in listed.iter().chain(HOP_BY_HOP.iter()) {
+        headers.remove(name);
+    }
+    if let Some(upgrade) = upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, upgrade);
+    }
+}
+
+fn status(code: StatusCode, msg: String) -> Response<HyperResponseBody> {
+    Response::builder()
+        .status(code)
+        .body(Full::new(msg.into()).map_err(map_io_error).boxed())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue, Request, Uri, header};
+
+    use super::{rewrite, strip_hop_by_hop};
+
+    #[test]
+    fn test_rewrite() {
+        let upstream: Uri =
+            "https://grafana.corp.example.com/grafana/".parse().unwrap();
+        let mut req = Request::builder()
+            .uri("/d/home?orgId=1")
+            .header(header::HOST, "localhost:3000")
+            .header("x-forwarded-for", "10.0.0.1")
+            .body(())
+            .unwrap();
+
+        rewrite(
+            &mut req,
+            &upstream,
+            "127.0.0.1:50000".parse().unwrap(),
+            "http",
+        )
+        .unwrap();
+        assert_eq!(
+            req.uri(),
+            "https://grafana.corp.example.com/grafana/d/home?orgId=1"
+        );
+        assert!(req.headers().get(header::HOST).is_none());
+        assert_eq!(req.headers()["x-forwarded-host"], "localhost:3000");
+        assert_eq!(req.headers()["x-forwarded-for"], "10.0.0.1, 127.0.0.1");
+        assert_eq!(req.headers()["x-forwarded-proto"], "http");
+
+        let upstream: Uri = "http://10.0.0.8:8080".parse().unwrap();
+        let mut req = Request::builder().uri("/").body(()).unwrap();
+        rewrite(
+            &mut req,
+            &upstream,
+            "127.0.0.1:50000".parse().unwrap(),
+            "https",
+        )
+        .unwrap();
+        assert_eq!(req.uri(), "http://10.0.0.8:8080/");
+        assert_eq!(req.headers()["x-forwarded-for"], "127.0.0.1");
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, X-Trace"),
+        );
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-trace", HeaderValue::from_static("1"));
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+        headers.insert(header::PROXY_AUTHORIZATION, HeaderValue::from_static("x"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[header::ACCEPT], "*/*");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, Upgrade"),
+        );
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers[header::CONNECTION], "upgrade");
+        assert_eq!(headers[header::UPGRADE], "websocket");
+    }
+}
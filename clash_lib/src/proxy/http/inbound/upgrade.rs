@@ -0,0 +1,148 @@
+//! Upgrades of the forwarded requests, e.g. WebSocket and h2c. Once the
+//! upstream switches protocols, the connections on both sides are spliced
+//! into a raw tunnel.
+
+use http::{HeaderMap, StatusCode, header};
+use hyper::{Request, Response, body::Incoming};
+use hyper_util::client::legacy::{Client, Error, connect::Connect};
+use tracing::{debug, warn};
+
+use crate::common::http::hyper::TokioIo;
+
+/// Whether the request asks to switch protocols, i.e. has an `Upgrade`
+/// header and `upgrade` in its `Connection` header
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .any(|x| x.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Forwards `req` with `client`, and tunnels the connection through if the
+/// upstream answers its upgrade with a 101
+pub async fn request<C>(
+    client: &Client<C, Incoming>,
+    mut req: Request<Incoming>,
+) -> Result<Response<Incoming>, Error>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    if !is_upgrade(req.headers()) {
+        return client.request(req).await;
+    }
+
+    let downstream = hyper::upgrade::on(&mut req);
+    let mut res = client.request(req).await?;
+    if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(res);
+    }
+
+    let upstream = hyper::upgrade::on(&mut res);
+    tokio::spawn(async move {
+        match tokio::try_join!(downstream, upstream) {
+            Ok((downstream, upstream)) => {
+                let mut downstream = TokioIo::new(downstream);
+                let mut upstream = TokioIo::new(upstream);
+                if let Err(e) =
+                    tokio::io::copy_bidirectional(&mut downstream, &mut upstream)
+                        .await
+                {
+                    debug!("upgraded connection closed: {}", e);
+                }
+            }
+            Err(e) => warn!("failed to upgrade the connection: {}", e),
+        }
+    });
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue, header};
+    use hyper::{server::conn::http1, service::service_fn};
+    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{is_upgrade, request};
+    use crate::common::http::hyper::TokioIo;
+
+    /// reads up to the end of an HTTP head
+    async fn read_head<S: AsyncRead + Unpin>(s: &mut S) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(s.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn test_is_upgrade() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        assert!(!is_upgrade(&headers));
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, Upgrade"),
+        );
+        assert!(is_upgrade(&headers));
+        headers.remove(header::UPGRADE);
+        assert!(!is_upgrade(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_tunnel() {
+        // switches to echoing whatever comes after the request
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = upstream.accept().await.unwrap();
+            let head = read_head(&mut s).await;
+            assert!(head.to_ascii_lowercase().contains("upgrade: websocket"));
+            s.write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\r\n",
+            )
+            .await
+            .unwrap();
+            let (mut r, mut w) = s.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+
+        let (mut client_io, server_io) = tokio::io::duplex(4096);
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let client = client.clone();
+                async move { request(&client, req).await }
+            });
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(server_io), service)
+                .with_upgrades()
+                .await;
+        });
+
+        client_io
+            .write_all(
+                format!(
+                    "GET http://{addr}/chat HTTP/1.1\r\nHost: \
+                     {addr}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let head = read_head(&mut client_io).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+        client_io.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client_io.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
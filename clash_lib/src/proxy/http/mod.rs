@@ -1,3 +1,3 @@
 mod inbound;
 
-pub use inbound::{HttpInbound, handle_http};
+pub use inbound::{HttpInbound, ReverseProxyInbound, handle_http};
@@ -39,15 +39,16 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::tls::DefaultTlsVerifier,
+    common::{errors::NetError, tls::DefaultTlsVerifier},
     session::{Session, SocksAddr},
 };
 use tracing::{debug, trace, warn};
 
 use super::{
     ConnectorType, DialWithConnector, OutboundHandler, OutboundType,
-    converters::hysteria2::PortGenerator, datagram::UdpPacket,
-    utils::new_udp_socket,
+    converters::hysteria2::PortGenerator,
+    datagram::UdpPacket,
+    utils::{new_udp_socket, quic_mtu_discovery},
 };
 
 use self::{
@@ -150,6 +151,8 @@ impl Handler {
         if opts.disable_mtu_discovery {
             tracing::debug!("disable mtu discovery");
             transport.mtu_discovery_config(None);
+        } else {
+            transport.mtu_discovery_config(Some(quic_mtu_discovery()));
         }
         // TODO
         // transport.congestion_controller_factory(DynCongestion);
@@ -343,7 +346,7 @@ impl Handler {
                     .new_authed_connection_inner(sess, resolver)
                     .await
                     .map_err(|e| {
-                        std::io::Error::other(format!(
+                        NetError::handshake(format!(
                             "connect to {} failed: {}",
                             self.opts.addr, e
                         ))
@@ -506,9 +509,11 @@ impl HysteriaConnection {
         session_id: u32,
         pkt_id: u16,
     ) -> std::io::Result<()> {
-        let max_frag_size = match self.udp_mtu.or(self.conn.max_datagram_size()) {
-            Some(x) => x,
-            None => {
+        // the discovered size wins over a configured one too large for the path
+        let max_frag_size = match (self.udp_mtu, self.conn.max_datagram_size()) {
+            (Some(mtu), Some(discovered)) => mtu.min(discovered),
+            (Some(x), None) | (None, Some(x)) => x,
+            (None, None) => {
                 return Err(std::io::Error::other(
                     "hysteria2 udp mtu not set, please check your \
                      disable_mtu_discovery and udp_mtu option",
@@ -42,16 +42,38 @@ impl SalamanderObfs {
     }
 
     fn encrypt(&self, data: &mut [u8]) -> Bytes {
-        let salt: [u8; 8] = rand::rng().random();
-
         let mut res = BytesMut::with_capacity(8 + data.len());
-        res.put_slice(&salt);
-        self.obfs(&salt, data);
-        res.put_slice(data);
-
+        self.encrypt_into(data, &mut res);
         res.freeze()
     }
 
+    /// appends the salt and `data` obfuscated with it to `out`
+    fn encrypt_into(&self, data: &[u8], out: &mut BytesMut) {
+        let salt: [u8; 8] = rand::rng().random();
+        out.put_slice(&salt);
+        let start = out.len();
+        out.put_slice(data);
+        self.obfs(&salt, &mut out[start..]);
+    }
+
+    /// decrypts the datagrams of a GRO batch, `stride` apart, and moves them
+    /// together without their salts. returns the length and the stride left
+    fn decrypt_batch(&self, data: &mut [u8], stride: usize) -> (usize, usize) {
+        let stride = stride.max(1);
+        let (mut start, mut len) = (0, 0);
+        while start < data.len() {
+            let end = (start + stride).min(data.len());
+            // too short to have been obfuscated, only the last one can be
+            if end - start > 8 {
+                self.decrypt(&mut data[start..end]);
+                data.copy_within(start + 8..end, len);
+                len += end - start - 8;
+            }
+            start = end;
+        }
+        (len, stride.saturating_sub(8))
+    }
+
     fn decrypt(&self, data: &mut [u8]) {
         assert!(data.len() > 8, "data len must > 8");
 
@@ -92,10 +114,18 @@ impl AsyncUdpSocket for Salamander {
     }
 
     fn try_send(&self, transmit: &Transmit) -> std::io::Result<()> {
+        // each datagram of a GSO batch gets a salt of its own
+        let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+        let segments = transmit.contents.chunks(segment_size.max(1));
+        let mut contents =
+            BytesMut::with_capacity(transmit.contents.len() + 8 * segments.len());
+        for segment in segments {
+            self.obfs.encrypt_into(segment, &mut contents);
+        }
+
         let mut v = transmit.to_owned();
-        // TODO: encrypt in place
-        let x = self.obfs.encrypt(&mut v.contents.to_vec());
-        v.contents = &x;
+        v.contents = &contents;
+        v.segment_size = transmit.segment_size.map(|x| x + 8);
         self.inner.try_send(&v)
     }
 
@@ -112,21 +142,11 @@ impl AsyncUdpSocket for Salamander {
         });
         bufs.iter_mut()
             .zip(meta.iter_mut())
-            // first step take and then filter
             .take(packet_nums)
-            .filter(|(_, meta)| meta.len > 8)
             .for_each(|(v, meta)| {
-                let x = &mut v.deref_mut()[..meta.len];
-                // decrypt in place, and drop first 8 bytes
-                self.obfs.decrypt(x);
-                let data = &mut x[8..];
-                unsafe {
-                    //  because IoSliceMut is transparent and .0 is also transparent, so it is a &[u8]
-                    let b: IoSliceMut<'_> = std::mem::transmute(data);
-                    *v = b;
-                }
+                let data = &mut v.deref_mut()[..meta.len];
                 // MUST update meta.len
-                meta.len -= 8;
+                (meta.len, meta.stride) = self.obfs.decrypt_batch(data, meta.stride);
             });
 
         Poll::Ready(Ok(packet_nums))
@@ -139,6 +159,14 @@ impl AsyncUdpSocket for Salamander {
     fn may_fragment(&self) -> bool {
         self.inner.may_fragment()
     }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
 }
 
 #[test]
@@ -161,6 +189,19 @@ fn test_skip() {
     });
 }
 
+#[test]
+fn test_obfs_batch() {
+    let obfs = SalamanderObfs::new(b"obfs".to_vec());
+    let mut batch = BytesMut::new();
+    for segment in [&b"aaaa"[..], b"bbbb", b"cc"] {
+        obfs.encrypt_into(segment, &mut batch);
+    }
+
+    let (len, stride) = obfs.decrypt_batch(&mut batch, 12);
+    assert_eq!((len, stride), (10, 4));
+    assert_eq!(&batch[..len], b"aaaabbbbcc");
+}
+
 #[test]
 fn test_obfs() {
     let obfs = SalamanderObfs::new(b"obfs".to_vec());
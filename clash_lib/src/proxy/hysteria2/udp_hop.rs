@@ -11,7 +11,7 @@ use std::{
 
 use quinn::{AsyncUdpSocket, Runtime, TokioRuntime, UdpPoller, udp::Transmit};
 
-use crate::proxy::converters::hysteria2::PortGenerator;
+use crate::proxy::{converters::hysteria2::PortGenerator, utils::new_socket};
 
 struct HopState {
     prev_conn: Option<Arc<dyn AsyncUdpSocket>>,
@@ -38,6 +38,12 @@ pub struct UdpHop {
     interval: Duration,
 }
 
+fn bind_udp() -> io::Result<std::net::UdpSocket> {
+    let socket = new_socket(socket2::Domain::IPV4, socket2::Type::DGRAM)?;
+    socket.bind(&SocketAddr::new([0, 0, 0, 0].into(), 0).into())?;
+    Ok(socket.into())
+}
+
 impl UdpHop {
     const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
 
@@ -46,8 +52,7 @@ impl UdpHop {
         port_range: PortGenerator,
         interval: Option<Duration>,
     ) -> io::Result<Self> {
-        let socket =
-            std::net::UdpSocket::bind(SocketAddr::new([0, 0, 0, 0].into(), 0))?;
+        let socket = bind_udp()?;
 
         let state = HopState {
             prev_conn: None,
@@ -81,7 +86,7 @@ impl UdpHop {
             *last = now;
             tracing::trace!("port hopping");
 
-            std::net::UdpSocket::bind(SocketAddr::new([0, 0, 0, 0].into(), 0))
+            bind_udp()
                 .and_then(|udp| TokioRuntime.wrap_udp_socket(udp))
                 .map(|new_conn| {
                     *new_hop_port = self.port_range.get();
@@ -239,4 +244,14 @@ impl AsyncUdpSocket for UdpHop {
     fn may_fragment(&self) -> bool {
         self.get_conn().1.may_fragment()
     }
+
+    // the sockets hopped between are alike, and hopping only changes the
+    // port, so the batches of the current one go through as they are
+    fn max_transmit_segments(&self) -> usize {
+        self.get_conn().1.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.get_conn().1.max_receive_segments()
+    }
 }
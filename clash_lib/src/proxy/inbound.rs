@@ -1,7 +1,9 @@
 use enum_dispatch::enum_dispatch;
 
 use super::{
-    http::HttpInbound, mixed::MixedInbound, socks::SocksInbound,
+    http::{HttpInbound, ReverseProxyInbound},
+    mixed::MixedInbound,
+    socks::SocksInbound,
     tunnel::TunnelInbound,
 };
 
@@ -23,4 +25,5 @@ pub enum InboudHandler {
     #[cfg(target_os = "linux")]
     TProxy(super::tproxy::TproxyInbound),
     Tunnel(TunnelInbound),
+    ReverseProxy(ReverseProxyInbound),
 }
@@ -1,12 +1,14 @@
 use crate::{
     Dispatcher,
-    common::auth::ThreadSafeAuthenticator,
+    app::handover,
+    common::{auth::ThreadSafeAuthenticator, tls},
     session::{Network, Session},
 };
 
 use std::{net::SocketAddr, sync::Arc};
 
-use tokio::net::TcpListener;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_rustls::TlsAcceptor;
 use tracing::warn;
 
 use super::{http, inbound::InboundHandlerTrait, socks, utils::apply_tcp_options};
@@ -16,6 +18,7 @@ pub struct MixedInbound {
     allow_lan: bool,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    tls: Option<TlsAcceptor>,
 }
 
 impl Drop for MixedInbound {
@@ -30,12 +33,14 @@ impl MixedInbound {
         allow_lan: bool,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        tls: Option<TlsAcceptor>,
     ) -> Self {
         Self {
             addr,
             allow_lan,
             dispatcher,
             authenticator,
+            tls,
         }
     }
 }
@@ -50,7 +55,7 @@ impl InboundHandlerTrait for MixedInbound {
     }
 
     async fn listen_tcp(&self) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener = handover::bind_tcp(self.addr).await?;
 
         loop {
             let (socket, _) = listener.accept().await?;
@@ -61,6 +66,56 @@ impl InboundHandlerTrait for MixedInbound {
             }
             let socket = apply_tcp_options(socket)?;
 
+            if let Some(acceptor) = self.tls.clone() {
+                let local_addr = socket.local_addr()?;
+                let dispatcher = self.dispatcher.clone();
+                let authenticator = self.authenticator.clone();
+                tokio::spawn(async move {
+                    let stream = match tls::accept(&acceptor, socket).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("TLS handshake with {} failed: {}", src_addr, e);
+                            return;
+                        }
+                    };
+                    // the protocol can only be told after decryption, the
+                    // buffered first byte is read again by the handler
+                    let mut stream = BufReader::new(stream);
+                    let first = match stream.fill_buf().await {
+                        Ok([first, ..]) => *first,
+                        _ => {
+                            warn!("failed to peek TLS stream from {}", src_addr);
+                            return;
+                        }
+                    };
+                    if first == socks::SOCKS5_VERSION {
+                        let mut sess = Session {
+                            network: Network::Tcp,
+                            source: src_addr,
+
+                            ..Default::default()
+                        };
+                        let _ = socks::handle_tcp(
+                            &mut sess,
+                            stream,
+                            local_addr,
+                            dispatcher,
+                            authenticator,
+                        )
+                        .await;
+                    } else {
+                        http::handle_http(
+                            Box::new(stream),
+                            src_addr,
+                            dispatcher,
+                            authenticator,
+                        )
+                        .await;
+                    }
+                });
+                continue;
+            }
+
             let mut p = [0; 1];
             let n = socket.peek(&mut p).await?;
             if n != 1 {
@@ -79,11 +134,13 @@ impl InboundHandlerTrait for MixedInbound {
 
                         ..Default::default()
                     };
+                    let local_addr = socket.local_addr()?;
 
                     tokio::spawn(async move {
                         socks::handle_tcp(
                             &mut sess,
                             socket,
+                            local_addr,
                             dispatcher,
                             authenticator,
                         )
@@ -4,12 +4,12 @@ use crate::{
         dns::ThreadSafeDNSResolver,
     },
     proxy::datagram::UdpPacket,
-    session::Session,
+    session::{Session, SocksAddr},
 };
 use async_trait::async_trait;
 use downcast_rs::{Downcast, impl_downcast};
 use erased_serde::Serialize as ESerialize;
-use futures::{Sink, Stream};
+use futures::{Sink, Stream, future::BoxFuture};
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
@@ -24,11 +24,14 @@ use tokio::io::{AsyncRead, AsyncWrite};
 
 use self::utils::RemoteConnector;
 
+pub mod block_page;
 pub mod direct;
 pub mod reject;
 
+pub mod dialer;
 pub mod http;
 pub mod mixed;
+pub mod multi_endpoint;
 #[cfg(target_os = "linux")]
 pub mod tproxy;
 
@@ -52,7 +55,7 @@ pub mod vmess;
 pub mod wg;
 
 pub mod group;
-pub use group::{fallback, loadbalance, relay, selector, urltest};
+pub use group::{fallback, loadbalance, relay, selector, smart, urltest};
 
 mod common;
 pub mod inbound;
@@ -60,7 +63,7 @@ mod options;
 mod transport;
 pub mod tunnel;
 
-pub use options::HandlerCommonOptions;
+pub use options::{HandlerCommonOptions, HandshakeTimeouts};
 
 #[cfg(test)]
 pub mod mocks;
@@ -131,9 +134,11 @@ pub enum OutboundType {
     Relay,
     LoadBalance,
     Fallback,
+    Smart,
 
     Direct,
     Reject,
+    BlockPage,
 }
 
 impl Display for OutboundType {
@@ -154,13 +159,25 @@ impl Display for OutboundType {
             OutboundType::Relay => write!(f, "Relay"),
             OutboundType::LoadBalance => write!(f, "LoadBalance"),
             OutboundType::Fallback => write!(f, "Fallback"),
+            OutboundType::Smart => write!(f, "Smart"),
 
             OutboundType::Direct => write!(f, "Direct"),
             OutboundType::Reject => write!(f, "Reject"),
+            OutboundType::BlockPage => write!(f, "BlockPage"),
         }
     }
 }
 
+/// A listener created by an outbound for a SOCKS5 BIND request
+pub struct PendingBind {
+    /// the outbound the listener was created by
+    pub outbound: String,
+    /// the address the remote peer is expected to connect to
+    pub bound: SocksAddr,
+    /// resolves to the address of the remote peer and the accepted stream
+    pub accept: BoxFuture<'static, io::Result<(SocksAddr, BoxedChainedStream)>>,
+}
+
 pub enum ConnectorType {
     Tcp,
     All,
@@ -221,6 +238,24 @@ pub trait OutboundHandler: Sync + Send + Unpin + DialWithConnector + Debug {
         ))
     }
 
+    /// accept a single incoming connection from `sess.destination`,
+    /// i.e. SOCKS5 BIND
+    async fn bind_stream(
+        &self,
+        _sess: &Session,
+        _resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("bind not supported for {}", self.proto()),
+        ))
+    }
+
+    /// for a group, the member a new connection of `sess` would go through
+    async fn current_proxy(&self, _sess: &Session) -> Option<String> {
+        None
+    }
+
     /// for API
     /// the map only contains basic information
     /// to populate history/liveness information, use the proxy_manager
@@ -234,6 +269,11 @@ pub trait OutboundHandler: Sync + Send + Unpin + DialWithConnector + Debug {
     fn icon(&self) -> Option<String> {
         None
     }
+
+    /// for API, how often the connections resumed a TLS session
+    fn tls_sessions(&self) -> Option<transport::SessionStats> {
+        None
+    }
 }
 pub type AnyOutboundHandler = Arc<dyn OutboundHandler>;
 
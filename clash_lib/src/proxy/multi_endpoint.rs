@@ -0,0 +1,269 @@
+use std::{collections::HashMap, fmt::Debug, future::Future, io, sync::Arc};
+
+use erased_serde::Serialize;
+use tracing::debug;
+
+use crate::{
+    app::{
+        dispatcher::{BoxedChainedDatagram, BoxedChainedStream},
+        dns::ThreadSafeDNSResolver,
+        remote_content_manager::ProxyManager,
+    },
+    common::errors::is_retryable,
+    proxy::{
+        AnyOutboundHandler, ConnectorType, DialWithConnector, OutboundHandler,
+        OutboundType, PendingBind, transport::SessionStats, utils::RemoteConnector,
+    },
+    session::Session,
+};
+
+/// A proxy with `backup-endpoints`.
+/// Each endpoint has its own handler built from the same config, they are
+/// tried in order when dialing fails, starting with the one that worked last.
+pub struct Handler {
+    name: String,
+    /// the primary endpoint first, then the backups in config order
+    endpoints: Vec<(String, AnyOutboundHandler)>,
+    proxy_manager: ProxyManager,
+}
+
+impl Debug for Handler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MultiEndpoint")
+            .field("name", &self.name)
+            .field(
+                "endpoints",
+                &self.endpoints.iter().map(|x| &x.0).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Handler {
+    /// `endpoints` must not be empty
+    pub fn new(
+        name: String,
+        endpoints: Vec<(String, AnyOutboundHandler)>,
+        proxy_manager: ProxyManager,
+    ) -> Self {
+        assert!(!endpoints.is_empty(), "no endpoints for {}", name);
+        Self {
+            name,
+            endpoints,
+            proxy_manager,
+        }
+    }
+
+    fn preferred(&self) -> usize {
+        self.proxy_manager
+            .preferred_endpoint(&self.name)
+            .min(self.endpoints.len() - 1)
+    }
+
+    async fn try_endpoints<'a, T, F, Fut>(&'a self, dial: F) -> io::Result<T>
+    where
+        F: Fn(&'a AnyOutboundHandler) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let preferred = self.preferred();
+        let order = std::iter::once(preferred)
+            .chain((0..self.endpoints.len()).filter(|i| *i != preferred));
+
+        let mut last_err = None;
+        for i in order {
+            let (endpoint, handler) = &self.endpoints[i];
+            match dial(handler).await {
+                Ok(r) => {
+                    if i != preferred {
+                        debug!("{} switched to endpoint {}", self.name, endpoint);
+                        self.proxy_manager.set_preferred_endpoint(&self.name, i);
+                    }
+                    return Ok(r);
+                }
+                Err(e) => {
+                    debug!(
+                        "{} failed to dial endpoint {}: {}",
+                        self.name, endpoint, e
+                    );
+                    let retry = is_retryable(&e);
+                    last_err = Some(e);
+                    if !retry {
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_err.expect("endpoints must not be empty"))
+    }
+}
+
+#[async_trait::async_trait]
+impl DialWithConnector for Handler {
+    fn support_dialer(&self) -> Option<&str> {
+        self.endpoints[0].1.support_dialer()
+    }
+
+    async fn register_connector(&self, connector: Arc<dyn RemoteConnector>) {
+        for (_, handler) in self.endpoints.iter() {
+            handler.register_connector(connector.clone()).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OutboundHandler for Handler {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn proto(&self) -> OutboundType {
+        self.endpoints[0].1.proto()
+    }
+
+    async fn support_udp(&self) -> bool {
+        self.endpoints[0].1.support_udp().await
+    }
+
+    async fn connect_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedStream> {
+        self.try_endpoints(|h| h.connect_stream(sess, resolver.clone()))
+            .await
+    }
+
+    async fn connect_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.try_endpoints(|h| h.connect_datagram(sess, resolver.clone()))
+            .await
+    }
+
+    async fn support_connector(&self) -> ConnectorType {
+        self.endpoints[0].1.support_connector().await
+    }
+
+    async fn connect_stream_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedStream> {
+        self.try_endpoints(|h| {
+            h.connect_stream_with_connector(sess, resolver.clone(), connector)
+        })
+        .await
+    }
+
+    async fn connect_datagram_with_connector(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        self.try_endpoints(|h| {
+            h.connect_datagram_with_connector(sess, resolver.clone(), connector)
+        })
+        .await
+    }
+
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> io::Result<PendingBind> {
+        self.try_endpoints(|h| h.bind_stream(sess, resolver.clone()))
+            .await
+    }
+
+    async fn as_map(&self) -> HashMap<String, Box<dyn Serialize + Send>> {
+        let mut m = self.endpoints[0].1.as_map().await;
+        m.insert(
+            "endpoints".to_string(),
+            Box::new(
+                self.endpoints
+                    .iter()
+                    .map(|x| x.0.clone())
+                    .collect::<Vec<_>>(),
+            ) as _,
+        );
+        m.insert(
+            "endpoint".to_string(),
+            Box::new(self.endpoints[self.preferred()].0.clone()) as _,
+        );
+        m
+    }
+
+    fn icon(&self) -> Option<String> {
+        self.endpoints[0].1.icon()
+    }
+
+    fn tls_sessions(&self) -> Option<SessionStats> {
+        self.endpoints
+            .iter()
+            .filter_map(|x| x.1.tls_sessions())
+            .reduce(SessionStats::merge)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Arc};
+
+    use crate::{
+        app::{
+            dispatcher::ChainedStreamWrapper, dns::MockClashResolver,
+            remote_content_manager::ProxyManager,
+        },
+        proxy::{OutboundHandler, mocks::MockDummyOutboundHandler},
+        session::Session,
+    };
+
+    use super::Handler;
+
+    #[tokio::test]
+    async fn test_failover_to_backup_endpoint() {
+        let resolver = Arc::new(MockClashResolver::new());
+        let manager = ProxyManager::new(resolver.clone());
+
+        let mut primary = MockDummyOutboundHandler::new();
+        primary
+            .expect_connect_stream()
+            .times(1)
+            .returning(|_, _| Err(io::ErrorKind::ConnectionRefused.into()));
+        let mut backup = MockDummyOutboundHandler::new();
+        backup.expect_connect_stream().times(2).returning(|_, _| {
+            Ok(Box::new(ChainedStreamWrapper::new(
+                tokio_test::io::Builder::new().build(),
+            )))
+        });
+
+        let handler = Handler::new(
+            "p".to_owned(),
+            vec![
+                ("a:1".to_owned(), Arc::new(primary) as _),
+                ("b:1".to_owned(), Arc::new(backup) as _),
+            ],
+            manager.clone(),
+        );
+
+        let sess = Session::default();
+        assert!(
+            handler
+                .connect_stream(&sess, resolver.clone())
+                .await
+                .is_ok()
+        );
+        assert_eq!(manager.preferred_endpoint("p"), 1);
+        // the backup is tried first now, the primary isn't dialed again
+        assert!(
+            handler
+                .connect_stream(&sess, resolver.clone())
+                .await
+                .is_ok()
+        );
+    }
+}
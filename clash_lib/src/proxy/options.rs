@@ -1,5 +1,93 @@
+use std::{future::Future, io, time::Duration};
+
+use super::transport::TlsFragment;
+
 #[derive(Default, Debug, Clone)]
 pub struct HandlerCommonOptions {
     pub connector: Option<String>,
     pub icon: Option<String>,
+    pub timeouts: HandshakeTimeouts,
+    /// splits the ClientHello of the likely TLS in the TLS of the proxy
+    pub tls_in_tls: Option<TlsFragment>,
+}
+
+/// Per phase deadlines for establishing an outbound connection.
+/// `None` means the phase is only bounded by the caller.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct HandshakeTimeouts {
+    /// resolving and connecting to the proxy server
+    pub connect: Option<Duration>,
+    /// TLS and transport (ws/h2/grpc/plugin) handshake
+    pub transport: Option<Duration>,
+    /// proxy protocol handshake, e.g. SOCKS5 negotiation
+    pub protocol: Option<Duration>,
+}
+
+impl HandshakeTimeouts {
+    pub async fn connect<F, T>(&self, fut: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        with_timeout("connect", self.connect, fut).await
+    }
+
+    pub async fn transport<F, T>(&self, fut: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        with_timeout("transport handshake", self.transport, fut).await
+    }
+
+    pub async fn protocol<F, T>(&self, fut: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        with_timeout("protocol handshake", self.protocol, fut).await
+    }
+}
+
+async fn with_timeout<F, T>(
+    phase: &str,
+    timeout: Option<Duration>,
+    fut: F,
+) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match timeout {
+        Some(t) => tokio::time::timeout(t, fut).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} timed out after {:?}", phase, t),
+            )
+        })?,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::HandshakeTimeouts;
+
+    #[tokio::test]
+    async fn test_phase_timeout() {
+        let t = HandshakeTimeouts {
+            connect: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let err = t
+            .connect(async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("connect"));
+
+        assert!(t.protocol(async { Ok(1) }).await.is_ok());
+    }
 }
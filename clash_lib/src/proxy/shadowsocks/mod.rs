@@ -1,10 +1,14 @@
 mod datagram;
 mod stream;
+mod udp_mux;
 
-use self::{datagram::OutboundDatagramShadowsocks, stream::ShadowSocksStream};
+use self::{
+    datagram::OutboundDatagramShadowsocks, stream::ShadowSocksStream,
+    udp_mux::UdpMux,
+};
 use super::{
     AnyStream, ConnectorType, DialWithConnector, OutboundType,
-    transport::Sip003Plugin,
+    transport::{Sip003Plugin, tls_in_tls},
     utils::{GLOBAL_DIRECT_CONNECTOR, RemoteConnector},
 };
 use crate::{
@@ -14,6 +18,7 @@ use crate::{
             ChainedDatagramWrapper, ChainedStream, ChainedStreamWrapper,
         },
         dns::ThreadSafeDNSResolver,
+        net::Interface,
     },
     common::errors::new_io_error,
     impl_default_connector,
@@ -27,7 +32,13 @@ use shadowsocks::{
     context::Context, crypto::CipherKind,
     relay::udprelay::proxy_socket::UdpSocketType,
 };
-use std::{fmt::Debug, io, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Weak},
+};
 use tracing::debug;
 
 pub struct HandlerOptions {
@@ -39,12 +50,18 @@ pub struct HandlerOptions {
     pub cipher: String,
     pub plugin: Option<Box<dyn Sip003Plugin>>,
     pub udp: bool,
+    /// carry all UDP sessions over one socket, 2022 ciphers only
+    pub udp_mux: bool,
 }
 
 pub struct Handler {
     opts: HandlerOptions,
 
     connector: tokio::sync::Mutex<Option<Arc<dyn RemoteConnector>>>,
+    /// the shared sockets while there are UDP sessions on them, by the
+    /// interface and mark of the sessions
+    udp_mux:
+        tokio::sync::Mutex<HashMap<(Option<Interface>, Option<u32>), Weak<UdpMux>>>,
 }
 
 impl_default_connector!(Handler);
@@ -62,6 +79,7 @@ impl Handler {
         Self {
             opts,
             connector: tokio::sync::Mutex::new(None),
+            udp_mux: Default::default(),
         }
     }
 
@@ -72,7 +90,13 @@ impl Handler {
         _resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<AnyStream> {
         let stream: AnyStream = match &self.opts.plugin {
-            Some(plugin) => plugin.proxy_stream(s).await?,
+            Some(plugin) => {
+                self.opts
+                    .common_opts
+                    .timeouts
+                    .transport(plugin.proxy_stream(s))
+                    .await?
+            }
             None => s,
         };
 
@@ -85,8 +109,17 @@ impl Handler {
             &cfg,
             (sess.destination.host(), sess.destination.port()),
         );
-
-        Ok(Box::new(ShadowSocksStream(stream)))
+        let stream: AnyStream = Box::new(ShadowSocksStream(stream));
+
+        Ok(match &self.opts.plugin {
+            Some(plugin) if plugin.is_tls() => tls_in_tls::guard(
+                self.name(),
+                sess,
+                stream,
+                self.opts.common_opts.tls_in_tls,
+            ),
+            _ => stream,
+        })
     }
 
     fn server_config(&self) -> Result<ServerConfig, io::Error> {
@@ -115,6 +148,90 @@ impl Handler {
         )
         .map_err(|e| new_io_error(e.to_string()))
     }
+
+    async fn new_proxy_socket(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<(ProxySocket<ShadowsocksUdpIo>, SocketAddr)> {
+        let ctx = Context::new_shared(ServerType::Local);
+        let cfg = self.server_config()?;
+
+        let socket = connector
+            .connect_datagram(
+                resolver.clone(),
+                None,
+                (self.opts.server.clone(), self.opts.port).try_into()?,
+                sess.iface.as_ref().cloned(),
+                #[cfg(target_os = "linux")]
+                sess.so_mark,
+            )
+            .await?;
+
+        let socket = ProxySocket::from_socket(
+            UdpSocketType::Client,
+            ctx,
+            &cfg,
+            ShadowsocksUdpIo::new(socket),
+        );
+        let server_addr =
+            resolve_server(&resolver, &self.opts.server, self.opts.port).await?;
+        Ok((socket, server_addr))
+    }
+
+    /// a socket is opened for the first session of each interface and mark,
+    /// and closed when the last session on it is gone
+    async fn mux_datagram(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+        connector: &dyn RemoteConnector,
+    ) -> io::Result<BoxedChainedDatagram> {
+        let mut muxes = self.udp_mux.lock().await;
+        muxes.retain(|_, mux| mux.strong_count() > 0);
+        let key = (sess.iface.clone(), sess.so_mark);
+        let mux = match muxes.get(&key).and_then(Weak::upgrade) {
+            Some(mux) if !mux.is_closed() => mux,
+            _ => {
+                let (socket, server_addr) = self
+                    .new_proxy_socket(sess, resolver.clone(), connector)
+                    .await?;
+                let server = self.opts.server.clone();
+                let port = self.opts.port;
+                let mux = Arc::new(UdpMux::new(
+                    socket,
+                    server_addr,
+                    Box::new(move || {
+                        let resolver = resolver.clone();
+                        let server = server.clone();
+                        Box::pin(async move {
+                            resolve_server(&resolver, &server, port).await
+                        })
+                    }),
+                ));
+                muxes.insert(key, Arc::downgrade(&mux));
+                mux
+            }
+        };
+
+        let d = ChainedDatagramWrapper::new(mux.new_session());
+        d.append_to_chain(self.name()).await;
+        Ok(Box::new(d))
+    }
+}
+
+async fn resolve_server(
+    resolver: &ThreadSafeDNSResolver,
+    server: &str,
+    port: u16,
+) -> io::Result<SocketAddr> {
+    let ip = resolver
+        .resolve(server, false)
+        .await
+        .map_err(|x| new_io_error(format!("failed to resolve {}: {}", server, x)))?
+        .ok_or(new_io_error(format!("failed to resolve {}", server)))?;
+    Ok((ip, port).into())
 }
 
 #[async_trait]
@@ -164,15 +281,15 @@ impl OutboundHandler for Handler {
             debug!("{:?} is connecting via {:?}", self, dialer);
         }
 
-        self.connect_datagram_with_connector(
-            sess,
-            resolver,
-            dialer
-                .as_ref()
-                .unwrap_or(&GLOBAL_DIRECT_CONNECTOR.clone())
-                .as_ref(),
-        )
-        .await
+        let connector = dialer
+            .as_ref()
+            .unwrap_or(&GLOBAL_DIRECT_CONNECTOR.clone())
+            .clone();
+        if self.opts.udp_mux {
+            return self.mux_datagram(sess, resolver, connector.as_ref()).await;
+        }
+        self.connect_datagram_with_connector(sess, resolver, connector.as_ref())
+            .await
     }
 
     async fn support_connector(&self) -> ConnectorType {
@@ -185,15 +302,18 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = connector
-            .connect_stream(
+        let stream = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
                 resolver.clone(),
                 self.opts.server.as_str(),
                 self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(target_os = "linux")]
                 sess.so_mark,
-            )
+            ))
             .await?;
 
         let s = self.proxy_stream(stream, sess, resolver).await?;
@@ -208,43 +328,9 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedDatagram> {
-        let ctx = Context::new_shared(ServerType::Local);
-        let cfg = self.server_config()?;
-
-        let socket = connector
-            .connect_datagram(
-                resolver.clone(),
-                None,
-                (self.opts.server.clone(), self.opts.port).try_into()?,
-                sess.iface.as_ref().cloned(),
-                #[cfg(target_os = "linux")]
-                sess.so_mark,
-            )
-            .await?;
-
-        let socket = ProxySocket::from_socket(
-            UdpSocketType::Client,
-            ctx,
-            &cfg,
-            ShadowsocksUdpIo::new(socket),
-        );
-        let server_addr = resolver
-            .resolve(&self.opts.server, false)
-            .await
-            .map_err(|x| {
-                new_io_error(format!(
-                    "failed to resolve {}: {}",
-                    self.opts.server, x
-                ))
-            })?
-            .ok_or(new_io_error(format!(
-                "failed to resolve {}",
-                self.opts.server
-            )))?;
-        let d = OutboundDatagramShadowsocks::new(
-            socket,
-            (server_addr, self.opts.port).into(),
-        );
+        let (socket, server_addr) =
+            self.new_proxy_socket(sess, resolver, connector).await?;
+        let d = OutboundDatagramShadowsocks::new(socket, server_addr);
         let d = ChainedDatagramWrapper::new(d);
         d.append_to_chain(self.name()).await;
         Ok(Box::new(d))
@@ -337,6 +423,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin: Default::default(),
             udp: false,
+            udp_mux: false,
         };
         let port = opts.port;
         let handler = Arc::new(Handler::new(opts));
@@ -394,6 +481,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin: Some(Box::new(client)),
             udp: false,
+            udp_mux: false,
         };
         let handler: Arc<dyn OutboundHandler> = Arc::new(Handler::new(opts));
         // we need to store all the runners in a container, to make sure all of
@@ -454,6 +542,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin: Some(plugin),
             udp: false,
+            udp_mux: false,
         };
 
         let handler: Arc<dyn OutboundHandler> = Arc::new(Handler::new(opts));
@@ -502,6 +591,7 @@ mod tests {
             cipher: CIPHER.to_owned(),
             plugin: Some(Box::new(plugin)),
             udp: false,
+            udp_mux: false,
         };
 
         let handler: Arc<dyn OutboundHandler> = Arc::new(Handler::new(opts));
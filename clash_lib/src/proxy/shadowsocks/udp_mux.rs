@@ -0,0 +1,379 @@
+//! Many UDP sessions over one shadowsocks socket.
+//! Only the 2022 ciphers can do this: the server echoes the client session
+//! id in every reply, which is what replies are handed back to sessions by.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{Sink, SinkExt, Stream, future::BoxFuture};
+use shadowsocks::{
+    ProxySocket,
+    relay::{Address, udprelay::options::UdpSocketControlData},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::PollSender;
+use tracing::{debug, trace};
+
+use super::datagram::ShadowsocksUdpIo;
+use crate::{
+    common::errors::new_io_error, proxy::datagram::UdpPacket, session::SocksAddr,
+};
+
+type Outgoing = (UdpSocketControlData, UdpPacket);
+type Sessions = Arc<Mutex<HashMap<u64, mpsc::Sender<UdpPacket>>>>;
+/// looks the server up again, after a packet failed to be sent to it
+pub type ResolveServer =
+    Box<dyn Fn() -> BoxFuture<'static, io::Result<SocketAddr>> + Send + Sync>;
+
+/// the packets a session may have queued either way
+const SESSION_QUEUE: usize = 64;
+
+/// The shared socket, alive as long as any of its sessions is
+pub struct UdpMux {
+    send_tx: mpsc::Sender<Outgoing>,
+    sessions: Sessions,
+    recv_task: JoinHandle<()>,
+}
+
+impl Drop for UdpMux {
+    fn drop(&mut self) {
+        // the send task ends by itself once `send_tx` is gone
+        self.recv_task.abort();
+    }
+}
+
+impl UdpMux {
+    pub fn new(
+        socket: ProxySocket<ShadowsocksUdpIo>,
+        server_addr: SocketAddr,
+        resolve: ResolveServer,
+    ) -> Self {
+        let socket = Arc::new(socket);
+        let sessions: Sessions = Default::default();
+        let (send_tx, mut send_rx) = mpsc::channel::<Outgoing>(SESSION_QUEUE);
+
+        let send_socket = socket.clone();
+        tokio::spawn(async move {
+            let mut server_addr = server_addr;
+            while let Some((ctrl, pkt)) = send_rx.recv().await {
+                let addr: Address =
+                    (pkt.dst_addr.host(), pkt.dst_addr.port()).into();
+                if let Err(e) = send_socket
+                    .send_to_with_ctrl(server_addr, &addr, &ctrl, &pkt.data)
+                    .await
+                {
+                    debug!("failed to send udp packet to {}: {}", server_addr, e);
+                    // the server may have moved since the socket was opened
+                    match resolve().await {
+                        Ok(addr) if addr != server_addr => {
+                            debug!("udp mux server moved to {}", addr);
+                            server_addr = addr;
+                        }
+                        Ok(_) => {}
+                        Err(e) => debug!("failed to resolve udp mux server: {}", e),
+                    }
+                }
+            }
+        });
+
+        let recv_sessions = sessions.clone();
+        let recv_task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 65535];
+            loop {
+                let (n, src, _, ctrl) = match socket.recv_with_ctrl(&mut buf).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        debug!("udp mux to {} closed: {}", server_addr, e);
+                        break;
+                    }
+                };
+                let Some(id) = ctrl.map(|c| c.client_session_id) else {
+                    continue;
+                };
+                let Some(session) = recv_sessions.lock().unwrap().get(&id).cloned()
+                else {
+                    trace!("udp packet for unknown session {:#x} dropped", id);
+                    continue;
+                };
+                // a slow session loses packets rather than holding up the rest
+                let _ = session.try_send(UdpPacket {
+                    data: buf[..n].to_vec(),
+                    src_addr: match src {
+                        Address::SocketAddress(a) => a.into(),
+                        Address::DomainNameAddress(host, port) => {
+                            SocksAddr::Domain(host, port)
+                        }
+                    },
+                    dst_addr: SocksAddr::any_ipv4(),
+                });
+            }
+            // ends the streams of all sessions
+            recv_sessions.lock().unwrap().clear();
+        });
+
+        Self {
+            send_tx,
+            sessions,
+            recv_task,
+        }
+    }
+
+    /// a broken socket isn't given new sessions
+    pub fn is_closed(&self) -> bool {
+        self.recv_task.is_finished()
+    }
+
+    pub fn new_session(self: &Arc<Self>) -> MuxDatagram {
+        let (recv_tx, recv_rx) = mpsc::channel(SESSION_QUEUE);
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut ctrl = UdpSocketControlData::default();
+        ctrl.client_session_id = loop {
+            let id = rand::random::<u64>();
+            if !sessions.contains_key(&id) {
+                break id;
+            }
+        };
+        sessions.insert(ctrl.client_session_id, recv_tx);
+
+        MuxDatagram {
+            ctrl,
+            send_tx: PollSender::new(self.send_tx.clone()),
+            recv_rx,
+            mux: self.clone(),
+        }
+    }
+
+    #[cfg(test)]
+    fn session_count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+}
+
+/// A UDP session on a `UdpMux`
+pub struct MuxDatagram {
+    ctrl: UdpSocketControlData,
+    send_tx: PollSender<Outgoing>,
+    recv_rx: mpsc::Receiver<UdpPacket>,
+    mux: Arc<UdpMux>,
+}
+
+impl Drop for MuxDatagram {
+    fn drop(&mut self) {
+        self.mux
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.ctrl.client_session_id);
+    }
+}
+
+impl Sink<UdpPacket> for MuxDatagram {
+    type Error = io::Error;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_ready_unpin(cx)
+            .map_err(|_| new_io_error("udp mux closed"))
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        item: UdpPacket,
+    ) -> Result<(), Self::Error> {
+        let ctrl = self.ctrl.clone();
+        self.ctrl.packet_id = ctrl
+            .packet_id
+            .checked_add(1)
+            .ok_or_else(|| new_io_error("packet_id overflow"))?;
+        self.send_tx
+            .start_send_unpin((ctrl, item))
+            .map_err(|_| new_io_error("udp mux closed"))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_flush_unpin(cx)
+            .map_err(|_| new_io_error("udp mux closed"))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.send_tx
+            .poll_close_unpin(cx)
+            .map_err(|_| new_io_error("udp mux closed"))
+    }
+}
+
+impl Stream for MuxDatagram {
+    type Item = UdpPacket;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.recv_rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use futures::{Sink, SinkExt, Stream};
+    use shadowsocks::{
+        ProxySocket, ServerConfig, config::ServerType,
+        context::Context as SsContext, crypto::CipherKind,
+        relay::udprelay::proxy_socket::UdpSocketType,
+    };
+
+    use super::UdpMux;
+    use crate::proxy::{
+        datagram::UdpPacket, shadowsocks::datagram::ShadowsocksUdpIo,
+    };
+
+    /// swallows everything, or fails to send everything if broken. never
+    /// receives
+    struct Blackhole {
+        broken: bool,
+    }
+
+    impl Stream for Blackhole {
+        type Item = UdpPacket;
+
+        fn poll_next(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            Poll::Pending
+        }
+    }
+
+    impl Sink<UdpPacket> for Blackhole {
+        type Error = std::io::Error;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            self: Pin<&mut Self>,
+            _: UdpPacket,
+        ) -> Result<(), Self::Error> {
+            if self.broken {
+                return Err(std::io::ErrorKind::NetworkUnreachable.into());
+            }
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// a mux on a `Blackhole`, counting how often the server is resolved
+    fn blackhole_mux(broken: bool) -> (Arc<UdpMux>, Arc<AtomicUsize>) {
+        let cfg = ServerConfig::new(
+            ("127.0.0.1", 8388),
+            "3SYJ/f8nmVuzKvKglykRQDSgg10e/ADilkdRWrrY9HU=",
+            CipherKind::AEAD2022_BLAKE3_AES_256_GCM,
+        )
+        .unwrap();
+        let socket = ProxySocket::from_socket(
+            UdpSocketType::Client,
+            SsContext::new_shared(ServerType::Local),
+            &cfg,
+            ShadowsocksUdpIo::new(Box::new(Blackhole { broken })),
+        );
+        let resolved = Arc::new(AtomicUsize::new(0));
+        let count = resolved.clone();
+        let mux = UdpMux::new(
+            socket,
+            "127.0.0.1:8388".parse().unwrap(),
+            Box::new(move || {
+                count.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async { Ok("127.0.0.2:8388".parse().unwrap()) })
+            }),
+        );
+        (Arc::new(mux), resolved)
+    }
+
+    #[tokio::test]
+    async fn test_sessions_share_socket() {
+        let (mux, resolved) = blackhole_mux(false);
+
+        let mut a = mux.new_session();
+        let b = mux.new_session();
+        assert_eq!(mux.session_count(), 2);
+        assert_ne!(a.ctrl.client_session_id, b.ctrl.client_session_id);
+
+        a.send(UdpPacket {
+            data: b"hello".to_vec(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(a.ctrl.packet_id, 1);
+
+        drop(b);
+        assert_eq!(mux.session_count(), 1);
+        assert!(!mux.is_closed());
+        assert_eq!(resolved.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_on_failure() {
+        let (mux, resolved) = blackhole_mux(true);
+        let mut a = mux.new_session();
+        for _ in 0..2 {
+            a.send(UdpPacket {
+                data: b"hello".to_vec(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while resolved.load(Ordering::Relaxed) < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the server wasn't resolved again");
+    }
+}
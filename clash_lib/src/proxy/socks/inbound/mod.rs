@@ -3,14 +3,15 @@ mod stream;
 
 use crate::{
     Dispatcher,
-    common::auth::ThreadSafeAuthenticator,
+    app::handover,
+    common::{auth::ThreadSafeAuthenticator, tls},
     proxy::{inbound::InboundHandlerTrait, utils::apply_tcp_options},
     session::{Network, Session, Type},
 };
 
 use std::{net::SocketAddr, sync::Arc};
 pub use stream::handle_tcp;
-use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use tracing::warn;
 
 pub use datagram::Socks5UDPCodec;
@@ -20,6 +21,7 @@ pub struct SocksInbound {
     allow_lan: bool,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
+    tls: Option<TlsAcceptor>,
 }
 
 impl Drop for SocksInbound {
@@ -34,12 +36,14 @@ impl SocksInbound {
         allow_lan: bool,
         dispatcher: Arc<Dispatcher>,
         authenticator: ThreadSafeAuthenticator,
+        tls: Option<TlsAcceptor>,
     ) -> Self {
         Self {
             addr,
             allow_lan,
             dispatcher,
             authenticator,
+            tls,
         }
     }
 }
@@ -54,7 +58,7 @@ impl InboundHandlerTrait for SocksInbound {
     }
 
     async fn listen_tcp(&self) -> anyhow::Result<()> {
-        let listener = TcpListener::bind(self.addr).await?;
+        let listener = handover::bind_tcp(self.addr).await?;
 
         loop {
             let (socket, _) = listener.accept().await?;
@@ -73,11 +77,43 @@ impl InboundHandlerTrait for SocksInbound {
                 ..Default::default()
             };
 
+            let local_addr = socket.local_addr()?;
             let dispatcher = self.dispatcher.clone();
             let authenticator = self.authenticator.clone();
+            let tls = self.tls.clone();
 
             tokio::spawn(async move {
-                handle_tcp(&mut sess, socket, dispatcher, authenticator).await
+                match tls {
+                    Some(acceptor) => match tls::accept(&acceptor, socket).await {
+                        Ok(stream) => {
+                            handle_tcp(
+                                &mut sess,
+                                stream,
+                                local_addr,
+                                dispatcher,
+                                authenticator,
+                            )
+                            .await
+                        }
+                        Err(e) => {
+                            warn!(
+                                "TLS handshake with {} failed: {}",
+                                sess.source, e
+                            );
+                            Ok(())
+                        }
+                    },
+                    None => {
+                        handle_tcp(
+                            &mut sess,
+                            socket,
+                            local_addr,
+                            dispatcher,
+                            authenticator,
+                        )
+                        .await
+                    }
+                }
             });
         }
     }
@@ -1,7 +1,8 @@
 use crate::{
     Dispatcher,
-    common::{auth::ThreadSafeAuthenticator, errors::new_io_error},
+    common::{auth::ThreadSafeAuthenticator, errors::NetError},
     proxy::{
+        ClientStream,
         socks::{
             SOCKS5_VERSION, Socks5UDPCodec,
             inbound::datagram::InboundUdp,
@@ -13,18 +14,21 @@ use crate::{
 };
 use bytes::{BufMut, BytesMut};
 
-use std::{io, net::SocketAddr, str, sync::Arc};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use std::{io, net::SocketAddr, str, sync::Arc, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_util::udp::UdpFramed;
 use tracing::{instrument, trace, warn};
 
+/// how long the peer of a BIND request has to connect
+const BIND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// `s` is the client connection, either TCP or TLS over TCP,
+/// `local_addr` the address the client reached us on
 #[instrument(skip(sess, s, dispatcher, authenticator))]
-pub async fn handle_tcp<'a>(
+pub async fn handle_tcp<'a, S: ClientStream>(
     sess: &'a mut Session,
-    mut s: TcpStream,
+    mut s: S,
+    local_addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     authenticator: ThreadSafeAuthenticator,
 ) -> io::Result<()> {
@@ -61,7 +65,7 @@ pub async fn handle_tcp<'a>(
                 response[1] = response_code::FAILURE;
                 s.write_all(&response).await?;
                 s.shutdown().await?;
-                return Err(new_io_error("auth required"));
+                return Err(NetError::auth("auth required"));
             }
 
             response[1] = auth_methods::USER_PASS;
@@ -133,13 +137,13 @@ pub async fn handle_tcp<'a>(
 
     match buf[1] {
         socks_command::CONNECT => {
-            trace!("Got a CONNECT request from {}", s.peer_addr()?);
+            trace!("Got a CONNECT request from {}", sess.source);
 
             buf.clear();
             buf.put_u8(SOCKS5_VERSION);
             buf.put_u8(response_code::SUCCEEDED);
             buf.put_u8(0x0);
-            let bnd = SocksAddr::from(s.local_addr()?);
+            let bnd = SocksAddr::from(local_addr);
             bnd.write_buf(&mut buf);
             s.write_all(&buf[..]).await?;
             sess.destination = dst;
@@ -150,8 +154,69 @@ pub async fn handle_tcp<'a>(
 
             Ok(())
         }
+        socks_command::BIND => {
+            trace!("Got a BIND request from {}", sess.source);
+            sess.destination = dst;
+
+            let pending = match dispatcher.bind_stream(sess.to_owned()).await {
+                Ok(pending) => pending,
+                Err(e) => {
+                    write_reply(
+                        &mut s,
+                        response_code::FAILURE,
+                        SocksAddr::any_ipv4(),
+                    )
+                    .await?;
+                    return Err(e);
+                }
+            };
+
+            // where the peer reaches the outbound, which the client passes on
+            let bound = pending.bound.clone();
+            trace!("BIND listening at {} for {}", bound, sess);
+            write_reply(&mut s, response_code::SUCCEEDED, bound).await?;
+
+            // the client sends nothing until the second reply, so a read
+            // returns only once it has given up waiting
+            let accepted = tokio::select! {
+                accepted = tokio::time::timeout(BIND_TIMEOUT, pending.accept) => {
+                    accepted.unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "no connection to the BIND address in time",
+                        ))
+                    })
+                }
+                _ = s.read_u8() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "client left while waiting for the BIND connection",
+                    ));
+                }
+            };
+            let (peer, rhs) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    write_reply(
+                        &mut s,
+                        response_code::FAILURE,
+                        SocksAddr::any_ipv4(),
+                    )
+                    .await?;
+                    return Err(e);
+                }
+            };
+            trace!("BIND accepted connection from {}", peer);
+            write_reply(&mut s, response_code::SUCCEEDED, peer).await?;
+
+            dispatcher
+                .dispatch_bound(sess.to_owned(), Box::new(s), &pending.outbound, rhs)
+                .await;
+
+            Ok(())
+        }
         socks_command::UDP_ASSOCIATE => {
-            let udp_addr = SocketAddr::new(s.local_addr()?.ip(), 0);
+            let udp_addr = SocketAddr::new(local_addr.ip(), 0);
             let udp_inbound = new_udp_socket(
                 Some(udp_addr),
                 None,
@@ -162,7 +227,7 @@ pub async fn handle_tcp<'a>(
 
             trace!(
                 "Got a UDP_ASSOCIATE request from {}, UDP assigned at {}",
-                s.peer_addr()?,
+                sess.source,
                 udp_inbound.local_addr()?
             );
 
@@ -225,3 +290,16 @@ pub async fn handle_tcp<'a>(
         }
     }
 }
+
+async fn write_reply<S: AsyncWrite + Unpin>(
+    s: &mut S,
+    code: u8,
+    addr: SocksAddr,
+) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    buf.put_u8(SOCKS5_VERSION);
+    buf.put_u8(code);
+    buf.put_u8(0x0);
+    addr.write_buf(&mut buf);
+    s.write_all(&buf[..]).await
+}
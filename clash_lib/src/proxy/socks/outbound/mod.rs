@@ -14,18 +14,19 @@ use crate::{
     impl_default_connector,
     proxy::{
         AnyStream, ConnectorType, DialWithConnector, HandlerCommonOptions,
-        OutboundHandler, OutboundType,
-        transport::Transport,
+        OutboundHandler, OutboundType, PendingBind,
+        transport::{SessionStats, Transport, tls_in_tls},
         utils::{GLOBAL_DIRECT_CONNECTOR, RemoteConnector, new_udp_socket},
     },
-    session::Session,
+    session::{Session, SocksAddr},
 };
 
 use async_trait::async_trait;
 use datagram::Socks5Datagram;
+use futures::FutureExt;
 use tracing::{debug, trace};
 
-use super::socks5::{client_handshake, socks_command};
+use super::socks5::{client_handshake, read_reply, socks_command};
 
 #[derive(Default)]
 pub struct HandlerOptions {
@@ -68,44 +69,103 @@ impl Handler {
         s: AnyStream,
         sess: &Session,
     ) -> std::io::Result<AnyStream> {
+        let timeouts = &self.opts.common_opts.timeouts;
         let mut s = if let Some(tls_client) = self.opts.tls_client.as_ref() {
-            tls_client.proxy_stream(s).await?
+            timeouts.transport(tls_client.proxy_stream(s)).await?
         } else {
             s
         };
 
-        client_handshake(
-            &mut s,
-            &sess.destination,
-            socks_command::CONNECT,
-            self.opts.user.clone(),
-            self.opts.password.clone(),
-        )
-        .await?;
+        timeouts
+            .protocol(client_handshake(
+                &mut s,
+                &sess.destination,
+                socks_command::CONNECT,
+                self.opts.user.clone(),
+                self.opts.password.clone(),
+            ))
+            .await?;
 
         Ok(s)
     }
 
+    async fn inner_bind_stream(
+        &self,
+        s: AnyStream,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> std::io::Result<PendingBind> {
+        let timeouts = &self.opts.common_opts.timeouts;
+        let mut s = if let Some(tls_client) = self.opts.tls_client.as_ref() {
+            timeouts.transport(tls_client.proxy_stream(s)).await?
+        } else {
+            s
+        };
+
+        let bound = timeouts
+            .protocol(client_handshake(
+                &mut s,
+                &sess.destination,
+                socks_command::BIND,
+                self.opts.user.clone(),
+                self.opts.password.clone(),
+            ))
+            .await?;
+
+        // same as UDP associate, the server may not know its public address
+        let bound = match bound.ip() {
+            Some(ip) if ip.is_unspecified() => {
+                let server_ip = resolver
+                    .resolve(&self.opts.server, false)
+                    .await
+                    .map_err(|x| new_io_error(x.to_string()))?
+                    .ok_or(new_io_error("failed to resolve server address"))?;
+                (server_ip, bound.port()).into()
+            }
+            _ => bound,
+        };
+        trace!("upstream bound at {} for {}", bound, sess);
+
+        let name = self.name().to_owned();
+        let accept = async move {
+            let peer = read_reply(&mut s).await?;
+            let s = ChainedStreamWrapper::new(s);
+            s.append_to_chain(&name).await;
+            Ok::<(SocksAddr, BoxedChainedStream), std::io::Error>((
+                peer,
+                Box::new(s),
+            ))
+        };
+
+        Ok(PendingBind {
+            outbound: self.name().to_owned(),
+            bound,
+            accept: accept.boxed(),
+        })
+    }
+
     async fn inner_connect_datagram(
         &self,
         s: AnyStream,
         sess: &Session,
         resolver: ThreadSafeDNSResolver,
     ) -> std::io::Result<Socks5Datagram> {
+        let timeouts = &self.opts.common_opts.timeouts;
         let mut s = if let Some(tls_client) = self.opts.tls_client.as_ref() {
-            tls_client.proxy_stream(s).await?
+            timeouts.transport(tls_client.proxy_stream(s)).await?
         } else {
             s
         };
 
-        let bind_addr = client_handshake(
-            &mut s,
-            &sess.destination,
-            socks_command::UDP_ASSOCIATE,
-            self.opts.user.clone(),
-            self.opts.password.clone(),
-        )
-        .await?;
+        let bind_addr = timeouts
+            .protocol(client_handshake(
+                &mut s,
+                &sess.destination,
+                socks_command::UDP_ASSOCIATE,
+                self.opts.user.clone(),
+                self.opts.password.clone(),
+            ))
+            .await?;
 
         let bind_ip = bind_addr
             .ip()
@@ -153,6 +213,13 @@ impl OutboundHandler for Handler {
         OutboundType::Socks5
     }
 
+    fn tls_sessions(&self) -> Option<SessionStats> {
+        self.opts
+            .tls_client
+            .as_ref()
+            .and_then(|x| x.session_stats())
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -203,6 +270,35 @@ impl OutboundHandler for Handler {
         .await
     }
 
+    async fn bind_stream(
+        &self,
+        sess: &Session,
+        resolver: ThreadSafeDNSResolver,
+    ) -> std::io::Result<PendingBind> {
+        let dialer = self.connector.lock().await;
+        let connector = dialer
+            .as_ref()
+            .unwrap_or(&GLOBAL_DIRECT_CONNECTOR.clone())
+            .clone();
+        drop(dialer);
+
+        let s = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
+                resolver.clone(),
+                self.opts.server.as_str(),
+                self.opts.port,
+                sess.iface.as_ref(),
+                #[cfg(target_os = "linux")]
+                sess.so_mark,
+            ))
+            .await?;
+
+        self.inner_bind_stream(s, sess, resolver).await
+    }
+
     async fn support_connector(&self) -> ConnectorType {
         ConnectorType::All
     }
@@ -213,18 +309,30 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> std::io::Result<BoxedChainedStream> {
-        let s = connector
-            .connect_stream(
+        let s = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(target_os = "linux")]
                 sess.so_mark,
-            )
+            ))
             .await?;
 
         let s = self.inner_connect_stream(s, sess).await?;
+        let s = match self.opts.tls_client {
+            Some(_) => tls_in_tls::guard(
+                self.name(),
+                sess,
+                s,
+                self.opts.common_opts.tls_in_tls,
+            ),
+            None => s,
+        };
 
         let s = ChainedStreamWrapper::new(s);
         s.append_to_chain(self.name()).await;
@@ -237,15 +345,18 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> std::io::Result<BoxedChainedDatagram> {
-        let s = connector
-            .connect_stream(
+        let s = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
                 resolver.clone(),
                 self.opts.server.as_str(),
                 self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(target_os = "linux")]
                 sess.so_mark,
-            )
+            ))
             .await?;
 
         let d = self.inner_connect_datagram(s, sess, resolver).await?;
@@ -1,7 +1,7 @@
 use bytes::{BufMut, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::{common::errors::new_io_error, proxy::AnyStream, session::SocksAddr};
+use crate::{common::errors::NetError, proxy::AnyStream, session::SocksAddr};
 
 pub const SOCKS5_VERSION: u8 = 0x05;
 
@@ -16,7 +16,7 @@ pub(crate) mod auth_methods {
 
 pub(crate) mod socks_command {
     pub const CONNECT: u8 = 0x01;
-    // pub const BIND: u8 = 0x02;
+    pub const BIND: u8 = 0x02;
     pub const UDP_ASSOCIATE: u8 = 0x3;
 }
 
@@ -29,7 +29,7 @@ pub(crate) mod response_code {
     // pub const CONNECTION_REFUSED: u8 = 0x05;
     // pub const TTL_EXPIRED: u8 = 0x06;
     pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
-    // pub const ADDR_TYPE_NOT_SUPPORTED: u8 = 0x08;
+    pub const ADDR_TYPE_NOT_SUPPORTED: u8 = 0x08;
 }
 
 const ERROR_CODE_LOOKUP: &[&str] = &[
@@ -65,17 +65,17 @@ pub(crate) async fn client_handshake(
 
     s.read_exact(&mut buf[..2]).await?;
     if buf[0] != SOCKS5_VERSION {
-        return Err(new_io_error("unsupported SOCKS version"));
+        return Err(NetError::protocol("unsupported SOCKS version"));
     }
 
     let method = buf[1];
     if method == auth_methods::USER_PASS {
         let username = username
             .as_ref()
-            .ok_or_else(|| new_io_error("missing username"))?;
+            .ok_or_else(|| NetError::auth("missing username"))?;
         let password = password
             .as_ref()
-            .ok_or_else(|| new_io_error("missing password"))?;
+            .ok_or_else(|| NetError::auth("missing password"))?;
 
         let mut buf = BytesMut::with_capacity(MAX_AUTH_LEN);
         buf.put_u8(1);
@@ -88,10 +88,12 @@ pub(crate) async fn client_handshake(
         s.read_exact(&mut buf[..2]).await?;
 
         if buf[1] != response_code::SUCCEEDED {
-            return Err(new_io_error("SOCKS5 authentication failed"));
+            return Err(NetError::auth("SOCKS5 authentication failed"));
         }
     } else if method != auth_methods::NO_AUTH {
-        return Err(new_io_error("unsupported SOCKS5 authentication method"));
+        return Err(NetError::protocol(
+            "unsupported SOCKS5 authentication method",
+        ));
     }
 
     let mut buf = BytesMut::with_capacity(MAX_ADDR_LEN);
@@ -106,25 +108,33 @@ pub(crate) async fn client_handshake(
     }
     s.write_all(&buf).await?;
 
-    buf.resize(3, 0);
+    read_reply(s).await
+}
+
+/// Read a server reply, a BIND request gets a second one when the peer
+/// connected.
+pub(crate) async fn read_reply(s: &mut AnyStream) -> std::io::Result<SocksAddr> {
+    let mut buf = [0u8; 3];
     s.read_exact(&mut buf).await?;
 
     if buf[0] != SOCKS5_VERSION {
-        return Err(new_io_error("unsupported SOCKS version"));
+        return Err(NetError::protocol("unsupported SOCKS version"));
     }
 
     if buf[1] != response_code::SUCCEEDED {
-        return Err(new_io_error(
-            format!(
-                "SOCKS5 request failed with {}",
-                if buf[1] < ERROR_CODE_LOOKUP.len() as u8 {
-                    ERROR_CODE_LOOKUP[buf[1] as usize]
-                } else {
-                    "unknown error"
-                }
-            )
-            .as_str(),
-        ));
+        let msg = format!(
+            "SOCKS5 request failed with {}",
+            ERROR_CODE_LOOKUP
+                .get(buf[1] as usize)
+                .unwrap_or(&"unknown error")
+        );
+        // servers reply a general failure too when the destination is down,
+        // only the unsupported requests aren't about it
+        return Err(match buf[1] {
+            response_code::COMMAND_NOT_SUPPORTED
+            | response_code::ADDR_TYPE_NOT_SUPPORTED => NetError::protocol(msg),
+            _ => NetError::remote(msg),
+        });
     }
 
     SocksAddr::read_from(s).await
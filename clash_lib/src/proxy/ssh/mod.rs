@@ -21,7 +21,7 @@ use crate::{
         },
         dns::ThreadSafeDNSResolver,
     },
-    common::errors::new_io_error,
+    common::errors::{NetError, new_io_error},
     impl_default_connector,
     session::Session,
 };
@@ -191,23 +191,47 @@ impl OutboundHandler for Handler {
         });
         let sh = connector::Client { server_public_key };
 
-        let mut session =
-            client::connect(config, (self.opts.server.as_str(), self.opts.port), sh)
+        let timeouts = &self.opts.common_opts.timeouts;
+        // what russh's `client::connect` does, split to time the connection
+        // and the key exchange apart
+        let stream = timeouts
+            .connect(async {
+                tokio::net::TcpStream::connect((
+                    self.opts.server.as_str(),
+                    self.opts.port,
+                ))
                 .await
-                .map_err(|ssh_e| io::Error::new(io::ErrorKind::Other, ssh_e))?;
-
-        auth0(&mut session, &self.opts).await?;
-
-        let dst = sess.destination.clone();
-        let channel = session
-            .channel_open_direct_tcpip(
-                dst.host(),
-                sess.destination.port() as _,
-                "0.0.0.0",
-                0,
-            )
-            .await
-            .map_err(|ssh_e| io::Error::new(io::ErrorKind::Other, ssh_e))?;
+                .map_err(|e| {
+                    NetError::dial(format!(
+                        "ssh: {}:{}: {}",
+                        self.opts.server, self.opts.port, e
+                    ))
+                })
+            })
+            .await?;
+        let mut session = timeouts
+            .transport(async {
+                client::connect_stream(config, stream, sh)
+                    .await
+                    .map_err(|e| NetError::handshake(format!("ssh: {}", e)))
+            })
+            .await?;
+
+        let channel = timeouts
+            .protocol(async {
+                auth0(&mut session, &self.opts).await?;
+                session
+                    .channel_open_direct_tcpip(
+                        sess.destination.host(),
+                        sess.destination.port() as _,
+                        "0.0.0.0",
+                        0,
+                    )
+                    .await
+                    // the server failed to open the channel to the destination
+                    .map_err(|e| NetError::remote(format!("ssh: {}", e)))
+            })
+            .await?;
         let s = Box::new(ChannelStreamWrapper {
             inner: channel.into_stream(),
         });
@@ -237,7 +261,7 @@ async fn auth0(
         Ok(_) => Ok(()),
         Err(e) => {
             tracing::error!("ssh auth failed: {:?}", e);
-            Err(new_io_error("ssh auth failed"))
+            Err(NetError::auth("ssh auth failed"))
         }
     }
 }
@@ -1,6 +1,6 @@
 use super::{inbound::InboundHandlerTrait, tun::TunDatagram};
 use crate::{
-    app::dispatcher::Dispatcher,
+    app::{dispatcher::Dispatcher, handover},
     proxy::{datagram::UdpPacket, utils::apply_tcp_options},
     session::{Network, Session, Type},
 };
@@ -50,12 +50,20 @@ impl InboundHandlerTrait for TproxyInbound {
     }
 
     async fn listen_tcp(&self) -> anyhow::Result<()> {
-        let socket =
-            Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
-        socket.set_ip_transparent(true)?;
+        let key = handover::tcp_key(self.addr);
+        let socket = match handover::take_inherited(&key) {
+            Some(fd) => Socket::from(fd),
+            None => {
+                let socket =
+                    Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None)?;
+                socket.set_ip_transparent(true)?;
+                socket.bind(&self.addr.into())?;
+                socket.listen(1024)?;
+                socket
+            }
+        };
         socket.set_nonblocking(true)?;
-        socket.bind(&self.addr.into())?;
-        socket.listen(1024)?;
+        handover::register(key, &socket);
 
         let listener = TcpListener::from_std(socket.into())?;
 
@@ -90,7 +98,13 @@ impl InboundHandlerTrait for TproxyInbound {
     }
 
     async fn listen_udp(&self) -> anyhow::Result<()> {
-        let socket = Socket::new(Domain::IPV4, socket2::Type::DGRAM, None)?;
+        let key = handover::udp_key(self.addr);
+        let inherited = handover::take_inherited(&key);
+        let bound = inherited.is_some();
+        let socket = match inherited {
+            Some(fd) => Socket::from(fd),
+            None => Socket::new(Domain::IPV4, socket2::Type::DGRAM, None)?,
+        };
         socket.set_ip_transparent(true)?;
         socket.set_nonblocking(true)?;
         socket.set_broadcast(true)?;
@@ -106,7 +120,10 @@ impl InboundHandlerTrait for TproxyInbound {
                 std::mem::size_of_val(&enable) as libc::socklen_t,
             )
         };
-        socket.bind(&self.addr.into())?;
+        if !bound {
+            socket.bind(&self.addr.into())?;
+        }
+        handover::register(key, &socket);
 
         let listener = unix_udp_sock::UdpSocket::from_std(socket.into())?;
 
@@ -19,25 +19,54 @@ use tokio::{
 use tracing::warn;
 
 use super::Transport;
-use crate::{common::errors::map_io_error, proxy::AnyStream};
+use crate::{
+    common::errors::{NetError, map_io_error},
+    proxy::AnyStream,
+};
+
+/// the gRPC method the stream is carried over, servers usually implement
+/// only one of them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mode {
+    /// `Tun`, one `Hunk` per message
+    #[default]
+    Gun,
+    /// `TunMulti`, a `MultiHunk` per message with any number of chunks
+    Multi,
+}
 
 #[derive(Clone)]
 pub struct Client {
     pub host: String,
     pub path: http::uri::PathAndQuery,
+    pub mode: Mode,
+    pub user_agent: String,
+    pub initial_window_size: u32,
+    pub initial_connection_window_size: u32,
 }
 
 impl Client {
     pub fn new(host: String, path: http::uri::PathAndQuery) -> Self {
-        Self { host, path }
+        Self {
+            host,
+            path,
+            mode: Mode::Gun,
+            user_agent: "tonic/0.10".to_owned(),
+            initial_window_size: 0x7FFFFFFF,
+            initial_connection_window_size: 0x7FFFFFFF,
+        }
     }
 
     fn req(&self) -> io::Result<Request<()>> {
+        let method = match self.mode {
+            Mode::Gun => "Tun",
+            Mode::Multi => "TunMulti",
+        };
         let uri: Uri = {
             Uri::builder()
                 .scheme("https")
                 .authority(self.host.as_str())
-                .path_and_query(format!("/{}/Tun", self.path.as_str()))
+                .path_and_query(format!("/{}/{}", self.path.as_str(), method))
                 .build()
                 .map_err(map_io_error)?
         };
@@ -46,8 +75,8 @@ impl Client {
             .uri(uri)
             .version(Version::HTTP_2)
             .header("content-type", "application/grpc")
-            .header("user-agent", "tonic/0.10");
-        Ok(request.body(()).unwrap())
+            .header("user-agent", self.user_agent.as_str());
+        request.body(()).map_err(map_io_error)
     }
 }
 
@@ -55,14 +84,17 @@ impl Client {
 impl Transport for Client {
     async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
         let (client, h2) = h2::client::Builder::new()
-            .initial_connection_window_size(0x7FFFFFFF)
-            .initial_window_size(0x7FFFFFFF)
+            .initial_connection_window_size(self.initial_connection_window_size)
+            .initial_window_size(self.initial_window_size)
             .initial_max_send_streams(1024)
             .enable_push(false)
             .handshake(stream)
             .await
-            .map_err(map_io_error)?;
-        let mut client = client.ready().await.map_err(map_io_error)?;
+            .map_err(|e| NetError::handshake(format!("grpc: {}", e)))?;
+        let mut client = client
+            .ready()
+            .await
+            .map_err(|e| NetError::handshake(format!("grpc: {}", e)))?;
 
         let req = self.req()?;
         let (resp, send_stream) =
@@ -115,20 +147,99 @@ pub struct GrpcStream {
     init_ready: mpsc::Receiver<()>,
     recv: Arc<Mutex<Option<RecvStream>>>,
     send: SendStream<Bytes>,
-    buffer: BytesMut,
-    payload_len: usize,
+    decoder: Decoder,
 }
 
 impl Debug for GrpcStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("GrpcStream")
             .field("send", &self.send)
-            .field("buffer", &self.buffer)
-            .field("payload_len", &self.payload_len)
+            .field("decoder", &self.decoder)
             .finish()
     }
 }
 
+/// Takes the chunks out of received gRPC messages. A `Hunk` has one `data`
+/// field, a `MultiHunk` any number of them, both are handled the same.
+#[derive(Debug)]
+struct Decoder {
+    buffer: BytesMut,
+    /// what's left of the current gRPC message
+    message_len: usize,
+    /// what's left of the current `data` field
+    payload_len: usize,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(1024 * 4),
+            message_len: 0,
+            payload_len: 0,
+        }
+    }
+
+    /// moves buffered payload into `out`, returns false if nothing could be
+    /// moved until more is received
+    fn decode(&mut self, out: &mut tokio::io::ReadBuf<'_>) -> io::Result<bool> {
+        let mut decoded = false;
+        while out.remaining() > 0 {
+            if self.payload_len > 0 {
+                let n = self.payload_len.min(self.buffer.len()).min(out.remaining());
+                if n == 0 {
+                    break;
+                }
+                out.put_slice(&self.buffer.split_to(n));
+                self.payload_len -= n;
+                self.message_len -= n;
+                decoded = true;
+                continue;
+            }
+
+            if self.message_len == 0 {
+                // compressed flag and message length
+                if self.buffer.len() < 5 {
+                    break;
+                }
+                self.message_len =
+                    u32::from_be_bytes(self.buffer[1..5].try_into().unwrap())
+                        as usize;
+                self.buffer.advance(5);
+                continue;
+            }
+
+            // tag and length of a `data` field
+            let Some(&tag) = self.buffer.first() else {
+                break;
+            };
+            if tag != 0x0a {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unexpected grpc field tag {:#x}", tag),
+                ));
+            }
+            let mut header = &self.buffer[1..];
+            let len = match decode_varint(&mut header) {
+                Ok(len) => len as usize,
+                // the varint is cut off
+                Err(_) if self.buffer.len() < 11 => break,
+                Err(e) => return Err(map_io_error(e)),
+            };
+            let header_len = self.buffer.len() - header.len();
+            self.message_len = self
+                .message_len
+                .checked_sub(header_len + len)
+                .map(|rest| rest + len)
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, "grpc field exceeds message")
+                })?;
+            self.buffer.advance(header_len);
+            self.payload_len = len;
+        }
+        Ok(decoded)
+    }
+}
+
 impl GrpcStream {
     pub fn new(
         init_ready: mpsc::Receiver<()>,
@@ -139,8 +250,7 @@ impl GrpcStream {
             init_ready,
             recv,
             send,
-            buffer: BytesMut::with_capacity(1024 * 4),
-            payload_len: 0,
+            decoder: Decoder::new(),
         }
     }
 
@@ -175,83 +285,34 @@ impl AsyncRead for GrpcStream {
         let recv = self.recv.clone();
 
         let mut recv = recv.try_lock().unwrap();
-        if recv.is_none() {
+        let Some(recv) = recv.as_mut() else {
             warn!("grpc initialization error");
             return Poll::Ready(Err(Error::new(
                 ErrorKind::ConnectionReset,
                 "initialization error",
             )));
-        }
-
-        if (self.payload_len > 0 && !self.buffer.is_empty())
-            || (self.payload_len == 0 && self.buffer.len() > 6)
-        {
-            if self.payload_len == 0 {
-                self.buffer.advance(6);
-                let payload_len =
-                    decode_varint(&mut self.buffer).map_err(map_io_error)?;
-                self.payload_len = payload_len as usize;
-            }
-
-            let to_read = std::cmp::min(buf.remaining(), self.payload_len);
-            let to_read = std::cmp::min(to_read, self.buffer.len());
-
-            if to_read == 0 {
-                assert!(buf.remaining() > 0);
+        };
 
-                return Poll::Pending;
+        loop {
+            if self.decoder.decode(buf)? {
+                return Poll::Ready(Ok(()));
             }
 
-            let data = self.buffer.split_to(to_read);
-
-            self.payload_len -= to_read;
-            buf.put_slice(&data[..]);
-            return Poll::Ready(Ok(()));
-        }
-
-        match ready!(Pin::new(&mut recv.as_mut().unwrap()).poll_data(cx)) {
-            Some(Ok(b)) => {
-                self.buffer.reserve(b.len());
-                self.buffer.extend_from_slice(&b[..]);
-
-                while self.payload_len > 0 || self.buffer.len() > 6 {
-                    if self.payload_len == 0 {
-                        self.buffer.advance(6);
-                        let payload_len =
-                            decode_varint(&mut self.buffer).map_err(map_io_error)?;
-                        self.payload_len = payload_len as usize;
-                    }
-                    let to_read = std::cmp::min(self.buffer.len(), self.payload_len);
-                    let to_read = std::cmp::min(buf.remaining(), to_read);
-                    if to_read == 0 {
-                        break;
-                    }
-
-                    buf.put_slice(self.buffer.split_to(to_read).freeze().as_ref());
-                    self.payload_len -= to_read;
+            match ready!(recv.poll_data(cx)) {
+                Some(Ok(b)) => {
+                    recv.flow_control()
+                        .release_capacity(b.len())
+                        .map_err(|e| Error::new(ErrorKind::ConnectionReset, e))?;
+                    self.decoder.buffer.extend_from_slice(&b[..]);
                 }
-
-                recv.as_mut()
-                    .unwrap()
-                    .flow_control()
-                    .release_capacity(b.len())
-                    .map_or_else(
-                        |e| {
-                            Poll::Ready(Err(Error::new(
-                                ErrorKind::ConnectionReset,
-                                e,
-                            )))
-                        },
-                        |_| Poll::Ready(Ok(())),
-                    )
-            }
-            _ => {
-                assert_eq!(self.payload_len, 0);
-                if recv.as_mut().unwrap().is_end_stream() {
-                    Poll::Ready(Ok(()))
-                } else {
-                    Poll::Pending
+                Some(Err(e)) => {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::ConnectionReset,
+                        e,
+                    )));
                 }
+                // end of stream
+                None => return Poll::Ready(Ok(())),
             }
         }
     }
@@ -304,3 +365,63 @@ impl AsyncWrite for GrpcStream {
             .map(|_| Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut;
+    use prost::encoding::encode_varint;
+    use tokio::io::ReadBuf;
+
+    use super::Decoder;
+
+    fn message(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for chunk in chunks {
+            body.put_u8(0x0a);
+            encode_varint(chunk.len() as u64, &mut body);
+            body.put_slice(chunk);
+        }
+        let mut buf = vec![0u8];
+        buf.put_u32(body.len() as u32);
+        buf.extend(body);
+        buf
+    }
+
+    fn read_all(decoder: &mut Decoder) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut storage = [0u8; 3];
+        loop {
+            let mut buf = ReadBuf::new(&mut storage);
+            if !decoder.decode(&mut buf).unwrap() {
+                return out;
+            }
+            out.extend_from_slice(buf.filled());
+        }
+    }
+
+    #[test]
+    fn test_decode_hunks() {
+        let mut decoder = Decoder::new();
+        // a gun hunk and a multi hunk, in pieces
+        let mut input = message(&[b"hello"]);
+        input.extend(message(&[b" ", b"grpc", b"", b" world"]));
+        let (a, b) = input.split_at(9);
+
+        decoder.buffer.extend_from_slice(a);
+        let mut out = read_all(&mut decoder);
+        decoder.buffer.extend_from_slice(b);
+        out.extend(read_all(&mut decoder));
+
+        assert_eq!(out, b"hello grpc world");
+        assert!(decoder.buffer.is_empty());
+        assert_eq!(decoder.message_len, 0);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut decoder = Decoder::new();
+        decoder.buffer.extend_from_slice(&[0, 0, 0, 0, 2, 0x12, 0]);
+        let mut storage = [0u8; 8];
+        assert!(decoder.decode(&mut ReadBuf::new(&mut storage)).is_err());
+    }
+}
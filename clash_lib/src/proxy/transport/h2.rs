@@ -9,7 +9,10 @@ use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::error;
 
 use super::Transport;
-use crate::{common::errors::map_io_error, proxy::AnyStream};
+use crate::{
+    common::errors::{NetError, map_io_error},
+    proxy::AnyStream,
+};
 
 pub struct Client {
     pub hosts: Vec<String>,
@@ -62,8 +65,9 @@ impl Client {
 #[async_trait]
 impl Transport for Client {
     async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
-        let (mut client, h2) =
-            h2::client::handshake(stream).await.map_err(map_io_error)?;
+        let (mut client, h2) = h2::client::handshake(stream)
+            .await
+            .map_err(|e| NetError::handshake(format!("h2: {}", e)))?;
         let req = self.req()?;
         let (resp, send_stream) =
             client.send_request(req, false).map_err(map_io_error)?;
@@ -73,7 +77,10 @@ impl Transport for Client {
             }
         });
 
-        let recv_stream = resp.await.map_err(map_io_error)?.into_body();
+        let recv_stream = resp
+            .await
+            .map_err(|e| NetError::handshake(format!("h2: {}", e)))?
+            .into_body();
 
         Ok(Box::new(Http2Stream::new(recv_stream, send_stream)))
     }
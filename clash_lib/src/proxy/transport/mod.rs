@@ -4,17 +4,20 @@ mod shadow_tls;
 mod simple_obfs;
 mod sip003;
 mod tls;
+mod tls_fragment;
+pub mod tls_in_tls;
 mod v2ray;
 mod ws;
 
-pub use grpc::Client as GrpcClient;
+pub use grpc::{Client as GrpcClient, Mode as GrpcMode};
 pub use h2::Client as H2Client;
 pub use shadow_tls::Client as Shadowtls;
 pub use simple_obfs::*;
 pub use sip003::Plugin as Sip003Plugin;
-pub use tls::Client as TlsClient;
+pub use tls::{Client as TlsClient, SessionStats};
+pub use tls_fragment::TlsFragment;
 pub use v2ray::{V2RayOBFSOption, V2rayWsClient};
-pub use ws::Client as WsClient;
+pub use ws::{Client as WsClient, check_path as check_ws_path};
 
 #[async_trait::async_trait]
 pub trait Transport: Send + Sync {
@@ -22,4 +25,14 @@ pub trait Transport: Send + Sync {
         &self,
         stream: super::AnyStream,
     ) -> std::io::Result<super::AnyStream>;
+
+    /// the resumption of the TLS sessions, for the transports with TLS
+    fn session_stats(&self) -> Option<SessionStats> {
+        None
+    }
+
+    /// whether the stream is wrapped in TLS, or what passes for it on the wire
+    fn is_tls(&self) -> bool {
+        false
+    }
 }
@@ -116,6 +116,10 @@ impl Transport for Client {
     async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
         self.wrap_shadow_tls_stream(stream).await
     }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
 }
 
 fn new_connector() -> TlsConnector {
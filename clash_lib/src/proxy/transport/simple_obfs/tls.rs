@@ -32,6 +32,10 @@ impl Transport for Client {
     async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
         Ok(TLSObfs::new(stream, self.server.clone()).into())
     }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Debug)]
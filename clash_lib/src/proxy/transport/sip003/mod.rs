@@ -6,6 +6,8 @@ use crate::proxy::AnyStream;
 #[async_trait]
 pub trait Plugin: Send + Sync {
     async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream>;
+
+    fn is_tls(&self) -> bool;
 }
 
 #[async_trait]
@@ -13,4 +15,8 @@ impl<T: Transport> Plugin for T {
     async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
         Transport::proxy_stream(self, stream).await
     }
+
+    fn is_tls(&self) -> bool {
+        Transport::is_tls(self)
+    }
 }
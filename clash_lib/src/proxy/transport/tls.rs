@@ -1,11 +1,28 @@
 use async_trait::async_trait;
+use rustls::{
+    NamedGroup,
+    client::{
+        ClientSessionMemoryCache, ClientSessionStore, Resumption,
+        Tls12ClientSessionValue, Tls13ClientSessionValue,
+    },
+    pki_types::ServerName,
+};
 use serde::Serialize;
-use std::{io, sync::Arc};
+use std::{
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
-use super::Transport;
+use super::{
+    Transport,
+    tls_fragment::{FragmentStream, TlsFragment},
+};
 use crate::{
     common::{
-        errors::map_io_error,
+        errors::{NetError, map_io_error},
         tls::{DefaultTlsVerifier, GLOBAL_ROOT_STORE},
     },
     proxy::AnyStream,
@@ -18,6 +35,122 @@ pub struct TLSOptions {
     pub alpn: Option<Vec<String>>,
 }
 
+/// sessions kept for each proxy, a few per server name
+const SESSIONS_PER_PROXY: usize = 32;
+
+/// How often the handshakes of a proxy had a session to resume, for the API
+#[derive(Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// `hits / (hits + misses)`, 0 before the first handshake
+    pub hit_rate: f64,
+}
+
+impl SessionStats {
+    fn new(hits: u64, misses: u64) -> Self {
+        Self {
+            hits,
+            misses,
+            hit_rate: if hits + misses > 0 {
+                hits as f64 / (hits + misses) as f64
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// the stats of two caches together, e.g. the endpoints of a proxy
+    pub fn merge(self, other: Self) -> Self {
+        Self::new(self.hits + other.hits, self.misses + other.misses)
+    }
+}
+
+/// The TLS sessions of a proxy, for its next connections to resume instead
+/// of a full handshake. The config of a connection is built for each one, so
+/// the cache rustls keeps in it would never be hit
+#[derive(Debug)]
+pub struct SessionCache {
+    inner: ClientSessionMemoryCache,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl SessionCache {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            inner: ClientSessionMemoryCache::new(SESSIONS_PER_PROXY),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        SessionStats::new(
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn count(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// rustls looks for a TLS 1.3 ticket first and for a TLS 1.2 session only
+// without one, so a handshake is a miss once neither is found
+impl ClientSessionStore for SessionCache {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.inner.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.inner.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls12ClientSessionValue,
+    ) {
+        self.inner.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(
+        &self,
+        server_name: &ServerName<'_>,
+    ) -> Option<Tls12ClientSessionValue> {
+        let session = self.inner.tls12_session(server_name);
+        self.count(session.is_some());
+        session
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.inner.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.inner.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        let ticket = self.inner.take_tls13_ticket(server_name);
+        if ticket.is_some() {
+            self.count(true);
+        }
+        ticket
+    }
+}
+
 impl From<TLSOptions> for Client {
     fn from(opt: TLSOptions) -> Self {
         Self::new(opt.skip_cert_verify, opt.sni, opt.alpn, None)
@@ -29,6 +162,9 @@ pub struct Client {
     pub sni: String,
     pub alpn: Option<Vec<String>>,
     pub expected_alpn: Option<String>,
+    /// the fragmentation and padding of the ClientHello
+    pub fragment: Option<TlsFragment>,
+    sessions: Arc<SessionCache>,
 }
 
 impl Client {
@@ -43,6 +179,8 @@ impl Client {
             sni,
             alpn,
             expected_alpn,
+            fragment: None,
+            sessions: SessionCache::new(),
         }
     }
 }
@@ -60,34 +198,85 @@ impl Transport for Client {
             .into_iter()
             .map(|x| x.as_bytes().to_vec())
             .collect();
+        if let Some(fragment) = self.fragment
+            && !tls_config.alpn_protocols.is_empty()
+        {
+            tls_config
+                .alpn_protocols
+                .extend(fragment.padding_protocols());
+        }
 
         tls_config.dangerous().set_certificate_verifier(Arc::new(
             DefaultTlsVerifier::new(None, self.skip_cert_verify),
         ));
 
         tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+        tls_config.resumption = Resumption::store(self.sessions.clone());
 
         let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config));
-        let dns_name =
-            rustls::pki_types::ServerName::try_from(self.sni.as_str().to_owned())
-                .map_err(map_io_error)?;
-
-        let c = connector.connect(dns_name, stream).await.and_then(|x| {
-            if let Some(expected_alpn) = self.expected_alpn.as_ref() {
-                if x.get_ref().1.alpn_protocol() != Some(expected_alpn.as_bytes()) {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!(
+        let dns_name = ServerName::try_from(self.sni.as_str().to_owned())
+            .map_err(map_io_error)?;
+
+        let stream = match self.fragment {
+            Some(fragment) => Box::new(FragmentStream::new(stream, fragment)) as _,
+            None => stream,
+        };
+
+        let c = connector
+            .connect(dns_name, stream)
+            .await
+            .map_err(|e| NetError::handshake(format!("tls: {}", e)))
+            .and_then(|x| {
+                if let Some(expected_alpn) = self.expected_alpn.as_ref() {
+                    if x.get_ref().1.alpn_protocol()
+                        != Some(expected_alpn.as_bytes())
+                    {
+                        return Err(NetError::handshake(format!(
                             "unexpected alpn protocol: {:?}, expected: {:?}",
                             x.get_ref().1.alpn_protocol(),
                             expected_alpn
-                        ),
-                    ));
+                        )));
+                    }
                 }
-            }
 
-            Ok(x)
-        });
+                Ok(x)
+            });
         c.map(|x| Box::new(x) as _)
     }
+
+    fn session_stats(&self) -> Option<SessionStats> {
+        Some(self.sessions.stats())
+    }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::{client::ClientSessionStore, pki_types::ServerName};
+
+    use super::{SessionCache, SessionStats};
+
+    #[test]
+    fn test_session_stats() {
+        let cache = SessionCache::new();
+        assert_eq!(cache.stats(), SessionStats::default());
+
+        let name = ServerName::try_from("example.org").unwrap();
+        // a handshake without a ticket falls back to a TLS 1.2 session
+        assert!(cache.take_tls13_ticket(&name).is_none());
+        assert!(cache.tls12_session(&name).is_none());
+        assert_eq!(cache.stats().misses, 1);
+        assert_eq!(cache.stats().hits, 0);
+
+        let merged = SessionStats {
+            hits: 3,
+            ..Default::default()
+        }
+        .merge(cache.stats());
+        assert_eq!((merged.hits, merged.misses), (3, 1));
+        assert_eq!(merged.hit_rate, 0.75);
+    }
 }
@@ -0,0 +1,359 @@
+//! Splits the ClientHello into several TLS records written apart, so the SNI
+//! isn't found by middleboxes that only look at the first packet, and pads it
+//! so that its size gives nothing away either.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::Future,
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::ready;
+use rand::distr::{Alphanumeric, Distribution};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{common::utils::rand_range, proxy::AnyStream};
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+/// the longest ALPN protocol name
+const MAX_PROTOCOL_LEN: usize = 255;
+const MAX_PADDING: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TlsFragment {
+    /// bytes of the ClientHello per record
+    pub size: (usize, usize),
+    /// milliseconds between records
+    pub interval: (u64, u64),
+    /// bytes added to the ClientHello of the TLS clients that offer ALPN
+    pub padding: Option<(usize, usize)>,
+}
+
+impl TlsFragment {
+    /// both are a number or a range like `10-30`, the interval defaults to 0
+    pub fn parse(size: &str, interval: Option<&str>) -> Result<Self, String> {
+        let size = parse_range::<usize>(size)
+            .filter(|(min, _)| *min > 0)
+            .ok_or_else(|| format!("invalid tls fragment size: {}", size))?;
+        let interval = match interval {
+            Some(interval) => parse_range::<u64>(interval).ok_or_else(|| {
+                format!("invalid tls fragment interval: {}", interval)
+            })?,
+            None => (0, 0),
+        };
+        Ok(Self {
+            size,
+            interval,
+            padding: None,
+        })
+    }
+
+    /// a number or a range like `100-200`, up to 4096
+    pub fn with_padding(mut self, padding: &str) -> Result<Self, String> {
+        let padding = parse_range::<usize>(padding)
+            .filter(|(_, max)| *max <= MAX_PADDING)
+            .ok_or_else(|| format!("invalid tls fragment padding: {}", padding))?;
+        self.padding = Some(padding);
+        Ok(self)
+    }
+
+    /// ALPN protocols no server knows, offered after the real ones. rustls
+    /// can't add a padding extension, and the ClientHello can't be changed
+    /// once it's in the handshake transcript, so the padding goes there.
+    /// a server only refuses the unknown protocols if it shares none of the
+    /// others, so there must be some.
+    pub fn padding_protocols(&self) -> Vec<Vec<u8>> {
+        let Some(padding) = self.padding else {
+            return vec![];
+        };
+        let mut n = rand_range(padding.0..=padding.1);
+        let mut protocols = vec![];
+        while n > 0 {
+            let len = n.min(MAX_PROTOCOL_LEN);
+            protocols.push(
+                Alphanumeric
+                    .sample_iter(rand::rng())
+                    .take(len)
+                    .map(|c| c.to_ascii_lowercase())
+                    .collect(),
+            );
+            n -= len;
+        }
+        protocols
+    }
+
+    /// the handshake payload of `record` in records of random sizes
+    fn split(&self, record: &[u8]) -> VecDeque<Vec<u8>> {
+        let (header, mut payload) = record.split_at(RECORD_HEADER_LEN);
+        let mut records = VecDeque::new();
+        while !payload.is_empty() {
+            let n = rand_range(self.size.0..=self.size.1).min(payload.len());
+            let (chunk, rest) = payload.split_at(n);
+            let mut r = Vec::with_capacity(RECORD_HEADER_LEN + n);
+            r.extend_from_slice(&header[..3]);
+            r.extend_from_slice(&(n as u16).to_be_bytes());
+            r.extend_from_slice(chunk);
+            records.push_back(r);
+            payload = rest;
+        }
+        records
+    }
+}
+
+fn parse_range<T: FromStr + PartialOrd + Copy>(s: &str) -> Option<(T, T)> {
+    let (min, max) = match s.split_once('-') {
+        Some((min, max)) => (min.trim().parse().ok()?, max.trim().parse().ok()?),
+        None => {
+            let v = s.trim().parse().ok()?;
+            (v, v)
+        }
+    };
+    (min <= max).then_some((min, max))
+}
+
+enum State {
+    /// collecting the first record
+    Hello(Vec<u8>),
+    /// writing out the fragments
+    Draining,
+    Done,
+}
+
+pub struct FragmentStream {
+    inner: AnyStream,
+    fragment: TlsFragment,
+    state: State,
+    queue: VecDeque<Vec<u8>>,
+    /// written bytes of the front of `queue`
+    written: usize,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Debug for FragmentStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FragmentStream")
+            .field("inner", &self.inner)
+            .field("fragment", &self.fragment)
+            .finish()
+    }
+}
+
+impl FragmentStream {
+    pub fn new(inner: AnyStream, fragment: TlsFragment) -> Self {
+        Self {
+            inner,
+            fragment,
+            state: State::Hello(Vec::new()),
+            queue: VecDeque::new(),
+            written: 0,
+            delay: None,
+        }
+    }
+
+    /// queues the fragments once the first record is complete
+    fn collect(&mut self, buf: &[u8]) {
+        let State::Hello(hello) = &mut self.state else {
+            return;
+        };
+        hello.extend_from_slice(buf);
+        if hello.len() < RECORD_HEADER_LEN {
+            return;
+        }
+        if hello[0] != CONTENT_TYPE_HANDSHAKE {
+            self.queue.push_back(std::mem::take(hello));
+            self.state = State::Draining;
+            return;
+        }
+        let len =
+            RECORD_HEADER_LEN + u16::from_be_bytes([hello[3], hello[4]]) as usize;
+        if hello.len() < len {
+            return;
+        }
+        let rest = hello.split_off(len);
+        self.queue = self.fragment.split(hello);
+        if !rest.is_empty() {
+            self.queue.push_back(rest);
+        }
+        self.state = State::Draining;
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let State::Hello(hello) = &mut self.state {
+            // flushed before the record is complete, sent as is
+            if !hello.is_empty() {
+                self.queue.push_back(std::mem::take(hello));
+            }
+            self.state = State::Draining;
+        }
+
+        loop {
+            if let Some(delay) = self.delay.as_mut() {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+
+            let Some(front) = self.queue.front() else {
+                self.state = State::Done;
+                return Poll::Ready(Ok(()));
+            };
+            while self.written < front.len() {
+                let n = ready!(
+                    Pin::new(&mut self.inner).poll_write(cx, &front[self.written..])
+                )?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.written += n;
+            }
+            // each record in its own segment
+            ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+            self.queue.pop_front();
+            self.written = 0;
+
+            if !self.queue.is_empty() && self.fragment.interval.1 > 0 {
+                let ms =
+                    rand_range(self.fragment.interval.0..=self.fragment.interval.1);
+                self.delay =
+                    Some(Box::pin(tokio::time::sleep(Duration::from_millis(ms))));
+            }
+        }
+    }
+}
+
+impl AsyncRead for FragmentStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for FragmentStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.as_mut().get_mut();
+        match this.state {
+            State::Hello(_) => {
+                this.collect(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+            State::Draining => {
+                ready!(this.poll_drain(cx))?;
+                Pin::new(&mut this.inner).poll_write(cx, buf)
+            }
+            State::Done => Pin::new(&mut this.inner).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.as_mut().get_mut();
+        if !matches!(this.state, State::Done) {
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.as_mut().get_mut();
+        if !matches!(this.state, State::Done) {
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{FragmentStream, TlsFragment, parse_range};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_range::<usize>("10-30"), Some((10, 30)));
+        assert_eq!(parse_range::<usize>(" 5 "), Some((5, 5)));
+        assert_eq!(parse_range::<usize>("30-10"), None);
+        assert!(TlsFragment::parse("0", None).is_err());
+        assert_eq!(
+            TlsFragment::parse("1-5", Some("10")).unwrap(),
+            TlsFragment {
+                size: (1, 5),
+                interval: (10, 10),
+                padding: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_padding() {
+        let fragment = TlsFragment::parse("10", None).unwrap();
+        assert!(fragment.padding_protocols().is_empty());
+        assert!(fragment.with_padding("5000").is_err());
+
+        let fragment = fragment.with_padding("300-400").unwrap();
+        let protocols = fragment.padding_protocols();
+        assert_eq!(protocols.len(), 2);
+        assert_eq!(protocols[0].len(), 255);
+        let total = protocols.iter().map(Vec::len).sum::<usize>();
+        assert!((300..=400).contains(&total));
+        assert!(
+            protocols
+                .iter()
+                .flatten()
+                .all(|c| c.is_ascii_alphanumeric())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fragment_client_hello() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = FragmentStream::new(
+            Box::new(client),
+            TlsFragment::parse("3-7", Some("0-1")).unwrap(),
+        );
+
+        let payload = (0..100u8).collect::<Vec<_>>();
+        let mut hello = vec![0x16, 0x03, 0x01, 0, payload.len() as u8];
+        hello.extend_from_slice(&payload);
+        stream.write_all(&hello).await.unwrap();
+        stream.write_all(b"after").await.unwrap();
+        stream.flush().await.unwrap();
+        drop(stream);
+
+        let mut out = Vec::new();
+        server.read_to_end(&mut out).await.unwrap();
+
+        // the payload is intact over records of the given sizes
+        let mut reassembled = Vec::new();
+        let mut rest = &out[..];
+        while rest[0] == 0x16 {
+            assert_eq!(&rest[1..3], &[0x03, 0x01]);
+            let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            assert!((1..=7).contains(&len));
+            reassembled.extend_from_slice(&rest[5..5 + len]);
+            rest = &rest[5 + len..];
+            if reassembled.len() == payload.len() {
+                break;
+            }
+        }
+        assert_eq!(reassembled, payload);
+        assert_eq!(rest, b"after");
+    }
+}
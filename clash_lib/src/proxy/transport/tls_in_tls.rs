@@ -0,0 +1,99 @@
+//! TLS in TLS: the TLS of a client to its destination, tunneled through a
+//! proxy that is over TLS itself. The records of the inner handshake show
+//! through the outer ones with telltale sizes and timing, which some firewalls
+//! use to spot proxies. Splitting the inner ClientHello, as `tls-fragment`
+//! does for the outer one, breaks the pattern up.
+
+use std::{
+    collections::HashSet,
+    sync::{LazyLock, Mutex},
+};
+
+use tracing::{debug, warn};
+
+use super::tls_fragment::{FragmentStream, TlsFragment};
+use crate::{proxy::AnyStream, session::Session};
+
+/// the proxies warned about, once each
+static WARNED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// Whether the session is likely TLS of its own, i.e. HTTPS
+pub fn likely(sess: &Session) -> bool {
+    sess.destination.port() == 443
+}
+
+/// Annotates the sessions through the TLS of `proxy` that are likely TLS in
+/// TLS, and splits their ClientHello by `mitigation` if set
+pub fn guard(
+    proxy: &str,
+    sess: &Session,
+    stream: AnyStream,
+    mitigation: Option<TlsFragment>,
+) -> AnyStream {
+    if !likely(sess) {
+        return stream;
+    }
+    match mitigation {
+        Some(fragment) => {
+            debug!(
+                "{} is likely TLS in the TLS of {}, splitting its ClientHello",
+                sess, proxy
+            );
+            Box::new(FragmentStream::new(stream, fragment))
+        }
+        None => {
+            if WARNED.lock().unwrap().insert(proxy.to_owned()) {
+                warn!(
+                    "{} is likely TLS in the TLS of {}, a pattern some firewalls \
+                     detect proxies by. set `tls-in-tls-fragment` on the proxy to \
+                     split the inner ClientHello",
+                    sess, proxy
+                );
+            } else {
+                debug!("{} is likely TLS in the TLS of {}", sess, proxy);
+            }
+            stream
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{TlsFragment, guard, likely};
+    use crate::session::{Session, SocksAddr};
+
+    #[tokio::test]
+    async fn test_tls_in_tls_guard() {
+        let plain = Session {
+            destination: SocksAddr::Domain("example.com".to_owned(), 80),
+            ..Default::default()
+        };
+        let https = Session {
+            destination: SocksAddr::Domain("example.com".to_owned(), 443),
+            ..Default::default()
+        };
+        assert!(!likely(&plain));
+        assert!(likely(&https));
+
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut stream = guard(
+            "trojan",
+            &https,
+            Box::new(client),
+            Some(TlsFragment::parse("10-20", None).unwrap()),
+        );
+        let mut hello = vec![0x16, 0x03, 0x01, 0, 100];
+        hello.extend_from_slice(&[0; 100]);
+        stream.write_all(&hello).await.unwrap();
+        stream.flush().await.unwrap();
+        drop(stream);
+
+        let mut out = Vec::new();
+        server.read_to_end(&mut out).await.unwrap();
+        // the inner ClientHello went out as several records
+        assert!(out.len() >= hello.len() + 5 * 4);
+        assert!(u16::from_be_bytes([out[3], out[4]]) <= 20);
+    }
+}
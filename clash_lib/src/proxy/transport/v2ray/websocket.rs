@@ -107,4 +107,8 @@ impl Transport for V2rayWsClient {
     async fn proxy_stream(&self, stream: AnyStream) -> std::io::Result<AnyStream> {
         self.new_v2ray_websocket_stream(stream).await
     }
+
+    fn is_tls(&self) -> bool {
+        self.tls_client.is_some()
+    }
 }
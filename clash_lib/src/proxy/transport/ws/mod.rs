@@ -7,7 +7,7 @@ use tokio_tungstenite::{
 };
 
 use super::Transport;
-use crate::{common::errors::map_io_error, proxy::AnyStream};
+use crate::{common::errors::NetError, proxy::AnyStream};
 
 mod websocket;
 mod websocket_early_data;
@@ -15,6 +15,13 @@ mod websocket_early_data;
 pub use websocket::WebsocketConn;
 pub use websocket_early_data::WebsocketEarlyDataConn;
 
+/// the early data header of `?ed=` paths
+const ED_HEADER_NAME: &str = "Sec-WebSocket-Protocol";
+
+/// the placeholders of a path, replaced for each connection by the `Host`
+/// header (or the server), the port and 16 random hex digits
+const PLACEHOLDERS: [&str; 3] = ["{host}", "{port}", "{rand}"];
+
 pub struct Client {
     server: String,
     port: u16,
@@ -35,6 +42,13 @@ impl Client {
         max_early_data: usize,
         early_data_header_name: String,
     ) -> Self {
+        let (path, max_early_data, early_data_header_name) =
+            match split_early_data(&path) {
+                Some((path, ed)) if max_early_data == 0 => {
+                    (path, ed, ED_HEADER_NAME.to_owned())
+                }
+                _ => (path, max_early_data, early_data_header_name),
+            };
         Self {
             server,
             port,
@@ -53,16 +67,81 @@ impl Client {
             .header("Upgrade", "websocket")
             .header("Sec-WebSocket-Version", "13")
             .header("Sec-WebSocket-Key", generate_key())
-            .uri(format!("ws://{}:{}{}", self.server, self.port, self.path));
+            .uri(format!(
+                "ws://{}:{}{}",
+                self.server,
+                self.port,
+                self.expand_path()
+            ));
         for (k, v) in self.headers.iter() {
             request = request.header(k.as_str(), v.as_str());
         }
-        if self.max_early_data > 0 {
+        if self.max_early_data > 0 && !self.early_data_header_name.is_empty() {
             // we will replace this field later
             request = request.header(self.early_data_header_name.as_str(), "xxoo");
         }
         request.body(()).unwrap()
     }
+
+    fn expand_path(&self) -> String {
+        if !self.path.contains('{') {
+            return self.path.clone();
+        }
+        let host = self
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("host"))
+            .map_or(self.server.as_str(), |(_, v)| v.as_str());
+        self.path
+            .replace("{host}", host)
+            .replace("{port}", &self.port.to_string())
+            .replace("{rand}", &format!("{:016x}", rand::random::<u64>()))
+    }
+}
+
+/// Fails on an unknown placeholder or an unclosed `{` in `path`
+pub fn check_path(path: &str) -> Result<(), String> {
+    let mut rest = path;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed `{{` in ws path {}", path))?;
+        let placeholder = &rest[start..=start + end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(format!(
+                "unknown placeholder {} in ws path {}, expected one of {}",
+                placeholder,
+                path,
+                PLACEHOLDERS.join(", ")
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+/// a path like `/ws?ed=2048`, as shared by many servers, asks for up to 2048
+/// bytes of early data in the `Sec-WebSocket-Protocol` header. returns the
+/// path without the `ed` parameter and the size.
+fn split_early_data(path: &str) -> Option<(String, usize)> {
+    let (base, query) = path.split_once('?')?;
+    let mut ed = None;
+    let rest = query
+        .split('&')
+        .filter(|kv| match kv.strip_prefix("ed=") {
+            Some(v) => {
+                ed = v.parse::<usize>().ok();
+                false
+            }
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    let ed = ed.filter(|ed| *ed > 0)?;
+    if rest.is_empty() {
+        Some((base.to_owned(), ed))
+    } else {
+        Some((format!("{}?{}", base, rest.join("&")), ed))
+    }
 }
 
 #[async_trait]
@@ -82,15 +161,69 @@ impl Transport for Client {
             let (stream, resp) =
                 client_async_with_config(req, stream, self.ws_config)
                     .await
-                    .map_err(map_io_error)?;
+                    .map_err(|e| NetError::handshake(format!("websocket: {}", e)))?;
 
             if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "invalid response",
-                ));
+                return Err(NetError::handshake(format!(
+                    "websocket: invalid response {}",
+                    resp.status()
+                )));
             }
             Ok(Box::new(WebsocketConn::from_websocket(stream)))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Client, check_path, split_early_data};
+
+    #[test]
+    fn test_path_template() {
+        assert!(check_path("/ws/{host}/{port}?id={rand}").is_ok());
+        assert!(check_path("/ws/{uuid}").is_err());
+        assert!(check_path("/ws/{host").is_err());
+
+        let client = Client::new(
+            "10.0.0.1".to_owned(),
+            443,
+            "/{host}/{port}/{rand}".to_owned(),
+            HashMap::from([("host".to_owned(), "cdn.example.com".to_owned())]),
+            None,
+            0,
+            String::new(),
+        );
+        let path = client.expand_path();
+        let rand = path.strip_prefix("/cdn.example.com/443/").unwrap();
+        assert_eq!(rand.len(), 16);
+        assert!(rand.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(client.expand_path(), path);
+
+        let client = Client::new(
+            "10.0.0.1".to_owned(),
+            80,
+            "/{host}".to_owned(),
+            HashMap::new(),
+            None,
+            0,
+            String::new(),
+        );
+        assert_eq!(client.req().uri(), "ws://10.0.0.1:80/10.0.0.1");
+    }
+
+    #[test]
+    fn test_split_early_data() {
+        assert_eq!(split_early_data("/ws"), None);
+        assert_eq!(split_early_data("/ws?ed=abc"), None);
+        assert_eq!(
+            split_early_data("/ws?ed=2048"),
+            Some(("/ws".to_owned(), 2048))
+        );
+        assert_eq!(
+            split_early_data("/ws?a=1&ed=2048&b=2"),
+            Some(("/ws?a=1&b=2".to_owned(), 2048))
+        );
+    }
+}
@@ -7,7 +7,7 @@ use std::{
 
 use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use futures::{Future, ready};
-use http::{HeaderValue, Request, StatusCode};
+use http::{HeaderValue, Request, StatusCode, Uri};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::{
     client_async_with_config, tungstenite::protocol::WebSocketConfig,
@@ -108,6 +108,17 @@ impl WebsocketEarlyDataConn {
     }
 }
 
+/// early data goes at the end of the path if there is no header for it
+fn append_to_path(uri: &Uri, data: &str) -> std::io::Result<Uri> {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}{}?{}", uri.path(), data, query),
+        None => format!("{}{}", uri.path(), data),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().map_err(map_io_error)?);
+    Uri::from_parts(parts).map_err(map_io_error)
+}
+
 impl AsyncRead for WebsocketEarlyDataConn {
     fn poll_read(
         mut self: Pin<&mut Self>,
@@ -154,15 +165,17 @@ impl AsyncWrite for WebsocketEarlyDataConn {
                     _ => {
                         let mut req =
                             self.as_mut().req.take().expect("req must be present");
-                        if let Some(v) = req
+                        self.as_mut().early_data_len =
+                            cmp::min(self.as_mut().early_data_len, buf.len());
+                        let early_data = URL_SAFE_NO_PAD
+                            .encode(&buf[..self.as_mut().early_data_len]);
+                        if self.early_data_header_name.is_empty() {
+                            *req.uri_mut() = append_to_path(req.uri(), &early_data)?;
+                        } else if let Some(v) = req
                             .headers_mut()
                             .get_mut(&self.as_mut().early_data_header_name)
                         {
-                            self.as_mut().early_data_len =
-                                cmp::min(self.as_mut().early_data_len, buf.len());
-                            let header_value = URL_SAFE_NO_PAD
-                                .encode(&buf[..self.as_mut().early_data_len]);
-                            *v = HeaderValue::from_str(&header_value)
+                            *v = HeaderValue::from_str(&early_data)
                                 .expect("bad header value");
                         }
 
@@ -212,3 +225,24 @@ impl AsyncWrite for WebsocketEarlyDataConn {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::Uri;
+
+    use super::append_to_path;
+
+    #[test]
+    fn test_append_to_path() {
+        let uri: Uri = "ws://example.com:443/ws?a=1".parse().unwrap();
+        assert_eq!(
+            append_to_path(&uri, "AAEC").unwrap().to_string(),
+            "ws://example.com:443/wsAAEC?a=1"
+        );
+        let uri: Uri = "ws://example.com:443/".parse().unwrap();
+        assert_eq!(
+            append_to_path(&uri, "AAEC").unwrap().to_string(),
+            "ws://example.com:443/AAEC"
+        );
+    }
+}
@@ -24,7 +24,7 @@ use self::datagram::OutboundDatagramTrojan;
 use super::{
     AnyStream, ConnectorType, DialWithConnector, HandlerCommonOptions,
     OutboundHandler, OutboundType,
-    transport::Transport,
+    transport::{SessionStats, Transport, tls_in_tls},
     utils::{GLOBAL_DIRECT_CONNECTOR, RemoteConnector},
 };
 
@@ -74,14 +74,15 @@ impl Handler {
         sess: &Session,
         udp: bool,
     ) -> io::Result<AnyStream> {
+        let timeouts = &self.opts.common_opts.timeouts;
         let s = if let Some(tls_client) = self.opts.tls.as_ref() {
-            tls_client.proxy_stream(s).await?
+            timeouts.transport(tls_client.proxy_stream(s)).await?
         } else {
             s
         };
 
         let mut s = if let Some(transport) = self.opts.transport.as_ref() {
-            transport.proxy_stream(s).await?
+            timeouts.transport(transport.proxy_stream(s)).await?
         } else {
             s
         };
@@ -94,7 +95,7 @@ impl Handler {
         buf.put_u8(if udp { 0x03 } else { 0x01 });
         sess.destination.write_buf(&mut buf);
         buf.put_slice(b"\r\n");
-        s.write_all(&buf).await?;
+        timeouts.protocol(s.write_all(&buf)).await?;
 
         Ok(s)
     }
@@ -110,6 +111,10 @@ impl OutboundHandler for Handler {
         OutboundType::Trojan
     }
 
+    fn tls_sessions(&self) -> Option<SessionStats> {
+        self.opts.tls.as_ref().and_then(|x| x.session_stats())
+    }
+
     async fn support_udp(&self) -> bool {
         self.opts.udp
     }
@@ -168,18 +173,30 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = connector
-            .connect_stream(
+        let stream = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(target_os = "linux")]
                 sess.so_mark,
-            )
+            ))
             .await?;
 
         let s = self.inner_proxy_stream(stream, sess, false).await?;
+        let s = match self.opts.tls {
+            Some(_) => tls_in_tls::guard(
+                self.name(),
+                sess,
+                s,
+                self.opts.common_opts.tls_in_tls,
+            ),
+            None => s,
+        };
         let chained = ChainedStreamWrapper::new(s);
         chained.append_to_chain(self.name()).await;
         Ok(Box::new(chained))
@@ -191,15 +208,18 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedDatagram> {
-        let stream = connector
-            .connect_stream(
+        let stream = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(target_os = "linux")]
                 sess.so_mark,
-            )
+            ))
             .await?;
 
         let stream = self.inner_proxy_stream(stream, sess, true).await?;
@@ -4,8 +4,11 @@ mod handle_task;
 pub(crate) mod types;
 
 use crate::{
-    common::tls::DefaultTlsVerifier,
-    proxy::{tuic::types::SocketAdderTrans, utils::new_udp_socket},
+    common::{errors::NetError, tls::DefaultTlsVerifier},
+    proxy::{
+        tuic::types::SocketAdderTrans,
+        utils::{new_udp_socket, quic_mtu_discovery},
+    },
 };
 use anyhow::Result;
 use async_trait::async_trait;
@@ -129,7 +132,7 @@ impl OutboundHandler for Handler {
     ) -> std::io::Result<BoxedChainedStream> {
         self.do_connect_stream(sess, resolver).await.map_err(|e| {
             tracing::error!("{:?}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+            NetError::handshake(e.to_string())
         })
     }
 
@@ -140,7 +143,7 @@ impl OutboundHandler for Handler {
     ) -> std::io::Result<BoxedChainedDatagram> {
         self.do_connect_datagram(sess, resolver).await.map_err(|e| {
             tracing::error!("{:?}", e);
-            std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+            NetError::handshake(e.to_string())
         })
     }
 
@@ -187,7 +190,8 @@ impl Handler {
             .max_concurrent_uni_streams(opts.max_open_stream)
             .send_window(opts.send_window)
             .stream_receive_window(opts.receive_window)
-            .max_idle_timeout(Some(opts.idle_timeout.try_into().unwrap()));
+            .max_idle_timeout(Some(opts.idle_timeout.try_into().unwrap()))
+            .mtu_discovery_config(Some(quic_mtu_discovery()));
         match opts.congestion_controller {
             CongestionControl::Cubic => transport_config
                 .congestion_controller_factory(Arc::new(CubicConfig::default())),
@@ -0,0 +1,508 @@
+//! IP fragments from the TUN device are put back together before they reach
+//! the stack, which drops them otherwise, and packets from the stack larger
+//! than the MTU are fragmented on the way out. The MSS of TCP SYNs in both
+//! directions is clamped so that segments fit the MTU.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use tracing::trace;
+
+use crate::common::pcapng::checksum;
+
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const FRAGMENT_HEADER_LEN: usize = 8;
+const TCP_MIN_HEADER_LEN: usize = 20;
+
+const PROTO_TCP: u8 = 6;
+const NEXT_HEADER_FRAGMENT: u8 = 44;
+
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+
+/// same as `net.ipv4.ipfrag_time` on Linux
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// datagrams being reassembled at a time, the oldest one is dropped beyond
+const MAX_PENDING: usize = 64;
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+struct Key {
+    src: IpAddr,
+    dst: IpAddr,
+    id: u32,
+    protocol: u8,
+}
+
+struct Fragment {
+    key: Key,
+    /// the header of the reassembled packet, without the fragment header
+    header: Vec<u8>,
+    offset: usize,
+    more: bool,
+    payload: Vec<u8>,
+}
+
+struct Pending {
+    /// taken from the first fragment
+    header: Option<Vec<u8>>,
+    /// payload by offset
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// known once the last fragment is in
+    len: Option<usize>,
+    started: Instant,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self {
+            header: None,
+            fragments: BTreeMap::new(),
+            len: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// the payload once the fragments cover all of it without overlapping
+    fn payload(&self) -> Option<Vec<u8>> {
+        let len = self.len?;
+        self.header.as_ref()?;
+
+        let mut payload = Vec::with_capacity(len);
+        for (offset, data) in &self.fragments {
+            if *offset != payload.len() {
+                return None;
+            }
+            payload.extend_from_slice(data);
+        }
+        (payload.len() == len).then_some(payload)
+    }
+}
+
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<Key, Pending>,
+}
+
+impl Reassembler {
+    /// the packet to pass on, `pkt` itself unless it's a fragment, the whole
+    /// datagram with its last fragment
+    pub fn push(&mut self, pkt: Vec<u8>) -> Option<Vec<u8>> {
+        let Some(fragment) = parse_fragment(&pkt) else {
+            return Some(pkt);
+        };
+
+        let now = Instant::now();
+        self.pending
+            .retain(|_, p| now.duration_since(p.started) < FRAGMENT_TIMEOUT);
+        if !self.pending.contains_key(&fragment.key)
+            && self.pending.len() >= MAX_PENDING
+            && let Some(oldest) = self
+                .pending
+                .iter()
+                .min_by_key(|(_, p)| p.started)
+                .map(|(k, _)| *k)
+        {
+            trace!("dropping fragments of {:?}, too many pending", oldest);
+            self.pending.remove(&oldest);
+        }
+
+        let key = fragment.key;
+        let pending = self.pending.entry(key).or_insert_with(Pending::new);
+        let end = fragment.offset + fragment.payload.len();
+        if end > MAX_DATAGRAM_LEN || pending.len.is_some_and(|len| end > len) {
+            trace!("dropping oversized fragment at {}", fragment.offset);
+            return None;
+        }
+        if fragment.offset == 0 {
+            pending.header = Some(fragment.header);
+        }
+        if !fragment.more {
+            pending.len = Some(end);
+        }
+        pending.fragments.insert(fragment.offset, fragment.payload);
+
+        let payload = pending.payload()?;
+        let header = self.pending.remove(&key)?.header?;
+        Some(reassemble(header, payload))
+    }
+}
+
+fn parse_fragment(pkt: &[u8]) -> Option<Fragment> {
+    match pkt.first()? >> 4 {
+        4 => {
+            let ihl = ((pkt[0] & 0x0f) as usize) * 4;
+            if ihl < IPV4_MIN_HEADER_LEN || pkt.len() < ihl {
+                return None;
+            }
+            let flags = u16::from_be_bytes([pkt[6], pkt[7]]);
+            let more = flags & IPV4_MORE_FRAGMENTS != 0;
+            let offset = ((flags & 0x1fff) as usize) * 8;
+            if !more && offset == 0 {
+                return None;
+            }
+            let total = (u16::from_be_bytes([pkt[2], pkt[3]]) as usize)
+                .clamp(ihl, pkt.len());
+            let src: [u8; 4] = pkt[12..16].try_into().ok()?;
+            let dst: [u8; 4] = pkt[16..20].try_into().ok()?;
+            Some(Fragment {
+                key: Key {
+                    src: Ipv4Addr::from(src).into(),
+                    dst: Ipv4Addr::from(dst).into(),
+                    id: u16::from_be_bytes([pkt[4], pkt[5]]) as u32,
+                    protocol: pkt[9],
+                },
+                header: pkt[..ihl].to_vec(),
+                offset,
+                more,
+                payload: pkt[ihl..total].to_vec(),
+            })
+        }
+        6 => {
+            // only a fragment header right after the fixed one, which is
+            // where it is without hop-by-hop or routing headers
+            if pkt.len() < IPV6_HEADER_LEN + FRAGMENT_HEADER_LEN
+                || pkt[6] != NEXT_HEADER_FRAGMENT
+            {
+                return None;
+            }
+            let frag = &pkt[IPV6_HEADER_LEN..IPV6_HEADER_LEN + FRAGMENT_HEADER_LEN];
+            let flags = u16::from_be_bytes([frag[2], frag[3]]);
+            let total = (IPV6_HEADER_LEN
+                + u16::from_be_bytes([pkt[4], pkt[5]]) as usize)
+                .clamp(IPV6_HEADER_LEN + FRAGMENT_HEADER_LEN, pkt.len());
+            let src: [u8; 16] = pkt[8..24].try_into().ok()?;
+            let dst: [u8; 16] = pkt[24..40].try_into().ok()?;
+
+            let mut header = pkt[..IPV6_HEADER_LEN].to_vec();
+            header[6] = frag[0];
+            Some(Fragment {
+                key: Key {
+                    src: Ipv6Addr::from(src).into(),
+                    dst: Ipv6Addr::from(dst).into(),
+                    id: u32::from_be_bytes([frag[4], frag[5], frag[6], frag[7]]),
+                    protocol: frag[0],
+                },
+                header,
+                offset: (flags & 0xfff8) as usize,
+                more: flags & 1 != 0,
+                payload: pkt[IPV6_HEADER_LEN + FRAGMENT_HEADER_LEN..total].to_vec(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// `header` followed by `payload`, with the lengths and checksum fixed
+fn reassemble(mut header: Vec<u8>, payload: Vec<u8>) -> Vec<u8> {
+    if header[0] >> 4 == 4 {
+        let total = (header.len() + payload.len()) as u16;
+        header[2..4].copy_from_slice(&total.to_be_bytes());
+        header[6..8].copy_from_slice(&[0, 0]);
+        set_ipv4_checksum(&mut header);
+    } else {
+        header[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    header.extend_from_slice(&payload);
+    header
+}
+
+fn set_ipv4_checksum(header: &mut [u8]) {
+    header[10..12].copy_from_slice(&[0, 0]);
+    let sum = checksum(header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// `pkt` in packets of at most `mtu` bytes, IPv4 packets that mustn't be
+/// fragmented are left as they are
+pub fn fragment(pkt: Vec<u8>, mtu: usize) -> Vec<Vec<u8>> {
+    if pkt.len() <= mtu {
+        return vec![pkt];
+    }
+    match pkt[0] >> 4 {
+        4 => fragment_v4(pkt, mtu),
+        6 => fragment_v6(pkt, mtu),
+        _ => vec![pkt],
+    }
+}
+
+fn fragment_v4(pkt: Vec<u8>, mtu: usize) -> Vec<Vec<u8>> {
+    if pkt.len() < IPV4_MIN_HEADER_LEN {
+        return vec![pkt];
+    }
+    let ihl = ((pkt[0] & 0x0f) as usize) * 4;
+    let flags = u16::from_be_bytes([pkt[6], pkt[7]]);
+    if ihl < IPV4_MIN_HEADER_LEN
+        || pkt.len() <= ihl
+        || flags & IPV4_DONT_FRAGMENT != 0
+        // already a fragment
+        || flags & (IPV4_MORE_FRAGMENTS | 0x1fff) != 0
+        || mtu < ihl + 8
+    {
+        return vec![pkt];
+    }
+
+    let (header, payload) = pkt.split_at(ihl);
+    let mut id = u16::from_be_bytes([header[4], header[5]]);
+    if id == 0 {
+        id = NEXT_ID.fetch_add(1, Ordering::Relaxed) as u16;
+    }
+    let chunk = (mtu - ihl) / 8 * 8;
+    let count = payload.len().div_ceil(chunk);
+
+    payload
+        .chunks(chunk)
+        .enumerate()
+        .map(|(i, data)| {
+            let mut p = Vec::with_capacity(ihl + data.len());
+            p.extend_from_slice(header);
+            p[2..4].copy_from_slice(&((ihl + data.len()) as u16).to_be_bytes());
+            p[4..6].copy_from_slice(&id.to_be_bytes());
+            let mut flags = ((i * chunk / 8) as u16) & 0x1fff;
+            if i + 1 < count {
+                flags |= IPV4_MORE_FRAGMENTS;
+            }
+            p[6..8].copy_from_slice(&flags.to_be_bytes());
+            set_ipv4_checksum(&mut p[..ihl]);
+            p.extend_from_slice(data);
+            p
+        })
+        .collect()
+}
+
+fn fragment_v6(pkt: Vec<u8>, mtu: usize) -> Vec<Vec<u8>> {
+    if pkt.len() <= IPV6_HEADER_LEN
+        || pkt[6] == NEXT_HEADER_FRAGMENT
+        || mtu < IPV6_HEADER_LEN + FRAGMENT_HEADER_LEN + 8
+    {
+        return vec![pkt];
+    }
+
+    let (header, payload) = pkt.split_at(IPV6_HEADER_LEN);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let chunk = (mtu - IPV6_HEADER_LEN - FRAGMENT_HEADER_LEN) / 8 * 8;
+    let count = payload.len().div_ceil(chunk);
+
+    payload
+        .chunks(chunk)
+        .enumerate()
+        .map(|(i, data)| {
+            let mut p = Vec::with_capacity(
+                IPV6_HEADER_LEN + FRAGMENT_HEADER_LEN + data.len(),
+            );
+            p.extend_from_slice(header);
+            p[4..6].copy_from_slice(
+                &((FRAGMENT_HEADER_LEN + data.len()) as u16).to_be_bytes(),
+            );
+            p[6] = NEXT_HEADER_FRAGMENT;
+            let mut flags = (i * chunk) as u16;
+            if i + 1 < count {
+                flags |= 1;
+            }
+            p.extend_from_slice(&[header[6], 0]);
+            p.extend_from_slice(&flags.to_be_bytes());
+            p.extend_from_slice(&id.to_be_bytes());
+            p.extend_from_slice(data);
+            p
+        })
+        .collect()
+}
+
+/// lowers the MSS option of a TCP SYN to what fits in `mtu`
+pub fn clamp_mss(pkt: &mut [u8], mtu: usize) {
+    let ip_len = match pkt.first().map(|b| b >> 4) {
+        Some(4) => {
+            if pkt.len() < IPV4_MIN_HEADER_LEN {
+                return;
+            }
+            let ihl = ((pkt[0] & 0x0f) as usize) * 4;
+            let flags = u16::from_be_bytes([pkt[6], pkt[7]]);
+            if ihl < IPV4_MIN_HEADER_LEN
+                || pkt[9] != PROTO_TCP
+                || flags & (IPV4_MORE_FRAGMENTS | 0x1fff) != 0
+            {
+                return;
+            }
+            ihl
+        }
+        Some(6) => {
+            if pkt.len() < IPV6_HEADER_LEN || pkt[6] != PROTO_TCP {
+                return;
+            }
+            IPV6_HEADER_LEN
+        }
+        _ => return,
+    };
+    if pkt.len() < ip_len + TCP_MIN_HEADER_LEN {
+        return;
+    }
+
+    let max = match mtu.checked_sub(ip_len + TCP_MIN_HEADER_LEN) {
+        Some(max) => max.min(u16::MAX as usize) as u16,
+        None => return,
+    };
+    let tcp = &mut pkt[ip_len..];
+    // SYN
+    if tcp[13] & 0x02 == 0 {
+        return;
+    }
+    let data_offset = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+
+    let mut i = TCP_MIN_HEADER_LEN;
+    while i < data_offset {
+        match tcp[i] {
+            // end of options
+            0 => return,
+            // no-op
+            1 => i += 1,
+            kind => {
+                let Some(len) = tcp.get(i + 1).map(|l| *l as usize) else {
+                    return;
+                };
+                if len < 2 || i + len > data_offset {
+                    return;
+                }
+                if kind == 2 && len == 4 {
+                    let mss = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+                    if mss > max {
+                        trace!("clamping tcp mss from {} to {}", mss, max);
+                        tcp[i + 2..i + 4].copy_from_slice(&max.to_be_bytes());
+                        let sum = u16::from_be_bytes([tcp[16], tcp[17]]);
+                        // an odd offset puts the value across two words
+                        let (old, new) = if i % 2 == 0 {
+                            (mss, max)
+                        } else {
+                            (mss.swap_bytes(), max.swap_bytes())
+                        };
+                        tcp[16..18].copy_from_slice(
+                            &update_checksum(sum, old, new).to_be_bytes(),
+                        );
+                    }
+                    return;
+                }
+                i += len;
+            }
+        }
+    }
+}
+
+/// RFC 1624, the checksum after `old` in the data is replaced with `new`
+fn update_checksum(sum: u16, old: u16, new: u16) -> u16 {
+    let mut s = (!sum) as u32 + (!old) as u32 + new as u32;
+    while s >> 16 != 0 {
+        s = (s & 0xffff) + (s >> 16);
+    }
+    !(s as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reassembler, checksum, clamp_mss, fragment};
+
+    fn udp_v4(payload_len: usize) -> Vec<u8> {
+        let payload = (0..payload_len).map(|i| i as u8).collect::<Vec<_>>();
+        let mut pkt = vec![
+            0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+        ];
+        let total = (20 + 8 + payload.len()) as u16;
+        pkt[2..4].copy_from_slice(&total.to_be_bytes());
+        pkt.extend_from_slice(&[0x30, 0x39, 0, 53]);
+        pkt.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        pkt.extend_from_slice(&[0, 0]);
+        pkt.extend_from_slice(&payload);
+        pkt
+    }
+
+    fn udp_v6(payload_len: usize) -> Vec<u8> {
+        let mut pkt = vec![0x60, 0, 0, 0];
+        pkt.extend_from_slice(&((8 + payload_len) as u16).to_be_bytes());
+        pkt.extend_from_slice(&[17, 64]);
+        pkt.extend_from_slice(&[0xfd; 16]);
+        pkt.extend_from_slice(&[0xfe; 16]);
+        pkt.extend_from_slice(&[0x30, 0x39, 0, 53]);
+        pkt.extend_from_slice(&((8 + payload_len) as u16).to_be_bytes());
+        pkt.extend_from_slice(&[0, 0]);
+        pkt.extend((0..payload_len).map(|i| i as u8));
+        pkt
+    }
+
+    #[test]
+    fn test_fragment_and_reassemble() {
+        for pkt in [udp_v4(3000), udp_v6(3000)] {
+            let fragments = fragment(pkt.clone(), 1280);
+            assert!(fragments.len() > 2);
+            assert!(fragments.iter().all(|f| f.len() <= 1280));
+
+            let mut reassembler = Reassembler::default();
+            // out of order
+            let mut out = None;
+            for f in fragments.into_iter().rev() {
+                assert!(out.is_none());
+                out = reassembler.push(f);
+            }
+            let mut out = out.unwrap();
+            if pkt[0] >> 4 == 4 {
+                assert_eq!(checksum(&out[..20]), 0);
+                // the id was assigned when fragmenting
+                out[4..6].copy_from_slice(&[0, 0]);
+                out[10..12].copy_from_slice(&[0, 0]);
+                let mut pkt = pkt;
+                pkt[10..12].copy_from_slice(&[0, 0]);
+                assert_eq!(out, pkt);
+            } else {
+                assert_eq!(out, pkt);
+            }
+            assert!(reassembler.pending.is_empty());
+        }
+
+        // whole packets are passed through
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(udp_v4(10)), Some(udp_v4(10)));
+        assert_eq!(fragment(udp_v4(10), 1280), vec![udp_v4(10)]);
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        for nops in [0, 1] {
+            let mut pkt = vec![
+                0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2,
+            ];
+            let options_len = (4 + nops).div_ceil(4) * 4;
+            let total = (20 + 20 + options_len) as u16;
+            pkt[2..4].copy_from_slice(&total.to_be_bytes());
+            // ports, seq, ack
+            pkt.extend_from_slice(&[0x30, 0x39, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0]);
+            pkt.extend_from_slice(&[(((20 + options_len) / 4) << 4) as u8, 0x02]);
+            pkt.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+            pkt.extend(std::iter::repeat_n(1, nops));
+            // mss 65495
+            pkt.extend_from_slice(&[2, 4, 0xff, 0xd7]);
+            while pkt.len() < total as usize {
+                pkt.push(0);
+            }
+
+            let tcp_checksum = |pkt: &[u8]| {
+                let mut pseudo = pkt[12..20].to_vec();
+                pseudo.extend_from_slice(&[0, 6]);
+                pseudo.extend_from_slice(&((pkt.len() - 20) as u16).to_be_bytes());
+                pseudo.extend_from_slice(&pkt[20..]);
+                checksum(&pseudo)
+            };
+            let sum = tcp_checksum(&pkt);
+            pkt[36..38].copy_from_slice(&sum.to_be_bytes());
+            assert_eq!(tcp_checksum(&pkt), 0);
+
+            clamp_mss(&mut pkt, 1500);
+            let at = 40 + nops + 2;
+            assert_eq!(u16::from_be_bytes([pkt[at], pkt[at + 1]]), 1460);
+            assert_eq!(tcp_checksum(&pkt), 0);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! The stack accepts a TCP connection from the TUN device before the remote
+//! is connected, which saves the app a round trip. If the remote fails then,
+//! the flow is reset: what the stack still sends on it is turned into RSTs,
+//! so the app sees an error rather than a connection closed by the remote.
+//!
+//! With `strict-tcp-handshake`, the SYN is held until the remote is connected
+//! instead. The SYN-ACK then means that the remote accepted the connection,
+//! and a failure is answered with a RST to the SYN.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{app::dispatcher::ConnectedStream, common::pcapng::checksum};
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+
+const PROTO_TCP: u8 = 6;
+
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_ACK: u8 = 0x10;
+
+/// how long the packets of a reset flow are turned into RSTs
+const RESET_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long a connected remote waits for the stack to accept its connection
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the client and the destination of a connection
+pub type Flow = (SocketAddr, SocketAddr);
+
+/// What to do with a packet from the TUN device
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    Pass,
+    /// the SYN of a new flow, to be sent to the stack once it's connected
+    Hold(Flow),
+    /// a SYN retransmitted while the remote is being connected
+    Drop,
+}
+
+enum Pending {
+    Connecting,
+    Connected(ConnectedStream, Instant),
+}
+
+#[derive(Default)]
+struct State {
+    /// when the flows were reset
+    resets: HashMap<Flow, Instant>,
+    pending: HashMap<Flow, Pending>,
+}
+
+#[derive(Clone)]
+pub struct Handshakes {
+    strict: bool,
+    state: Arc<Mutex<State>>,
+}
+
+impl Handshakes {
+    pub fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            state: Default::default(),
+        }
+    }
+
+    /// whether `pkt` goes on to the stack
+    pub fn incoming(&self, pkt: &[u8]) -> Incoming {
+        if !self.strict {
+            return Incoming::Pass;
+        }
+        let Some(seg) = parse(pkt) else {
+            return Incoming::Pass;
+        };
+        if seg.flags & (FLAG_SYN | FLAG_ACK) != FLAG_SYN {
+            return Incoming::Pass;
+        }
+
+        let flow = (seg.src, seg.dst);
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.pending.retain(|_, p| match p {
+            Pending::Connected(_, at) => now.duration_since(*at) < ACCEPT_TIMEOUT,
+            Pending::Connecting => true,
+        });
+        match state.pending.get(&flow) {
+            Some(Pending::Connecting) => Incoming::Drop,
+            Some(Pending::Connected(..)) => Incoming::Pass,
+            None => {
+                state.pending.insert(flow, Pending::Connecting);
+                Incoming::Hold(flow)
+            }
+        }
+    }
+
+    /// the remote of a held `flow` is connected, its SYN may go to the stack
+    pub fn connected(&self, flow: Flow, remote: ConnectedStream) {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .insert(flow, Pending::Connected(remote, Instant::now()));
+    }
+
+    /// the remote of a held `flow` failed, the RST to answer `syn` with
+    pub fn refused(&self, flow: Flow, syn: &[u8]) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().pending.remove(&flow);
+        let seg = parse(syn)?;
+        rst(seg.dst, seg.src, 0, seg.seq.wrapping_add(1))
+    }
+
+    /// the remote connected for `flow` before the stack accepted it
+    pub fn accept(&self, flow: Flow) -> Option<ConnectedStream> {
+        match self.state.lock().unwrap().pending.remove(&flow)? {
+            Pending::Connected(remote, _) => Some(remote),
+            Pending::Connecting => None,
+        }
+    }
+
+    /// the remote of `flow`, which the stack accepted, failed
+    pub fn reset(&self, flow: Flow) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state
+            .resets
+            .retain(|_, at| now.duration_since(*at) < RESET_TIMEOUT);
+        state.resets.insert(flow, now);
+    }
+
+    /// `pkt` from the stack, or a RST in its place if its flow was reset
+    pub fn outgoing(&self, pkt: Vec<u8>) -> Vec<u8> {
+        let state = self.state.lock().unwrap();
+        if state.resets.is_empty() {
+            return pkt;
+        }
+        let Some(seg) = parse(&pkt) else {
+            return pkt;
+        };
+        // from the destination to the client
+        match state.resets.get(&(seg.dst, seg.src)) {
+            Some(at) if at.elapsed() < RESET_TIMEOUT => {
+                rst(seg.src, seg.dst, seg.seq, seg.ack).unwrap_or(pkt)
+            }
+            _ => pkt,
+        }
+    }
+}
+
+struct Segment {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+}
+
+fn parse(pkt: &[u8]) -> Option<Segment> {
+    let (src, dst, ip_len) = match pkt.first()? >> 4 {
+        4 => {
+            let ihl = ((pkt[0] & 0x0f) as usize) * 4;
+            if ihl < IPV4_HEADER_LEN
+                || pkt.len() < ihl + TCP_HEADER_LEN
+                || pkt[9] != PROTO_TCP
+                // a fragment
+                || u16::from_be_bytes([pkt[6], pkt[7]]) & 0x3fff != 0
+            {
+                return None;
+            }
+            let src: [u8; 4] = pkt[12..16].try_into().ok()?;
+            let dst: [u8; 4] = pkt[16..20].try_into().ok()?;
+            (IpAddr::from(src), IpAddr::from(dst), ihl)
+        }
+        6 => {
+            if pkt.len() < IPV6_HEADER_LEN + TCP_HEADER_LEN || pkt[6] != PROTO_TCP {
+                return None;
+            }
+            let src: [u8; 16] = pkt[8..24].try_into().ok()?;
+            let dst: [u8; 16] = pkt[24..40].try_into().ok()?;
+            (IpAddr::from(src), IpAddr::from(dst), IPV6_HEADER_LEN)
+        }
+        _ => return None,
+    };
+
+    let tcp = &pkt[ip_len..];
+    Some(Segment {
+        src: SocketAddr::new(src, u16::from_be_bytes([tcp[0], tcp[1]])),
+        dst: SocketAddr::new(dst, u16::from_be_bytes([tcp[2], tcp[3]])),
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        ack: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+        flags: tcp[13],
+    })
+}
+
+/// a RST from `src` to `dst`
+fn rst(src: SocketAddr, dst: SocketAddr, seq: u32, ack: u32) -> Option<Vec<u8>> {
+    let mut tcp = Vec::with_capacity(TCP_HEADER_LEN);
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    // data offset, flags, window, checksum and urgent pointer
+    tcp.extend_from_slice(&[
+        ((TCP_HEADER_LEN / 4) << 4) as u8,
+        FLAG_RST | FLAG_ACK,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ]);
+
+    let (mut pkt, mut pseudo) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut ip = vec![0x45, 0];
+            ip.extend_from_slice(
+                &((IPV4_HEADER_LEN + TCP_HEADER_LEN) as u16).to_be_bytes(),
+            );
+            ip.extend_from_slice(&[0, 0, 0x40, 0, 64, PROTO_TCP, 0, 0]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+            let sum = checksum(&ip);
+            ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            let mut pseudo = ip[12..20].to_vec();
+            pseudo.extend_from_slice(&[0, PROTO_TCP]);
+            pseudo.extend_from_slice(&(TCP_HEADER_LEN as u16).to_be_bytes());
+            (ip, pseudo)
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            let mut ip = vec![0x60, 0, 0, 0];
+            ip.extend_from_slice(&(TCP_HEADER_LEN as u16).to_be_bytes());
+            ip.extend_from_slice(&[PROTO_TCP, 64]);
+            ip.extend_from_slice(&src.octets());
+            ip.extend_from_slice(&dst.octets());
+
+            let mut pseudo = ip[8..40].to_vec();
+            pseudo.extend_from_slice(&(TCP_HEADER_LEN as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, PROTO_TCP]);
+            (ip, pseudo)
+        }
+        _ => return None,
+    };
+    pseudo.extend_from_slice(&tcp);
+    let sum = checksum(&pseudo);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+    pkt.extend_from_slice(&tcp);
+    Some(pkt)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{
+        FLAG_ACK, FLAG_RST, FLAG_SYN, Handshakes, Incoming, checksum, parse, rst,
+    };
+
+    fn tcp_checksum(pkt: &[u8]) -> u16 {
+        let (mut pseudo, tcp) = if pkt[0] >> 4 == 4 {
+            (pkt[12..20].to_vec(), &pkt[20..])
+        } else {
+            (pkt[8..40].to_vec(), &pkt[40..])
+        };
+        pseudo.extend_from_slice(&[0, 6]);
+        pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        pseudo.extend_from_slice(tcp);
+        checksum(&pseudo)
+    }
+
+    /// a segment with `flags` from `src` to `dst`, taking a RST as the base
+    fn segment(src: &str, dst: &str, seq: u32, flags: u8) -> Vec<u8> {
+        let src: SocketAddr = src.parse().unwrap();
+        let dst: SocketAddr = dst.parse().unwrap();
+        let mut pkt = rst(src, dst, seq, 0).unwrap();
+        let at = pkt.len() - 20;
+        pkt[at + 13] = flags;
+        pkt[at + 16..at + 18].copy_from_slice(&[0, 0]);
+        let sum = tcp_checksum(&pkt);
+        pkt[at + 16..at + 18].copy_from_slice(&sum.to_be_bytes());
+        pkt
+    }
+
+    #[test]
+    fn test_strict_handshake() {
+        let handshakes = Handshakes::new(true);
+        for (client, server) in [
+            ("10.0.0.2:40000", "1.1.1.1:443"),
+            ("[fd00::2]:40000", "[2606:4700::1111]:443"),
+        ] {
+            let syn = segment(client, server, 1000, FLAG_SYN);
+            let flow = (client.parse().unwrap(), server.parse().unwrap());
+            assert_eq!(handshakes.incoming(&syn), Incoming::Hold(flow));
+            assert_eq!(handshakes.incoming(&syn), Incoming::Drop);
+            let ack = segment(client, server, 1001, FLAG_ACK);
+            assert_eq!(handshakes.incoming(&ack), Incoming::Pass);
+
+            let refused = handshakes.refused(flow, &syn).unwrap();
+            assert_eq!(tcp_checksum(&refused), 0);
+            let seg = parse(&refused).unwrap();
+            assert_eq!((seg.src, seg.dst), (flow.1, flow.0));
+            assert_eq!((seg.seq, seg.ack), (0, 1001));
+            assert_eq!(seg.flags, FLAG_RST | FLAG_ACK);
+            // tried again by the app
+            assert_eq!(handshakes.incoming(&syn), Incoming::Hold(flow));
+        }
+
+        let syn = segment("10.0.0.2:40001", "1.1.1.1:443", 1, FLAG_SYN);
+        assert_eq!(Handshakes::new(false).incoming(&syn), Incoming::Pass);
+    }
+
+    #[test]
+    fn test_reset_flow() {
+        let handshakes = Handshakes::new(false);
+        let fin = segment("1.1.1.1:443", "10.0.0.2:40000", 5000, 0x01 | FLAG_ACK);
+        assert_eq!(handshakes.outgoing(fin.clone()), fin);
+
+        handshakes.reset((
+            "10.0.0.2:40000".parse().unwrap(),
+            "1.1.1.1:443".parse().unwrap(),
+        ));
+        let out = handshakes.outgoing(fin.clone());
+        assert_eq!(tcp_checksum(&out), 0);
+        let seg = parse(&out).unwrap();
+        assert_eq!(seg.flags, FLAG_RST | FLAG_ACK);
+        assert_eq!(seg.seq, 5000);
+
+        let other = segment("1.1.1.1:443", "10.0.0.2:40001", 5000, FLAG_ACK);
+        assert_eq!(handshakes.outgoing(other.clone()), other);
+    }
+}
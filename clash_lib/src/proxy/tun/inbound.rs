@@ -1,10 +1,11 @@
 use super::datagram::TunDatagram;
 use std::{
+    io,
     net::{IpAddr, SocketAddr},
     sync::Arc,
 };
 
-use futures::{SinkExt, StreamExt};
+use futures::{SinkExt, StreamExt, channel::mpsc::UnboundedSender};
 
 use hickory_proto::rr::RecordType;
 use netstack_smoltcp::StackBuilder;
@@ -17,24 +18,30 @@ use crate::{
     app::{
         dispatcher::Dispatcher,
         dns::{ThreadSafeDNSResolver, exchange_with_resolver},
+        handover,
         net::get_outbound_interface,
     },
     common::errors::{map_io_error, new_io_error},
     config::internal::config::TunConfig,
-    proxy::{datagram::UdpPacket, tun::routes::maybe_add_routes},
+    proxy::{
+        datagram::UdpPacket,
+        tun::{
+            fragment::{Reassembler, clamp_mss, fragment},
+            handshake::{Flow, Handshakes, Incoming},
+            routes::maybe_add_routes,
+        },
+    },
     session::{Network, Session, Type},
 };
 
 use crate::{defer, proxy::tun::routes};
 
-async fn handle_inbound_stream(
-    stream: netstack_smoltcp::TcpStream,
+fn tcp_session(
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
-    dispatcher: Arc<Dispatcher>,
     so_mark: u32,
-) {
-    let sess = Session {
+) -> Session {
+    Session {
         network: Network::Tcp,
         typ: Type::Tun,
         source: local_addr,
@@ -49,10 +56,56 @@ async fn handle_inbound_stream(
             }),
         so_mark: Some(so_mark),
         ..Default::default()
-    };
+    }
+}
+
+async fn handle_inbound_stream(
+    stream: netstack_smoltcp::TcpStream,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    dispatcher: Arc<Dispatcher>,
+    handshakes: Handshakes,
+    so_mark: u32,
+) {
+    let flow = (local_addr, remote_addr);
+    if let Some(remote) = handshakes.accept(flow) {
+        return dispatcher.relay_connected(Box::new(stream), remote).await;
+    }
 
+    let mut sess = tcp_session(local_addr, remote_addr, so_mark);
     debug!("new tun TCP session assigned: {}", sess);
-    dispatcher.dispatch_stream(sess, Box::new(stream)).await;
+    let stream = dispatcher.sniff_stream(&mut sess, Box::new(stream)).await;
+    match dispatcher.connect_stream(sess).await {
+        Ok(remote) => dispatcher.relay_connected(stream, remote).await,
+        // what the stack sends on closing it becomes a RST
+        Err(_) => handshakes.reset(flow),
+    }
+}
+
+/// connects the remote of a flow whose `syn` is held, then lets the SYN
+/// through to the stack or answers it with a RST
+async fn connect_held(
+    syn: Vec<u8>,
+    flow: Flow,
+    dispatcher: Arc<Dispatcher>,
+    handshakes: Handshakes,
+    so_mark: u32,
+    to_stack: UnboundedSender<io::Result<Vec<u8>>>,
+    to_tun: UnboundedSender<io::Result<Vec<u8>>>,
+) {
+    let sess = tcp_session(flow.0, flow.1, so_mark);
+    debug!("holding the SYN of {} until the remote is connected", sess);
+    match dispatcher.connect_stream(sess).await {
+        Ok(remote) => {
+            handshakes.connected(flow, remote);
+            to_stack.unbounded_send(Ok(syn)).ok();
+        }
+        Err(_) => {
+            if let Some(rst) = handshakes.refused(flow, &syn) {
+                to_tun.unbounded_send(Ok(rst)).ok();
+            }
+        }
+    }
 }
 
 async fn handle_inbound_datagram(
@@ -289,23 +342,42 @@ pub fn get_runner(
         }
     }
 
+    // the device of the process upgraded from, with its routes set up
+    #[cfg(unix)]
+    let inherited =
+        match handover::take_inherited(&handover::tun_key(&cfg.device_id)) {
+            Some(fd) => {
+                use std::os::fd::IntoRawFd;
+                tun_cfg.raw_fd(fd.into_raw_fd());
+                true
+            }
+            None => false,
+        };
+    #[cfg(not(unix))]
+    let inherited = false;
+
     let gw = cfg.gateway;
+    let mtu = cfg
+        .mtu
+        .unwrap_or(if cfg!(windows) { 65535u16 } else { 1500u16 });
     tun_cfg
         .address(gw.addr())
         .netmask(gw.netmask())
-        .mtu(
-            cfg.mtu
-                .unwrap_or(if cfg!(windows) { 65535u16 } else { 1500u16 }),
-        )
+        .mtu(mtu)
         .up();
+    let mtu = mtu as usize;
 
     let tun = tun::create_as_async(&tun_cfg)
         .map_err(|x| new_io_error(format!("failed to create tun device: {}", x)))?;
 
     let tun_name = tun.tun_name().map_err(map_io_error)?;
     info!("tun started at {}", tun_name);
+    #[cfg(unix)]
+    handover::register(handover::tun_key(&cfg.device_id), &tun);
 
-    maybe_add_routes(&cfg, &tun_name)?;
+    if !inherited {
+        maybe_add_routes(&cfg, &tun_name)?;
+    }
 
     let mut builder = StackBuilder::default()
         .enable_tcp(true)
@@ -325,33 +397,49 @@ pub fn get_runner(
 
     Ok(Some(Box::pin(async move {
         defer! {
-            warn!("cleaning up routes");
+            if handover::handed_over() {
+                debug!("routes left to the process upgraded to");
+            } else {
+                warn!("cleaning up routes");
 
-            match routes::maybe_routes_clean_up(&cfg) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("failed to clean up routes: {}", e);
+                match routes::maybe_routes_clean_up(&cfg) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("failed to clean up routes: {}", e);
+                    }
                 }
             }
         }
 
         let so_mark = cfg.so_mark;
+        let handshakes = Handshakes::new(cfg.strict_tcp_handshake);
 
         let framed = tun.into_framed();
 
-        let (mut tun_sink, mut tun_stream) = framed.split();
-        let (mut stack_sink, mut stack_stream) = stack.split();
+        let (mut tun_sink, tun_stream) = framed.split();
+        let (mut stack_sink, stack_stream) = stack.split();
+
+        // held SYNs once their remote is connected, and RSTs for those failed
+        let (to_stack, held) = futures::channel::mpsc::unbounded();
+        let (to_tun, refused) = futures::channel::mpsc::unbounded();
+        let mut tun_stream = futures::stream::select(tun_stream, held);
+        let mut stack_stream = futures::stream::select(stack_stream, refused);
 
         let mut futs: Vec<Runner> = vec![];
 
         // dispatcher -> stack -> tun
+        let hs = handshakes.clone();
         futs.push(Box::pin(async move {
-            while let Some(pkt) = stack_stream.next().await {
+            'read_packet: while let Some(pkt) = stack_stream.next().await {
                 match pkt {
                     Ok(pkt) => {
-                        if let Err(e) = tun_sink.send(pkt).await {
-                            error!("failed to send pkt to tun: {}", e);
-                            break;
+                        let mut pkt = hs.outgoing(pkt);
+                        clamp_mss(&mut pkt, mtu);
+                        for pkt in fragment(pkt, mtu) {
+                            if let Err(e) = tun_sink.send(pkt).await {
+                                error!("failed to send pkt to tun: {}", e);
+                                break 'read_packet;
+                            }
                         }
                     }
                     Err(e) => {
@@ -365,10 +453,43 @@ pub fn get_runner(
         }));
 
         // tun -> stack -> dispatcher
+        let hs = handshakes.clone();
+        let dsp = dispatcher.clone();
         futs.push(Box::pin(async move {
-            while let Some(pkt) = tun_stream.next().await {
+            let mut reassembler = Reassembler::default();
+            // not read while the new process of an upgrade takes over
+            let mut handing_over = handover::handing_over();
+            loop {
+                let pkt = tokio::select! {
+                    pkt = tun_stream.next(), if !*handing_over.borrow() => pkt,
+                    _ = handing_over.changed() => continue,
+                };
+                let Some(pkt) = pkt else {
+                    break;
+                };
                 match pkt {
                     Ok(pkt) => {
+                        // the stack only takes whole datagrams
+                        let Some(mut pkt) = reassembler.push(pkt) else {
+                            continue;
+                        };
+                        clamp_mss(&mut pkt, mtu);
+                        match hs.incoming(&pkt) {
+                            Incoming::Pass => {}
+                            Incoming::Hold(flow) => {
+                                tokio::spawn(connect_held(
+                                    pkt,
+                                    flow,
+                                    dsp.clone(),
+                                    hs.clone(),
+                                    so_mark,
+                                    to_stack.clone(),
+                                    to_tun.clone(),
+                                ));
+                                continue;
+                            }
+                            Incoming::Drop => continue,
+                        }
                         if let Err(e) = stack_sink.send(pkt).await {
                             error!("failed to send pkt to stack: {}", e);
                             break;
@@ -396,6 +517,7 @@ pub fn get_runner(
                     local_addr,
                     remote_addr,
                     dsp.clone(),
+                    handshakes.clone(),
                     so_mark,
                 ));
             }
@@ -1,4 +1,6 @@
 mod datagram;
+mod fragment;
+mod handshake;
 pub mod inbound;
 pub use inbound::get_runner as get_tun_runner;
 mod routes;
@@ -60,6 +62,7 @@ mod tests {
                 cwd: Some(cwd_clone),
                 rt: None,
                 log_file: Some(log_file_clone),
+                hooks: vec![],
             })
             .unwrap()
         });
@@ -131,6 +134,7 @@ mod tests {
                 cwd: Some(cwd_clone),
                 rt: None,
                 log_file: Some(log_file_clone),
+                hooks: vec![],
             })
             .unwrap()
         });
@@ -9,15 +9,12 @@ use std::{
 };
 
 use crate::{
-    app::dispatcher::Dispatcher,
+    app::{dispatcher::Dispatcher, handover},
     common::errors::new_io_error,
     session::{Network, Session, SocksAddr, Type},
 };
 use futures::{Sink, Stream};
-use tokio::{
-    io::ReadBuf,
-    net::{TcpListener, UdpSocket},
-};
+use tokio::{io::ReadBuf, net::UdpSocket};
 use tracing::{info, warn};
 
 use super::{
@@ -30,11 +27,12 @@ pub struct TunnelInbound {
     dispatcher: Arc<Dispatcher>,
     network: Vec<String>,
     target: SocksAddr,
+    proxy: Option<String>,
 }
 
 impl Drop for TunnelInbound {
     fn drop(&mut self) {
-        warn!("HTTP inbound listener on {} stopped", self.listen);
+        warn!("Tunnel inbound listener on {} stopped", self.listen);
     }
 }
 
@@ -44,12 +42,14 @@ impl TunnelInbound {
         dispatcher: Arc<Dispatcher>,
         network: Vec<String>,
         target: String,
+        proxy: Option<String>,
     ) -> anyhow::Result<Self> {
         Ok(Self {
             listen: addr,
             dispatcher,
             network,
             target: SocksAddr::from_str(&target)?,
+            proxy,
         })
     }
 }
@@ -71,7 +71,7 @@ impl InboundHandlerTrait for TunnelInbound {
             "[Tunnel-TCP] listening on {}, remote: {}",
             self.listen, self.target
         );
-        let listener = TcpListener::bind(self.listen).await?;
+        let listener = handover::bind_tcp(self.listen).await?;
 
         loop {
             let (socket, src_addr) = listener.accept().await?;
@@ -84,6 +84,7 @@ impl InboundHandlerTrait for TunnelInbound {
                 typ: Type::Tunnel,
                 source: src_addr,
                 destination: self.target.clone(),
+                outbound: self.proxy.clone(),
                 ..Default::default()
             };
 
@@ -101,11 +102,12 @@ impl InboundHandlerTrait for TunnelInbound {
             "[Tunnel-UDP] listening on {}, remote: {}",
             self.listen, self.target
         );
-        let socket = UdpSocket::bind(self.listen).await?;
+        let socket = handover::bind_udp(self.listen).await?;
         let sess = Session {
             network: Network::Udp,
             typ: Type::Tunnel,
             destination: self.target.clone(),
+            outbound: self.proxy.clone(),
             ..Default::default()
         };
         let inbound = UdpSession::new(socket, self.target.clone());
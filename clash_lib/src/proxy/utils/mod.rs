@@ -1,4 +1,4 @@
-#[cfg(test)]
+#[cfg(any(test, feature = "test-fixtures"))]
 pub mod test_utils;
 
 mod platform;
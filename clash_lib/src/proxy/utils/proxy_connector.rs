@@ -13,7 +13,7 @@ use crate::{
         dns::ThreadSafeDNSResolver,
         net::Interface,
     },
-    common::errors::new_io_error,
+    common::errors::NetError,
     proxy::{
         AnyOutboundDatagram, AnyOutboundHandler, AnyStream,
         datagram::OutboundDatagramImpl,
@@ -74,8 +74,10 @@ impl RemoteConnector for DirectConnector {
         let dial_addr = resolver
             .resolve(address, false)
             .await
-            .map_err(|v| new_io_error(format!("can't resolve dns: {}", v)))?
-            .ok_or(new_io_error("no dns result"))?;
+            .map_err(|v| NetError::dns(format!("can't resolve dns: {}", v)))?
+            .ok_or_else(|| {
+                NetError::no_record(format!("no record for {}", address))
+            })?;
 
         new_tcp_stream(
             (dial_addr, port).into(),
@@ -85,6 +87,7 @@ impl RemoteConnector for DirectConnector {
         )
         .await
         .map(|x| Box::new(x) as _)
+        .map_err(|e| NetError::dial(format!("{}:{}: {}", address, port, e)))
     }
 
     async fn connect_datagram(
@@ -1,8 +1,16 @@
 #[cfg(not(target_os = "android"))]
 use super::platform::must_bind_socket_on_interface;
-use crate::app::net::Interface;
+use crate::app::net::{Interface, nat64};
 use socket2::TcpKeepalive;
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 use tokio::{
     net::{TcpSocket, TcpStream, UdpSocket},
     time::timeout,
@@ -10,7 +18,102 @@ use tokio::{
 #[cfg(not(target_os = "android"))]
 use tracing::{debug, error};
 
+/// Creates the outbound sockets, for applications embedding clash to apply
+/// their own options to each, e.g. to `VpnService.protect()` it on Android so
+/// that its traffic doesn't loop back into the TUN.
+/// The interface, routing mark and options of clash are applied after.
+pub trait SocketFactory: Send + Sync {
+    fn create(
+        &self,
+        domain: socket2::Domain,
+        ty: socket2::Type,
+    ) -> io::Result<socket2::Socket> {
+        socket2::Socket::new(domain, ty, None)
+    }
+}
+
+/// Called with the file descriptor of each outbound socket before it's bound or
+/// connected. Returning false fails the connection.
+pub type SocketProtector = Arc<dyn Fn(i32) -> bool + Send + Sync>;
+
+struct Protect(SocketProtector);
+
+impl SocketFactory for Protect {
+    fn create(
+        &self,
+        domain: socket2::Domain,
+        ty: socket2::Type,
+    ) -> io::Result<socket2::Socket> {
+        let socket = socket2::Socket::new(domain, ty, None)?;
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            if !(self.0)(socket.as_raw_fd()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "failed to protect socket",
+                ));
+            }
+        }
+        Ok(socket)
+    }
+}
+
+static SOCKET_FACTORY: RwLock<Option<Arc<dyn SocketFactory>>> = RwLock::new(None);
+
+/// Sets the factory of the outbound sockets of the process, or unsets it
+pub fn set_socket_factory(factory: Option<Arc<dyn SocketFactory>>) {
+    *SOCKET_FACTORY.write().unwrap() = factory;
+}
+
+/// Sets a factory which has `protector` called on each socket, or unsets it
+pub fn set_socket_protector(protector: Option<SocketProtector>) {
+    set_socket_factory(
+        protector.map(|x| Arc::new(Protect(x)) as Arc<dyn SocketFactory>),
+    );
+}
+
+/// An outbound socket from the factory, if one is set
+pub fn new_socket(
+    domain: socket2::Domain,
+    ty: socket2::Type,
+) -> io::Result<socket2::Socket> {
+    match SOCKET_FACTORY.read().unwrap().as_ref() {
+        Some(factory) => factory.create(domain, ty),
+        None => socket2::Socket::new(domain, ty, None),
+    }
+}
+
+/// TCP_NODELAY of both legs of the relays, unless a rule overrides it
+static TCP_NODELAY: AtomicBool = AtomicBool::new(true);
+
+tokio::task_local! {
+    static NODELAY_OVERRIDE: bool;
+}
+
+pub fn set_tcp_nodelay(nodelay: bool) {
+    TCP_NODELAY.store(nodelay, Ordering::Relaxed);
+}
+
+/// TCP_NODELAY of the sockets connected now, that of `with_nodelay` around
+/// them if any
+pub fn tcp_nodelay() -> bool {
+    NODELAY_OVERRIDE
+        .try_with(|x| *x)
+        .unwrap_or_else(|_| TCP_NODELAY.load(Ordering::Relaxed))
+}
+
+/// Has the sockets connected by `f`, through the whole proxy chain, set to
+/// `nodelay` if it's some
+pub async fn with_nodelay<F: Future>(nodelay: Option<bool>, f: F) -> F::Output {
+    match nodelay {
+        Some(nodelay) => NODELAY_OVERRIDE.scope(nodelay, f).await,
+        None => f.await,
+    }
+}
+
 pub fn apply_tcp_options(s: TcpStream) -> std::io::Result<TcpStream> {
+    s.set_nodelay(tcp_nodelay())?;
     #[cfg(not(target_os = "windows"))]
     {
         let s = socket2::Socket::from(s.into_std()?);
@@ -40,21 +143,14 @@ pub async fn new_tcp_stream(
     iface: Option<Interface>,
     #[cfg(target_os = "linux")] so_mark: Option<u32>,
 ) -> io::Result<TcpStream> {
+    let endpoint = nat64::map_socket_addr(endpoint);
     let (socket, family) = match endpoint {
         SocketAddr::V4(_) => (
-            socket2::Socket::new(
-                socket2::Domain::IPV4,
-                socket2::Type::STREAM,
-                None,
-            )?,
+            new_socket(socket2::Domain::IPV4, socket2::Type::STREAM)?,
             socket2::Domain::IPV4,
         ),
         SocketAddr::V6(_) => (
-            socket2::Socket::new(
-                socket2::Domain::IPV6,
-                socket2::Type::STREAM,
-                None,
-            )?,
+            new_socket(socket2::Domain::IPV6, socket2::Type::STREAM)?,
             socket2::Domain::IPV6,
         ),
     };
@@ -71,7 +167,7 @@ pub async fn new_tcp_stream(
     }
 
     socket.set_keepalive(true)?;
-    socket.set_nodelay(true)?;
+    socket.set_nodelay(tcp_nodelay())?;
     socket.set_nonblocking(true)?;
 
     timeout(
@@ -81,6 +177,18 @@ pub async fn new_tcp_stream(
     .await?
 }
 
+/// The path MTU discovery of the QUIC outbounds, which also lowers the MTU
+/// when the larger packets are lost to a black hole. It's probed again every
+/// minute rather than quinn's 10, so a path that carries them again gets
+/// back to the larger datagrams soon
+pub fn quic_mtu_discovery() -> quinn::MtuDiscoveryConfig {
+    let mut config = quinn::MtuDiscoveryConfig::default();
+    config
+        .interval(Duration::from_secs(60))
+        .black_hole_cooldown(Duration::from_secs(60));
+    config
+}
+
 #[allow(unused_variables)]
 pub async fn new_udp_socket(
     src: Option<SocketAddr>,
@@ -91,26 +199,23 @@ pub async fn new_udp_socket(
         Some(src) => {
             if src.is_ipv4() {
                 (
-                    socket2::Socket::new(
-                        socket2::Domain::IPV4,
-                        socket2::Type::DGRAM,
-                        None,
-                    )?,
+                    new_socket(socket2::Domain::IPV4, socket2::Type::DGRAM)?,
                     socket2::Domain::IPV4,
                 )
             } else {
                 (
-                    socket2::Socket::new(
-                        socket2::Domain::IPV6,
-                        socket2::Type::DGRAM,
-                        None,
-                    )?,
+                    new_socket(socket2::Domain::IPV6, socket2::Type::DGRAM)?,
                     socket2::Domain::IPV6,
                 )
             }
         }
+        // there is no IPv4 to send from through NAT64
+        None if nat64::prefix().is_some() => (
+            new_socket(socket2::Domain::IPV6, socket2::Type::DGRAM)?,
+            socket2::Domain::IPV6,
+        ),
         None => (
-            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, None)?,
+            new_socket(socket2::Domain::IPV4, socket2::Type::DGRAM)?,
             socket2::Domain::IPV4,
         ),
     };
@@ -152,3 +257,100 @@ pub async fn new_udp_socket(
 
     UdpSocket::from_std(socket.into())
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread::{self, ThreadId},
+    };
+
+    use super::{
+        SocketFactory, new_tcp_stream, new_udp_socket, set_socket_factory,
+        set_socket_protector, tcp_nodelay, with_nodelay,
+    };
+
+    /// sets the TTL of the sockets of the thread of the test, the tests run
+    /// meanwhile get theirs as usual
+    struct Ttl(ThreadId);
+
+    impl SocketFactory for Ttl {
+        fn create(
+            &self,
+            domain: socket2::Domain,
+            ty: socket2::Type,
+        ) -> io::Result<socket2::Socket> {
+            let socket = socket2::Socket::new(domain, ty, None)?;
+            if thread::current().id() == self.0 {
+                socket.set_ttl(7)?;
+            }
+            Ok(socket)
+        }
+    }
+
+    /// unsets the factory even if the test fails
+    struct Unset;
+
+    impl Drop for Unset {
+        fn drop(&mut self) {
+            set_socket_factory(None);
+        }
+    }
+
+    // the factory is process-wide, so both are in one test
+    #[tokio::test]
+    async fn test_socket_factory() {
+        let _unset = Unset;
+        let test_thread = thread::current().id();
+        let new_socket = || {
+            new_udp_socket(
+                None,
+                None,
+                #[cfg(target_os = "linux")]
+                None,
+            )
+        };
+
+        set_socket_factory(Some(Arc::new(Ttl(test_thread))));
+        let socket = new_socket().await;
+        set_socket_factory(None);
+        assert_eq!(socket.unwrap().ttl().unwrap(), 7);
+
+        let protected = Arc::new(AtomicBool::new(false));
+        let flag = protected.clone();
+        set_socket_protector(Some(Arc::new(move |fd| {
+            if thread::current().id() == test_thread {
+                flag.store(fd >= 0, Ordering::Relaxed);
+            }
+            true
+        })));
+        let socket = new_socket().await;
+        set_socket_protector(None);
+        assert!(socket.is_ok());
+        assert!(protected.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_nodelay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = || {
+            new_tcp_stream(
+                addr,
+                None,
+                #[cfg(target_os = "linux")]
+                None,
+            )
+        };
+
+        assert!(tcp_nodelay());
+        assert!(connect().await.unwrap().nodelay().unwrap());
+        let s = with_nodelay(Some(false), connect()).await.unwrap();
+        assert!(!s.nodelay().unwrap());
+        assert!(with_nodelay(None, async { tcp_nodelay() }).await);
+    }
+}
@@ -46,9 +46,11 @@ pub async fn build_dns_resolver() -> anyhow::Result<Arc<dyn ClashResolver>> {
     );
 
     debug!("initializing cache store");
-    let cache_store = profile::ThreadSafeCacheFile::new(
-        root.join("cache.db").as_path().to_str().unwrap(),
+    let cache_store = profile::ThreadSafeCacheFile::open(
+        &root,
+        config.profile.cache_store,
         config.profile.store_selected,
+        config.profile.store_delay_history,
     );
 
     let dns_resolver = Arc::new(
@@ -0,0 +1,483 @@
+//! Local servers for the end-to-end tests of the outbounds, without docker:
+//! an echo server as the destination, and the socks5, shadowsocks, trojan and
+//! vmess servers, simple enough to run in process. The docker fixtures in
+//! `docker_utils` cover the rest.
+//!
+//! Enabled with the `test-fixtures` feature outside of the tests of this
+//! crate, as `clash_lib::test_fixtures`.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+};
+
+use crate::{
+    app::dns::{SystemResolver, ThreadSafeDNSResolver},
+    common::utils::encode_hex,
+    proxy::{AnyOutboundHandler, datagram::UdpPacket, vmess},
+    session::{Session, SocksAddr},
+};
+
+/// Echoes TCP and UDP on ephemeral ports of 127.0.0.1 until dropped
+pub struct EchoServer {
+    tcp: SocketAddr,
+    udp: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl EchoServer {
+    pub async fn start() -> io::Result<Self> {
+        let tcp = TcpListener::bind("127.0.0.1:0").await?;
+        let udp = UdpSocket::bind("127.0.0.1:0").await?;
+        let (tcp_addr, udp_addr) = (tcp.local_addr()?, udp.local_addr()?);
+
+        let tcp_task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = tcp.accept().await {
+                tokio::spawn(async move {
+                    let (mut r, mut w) = stream.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+        let udp_task = tokio::spawn(async move {
+            let mut buf = vec![0; 65535];
+            while let Ok((n, src)) = udp.recv_from(&mut buf).await {
+                let _ = udp.send_to(&buf[..n], src).await;
+            }
+        });
+
+        Ok(Self {
+            tcp: tcp_addr,
+            udp: udp_addr,
+            tasks: vec![tcp_task, udp_task],
+        })
+    }
+
+    pub fn tcp_addr(&self) -> SocketAddr {
+        self.tcp
+    }
+
+    pub fn udp_addr(&self) -> SocketAddr {
+        self.udp
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A SOCKS5 server on an ephemeral port of 127.0.0.1, without auth and
+/// CONNECT only
+pub struct Socks5Server {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Socks5Server {
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = socks5_connect(stream).await;
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Socks5Server {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn socks5_connect(mut stream: TcpStream) -> io::Result<()> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&0) {
+        stream.write_all(&[5, 0xff]).await?;
+        return Err(io::Error::other("no acceptable auth method"));
+    }
+    stream.write_all(&[5, 0]).await?;
+
+    let mut head = [0u8; 3];
+    stream.read_exact(&mut head).await?;
+    let target = SocksAddr::read_from(&mut stream).await?;
+    if head[1] != 1 {
+        stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        return Err(io::Error::other("only CONNECT is supported"));
+    }
+
+    let mut remote = connect(target).await?;
+    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    copy_bidirectional(&mut stream, &mut remote).await?;
+    Ok(())
+}
+
+/// A shadowsocks server on an ephemeral port of 127.0.0.1, TCP only
+#[cfg(feature = "shadowsocks")]
+pub struct ShadowsocksServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+#[cfg(feature = "shadowsocks")]
+impl ShadowsocksServer {
+    /// `cipher` as in the config, e.g. `aes-256-gcm`
+    pub async fn start(cipher: &str, password: &str) -> io::Result<Self> {
+        use shadowsocks::{
+            ProxyListener, ServerConfig, config::ServerType, context::Context,
+            crypto::CipherKind, relay::Address,
+        };
+
+        let cipher: CipherKind = cipher
+            .parse()
+            .map_err(|_| io::Error::other(format!("unknown cipher {}", cipher)))?;
+        let cfg = ServerConfig::new(("127.0.0.1", 0), password, cipher)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        let listener =
+            ProxyListener::bind(Context::new_shared(ServerType::Server), &cfg)
+                .await?;
+        let addr = listener.local_addr()?;
+
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let Ok(target) = stream.handshake().await else {
+                        return;
+                    };
+                    let target = match target {
+                        Address::SocketAddress(addr) => SocksAddr::Ip(addr),
+                        Address::DomainNameAddress(host, port) => {
+                            SocksAddr::Domain(host, port)
+                        }
+                    };
+                    if let Ok(mut remote) = connect(target).await {
+                        let _ = copy_bidirectional(&mut stream, &mut remote).await;
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+#[cfg(feature = "shadowsocks")]
+impl Drop for ShadowsocksServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A trojan server on an ephemeral port of 127.0.0.1, without TLS and
+/// CONNECT only
+pub struct TrojanServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TrojanServer {
+    pub async fn start(password: &str) -> io::Result<Self> {
+        use sha2::{Digest, Sha224};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let password = encode_hex(&Sha224::digest(password.as_bytes()));
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let password = password.clone();
+                tokio::spawn(async move {
+                    let _ = trojan_connect(stream, &password).await;
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for TrojanServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn trojan_connect(mut stream: TcpStream, password: &str) -> io::Result<()> {
+    let mut head = vec![0u8; password.len() + 3];
+    stream.read_exact(&mut head).await?;
+    if &head[..password.len()] != password.as_bytes() {
+        return Err(io::Error::other("wrong password"));
+    }
+    if head[password.len() + 2] != 1 {
+        return Err(io::Error::other("only CONNECT is supported"));
+    }
+    let target = SocksAddr::read_from(&mut stream).await?;
+    let mut crlf = [0u8; 2];
+    stream.read_exact(&mut crlf).await?;
+
+    let mut remote = connect(target).await?;
+    copy_bidirectional(&mut stream, &mut remote).await?;
+    Ok(())
+}
+
+/// A VMess server on an ephemeral port of 127.0.0.1, for the clients with
+/// `alter-id: 0` and `cipher: none`, TCP only
+pub struct VmessServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl VmessServer {
+    pub async fn start(uuid: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let uuid = uuid.to_owned();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let uuid = uuid.clone();
+                tokio::spawn(async move {
+                    let _ = vmess_connect(stream, &uuid).await;
+                });
+            }
+        });
+        Ok(Self { addr, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for VmessServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn vmess_connect(mut stream: TcpStream, uuid: &str) -> io::Result<()> {
+    let req = vmess::server::read_request(&mut stream, uuid).await?;
+    let remote = connect(req.dst.clone()).await?;
+    stream.write_all(&req.response()?).await?;
+
+    let (mut client_r, mut client_w) = stream.into_split();
+    let (mut remote_r, mut remote_w) = remote.into_split();
+    // the chunks are a length in u16 and the data without `security`
+    let upload = async move {
+        let mut buf = Vec::new();
+        while let Ok(len) = client_r.read_u16().await {
+            buf.resize(len as usize, 0);
+            client_r.read_exact(&mut buf).await?;
+            remote_w.write_all(&buf).await?;
+        }
+        io::Result::Ok(())
+    };
+    let download = async move {
+        let mut buf = vec![0; 1 << 14];
+        loop {
+            let n = remote_r.read(&mut buf).await?;
+            if n == 0 {
+                return io::Result::Ok(());
+            }
+            client_w.write_u16(n as u16).await?;
+            client_w.write_all(&buf[..n]).await?;
+        }
+    };
+    tokio::select! {
+        r = upload => r,
+        r = download => r,
+    }
+}
+
+async fn connect(target: SocksAddr) -> io::Result<TcpStream> {
+    match target {
+        SocksAddr::Ip(addr) => TcpStream::connect(addr).await,
+        SocksAddr::Domain(host, port) => {
+            TcpStream::connect((host.as_str(), port)).await
+        }
+    }
+}
+
+fn resolver() -> anyhow::Result<ThreadSafeDNSResolver> {
+    Ok(Arc::new(
+        SystemResolver::new(false).map_err(|e| anyhow!("{}", e))?,
+    ))
+}
+
+/// Round trips a few messages through `handler` to the TCP of `echo`
+pub async fn echo_tcp_test(
+    handler: AnyOutboundHandler,
+    echo: &EchoServer,
+) -> anyhow::Result<()> {
+    let sess = Session {
+        destination: SocksAddr::Ip(echo.tcp_addr()),
+        ..Default::default()
+    };
+    let mut stream = handler.connect_stream(&sess, resolver()?).await?;
+
+    for i in 0..10 {
+        let msg = format!("ping {}", i);
+        stream.write_all(msg.as_bytes()).await?;
+        let mut buf = vec![0; msg.len()];
+        stream.read_exact(&mut buf).await?;
+        ensure!(buf == msg.as_bytes(), "{} echoed {:?}", msg, buf);
+    }
+    Ok(())
+}
+
+/// Round trips a few packets through `handler` to the UDP of `echo`
+pub async fn echo_udp_test(
+    handler: AnyOutboundHandler,
+    echo: &EchoServer,
+) -> anyhow::Result<()> {
+    let src = SocksAddr::Ip("127.0.0.1:0".parse()?);
+    let dst = SocksAddr::Ip(echo.udp_addr());
+    let sess = Session {
+        destination: dst.clone(),
+        ..Default::default()
+    };
+    let mut datagram = handler.connect_datagram(&sess, resolver()?).await?;
+
+    for i in 0..10 {
+        let msg = format!("ping {}", i).into_bytes();
+        datagram
+            .send(UdpPacket::new(msg.clone(), src.clone(), dst.clone()))
+            .await?;
+        let pkt = datagram
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("no packet received"))?;
+        ensure!(pkt.data == msg, "{:?} echoed {:?}", msg, pkt.data);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{
+        EchoServer, Socks5Server, TrojanServer, VmessServer, echo_tcp_test,
+        echo_udp_test,
+    };
+    use crate::proxy::{direct, socks, trojan, vmess};
+
+    #[tokio::test]
+    async fn test_direct_echo() {
+        let echo = EchoServer::start().await.unwrap();
+        let handler = Arc::new(direct::Handler::new());
+
+        echo_tcp_test(handler.clone(), &echo).await.unwrap();
+        echo_udp_test(handler, &echo).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_echo() {
+        let echo = EchoServer::start().await.unwrap();
+        let server = Socks5Server::start().await.unwrap();
+        let handler = Arc::new(socks::Handler::new(socks::HandlerOptions {
+            name: "socks".to_owned(),
+            common_opts: Default::default(),
+            server: server.addr().ip().to_string(),
+            port: server.addr().port(),
+            user: None,
+            password: None,
+            udp: false,
+            tls_client: None,
+        }));
+
+        echo_tcp_test(handler, &echo).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trojan_echo() {
+        const PASSWORD: &str = "FzcLbKs2dY9mhL";
+
+        let echo = EchoServer::start().await.unwrap();
+        let server = TrojanServer::start(PASSWORD).await.unwrap();
+        let handler = Arc::new(trojan::Handler::new(trojan::HandlerOptions {
+            name: "trojan".to_owned(),
+            common_opts: Default::default(),
+            server: server.addr().ip().to_string(),
+            port: server.addr().port(),
+            password: PASSWORD.to_owned(),
+            udp: false,
+            tls: None,
+            transport: None,
+        }));
+
+        echo_tcp_test(handler, &echo).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vmess_echo() {
+        const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+        let echo = EchoServer::start().await.unwrap();
+        let server = VmessServer::start(UUID).await.unwrap();
+        let handler = Arc::new(vmess::Handler::new(vmess::HandlerOptions {
+            name: "vmess".to_owned(),
+            common_opts: Default::default(),
+            server: server.addr().ip().to_string(),
+            port: server.addr().port(),
+            uuid: UUID.to_owned(),
+            alter_id: 0,
+            security: "none".to_owned(),
+            udp: false,
+            transport: None,
+            tls: None,
+        }));
+
+        echo_tcp_test(handler, &echo).await.unwrap();
+    }
+
+    #[cfg(feature = "shadowsocks")]
+    #[tokio::test]
+    async fn test_shadowsocks_echo() {
+        use super::ShadowsocksServer;
+        use crate::proxy::shadowsocks;
+
+        const CIPHER: &str = "aes-256-gcm";
+        const PASSWORD: &str = "FzcLbKs2dY9mhL";
+
+        let echo = EchoServer::start().await.unwrap();
+        let server = ShadowsocksServer::start(CIPHER, PASSWORD).await.unwrap();
+        let handler =
+            Arc::new(shadowsocks::Handler::new(shadowsocks::HandlerOptions {
+                name: "ss".to_owned(),
+                common_opts: Default::default(),
+                server: server.addr().ip().to_string(),
+                port: server.addr().port(),
+                password: PASSWORD.to_owned(),
+                cipher: CIPHER.to_owned(),
+                plugin: None,
+                udp: false,
+                udp_mux: false,
+            }));
+
+        echo_tcp_test(handler, &echo).await.unwrap();
+    }
+}
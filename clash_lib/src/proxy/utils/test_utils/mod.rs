@@ -1,6 +1,9 @@
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixtures;
+#[cfg(test)]
 pub mod noop;
 
-#[cfg(docker_test)]
+#[cfg(all(test, docker_test))]
 pub mod docker_utils;
-#[cfg(docker_test)]
+#[cfg(all(test, docker_test))]
 pub use docker_utils::*;
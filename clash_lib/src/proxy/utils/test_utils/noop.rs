@@ -58,6 +58,10 @@ impl ClashResolver for NoopResolver {
         false
     }
 
+    async fn retain_fake_ip(&self, _ip: std::net::IpAddr) {}
+
+    async fn release_fake_ip(&self, _ip: std::net::IpAddr) {}
+
     fn fake_ip_enabled(&self) -> bool {
         false
     }
@@ -18,11 +18,13 @@ use crate::{
 };
 
 use self::vmess_impl::OutboundDatagramVmess;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub(crate) use self::vmess_impl::server;
 
 use super::{
     AnyStream, ConnectorType, DialWithConnector, HandlerCommonOptions,
     OutboundHandler, OutboundType,
-    transport::Transport,
+    transport::{SessionStats, Transport, tls_in_tls},
     utils::{GLOBAL_DIRECT_CONNECTOR, RemoteConnector},
 };
 
@@ -70,14 +72,15 @@ impl Handler {
         sess: &'a Session,
         udp: bool,
     ) -> io::Result<AnyStream> {
+        let timeouts = &self.opts.common_opts.timeouts;
         let s = if let Some(tls) = self.opts.tls.as_ref() {
-            tls.proxy_stream(s).await?
+            timeouts.transport(tls.proxy_stream(s)).await?
         } else {
             s
         };
 
         let s = if let Some(transport) = self.opts.transport.as_ref() {
-            transport.proxy_stream(s).await?
+            timeouts.transport(transport.proxy_stream(s)).await?
         } else {
             s
         };
@@ -90,7 +93,7 @@ impl Handler {
             dst: sess.destination.clone(),
         })?;
 
-        vmess_builder.proxy_stream(s).await
+        timeouts.protocol(vmess_builder.proxy_stream(s)).await
     }
 }
 
@@ -105,6 +108,10 @@ impl OutboundHandler for Handler {
         OutboundType::Vmess
     }
 
+    fn tls_sessions(&self) -> Option<SessionStats> {
+        self.opts.tls.as_ref().and_then(|x| x.session_stats())
+    }
+
     /// whether the outbound handler support UDP
     async fn support_udp(&self) -> bool {
         self.opts.udp
@@ -164,18 +171,30 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedStream> {
-        let stream = connector
-            .connect_stream(
+        let stream = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(target_os = "linux")]
                 sess.so_mark,
-            )
+            ))
             .await?;
 
         let s = self.inner_proxy_stream(stream, sess, false).await?;
+        let s = match self.opts.tls {
+            Some(_) => tls_in_tls::guard(
+                self.name(),
+                sess,
+                s,
+                self.opts.common_opts.tls_in_tls,
+            ),
+            None => s,
+        };
         let chained = ChainedStreamWrapper::new(s);
         chained.append_to_chain(self.name()).await;
         Ok(Box::new(chained))
@@ -187,15 +206,18 @@ impl OutboundHandler for Handler {
         resolver: ThreadSafeDNSResolver,
         connector: &dyn RemoteConnector,
     ) -> io::Result<BoxedChainedDatagram> {
-        let stream = connector
-            .connect_stream(
+        let stream = self
+            .opts
+            .common_opts
+            .timeouts
+            .connect(connector.connect_stream(
                 resolver,
                 self.opts.server.as_str(),
                 self.opts.port,
                 sess.iface.as_ref(),
                 #[cfg(target_os = "linux")]
                 sess.so_mark,
-            )
+            ))
             .await?;
 
         let stream = self.inner_proxy_stream(stream, sess, true).await?;
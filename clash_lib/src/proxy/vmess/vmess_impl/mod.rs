@@ -4,6 +4,8 @@ mod header;
 // pub mod http;
 mod datagram;
 mod kdf;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub(crate) mod server;
 mod stream;
 mod user;
 
@@ -0,0 +1,214 @@
+//! The server side of the AEAD handshake, for the local VMess server of the
+//! outbound tests. `security: none` and TCP only, the chunks that follow
+//! are a length in u16 and the data.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use aead::{KeyInit, generic_array::GenericArray};
+use aes::cipher::BlockDecrypt;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    common::{crypto, errors::map_io_error, utils},
+    session::SocksAddr,
+};
+
+use super::{
+    COMMAND_TCP, SECURITY_NONE, VERSION,
+    kdf::{
+        self, KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_IV,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_IV,
+        KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_KEY,
+        KDF_SALT_CONST_AUTH_ID_ENCRYPTION_KEY,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+        KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+    },
+    user,
+};
+
+/// A request of a client, accepted
+pub struct Request {
+    pub dst: SocksAddr,
+    resp_body_key: Vec<u8>,
+    resp_body_iv: Vec<u8>,
+    resp_v: u8,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Reads the request header of a client of `uuid`
+pub async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    uuid: &str,
+) -> io::Result<Request> {
+    let uuid = uuid::Uuid::parse_str(uuid)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid uuid"))?;
+    let cmd_key = user::new_id(&uuid).cmd_key;
+
+    let mut auth_id = [0u8; 16];
+    stream.read_exact(&mut auth_id).await?;
+    let pk =
+        kdf::vmess_kdf_1_one_shot(&cmd_key, KDF_SALT_CONST_AUTH_ID_ENCRYPTION_KEY);
+    let pk: [u8; 16] = pk[..16].try_into().unwrap();
+    let mut block = GenericArray::from(auth_id);
+    aes::Aes128::new(&GenericArray::from(pk)).decrypt_block(&mut block);
+    if crc32fast::hash(&block[..12]).to_be_bytes() != block[12..] {
+        return Err(invalid("invalid auth id"));
+    }
+
+    let mut len = [0u8; 18];
+    stream.read_exact(&mut len).await?;
+    let mut nonce = [0u8; 8];
+    stream.read_exact(&mut nonce).await?;
+    let len = crypto::aes_gcm_decrypt(
+        &kdf::vmess_kdf_3_one_shot(
+            &cmd_key,
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+            &auth_id,
+            &nonce,
+        )[..16],
+        &kdf::vmess_kdf_3_one_shot(
+            &cmd_key,
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+            &auth_id,
+            &nonce,
+        )[..12],
+        &len,
+        Some(&auth_id),
+    )
+    .map_err(map_io_error)?;
+    let len = u16::from_be_bytes(
+        len[..]
+            .try_into()
+            .map_err(|_| invalid("invalid header length"))?,
+    );
+
+    let mut header = vec![0u8; len as usize + 16];
+    stream.read_exact(&mut header).await?;
+    let header = crypto::aes_gcm_decrypt(
+        &kdf::vmess_kdf_3_one_shot(
+            &cmd_key,
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+            &auth_id,
+            &nonce,
+        )[..16],
+        &kdf::vmess_kdf_3_one_shot(
+            &cmd_key,
+            KDF_SALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV,
+            &auth_id,
+            &nonce,
+        )[..12],
+        &header,
+        Some(&auth_id),
+    )
+    .map_err(map_io_error)?;
+    parse_request(&header)
+}
+
+/// version, the body IV and key, the response byte, the options,
+/// padding length and security, a reserved byte, the command, the address,
+/// padding and the FNV-1a of all before it
+fn parse_request(header: &[u8]) -> io::Result<Request> {
+    let (mut buf, sum) = header
+        .split_at_checked(header.len().saturating_sub(4))
+        .filter(|(buf, _)| buf.len() > 41)
+        .ok_or_else(|| invalid("header too short"))?;
+    if const_fnv1a_hash::fnv1a_hash_32(buf, None).to_be_bytes() != sum {
+        return Err(invalid("header checksum mismatch"));
+    }
+
+    if buf.get_u8() != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    let req_body_iv = buf.copy_to_bytes(16);
+    let req_body_key = buf.copy_to_bytes(16);
+    let resp_v = buf.get_u8();
+    let _options = buf.get_u8();
+    let padding_and_security = buf.get_u8();
+    if padding_and_security & 0x0f != SECURITY_NONE {
+        return Err(invalid("only security none is supported"));
+    }
+    let _reserved = buf.get_u8();
+    if buf.get_u8() != COMMAND_TCP {
+        return Err(invalid("only TCP is supported"));
+    }
+
+    let port = buf.get_u16();
+    let dst = match buf.get_u8() {
+        0x01 if buf.remaining() >= 4 => SocksAddr::Ip(SocketAddr::new(
+            Ipv4Addr::from(buf.get_u32()).into(),
+            port,
+        )),
+        0x02 => {
+            let len = buf.get_u8() as usize;
+            if buf.remaining() < len {
+                return Err(invalid("domain too long"));
+            }
+            let host = String::from_utf8(buf.copy_to_bytes(len).to_vec())
+                .map_err(|_| invalid("invalid domain"))?;
+            SocksAddr::Domain(host, port)
+        }
+        0x03 if buf.remaining() >= 16 => SocksAddr::Ip(SocketAddr::new(
+            Ipv6Addr::from(buf.get_u128()).into(),
+            port,
+        )),
+        _ => return Err(invalid("invalid address")),
+    };
+    if buf.remaining() != (padding_and_security >> 4) as usize {
+        return Err(invalid("invalid padding"));
+    }
+
+    Ok(Request {
+        dst,
+        resp_body_key: utils::sha256(&req_body_key)[..16].to_vec(),
+        resp_body_iv: utils::sha256(&req_body_iv)[..16].to_vec(),
+        resp_v,
+    })
+}
+
+impl Request {
+    /// The response header that accepts the request
+    pub fn response(&self) -> io::Result<Vec<u8>> {
+        let header = [self.resp_v, 0, 0, 0];
+        let len = crypto::aes_gcm_encrypt(
+            &kdf::vmess_kdf_1_one_shot(
+                &self.resp_body_key,
+                KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_KEY,
+            )[..16],
+            &kdf::vmess_kdf_1_one_shot(
+                &self.resp_body_iv,
+                KDF_SALT_CONST_AEAD_RESP_HEADER_LEN_IV,
+            )[..12],
+            &(header.len() as u16).to_be_bytes(),
+            None,
+        )
+        .map_err(map_io_error)?;
+        let header = crypto::aes_gcm_encrypt(
+            &kdf::vmess_kdf_1_one_shot(
+                &self.resp_body_key,
+                KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_KEY,
+            )[..16],
+            &kdf::vmess_kdf_1_one_shot(
+                &self.resp_body_iv,
+                KDF_SALT_CONST_AEAD_RESP_HEADER_PAYLOAD_IV,
+            )[..12],
+            &header,
+            None,
+        )
+        .map_err(map_io_error)?;
+
+        let mut out = BytesMut::with_capacity(len.len() + header.len());
+        out.put_slice(&len);
+        out.put_slice(&header);
+        Ok(out.to_vec())
+    }
+}
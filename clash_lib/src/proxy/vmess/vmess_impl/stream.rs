@@ -10,6 +10,7 @@ use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 use crate::{
     common::{
+        clock,
         crypto::{self, AeadCipherHelper},
         errors::map_io_error,
         utils,
@@ -211,7 +212,7 @@ where
             ..
         } = self;
 
-        let now = SystemTime::now()
+        let now = clock::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("check your system clock")
             .as_secs();
@@ -31,6 +31,7 @@ use crate::{
 
 use super::{
     events::PortProtocol,
+    mtu::PathMtu,
     ports::PortPool,
     stack::{
         tcp::SocketPair,
@@ -251,11 +252,13 @@ impl DeviceManager {
         }
     }
 
-    pub async fn poll_sockets(&self, mut device: VirtualIpDevice) {
+    /// The interface takes the MTU of the device when it's created, so it's
+    /// created again when the MTU changes
+    fn new_interface(&self, device: &mut VirtualIpDevice) -> Interface {
         let mut config = Config::new(smoltcp::wire::HardwareAddress::Ip);
         config.random_seed = rand::random();
 
-        let mut iface = Interface::new(config, &mut device, Instant::now());
+        let mut iface = Interface::new(config, device, Instant::now());
         iface.update_ip_addrs(|addrs| {
             addrs.push(IpCidr::new(self.addr.into(), 32)).unwrap();
 
@@ -263,6 +266,12 @@ impl DeviceManager {
                 addrs.push(IpCidr::new(addr_v6.into(), 128)).unwrap();
             }
         });
+        iface
+    }
+
+    pub async fn poll_sockets(&self, mut device: VirtualIpDevice) {
+        let mut iface_mtu = device.mtu.current();
+        let mut iface = self.new_interface(&mut device);
 
         let (device_sender, mut device_receiver) = tokio::sync::mpsc::channel(1024);
 
@@ -392,6 +401,11 @@ impl DeviceManager {
                 } => {
                     let _ = trace_span!("poll_sockets").enter();
 
+                    if device.mtu.current() != iface_mtu {
+                        iface_mtu = device.mtu.current();
+                        iface = self.new_interface(&mut device);
+                    }
+
                     let timestamp = Instant::now();
                     iface.poll(timestamp, &mut device, &mut sockets);
 
@@ -642,7 +656,7 @@ impl DeviceManager {
 }
 
 pub struct VirtualIpDevice {
-    mtu: usize,
+    mtu: Arc<PathMtu>,
 
     packet_sender: Sender<Bytes>,
     packet_receiver: Receiver<(PortProtocol, Bytes)>,
@@ -658,7 +672,7 @@ impl VirtualIpDevice {
         // when wg stack receives a packet, it will send a notification to this
         // sender
         packet_notifier: Sender<()>,
-        mtu: usize,
+        mtu: Arc<PathMtu>,
     ) -> Self {
         let (inner_packet_sender, inner_packet_receiver) =
             tokio::sync::mpsc::channel(1024);
@@ -725,7 +739,7 @@ impl Device for VirtualIpDevice {
     fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
         let mut caps = smoltcp::phy::DeviceCapabilities::default();
         caps.medium = smoltcp::phy::Medium::Ip;
-        caps.max_transmission_unit = self.mtu;
+        caps.max_transmission_unit = self.mtu.current();
         caps
     }
 }
@@ -36,6 +36,7 @@ use tracing::debug;
 mod device;
 mod events;
 mod keys;
+mod mtu;
 mod ports;
 mod stack;
 mod wireguard;
@@ -53,6 +54,7 @@ pub struct HandlerOptions {
     pub remote_dns_resolve: bool,
     pub dns: Option<Vec<String>>,
     pub mtu: Option<u16>,
+    pub mtu_discovery: bool,
     pub udp: bool,
     pub allowed_ips: Option<Vec<String>>,
     pub reserved_bits: Option<Vec<u8>>,
@@ -132,6 +134,17 @@ impl Handler {
                     .transpose()?
                     .unwrap_or_default();
 
+                let mtu =
+                    Arc::new(mtu::PathMtu::new(self.opts.mtu.unwrap_or(1420) as _));
+                let mtu_probe = self.opts.mtu_discovery.then(|| {
+                    self.opts
+                        .dns
+                        .iter()
+                        .flatten()
+                        .find_map(|s| s.parse::<Ipv4Addr>().ok())
+                        .unwrap_or(Ipv4Addr::new(1, 1, 1, 1))
+                });
+
                 let wg = wireguard::WireguardTunnel::new(
                     Config {
                         private_key: self
@@ -168,6 +181,8 @@ impl Handler {
                             }
                             None => [0, 0, 0],
                         },
+                        mtu: mtu.clone(),
+                        mtu_probe,
                     },
                     recv_pair.0,
                     send_pair.1,
@@ -189,7 +204,7 @@ impl Handler {
                     send_pair.0,
                     recv_pair.1,
                     packet_notifier.0,
-                    self.opts.mtu.unwrap_or(1420) as usize,
+                    mtu,
                 );
 
                 let device_manager = Arc::new(device::DeviceManager::new(
@@ -382,6 +397,7 @@ mod tests {
             remote_dns_resolve: false,
             dns: None,
             mtu: Some(1000),
+            mtu_discovery: false,
             udp: true,
             allowed_ips: Some(vec!["0.0.0.0/0".to_owned()]),
             reserved_bits: None,
@@ -0,0 +1,237 @@
+//! Path MTU discovery of the tunnel. A path dropping the larger datagrams of
+//! UDP without an ICMP error, e.g. a PPPoE link or a mobile network, answers
+//! the small pings through the tunnel but not the large ones, so the MTU of
+//! the virtual device is the largest answered, re-probed every 10 minutes.
+
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        Icmpv4Message, Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Packet, Ipv4Repr,
+    },
+};
+use tokio::sync::oneshot;
+
+/// the minimum MTU of IPv6, which any path carrying the tunnel should take
+pub const MIN_MTU: usize = 1280;
+/// what the search stops at, a few bytes of MTU aren't worth the probes
+const PRECISION: usize = 8;
+
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+pub const PROBE_TRIES: usize = 2;
+pub const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+const IPV4_HEADER: usize = 20;
+const ICMP_HEADER: usize = 8;
+
+/// The MTU of the virtual device, the configured one unless it's discovered
+/// to be lower
+pub struct PathMtu {
+    max: usize,
+    current: AtomicUsize,
+
+    ident: u16,
+    seq_no: AtomicU16,
+    pending: Mutex<Option<(u16, oneshot::Sender<()>)>>,
+}
+
+impl PathMtu {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            current: AtomicUsize::new(max),
+            ident: rand::random(),
+            seq_no: AtomicU16::new(0),
+            pending: Mutex::new(None),
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Returns the previous MTU if it changed
+    pub fn set(&self, mtu: usize) -> Option<usize> {
+        let prev = self.current.swap(mtu, Ordering::Relaxed);
+        (prev != mtu).then_some(prev)
+    }
+
+    /// Finds the largest size up to the configured MTU that `ping` gets an
+    /// answer for, None if even the minimum isn't answered, i.e. the probed
+    /// host doesn't answer pings and tells nothing of the path
+    pub async fn discover<F, Fut>(&self, mut ping: F) -> Option<usize>
+    where
+        F: FnMut(usize) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        if self.max <= MIN_MTU {
+            return Some(self.max);
+        }
+        if !ping(MIN_MTU).await {
+            return None;
+        }
+        if ping(self.max).await {
+            return Some(self.max);
+        }
+
+        // `lo` is answered, above `hi` isn't
+        let (mut lo, mut hi) = (MIN_MTU, self.max - 1);
+        while hi - lo >= PRECISION {
+            let mid = lo + (hi - lo).div_ceil(2);
+            if ping(mid).await {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        Some(lo)
+    }
+
+    /// An ICMP echo request of `size` bytes from `src` to `dst`, and the
+    /// receiver of its reply. A new request replaces the pending one
+    pub fn echo_request(
+        &self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        size: usize,
+    ) -> (Vec<u8>, oneshot::Receiver<()>) {
+        let seq_no = self.seq_no.fetch_add(1, Ordering::Relaxed);
+        let data = vec![0u8; size.saturating_sub(IPV4_HEADER + ICMP_HEADER)];
+        let icmp = Icmpv4Repr::EchoRequest {
+            ident: self.ident,
+            seq_no,
+            data: &data,
+        };
+        let ip = Ipv4Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Icmp,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 64,
+        };
+
+        let checksum = ChecksumCapabilities::default();
+        let mut packet = vec![0u8; ip.buffer_len() + icmp.buffer_len()];
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet);
+        ip.emit(&mut ip_packet, &checksum);
+        icmp.emit(
+            &mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()),
+            &checksum,
+        );
+
+        let (tx, rx) = oneshot::channel();
+        *self.pending.lock().unwrap() = Some((seq_no, tx));
+        (packet, rx)
+    }
+
+    /// Whether `packet` is the reply to the pending probe, which is answered
+    pub fn on_echo_reply(&self, packet: &[u8]) -> bool {
+        let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+            return false;
+        };
+        if ip.next_header() != IpProtocol::Icmp {
+            return false;
+        }
+        let Ok(icmp) = Icmpv4Packet::new_checked(ip.payload()) else {
+            return false;
+        };
+        if icmp.msg_type() != Icmpv4Message::EchoReply
+            || icmp.echo_ident() != self.ident
+        {
+            return false;
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        if pending
+            .as_ref()
+            .is_some_and(|(seq_no, _)| *seq_no == icmp.echo_seq_no())
+            && let Some((_, tx)) = pending.take()
+        {
+            let _ = tx.send(());
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use smoltcp::{
+        phy::ChecksumCapabilities,
+        wire::{Icmpv4Packet, Icmpv4Repr, IpProtocol, Ipv4Packet, Ipv4Repr},
+    };
+
+    use super::{MIN_MTU, PRECISION, PathMtu};
+
+    #[tokio::test]
+    async fn test_discover() {
+        let mtu = PathMtu::new(1420);
+        assert_eq!(mtu.discover(|_| async { true }).await, Some(1420));
+        assert_eq!(mtu.discover(|_| async { false }).await, None);
+
+        let found = mtu.discover(|size| async move { size <= 1372 }).await;
+        let found = found.unwrap();
+        assert!(found <= 1372 && 1372 - found < PRECISION);
+
+        let found = mtu.discover(|size| async move { size <= MIN_MTU }).await;
+        assert_eq!(found, Some(MIN_MTU));
+
+        assert_eq!(mtu.set(1372), Some(1420));
+        assert_eq!(mtu.set(1372), None);
+        assert_eq!(mtu.current(), 1372);
+    }
+
+    #[tokio::test]
+    async fn test_echo_reply() {
+        let (src, dst) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1));
+        let mtu = PathMtu::new(1420);
+        let (request, mut reply) = mtu.echo_request(src, dst, 1400);
+        assert_eq!(request.len(), 1400);
+
+        // the peer echoes the request back
+        let checksum = ChecksumCapabilities::default();
+        let request = Ipv4Packet::new_checked(&request).unwrap();
+        let icmp = Icmpv4Packet::new_checked(request.payload()).unwrap();
+        let Icmpv4Repr::EchoRequest {
+            ident,
+            seq_no,
+            data,
+        } = Icmpv4Repr::parse(&icmp, &checksum).unwrap()
+        else {
+            panic!("not an echo request");
+        };
+        let icmp = Icmpv4Repr::EchoReply {
+            ident,
+            seq_no,
+            data,
+        };
+        let ip = Ipv4Repr {
+            src_addr: dst,
+            dst_addr: src,
+            next_header: IpProtocol::Icmp,
+            payload_len: icmp.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut packet = vec![0u8; ip.buffer_len() + icmp.buffer_len()];
+        let mut ip_packet = Ipv4Packet::new_unchecked(&mut packet);
+        ip.emit(&mut ip_packet, &checksum);
+        icmp.emit(
+            &mut Icmpv4Packet::new_unchecked(ip_packet.payload_mut()),
+            &checksum,
+        );
+
+        assert!(reply.try_recv().is_err());
+        assert!(mtu.on_echo_reply(&packet));
+        assert!(reply.try_recv().is_ok());
+        assert!(!mtu.on_echo_reply(&packet[..10]));
+    }
+}
@@ -22,7 +22,7 @@ use tokio::sync::{
     Mutex,
     mpsc::{Receiver, Sender},
 };
-use tracing::{Instrument, enabled, error, trace, trace_span, warn};
+use tracing::{Instrument, debug, enabled, error, info, trace, trace_span, warn};
 
 use crate::{
     Error,
@@ -35,7 +35,10 @@ use crate::{
     session::{Session, SocksAddr},
 };
 
-use super::events::PortProtocol;
+use super::{
+    events::PortProtocol,
+    mtu::{PROBE_TIMEOUT, PROBE_TRIES, PathMtu, REPROBE_INTERVAL},
+};
 
 pub struct WireguardTunnel {
     pub(crate) source_peer_ip: Ipv4Addr,
@@ -45,6 +48,10 @@ pub struct WireguardTunnel {
     allowed_ips: Vec<IpNet>,
     reserved_bits: [u8; 3],
 
+    mtu: Arc<PathMtu>,
+    // pinged to discover the MTU of the path
+    mtu_probe: Option<Ipv4Addr>,
+
     // UDP socket to the remote WireGuard endpoint
     tx: tokio::sync::Mutex<SplitSink<AnyOutboundDatagram, UdpPacket>>,
     rx: tokio::sync::Mutex<SplitStream<AnyOutboundDatagram>>,
@@ -74,6 +81,8 @@ pub struct Config {
     pub keepalive_seconds: Option<u16>,
     pub allowed_ips: Vec<IpNet>,
     pub reserved_bits: [u8; 3],
+    pub mtu: Arc<PathMtu>,
+    pub mtu_probe: Option<Ipv4Addr>,
 }
 
 impl WireguardTunnel {
@@ -118,6 +127,9 @@ impl WireguardTunnel {
             allowed_ips: config.allowed_ips,
             reserved_bits: config.reserved_bits,
 
+            mtu: config.mtu,
+            mtu_probe: config.mtu_probe,
+
             tx: tokio::sync::Mutex::new(tx),
             rx: tokio::sync::Mutex::new(rx),
 
@@ -174,9 +186,57 @@ impl WireguardTunnel {
             _ = self.start_receiving() => {
                 trace!("receiving stopped")
             }
+            _ = self.start_mtu_discovery() => {
+                trace!("mtu discovery stopped")
+            }
         }
     }
 
+    async fn start_mtu_discovery(&self) {
+        let Some(probe) = self.mtu_probe else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            let mtu = &self.mtu;
+            match mtu.discover(move |size| self.ping(probe, size)).await {
+                Some(size) => {
+                    if let Some(prev) = mtu.set(size) {
+                        info!(
+                            "wireguard {} path mtu changed from {} to {}",
+                            self.endpoint, prev, size
+                        );
+                    }
+                }
+                None => {
+                    debug!(
+                        "{} not answering pings through wireguard {}, keeping mtu \
+                         {}",
+                        probe,
+                        self.endpoint,
+                        mtu.current()
+                    );
+                }
+            }
+            tokio::time::sleep(REPROBE_INTERVAL).await;
+        }
+    }
+
+    async fn ping(&self, dst: Ipv4Addr, size: usize) -> bool {
+        for _ in 0..PROBE_TRIES {
+            let (packet, reply) =
+                self.mtu.echo_request(self.source_peer_ip, dst, size);
+            if let Err(e) = self.send_ip_packet(&packet).await {
+                debug!("failed to send mtu probe: {}", e);
+                return false;
+            }
+            if let Ok(Ok(())) = tokio::time::timeout(PROBE_TIMEOUT, reply).await {
+                return true;
+            }
+        }
+        false
+    }
+
     pub async fn start_forwarding(&self) {
         let mut packet_reader = self.packet_reader.lock().await;
         loop {
@@ -292,6 +352,10 @@ impl WireguardTunnel {
                         continue;
                     }
 
+                    if self.mtu_probe.is_some() && self.mtu.on_echo_reply(packet) {
+                        continue;
+                    }
+
                     let _ =
                         trace_span!("wg_write_stack", endpoint = %self.endpoint, size = packet.len())
                             .entered();
@@ -0,0 +1,512 @@
+//! The facade for applications embedding clash, e.g. GUI clients and mobile
+//! apps: a [`Runtime`] is started on the tokio runtime of the application,
+//! reloaded and stopped through its methods, and looked into through handles
+//! that keep working across reloads.
+
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{Mutex, broadcast, mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info};
+
+use crate::{
+    Config, DispatcherHook, Error, GlobalState, Result,
+    app::{
+        dispatcher::{Hooks, StatisticsManager},
+        dns::ThreadSafeDNSResolver,
+        inbound::manager::InboundManager,
+        logging::LogEvent,
+        outbound::manager::OutboundManager,
+        profile::ThreadSafeCacheFile,
+    },
+    config::internal::InternalConfig,
+    create_components,
+};
+
+/// The parts of the running config the handles reach, replaced on reload
+#[derive(Clone)]
+struct Components {
+    dns_resolver: ThreadSafeDNSResolver,
+    outbound_manager: Arc<OutboundManager>,
+    cache_store: ThreadSafeCacheFile,
+    inbound_manager: Arc<InboundManager>,
+}
+
+/// A running clash. It runs until [`Runtime::stop`] is called or it's
+/// dropped, or until it fails to reload a config.
+///
+/// Logging is left to the application, the events only go to the `/logs`
+/// endpoint of the controller if it sets up the logging of clash.
+///
+/// # Example
+/// ```no_run
+/// # async fn run() -> clash_lib::Result<()> {
+/// let runtime = clash_lib::Runtime::start(
+///     clash_lib::Config::File("config.yaml".to_owned()),
+///     None,
+///     vec![],
+/// )
+/// .await?;
+/// let ip = runtime.resolver().resolve("example.com").await?;
+/// runtime.stop().await
+/// # }
+/// ```
+pub struct Runtime {
+    pub(crate) shutdown_tx: mpsc::Sender<()>,
+    /// stops the listeners and waits up to the duration for the connections
+    /// before stopping, once handed over to a new process
+    pub(crate) drain_tx: mpsc::Sender<Duration>,
+    reload_tx: mpsc::Sender<(Config, oneshot::Sender<()>)>,
+    components: Arc<RwLock<Components>>,
+    statistics_manager: Arc<StatisticsManager>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Runtime {
+    /// Starts `config` with the paths in it relative to `cwd`, the current
+    /// directory if `None`
+    pub async fn start(
+        config: Config,
+        cwd: Option<String>,
+        hooks: Vec<Arc<dyn DispatcherHook>>,
+    ) -> Result<Self> {
+        let (log_tx, _) = broadcast::channel(100);
+        Self::start_with_logs(
+            config.try_parse()?,
+            PathBuf::from(cwd.unwrap_or_else(|| ".".to_string())),
+            log_tx,
+            hooks,
+        )
+        .await
+    }
+
+    pub(crate) async fn start_with_logs(
+        config: InternalConfig,
+        cwd: PathBuf,
+        log_tx: broadcast::Sender<LogEvent>,
+        hooks: Vec<Arc<dyn DispatcherHook>>,
+    ) -> Result<Self> {
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        let (drain_tx, mut drain_rx) = mpsc::channel(1);
+
+        // things we need to clone before consuming config
+        #[cfg(feature = "api")]
+        let controller_cfg = config.general.controller.clone();
+        let log_level = config.general.log_level;
+
+        let components = create_components(cwd.clone(), config, None).await?;
+        let statistics_manager = components.statistics_manager.clone();
+        let hooks = Hooks::new(hooks);
+        hooks.check(&components.outbound_manager)?;
+        statistics_manager.set_hooks(hooks);
+
+        components.inbound_manager.start().await;
+
+        let tun_runner_handle = components.tun_runner.map(tokio::spawn);
+        let dns_listener_handle = components.dns_listener.map(tokio::spawn);
+
+        let (reload_tx, mut reload_rx) = mpsc::channel(1);
+
+        let global_state = Arc::new(Mutex::new(GlobalState {
+            log_level,
+            tunnel_listener_handle: tun_runner_handle,
+            dns_listener_handle,
+            reload_tx: reload_tx.clone(),
+            api_listener_handle: None,
+            cwd: cwd.to_string_lossy().to_string(),
+        }));
+
+        let current = Arc::new(RwLock::new(Components {
+            dns_resolver: components.dns_resolver.clone(),
+            outbound_manager: components.outbound_manager.clone(),
+            cache_store: components.cache_store.clone(),
+            inbound_manager: components.inbound_manager.clone(),
+        }));
+
+        #[cfg(feature = "api")]
+        {
+            let api_runner = crate::app::api::get_api_runner(
+                controller_cfg,
+                log_tx.clone(),
+                components.inbound_manager,
+                components.dispatcher,
+                global_state.clone(),
+                components.dns_resolver,
+                components.outbound_manager,
+                components.statistics_manager,
+                components.cache_store,
+                components.router,
+                components.lifecycle,
+                cwd.to_string_lossy().to_string(),
+            );
+            if let Some(r) = api_runner {
+                let api_listener_handle = tokio::spawn(r);
+                global_state.lock().await.api_listener_handle =
+                    Some(api_listener_handle);
+            }
+        }
+        #[cfg(not(feature = "api"))]
+        let _ = log_tx;
+
+        let reloaded = current.clone();
+        let stats = statistics_manager.clone();
+        let state = global_state.clone();
+        let reload = async move {
+            while let Some((config, done)) = reload_rx.recv().await {
+                info!("reloading config");
+                let config = match config.try_parse() {
+                    Ok(c) => c,
+                    Err(e) => {
+                        error!("failed to reload config: {}", e);
+                        continue;
+                    }
+                };
+
+                #[cfg(feature = "api")]
+                let controller_cfg = config.general.controller.clone();
+
+                let new_components =
+                    create_components(cwd.clone(), config, Some(stats.clone()))
+                        .await?;
+                if let Err(e) = stats.hooks().check(&new_components.outbound_manager)
+                {
+                    error!("failed to reload config: {}", e);
+                    continue;
+                }
+
+                let _ = done.send(());
+
+                // the connections still routed the same way keep going
+                let closed = stats.close_changed(&new_components.dispatcher).await;
+                info!("{} connections routed differently now closed", closed);
+
+                debug!("stopping listeners");
+                let old = reloaded.read().unwrap().inbound_manager.clone();
+                old.shutdown().await;
+                let mut g = state.lock().await;
+
+                if let Some(h) = g.tunnel_listener_handle.take() {
+                    h.abort();
+                }
+                if let Some(h) = g.dns_listener_handle.take() {
+                    h.abort();
+                }
+                if let Some(h) = g.api_listener_handle.take() {
+                    h.abort();
+                }
+
+                *reloaded.write().unwrap() = Components {
+                    dns_resolver: new_components.dns_resolver.clone(),
+                    outbound_manager: new_components.outbound_manager.clone(),
+                    cache_store: new_components.cache_store.clone(),
+                    inbound_manager: new_components.inbound_manager.clone(),
+                };
+
+                debug!("reloading inbound listener");
+                new_components.inbound_manager.restart().await;
+
+                debug!("reloading tun runner");
+                g.tunnel_listener_handle =
+                    new_components.tun_runner.map(tokio::spawn);
+
+                debug!("reloading dns listener");
+                g.dns_listener_handle =
+                    new_components.dns_listener.map(tokio::spawn);
+
+                #[cfg(feature = "api")]
+                {
+                    debug!("reloading api listener");
+                    g.api_listener_handle = crate::app::api::get_api_runner(
+                        controller_cfg,
+                        log_tx.clone(),
+                        new_components.inbound_manager,
+                        new_components.dispatcher,
+                        state.clone(),
+                        new_components.dns_resolver,
+                        new_components.outbound_manager,
+                        new_components.statistics_manager,
+                        new_components.cache_store,
+                        new_components.router,
+                        new_components.lifecycle,
+                        cwd.to_string_lossy().to_string(),
+                    )
+                    .map(tokio::spawn);
+                }
+            }
+            Ok::<_, Error>(())
+        };
+
+        let stopped = current.clone();
+        let stats = statistics_manager.clone();
+        let task = tokio::spawn(async move {
+            let (result, drain) = tokio::select! {
+                _ = shutdown_rx.recv() => {
+                    info!("receiving shutdown signal");
+                    (Ok(()), None)
+                }
+                Some(timeout) = drain_rx.recv() => {
+                    info!("handed over, stopping once the connections are done");
+                    (Ok(()), Some(timeout))
+                }
+                r = reload => (r, None),
+            };
+
+            let inbound_manager = stopped.read().unwrap().inbound_manager.clone();
+            inbound_manager.shutdown().await;
+            let mut g = global_state.lock().await;
+            for h in [
+                g.tunnel_listener_handle.take(),
+                g.dns_listener_handle.take(),
+                g.api_listener_handle.take(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                h.abort();
+            }
+            if let Some(timeout) = drain {
+                // the stack they're on is gone with the TUN runner
+                let closed = stats.close_inbound(crate::session::Type::Tun).await;
+                if closed > 0 {
+                    info!("{} TUN connections closed", closed);
+                }
+                let open = stats.wait_closed(timeout).await;
+                if open > 0 {
+                    info!("closing {} connections still open", open);
+                }
+            }
+            stats.close_all().await;
+
+            result.inspect_err(|x| error!("runtime error: {}, shutting down", x))
+        });
+
+        Ok(Self {
+            shutdown_tx,
+            drain_tx,
+            reload_tx,
+            components: current,
+            statistics_manager,
+            task,
+        })
+    }
+
+    /// Replaces the running config with `config`, keeping the connections
+    /// still routed the same way
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        let (done, reloaded) = oneshot::channel();
+        self.reload_tx
+            .send((config, done))
+            .await
+            .map_err(|_| Error::Operation("the runtime is stopped".to_owned()))?;
+        reloaded.await.map_err(|_| {
+            Error::Operation("failed to reload config, see the logs".to_owned())
+        })
+    }
+
+    /// Stops the listeners and closes the connections
+    pub async fn stop(self) -> Result<()> {
+        let _ = self.shutdown_tx.send(()).await;
+        self.wait().await
+    }
+
+    /// Waits until the runtime is stopped, by [`crate::shutdown`] or a failed
+    /// reload
+    pub async fn wait(self) -> Result<()> {
+        // it's stopped when the last sender is dropped
+        let _shutdown_tx = self.shutdown_tx;
+        self.task
+            .await
+            .map_err(|e| Error::Operation(format!("runtime task failed: {}", e)))?
+    }
+
+    pub fn resolver(&self) -> ResolverHandle {
+        ResolverHandle {
+            components: self.components.clone(),
+        }
+    }
+
+    pub fn proxies(&self) -> ProxyHandle {
+        ProxyHandle {
+            components: self.components.clone(),
+        }
+    }
+
+    pub fn connections(&self) -> ConnectionHandle {
+        ConnectionHandle {
+            manager: self.statistics_manager.clone(),
+        }
+    }
+}
+
+/// The DNS resolver of the running config
+#[derive(Clone)]
+pub struct ResolverHandle {
+    components: Arc<RwLock<Components>>,
+}
+
+impl ResolverHandle {
+    fn inner(&self) -> ThreadSafeDNSResolver {
+        self.components.read().unwrap().dns_resolver.clone()
+    }
+
+    /// an address of `host`, a fake one in the fake-ip mode
+    pub async fn resolve(&self, host: &str) -> Result<Option<IpAddr>> {
+        self.inner()
+            .resolve(host, true)
+            .await
+            .map_err(|e| Error::DNSError(e.to_string()))
+    }
+
+    /// the domain `ip` was given for in the fake-ip mode, or resolved to
+    pub async fn reverse_lookup(&self, ip: IpAddr) -> Option<String> {
+        self.inner().reverse_lookup(ip).await
+    }
+}
+
+/// The proxies and groups of the running config
+#[derive(Clone)]
+pub struct ProxyHandle {
+    components: Arc<RwLock<Components>>,
+}
+
+impl ProxyHandle {
+    fn outbound_manager(&self) -> Arc<OutboundManager> {
+        self.components.read().unwrap().outbound_manager.clone()
+    }
+
+    /// all of them, as `GET /proxies` lists them
+    pub async fn list(&self) -> serde_json::Value {
+        serde_json::to_value(self.outbound_manager().get_proxies().await)
+            .unwrap_or_default()
+    }
+
+    /// the proxy selected in a `select` group
+    pub async fn selected(&self, group: &str) -> Option<String> {
+        let ctrl = self.outbound_manager().get_selector_control(group)?;
+        Some(ctrl.lock().await.current().await)
+    }
+
+    /// selects `name` in a `select` group, which is remembered if the
+    /// config stores the selections
+    pub async fn select(&self, group: &str, name: &str) -> Result<()> {
+        let (outbound_manager, cache_store) = {
+            let c = self.components.read().unwrap();
+            (c.outbound_manager.clone(), c.cache_store.clone())
+        };
+        let ctrl =
+            outbound_manager
+                .get_selector_control(group)
+                .ok_or_else(|| {
+                    Error::Operation(format!("{} is not a select group", group))
+                })?;
+        ctrl.lock()
+            .await
+            .select(name)
+            .await
+            .map_err(|e| Error::Operation(e.to_string()))?;
+        cache_store.set_selected(group, name).await;
+        Ok(())
+    }
+
+    /// the delay and the mean delay of a request to `url` through `name`, in
+    /// milliseconds
+    pub async fn delay(
+        &self,
+        name: &str,
+        url: &str,
+        timeout: Duration,
+    ) -> Result<(u16, u16)> {
+        let outbound_manager = self.outbound_manager();
+        let proxy = outbound_manager
+            .get_outbound(name)
+            .ok_or_else(|| Error::Operation(format!("proxy {} not found", name)))?;
+        Ok(outbound_manager.url_test(proxy, url, timeout).await?)
+    }
+}
+
+/// The connections, which are kept across reloads
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    manager: Arc<StatisticsManager>,
+}
+
+impl ConnectionHandle {
+    /// the open connections and the byte totals, as `GET /connections` has
+    /// them
+    pub async fn snapshot(&self) -> serde_json::Value {
+        serde_json::to_value(self.manager.snapshot().await).unwrap_or_default()
+    }
+
+    /// the bytes per second sent and received
+    pub fn traffic(&self) -> (u64, u64) {
+        self.manager.now()
+    }
+
+    /// closes the connection of the `id` in the snapshot
+    pub async fn close(&self, id: &str) -> Result<()> {
+        let id = id.parse().map_err(|_| {
+            Error::Operation(format!("invalid connection id {}", id))
+        })?;
+        self.manager.close(id).await;
+        Ok(())
+    }
+
+    pub async fn close_all(&self) {
+        self.manager.close_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Config;
+
+    use super::Runtime;
+
+    #[tokio::test]
+    async fn test_runtime() {
+        let conf = r#"
+        socks-port: 7895
+        bind-address: 127.0.0.1
+        mmdb: "tests/data/Country.mmdb"
+        proxy-groups:
+          - name: select
+            type: select
+            proxies:
+              - DIRECT
+              - REJECT
+        "#;
+
+        let runtime = Runtime::start(Config::Str(conf.to_owned()), None, vec![])
+            .await
+            .unwrap();
+        let proxies = runtime.proxies();
+        assert!(proxies.list().await.get("select").is_some());
+        assert_eq!(proxies.selected("select").await.as_deref(), Some("DIRECT"));
+        proxies.select("select", "REJECT").await.unwrap();
+        assert_eq!(proxies.selected("select").await.as_deref(), Some("REJECT"));
+        assert!(proxies.select("select", "missing").await.is_err());
+
+        // the handles see the new config
+        runtime
+            .reload(Config::Str(conf.replace("name: select", "name: chosen")))
+            .await
+            .unwrap();
+        assert!(proxies.list().await.get("chosen").is_some());
+        assert!(runtime.reload(Config::Str("{".to_owned())).await.is_err());
+
+        assert!(
+            runtime.connections().snapshot().await["connections"]
+                .as_array()
+                .unwrap()
+                .is_empty()
+        );
+        runtime.stop().await.unwrap();
+    }
+}
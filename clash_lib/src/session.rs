@@ -14,7 +14,7 @@ use erased_serde::Serialize as ESerialize;
 
 use crate::app::net::Interface;
 
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Debug, PartialEq, Eq, Hash, Serialize)]
 pub enum SocksAddr {
     Ip(SocketAddr),
     Domain(String, u16),
@@ -412,9 +412,38 @@ pub enum Type {
     #[cfg(target_os = "linux")]
     Tproxy,
     Tunnel,
+    ReverseProxy,
     Ignore,
 }
 
+/// An application protocol told by the sniffer
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Serialize)]
+pub enum Protocol {
+    BitTorrent,
+    Quic,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::BitTorrent => "BITTORRENT",
+            Protocol::Quic => "QUIC",
+        })
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "BITTORRENT" => Ok(Protocol::BitTorrent),
+            "QUIC" => Ok(Protocol::Quic),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
 impl Display for Network {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(match self {
@@ -442,6 +471,12 @@ pub struct Session {
     pub iface: Option<Interface>,
     /// The ASN of the destination IP address. Only for display.
     pub asn: Option<String>,
+    /// The outbound set by the inbound, e.g. a tunnel, bypassing the rules.
+    pub outbound: Option<String>,
+    /// The `sub-rules` entry of the listener, routed by instead of the rules.
+    pub sub_rules: Option<String>,
+    /// The protocol sniffed from the first bytes sent.
+    pub protocol: Option<Protocol>,
 }
 
 impl Session {
@@ -468,6 +503,10 @@ impl Session {
         );
         rv.insert("host".to_string(), Box::new(self.destination.host()) as _);
         rv.insert("asn".to_string(), Box::new(self.asn.clone()) as _);
+        rv.insert(
+            "protocol".to_string(),
+            Box::new(self.protocol.map(|p| p.to_string())) as _,
+        );
         rv
     }
 }
@@ -483,6 +522,9 @@ impl Default for Session {
             so_mark: None,
             iface: None,
             asn: None,
+            outbound: None,
+            sub_rules: None,
+            protocol: None,
         }
     }
 }
@@ -506,6 +548,9 @@ impl Debug for Session {
             .field("packet_mark", &self.so_mark)
             .field("iface", &self.iface)
             .field("asn", &self.asn)
+            .field("outbound", &self.outbound)
+            .field("sub_rules", &self.sub_rules)
+            .field("protocol", &self.protocol)
             .finish()
     }
 }
@@ -521,6 +566,9 @@ impl Clone for Session {
             so_mark: self.so_mark,
             iface: self.iface.as_ref().cloned(),
             asn: self.asn.clone(),
+            outbound: self.outbound.clone(),
+            sub_rules: self.sub_rules.clone(),
+            protocol: self.protocol,
         }
     }
 }